@@ -0,0 +1,125 @@
+//! Averaging an autonomous test's [`Measurement`]s into the [`LoggedSample`]s the battery
+//! interface keeps in flash, fewer than the PC saves so a long discharge fits.
+
+use crate::{LoggedSample, Measurement, MilliAmp, MilliVolt};
+
+/// Measurements averaged into each sample, about 10 s of them
+pub const SAMPLE_MEASUREMENTS: u32 = 10;
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct SampleAverage {
+	/// When the test started, ms since the battery interface booted
+	started_ms: u64,
+	measurements: u32,
+	first_dt: u64,
+	/// When the last measurement added ended
+	end_dt: u64,
+	sum_millivolts: u32,
+	sum_milliamps: i32,
+}
+
+impl SampleAverage {
+	pub fn new(started_ms: u64) -> Self {
+		Self {
+			started_ms,
+			..Self::default()
+		}
+	}
+
+	/// Add a measurement, returns the sample once it's full
+	pub fn push(&mut self, m: &Measurement) -> Option<LoggedSample> {
+		if self.measurements == 0 {
+			self.first_dt = m.sample_start_ms;
+		}
+		self.end_dt = m.sample_start_ms + m.sample_duration_ms;
+		// can't overflow, SAMPLE_MEASUREMENTS * u16::MAX < u32::MAX
+		self.sum_millivolts += u32::from(u16::from(m.vbat));
+		self.sum_milliamps += i32::from(i16::from(m.ibat));
+		self.measurements += 1;
+		if self.measurements < SAMPLE_MEASUREMENTS {
+			return None;
+		}
+		self.finish()
+	}
+
+	/// The partial sample at the end of the test, `None` if there's nothing in it
+	pub fn finish(&mut self) -> Option<LoggedSample> {
+		if self.measurements == 0 {
+			return None;
+		}
+		let sample = LoggedSample {
+			sample_start_ms: ms_u32(self.first_dt.saturating_sub(self.started_ms)),
+			sample_duration_ms: ms_u32(self.end_dt - self.first_dt),
+			vbat: MilliVolt::new((self.sum_millivolts / self.measurements) as u16),
+			ibat: MilliAmp::new((self.sum_milliamps / self.measurements as i32) as i16),
+		};
+		*self = Self::new(self.started_ms);
+		Some(sample)
+	}
+}
+
+/// A test would have to run for 49 days to reach the end
+fn ms_u32(ms: u64) -> u32 {
+	u32::try_from(ms).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::MilliWatt;
+
+	fn measurement(millivolts: u16, milliamps: i16, dt: u64) -> Measurement {
+		Measurement {
+			vbat: MilliVolt::new(millivolts),
+			ibat: MilliAmp::new(milliamps),
+			milliwatts: MilliWatt::new(0),
+			sample_index: 0,
+			sample_start_ms: dt,
+			sample_duration_ms: 900,
+			temp_centi_c: None,
+			load_temp_centi_c: None,
+			fan_on: false,
+			load: None,
+		}
+	}
+
+	#[test]
+	fn test_sample_averages_from_the_test_start() {
+		let mut average = SampleAverage::new(5_000);
+		let mut sample = None;
+		for i in 0..SAMPLE_MEASUREMENTS {
+			// 12_000..=12_900 mV
+			sample = average.push(&measurement(
+				12_000 + i as u16 * 100,
+				2_000 + i as i16,
+				6_000 + u64::from(i) * 1_000,
+			));
+		}
+		assert_eq!(
+			sample,
+			Some(LoggedSample {
+				sample_start_ms: 1_000,
+				sample_duration_ms: 9_900,
+				vbat: MilliVolt::new(12_450),
+				ibat: MilliAmp::new(2_004),
+			})
+		);
+		assert_eq!(average.finish(), None);
+	}
+
+	#[test]
+	fn test_only_full_samples_until_finished() {
+		let mut average = SampleAverage::new(0);
+		assert_eq!(average.push(&measurement(11_000, 2_000, 0)), None);
+		assert_eq!(average.push(&measurement(11_100, 2_000, 1_000)), None);
+		assert_eq!(
+			average.finish(),
+			Some(LoggedSample {
+				sample_start_ms: 0,
+				sample_duration_ms: 1_900,
+				vbat: MilliVolt::new(11_050),
+				ibat: MilliAmp::new(2_000),
+			})
+		);
+	}
+}
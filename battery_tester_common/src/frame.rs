@@ -0,0 +1,209 @@
+//! Framing for the UART link between the PC and the battery interface.
+//!
+//! A frame is the postcard encoded message followed by a big endian CRC16 of it,
+//! COBS encoded and terminated with [`FRAME_DELIMITER`].
+//! COBS guarantees the delimiter never shows up inside a frame so the receiver
+//! can always resync at the next delimiter after a corrupted or dropped byte.
+
+use crc::{CRC_16_IBM_3740, Crc, Digest};
+use defmt::Format;
+use postcard::ser_flavors::Flavor;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{COMMAND_MAX_SIZE, REPLY_MAX_SIZE};
+
+/// Marks the end of every frame
+pub const FRAME_DELIMITER: u8 = 0x00;
+const CRC_SIZE: usize = 2;
+static CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+
+/// Worst case size of a whole frame holding a message of at most `msg_max_size` bytes
+pub const fn max_frame_size(msg_max_size: usize) -> usize {
+	// + 1 for the delimiter
+	cobs::max_encoding_length(msg_max_size + CRC_SIZE) + 1
+}
+
+pub const COMMAND_FRAME_MAX_SIZE: usize = max_frame_size(COMMAND_MAX_SIZE);
+pub const REPLY_FRAME_MAX_SIZE: usize = max_frame_size(REPLY_MAX_SIZE);
+
+#[derive(Debug, PartialEq, Eq, Format, Clone, Copy)]
+pub enum FrameError {
+	/// Output buffer can't hold the whole frame
+	BufferTooSmall,
+	/// Incoming frame was longer than the receive buffer
+	Overflow,
+	/// COBS encoding is invalid
+	Cobs,
+	/// Frame is too short to hold a checksum
+	TooShort,
+	/// Checksum doesn't match the message
+	Crc,
+	/// Checksum matched but the message couldn't be deserialized
+	Deserialize,
+}
+
+/// Postcard flavor that checksums and COBS encodes the message as it's serialized
+struct CrcCobs<'a> {
+	cobs: cobs::CobsEncoder<'a>,
+	digest: Digest<'static, u16>,
+}
+
+impl Flavor for CrcCobs<'_> {
+	type Output = usize;
+
+	fn try_push(&mut self, data: u8) -> postcard::Result<()> {
+		self.try_extend(&[data])
+	}
+
+	fn try_extend(&mut self, data: &[u8]) -> postcard::Result<()> {
+		self.digest.update(data);
+		self.cobs
+			.push(data)
+			.map_err(|_| postcard::Error::SerializeBufferFull)
+	}
+
+	fn finalize(mut self) -> postcard::Result<usize> {
+		let crc = self.digest.finalize().to_be_bytes();
+		self.cobs
+			.push(&crc)
+			.map_err(|_| postcard::Error::SerializeBufferFull)?;
+		Ok(self.cobs.finalize())
+	}
+}
+
+/// Serialize `msg` into `buf` as a complete frame, delimiter included.
+/// Returns the part of `buf` to send.
+pub fn encode<'a, T>(msg: &T, buf: &'a mut [u8]) -> Result<&'a [u8], FrameError>
+where
+	T: Serialize + ?Sized,
+{
+	let flavor = CrcCobs {
+		cobs: cobs::CobsEncoder::new(buf),
+		digest: CRC.digest(),
+	};
+	let len =
+		postcard::serialize_with_flavor(msg, flavor).map_err(|_| FrameError::BufferTooSmall)?;
+	*buf.get_mut(len).ok_or(FrameError::BufferTooSmall)? = FRAME_DELIMITER;
+	Ok(&buf[..=len])
+}
+
+/// Decode a frame in place, `frame` must not include the delimiter.
+pub fn decode<T>(frame: &mut [u8]) -> Result<T, FrameError>
+where
+	T: DeserializeOwned,
+{
+	let len = cobs::decode_in_place(frame).map_err(|_| FrameError::Cobs)?;
+	let msg_len = len.checked_sub(CRC_SIZE).ok_or(FrameError::TooShort)?;
+	let (msg, crc) = frame[..len].split_at(msg_len);
+	if CRC.checksum(msg).to_be_bytes() != crc {
+		return Err(FrameError::Crc);
+	}
+	postcard::from_bytes(msg).map_err(|_| FrameError::Deserialize)
+}
+
+/// Collects bytes from the link until a whole frame has arrived.
+pub struct FrameBuffer<const N: usize> {
+	buf: [u8; N],
+	len: usize,
+	/// the current frame didn't fit, drop everything up to the next delimiter
+	overflowed: bool,
+}
+
+impl<const N: usize> Default for FrameBuffer<N> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<const N: usize> FrameBuffer<N> {
+	pub const fn new() -> Self {
+		Self {
+			buf: [0; N],
+			len: 0,
+			overflowed: false,
+		}
+	}
+
+	/// Drop any partially received frame
+	pub fn clear(&mut self) {
+		self.len = 0;
+		self.overflowed = false;
+	}
+
+	/// Add one byte from the link.
+	/// Returns the decoded message (or why it was dropped) when `byte` ends a frame.
+	pub fn push<T>(&mut self, byte: u8) -> Option<Result<T, FrameError>>
+	where
+		T: DeserializeOwned,
+	{
+		if byte == FRAME_DELIMITER {
+			let len = core::mem::take(&mut self.len);
+			if core::mem::take(&mut self.overflowed) {
+				return Some(Err(FrameError::Overflow));
+			}
+			if len == 0 {
+				// back to back delimiters, nothing was lost
+				return None;
+			}
+			return Some(decode(&mut self.buf[..len]));
+		}
+		if !self.overflowed {
+			match self.buf.get_mut(self.len) {
+				Some(b) => {
+					*b = byte;
+					self.len += 1;
+				}
+				None => self.overflowed = true,
+			}
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{BiCommand, CommandKind, ControlWord, LoadState};
+
+	fn on_command() -> BiCommand {
+		BiCommand {
+			seq: 7,
+			kind: CommandKind::Control(ControlWord {
+				load: LoadState::On,
+				..Default::default()
+			}),
+		}
+	}
+
+	fn feed<const N: usize>(
+		frame_buf: &mut FrameBuffer<N>,
+		bytes: &[u8],
+	) -> Option<Result<BiCommand, FrameError>> {
+		bytes.iter().find_map(|b| frame_buf.push(*b))
+	}
+
+	#[test]
+	fn test_round_trip() {
+		let mut out = [0u8; COMMAND_FRAME_MAX_SIZE];
+		let frame = encode(&on_command(), &mut out).unwrap();
+		assert_eq!(frame.last(), Some(&FRAME_DELIMITER));
+		assert!(!frame[..frame.len() - 1].contains(&FRAME_DELIMITER));
+		let mut frame_buf = FrameBuffer::<COMMAND_FRAME_MAX_SIZE>::new();
+		assert_eq!(feed(&mut frame_buf, frame), Some(Ok(on_command())));
+	}
+
+	#[test]
+	fn test_corrupt_frame_then_resync() {
+		let mut out = [0u8; COMMAND_FRAME_MAX_SIZE];
+		let frame = encode(&on_command(), &mut out).unwrap();
+		let mut corrupt = [0u8; COMMAND_FRAME_MAX_SIZE];
+		corrupt[..frame.len()].copy_from_slice(frame);
+		corrupt[1] ^= 0x01;
+		let mut frame_buf = FrameBuffer::<COMMAND_FRAME_MAX_SIZE>::new();
+		assert!(matches!(
+			feed(&mut frame_buf, &corrupt[..frame.len()]),
+			Some(Err(_))
+		));
+		assert_eq!(feed(&mut frame_buf, frame), Some(Ok(on_command())));
+	}
+}
@@ -5,8 +5,79 @@ use nutype::nutype;
 use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
 
+pub mod autonomous;
+pub mod frame;
+pub mod pwm;
+pub mod window;
+
 pub const COMMAND_MAX_SIZE: usize = BiCommand::POSTCARD_MAX_SIZE;
 pub const REPLY_MAX_SIZE: usize = BIReply::POSTCARD_MAX_SIZE;
+/// Bump whenever the PC <-> battery interface messages change
+pub const PROTOCOL_VERSION: u16 = 34;
+/// Highest vbat the battery interface turns the load on at when it isn't sent a limit,
+/// a 12 V battery on its charger reads under it and a 24 V pack well over
+pub const DEFAULT_MAX_MILLIV: u16 = 16_000;
+/// How often the PC sends a command when it doesn't say, see [`ControlWord::poll_ms`]
+pub const DEFAULT_POLL_MS: u16 = 500;
+pub const MIN_POLL_MS: u16 = 100;
+pub const MAX_POLL_MS: u16 = 5_000;
+
+/// How long the battery interface waits for a command before turning the load off,
+/// two and a half of the PC's intervals so one late command isn't taken as the link going down
+pub fn com_timeout_ms(poll_ms: Option<u16>) -> u64 {
+	let poll_ms = poll_ms
+		.unwrap_or(DEFAULT_POLL_MS)
+		.clamp(MIN_POLL_MS, MAX_POLL_MS);
+	u64::from(poll_ms) * 5 / 2
+}
+
+/// The battery interface's UART baud unless its firmware is built with another,
+/// see [`ReplyKind::Version`]
+pub const DEFAULT_BAUD: u32 = 230_400;
+/// What the nRF's UARTE can run at
+pub const BAUDS: [u32; 18] = [
+	1_200, 2_400, 4_800, 9_600, 14_400, 19_200, 28_800, 31_250, 38_400, 56_000, 57_600, 76_800,
+	115_200, 230_400, 250_000, 460_800, 921_600, 1_000_000,
+];
+
+pub const fn baud_supported(baud: u32) -> bool {
+	let mut i = 0;
+	while i < BAUDS.len() {
+		if BAUDS[i] == baud {
+			return true;
+		}
+		i += 1;
+	}
+	false
+}
+
+/// One of [`BAUDS`] in decimal, usable in a const so the firmware's is checked as it's built
+pub const fn parse_baud(digits: &str) -> Option<u32> {
+	let digits = digits.as_bytes();
+	if digits.is_empty() {
+		return None;
+	}
+	let mut baud: u32 = 0;
+	let mut i = 0;
+	while i < digits.len() {
+		if !digits[i].is_ascii_digit() {
+			return None;
+		}
+		baud = match baud.checked_mul(10) {
+			Some(baud) => match baud.checked_add((digits[i] - b'0') as u32) {
+				Some(baud) => baud,
+				None => return None,
+			},
+			None => return None,
+		};
+		i += 1;
+	}
+	if baud_supported(baud) {
+		Some(baud)
+	} else {
+		None
+	}
+}
 
 #[nutype(
 	derive(
@@ -31,7 +102,8 @@ pub const REPLY_MAX_SIZE: usize = BIReply::POSTCARD_MAX_SIZE;
 	default = 0,
 	const_fn
 )]
-pub struct MilliAmp(u16);
+/// Battery current, positive while discharging and negative while charging
+pub struct MilliAmp(i16);
 
 #[nutype(
 	derive(
@@ -58,12 +130,237 @@ pub struct MilliAmp(u16);
 )]
 pub struct MilliVolt(u16);
 
-#[derive(Debug, Default, PartialEq, Eq, MaxSize, Format, Clone, Copy, Serialize, Deserialize)]
+#[nutype(
+	derive(
+		Debug,
+		PartialEq,
+		Eq,
+		PartialOrd,
+		Ord,
+		Clone,
+		Copy,
+		AsRef,
+		Deref,
+		Borrow,
+		Display,
+		Default,
+		From,
+		Into,
+		Deserialize,
+		Serialize
+	),
+	derive_unsafe(Format, MaxSize),
+	default = 0,
+	const_fn
+)]
+pub struct MilliWatt(u32);
+
+/// USB IDs of the battery interface's own CDC-ACM port, the pid.codes test IDs.
+/// The PC tells it apart from the micro:bit's interface MCU by them.
+pub const USB_VID: u16 = 0x1209;
+pub const USB_PID: u16 = 0x0001;
+
+/// `seq` of replies the firmware sends without being asked,
+/// e.g. when a fault is cleared with the button. The PC never sends it.
+pub const UNSOLICITED_SEQ: u32 = u32::MAX;
+
+/// Every command gets exactly one [`BIReply`] with the same `seq`
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Serialize, Deserialize)]
 pub struct BiCommand {
+	/// Incremented by the PC for each command sent
+	pub seq: u32,
+	pub kind: CommandKind,
+}
+
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Serialize, Deserialize)]
+pub enum CommandKind {
+	/// Ask for a [`ReplyKind::Version`]
+	Hello,
+	Control(ControlWord),
+	/// Use and save this trim, answered with [`ReplyKind::Trim`]
+	SetTrim(Trim),
+	/// Ask for a [`ReplyKind::Trim`]
+	GetTrim,
+	/// Ask for the faults kept in flash, each is sent unsolicited as a [`ReplyKind::FaultLog`],
+	/// oldest first, then one without a fault answers this
+	DumpFaultLog,
+	/// Erase the autonomous log and run the test without the PC, answered with
+	/// [`ReplyKind::Autonomous`]. Control words don't change the load while it runs,
+	/// one that resets ends it.
+	StartAutonomous(AutonomousTest),
+	/// Ask for the last autonomous test's log, sent like [`CommandKind::DumpFaultLog`]
+	/// as [`ReplyKind::AutonomousLog`]s
+	DumpAutonomousLog,
+	/// Check the sensors and pulse the load, answered with [`ReplyKind::SelfTest`].
+	/// Dropped while the battery interface waits on a battery or a fault to clear.
+	SelfTest,
+	/// Use and save this, answered with [`ReplyKind::BatteryDetect`]
+	SetBatteryDetect(BatteryDetect),
+	/// Ask for a [`ReplyKind::BatteryDetect`]
+	GetBatteryDetect,
+	/// Keep the link up without changing anything, answered with a [`ReplyKind::Status`]
+	/// like a [`CommandKind::Control`]. The battery interface keeps the last control word.
+	Heartbeat,
+}
+
+/// Desired state of the battery interface
+#[derive(Debug, Default, PartialEq, Eq, MaxSize, Format, Clone, Copy, Serialize, Deserialize)]
+pub struct ControlWord {
 	pub load: LoadState,
 	pub reset: Reset,
 	pub clear_fault: ClearFault,
 	pub allow_undercurrent: AllowUndercurrent,
+	/// Turn the load off on our own once vbat drops to this, even if the PC goes quiet
+	pub cutoff: Option<MilliVolt>,
+	/// Current the load should draw, `None` for [`LoadModel::default`]
+	pub load_model: Option<LoadModel>,
+	/// Hold the current here with the load on, `None` for the load's full current
+	pub current_setpoint: Option<MilliAmp>,
+	/// Fault with [`FaultKind::Overvoltage`] rather than load a battery over this,
+	/// `None` for [`DEFAULT_MAX_MILLIV`]
+	pub max_voltage: Option<MilliVolt>,
+	/// Samples averaged into each measurement, `None` for [`window::WINDOW_SAMPLES`],
+	/// see [`window::SampleWindow::set_len`]
+	pub window_samples: Option<u8>,
+	/// How each measurement's samples are combined
+	pub filter: window::WindowFilter,
+	/// How often the PC sends commands, see [`com_timeout_ms`]. `None` keeps the last one,
+	/// [`DEFAULT_POLL_MS`] until one is sent.
+	pub poll_ms: Option<u16>,
+}
+
+/// What the heater load draws, for the under and overcurrent faults,
+/// so a different load doesn't need the firmware rebuilt.
+/// A line through the current at each vbat, measured for each rig.
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Serialize, Deserialize)]
+pub struct LoadModel {
+	/// Resistance of the load, the expected current goes up by vbat over it
+	pub milliohms: u32,
+	/// Added to vbat over [`LoadModel::milliohms`], what's drawn alongside the heater
+	/// or the load's own offset
+	pub offset: MilliAmp,
+	/// How far the current can be from the expected before it's a fault
+	pub max_deviation: MilliAmp,
+}
+
+impl Default for LoadModel {
+	/// The heater this was built for, 8.4 A at 12 V, within 200 mA
+	fn default() -> Self {
+		Self {
+			milliohms: 12_000 * 1000 / 8_400,
+			offset: MilliAmp::new(0),
+			max_deviation: MilliAmp::new(200),
+		}
+	}
+}
+
+impl LoadModel {
+	pub fn expected_current(&self, vbat: MilliVolt) -> MilliAmp {
+		// I = V / R + offset, in µV over mOhm to keep it in integers
+		let milliamps = i64::from(u16::from(vbat)) * 1000 / i64::from(self.milliohms.max(1))
+			+ i64::from(i16::from(self.offset));
+		MilliAmp::new(milliamps.clamp(i64::from(i16::MIN), i64::from(i16::MAX)) as i16)
+	}
+
+	/// Lowest and highest current that isn't a fault at `vbat`
+	pub fn current_range(&self, vbat: MilliVolt) -> (MilliAmp, MilliAmp) {
+		let nominal = i16::from(self.expected_current(vbat));
+		let deviation = i16::from(self.max_deviation);
+		(
+			MilliAmp::new(nominal.saturating_sub(deviation)),
+			MilliAmp::new(nominal.saturating_add(deviation)),
+		)
+	}
+}
+
+/// Corrections the battery interface makes to its INA260 readings and PWM output,
+/// kept in its flash so they're measured once for each board
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Serialize, Deserialize)]
+pub struct Trim {
+	/// Parts per million to scale current readings by, [`TRIM_UNITY_PPM`] leaves them
+	pub milliamps_ppm: u32,
+	/// Added to current readings after scaling
+	pub milliamps_offset: i16,
+	/// Parts per million to scale voltage readings by, [`TRIM_UNITY_PPM`] leaves them
+	pub millivolts_ppm: u32,
+	/// Added to voltage readings after scaling
+	pub millivolts_offset: i16,
+	/// µs added to the PWM pulse width, measured with a scope
+	pub pwm_us: u16,
+}
+
+pub const TRIM_UNITY_PPM: u32 = 1_000_000;
+
+impl Default for Trim {
+	fn default() -> Self {
+		Self::DEFAULT
+	}
+}
+
+impl Trim {
+	/// Readings as the INA260 gives them, and the PWM trim of the first board
+	pub const DEFAULT: Self = Self {
+		milliamps_ppm: TRIM_UNITY_PPM,
+		milliamps_offset: 0,
+		millivolts_ppm: TRIM_UNITY_PPM,
+		millivolts_offset: 0,
+		pwm_us: 16,
+	};
+
+	/// Trimmed current from the INA260's, in mA
+	pub fn milliamps(&self, raw: i32) -> MilliAmp {
+		let scaled = i64::from(raw) * i64::from(self.milliamps_ppm) / i64::from(TRIM_UNITY_PPM);
+		let trimmed = scaled + i64::from(self.milliamps_offset);
+		MilliAmp::new(trimmed.clamp(i16::MIN.into(), i16::MAX.into()) as i16)
+	}
+
+	/// Trimmed voltage from the INA260's, in mV
+	pub fn millivolts(&self, raw: u32) -> MilliVolt {
+		let scaled = i64::from(raw) * i64::from(self.millivolts_ppm) / i64::from(TRIM_UNITY_PPM);
+		let trimmed = scaled + i64::from(self.millivolts_offset);
+		MilliVolt::new(trimmed.clamp(0, u16::MAX.into()) as u16)
+	}
+
+	/// Power from the INA260's, in mW, scaled like the current and voltage it's the product of
+	pub fn milliwatts(&self, raw: u32) -> MilliWatt {
+		let unity = u64::from(TRIM_UNITY_PPM);
+		let scaled = u64::from(raw) * u64::from(self.milliamps_ppm) / unity
+			* u64::from(self.millivolts_ppm)
+			/ unity;
+		MilliWatt::new(scaled.min(u32::MAX.into()) as u32)
+	}
+}
+
+/// How the battery interface tells a battery is connected, kept in its flash with the [`Trim`]
+/// since it depends on the fixture wired to the input
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Serialize, Deserialize)]
+pub struct BatteryDetect {
+	/// The input has to read present this long before a battery counts as connected,
+	/// it counts as removed as soon as it doesn't
+	pub debounce_ms: u16,
+	pub polarity: Polarity,
+}
+
+impl Default for BatteryDetect {
+	fn default() -> Self {
+		Self::DEFAULT
+	}
+}
+
+impl BatteryDetect {
+	/// The SparkFun opto-isolator breakout, high with a battery
+	pub const DEFAULT: Self = Self {
+		debounce_ms: 250,
+		polarity: Polarity::ActiveHigh,
+	};
+}
+
+/// Which level of the battery-present input means there's a battery
+#[derive(Debug, Default, PartialEq, Eq, MaxSize, Format, Clone, Copy, Serialize, Deserialize)]
+pub enum Polarity {
+	#[default]
+	ActiveHigh,
+	ActiveLow,
 }
 
 #[derive(Debug, Default, PartialEq, Eq, MaxSize, Format, Clone, Copy, Serialize, Deserialize)]
@@ -97,17 +394,187 @@ pub enum LoadState {
 }
 
 #[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+/// Average of a [`window`] of samples
 pub struct Measurement {
 	pub vbat: MilliVolt,
 	pub ibat: MilliAmp,
-	pub dt: u64,
-	pub duration: u64,
+	/// From the INA260's power register, a magnitude whichever way the current flows
+	pub milliwatts: MilliWatt,
+	/// Counts measurements since the battery interface booted, one apart unless some were
+	/// dropped, e.g. the oldest buffered while the PC wasn't sending commands
+	pub sample_index: u32,
+	/// When the window's first sample was taken, ms since the battery interface booted
+	pub sample_start_ms: u64,
+	/// ms from the window's first sample to its last,
+	/// every sample is within `sample_start_ms..=sample_start_ms + sample_duration_ms`
+	pub sample_duration_ms: u64,
+	/// SHT4x temperature at the end of the window, `None` if there's no sensor
+	pub temp_centi_c: Option<i16>,
+	/// The heater's thermistor at the end of the window, `None` if there's no thermistor
+	pub load_temp_centi_c: Option<i16>,
+	/// The heater's cooling fan was running at the end of the window
+	pub fan_on: bool,
+	/// The heater branch averaged over the same window, `None` without the second INA260
+	pub load: Option<LoadChannel>,
+}
+
+/// What a second INA260 on the heater side measures, the battery current less what the
+/// battery interface's own circuits and any leakage take
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+pub struct LoadChannel {
+	pub millivolts: MilliVolt,
+	pub milliamps: MilliAmp,
 }
 
 #[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
 pub struct BIReply {
+	/// `seq` of the [`BiCommand`] this answers
+	pub seq: u32,
+	/// The battery-present input as the [`BatteryDetect`] in use reads it, not debounced,
+	/// so a battery shows up before its voltage is measured
+	pub bat_present: bool,
+	/// What the load is set to as the reply goes out, by the PC, button B or an autonomous test
+	pub load: LoadState,
+	pub kind: ReplyKind,
+}
+
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+pub enum ReplyKind {
+	/// Answer to [`CommandKind::Hello`]
+	Version {
+		protocol: u16,
+		firmware: FirmwareVersion,
+		/// Unique to the board, the nRF's factory programmed FICR DEVICEID
+		device_id: u64,
+		/// The self test run once there was a battery after it started, `None` until then
+		self_test: Option<SelfTestReport>,
+		/// The UART's, the battery interface's own USB doesn't have one
+		baud: u32,
+	},
+	/// Answer to [`CommandKind::Control`]
+	Status(Status),
+	/// Answer to [`CommandKind::SetTrim`] and [`CommandKind::GetTrim`], the trim in use.
+	/// `Err` when a new trim couldn't be saved, it's used until the battery interface restarts.
+	Trim(Result<Trim, FlashError>),
+	/// One of the faults asked for by [`CommandKind::DumpFaultLog`], `None` once they're all sent
+	FaultLog(Option<LoggedFault>),
+	/// A measurement taken while the PC wasn't sending commands, sent unsolicited and
+	/// oldest first before the [`Status`] answering a [`CommandKind::Control`].
+	/// The [`Status`] has no measurement until they've all been sent, so they stay in order.
+	Replay(Measurement),
+	/// Answer to [`CommandKind::StartAutonomous`], `Err` if the log couldn't be erased
+	/// and the test wasn't started
+	Autonomous(Result<(), FlashError>),
+	/// One of the samples asked for by [`CommandKind::DumpAutonomousLog`], `None` once they're all sent
+	AutonomousLog(Option<LoggedSample>),
+	/// Answer to [`CommandKind::SelfTest`]
+	SelfTest(SelfTestReport),
+	/// Answer to [`CommandKind::SetBatteryDetect`] and [`CommandKind::GetBatteryDetect`],
+	/// `Err` when it couldn't be saved, like [`ReplyKind::Trim`]
+	BatteryDetect(Result<BatteryDetect, FlashError>),
+}
+
+/// What a self test found
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+pub struct SelfTestReport {
+	/// The battery side INA260 answered with an INA260's IDs
+	pub ina_vin: Result<(), I2CError>,
+	/// The same for the load side one, `None` when it wasn't found as the battery interface started
+	pub ina_load: Option<Result<(), I2CError>>,
+	/// The load pulsed on, `None` when it was already on for a test
+	pub load: Option<Result<LoadPulse, I2CError>>,
+}
+
+impl SelfTestReport {
+	/// Nothing failed, parts that weren't tested don't count against it
+	pub fn passed(&self) -> bool {
+		self.ina_vin.is_ok()
+			&& !matches!(self.ina_load, Some(Err(_)))
+			&& match self.load {
+				Some(Ok(pulse)) => pulse.in_range(),
+				Some(Err(_)) => false,
+				None => true,
+			}
+	}
+}
+
+/// The load's current once it ramped up during a self test, against what the
+/// [`LoadModel`] in use expects at that voltage
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+pub struct LoadPulse {
+	pub vbat: MilliVolt,
+	pub ibat: MilliAmp,
+	pub expected_min: MilliAmp,
+	pub expected_max: MilliAmp,
+}
+
+impl LoadPulse {
+	pub fn in_range(&self) -> bool {
+		(self.expected_min..=self.expected_max).contains(&self.ibat)
+	}
+}
+
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+pub enum FlashError {
+	Erase,
+	Write,
+}
+
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+pub struct Status {
 	pub measurement: Option<Measurement>,
 	pub fault: Result<(), Fault>,
+	/// The load was turned off at [`ControlWord::cutoff`] and stays off until a reset
+	pub cutoff_reached: bool,
+	/// Button B toggled the load to this since the last reply,
+	/// the command this answers was sent before the PC knew and wasn't applied to the load
+	pub local_load: Option<LoadState>,
+	/// Why the battery interface started, only in the first status after it boots
+	pub reset_reason: Option<ResetReason>,
+	/// It has no control word to keep, a [`CommandKind::Heartbeat`] won't do. Set from boot,
+	/// a fault or waiting on the battery until a control word reaches the power loop.
+	pub wants_control: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+pub enum ResetReason {
+	/// Power was applied, nothing else is recorded
+	PowerOn,
+	/// The reset pin, the micro:bit's button or its USB interface
+	Pin,
+	/// The hardware watchdog wasn't fed, the firmware hung
+	Watchdog,
+	/// The firmware or a debugger asked for it
+	SoftReset,
+	/// The CPU locked up
+	Lockup,
+	/// Woken from system off, the firmware never sleeps there
+	Other,
+}
+
+impl ResetReason {
+	/// A reset nobody asked for, the firmware hung or crashed
+	pub const fn unexpected(&self) -> bool {
+		matches!(self, ResetReason::Watchdog | ResetReason::Lockup)
+	}
+}
+
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+pub struct FirmwareVersion {
+	pub major: u16,
+	pub minor: u16,
+	pub patch: u16,
+}
+
+impl core::fmt::Display for FirmwareVersion {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+	}
+}
+
+/// The PC only talks to firmware built for the same protocol version
+pub const fn protocol_compatible(protocol: u16) -> bool {
+	protocol == PROTOCOL_VERSION
 }
 
 #[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
@@ -116,6 +583,51 @@ pub struct Fault {
 	pub time: u64,
 }
 
+/// A fault as the battery interface keeps it in flash, so ones without the PC connected are kept
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+pub struct LoggedFault {
+	/// `time` is the battery interface's uptime, it starts over each time it boots
+	pub fault: Fault,
+	/// The last measurement before it, `None` if there wasn't one since it was set up
+	pub snapshot: Option<FaultSnapshot>,
+}
+
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+pub struct FaultSnapshot {
+	pub vbat: MilliVolt,
+	pub ibat: MilliAmp,
+}
+
+/// A discharge the battery interface runs and logs to flash on its own,
+/// so the test carries on if the PC sleeps or crashes
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+pub struct AutonomousTest {
+	/// The load is turned off and the test ends once vbat drops to this
+	pub cutoff: MilliVolt,
+	pub allow_undercurrent: AllowUndercurrent,
+	pub load_model: Option<LoadModel>,
+	pub current_setpoint: Option<MilliAmp>,
+	/// End the test this many seconds after it started even above the cutoff,
+	/// `None` to run to the cutoff
+	pub max_duration_s: Option<u32>,
+	/// Like [`ControlWord::max_voltage`]
+	pub max_voltage: Option<MilliVolt>,
+	/// Like [`ControlWord::window_samples`]
+	pub window_samples: Option<u8>,
+	pub filter: window::WindowFilter,
+}
+
+/// Measurements of an autonomous test averaged, see [`autonomous`]
+#[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
+pub struct LoggedSample {
+	/// ms from the start of the test to the first measurement averaged
+	pub sample_start_ms: u32,
+	/// ms from the start of the first measurement averaged to the end of the last
+	pub sample_duration_ms: u32,
+	pub vbat: MilliVolt,
+	pub ibat: MilliAmp,
+}
+
 #[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
 pub enum FaultKind {
 	/// Some I2C fault
@@ -125,6 +637,18 @@ pub enum FaultKind {
 	/// Battery not detected,
 	NoBattery,
 	Overcurrent,
+	/// INA260 power register doesn't agree with its voltage and current registers
+	SensorIntegrity,
+	/// SHT4x read above the temperature limit
+	OverTemperature,
+	/// The heater's thermistor read above its temperature limit
+	LoadOverTemperature,
+	/// The heater's thermistor read open after it was found, it can't be watched
+	LoadThermistor,
+	/// vbat is over the limit, the wrong battery or one still on its charger
+	Overvoltage,
+	/// Current kept flowing with the heater set to off, its MOSFET or servo path failed
+	LoadStuckOn,
 }
 
 #[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
@@ -132,7 +656,24 @@ pub enum I2CError {
 	InaVinCurrent(TiwmError),
 	InaVinVoltage(TiwmError),
 	InaVinConfig(TiwmError),
+	/// The configuration read back differently to what was written
+	InaVinConfigMismatch,
 	InaVinId(TiwmError),
+	/// The manufacturer or chip ID isn't an INA260's
+	InaVinNotIna260,
+	InaVinPower(TiwmError),
+	/// Reading the conversion ready flag
+	InaVinMaskEnable(TiwmError),
+	InaLoadCurrent(TiwmError),
+	InaLoadVoltage(TiwmError),
+	InaLoadId(TiwmError),
+	/// The load side INA260's manufacturer or chip ID isn't an INA260's
+	InaLoadNotIna260,
+	/// No conversion finished in several conversion times
+	InaVinStalled,
+	Sht4xMeasure(TiwmError),
+	/// SHT4x reply failed its checksum
+	Sht4xCrc,
 }
 
 #[derive(Debug, PartialEq, Eq, MaxSize, Format, Clone, Copy, Deserialize, Serialize)]
@@ -162,4 +703,157 @@ mod tests {
 	fn test_max_command_size() {
 		assert!(COMMAND_MAX_SIZE <= u8::MAX as usize);
 	}
+
+	#[test]
+	fn test_parse_baud() {
+		assert_eq!(parse_baud("230400"), Some(DEFAULT_BAUD));
+		assert_eq!(parse_baud("1000000"), Some(1_000_000));
+		assert_eq!(parse_baud("230401"), None);
+		assert_eq!(parse_baud("115_200"), None);
+		assert_eq!(parse_baud(""), None);
+		assert_eq!(parse_baud("99999999999"), None);
+	}
+
+	#[test]
+	fn test_load_model_current_range() {
+		let model = LoadModel::default();
+		assert_eq!(
+			model.current_range(MilliVolt::new(12_000)),
+			(MilliAmp::new(8_203), MilliAmp::new(8_603))
+		);
+		let model = LoadModel {
+			milliohms: 6_000,
+			offset: MilliAmp::new(0),
+			max_deviation: MilliAmp::new(100),
+		};
+		assert_eq!(
+			model.current_range(MilliVolt::new(12_000)),
+			(MilliAmp::new(1_900), MilliAmp::new(2_100))
+		);
+	}
+
+	#[test]
+	fn test_load_model_offset() {
+		let model = LoadModel {
+			milliohms: 1_500,
+			offset: MilliAmp::new(150),
+			max_deviation: MilliAmp::new(200),
+		};
+		assert_eq!(
+			model.expected_current(MilliVolt::new(12_000)),
+			MilliAmp::new(8_150)
+		);
+		// a flat battery still draws the offset
+		assert_eq!(
+			model.expected_current(MilliVolt::new(0)),
+			MilliAmp::new(150)
+		);
+		let below = LoadModel {
+			offset: MilliAmp::new(-300),
+			..model
+		};
+		assert_eq!(
+			below.current_range(MilliVolt::new(0)),
+			(MilliAmp::new(-500), MilliAmp::new(-100))
+		);
+	}
+
+	#[test]
+	fn test_load_model_limits() {
+		// a short, and no resistance at all, can't overflow
+		let short = LoadModel {
+			milliohms: 1,
+			offset: MilliAmp::new(i16::MAX),
+			max_deviation: MilliAmp::new(i16::MAX),
+		};
+		assert_eq!(
+			short.expected_current(MilliVolt::new(u16::MAX)),
+			MilliAmp::new(i16::MAX)
+		);
+		assert_eq!(
+			LoadModel {
+				milliohms: 0,
+				..short
+			}
+			.current_range(MilliVolt::new(12_000)),
+			(MilliAmp::new(0), MilliAmp::new(i16::MAX))
+		);
+		let open = LoadModel {
+			milliohms: u32::MAX,
+			offset: MilliAmp::new(i16::MIN),
+			max_deviation: MilliAmp::new(1),
+		};
+		assert_eq!(
+			open.current_range(MilliVolt::new(12_000)),
+			(MilliAmp::new(i16::MIN), MilliAmp::new(i16::MIN + 1))
+		);
+	}
+
+	#[test]
+	fn test_self_test_passed() {
+		let pulse = LoadPulse {
+			vbat: MilliVolt::new(12_000),
+			ibat: MilliAmp::new(8_400),
+			expected_min: MilliAmp::new(8_203),
+			expected_max: MilliAmp::new(8_603),
+		};
+		let report = SelfTestReport {
+			ina_vin: Ok(()),
+			ina_load: None,
+			load: Some(Ok(pulse)),
+		};
+		assert!(report.passed());
+		// not pulsed with the load already on
+		assert!(
+			SelfTestReport {
+				load: None,
+				..report
+			}
+			.passed()
+		);
+		let open_load = LoadPulse {
+			ibat: MilliAmp::new(0),
+			..pulse
+		};
+		assert!(
+			!SelfTestReport {
+				load: Some(Ok(open_load)),
+				..report
+			}
+			.passed()
+		);
+		let missing = Some(Err(I2CError::InaLoadId(TiwmError::AddressNack)));
+		assert!(
+			!SelfTestReport {
+				ina_load: missing,
+				..report
+			}
+			.passed()
+		);
+	}
+
+	#[test]
+	fn test_trim() {
+		let trim = Trim::default();
+		assert_eq!(trim.milliamps(-2_000), MilliAmp::new(-2_000));
+		assert_eq!(trim.millivolts(12_000), MilliVolt::new(12_000));
+		let trim = Trim {
+			milliamps_ppm: 1_010_000,
+			milliamps_offset: -15,
+			millivolts_ppm: 998_000,
+			millivolts_offset: 5,
+			..Trim::default()
+		};
+		assert_eq!(trim.milliamps(8_000), MilliAmp::new(8_065));
+		assert_eq!(trim.millivolts(12_000), MilliVolt::new(11_981));
+		assert_eq!(trim.milliwatts(96_000), MilliWatt::new(96_766));
+		// clamped to what fits
+		assert_eq!(trim.milliamps(40_000), MilliAmp::new(i16::MAX));
+		assert_eq!(trim.millivolts(0), MilliVolt::new(5));
+		let trim = Trim {
+			millivolts_offset: -5,
+			..Trim::default()
+		};
+		assert_eq!(trim.millivolts(0), MilliVolt::new(0));
+	}
 }
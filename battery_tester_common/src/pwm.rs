@@ -0,0 +1,149 @@
+//! The heater load's servo rate PWM and the current checks on it, apart from the hardware
+//! so they run on the host too.
+//!
+//! The pulse width is in µs of a 20 ms period, from [`PWM_ZERO_OUTPUT`] for off
+//! to [`PWM_MAX_OUTPUT`] for full on.
+
+use defmt::Format;
+
+use crate::{LoadModel, MilliAmp, MilliVolt};
+
+const PWM_CLOCK_HZ: f64 = 1_000_000.0;
+const PWM_CLOCK_PERIOD: f64 = 1.0 / PWM_CLOCK_HZ;
+const SERVO_HZ: f64 = 50.0;
+const SERVO_PERIOD: f64 = 1.0 / SERVO_HZ;
+pub const PWM_MAX_DUTY: u16 = (SERVO_PERIOD / PWM_CLOCK_PERIOD) as u16; // 20,000 = 20 ms
+/// this is 1 / (13 + 1/3) of 20 milliseconds (1.5 millis aka 1500 micros)
+pub const PWM_ZERO_OUTPUT: u16 = (PWM_MAX_DUTY as f64 / (13.0 + (1.0 / 3.0))) as u16;
+pub const PWM_MAX_OUTPUT: u16 = PWM_MAX_DUTY / 10;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Range {
+	Hi,
+	Lo,
+	Ok,
+}
+
+pub fn in_range_inclusive<V>(max: V, min: V, x: V) -> Range
+where
+	V: Copy + Ord,
+{
+	if x > max {
+		Range::Hi
+	} else if x < min {
+		Range::Lo
+	} else {
+		Range::Ok
+	}
+}
+
+/// The load model is set by the PC, see [`LoadModel`]
+pub fn current_in_range(load_model: &LoadModel, vbat: MilliVolt, ibat: MilliAmp) -> Range {
+	let (min, max) = load_model.current_range(vbat);
+	in_range_inclusive(max, min, ibat)
+}
+
+/// Pulse width for the load `pwm_on_percent` on, over 100 is full on
+pub fn percent_to_micros(pwm_on_percent: u8) -> u16 {
+	let pwm_on_percent = pwm_on_percent.min(100) as u16;
+
+	const MULTIPLYER: u16 = (PWM_MAX_OUTPUT - PWM_ZERO_OUTPUT) / 100;
+
+	PWM_ZERO_OUTPUT + pwm_on_percent * MULTIPLYER
+}
+
+/// `trim_us` is measured with a scope, see [`crate::Trim::pwm_us`]
+pub fn pwm_output_trim(setpoint: u16, trim_us: u16) -> u16 {
+	setpoint.saturating_add(trim_us).min(PWM_MAX_DUTY)
+}
+
+/// From [`PWM_ZERO_OUTPUT`] to [`PWM_MAX_OUTPUT`] over `ramp_ms`
+pub fn ramp_duty(elapsed_ms: u64, ramp_ms: u64) -> u16 {
+	if elapsed_ms >= ramp_ms {
+		return PWM_MAX_OUTPUT;
+	}
+	let span = (PWM_MAX_OUTPUT - PWM_ZERO_OUTPUT) as u64;
+	PWM_ZERO_OUTPUT + (span * elapsed_ms / ramp_ms) as u16
+}
+
+/// mA off the setpoint for each µs the constant current loop steps the pulse width.
+/// The default load draws roughly 17 mA more per µs, so each sample closes about half the gap.
+const REGULATE_MA_PER_US: i32 = 32;
+
+/// One integral step of the constant current loop
+pub fn regulate(duty: u16, setpoint: MilliAmp, milliamps: MilliAmp) -> u16 {
+	let error = i16::from(setpoint) as i32 - i16::from(milliamps) as i32;
+	let duty = duty as i32 + error / REGULATE_MA_PER_US;
+	duty.clamp(PWM_ZERO_OUTPUT as i32, PWM_MAX_OUTPUT as i32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_servo_pulse_widths() {
+		assert_eq!(PWM_MAX_DUTY, 20_000);
+		assert_eq!(PWM_ZERO_OUTPUT, 1_500);
+		assert_eq!(PWM_MAX_OUTPUT, 2_000);
+	}
+
+	#[test]
+	fn test_range_is_inclusive() {
+		assert_eq!(in_range_inclusive(10, 5, 5), Range::Ok);
+		assert_eq!(in_range_inclusive(10, 5, 10), Range::Ok);
+		assert_eq!(in_range_inclusive(10, 5, 4), Range::Lo);
+		assert_eq!(in_range_inclusive(10, 5, 11), Range::Hi);
+	}
+
+	#[test]
+	fn test_current_in_range_edges() {
+		// 8_203..=8_603 mA at 12 V
+		let model = LoadModel::default();
+		let vbat = MilliVolt::new(12_000);
+		let range = |milliamps| current_in_range(&model, vbat, MilliAmp::new(milliamps));
+		assert_eq!(range(8_202), Range::Lo);
+		assert_eq!(range(8_203), Range::Ok);
+		assert_eq!(range(8_603), Range::Ok);
+		assert_eq!(range(8_604), Range::Hi);
+	}
+
+	#[test]
+	fn test_percent_to_micros_clamps() {
+		assert_eq!(percent_to_micros(0), PWM_ZERO_OUTPUT);
+		assert_eq!(percent_to_micros(50), 1_750);
+		assert_eq!(percent_to_micros(100), PWM_MAX_OUTPUT);
+		assert_eq!(percent_to_micros(u8::MAX), PWM_MAX_OUTPUT);
+	}
+
+	#[test]
+	fn test_trim_stays_in_the_period() {
+		assert_eq!(pwm_output_trim(PWM_MAX_OUTPUT, 16), 2_016);
+		assert_eq!(pwm_output_trim(PWM_MAX_DUTY, 16), PWM_MAX_DUTY);
+		assert_eq!(pwm_output_trim(u16::MAX, u16::MAX), PWM_MAX_DUTY);
+	}
+
+	#[test]
+	fn test_ramp() {
+		assert_eq!(ramp_duty(0, 1_000), PWM_ZERO_OUTPUT);
+		assert_eq!(ramp_duty(500, 1_000), 1_750);
+		assert_eq!(ramp_duty(1_000, 1_000), PWM_MAX_OUTPUT);
+		// no ramp is straight to full
+		assert_eq!(ramp_duty(0, 0), PWM_MAX_OUTPUT);
+	}
+
+	#[test]
+	fn test_regulate_clamps() {
+		let setpoint = MilliAmp::new(4_000);
+		assert_eq!(regulate(1_800, setpoint, MilliAmp::new(3_680)), 1_810);
+		assert_eq!(regulate(1_800, setpoint, MilliAmp::new(4_320)), 1_790);
+		assert_eq!(
+			regulate(PWM_MAX_OUTPUT, setpoint, MilliAmp::new(0)),
+			PWM_MAX_OUTPUT
+		);
+		assert_eq!(
+			regulate(PWM_ZERO_OUTPUT, MilliAmp::new(0), MilliAmp::new(i16::MAX)),
+			PWM_ZERO_OUTPUT
+		);
+	}
+}
@@ -0,0 +1,470 @@
+//! Averaging DAQ samples into the windows reported as [`Measurement`](crate::Measurement)s.
+//!
+//! A window starts when its first sample is taken and ends when its last one is,
+//! so every sample averaged into it was taken within `start_ms..=start_ms + duration_ms`.
+//! The next window starts with the next sample, the time between two samples
+//! isn't part of either window.
+//!
+//! The PC picks how many samples go into each, fewer for finer-grained data and more
+//! for slow trend tests, [`WINDOW_SAMPLES`] unless it says otherwise.
+//! It picks a [`WindowFilter`] too, for whether a single spike moves the average.
+
+use defmt::Format;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+use crate::{LoadChannel, MilliAmp, MilliVolt, MilliWatt};
+
+/// Samples averaged into each window unless the PC picks another size
+pub const WINDOW_SAMPLES: u32 = 10;
+/// Most samples a window can take, about 10 s of them
+pub const MAX_WINDOW_SAMPLES: u32 = 100;
+
+/// How a window's samples are combined
+#[derive(Debug, Default, PartialEq, Eq, MaxSize, Format, Clone, Copy, Serialize, Deserialize)]
+pub enum WindowFilter {
+	#[default]
+	Mean,
+	/// The mean without each channel's highest and lowest sample, so a single spike from
+	/// contact bounce or PWM switching doesn't land in it. A window under 3 samples is
+	/// just averaged.
+	TrimmedMean,
+}
+
+/// Average of a full window of samples
+#[derive(Debug, PartialEq, Eq, Format, Clone, Copy)]
+pub struct Window {
+	pub millivolts: MilliVolt,
+	pub milliamps: MilliAmp,
+	pub milliwatts: MilliWatt,
+	/// When the first sample was taken, ms since boot
+	pub start_ms: u64,
+	/// From the first sample to the last
+	pub duration_ms: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SampleWindow {
+	/// Samples in a full window
+	len: u32,
+	filter: WindowFilter,
+	samples: u32,
+	start_ms: u64,
+	millivolts: Channel,
+	milliamps: Channel,
+	milliwatts: Channel,
+}
+
+impl Default for SampleWindow {
+	fn default() -> Self {
+		Self {
+			len: WINDOW_SAMPLES,
+			filter: WindowFilter::default(),
+			samples: 0,
+			start_ms: 0,
+			millivolts: Channel::default(),
+			milliamps: Channel::default(),
+			milliwatts: Channel::default(),
+		}
+	}
+}
+
+impl SampleWindow {
+	/// Throw away a partial window, the next sample starts a new one
+	pub fn reset(&mut self) {
+		*self = Self {
+			len: self.len,
+			filter: self.filter,
+			..Self::default()
+		};
+	}
+
+	/// From the next window on
+	pub fn set_filter(&mut self, filter: WindowFilter) {
+		self.filter = filter;
+	}
+
+	/// Samples in each window from now on, `1..=`[`MAX_WINDOW_SAMPLES`].
+	/// A partial window already that long ends with its next sample.
+	pub fn set_len(&mut self, len: u32) {
+		self.len = len.clamp(1, MAX_WINDOW_SAMPLES);
+	}
+
+	/// Add a sample taken at `now_ms`, returns the window once it's full
+	pub fn push(
+		&mut self,
+		millivolts: MilliVolt,
+		milliamps: MilliAmp,
+		milliwatts: MilliWatt,
+		now_ms: u64,
+	) -> Option<Window> {
+		if self.samples == 0 {
+			self.start_ms = now_ms;
+		}
+		let first = self.samples == 0;
+		self.millivolts
+			.push(i64::from(u16::from(millivolts)), first);
+		self.milliamps.push(i64::from(i16::from(milliamps)), first);
+		self.milliwatts
+			.push(i64::from(milliwatts.into_inner()), first);
+		self.samples += 1;
+		if self.samples < self.len {
+			return None;
+		}
+		let trimmed = self.filter == WindowFilter::TrimmedMean && self.samples >= 3;
+		let samples = self.samples;
+		let window = Window {
+			// a mean is within its samples' range, these fit back
+			millivolts: MilliVolt::new(self.millivolts.mean(samples, trimmed) as u16),
+			milliamps: MilliAmp::new(self.milliamps.mean(samples, trimmed) as i16),
+			milliwatts: MilliWatt::new(self.milliwatts.mean(samples, trimmed) as u32),
+			start_ms: self.start_ms,
+			duration_ms: now_ms - self.start_ms,
+		};
+		self.reset();
+		Some(window)
+	}
+}
+
+/// The sum of one channel's samples, with the extremes for [`WindowFilter::TrimmedMean`]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+struct Channel {
+	sum: i64,
+	min: i64,
+	max: i64,
+}
+
+impl Channel {
+	fn push(&mut self, sample: i64, first: bool) {
+		if first {
+			self.min = sample;
+			self.max = sample;
+		}
+		self.min = self.min.min(sample);
+		self.max = self.max.max(sample);
+		self.sum += sample;
+	}
+
+	fn mean(&self, samples: u32, trimmed: bool) -> i64 {
+		let samples = i64::from(samples);
+		if trimmed {
+			(self.sum - self.min - self.max) / (samples - 2)
+		} else {
+			self.sum / samples
+		}
+	}
+}
+
+/// Timestamps samples for a [`SampleWindow`], the load channel's over the same window
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct DaqDataQueue {
+	window: SampleWindow,
+	load: SampleWindow,
+}
+
+impl DaqDataQueue {
+	pub fn reset(&mut self) {
+		self.window.reset();
+		self.load.reset();
+	}
+
+	/// Samples averaged into each measurement, see [`SampleWindow::set_len`]
+	pub fn set_window_samples(&mut self, samples: u32) {
+		self.window.set_len(samples);
+		self.load.set_len(samples);
+	}
+
+	pub fn set_filter(&mut self, filter: WindowFilter) {
+		self.window.set_filter(filter);
+		self.load.set_filter(filter);
+	}
+
+	/// `load` is `None` without the second INA260. A window the load channel missed
+	/// a sample of is reported without it, the next one lines up again.
+	pub fn push(
+		&mut self,
+		vin_milliamps: MilliAmp,
+		vin_millivolts: MilliVolt,
+		vin_milliwatts: MilliWatt,
+		load: Option<LoadChannel>,
+		now_ms: u64,
+	) -> Option<(Window, Option<LoadChannel>)> {
+		// the load's power isn't reported
+		let load = match load {
+			Some(load) => {
+				self.load
+					.push(load.millivolts, load.milliamps, MilliWatt::new(0), now_ms)
+			}
+			None => {
+				self.load.reset();
+				None
+			}
+		};
+		let window = self
+			.window
+			.push(vin_millivolts, vin_milliamps, vin_milliwatts, now_ms)?;
+		// started partway through this window, the next starts with the next sample
+		if load.is_none() {
+			self.load.reset();
+		}
+		Some((
+			window,
+			load.map(|load| LoadChannel {
+				millivolts: load.millivolts,
+				milliamps: load.milliamps,
+			}),
+		))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Push a full window of the same reading, `interval_ms` apart from `start_ms`
+	fn fill(
+		window: &mut SampleWindow,
+		millivolts: u16,
+		milliamps: i16,
+		start_ms: u64,
+		interval_ms: u64,
+	) -> Option<Window> {
+		(0..WINDOW_SAMPLES as u64).find_map(|i| {
+			window.push(
+				MilliVolt::new(millivolts),
+				MilliAmp::new(milliamps),
+				MilliWatt::new(u32::from(millivolts) * milliamps.unsigned_abs() as u32 / 1000),
+				start_ms + i * interval_ms,
+			)
+		})
+	}
+
+	#[test]
+	fn test_window_covers_first_to_last_sample() {
+		let mut window = SampleWindow::default();
+		let full = fill(&mut window, 12_000, 8_000, 1_000, 100).unwrap();
+		assert_eq!(full.start_ms, 1_000);
+		assert_eq!(full.duration_ms, 900);
+	}
+
+	#[test]
+	fn test_only_full_windows_are_reported() {
+		let mut window = SampleWindow::default();
+		for i in 0..WINDOW_SAMPLES as u64 - 1 {
+			assert_eq!(
+				window.push(
+					MilliVolt::new(12_000),
+					MilliAmp::new(8_000),
+					MilliWatt::new(96_000),
+					i * 100
+				),
+				None
+			);
+		}
+		assert!(
+			window
+				.push(
+					MilliVolt::new(12_000),
+					MilliAmp::new(8_000),
+					MilliWatt::new(96_000),
+					900
+				)
+				.is_some()
+		);
+	}
+
+	#[test]
+	fn test_next_window_starts_at_its_first_sample() {
+		let mut window = SampleWindow::default();
+		fill(&mut window, 12_000, 8_000, 0, 100).unwrap();
+		let second = fill(&mut window, 12_000, 8_000, 1_000, 100).unwrap();
+		assert_eq!(second.start_ms, 1_000);
+		assert_eq!(second.duration_ms, 900);
+	}
+
+	#[test]
+	fn test_averages() {
+		let mut window = SampleWindow::default();
+		let mut full = None;
+		for i in 0..WINDOW_SAMPLES {
+			// 11_000..=11_900 mV and -500..=400 mA
+			full = window.push(
+				MilliVolt::new(11_000 + i as u16 * 100),
+				MilliAmp::new(-500 + i as i16 * 100),
+				MilliWatt::new(i * 1_000),
+				i as u64 * 100,
+			);
+		}
+		let full = full.unwrap();
+		assert_eq!(full.millivolts, MilliVolt::new(11_450));
+		assert_eq!(full.milliamps, MilliAmp::new(-50));
+		assert_eq!(full.milliwatts, MilliWatt::new(4_500));
+	}
+
+	#[test]
+	fn test_reset_drops_partial_window() {
+		let mut window = SampleWindow::default();
+		window.push(MilliVolt::new(1), MilliAmp::new(1), MilliWatt::new(1), 0);
+		window.reset();
+		let full = fill(&mut window, 12_000, 8_000, 5_000, 100).unwrap();
+		assert_eq!(full.start_ms, 5_000);
+		assert_eq!(full.millivolts, MilliVolt::new(12_000));
+	}
+
+	#[test]
+	fn test_window_size() {
+		let mut window = SampleWindow::default();
+		let push = |window: &mut SampleWindow, now_ms| {
+			window.push(
+				MilliVolt::new(12_000),
+				MilliAmp::new(8_000),
+				MilliWatt::new(96_000),
+				now_ms,
+			)
+		};
+		window.set_len(2);
+		assert_eq!(push(&mut window, 0), None);
+		assert_eq!(push(&mut window, 100).unwrap().duration_ms, 100);
+		// a reset keeps the size
+		window.reset();
+		assert_eq!(push(&mut window, 200), None);
+		assert!(push(&mut window, 300).is_some());
+
+		// shorter than the partial window so far, it ends with the next sample
+		window.set_len(5);
+		for now_ms in [400, 500, 600] {
+			assert_eq!(push(&mut window, now_ms), None);
+		}
+		window.set_len(3);
+		let full = push(&mut window, 700).unwrap();
+		assert_eq!(full.start_ms, 400);
+		assert_eq!(full.millivolts, MilliVolt::new(12_000));
+
+		window.set_len(0);
+		assert!(push(&mut window, 800).is_some());
+		window.set_len(u32::MAX);
+		let longest = (0..u64::from(MAX_WINDOW_SAMPLES))
+			.filter_map(|i| push(&mut window, 1_000 + i))
+			.count();
+		assert_eq!(longest, 1);
+	}
+
+	#[test]
+	fn test_trimmed_mean_drops_a_spike() {
+		let mut window = SampleWindow::default();
+		window.set_filter(WindowFilter::TrimmedMean);
+		let full = (0..WINDOW_SAMPLES as u64)
+			.find_map(|i| {
+				// contact bounce on one sample
+				let (millivolts, milliamps) = if i == 3 {
+					(9_000, 12_000)
+				} else {
+					(12_000, 8_000)
+				};
+				window.push(
+					MilliVolt::new(millivolts),
+					MilliAmp::new(milliamps),
+					MilliWatt::new(millivolts as u32 * milliamps as u32 / 1000),
+					i * 100,
+				)
+			})
+			.unwrap();
+		assert_eq!(full.millivolts, MilliVolt::new(12_000));
+		assert_eq!(full.milliamps, MilliAmp::new(8_000));
+		assert_eq!(full.milliwatts, MilliWatt::new(96_000));
+
+		// too few to trim
+		window.set_len(2);
+		window.push(
+			MilliVolt::new(12_000),
+			MilliAmp::new(0),
+			MilliWatt::new(0),
+			0,
+		);
+		let short = window
+			.push(
+				MilliVolt::new(11_000),
+				MilliAmp::new(0),
+				MilliWatt::new(0),
+				100,
+			)
+			.unwrap();
+		assert_eq!(short.millivolts, MilliVolt::new(11_500));
+	}
+
+	/// One sample into the queue at `now_ms`, the load channel at half the battery's current
+	fn push_daq(
+		queue: &mut DaqDataQueue,
+		with_load: bool,
+		now_ms: u64,
+	) -> Option<(Window, Option<LoadChannel>)> {
+		let load = with_load.then_some(LoadChannel {
+			millivolts: MilliVolt::new(11_900),
+			milliamps: MilliAmp::new(4_000),
+		});
+		queue.push(
+			MilliAmp::new(8_000),
+			MilliVolt::new(12_000),
+			MilliWatt::new(96_000),
+			load,
+			now_ms,
+		)
+	}
+
+	#[test]
+	fn test_daq_queue_fills_on_the_last_sample() {
+		let mut queue = DaqDataQueue::default();
+		for i in 0..WINDOW_SAMPLES as u64 - 1 {
+			assert_eq!(push_daq(&mut queue, true, i * 100), None);
+		}
+		let (window, load) = push_daq(&mut queue, true, 900).unwrap();
+		assert_eq!(window.duration_ms, 900);
+		assert_eq!(
+			load,
+			Some(LoadChannel {
+				millivolts: MilliVolt::new(11_900),
+				milliamps: MilliAmp::new(4_000),
+			})
+		);
+		// and starts again with the next
+		assert_eq!(push_daq(&mut queue, true, 1_000), None);
+	}
+
+	#[test]
+	fn test_daq_queue_load_realigns_after_a_miss() {
+		let mut queue = DaqDataQueue::default();
+		let mut now_ms = 0;
+		let mut window = |queue: &mut DaqDataQueue, missed: Option<u64>| {
+			(0..WINDOW_SAMPLES as u64)
+				.filter_map(|i| {
+					now_ms += 100;
+					push_daq(queue, missed != Some(i), now_ms)
+				})
+				.last()
+				.unwrap()
+		};
+		let (_, load) = window(&mut queue, Some(4));
+		assert_eq!(load, None);
+		// the load channel's window lines up with the battery's again
+		let (_, load) = window(&mut queue, None);
+		assert!(load.is_some());
+		let (_, load) = window(&mut queue, Some(0));
+		assert_eq!(load, None);
+		let (_, load) = window(&mut queue, None);
+		assert!(load.is_some());
+	}
+
+	#[test]
+	fn test_daq_queue_reset() {
+		let mut queue = DaqDataQueue::default();
+		for i in 0..5 {
+			push_daq(&mut queue, true, i * 100);
+		}
+		queue.reset();
+		let (window, load) = (0..WINDOW_SAMPLES as u64)
+			.find_map(|i| push_daq(&mut queue, true, 2_000 + i * 100))
+			.unwrap();
+		assert_eq!(window.start_ms, 2_000);
+		assert!(load.is_some());
+	}
+}
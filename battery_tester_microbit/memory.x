@@ -1,6 +1,9 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x00000000, LENGTH = 512K
+  /* the last 4K page holds the settings, see settings.rs,
+     the one before it the fault log, see fault_log.rs,
+     the 16 before that the autonomous log, see autonomous.rs */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 440K
   RAM : ORIGIN = 0x20000000, LENGTH = 128K
 }
@@ -0,0 +1,121 @@
+//! A test the battery interface runs without the PC, logged to the flash pages
+//! before the fault log so it survives the PC sleeping or crashing and is read afterward.
+//!
+//! Each sample is a frame in its own slot, written in order from the first.
+//! An erased slot ends the log, so does a half written one from losing power mid-write.
+
+use battery_tester_common::{
+	AutonomousTest, FlashError, LoggedSample, Measurement, autonomous::SampleAverage, frame,
+};
+use defmt::error;
+use embassy_nrf::nvmc::{FLASH_SIZE, Nvmc, PAGE_SIZE};
+use embassy_time::{Duration, Instant};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use postcard::experimental::max_size::MaxSize;
+
+/// 64K, memory.x leaves them out of the program's flash
+const LOG_PAGES: usize = 16;
+/// Below the fault log and settings pages
+const LOG_OFFSET: u32 = (FLASH_SIZE - (2 + LOG_PAGES) * PAGE_SIZE) as u32;
+/// Flash is written a word at a time
+const SLOT_SIZE: usize = frame::max_frame_size(LoggedSample::POSTCARD_MAX_SIZE).next_multiple_of(4);
+/// About 9 hours of samples
+pub const SLOTS: usize = LOG_PAGES * PAGE_SIZE / SLOT_SIZE;
+/// Erased flash, no frame starts with it since they're too short to need a 0xFF COBS code
+const ERASED: u8 = 0xFF;
+
+/// A test that's running
+pub struct Run {
+	pub test: AutonomousTest,
+	started: Instant,
+	average: SampleAverage,
+	/// Slots written, the next sample goes in the first one after them
+	logged: usize,
+}
+
+impl Run {
+	/// Starts now, the log should have just been erased
+	pub fn new(test: AutonomousTest) -> Self {
+		let started = Instant::now();
+		Self {
+			test,
+			started,
+			average: SampleAverage::new(started.as_millis()),
+			logged: 0,
+		}
+	}
+
+	/// Log `measurement` once it fills a sample, an error ends the test
+	pub fn measured(
+		&mut self,
+		flash: &mut Nvmc<'_>,
+		measurement: &Measurement,
+	) -> Result<(), FlashError> {
+		match self.average.push(measurement) {
+			Some(sample) => self.log(flash, sample),
+			None => Ok(()),
+		}
+	}
+
+	/// Log what's been averaged since the last sample, at the end of the test
+	pub fn finish(&mut self, flash: &mut Nvmc<'_>) -> Result<(), FlashError> {
+		match self.average.finish() {
+			Some(sample) => self.log(flash, sample),
+			None => Ok(()),
+		}
+	}
+
+	/// Ran for `max_duration_s` or filled the log
+	pub fn over(&self) -> bool {
+		let timed_out = self.test.max_duration_s.is_some_and(|max| {
+			Instant::now() - self.started >= Duration::from_secs(u64::from(max))
+		});
+		timed_out || self.logged == SLOTS
+	}
+
+	fn log(&mut self, flash: &mut Nvmc<'_>, sample: LoggedSample) -> Result<(), FlashError> {
+		if self.logged == SLOTS {
+			return Ok(());
+		}
+		// erased flash reads 0xFF, the rest of the slot is left that way
+		let mut stored = [ERASED; SLOT_SIZE];
+		// the buffer always fits the largest possible frame
+		frame::encode(&sample, &mut stored).unwrap();
+		flash
+			.write(LOG_OFFSET + (self.logged * SLOT_SIZE) as u32, &stored)
+			.map_err(|e| {
+				error!("write autonomous log error: {}", e);
+				FlashError::Write
+			})?;
+		self.logged += 1;
+		Ok(())
+	}
+}
+
+/// Erase the log a page at a time, calling `each_page` after each.
+/// The CPU stalls about 85 ms for each page.
+pub fn erase(flash: &mut Nvmc<'_>, mut each_page: impl FnMut()) -> Result<(), FlashError> {
+	for page in 0..LOG_PAGES {
+		let from = LOG_OFFSET + (page * PAGE_SIZE) as u32;
+		flash.erase(from, from + PAGE_SIZE as u32).map_err(|e| {
+			error!("erase autonomous log error: {}", e);
+			FlashError::Erase
+		})?;
+		each_page();
+	}
+	Ok(())
+}
+
+/// The sample in `slot`, `None` past the end of the log
+pub fn read(flash: &mut Nvmc<'_>, slot: usize) -> Option<LoggedSample> {
+	if slot >= SLOTS {
+		return None;
+	}
+	let mut stored = [0u8; SLOT_SIZE];
+	let offset = LOG_OFFSET + (slot * SLOT_SIZE) as u32;
+	if flash.read(offset, &mut stored).is_err() || stored[0] == ERASED {
+		return None;
+	}
+	let len = stored.iter().position(|b| *b == frame::FRAME_DELIMITER)?;
+	frame::decode::<LoggedSample>(&mut stored[..len]).ok()
+}
@@ -0,0 +1,74 @@
+//! The battery-present input from ring 2 (P0.04), read through the
+//! [`BatteryDetect`](battery_tester_common::BatteryDetect) in use.
+//! What it last read goes out with every reply to the PC.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use battery_tester_common::Polarity;
+use embassy_nrf::gpio::Input;
+
+use crate::settings;
+
+/// Kept current by [`BatteryInput`], read by whatever builds a reply
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// What the input last read, not debounced
+pub fn present() -> bool {
+	PRESENT.load(Ordering::Relaxed)
+}
+
+pub struct BatteryInput {
+	input: Input<'static>,
+}
+
+impl BatteryInput {
+	pub fn new(input: Input<'static>) -> Self {
+		let battery = Self { input };
+		battery.is_present();
+		battery
+	}
+
+	pub fn is_present(&self) -> bool {
+		let present = match settings::battery_detect().polarity {
+			Polarity::ActiveHigh => self.input.is_high(),
+			Polarity::ActiveLow => self.input.is_low(),
+		};
+		PRESENT.store(present, Ordering::Relaxed);
+		present
+	}
+
+	/// Returns straight away if there's a battery
+	pub async fn wait_for_present(&mut self) {
+		match settings::battery_detect().polarity {
+			Polarity::ActiveHigh => self.input.wait_for_high().await,
+			Polarity::ActiveLow => self.input.wait_for_low().await,
+		}
+		self.is_present();
+	}
+
+	/// Returns straight away if there isn't a battery
+	pub async fn wait_for_absent(&mut self) {
+		match settings::battery_detect().polarity {
+			Polarity::ActiveHigh => self.input.wait_for_low().await,
+			Polarity::ActiveLow => self.input.wait_for_high().await,
+		}
+		self.is_present();
+	}
+
+	/// Until a battery is connected, one that already is has to be removed first
+	pub async fn wait_for_connect(&mut self) {
+		match settings::battery_detect().polarity {
+			Polarity::ActiveHigh => self.input.wait_for_rising_edge().await,
+			Polarity::ActiveLow => self.input.wait_for_falling_edge().await,
+		}
+		self.is_present();
+	}
+
+	/// Keeps [`present`] current while the power task waits on something else
+	pub async fn track(&mut self) -> ! {
+		loop {
+			self.input.wait_for_any_edge().await;
+			self.is_present();
+		}
+	}
+}
@@ -0,0 +1,74 @@
+//! The micro:bit v2's nRF52833 behind the `embedded-hal` traits the drivers, heater control
+//! and PC link are written against. Another board implements these for its own peripherals,
+//! see [`crate::I2cErrorToCommon`], [`crate::pwm::PwmCtrl`] and [`crate::link`].
+
+use battery_tester_common::{TiwmError, pwm::PWM_MAX_DUTY};
+use embassy_nrf::{
+	pwm::{Prescaler, SimplePwm},
+	twim,
+	uarte::{self, UarteRxWithIdle},
+};
+use embedded_hal::pwm::{ErrorType, SetDutyCycle};
+
+use crate::{I2cErrorToCommon, twim_err_to_common};
+
+impl I2cErrorToCommon for twim::Error {
+	fn to_common(&self) -> TiwmError {
+		twim_err_to_common(*self)
+	}
+}
+
+/// The heater's PWM at the servo rate, one µs per count.
+/// The output starts with the first pulse width set so there's never a stray one.
+pub struct ServoPwm {
+	pwm: SimplePwm<'static>,
+}
+
+impl ServoPwm {
+	pub fn new(pwm: SimplePwm<'static>) -> Self {
+		pwm.disable();
+		pwm.set_prescaler(Prescaler::Div16); // 1Mhz clock
+		pwm.set_max_duty(PWM_MAX_DUTY);
+		Self { pwm }
+	}
+}
+
+impl ErrorType for ServoPwm {
+	type Error = core::convert::Infallible;
+}
+
+impl SetDutyCycle for ServoPwm {
+	fn max_duty_cycle(&self) -> u16 {
+		PWM_MAX_DUTY
+	}
+
+	fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+		// SimplePwm's duty is the time the pin's low, the servo wants the high time
+		self.pwm.set_duty(0, PWM_MAX_DUTY.saturating_sub(duty));
+		if !self.pwm.is_enabled() {
+			self.pwm.enable();
+		}
+		Ok(())
+	}
+}
+
+/// Reads whatever has arrived once the line goes idle instead of a fixed length
+pub struct IdleRx {
+	rx: UarteRxWithIdle<'static>,
+}
+
+impl IdleRx {
+	pub fn new(rx: UarteRxWithIdle<'static>) -> Self {
+		Self { rx }
+	}
+}
+
+impl embedded_io_async::ErrorType for IdleRx {
+	type Error = uarte::Error;
+}
+
+impl embedded_io_async::Read for IdleRx {
+	async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+		self.rx.read_until_idle(buf).await
+	}
+}
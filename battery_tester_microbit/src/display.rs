@@ -0,0 +1,128 @@
+//! The micro:bit's 5×5 LED matrix, shows what the battery interface is doing
+//! without a PC attached
+
+use core::cell::Cell;
+
+use battery_tester_common::{FaultKind, MilliVolt};
+use embassy_nrf::gpio::Output;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Instant, Timer};
+
+use crate::pwm::HeaterCmd;
+
+/// Battery voltage shown as an empty bar, a flat 6 cell lead acid battery
+pub const BAR_EMPTY_MV: u16 = 10_500;
+/// Battery voltage shown as a full bar, a charged 6 cell lead acid battery at rest
+pub const BAR_FULL_MV: u16 = 12_700;
+/// How long each row is lit, the five rows make a 100 Hz frame
+const ROW_MS: u64 = 2;
+/// How long the load on dot stays in each column
+const LOAD_STEP_MS: u64 = 150;
+/// The idle dot is on for the first half of this
+const IDLE_BLINK_MS: u64 = 1_000;
+/// On and off time of each blink in a fault's code
+const FAULT_BLINK_MS: u64 = 250;
+/// Dark time between repeats of a fault's code
+const FAULT_PAUSE_MS: u64 = 1_500;
+
+/// One bit per column, bit 0 is the left column, row 0 is the top
+pub type Frame = [u8; 5];
+
+const CENTER_DOT: Frame = [0, 0, 0b00100, 0, 0];
+const CROSS: Frame = [0b10001, 0b01010, 0b00100, 0b01010, 0b10001];
+
+static SHOWN: Mutex<CriticalSectionRawMutex, Cell<Shown>> = Mutex::new(Cell::new(Shown::Idle));
+
+#[derive(defmt::Format, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Shown {
+	/// Waiting for the battery to be connected, blinks the center dot
+	Idle,
+	/// The top row runs a dot across while the load is on,
+	/// the bottom four are a bar from [`BAR_EMPTY_MV`] to [`BAR_FULL_MV`]
+	Measuring { vbat: MilliVolt, load: HeaterCmd },
+	/// Blinks a cross [`blink_code`] times then pauses, until the fault is cleared
+	Fault(FaultKind),
+}
+
+/// What the display task shows from its next frame on
+pub fn show(shown: Shown) {
+	SHOWN.lock(|cell| cell.set(shown));
+}
+
+/// How many times the cross blinks for each fault
+pub const fn blink_code(kind: FaultKind) -> u64 {
+	match kind {
+		FaultKind::I2C(_) => 1,
+		FaultKind::Undercurrent => 2,
+		FaultKind::NoBattery => 3,
+		FaultKind::Overcurrent => 4,
+		FaultKind::SensorIntegrity => 5,
+		FaultKind::OverTemperature => 6,
+		FaultKind::LoadOverTemperature => 7,
+		FaultKind::LoadThermistor => 8,
+		FaultKind::Overvoltage => 9,
+		FaultKind::LoadStuckOn => 10,
+	}
+}
+
+/// How many of the five columns the voltage bar lights, any charge above empty lights one
+pub fn bar_level(vbat: MilliVolt) -> u8 {
+	let above_empty = u32::from(u16::from(vbat).saturating_sub(BAR_EMPTY_MV));
+	let span = u32::from(BAR_FULL_MV - BAR_EMPTY_MV);
+	(above_empty * 5).div_ceil(span).min(5) as u8
+}
+
+pub fn frame(shown: Shown, now_ms: u64) -> Frame {
+	match shown {
+		Shown::Idle if now_ms % IDLE_BLINK_MS < IDLE_BLINK_MS / 2 => CENTER_DOT,
+		Shown::Idle => Frame::default(),
+		Shown::Measuring { vbat, load } => {
+			let bar = (1u8 << bar_level(vbat)) - 1;
+			let top = match load {
+				HeaterCmd::On => 1 << (now_ms / LOAD_STEP_MS % 5),
+				HeaterCmd::Off => 0,
+			};
+			[top, bar, bar, bar, bar]
+		}
+		Shown::Fault(kind) => {
+			let blinking_ms = blink_code(kind) * FAULT_BLINK_MS * 2;
+			let t = now_ms % (blinking_ms + FAULT_PAUSE_MS);
+			if t < blinking_ms && (t / FAULT_BLINK_MS).is_multiple_of(2) {
+				CROSS
+			} else {
+				Frame::default()
+			}
+		}
+	}
+}
+
+/// Scans the matrix a row at a time, rows are driven high and columns low to light an LED
+pub struct Matrix {
+	rows: [Output<'static>; 5],
+	cols: [Output<'static>; 5],
+}
+
+impl Matrix {
+	/// `rows` start low and `cols` high so every LED starts off
+	pub fn new(rows: [Output<'static>; 5], cols: [Output<'static>; 5]) -> Self {
+		Self { rows, cols }
+	}
+
+	pub async fn run(&mut self) -> ! {
+		loop {
+			let frame = frame(SHOWN.lock(Cell::get), Instant::now().as_millis());
+			for (row, bits) in self.rows.iter_mut().zip(frame) {
+				for (i, col) in self.cols.iter_mut().enumerate() {
+					if bits & (1 << i) == 0 {
+						col.set_high();
+					} else {
+						col.set_low();
+					}
+				}
+				row.set_high();
+				Timer::after_millis(ROW_MS).await;
+				row.set_low();
+			}
+		}
+	}
+}
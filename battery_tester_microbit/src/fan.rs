@@ -0,0 +1,58 @@
+//! A cooling fan on the load resistor, switched by a MOSFET from ring 0 (P0.02).
+//! It runs while the load is on and after until the heater has cooled,
+//! or for [`RUN_ON_MS`] without the heater's thermistor.
+
+use embassy_time::{Duration, Instant};
+use embedded_hal::digital::{OutputPin, PinState};
+
+use crate::pwm::HeaterCmd;
+
+/// Keep cooling the heater until its thermistor reads below this, 45 °C
+pub const COOL_CENTI_C: i16 = 4_500;
+/// How long the fan runs after the load turns off without a thermistor
+pub const RUN_ON_MS: u64 = 60_000;
+
+pub struct Fan<O> {
+	out: O,
+	on: bool,
+	/// When the load last turned off, `None` while it's on or once the fan's stopped
+	load_off_at: Option<Instant>,
+}
+
+impl<O: OutputPin> Fan<O> {
+	/// `out` starts low, the fan off
+	pub fn new(out: O) -> Self {
+		Self {
+			out,
+			on: false,
+			load_off_at: None,
+		}
+	}
+
+	/// Once for each measurement, `load_temp_centi_c` is `None` without a thermistor.
+	/// A fault leaves the fan as it was until measuring starts again.
+	pub fn update(&mut self, load: HeaterCmd, load_temp_centi_c: Option<i16>) {
+		let on = match (load, load_temp_centi_c) {
+			(HeaterCmd::On, _) => {
+				self.load_off_at = None;
+				true
+			}
+			(HeaterCmd::Off, Some(t)) => t > COOL_CENTI_C,
+			(HeaterCmd::Off, None) if self.is_on() => {
+				let off_at = *self.load_off_at.get_or_insert_with(Instant::now);
+				Instant::now() - off_at < Duration::from_millis(RUN_ON_MS)
+			}
+			(HeaterCmd::Off, None) => false,
+		};
+		if !on {
+			self.load_off_at = None;
+		}
+		self.on = on;
+		// a pin that can fail leaves the fan as it was, the thermistor still faults if it overheats
+		let _ = self.out.set_state(PinState::from(on));
+	}
+
+	pub fn is_on(&self) -> bool {
+		self.on
+	}
+}
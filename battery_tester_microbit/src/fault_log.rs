@@ -0,0 +1,119 @@
+//! The last faults, kept in the page of flash before the settings so the ones
+//! the PC wasn't connected for aren't lost.
+//!
+//! Each fault is a frame in its own slot, appended until the page is full.
+//! The page is then erased and the newest [`FAULT_LOG_LEN`] written back.
+//! An erased slot ends the log, a half written one fails its checksum and is skipped.
+
+use core::cell::RefCell;
+
+use battery_tester_common::{FlashError, LoggedFault, frame};
+use defmt::{error, info};
+use embassy_nrf::nvmc::{FLASH_SIZE, Nvmc, PAGE_SIZE};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use heapless::Deque;
+use postcard::experimental::max_size::MaxSize;
+
+/// How many faults are kept
+pub const FAULT_LOG_LEN: usize = 16;
+/// The page before the settings, memory.x leaves it out of the program's flash
+const LOG_OFFSET: u32 = (FLASH_SIZE - 2 * PAGE_SIZE) as u32;
+/// Flash is written a word at a time
+const SLOT_SIZE: usize = frame::max_frame_size(LoggedFault::POSTCARD_MAX_SIZE).next_multiple_of(4);
+const SLOTS: usize = PAGE_SIZE / SLOT_SIZE;
+/// Erased flash, no frame starts with it since they're too short to need a 0xFF COBS code
+const ERASED: u8 = 0xFF;
+
+/// Newest last, read for [`battery_tester_common::CommandKind::DumpFaultLog`].
+/// Until it's loaded the page counts as full so nothing is written over what's there.
+static FAULTS: Mutex<CriticalSectionRawMutex, RefCell<Log>> = Mutex::new(RefCell::new(Log {
+	faults: Deque::new(),
+	used_slots: SLOTS,
+}));
+
+struct Log {
+	faults: Deque<LoggedFault, FAULT_LOG_LEN>,
+	/// Slots written since the page was erased, the next fault goes in the first one after them
+	used_slots: usize,
+}
+
+impl Log {
+	fn push(&mut self, fault: LoggedFault) {
+		if self.faults.is_full() {
+			self.faults.pop_front();
+		}
+		// can't fail, there's room now
+		let _ = self.faults.push_back(fault);
+	}
+}
+
+/// Read the faults kept in flash
+pub fn load(flash: &mut Nvmc<'_>) {
+	let mut log = Log {
+		faults: Deque::new(),
+		used_slots: 0,
+	};
+	let mut slot = [0u8; SLOT_SIZE];
+	while log.used_slots < SLOTS {
+		let offset = LOG_OFFSET + (log.used_slots * SLOT_SIZE) as u32;
+		if flash.read(offset, &mut slot).is_err() || slot[0] == ERASED {
+			break;
+		}
+		log.used_slots += 1;
+		let saved = slot
+			.iter()
+			.position(|b| *b == frame::FRAME_DELIMITER)
+			.and_then(|len| frame::decode::<LoggedFault>(&mut slot[..len]).ok());
+		match saved {
+			Some(fault) => log.push(fault),
+			None => error!("skipped a corrupt fault log slot: {}", log.used_slots - 1),
+		}
+	}
+	info!("loaded {} logged faults", log.faults.len());
+	FAULTS.lock(|faults| faults.replace(log));
+}
+
+/// Keep `fault` in the log and flash. It's kept until the next start even if it can't be saved.
+/// The CPU stalls while the page is erased, about 85 ms, once every few dozen faults.
+pub fn record(flash: &mut Nvmc<'_>, fault: LoggedFault) -> Result<(), FlashError> {
+	FAULTS.lock(|log| {
+		let mut log = log.borrow_mut();
+		log.push(fault);
+		if log.used_slots < SLOTS {
+			let slot = log.used_slots;
+			log.used_slots += 1;
+			return write_slot(flash, slot, &fault);
+		}
+		// full, start the page over with what's kept
+		flash
+			.erase(LOG_OFFSET, LOG_OFFSET + PAGE_SIZE as u32)
+			.map_err(|e| {
+				error!("erase fault log error: {}", e);
+				FlashError::Erase
+			})?;
+		log.used_slots = log.faults.len();
+		log.faults
+			.iter()
+			.enumerate()
+			.try_for_each(|(slot, fault)| write_slot(flash, slot, fault))
+	})
+}
+
+/// Oldest first
+pub fn faults() -> Deque<LoggedFault, FAULT_LOG_LEN> {
+	FAULTS.lock(|log| log.borrow().faults.clone())
+}
+
+fn write_slot(flash: &mut Nvmc<'_>, slot: usize, fault: &LoggedFault) -> Result<(), FlashError> {
+	// erased flash reads 0xFF, the rest of the slot is left that way
+	let mut stored = [ERASED; SLOT_SIZE];
+	// the buffer always fits the largest possible frame
+	frame::encode(fault, &mut stored).unwrap();
+	flash
+		.write(LOG_OFFSET + (slot * SLOT_SIZE) as u32, &stored)
+		.map_err(|e| {
+			error!("write fault log error: {}", e);
+			FlashError::Write
+		})
+}
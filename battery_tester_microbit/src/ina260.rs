@@ -0,0 +1,33 @@
+//! The battery interface's INA260s, read with [`ina260_async`] and corrected by the device's trim
+
+use battery_tester_common::{MilliAmp, MilliVolt, MilliWatt, Trim};
+use embedded_hal_async::i2c::I2c;
+pub use ina260_async::{
+	Averaging, BVConvTime, Error, INA260Config, OperMode, SCConvTime, check_id, conversion_ready,
+	set_config,
+};
+
+/// Returns current in milliamps, corrected by `trim`
+pub async fn get_amps<I: I2c>(address: u8, i2c: &mut I, trim: &Trim) -> Result<MilliAmp, I::Error> {
+	// IN+ is on the battery side so discharge reads positive,
+	// the register's full scale is past the 15 A the part can measure, trim clamps it
+	Ok(trim.milliamps(ina260_async::read_current(address, i2c).await?))
+}
+
+/// Returns voltage as millivolts, corrected by `trim`
+pub async fn get_voltage<I: I2c>(
+	address: u8,
+	i2c: &mut I,
+	trim: &Trim,
+) -> Result<MilliVolt, I::Error> {
+	Ok(trim.millivolts(ina260_async::read_voltage(address, i2c).await?))
+}
+
+/// Returns power as milliwatts, corrected by `trim`
+pub async fn get_power<I: I2c>(
+	address: u8,
+	i2c: &mut I,
+	trim: &Trim,
+) -> Result<MilliWatt, I::Error> {
+	Ok(trim.milliwatts(ina260_async::read_power(address, i2c).await?))
+}
@@ -1,14 +1,135 @@
 #![no_std]
 
-use battery_tester_common::{MilliAmp, MilliVolt, TiwmError};
-use embassy_nrf::twim;
-use embassy_time::{Duration, Instant, Timer};
+use core::sync::atomic::{AtomicU32, Ordering};
 
+use battery_tester_common::{
+	DEFAULT_BAUD, FaultKind, FirmwareVersion, I2CError, MilliAmp, MilliVolt, MilliWatt,
+	ResetReason, TiwmError, parse_baud,
+};
+use defmt::error;
+use embassy_nrf::twim;
+use embassy_time::Timer;
+use embedded_hal_async::i2c;
+
+pub mod autonomous;
+pub mod battery;
+pub mod board;
+pub mod display;
+pub mod fan;
+pub mod fault_log;
 pub mod ina260;
+pub mod link;
+pub mod load_temp;
+pub mod offline;
 pub mod pwm;
+pub mod settings;
+pub mod sht4x;
+pub mod speaker;
+
+/// Reported to the PC in [`battery_tester_common::ReplyKind::Version`]
+pub const FIRMWARE_VERSION: FirmwareVersion = FirmwareVersion {
+	major: parse_version_part(env!("CARGO_PKG_VERSION_MAJOR")),
+	minor: parse_version_part(env!("CARGO_PKG_VERSION_MINOR")),
+	patch: parse_version_part(env!("CARGO_PKG_VERSION_PATCH")),
+};
+
+/// The UART's, [`DEFAULT_BAUD`] unless built with `BATTERY_TESTER_BAUD` set to another of
+/// [`battery_tester_common::BAUDS`]. Reported to the PC in
+/// [`battery_tester_common::ReplyKind::Version`].
+pub const BAUD: u32 = match option_env!("BATTERY_TESTER_BAUD") {
+	Some(baud) => match parse_baud(baud) {
+		Some(baud) => baud,
+		None => panic!("BATTERY_TESTER_BAUD isn't a baud the UARTE can run at"),
+	},
+	None => DEFAULT_BAUD,
+};
+
+/// The UARTE's setting for [`BAUD`]
+pub const fn uarte_baudrate() -> embassy_nrf::uarte::Baudrate {
+	use embassy_nrf::uarte::Baudrate;
+	match BAUD {
+		1_200 => Baudrate::BAUD1200,
+		2_400 => Baudrate::BAUD2400,
+		4_800 => Baudrate::BAUD4800,
+		9_600 => Baudrate::BAUD9600,
+		14_400 => Baudrate::BAUD14400,
+		19_200 => Baudrate::BAUD19200,
+		28_800 => Baudrate::BAUD28800,
+		31_250 => Baudrate::BAUD31250,
+		38_400 => Baudrate::BAUD38400,
+		56_000 => Baudrate::BAUD56000,
+		57_600 => Baudrate::BAUD57600,
+		76_800 => Baudrate::BAUD76800,
+		115_200 => Baudrate::BAUD115200,
+		230_400 => Baudrate::BAUD230400,
+		250_000 => Baudrate::BAUD250000,
+		460_800 => Baudrate::BAUD460800,
+		921_600 => Baudrate::BAUD921600,
+		1_000_000 => Baudrate::BAUD1M,
+		// checked as BAUD is parsed
+		_ => unreachable!(),
+	}
+}
 
-/// How long to wait to ensure battery connection is secure
-pub const BAT_CONNECT_DEBOUNCE_MS: u64 = 250;
+/// Reported to the PC in [`battery_tester_common::ReplyKind::Version`], set at the factory
+pub fn device_id() -> u64 {
+	let ficr = embassy_nrf::pac::FICR;
+	let low = ficr.deviceid(0).read() as u64;
+	let high = ficr.deviceid(1).read() as u64;
+	high << 32 | low
+}
+
+/// Reads why the chip last reset and clears it, the reasons add up until they're cleared
+pub fn take_reset_reason() -> ResetReason {
+	let power = embassy_nrf::pac::POWER;
+	let reasons = power.resetreas().read();
+	power.resetreas().write_value(reasons);
+	// the watchdog first, a hang is what the PC most needs to hear about
+	if reasons.dog() {
+		ResetReason::Watchdog
+	} else if reasons.lockup() {
+		ResetReason::Lockup
+	} else if reasons.sreq() {
+		ResetReason::SoftReset
+	} else if reasons.resetpin() {
+		ResetReason::Pin
+	} else if reasons.0 == 0 {
+		ResetReason::PowerOn
+	} else {
+		ResetReason::Other
+	}
+}
+
+const fn parse_version_part(part: &str) -> u16 {
+	let digits = part.as_bytes();
+	let mut value = 0;
+	let mut i = 0;
+	while i < digits.len() {
+		value = value * 10 + (digits[i] - b'0') as u16;
+		i += 1;
+	}
+	value
+}
+
+/// How long the heater takes to come up to full load, stepped each DAQ sample,
+/// so turning it on doesn't trip an overcurrent
+pub const HEATER_RAMP_MS: u64 = 500;
+/// The chip resets if the power task doesn't feed the hardware watchdog for this long,
+/// the heater's PWM stops with it so the load is left off
+pub const WATCHDOG_TIMEOUT_MS: u32 = 1_000;
+/// How often the watchdog is fed while waiting on the operator
+pub const WATCHDOG_FEED_MS: u64 = 250;
+/// Consecutive samples where the power register disagrees with V × I before faulting
+pub const POWER_MISMATCH_LIMIT: u8 = 5;
+/// Allowed difference between the power register and V × I in percent
+pub const POWER_TOLERANCE_PERCENT: u32 = 10;
+/// Allowed difference between the power register and V × I at low power,
+/// covers the 10 mW power LSB and rounding of the current and voltage
+pub const POWER_TOLERANCE_MW: u32 = 250;
+/// Fault if the SHT4x reads above this, 60 °C
+pub const OVER_TEMPERATURE_CENTI_C: i16 = 6_000;
+/// Fault if the heater's thermistor reads above this, 100 °C
+pub const LOAD_OVER_TEMPERATURE_CENTI_C: i16 = 10_000;
 
 #[derive(Copy, Clone, Default)]
 pub struct EmbassyDelayer;
@@ -48,72 +169,85 @@ pub const fn twim_err_to_common(twim_err: twim::Error) -> TiwmError {
 	}
 }
 
-fn milliamp_to_u32(milliamp: &MilliAmp) -> u32 {
-	u16::from(*milliamp) as u32
+/// How a board's I2C errors are reported to the PC,
+/// a board can say more than [`i2c::ErrorKind`] does, see [`twim_err_to_common`]
+pub trait I2cErrorToCommon: i2c::Error {
+	fn to_common(&self) -> TiwmError {
+		match self.kind() {
+			i2c::ErrorKind::NoAcknowledge(i2c::NoAcknowledgeSource::Data) => TiwmError::DataNack,
+			i2c::ErrorKind::NoAcknowledge(_) => TiwmError::AddressNack,
+			i2c::ErrorKind::Overrun => TiwmError::Overrun,
+			_ => TiwmError::Unknown,
+		}
+	}
 }
 
-fn millivolt_to_u32(millivolt: &MilliVolt) -> u32 {
-	u16::from(*millivolt) as u32
+/// `bus` is the variant for an I2C error during the call that failed
+pub fn ina260_err_to_common<E: I2cErrorToCommon>(
+	ina260_err: ina260::Error<E>,
+	bus: fn(TiwmError) -> I2CError,
+) -> I2CError {
+	match ina260_err {
+		ina260::Error::I2C(e) => bus(e.to_common()),
+		ina260::Error::ConfigMismatch { .. } => I2CError::InaVinConfigMismatch,
+		ina260::Error::NotIna260 { .. } => I2CError::InaVinNotIna260,
+	}
 }
 
-pub struct DaqDataQueue {
-	index: usize,
-	start: Instant,
-	milliamps: [MilliAmp; 10],
-	millivolts: [MilliVolt; 10],
+pub fn sht4x_err_to_common<E: I2cErrorToCommon>(sht4x_err: sht4x::Error<E>) -> I2CError {
+	match sht4x_err {
+		sht4x::Error::I2C(e) => I2CError::Sht4xMeasure(e.to_common()),
+		sht4x::Error::Crc => I2CError::Sht4xCrc,
+	}
 }
 
-impl DaqDataQueue {
-	pub fn reset(&mut self) {
-		self.index = 0;
-		self.start = Instant::now();
-		self.milliamps = [MilliAmp::default(); 10];
-		self.millivolts = [MilliVolt::default(); 10];
-	}
+fn milliamp_to_i32(milliamp: &MilliAmp) -> i32 {
+	i16::from(*milliamp) as i32
+}
 
-	pub fn default() -> Self {
-		Self {
-			index: 0,
-			start: Instant::now(),
-			milliamps: [MilliAmp::new(0u16); 10],
-			millivolts: [MilliVolt::new(0u16); 10],
-		}
-	}
+fn millivolt_to_u32(millivolt: &MilliVolt) -> u32 {
+	u16::from(*millivolt) as u32
+}
 
-	pub fn avg_milliamps(&self) -> MilliAmp {
-		let sum: u32 = self.milliamps.iter().map(milliamp_to_u32).sum();
-		MilliAmp::new((sum / 10) as u16)
-	}
+static SAMPLE_INDEX: AtomicU32 = AtomicU32::new(0);
 
-	pub fn avg_millivolts(&self) -> MilliVolt {
-		let sum: u32 = self.millivolts.iter().map(millivolt_to_u32).sum();
-		MilliVolt::new((sum / 10) as u16)
-	}
+/// For the next measurement, counting from 0 at boot so the PC sees when some are dropped
+pub fn next_sample_index() -> u32 {
+	SAMPLE_INDEX.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Cross checks the INA260 power register against its current and voltage registers.
+/// The registers update at slightly different times so only a mismatch lasting
+/// [`POWER_MISMATCH_LIMIT`] samples counts as a fault.
+#[derive(Default)]
+pub struct PowerCheck {
+	mismatches: u8,
+}
 
-	pub fn push(
+impl PowerCheck {
+	pub fn check(
 		&mut self,
-		vin_milliamps: MilliAmp,
-		vin_millivolts: MilliVolt,
-	) -> Option<(MilliVolt, MilliAmp, Instant, Duration)> {
-		self.milliamps[self.index] = vin_milliamps;
-		self.millivolts[self.index] = vin_millivolts;
-		if self.index == 9 {
-			let now = Instant::now();
-			let duration = now - self.start;
-			self.index = 0;
-			self.start = now;
-			Some((
-				self.avg_millivolts(),
-				self.avg_milliamps(),
-				self.start,
-				duration,
-			))
-		} else if self.index == 0 {
-			self.index += 1;
-			None
+		millivolts: MilliVolt,
+		milliamps: MilliAmp,
+		milliwatts: MilliWatt,
+	) -> Result<(), FaultKind> {
+		// the power register is unsigned, compare magnitudes
+		// can't overflow, u16::MAX * u16::MAX < u32::MAX
+		let calculated =
+			millivolt_to_u32(&millivolts) * milliamp_to_i32(&milliamps).unsigned_abs() / 1000;
+		let tolerance = (calculated * POWER_TOLERANCE_PERCENT / 100).max(POWER_TOLERANCE_MW);
+		if calculated.abs_diff(milliwatts.into_inner()) > tolerance {
+			self.mismatches = self.mismatches.saturating_add(1);
+			if self.mismatches >= POWER_MISMATCH_LIMIT {
+				error!(
+					"power register: {} mW, V x I: {} mW",
+					milliwatts, calculated
+				);
+				return Err(FaultKind::SensorIntegrity);
+			}
 		} else {
-			self.index += 1;
-			None
+			self.mismatches = 0;
 		}
+		Ok(())
 	}
 }
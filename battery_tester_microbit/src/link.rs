@@ -0,0 +1,78 @@
+//! The PC link's framing over any `embedded-io-async` serial port, see
+//! [`battery_tester_common::frame`] for the frame format
+
+use battery_tester_common::{
+	BIReply, BiCommand,
+	frame::{self, COMMAND_FRAME_MAX_SIZE, FrameBuffer, REPLY_FRAME_MAX_SIZE},
+};
+use defmt::error;
+use embedded_io_async::{Read, Write};
+
+/// Sends replies, the buffer always fits the largest possible reply frame
+pub struct ReplyWriter<W> {
+	tx: W,
+	buf: [u8; REPLY_FRAME_MAX_SIZE],
+}
+
+impl<W: Write> ReplyWriter<W> {
+	pub fn new(tx: W) -> Self {
+		Self {
+			tx,
+			buf: [0; REPLY_FRAME_MAX_SIZE],
+		}
+	}
+
+	pub async fn send(&mut self, reply: &BIReply) -> Result<(), W::Error> {
+		let out_frame = frame::encode(reply, &mut self.buf).unwrap();
+		self.tx.write_all(out_frame).await
+	}
+}
+
+/// Collects commands from whatever the port has read, a read can hold several
+pub struct CommandReader<R> {
+	rx: R,
+	in_buf: [u8; COMMAND_FRAME_MAX_SIZE],
+	/// The part of `in_buf` not yet pushed into `frame_buf`
+	pending: core::ops::Range<usize>,
+	frame_buf: FrameBuffer<COMMAND_FRAME_MAX_SIZE>,
+	bad_frames: u32,
+}
+
+impl<R> CommandReader<R>
+where
+	R: Read,
+	R::Error: defmt::Format,
+{
+	pub fn new(rx: R) -> Self {
+		Self {
+			rx,
+			in_buf: [0; COMMAND_FRAME_MAX_SIZE],
+			pending: 0..0,
+			frame_buf: FrameBuffer::new(),
+			bad_frames: 0,
+		}
+	}
+
+	/// The next good command, bad frames and read errors are logged and dropped
+	pub async fn next(&mut self) -> BiCommand {
+		loop {
+			while let Some(i) = self.pending.next() {
+				match self.frame_buf.push::<BiCommand>(self.in_buf[i]) {
+					Some(Ok(cmd)) => return cmd,
+					Some(Err(e)) => {
+						self.bad_frames = self.bad_frames.wrapping_add(1);
+						error!(
+							"dropped bad command frame: {}, {} total",
+							e, self.bad_frames
+						);
+					}
+					None => {}
+				}
+			}
+			match self.rx.read(&mut self.in_buf).await {
+				Ok(num_read) => self.pending = 0..num_read,
+				Err(e) => error!("read error: {}", e),
+			}
+		}
+	}
+}
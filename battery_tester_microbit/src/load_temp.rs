@@ -0,0 +1,112 @@
+//! The heater's temperature, from a 10k B3950 NTC thermistor on the load resistor.
+//!
+//! The thermistor is between ring 1 (P0.03, AIN1) and GND with a 10k pull-up to 3V,
+//! read against VDD so the divider's ratio is all that matters.
+//! It's optional, one that reads open at boot is taken to not be fitted.
+
+use core::cell::Cell;
+
+use battery_tester_common::FaultKind;
+use defmt::{error, info};
+use embassy_nrf::saadc::Saadc;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Ticker};
+
+use crate::LOAD_OVER_TEMPERATURE_CENTI_C;
+
+/// A few readings per measurement window, the heater's temperature changes over seconds
+const SAMPLE_MS: u64 = 250;
+/// Full scale of a 12 bit reading
+const FULL_SCALE: i16 = 4_095;
+/// Colder than -45 °C, nothing's connected
+const OPEN_COUNTS: i16 = 4_000;
+/// Readings at each temperature, hottest last, interpolated between.
+/// `4096 * R / (R + 10k)` for the thermistor's resistance R at each.
+const TABLE: [(i16, i16); 16] = [
+	(3_741, -2_000),
+	(3_496, -1_000),
+	(3_157, 0),
+	(2_739, 1_000),
+	(2_278, 2_000),
+	(1_825, 3_000),
+	(1_419, 4_000),
+	(1_082, 5_000),
+	(816, 6_000),
+	(613, 7_000),
+	(462, 8_000),
+	(350, 9_000),
+	(267, 10_000),
+	(206, 11_000),
+	(160, 12_000),
+	(126, 13_000),
+];
+
+#[derive(defmt::Format, Copy, Clone, Debug, PartialEq, Eq)]
+enum Reading {
+	/// Before the first reading
+	Unknown,
+	/// Read open at boot
+	NotFitted,
+	CentiC(i16),
+	/// Read open after it was found, a wire's come off
+	Open,
+}
+
+static LOAD_TEMP: Mutex<CriticalSectionRawMutex, Cell<Reading>> =
+	Mutex::new(Cell::new(Reading::Unknown));
+
+/// Keep reading the thermistor for [`check`]
+pub async fn run(mut saadc: Saadc<'static, 1>) -> ! {
+	saadc.calibrate().await;
+	let mut ticker = Ticker::every(Duration::from_millis(SAMPLE_MS));
+	loop {
+		let mut buf = [0; 1];
+		saadc.sample(&mut buf).await;
+		let counts = buf[0].clamp(0, FULL_SCALE);
+		let reading = match LOAD_TEMP.lock(Cell::get) {
+			Reading::Unknown if counts > OPEN_COUNTS => {
+				info!("no load thermistor, testing without the heater's temperature");
+				Reading::NotFitted
+			}
+			Reading::NotFitted => Reading::NotFitted,
+			_ if counts > OPEN_COUNTS => Reading::Open,
+			_ => Reading::CentiC(centi_c(counts)),
+		};
+		LOAD_TEMP.lock(|cell| cell.set(reading));
+		ticker.next().await;
+	}
+}
+
+/// The heater's latest temperature, `None` without a thermistor.
+/// Too hot, or the thermistor coming off, is a fault.
+pub fn check() -> Result<Option<i16>, FaultKind> {
+	match LOAD_TEMP.lock(Cell::get) {
+		Reading::Unknown | Reading::NotFitted => Ok(None),
+		Reading::CentiC(t) if t > LOAD_OVER_TEMPERATURE_CENTI_C => {
+			error!("load over temperature: {} c°C", t);
+			Err(FaultKind::LoadOverTemperature)
+		}
+		Reading::CentiC(t) => Ok(Some(t)),
+		Reading::Open => {
+			error!("load thermistor open");
+			Err(FaultKind::LoadThermistor)
+		}
+	}
+}
+
+/// Off the ends of the table reads as its coldest or hottest, a short as the hottest
+fn centi_c(counts: i16) -> i16 {
+	let (first, last) = (TABLE[0], TABLE[TABLE.len() - 1]);
+	if counts >= first.0 {
+		return first.1;
+	}
+	if counts <= last.0 {
+		return last.1;
+	}
+	// found, counts is between the first and last
+	let i = TABLE.iter().position(|(c, _)| counts >= *c).unwrap_or(1);
+	let ((hot_c, hot_t), (cold_c, cold_t)) = (TABLE[i], TABLE[i - 1]);
+	let t = i32::from(cold_t)
+		+ i32::from(hot_t - cold_t) * i32::from(cold_c - counts) / i32::from(cold_c - hot_c);
+	t as i16
+}
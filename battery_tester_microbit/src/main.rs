@@ -1,45 +1,96 @@
 #![no_std]
 #![no_main]
 
+use core::cell::{Cell, RefCell};
+
 use battery_tester_common::{
-	AllowUndercurrent, BIReply, BiCommand, COMMAND_MAX_SIZE, ClearFault, Fault, FaultKind,
-	I2CError, LoadState, Measurement, MilliAmp, MilliVolt, REPLY_MAX_SIZE, Reset,
+	AllowUndercurrent, AutonomousTest, BIReply, BiCommand, ClearFault, CommandKind, ControlWord,
+	DEFAULT_MAX_MILLIV, Fault, FaultKind, FaultSnapshot, I2CError, LoadChannel, LoadPulse,
+	LoadState, LoggedFault, Measurement, MilliVolt, PROTOCOL_VERSION, ReplyKind, Reset,
+	ResetReason, SelfTestReport, Status, Trim, UNSOLICITED_SEQ, com_timeout_ms,
+	window::{DaqDataQueue, WINDOW_SAMPLES, Window},
 };
 use defmt::{error, info};
 use defmt_rtt as _;
 use embassy_executor::Spawner;
-use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_futures::select::{Either, Either3, Either4, select, select3, select4};
 use embassy_nrf::{
 	Peri, bind_interrupts,
-	gpio::{Input, Pull},
-	peripherals::{self, P0_04, P0_14, P0_26, P1_00, TWISPI1},
+	gpio::{AnyPin, Input, Level, Output, OutputDrive, Pull},
+	nvmc::Nvmc,
+	peripherals::{self, P0_04, P0_14, P0_23},
 	pwm::SimplePwm,
+	saadc::{self, ChannelConfig, Gain, Reference, Saadc, Time},
 	twim::{self, Frequency, Twim},
-	uarte::{self, Uarte, UarteRx, UarteTx},
+	uarte::{self, Uarte, UarteRxWithIdle, UarteTx},
+	wdt::{self, HaltConfig, Watchdog, WatchdogHandle},
+};
+use embassy_sync::{
+	blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+	channel::Channel,
+	signal::Signal,
 };
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embassy_time::{Duration, Instant, Ticker};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use embedded_hal_async::i2c::I2c;
 // use fixed::types::{I16F16, I18F14, U16F16};
 use microbit_side_lib::{
-	BAT_CONNECT_DEBOUNCE_MS, DaqDataQueue,
-	ina260::{self, Averaging, BVConvTime, INA260Config, OperMode, Register, SCConvTime},
-	pwm::{HeaterCmd, PwmCtrl},
-	twim_err_to_common,
+	BAUD, FIRMWARE_VERSION, HEATER_RAMP_MS, I2cErrorToCommon, OVER_TEMPERATURE_CENTI_C, PowerCheck,
+	WATCHDOG_FEED_MS, WATCHDOG_TIMEOUT_MS,
+	autonomous::{self, Run},
+	battery::{self, BatteryInput},
+	board::{IdleRx, ServoPwm},
+	device_id,
+	display::{self, Matrix, Shown},
+	fan::Fan,
+	fault_log,
+	ina260::{self, Averaging, BVConvTime, INA260Config, OperMode, SCConvTime},
+	ina260_err_to_common,
+	link::{CommandReader, ReplyWriter},
+	load_temp, next_sample_index,
+	offline::{self, REPLAY_BATCH},
+	pwm::{self, HeaterCmd, PwmCtrl},
+	settings,
+	sht4x::{self, SHT4X_ADDRESS},
+	sht4x_err_to_common,
+	speaker::{self, Alert, Speaker},
+	take_reset_reason, uarte_baudrate,
 };
 use panic_probe as _;
-// use sht4x::Sht4xAsync;
 
-static CMD_CH: Channel<CriticalSectionRawMutex, BiCommand, 4> = Channel::new();
+/// Control words along with the sequence number to echo in the reply, `None` for a heartbeat
+static CMD_CH: Channel<CriticalSectionRawMutex, (u32, Option<ControlWord>), 4> = Channel::new();
 static REPLY_CH: Channel<CriticalSectionRawMutex, BIReply, 4> = Channel::new();
+/// Autonomous test to start along with the sequence number to echo in the reply.
+/// One sent while the power task waits on the battery or a fault is dropped.
+static AUTONOMOUS: Signal<CriticalSectionRawMutex, (u32, AutonomousTest)> = Signal::new();
+/// Self test asked for by the PC, the sequence number to echo in the reply, dropped the same way
+static SELF_TEST: Signal<CriticalSectionRawMutex, u32> = Signal::new();
+/// The self test run once there was a battery after boot, sent with the version
+static BOOT_SELF_TEST: Mutex<CriticalSectionRawMutex, Cell<Option<SelfTestReport>>> =
+	Mutex::new(Cell::new(None));
+static FLASH: Mutex<CriticalSectionRawMutex, RefCell<Option<Nvmc<'static>>>> =
+	Mutex::new(RefCell::new(None));
 
 pub type I2C = Twim<'static>;
+/// The heater and its fan on the micro:bit's pins
+pub type Heater = PwmCtrl<ServoPwm, Output<'static>>;
 
 /// adress is GND, GND (both pads not connected).
 pub const INA260_VIN_ADDRESS: u8 = 0x40;
+/// Optional second INA260 on the heater side, A0 bridged to VS
+pub const INA260_LOAD_ADDRESS: u8 = 0x41;
+
+/// The optional I2C devices found at init
+#[derive(Copy, Clone, defmt::Format)]
+struct Sensors {
+	sht4x: bool,
+	load_ina260: bool,
+}
 
 bind_interrupts!(struct Irqs {
 	UARTE0 => uarte::InterruptHandler<peripherals::UARTE0>;
 	TWISPI1 => twim::InterruptHandler<peripherals::TWISPI1>;
+	SAADC => saadc::InterruptHandler;
 });
 
 #[embassy_executor::main]
@@ -48,83 +99,257 @@ async fn main(spawner: Spawner) {
 
 	let p = embassy_nrf::init(Default::default());
 
-	let i2c_sda = p.P1_00;
-	let i2c_scl = p.P0_26;
-	let i2c_driver = p.TWISPI1;
+	// before anything else so a reset by it is recorded
+	let reset_reason = take_reset_reason();
+	let mut watchdog_conf = wdt::Config::default();
+	watchdog_conf.timeout_ticks = WATCHDOG_TIMEOUT_MS * 32_768 / 1_000;
+	// stopping at a breakpoint isn't a hang
+	watchdog_conf.action_during_debug_halt = HaltConfig::PAUSE;
+	let Ok((_watchdog, [watchdog])) = Watchdog::try_new(p.WDT, watchdog_conf) else {
+		panic!("watchdog already running with another config");
+	};
+
+	let mut i2c_conf = twim::Config::default();
+	i2c_conf.frequency = Frequency::K250;
+	let i2c = Twim::new(p.TWISPI1, Irqs, p.P1_00, p.P0_26, i2c_conf, &mut []);
 	// RING2 - P0.04/P0_04 - P2
 	let bat = p.P0_04;
+	// RING1 - P0.03/AIN1 - P1, the heater's thermistor divider
+	let mut ntc_conf = ChannelConfig::single_ended(p.P0_03);
+	ntc_conf.reference = Reference::VDD1_4;
+	ntc_conf.gain = Gain::GAIN1_4;
+	// the divider's 5k source needs longer than the default to settle
+	ntc_conf.time = Time::_40US;
+	let ntc = Saadc::new(p.SAADC, Irqs, saadc::Config::default(), [ntc_conf]);
 	let btn_a = p.P0_14;
+	let btn_b = p.P0_23;
 	let uarte = p.UARTE0;
 	let rxd = p.P1_08;
 	let txd = p.P0_06;
 
+	// LED matrix, rows source and columns sink
+	let led = |pin: Peri<'static, AnyPin>, level| Output::new(pin, level, OutputDrive::Standard);
+	let matrix = Matrix::new(
+		[
+			led(p.P0_21.into(), Level::Low),
+			led(p.P0_22.into(), Level::Low),
+			led(p.P0_15.into(), Level::Low),
+			led(p.P0_24.into(), Level::Low),
+			led(p.P0_19.into(), Level::Low),
+		],
+		[
+			led(p.P0_28.into(), Level::High),
+			led(p.P0_11.into(), Level::High),
+			led(p.P0_31.into(), Level::High),
+			led(p.P1_05.into(), Level::High),
+			led(p.P0_30.into(), Level::High),
+		],
+	);
+
+	// before the PWM starts, it's trimmed too
+	let mut flash = Nvmc::new(p.NVMC);
+	settings::load(&mut flash);
+	fault_log::load(&mut flash);
+	FLASH.lock(|shared| shared.replace(Some(flash)));
+
 	//PWM
-	let pwm = SimplePwm::new_1ch(p.PWM0, p.P1_02); // p1.02 = P16
-	let pwm_ctrl = PwmCtrl::new(pwm);
+	let pwm = ServoPwm::new(SimplePwm::new_1ch(p.PWM0, p.P1_02)); // p1.02 = P16
+	// RING0 - P0.02 - P0, the fan's MOSFET
+	let fan = Fan::new(Output::new(p.P0_02, Level::Low, OutputDrive::Standard));
+	let pwm_ctrl = PwmCtrl::new(pwm, fan, HEATER_RAMP_MS);
+	// the speaker gets its own PWM, the heater's runs at the servo rate
+	let speaker = Speaker::new(SimplePwm::new_1ch(p.PWM1, p.P0_00));
 
 	//UART
 	let mut uart_conf = embassy_nrf::uarte::Config::default();
 	uart_conf.parity = embassy_nrf::uarte::Parity::EXCLUDED;
-	uart_conf.baudrate = embassy_nrf::uarte::Baudrate::BAUD230400;
+	uart_conf.baudrate = uarte_baudrate();
 	let serial = Uarte::new(uarte, rxd, txd, Irqs, uart_conf);
-	let (serial_out, serial_in) = serial.split();
+	// the idle timer lets us read whatever has arrived instead of a fixed length
+	let (serial_out, serial_in) = serial.split_with_idle(p.TIMER0, p.PPI_CH0, p.PPI_CH1);
 
-	spawner.spawn(serial_reply_task(serial_out)).unwrap();
+	spawner.spawn(display_task(matrix)).unwrap();
+	spawner.spawn(speaker_task(speaker)).unwrap();
+	spawner.spawn(load_temp_task(ntc)).unwrap();
 	spawner
-		.spawn(power_task(
-			pwm_ctrl, i2c_driver, i2c_sda, i2c_scl, bat, btn_a,
-		))
+		.spawn(serial_reply_task(serial_out, reset_reason))
+		.unwrap();
+	spawner
+		.spawn(power_task(pwm_ctrl, i2c, bat, btn_a, btn_b, watchdog))
 		.unwrap();
 	spawner.spawn(serial_in_task(serial_in)).unwrap();
 }
 
 #[embassy_executor::task]
-async fn serial_reply_task(mut serial_out: UarteTx<'static>) -> ! {
-	info!("init serial reply task");
-	assert!(REPLY_MAX_SIZE <= u8::MAX as usize);
-	let mut out_buf: [u8; REPLY_MAX_SIZE] = [0; REPLY_MAX_SIZE];
+async fn display_task(mut matrix: Matrix) -> ! {
+	info!("init display task");
+	matrix.run().await
+}
+
+#[embassy_executor::task]
+async fn speaker_task(mut speaker: Speaker) -> ! {
+	info!("init speaker task");
+	speaker.run().await
+}
+
+#[embassy_executor::task]
+async fn load_temp_task(ntc: Saadc<'static, 1>) -> ! {
+	info!("init load temperature task");
+	load_temp::run(ntc).await
+}
+
+#[embassy_executor::task]
+async fn serial_reply_task(serial_out: UarteTx<'static>, reset_reason: ResetReason) -> ! {
+	info!("init serial reply task, reset reason: {}", reset_reason);
+	let mut serial_out = ReplyWriter::new(serial_out);
+	// the PC is told once, with the first status
+	let mut reset_reason = Some(reset_reason);
 	loop {
-		let reply = REPLY_CH.receive().await;
-		let out_msg = postcard::to_slice(&reply, &mut out_buf).unwrap();
-		let out_len = out_msg.len() as u8;
-		// info!("len: {}", out_len);
-		if let Err(e) = serial_out.write(&[out_len]).await {
-			error!("write len error: {}", e);
-			continue;
+		let mut reply = REPLY_CH.receive().await;
+		if let ReplyKind::Status(status) = &mut reply.kind {
+			status.reset_reason = reset_reason.take();
 		}
-		if let Err(e) = serial_out.write(&out_msg).await {
-			error!("write msg error: {}", e);
+		if let Err(e) = serial_out.send(&reply).await {
+			error!("write reply error: {}", e);
 		}
 	}
 }
 
 #[embassy_executor::task]
-async fn serial_in_task(mut serial_in: UarteRx<'static>) -> ! {
+async fn serial_in_task(serial_in: UarteRxWithIdle<'static>) -> ! {
 	info!("init serial in task");
-	assert!(COMMAND_MAX_SIZE <= u8::MAX as usize);
-	let mut in_buf: [u8; COMMAND_MAX_SIZE] = [0; COMMAND_MAX_SIZE];
-	let mut len_buf = [0u8; 1];
+	let mut serial_in = CommandReader::new(IdleRx::new(serial_in));
 	loop {
-		match serial_in.read(&mut len_buf).await {
-			Ok(()) => {
-				// get msg len
-				let msg_len = len_buf[0] as usize;
-				// get slice of msg len
-				let in_msg = &mut in_buf[..msg_len];
-				// read exact msg length
-				match serial_in.read(in_msg).await {
-					Ok(_) => {
-						let cmd: BiCommand = postcard::from_bytes(in_msg).unwrap();
-						CMD_CH.send(cmd).await;
-						// info!("msg: {}:{:?}", msg_len, &in_msg);
-					}
-					Err(e) => {
-						error!("read msg error: {}", e);
-					}
+		match serial_in.next().await {
+			BiCommand {
+				seq,
+				kind: CommandKind::Control(cmd),
+			} => CMD_CH.send((seq, Some(cmd))).await,
+			BiCommand {
+				seq,
+				kind: CommandKind::Heartbeat,
+			} => CMD_CH.send((seq, None)).await,
+			BiCommand {
+				seq,
+				kind: CommandKind::Hello,
+			} => {
+				let reply = BIReply {
+					seq,
+					bat_present: battery::present(),
+					load: pwm::load_state(),
+					kind: ReplyKind::Version {
+						protocol: PROTOCOL_VERSION,
+						firmware: FIRMWARE_VERSION,
+						device_id: device_id(),
+						self_test: BOOT_SELF_TEST.lock(Cell::get),
+						baud: BAUD,
+					},
+				};
+				REPLY_CH.send(reply).await;
+			}
+			BiCommand {
+				seq,
+				kind: CommandKind::SetTrim(trim),
+			} => {
+				let reply = BIReply {
+					seq,
+					bat_present: battery::present(),
+					load: pwm::load_state(),
+					kind: ReplyKind::Trim(with_flash(|flash| settings::save_trim(flash, trim))),
+				};
+				REPLY_CH.send(reply).await;
+			}
+			BiCommand {
+				seq,
+				kind: CommandKind::GetTrim,
+			} => {
+				let reply = BIReply {
+					seq,
+					bat_present: battery::present(),
+					load: pwm::load_state(),
+					kind: ReplyKind::Trim(Ok(settings::trim())),
+				};
+				REPLY_CH.send(reply).await;
+			}
+			BiCommand {
+				seq,
+				kind: CommandKind::SetBatteryDetect(detect),
+			} => {
+				let reply = BIReply {
+					seq,
+					bat_present: battery::present(),
+					load: pwm::load_state(),
+					kind: ReplyKind::BatteryDetect(with_flash(|flash| {
+						settings::save_battery_detect(flash, detect)
+					})),
+				};
+				REPLY_CH.send(reply).await;
+			}
+			BiCommand {
+				seq,
+				kind: CommandKind::GetBatteryDetect,
+			} => {
+				let reply = BIReply {
+					seq,
+					bat_present: battery::present(),
+					load: pwm::load_state(),
+					kind: ReplyKind::BatteryDetect(Ok(settings::battery_detect())),
+				};
+				REPLY_CH.send(reply).await;
+			}
+			BiCommand {
+				seq,
+				kind: CommandKind::DumpFaultLog,
+			} => {
+				for fault in fault_log::faults().iter() {
+					let logged = BIReply {
+						seq: UNSOLICITED_SEQ,
+						bat_present: battery::present(),
+						load: pwm::load_state(),
+						kind: ReplyKind::FaultLog(Some(*fault)),
+					};
+					REPLY_CH.send(logged).await;
 				}
+				let reply = BIReply {
+					seq,
+					bat_present: battery::present(),
+					load: pwm::load_state(),
+					kind: ReplyKind::FaultLog(None),
+				};
+				REPLY_CH.send(reply).await;
 			}
-			Err(e) => {
-				error!("read len error: {}", e);
+			BiCommand {
+				seq,
+				kind: CommandKind::StartAutonomous(test),
+			} => AUTONOMOUS.signal((seq, test)),
+			BiCommand {
+				seq,
+				kind: CommandKind::SelfTest,
+			} => SELF_TEST.signal(seq),
+			BiCommand {
+				seq,
+				kind: CommandKind::DumpAutonomousLog,
+			} => {
+				// a slot at a time, the power task logs to flash too
+				for slot in 0..autonomous::SLOTS {
+					let Some(sample) = with_flash(|flash| autonomous::read(flash, slot)) else {
+						break;
+					};
+					let logged = BIReply {
+						seq: UNSOLICITED_SEQ,
+						bat_present: battery::present(),
+						load: pwm::load_state(),
+						kind: ReplyKind::AutonomousLog(Some(sample)),
+					};
+					REPLY_CH.send(logged).await;
+				}
+				let reply = BIReply {
+					seq,
+					bat_present: battery::present(),
+					load: pwm::load_state(),
+					kind: ReplyKind::AutonomousLog(None),
+				};
+				REPLY_CH.send(reply).await;
 			}
 		}
 	}
@@ -132,180 +357,677 @@ async fn serial_in_task(mut serial_in: UarteRx<'static>) -> ! {
 
 #[embassy_executor::task]
 async fn power_task(
-	mut pwm_ctrl: PwmCtrl,
-	i2c_driver: Peri<'static, TWISPI1>,
-	sda: Peri<'static, P1_00>,
-	scl: Peri<'static, P0_26>,
+	mut pwm_ctrl: Heater,
+	mut i2c: I2C,
 	bat: Peri<'static, P0_04>,
 	btn_a: Peri<'static, P0_14>,
+	btn_b: Peri<'static, P0_23>,
+	mut watchdog: WatchdogHandle,
 ) -> ! {
 	info!("Init power task");
 	// TODO: pull down here makes a voltage divider with the SparkFun Opto-isolator Breakout?
 	// it should be pull none because the OI circuit is connected to ground or vcc?
-	let mut bat_present = Input::new(bat, Pull::None);
+	let mut bat_present = BatteryInput::new(Input::new(bat, Pull::None));
 	let mut fault_clear_btn = Input::new(btn_a, Pull::None);
-	let mut i2c_conf = twim::Config::default();
-	i2c_conf.frequency = Frequency::K250;
-	let mut i2c = Twim::new(i2c_driver, Irqs, sda, scl, i2c_conf, &mut []);
+	let mut local_load_btn = Input::new(btn_b, Pull::None);
 
 	info!("waiting for battery reconnect");
-	wait_bat_reconnect(&mut bat_present, BAT_CONNECT_DEBOUNCE_MS).await;
+	fed(&mut watchdog, wait_bat_reconnect(&mut bat_present)).await;
 
 	loop {
-		i2c_init_loop(&mut i2c, &mut fault_clear_btn).await;
-		let fkind = power_ctrl_loop(&mut i2c, &mut bat_present, &mut pwm_ctrl).await;
+		let sensors = i2c_init_loop(
+			&mut i2c,
+			&mut bat_present,
+			&mut fault_clear_btn,
+			&mut watchdog,
+		)
+		.await;
+		if BOOT_SELF_TEST.lock(Cell::get).is_none() {
+			let report = self_test(&mut i2c, &mut pwm_ctrl, sensors, &mut watchdog, true).await;
+			info!("boot self test: {}", report);
+			BOOT_SELF_TEST.lock(|boot| boot.set(Some(report)));
+		}
+		let mut snapshot = None;
+		let fkind = power_ctrl_loop(
+			&mut i2c,
+			&mut bat_present,
+			&mut local_load_btn,
+			&mut pwm_ctrl,
+			sensors,
+			&mut watchdog,
+			&mut snapshot,
+		)
+		.await;
 		pwm_ctrl.set_cmd(HeaterCmd::Off);
 		let fault = Fault {
 			kind: fkind,
 			time: Instant::now().as_millis(),
 		};
+		log_fault(fault, snapshot);
 		info!("waiting for fault clear");
-		wait_fault_clear(&mut fault_clear_btn, fault).await;
+		fed(
+			&mut watchdog,
+			tracked(
+				&mut bat_present,
+				wait_fault_clear(&mut fault_clear_btn, fault),
+			),
+		)
+		.await;
+		display::show(Shown::Idle);
 		info!("waiting for battery");
-		wait_bat_present(&mut bat_present, BAT_CONNECT_DEBOUNCE_MS).await;
+		fed(&mut watchdog, wait_bat_present(&mut bat_present)).await;
 	}
 }
 
-async fn power_ctrl_loop(
-	i2c: &mut I2C,
-	bat_present: &mut Input<'static>,
-	pwm_ctrl: &mut PwmCtrl,
-) -> FaultKind {
-	/// collect data @ 10Hz
-	const DAQ_INTERVAL_MS: u64 = 100;
-	/// Turn off heater if we don't get a command from the PC for this many ms
-	const COM_TIMEOUT: u64 = 1_250;
+/// Button B toggles the load for bench bring-up, the toggle is reported in the next reply
+/// and the load stays on without the PC until it sends a command.
+/// `snapshot` is kept at the newest measurement for the fault log.
+/// Once the PC stops sending commands the measurements go to [`offline`] until it's replayed them.
+/// An autonomous test holds the load until it ends, whatever the PC or button B send.
+async fn power_ctrl_loop<I>(
+	i2c: &mut I,
+	bat_present: &mut BatteryInput,
+	local_load_btn: &mut Input<'static>,
+	pwm_ctrl: &mut Heater,
+	sensors: Sensors,
+	watchdog: &mut WatchdogHandle,
+	snapshot: &mut Option<FaultSnapshot>,
+) -> FaultKind
+where
+	I: I2c,
+	I::Error: I2cErrorToCommon + defmt::Format,
+{
+	/// How often to check whether the INA260 has finished a conversion,
+	/// each sample is taken once one has, see [`ina260_config`] for the rate
+	const CONVERSION_POLL_MS: u64 = 5;
+	/// Conversion times without a new one before the INA260 is taken to have stalled
+	const STALLED_CONVERSIONS: u64 = 3;
+	/// Presses of button B this soon after the last toggle are switch bounce
+	const LOCAL_LOAD_DEBOUNCE_MS: u64 = 250;
+	let stalled_after =
+		Duration::from_micros(u64::from(ina260_config().conversion_us()) * STALLED_CONVERSIONS);
+	// the PC's command interval, kept across tests, the load is turned off when commands stop
+	let mut poll_ms: Option<u16> = None;
 	loop {
+		offline::clear();
+		AUTONOMOUS.reset();
+		SELF_TEST.reset();
 		let mut measurement: Option<Measurement> = None;
 		// do this so the ticker doesn't store ticks while we wait for fault clear
-		let mut com_timeout_ticker = Ticker::every(Duration::from_millis(COM_TIMEOUT));
+		let mut com_timeout_ticker = Ticker::every(Duration::from_millis(com_timeout_ms(poll_ms)));
 		let mut allow_undercurrent = AllowUndercurrent::default();
+		let mut cutoff: Option<MilliVolt> = None;
+		// latched until the PC resets us so a dead link can't run the battery flat
+		let mut cutoff_reached = false;
+		// the load isn't turned on, and is turned off with a fault, over this
+		let mut max_voltage = MilliVolt::new(DEFAULT_MAX_MILLIV);
 		let mut daq_queue = DaqDataQueue::default();
-		let mut daq_ticker = Ticker::every(Duration::from_millis(DAQ_INTERVAL_MS));
+		let mut power_check = PowerCheck::default();
+		let mut poll_ticker = Ticker::every(Duration::from_millis(CONVERSION_POLL_MS));
+		let mut last_conversion = Instant::now();
+		// button B's toggle, until it's sent to the PC
+		let mut local_load: Option<LoadState> = None;
+		// the load was turned on with button B and the PC hasn't sent a command since
+		let mut held_locally = false;
+		let mut last_toggle = Instant::MIN;
+		// the test is only complete at a reset if the load was on for it
+		let mut load_was_on = false;
+		// the com timeout fired and the PC hasn't sent a command since
+		let mut link_lost = false;
+		// the state above is the defaults until the PC sends its control word
+		let mut wants_control = true;
+		let mut run: Option<Run> = None;
 		loop {
-			match select3(
-				daq_ticker.next(),
-				CMD_CH.receive(),
+			match select4(
+				poll_ticker.next(),
+				select3(CMD_CH.receive(), AUTONOMOUS.wait(), SELF_TEST.wait()),
 				com_timeout_ticker.next(),
+				local_load_btn.wait_for_falling_edge(),
 			)
 			.await
 			{
-				Either3::First(_daq_interval) => {
+				Either4::First(_poll_interval) => {
+					// a hung I2C bus stops the polling and the watchdog resets us
+					watchdog.pet();
+					match ina260::conversion_ready(INA260_VIN_ADDRESS, i2c).await {
+						Ok(true) => last_conversion = Instant::now(),
+						Ok(false) if Instant::now() - last_conversion > stalled_after => {
+							error!("INA260 stopped converting");
+							return FaultKind::I2C(I2CError::InaVinStalled);
+						}
+						Ok(false) => continue,
+						Err(e) => {
+							error!("I2C read conversion ready error:\n{}", e);
+							return FaultKind::I2C(I2CError::InaVinMaskEnable(e.to_common()));
+						}
+					}
 					match daq(
 						i2c,
-						&bat_present,
+						bat_present,
 						pwm_ctrl,
 						&mut daq_queue,
+						&mut power_check,
 						allow_undercurrent,
+						sensors,
 					)
 					.await
 					{
 						Ok(Some(new_measurement)) => {
 							info!(
-								"daq: {}, {}, t: {}, d: {}",
+								"daq: #{}, {}, {}, t: {}, d: {}",
+								new_measurement.sample_index,
 								new_measurement.vbat,
 								new_measurement.ibat,
-								new_measurement.dt,
-								new_measurement.duration
+								new_measurement.sample_start_ms,
+								new_measurement.sample_duration_ms
 							);
-							let _old_measurement = measurement.replace(new_measurement);
+							if let Some(cutoff) = cutoff
+								&& !cutoff_reached && new_measurement.vbat <= cutoff
+							{
+								pwm_ctrl.set_cmd(HeaterCmd::Off);
+								cutoff_reached = true;
+								info!("reached cutoff: {}, load off", cutoff);
+							}
+							if pwm_ctrl.cmd() == HeaterCmd::On && new_measurement.vbat > max_voltage
+							{
+								error!(
+									"vbat: {} over the limit: {}",
+									new_measurement.vbat, max_voltage
+								);
+								return FaultKind::Overvoltage;
+							}
+							display::show(Shown::Measuring {
+								vbat: new_measurement.vbat,
+								load: pwm_ctrl.cmd(),
+							});
+							*snapshot = Some(FaultSnapshot {
+								vbat: new_measurement.vbat,
+								ibat: new_measurement.ibat,
+							});
+							if let Some(test) = &mut run {
+								let logged =
+									with_flash(|flash| test.measured(flash, &new_measurement));
+								if logged.is_err() || cutoff_reached || test.over() {
+									if with_flash(|flash| test.finish(flash)).is_err() {
+										error!("last autonomous sample not logged");
+									}
+									pwm_ctrl.set_cmd(HeaterCmd::Off);
+									info!("autonomous test done, load off");
+									speaker::alert(Alert::TestComplete);
+									run = None;
+									load_was_on = false;
+								}
+							}
+							if link_lost || !offline::is_empty() {
+								// after the ones the PC hasn't had yet
+								offline::push(new_measurement);
+							} else {
+								let _old_measurement = measurement.replace(new_measurement);
+							}
 						}
 						Ok(None) => {}
 						Err(fk) => return fk,
 					}
 				}
-				Either3::Second(cmd) => {
-					match cmd.load {
-						LoadState::Off => {
-							pwm_ctrl.set_cmd(HeaterCmd::Off);
-						}
-						LoadState::On => {
+				Either4::Second(Either3::Second((seq, test))) => {
+					max_voltage = test
+						.max_voltage
+						.unwrap_or(MilliVolt::new(DEFAULT_MAX_MILLIV));
+					let refused = over_voltage(snapshot, max_voltage);
+					let erased = if refused {
+						Ok(())
+					} else {
+						with_flash(|flash| autonomous::erase(flash, || watchdog.pet()))
+					};
+					if erased.is_ok() && !refused {
+						info!("autonomous test to: {}", test.cutoff);
+						allow_undercurrent = test.allow_undercurrent;
+						pwm_ctrl.set_load_model(test.load_model.unwrap_or_default());
+						pwm_ctrl.set_current_setpoint(test.current_setpoint);
+						daq_queue.set_window_samples(
+							test.window_samples.map_or(WINDOW_SAMPLES, u32::from),
+						);
+						daq_queue.set_filter(test.filter);
+						cutoff = Some(test.cutoff);
+						if !cutoff_reached {
 							pwm_ctrl.set_cmd(HeaterCmd::On);
 						}
+						load_was_on = true;
+						run = Some(Run::new(test));
+					}
+					REPLY_CH
+						.send(BIReply {
+							seq,
+							bat_present: battery::present(),
+							load: pwm::load_state(),
+							kind: ReplyKind::Autonomous(erased),
+						})
+						.await;
+					if refused {
+						error!("autonomous test refused, vbat over: {}", max_voltage);
+						return FaultKind::Overvoltage;
+					}
+					com_timeout_ticker.reset();
+				}
+				Either4::Second(Either3::Third(seq)) => {
+					// a test's load isn't interrupted, only the sensors are checked
+					let pulse = pwm_ctrl.cmd() == HeaterCmd::Off && run.is_none();
+					let report = self_test(i2c, pwm_ctrl, sensors, watchdog, pulse).await;
+					info!("self test: {}", report);
+					REPLY_CH
+						.send(BIReply {
+							seq,
+							bat_present: battery::present(),
+							load: pwm::load_state(),
+							kind: ReplyKind::SelfTest(report),
+						})
+						.await;
+					// the INA260 was read while it ran, its conversions carried on
+					last_conversion = Instant::now();
+					com_timeout_ticker.reset();
+				}
+				Either4::Second(Either3::First((seq, cmd))) => {
+					// the PC sent this before it knew about the toggle, so it can't undo it
+					let toggled = local_load.take();
+					// faulted once the PC has its reply
+					let mut refused = false;
+					// a heartbeat only keeps the link up, the last control word stands
+					if let Some(cmd) = &cmd {
+						if run.is_none() {
+							max_voltage = cmd
+								.max_voltage
+								.unwrap_or(MilliVolt::new(DEFAULT_MAX_MILLIV));
+						}
+						match (toggled, cmd.load) {
+							(Some(_), _) => {}
+							(None, _) if run.is_some() => {}
+							(None, LoadState::On)
+								if !cutoff_reached && over_voltage(snapshot, max_voltage) =>
+							{
+								pwm_ctrl.set_cmd(HeaterCmd::Off);
+								refused = true;
+							}
+							(None, LoadState::On) if !cutoff_reached => {
+								pwm_ctrl.set_cmd(HeaterCmd::On);
+							}
+							(None, LoadState::Off | LoadState::On) => {
+								pwm_ctrl.set_cmd(HeaterCmd::Off);
+							}
+						};
+					}
+					held_locally = false;
+					link_lost = false;
+					wants_control &= cmd.is_none();
+					load_was_on |= pwm_ctrl.cmd() == HeaterCmd::On;
+					for _ in 0..REPLAY_BATCH {
+						let Some(buffered) = offline::pop() else {
+							break;
+						};
+						REPLY_CH
+							.send(BIReply {
+								seq: UNSOLICITED_SEQ,
+								bat_present: battery::present(),
+								load: pwm::load_state(),
+								kind: ReplyKind::Replay(buffered),
+							})
+							.await;
+					}
+					// if there's a measurement, take and send it
+					REPLY_CH
+						.send(BIReply {
+							seq,
+							bat_present: battery::present(),
+							load: pwm::load_state(),
+							kind: ReplyKind::Status(Status {
+								measurement: measurement.take(),
+								fault: Ok(()),
+								cutoff_reached,
+								local_load: toggled,
+								reset_reason: None,
+								wants_control,
+							}),
+						})
+						.await;
+					if refused {
+						error!("load refused, vbat over: {}", max_voltage);
+						return FaultKind::Overvoltage;
+					}
+					let Some(cmd) = cmd else {
+						com_timeout_ticker.reset();
+						continue;
 					};
-					let reply = BIReply {
-						// if there's a measurement, take and send it
-						measurement: measurement.take(),
-						fault: Ok(()),
-					};
-					REPLY_CH.send(reply).await;
 					if let Reset::Yes = cmd.reset {
 						pwm_ctrl.set_cmd(HeaterCmd::Off);
+						if let Some(test) = &mut run
+							&& with_flash(|flash| test.finish(flash)).is_err()
+						{
+							error!("last autonomous sample not logged");
+						}
+						if load_was_on {
+							speaker::alert(Alert::TestComplete);
+						}
 						break;
 					}
-					allow_undercurrent = cmd.allow_undercurrent;
+					if cmd.poll_ms.is_some() && cmd.poll_ms != poll_ms {
+						poll_ms = cmd.poll_ms;
+						info!("commands every: {} ms", poll_ms);
+						com_timeout_ticker =
+							Ticker::every(Duration::from_millis(com_timeout_ms(poll_ms)));
+					}
 					com_timeout_ticker.reset();
+					if run.is_some() {
+						continue;
+					}
+					allow_undercurrent = cmd.allow_undercurrent;
+					pwm_ctrl.set_load_model(cmd.load_model.unwrap_or_default());
+					pwm_ctrl.set_current_setpoint(cmd.current_setpoint);
+					daq_queue
+						.set_window_samples(cmd.window_samples.map_or(WINDOW_SAMPLES, u32::from));
+					daq_queue.set_filter(cmd.filter);
+					cutoff = match cmd.load {
+						LoadState::On => cmd.cutoff,
+						LoadState::Off => None,
+					};
+				}
+				Either4::Third(_com_timeout) => {
+					if !link_lost {
+						info!("buffering measurements until the PC is back");
+						link_lost = true;
+						if let Some(unsent) = measurement.take() {
+							offline::push(unsent);
+						}
+					}
+					if !held_locally && run.is_none() {
+						pwm_ctrl.set_cmd(HeaterCmd::Off);
+						error!("lost comms");
+					}
 				}
-				Either3::Third(_com_timeout) => {
-					pwm_ctrl.set_cmd(HeaterCmd::Off);
-					error!("lost comms");
+				Either4::Fourth(_pressed) if run.is_some() => {}
+				Either4::Fourth(_pressed)
+					if Instant::now() - last_toggle
+						< Duration::from_millis(LOCAL_LOAD_DEBOUNCE_MS) => {}
+				Either4::Fourth(_pressed) => {
+					last_toggle = Instant::now();
+					let toggled = match pwm_ctrl.cmd() {
+						HeaterCmd::On => LoadState::Off,
+						// latched off at the cutoff like a PC command
+						HeaterCmd::Off if cutoff_reached => continue,
+						HeaterCmd::Off if over_voltage(snapshot, max_voltage) => {
+							error!("button B refused, vbat over: {}", max_voltage);
+							return FaultKind::Overvoltage;
+						}
+						HeaterCmd::Off => LoadState::On,
+					};
+					info!("button B: load {}", toggled);
+					pwm_ctrl.set_cmd(match toggled {
+						LoadState::On => HeaterCmd::On,
+						LoadState::Off => HeaterCmd::Off,
+					});
+					held_locally = toggled == LoadState::On;
+					load_was_on |= held_locally;
+					local_load = Some(toggled);
 				}
 			};
 		}
 		info!("disconnect and reconnect battery");
-		wait_bat_reconnect(bat_present, BAT_CONNECT_DEBOUNCE_MS).await;
+		display::show(Shown::Idle);
+		fed(watchdog, wait_bat_reconnect(bat_present)).await;
 	}
 }
 
-async fn daq(
-	i2c: &mut I2C,
-	bat_present: &Input<'static>,
-	pwm_ctrl: &mut PwmCtrl,
+/// The newest measurement is over `max_voltage`, so the load mustn't go on
+fn over_voltage(snapshot: &Option<FaultSnapshot>, max_voltage: MilliVolt) -> bool {
+	snapshot
+		.as_ref()
+		.is_some_and(|snapshot| snapshot.vbat > max_voltage)
+}
+
+async fn daq<I>(
+	i2c: &mut I,
+	bat_present: &BatteryInput,
+	pwm_ctrl: &mut Heater,
 	daq_queue: &mut DaqDataQueue,
+	power_check: &mut PowerCheck,
 	allow_undercurrent: AllowUndercurrent,
-) -> Result<Option<Measurement>, FaultKind> {
-	if bat_present.is_low() {
+	sensors: Sensors,
+) -> Result<Option<Measurement>, FaultKind>
+where
+	I: I2c,
+	I::Error: I2cErrorToCommon + defmt::Format,
+{
+	if !bat_present.is_present() {
 		error!("Battery disconnected");
 		return Err(FaultKind::NoBattery);
 	}
 
+	let trim = settings::trim();
+
 	// IBat
-	let milliamps = ina260::get_amps(INA260_VIN_ADDRESS, i2c)
+	let milliamps = ina260::get_amps(INA260_VIN_ADDRESS, i2c, &trim)
 		.await
-		.map_err(|e| FaultKind::I2C(I2CError::InaVinCurrent(twim_err_to_common(e))))
+		.map_err(|e| FaultKind::I2C(I2CError::InaVinCurrent(e.to_common())))
 		.inspect_err(|f| error!("I2C read milliamps error:\n{}", f))?;
 
-	if bat_present.is_low() {
+	if !bat_present.is_present() {
 		error!("Battery disconnected");
 		return Err(FaultKind::NoBattery);
 	}
 
 	// VBat
-	let millivolts = ina260::get_voltage(INA260_VIN_ADDRESS, i2c)
+	let millivolts = ina260::get_voltage(INA260_VIN_ADDRESS, i2c, &trim)
 		.await
-		.map_err(|e| FaultKind::I2C(I2CError::InaVinVoltage(twim_err_to_common(e))))
+		.map_err(|e| FaultKind::I2C(I2CError::InaVinVoltage(e.to_common())))
 		.inspect_err(|f| error!("I2C read millivolts error:\n{}", f))?;
 
+	// PBat, checks that V and I are believable and is reported for the energy taken out
+	let milliwatts = ina260::get_power(INA260_VIN_ADDRESS, i2c, &trim)
+		.await
+		.map_err(|e| FaultKind::I2C(I2CError::InaVinPower(e.to_common())))
+		.inspect_err(|f| error!("I2C read milliwatts error:\n{}", f))?;
+	power_check.check(millivolts, milliamps, milliwatts)?;
+
 	// IBat in range/heater fault check
+	pwm_ctrl.update(milliamps);
 	pwm_ctrl.watchdog(millivolts, milliamps, allow_undercurrent)?;
 
-	Ok(daq_queue
-		.push(milliamps, millivolts)
-		.map(daq_to_measurement))
+	// the heater branch, it's only logged, the battery side is what's checked
+	let load = if sensors.load_ina260 {
+		let milliamps = ina260::get_amps(INA260_LOAD_ADDRESS, i2c, &Trim::DEFAULT)
+			.await
+			.map_err(|e| FaultKind::I2C(I2CError::InaLoadCurrent(e.to_common())))
+			.inspect_err(|f| error!("I2C read load milliamps error:\n{}", f))?;
+		let millivolts = ina260::get_voltage(INA260_LOAD_ADDRESS, i2c, &Trim::DEFAULT)
+			.await
+			.map_err(|e| FaultKind::I2C(I2CError::InaLoadVoltage(e.to_common())))
+			.inspect_err(|f| error!("I2C read load millivolts error:\n{}", f))?;
+		Some(LoadChannel {
+			millivolts,
+			milliamps,
+		})
+	} else {
+		None
+	};
+
+	let now_ms = Instant::now().as_millis();
+	let Some((window, load)) = daq_queue.push(milliamps, millivolts, milliwatts, load, now_ms)
+	else {
+		return Ok(None);
+	};
+
+	// Temperature, once per averaging window is plenty
+	let temp_centi_c = if sensors.sht4x {
+		let reading = sht4x::measure(SHT4X_ADDRESS, i2c, sht4x::Command::MEASURE_MEDIUM)
+			.await
+			.map_err(|e| FaultKind::I2C(sht4x_err_to_common(e)))
+			.inspect_err(|f| error!("I2C read temperature error:\n{}", f))?;
+		if reading.temp_centi_c > OVER_TEMPERATURE_CENTI_C {
+			error!("over temperature: {} c°C", reading.temp_centi_c);
+			return Err(FaultKind::OverTemperature);
+		}
+		Some(reading.temp_centi_c)
+	} else {
+		None
+	};
+
+	let load_temp_centi_c = load_temp::check()?;
+	let fan_on = pwm_ctrl.update_fan(load_temp_centi_c);
+
+	Ok(Some(daq_to_measurement(
+		window,
+		temp_centi_c,
+		load_temp_centi_c,
+		fan_on,
+		load,
+	)))
+}
+
+/// Checks the INA260s still answer and, with `pulse`, that the load draws what its
+/// [`battery_tester_common::LoadModel`] expects. The load is left off.
+async fn self_test<I>(
+	i2c: &mut I,
+	pwm_ctrl: &mut Heater,
+	sensors: Sensors,
+	watchdog: &mut WatchdogHandle,
+	pulse: bool,
+) -> SelfTestReport
+where
+	I: I2c,
+	I::Error: I2cErrorToCommon + defmt::Format,
+{
+	let ina_vin = ina260::check_id(INA260_VIN_ADDRESS, i2c)
+		.await
+		.map(|_id| ())
+		.map_err(|e| ina260_err_to_common(e, I2CError::InaVinId));
+	let ina_load = if sensors.load_ina260 {
+		let checked = ina260::check_id(INA260_LOAD_ADDRESS, i2c)
+			.await
+			.map(|_id| ())
+			.map_err(|e| match e {
+				ina260::Error::NotIna260 { .. } => I2CError::InaLoadNotIna260,
+				e => ina260_err_to_common(e, I2CError::InaLoadId),
+			});
+		Some(checked)
+	} else {
+		None
+	};
+	// the battery side measures the pulse
+	let load = match ina_vin {
+		Ok(()) if pulse => Some(pulse_load(i2c, pwm_ctrl, watchdog).await),
+		_ => None,
+	};
+	SelfTestReport {
+		ina_vin,
+		ina_load,
+		load,
+	}
+}
+
+/// Turns the load on through its ramp and measures it once it's had time to settle,
+/// over the load's full current it's turned off straight away
+async fn pulse_load<I>(
+	i2c: &mut I,
+	pwm_ctrl: &mut Heater,
+	watchdog: &mut WatchdogHandle,
+) -> Result<LoadPulse, I2CError>
+where
+	I: I2c,
+	I::Error: I2cErrorToCommon + defmt::Format,
+{
+	/// After the ramp, long enough for a fresh conversion, see [`ina260_config`]
+	const SETTLE_MS: u64 = 300;
+	/// How often the ramp is stepped
+	const STEP_MS: u64 = 20;
+	let trim = settings::trim();
+	// the full load, the PC's next command sets its constant current again
+	pwm_ctrl.set_current_setpoint(None);
+	pwm_ctrl.set_cmd(HeaterCmd::On);
+	let settled = Instant::now() + Duration::from_millis(HEATER_RAMP_MS + SETTLE_MS);
+	let mut ticker = Ticker::every(Duration::from_millis(STEP_MS));
+	let measured = async {
+		loop {
+			ticker.next().await;
+			watchdog.pet();
+			let ibat = ina260::get_amps(INA260_VIN_ADDRESS, i2c, &trim)
+				.await
+				.map_err(|e| I2CError::InaVinCurrent(e.to_common()))?;
+			let vbat = ina260::get_voltage(INA260_VIN_ADDRESS, i2c, &trim)
+				.await
+				.map_err(|e| I2CError::InaVinVoltage(e.to_common()))?;
+			pwm_ctrl.update(ibat);
+			let (expected_min, expected_max) = pwm_ctrl.load_model().current_range(vbat);
+			if ibat > expected_max || Instant::now() >= settled {
+				return Ok(LoadPulse {
+					vbat,
+					ibat,
+					expected_min,
+					expected_max,
+				});
+			}
+		}
+	}
+	.await;
+	pwm_ctrl.set_cmd(HeaterCmd::Off);
+	measured
+}
+
+/// Kept in flash for [`CommandKind::DumpFaultLog`], if it can't be saved it's kept until a restart
+fn log_fault(fault: Fault, snapshot: Option<FaultSnapshot>) {
+	let logged = LoggedFault { fault, snapshot };
+	if with_flash(|flash| fault_log::record(flash, logged)).is_err() {
+		error!("fault log not saved");
+	}
+}
+
+/// The serial in task saves the trim, the power task the fault log
+fn with_flash<R>(f: impl FnOnce(&mut Nvmc<'static>) -> R) -> R {
+	// set in main before the tasks are spawned
+	FLASH.lock(|flash| f(flash.borrow_mut().as_mut().unwrap()))
+}
+
+/// Runs `fut` while feeding the watchdog, for waits on the operator rather than the hardware
+async fn fed<F: Future>(watchdog: &mut WatchdogHandle, fut: F) -> F::Output {
+	match select(fut, feed(watchdog)).await {
+		Either::First(output) => output,
+		Either::Second(never) => never,
+	}
+}
+
+/// Runs `fut` keeping what the battery input reads current for the replies,
+/// for waits that don't watch it themselves
+async fn tracked<F: Future>(bat_present: &mut BatteryInput, fut: F) -> F::Output {
+	match select(fut, bat_present.track()).await {
+		Either::First(output) => output,
+		Either::Second(never) => never,
+	}
+}
+
+async fn feed(watchdog: &mut WatchdogHandle) -> ! {
+	loop {
+		watchdog.pet();
+		Timer::after_millis(WATCHDOG_FEED_MS).await;
+	}
 }
 
 async fn wait_fault_clear(btn_a: &mut Input<'static>, fault: Fault) {
+	display::show(Shown::Fault(fault.kind));
+	speaker::alert(Alert::Fault);
 	loop {
-		loop {
-			match select(CMD_CH.receive(), btn_a.wait_for_falling_edge()).await {
-				Either::First(cmd) => {
-					// send reply
-					if let ClearFault::Yes = cmd.clear_fault {
-						let reply = BIReply {
-							measurement: None,
-							fault: Ok(()),
-						};
-						REPLY_CH.send(reply).await;
-						return;
-					}
-					let reply = BIReply {
-						measurement: None,
-						fault: Err(fault),
-					};
-					REPLY_CH.send(reply).await;
-				}
-				Either::Second(_btn_a_fell) => break,
+		// until button A falls
+		while let Either::First((seq, cmd)) =
+			select(CMD_CH.receive(), btn_a.wait_for_falling_edge()).await
+		{
+			// send reply
+			if let Some(ControlWord {
+				clear_fault: ClearFault::Yes,
+				..
+			}) = cmd
+			{
+				REPLY_CH.send(status_reply(seq, None, Ok(()), false)).await;
+				return;
 			}
+			REPLY_CH
+				.send(status_reply(seq, None, Err(fault), false))
+				.await;
 		}
 		// debounce - wait for button to be down for 1 second (1000 ms)
 		let mut ticker = Ticker::every(Duration::from_millis(1000));
@@ -313,184 +1035,236 @@ async fn wait_fault_clear(btn_a: &mut Input<'static>, fault: Fault) {
 			// hold for 1 second (1000 ms)
 			match select3(ticker.next(), btn_a.wait_for_high(), CMD_CH.receive()).await {
 				Either3::First(_held_for_time) => {
-					let reply = BIReply {
-						measurement: None,
-						fault: Ok(()),
-					};
-					REPLY_CH.send(reply).await;
+					// nothing asked for this one
+					REPLY_CH
+						.send(status_reply(UNSOLICITED_SEQ, None, Ok(()), false))
+						.await;
 					return;
 				}
 				Either3::Second(_released_too_soon) => break,
-				Either3::Third(cmd) => {
+				Either3::Third((seq, cmd)) => {
 					// send reply
-					if let ClearFault::Yes = cmd.clear_fault {
-						let reply = BIReply {
-							measurement: None,
-							fault: Ok(()),
-						};
-						REPLY_CH.send(reply).await;
+					if let Some(ControlWord {
+						clear_fault: ClearFault::Yes,
+						..
+					}) = cmd
+					{
+						REPLY_CH.send(status_reply(seq, None, Ok(()), false)).await;
 						return;
 					}
-					let reply = BIReply {
-						measurement: None,
-						fault: Err(fault),
-					};
-					REPLY_CH.send(reply).await;
+					REPLY_CH
+						.send(status_reply(seq, None, Err(fault), false))
+						.await;
 				}
 			}
 		}
 	}
 }
 
-fn daq_to_measurement(pwr: (MilliVolt, MilliAmp, Instant, Duration)) -> Measurement {
+fn status_reply(
+	seq: u32,
+	measurement: Option<Measurement>,
+	fault: Result<(), Fault>,
+	cutoff_reached: bool,
+) -> BIReply {
+	BIReply {
+		seq,
+		bat_present: battery::present(),
+		load: pwm::load_state(),
+		kind: ReplyKind::Status(Status {
+			measurement,
+			fault,
+			cutoff_reached,
+			local_load: None,
+			reset_reason: None,
+			wants_control: true,
+		}),
+	}
+}
+
+fn daq_to_measurement(
+	window: Window,
+	temp_centi_c: Option<i16>,
+	load_temp_centi_c: Option<i16>,
+	fan_on: bool,
+	load: Option<LoadChannel>,
+) -> Measurement {
 	Measurement {
-		vbat: pwr.0,
-		ibat: pwr.1,
-		dt: pwr.2.as_millis(),
-		duration: pwr.3.as_millis(),
+		vbat: window.millivolts,
+		ibat: window.milliamps,
+		milliwatts: window.milliwatts,
+		sample_index: next_sample_index(),
+		sample_start_ms: window.start_ms,
+		sample_duration_ms: window.duration_ms,
+		temp_centi_c,
+		load_temp_centi_c,
+		fan_on,
+		load,
 	}
 }
 
-async fn wait_bat_present(input: &mut Input<'static>, ms: u64) {
+async fn wait_bat_present(input: &mut BatteryInput) {
 	loop {
 		// wait for battery connection
 		loop {
-			match select(input.wait_for_high(), CMD_CH.receive()).await {
+			match select(input.wait_for_present(), CMD_CH.receive()).await {
 				Either::First(_battery_present) => break,
-				Either::Second(_cmd) => {
-					let reply = BIReply {
-						measurement: None,
-						fault: Ok(()),
-					};
-					REPLY_CH.send(reply).await;
+				Either::Second((seq, _cmd)) => {
+					REPLY_CH.send(status_reply(seq, None, Ok(()), false)).await;
 				}
 			}
 		}
 
-		// debounce - wait for battery to be connected for "ms" time
-		let mut ticker = Ticker::every(Duration::from_millis(ms));
+		// debounce - wait for battery to be connected for the configured time
+		let debounce_ms = settings::battery_detect().debounce_ms;
+		let mut ticker = Ticker::every(Duration::from_millis(debounce_ms.into()));
 		loop {
-			match select3(ticker.next(), input.wait_for_low(), CMD_CH.receive()).await {
+			match select3(ticker.next(), input.wait_for_absent(), CMD_CH.receive()).await {
 				Either3::First(_timer_passed) => {
-					// timer passed and the input never went from hi to low
+					// timer passed and the input never stopped reading present
 					info!("battery connected");
 					return;
 				}
 				Either3::Second(_battery_dc) => {
-					// input went low (battery dc) before timer ended
-					// wait for rising edge again
+					// input went absent (battery dc) before timer ended
+					// wait for it to read present again
 					break;
 				}
-				Either3::Third(_cmd) => {
-					let reply = BIReply {
-						measurement: None,
-						fault: Ok(()),
-					};
-					REPLY_CH.send(reply).await;
+				Either3::Third((seq, _cmd)) => {
+					REPLY_CH.send(status_reply(seq, None, Ok(()), false)).await;
 				}
 			}
 		}
 	}
 }
 
-/// Wait for the battery to connect and stay connected for ms - milliseconds
+/// Wait for the battery to connect and stay connected for the debounce time
 /// If the battery was already connected it must be disconneted and reconnected
-async fn wait_bat_reconnect(input: &mut Input<'static>, ms: u64) {
+async fn wait_bat_reconnect(input: &mut BatteryInput) {
 	loop {
 		// wait for initial battery connection
 		loop {
-			match select(input.wait_for_rising_edge(), CMD_CH.receive()).await {
+			match select(input.wait_for_connect(), CMD_CH.receive()).await {
 				Either::First(_initial_contact) => break,
-				Either::Second(_cmd) => {
-					let reply = BIReply {
-						measurement: None,
-						fault: Ok(()),
-					};
-					REPLY_CH.send(reply).await;
+				Either::Second((seq, _cmd)) => {
+					REPLY_CH.send(status_reply(seq, None, Ok(()), false)).await;
 				}
 			}
 		}
 
-		// debounce - wait for battery to be connected for "ms" time
-		let mut ticker = Ticker::every(Duration::from_millis(ms));
+		// debounce - wait for battery to be connected for the configured time
+		let debounce_ms = settings::battery_detect().debounce_ms;
+		let mut ticker = Ticker::every(Duration::from_millis(debounce_ms.into()));
 		loop {
-			match select3(ticker.next(), input.wait_for_low(), CMD_CH.receive()).await {
+			match select3(ticker.next(), input.wait_for_absent(), CMD_CH.receive()).await {
 				Either3::First(_timer_passed) => {
-					// timer passed and the input never went from hi to low
+					// timer passed and the input never stopped reading present
 					info!("battery connected");
 					return;
 				}
 				Either3::Second(_battery_dc) => {
-					// input went low (battery dc) before timer ended
-					// wait for rising edge again
+					// input went absent (battery dc) before timer ended
+					// wait for it to read present again
 					break;
 				}
-				Either3::Third(_cmd) => {
-					let reply = BIReply {
-						measurement: None,
-						fault: Ok(()),
-					};
-					REPLY_CH.send(reply).await;
+				Either3::Third((seq, _cmd)) => {
+					REPLY_CH.send(status_reply(seq, None, Ok(()), false)).await;
 				}
 			}
 		}
 	}
 }
 
-async fn i2c_init_loop(i2c: &mut I2C, fault_clear_btn: &mut Input<'static>) {
+/// Returns which of the optional sensors were found
+async fn i2c_init_loop<I>(
+	i2c: &mut I,
+	bat_present: &mut BatteryInput,
+	fault_clear_btn: &mut Input<'static>,
+	watchdog: &mut WatchdogHandle,
+) -> Sensors
+where
+	I: I2c,
+	I::Error: I2cErrorToCommon + defmt::Format,
+{
 	loop {
+		watchdog.pet();
 		match init_i2c(i2c).await {
-			Ok(_) => break,
+			Ok(sensors) => break sensors,
 			Err(fault) => {
 				error!("I2C init error:\n{}", fault);
-				wait_fault_clear(fault_clear_btn, fault).await;
+				log_fault(fault, None);
+				fed(
+					watchdog,
+					tracked(bat_present, wait_fault_clear(fault_clear_btn, fault)),
+				)
+				.await;
+				display::show(Shown::Idle);
 			}
 		}
 	}
 }
 
-async fn init_i2c(i2c: &mut I2C) -> Result<(), Fault> {
-	// adress is GND, GND (both pads not connected).
-	info!("init_i2c()");
+/// Sets the sample rate, the DAQ takes a sample each time a conversion finishes.
+/// 256 sample average * 204 us conv time * 2 (both I & V) = 104.4 ms per measurement
+fn ina260_config() -> INA260Config {
 	let mut conf = INA260Config::new();
-	// 4 sample average * 4.156 ms conv time * 2 (both I & V) = 33.248 ms per measurement
-	conf.set_averaging_mode(Averaging::AVG4)
+	conf.set_averaging_mode(Averaging::AVG256)
 		.set_operating_mode(OperMode::SCBVC)
-		.set_sccov_time(SCConvTime::MS4_156)
-		.set_bvcov_time(BVConvTime::MS4_156);
+		.set_sccov_time(SCConvTime::US204)
+		.set_bvcov_time(BVConvTime::US204);
+	conf
+}
 
-	info!("write ina configs");
-	ina260::set_config(INA260_VIN_ADDRESS, i2c, conf)
+async fn init_i2c<I>(i2c: &mut I) -> Result<Sensors, Fault>
+where
+	I: I2c,
+	I::Error: I2cErrorToCommon + defmt::Format,
+{
+	// adress is GND, GND (both pads not connected).
+	info!("init_i2c()");
+	let id = ina260::check_id(INA260_VIN_ADDRESS, i2c)
 		.await
-		.map_err(|e| {
-			let kind = FaultKind::I2C(I2CError::InaVinConfig(twim_err_to_common(e)));
-			Fault {
-				kind,
-				time: Instant::now().as_millis(),
-			}
-		})?;
-
-	let mut rd_buffer = [0u8; 2];
-	i2c.write_read(
-		INA260_VIN_ADDRESS,
-		&[Register::DIE_ID.addr()],
-		&mut rd_buffer,
-	)
-	.await
-	.map_err(|e| {
-		let kind = FaultKind::I2C(I2CError::InaVinId(twim_err_to_common(e)));
-		Fault {
-			kind,
-			time: Instant::now().as_millis(),
-		}
-	})?;
-	let id = u16::from_be_bytes(rd_buffer);
-	let chip_id = id >> 4;
-	let die_rev_id = id & 0b1111;
-
+		.map_err(|e| init_fault(ina260_err_to_common(e, I2CError::InaVinId)))?;
 	info!(
 		"setup VIN INA260... CHIP ID: {}, DIE REV: {}",
-		chip_id, die_rev_id
+		id.chip_id, id.revision
 	);
-	Ok(())
+
+	info!("write ina configs");
+	ina260::set_config(INA260_VIN_ADDRESS, i2c, ina260_config())
+		.await
+		.map_err(|e| init_fault(ina260_err_to_common(e, I2CError::InaVinConfig)))?;
+
+	// the temperature sensor is optional, test without it if it's not there
+	let sht4x = match sht4x::serial_number(SHT4X_ADDRESS, i2c).await {
+		Ok(serial) => {
+			info!("found SHT4x, serial number: {}", serial);
+			true
+		}
+		Err(e) => {
+			info!("no SHT4x, measuring without temperature: {}", e);
+			false
+		}
+	};
+
+	// so is the load side INA260, it converts in step with the VIN one
+	let load_ina260 = match ina260::set_config(INA260_LOAD_ADDRESS, i2c, ina260_config()).await {
+		Ok(()) => {
+			info!("found the load INA260");
+			true
+		}
+		Err(e) => {
+			info!("no load INA260, measuring the battery side only: {}", e);
+			false
+		}
+	};
+
+	Ok(Sensors { sht4x, load_ina260 })
+}
+
+fn init_fault(i2c_error: I2CError) -> Fault {
+	Fault {
+		kind: FaultKind::I2C(i2c_error),
+		time: Instant::now().as_millis(),
+	}
 }
@@ -0,0 +1,42 @@
+//! Measurements taken while the PC isn't sending commands, kept in RAM and replayed
+//! with their own timestamps once it is, so a comms glitch doesn't leave a gap in the test.
+
+use core::cell::RefCell;
+
+use battery_tester_common::Measurement;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use heapless::Deque;
+
+/// About 8 minutes at one measurement a second, the oldest are dropped after that
+pub const OFFLINE_LEN: usize = 512;
+/// Replayed before each status, few enough that the command it answers isn't held up
+pub const REPLAY_BATCH: usize = 16;
+
+static BACKLOG: Mutex<CriticalSectionRawMutex, RefCell<Deque<Measurement, OFFLINE_LEN>>> =
+	Mutex::new(RefCell::new(Deque::new()));
+
+/// Keep `measurement` for the PC, dropping the oldest if it's full
+pub fn push(measurement: Measurement) {
+	BACKLOG.lock(|backlog| {
+		let mut backlog = backlog.borrow_mut();
+		if backlog.is_full() {
+			backlog.pop_front();
+		}
+		// can't fail, there's room now
+		let _ = backlog.push_back(measurement);
+	})
+}
+
+/// Oldest first
+pub fn pop() -> Option<Measurement> {
+	BACKLOG.lock(|backlog| backlog.borrow_mut().pop_front())
+}
+
+pub fn is_empty() -> bool {
+	BACKLOG.lock(|backlog| backlog.borrow().is_empty())
+}
+
+/// The measurements belong to the battery before a reset or fault
+pub fn clear() {
+	BACKLOG.lock(|backlog| backlog.borrow_mut().clear())
+}
@@ -1,40 +1,147 @@
 use core::prelude::v1::Err;
-
-use battery_tester_common::{AllowUndercurrent, FaultKind};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use battery_tester_common::{
+	AllowUndercurrent, FaultKind, LoadModel, LoadState,
+	pwm::{
+		PWM_MAX_DUTY, PWM_MAX_OUTPUT, PWM_ZERO_OUTPUT, Range, current_in_range, pwm_output_trim,
+		ramp_duty, regulate,
+	},
+};
 // use battery_tester_common::HeaterCmd;
 use defmt::{error, info};
-use embassy_nrf::pwm::{Prescaler, SimplePwm};
 use embassy_time::Instant;
+use embedded_hal::{digital::OutputPin, pwm::SetDutyCycle};
+
+use crate::{MilliAmp, MilliVolt, fan::Fan, settings};
+
+/// The heater's last command, for the replies to the PC
+static LOAD_ON: AtomicBool = AtomicBool::new(false);
+
+/// Samples in a row over 100 mA with the load off before it's taken to be stuck on,
+/// rather than one noisy reading
+const STUCK_ON_SAMPLES: u8 = 5;
 
-use crate::{MilliAmp, MilliVolt};
+/// What the load is set to, ramping up counts as on
+pub fn load_state() -> LoadState {
+	if LOAD_ON.load(Ordering::Relaxed) {
+		LoadState::On
+	} else {
+		LoadState::Off
+	}
+}
 
-pub struct PwmCtrl {
+/// Drives the heater through a servo rate PWM, 20 ms periods, and the fan from `O`,
+/// see [`crate::board::ServoPwm`] for the micro:bit's
+pub struct PwmCtrl<P, O> {
 	cmd: HeaterCmd,
-	pwm: SimplePwm<'static>,
+	pwm: P,
 	change_time: Instant,
+	/// What the load should draw when it's on
+	load_model: LoadModel,
+	/// How long the load takes to come up to full after it's turned on
+	ramp_ms: u64,
+	/// Current to hold with the load on, `None` for full on
+	setpoint: Option<MilliAmp>,
+	/// Pulse width the constant current loop has settled on
+	regulated: u16,
+	/// Cools the heater, follows the load
+	fan: Fan<O>,
+	/// Samples in a row with current flowing while the load is off
+	stuck_samples: u8,
 }
 
-impl PwmCtrl {
-	pub fn new(mut pwm: SimplePwm<'static>) -> Self {
-		init_pwm_out(&mut pwm);
-		Self {
+impl<P: SetDutyCycle, O: OutputPin> PwmCtrl<P, O> {
+	pub fn new(pwm: P, fan: Fan<O>, ramp_ms: u64) -> Self {
+		let mut pwm_ctrl = Self {
 			cmd: HeaterCmd::default(),
 			pwm,
 			change_time: Instant::now(),
-		}
+			load_model: LoadModel::default(),
+			ramp_ms,
+			setpoint: None,
+			regulated: PWM_ZERO_OUTPUT,
+			fan,
+			stuck_samples: 0,
+		};
+		pwm_ctrl.set_duty(PWM_ZERO_OUTPUT);
+		info!("init pwm");
+		pwm_ctrl
 	}
 
-	/// sets pwm output based on desired heater state
+	/// sets pwm output based on desired heater state,
+	/// turning on starts a ramp that [`PwmCtrl::update`] steps up to full
 	pub fn set_cmd(&mut self, new_cmd: HeaterCmd) {
-		set_pwm(&mut self.pwm, new_cmd);
 		match (self.cmd, new_cmd) {
 			// if there was a change record the time
 			(HeaterCmd::Off, HeaterCmd::On) | (HeaterCmd::On, HeaterCmd::Off) => {
 				self.change_time = Instant::now();
+				self.regulated = PWM_ZERO_OUTPUT;
+				self.stuck_samples = 0;
 			}
 			_ => {}
 		};
-		self.cmd = new_cmd
+		self.cmd = new_cmd;
+		LOAD_ON.store(new_cmd == HeaterCmd::On, Ordering::Relaxed);
+		self.set_duty(self.duty());
+	}
+
+	pub fn cmd(&self) -> HeaterCmd {
+		self.cmd
+	}
+
+	/// Steps the pwm output up while the load ramps on and toward the setpoint,
+	/// once for each sample of `milliamps`
+	pub fn update(&mut self, milliamps: MilliAmp) {
+		if let (HeaterCmd::On, Some(setpoint)) = (self.cmd, self.setpoint) {
+			self.regulated = regulate(self.duty(), setpoint, milliamps);
+		}
+		self.set_duty(self.duty());
+	}
+
+	/// Turning off is immediate, on is ramped up to full or the constant current
+	fn duty(&self) -> u16 {
+		match self.cmd {
+			HeaterCmd::Off => PWM_ZERO_OUTPUT,
+			HeaterCmd::On => {
+				let dt = Instant::now() - self.change_time;
+				let ramp = ramp_duty(dt.as_millis(), self.ramp_ms);
+				match self.setpoint {
+					Some(_) => self.regulated.min(ramp),
+					None => ramp,
+				}
+			}
+		}
+	}
+
+	/// `duty` is the pulse width in µs, trimmed by [`battery_tester_common::Trim::pwm_us`]
+	fn set_duty(&mut self, duty: u16) {
+		let pulse_us = pwm_output_trim(duty, settings::trim().pwm_us);
+		if self
+			.pwm
+			.set_duty_cycle_fraction(pulse_us, PWM_MAX_DUTY)
+			.is_err()
+		{
+			error!("heater PWM not set");
+		}
+	}
+
+	/// Once for each measurement, returns whether the fan's running
+	pub fn update_fan(&mut self, load_temp_centi_c: Option<i16>) -> bool {
+		self.fan.update(self.cmd, load_temp_centi_c);
+		self.fan.is_on()
+	}
+
+	pub fn set_current_setpoint(&mut self, setpoint: Option<MilliAmp>) {
+		self.setpoint = setpoint;
+	}
+
+	pub fn set_load_model(&mut self, load_model: LoadModel) {
+		self.load_model = load_model;
+	}
+
+	pub fn load_model(&self) -> LoadModel {
+		self.load_model
 	}
 
 	/// IBat in range/heater fault check
@@ -51,21 +158,30 @@ impl PwmCtrl {
 		const WAIT_MS: u64 = (PWM_MS_PERIOD + HW_REACTION_MS) as u64;
 
 		let dt = Instant::now() - self.change_time;
+		// the current is below full until the ramp is done
+		let ramping = dt.as_millis() <= self.ramp_ms + WAIT_MS;
 		if dt.as_millis() > WAIT_MS {
 			match self.cmd {
 				HeaterCmd::Off => {
+					// a charger pulling current the other way is fine
 					if milliamps > MilliAmp::new(100) {
-						error!("Current above expected");
-						Err(FaultKind::Overcurrent)
+						self.stuck_samples = self.stuck_samples.saturating_add(1);
+					} else {
+						self.stuck_samples = 0;
+					}
+					if self.stuck_samples >= STUCK_ON_SAMPLES {
+						error!("Current with the load off: {}", milliamps);
+						Err(FaultKind::LoadStuckOn)
 					} else {
 						Ok(())
 					}
 				}
-				HeaterCmd::On => match current_in_range(millivolts, milliamps) {
+				HeaterCmd::On => match self.on_range(millivolts, milliamps) {
 					Range::Hi => {
 						error!("Current above expected");
 						Err(FaultKind::Overcurrent)
 					}
+					Range::Lo if ramping => Ok(()),
 					Range::Lo => match allow_undercurrent {
 						AllowUndercurrent::No => {
 							error!("Current below expected");
@@ -80,6 +196,21 @@ impl PwmCtrl {
 			Ok(())
 		}
 	}
+
+	/// Over the load's full current is always a fault. Under the setpoint only is
+	/// when the loop is already full on, the battery can't push the setpoint through the load.
+	fn on_range(&self, millivolts: MilliVolt, milliamps: MilliAmp) -> Range {
+		let full = current_in_range(&self.load_model, millivolts, milliamps);
+		let Some(setpoint) = self.setpoint else {
+			return full;
+		};
+		let min = i16::from(setpoint).saturating_sub(i16::from(self.load_model.max_deviation));
+		match full {
+			Range::Hi => Range::Hi,
+			_ if i16::from(milliamps) < min && self.regulated >= PWM_MAX_OUTPUT => Range::Lo,
+			_ => Range::Ok,
+		}
+	}
 }
 
 #[derive(defmt::Format, Copy, Clone, Debug, Default, PartialEq, Eq)]
@@ -106,83 +237,3 @@ impl PartialOrd for HeaterCmd {
 		Some(self.cmp(other))
 	}
 }
-
-#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Range {
-	Hi,
-	Lo,
-	Ok,
-}
-
-pub fn in_range_inclusive<V>(max: V, min: V, x: V) -> Range
-where
-	V: Copy + Ord,
-{
-	if x > max {
-		Range::Hi
-	} else if x < min {
-		Range::Lo
-	} else {
-		Range::Ok
-	}
-}
-
-pub fn expected_current(vbat: MilliVolt) -> MilliAmp {
-	const TEST_MILLIVOLTS: u16 = 12_000;
-	// TODO: test this
-	const IMPERICAL_MILLIAMPS: u16 = 8_400;
-	/// calculate system resistance (R = V / I)
-	const R: u16 = TEST_MILLIVOLTS / IMPERICAL_MILLIAMPS;
-	// I = V / R
-	MilliAmp::new(Into::<u16>::into(vbat) / R)
-}
-
-pub fn current_in_range(vbat: MilliVolt, ibat: MilliAmp) -> Range {
-	const MAX_DEVIATION: u16 = 200;
-	let nom = expected_current(vbat);
-	let max = MilliAmp::new(Into::<u16>::into(nom) + MAX_DEVIATION);
-	let min = MilliAmp::new(Into::<u16>::into(nom) - MAX_DEVIATION);
-	in_range_inclusive(max, min, ibat)
-}
-
-const PWM_CLOCK_HZ: f64 = 1_000_000.0;
-const PWM_CLOCK_PERIOD: f64 = 1.0 / PWM_CLOCK_HZ;
-const SERVO_HZ: f64 = 50.0;
-const SERVO_PERIOD: f64 = 1.0 / SERVO_HZ;
-const PWM_MAX_DUTY: u16 = (SERVO_PERIOD / PWM_CLOCK_PERIOD) as u16; // 20,000 = 20 ms
-/// this is 1 / (13 + 1/3) of 20 milliseconds (1.5 millis aka 1500 micros)
-pub const PWM_ZERO_OUTPUT: u16 = (PWM_MAX_DUTY as f64 / (13.0 + (1.0 / 3.0))) as u16;
-pub const PWM_MAX_OUTPUT: u16 = PWM_MAX_DUTY / 10;
-
-pub fn init_pwm_out(pwm: &mut SimplePwm<'static>) {
-	pwm.disable();
-	pwm.set_prescaler(Prescaler::Div16); // 1Mhz clock
-	pwm.set_max_duty(PWM_MAX_DUTY);
-	set_pwm(pwm, HeaterCmd::Off);
-	pwm.enable();
-	info!("init pwm");
-}
-
-pub fn percent_to_micros(pwm_on_percent: u8) -> u16 {
-	let pwm_on_percent = pwm_on_percent as u16;
-
-	const MULTIPLYER: u16 = (PWM_MAX_OUTPUT - PWM_ZERO_OUTPUT) / 100;
-
-	let pwm_on_micros = pwm_on_percent * MULTIPLYER;
-
-	pwm_on_micros.clamp(PWM_ZERO_OUTPUT, PWM_MAX_OUTPUT)
-}
-
-pub fn pwm_output_trim(setpoint: u16) -> u16 {
-	// calibrate with o-scope
-	const PLUS_TRIM: u16 = 16;
-	PWM_MAX_DUTY - (setpoint + PLUS_TRIM)
-}
-
-pub fn set_pwm(pwm: &mut SimplePwm<'static>, cmd: HeaterCmd) {
-	let duty = match cmd {
-		HeaterCmd::Off => PWM_ZERO_OUTPUT,
-		HeaterCmd::On => PWM_MAX_OUTPUT,
-	};
-	pwm.set_duty(0, pwm_output_trim(duty));
-}
@@ -0,0 +1,122 @@
+//! Settings kept in the last page of flash, the [`Trim`] measured for this board and the
+//! [`BatteryDetect`] for its fixture.
+//!
+//! Each is saved as a frame, the same as on the serial link, so a blank or half written page
+//! fails its checksum and the defaults are used. The trim comes first, where firmware from
+//! before the battery detection was kept left it.
+
+use core::cell::Cell;
+
+use battery_tester_common::{BatteryDetect, FlashError, Trim, frame};
+use defmt::{error, info};
+use embassy_nrf::nvmc::{FLASH_SIZE, Nvmc, PAGE_SIZE};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use postcard::experimental::max_size::MaxSize;
+
+/// The last page, memory.x leaves it out of the program's flash
+const SETTINGS_OFFSET: u32 = (FLASH_SIZE - PAGE_SIZE) as u32;
+/// Flash is written a word at a time
+const TRIM_SIZE: usize = frame::max_frame_size(Trim::POSTCARD_MAX_SIZE).next_multiple_of(4);
+const BATTERY_DETECT_OFFSET: u32 = SETTINGS_OFFSET + TRIM_SIZE as u32;
+const BATTERY_DETECT_SIZE: usize =
+	frame::max_frame_size(BatteryDetect::POSTCARD_MAX_SIZE).next_multiple_of(4);
+
+/// Read by the power task for every sample
+static TRIM: Mutex<CriticalSectionRawMutex, Cell<Trim>> = Mutex::new(Cell::new(Trim::DEFAULT));
+/// Read by the power task each time it checks for a battery
+static BATTERY_DETECT: Mutex<CriticalSectionRawMutex, Cell<BatteryDetect>> =
+	Mutex::new(Cell::new(BatteryDetect::DEFAULT));
+
+/// The trim in use
+pub fn trim() -> Trim {
+	TRIM.lock(Cell::get)
+}
+
+/// The battery detection in use
+pub fn battery_detect() -> BatteryDetect {
+	BATTERY_DETECT.lock(Cell::get)
+}
+
+/// Use the saved settings, the defaults for any there aren't
+pub fn load(flash: &mut Nvmc<'_>) {
+	let mut stored = [0u8; TRIM_SIZE];
+	let saved = read_frame(flash, SETTINGS_OFFSET, &mut stored)
+		.and_then(|len| frame::decode::<Trim>(&mut stored[..len]).ok());
+	let trim = match saved {
+		Some(trim) => {
+			info!("loaded trim: {}", trim);
+			trim
+		}
+		None => {
+			info!("no saved trim, using the default");
+			Trim::DEFAULT
+		}
+	};
+	TRIM.lock(|t| t.set(trim));
+	let mut stored = [0u8; BATTERY_DETECT_SIZE];
+	let saved = read_frame(flash, BATTERY_DETECT_OFFSET, &mut stored)
+		.and_then(|len| frame::decode::<BatteryDetect>(&mut stored[..len]).ok());
+	let detect = match saved {
+		Some(detect) => {
+			info!("loaded battery detection: {}", detect);
+			detect
+		}
+		None => {
+			info!("no saved battery detection, using the default");
+			BatteryDetect::DEFAULT
+		}
+	};
+	BATTERY_DETECT.lock(|d| d.set(detect));
+}
+
+/// Length of the frame read into `stored`, `None` if there isn't one
+fn read_frame(flash: &mut Nvmc<'_>, offset: u32, stored: &mut [u8]) -> Option<usize> {
+	flash
+		.read(offset, stored)
+		.ok()
+		.and_then(|()| stored.iter().position(|b| *b == frame::FRAME_DELIMITER))
+}
+
+/// Use `trim` and save it for the next start. It's used even if it can't be saved.
+/// The CPU stalls while the page is erased, about 85 ms, the power task catches up after.
+pub fn save_trim(flash: &mut Nvmc<'_>, trim: Trim) -> Result<Trim, FlashError> {
+	TRIM.lock(|t| t.set(trim));
+	save(flash)?;
+	info!("saved trim: {}", trim);
+	Ok(trim)
+}
+
+/// Use `detect` and save it for the next start, the same as [`save_trim`]
+pub fn save_battery_detect(
+	flash: &mut Nvmc<'_>,
+	detect: BatteryDetect,
+) -> Result<BatteryDetect, FlashError> {
+	BATTERY_DETECT.lock(|d| d.set(detect));
+	save(flash)?;
+	info!("saved battery detection: {}", detect);
+	Ok(detect)
+}
+
+/// Both are written each time, the page is erased as a whole
+fn save(flash: &mut Nvmc<'_>) -> Result<(), FlashError> {
+	// erased flash reads 0xFF, the rest of the page is left that way
+	let mut trim = [0xFFu8; TRIM_SIZE];
+	let mut detect = [0xFFu8; BATTERY_DETECT_SIZE];
+	// the buffers always fit the largest possible frame
+	frame::encode(&self::trim(), &mut trim).unwrap();
+	frame::encode(&battery_detect(), &mut detect).unwrap();
+	flash
+		.erase(SETTINGS_OFFSET, SETTINGS_OFFSET + PAGE_SIZE as u32)
+		.map_err(|e| {
+			error!("erase settings error: {}", e);
+			FlashError::Erase
+		})?;
+	flash
+		.write(SETTINGS_OFFSET, &trim)
+		.and_then(|()| flash.write(BATTERY_DETECT_OFFSET, &detect))
+		.map_err(|e| {
+			error!("write settings error: {}", e);
+			FlashError::Write
+		})
+}
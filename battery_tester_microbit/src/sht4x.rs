@@ -0,0 +1,118 @@
+//! Sensirion SHT4x temperature and humidity sensor
+
+use embassy_time::Timer;
+use embedded_hal_async::i2c::I2c;
+
+/// SHT40-AD1B, the other parts use 0x45 and 0x46
+pub const SHT4X_ADDRESS: u8 = 0x44;
+
+#[allow(dead_code)]
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, defmt::Format)]
+pub enum Command {
+	// Measure T & RH with high precision (high repeatability), 8.3 ms
+	MEASURE_HIGH = 0xFD,
+	// Measure T & RH with medium precision (medium repeatability), 4.5 ms
+	MEASURE_MEDIUM = 0xF6,
+	// Measure T & RH with lowest precision (low repeatability), 1.6 ms
+	MEASURE_LOW = 0xE0,
+	// Read serial number
+	SERIAL_NUMBER = 0x89,
+	// Soft reset
+	SOFT_RESET = 0x94,
+}
+
+impl Command {
+	#[inline(always)]
+	pub fn addr(self) -> u8 {
+		self as u8
+	}
+
+	/// Max time from the command until the result can be read
+	pub fn duration_ms(self) -> u64 {
+		match self {
+			Command::MEASURE_HIGH => 9,
+			Command::MEASURE_MEDIUM => 5,
+			Command::MEASURE_LOW | Command::SERIAL_NUMBER | Command::SOFT_RESET => 2,
+		}
+	}
+}
+
+#[derive(Copy, Clone, defmt::Format)]
+pub enum Error<E> {
+	I2C(E),
+	/// Checksum on a reply word was wrong
+	Crc,
+}
+
+impl<E> From<E> for Error<E> {
+	fn from(e: E) -> Self {
+		Error::I2C(e)
+	}
+}
+
+/// Temperature in hundredths of a °C and relative humidity in hundredths of a percent
+#[derive(Copy, Clone, defmt::Format)]
+pub struct Reading {
+	pub temp_centi_c: i16,
+	pub rh_centi_pct: u16,
+}
+
+/// Returns the sensor's unique serial number, good for checking it's there
+pub async fn serial_number<I: I2c>(address: u8, i2c: &mut I) -> Result<u32, Error<I::Error>> {
+	let [high, low] = command(address, i2c, Command::SERIAL_NUMBER).await?;
+	Ok(((high as u32) << 16) | low as u32)
+}
+
+pub async fn measure<I: I2c>(
+	address: u8,
+	i2c: &mut I,
+	precision: Command,
+) -> Result<Reading, Error<I::Error>> {
+	let [raw_t, raw_rh] = command(address, i2c, precision).await?;
+	// T = -45 + 175 * raw / (2^16 - 1), fits i16 as -4500..=13000
+	let temp_centi_c = (17_500 * raw_t as i32 / 65_535 - 4_500) as i16;
+	// RH = -6 + 125 * raw / (2^16 - 1), clamped to what's physically possible
+	let rh_centi_pct = (12_500 * raw_rh as i32 / 65_535 - 600).clamp(0, 10_000) as u16;
+	Ok(Reading {
+		temp_centi_c,
+		rh_centi_pct,
+	})
+}
+
+/// Send a command and read back the two checksummed words every command answers with
+async fn command<I: I2c>(
+	address: u8,
+	i2c: &mut I,
+	cmd: Command,
+) -> Result<[u16; 2], Error<I::Error>> {
+	i2c.write(address, &[cmd.addr()]).await?;
+	Timer::after_millis(cmd.duration_ms()).await;
+	let mut buffer = [0u8; 6];
+	i2c.read(address, &mut buffer).await?;
+	let mut words = [0u16; 2];
+	let (chunks, _rest) = buffer.as_chunks::<3>();
+	for (word, [msb, lsb, crc]) in words.iter_mut().zip(chunks) {
+		if crc8(&[*msb, *lsb]) != *crc {
+			return Err(Error::Crc);
+		}
+		*word = u16::from_be_bytes([*msb, *lsb]);
+	}
+	Ok(words)
+}
+
+/// CRC-8, polynomial 0x31, init 0xFF
+fn crc8(data: &[u8]) -> u8 {
+	let mut crc: u8 = 0xFF;
+	for byte in data {
+		crc ^= byte;
+		for _ in 0..8 {
+			crc = if crc & 0x80 != 0 {
+				(crc << 1) ^ 0x31
+			} else {
+				crc << 1
+			};
+		}
+	}
+	crc
+}
@@ -0,0 +1,91 @@
+//! The micro:bit's speaker, beeps so the rig can be heard across the lab.
+//! It's on its own PWM instance, the heater's has to stay at the servo rate.
+
+use embassy_nrf::pwm::{Prescaler, SimplePwm};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::Timer;
+
+/// Alerts waiting to be played, more than this while one plays are dropped
+static ALERTS: Channel<CriticalSectionRawMutex, Alert, 2> = Channel::new();
+
+#[derive(defmt::Format, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Alert {
+	/// Three long low beeps
+	Fault,
+	/// Two short rising beeps
+	TestComplete,
+}
+
+/// A tone then silence
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Beep {
+	pub hz: u32,
+	pub on_ms: u64,
+	pub off_ms: u64,
+}
+
+const FAULT_BEEP: Beep = Beep {
+	hz: 880,
+	on_ms: 400,
+	off_ms: 200,
+};
+
+impl Alert {
+	pub const fn pattern(self) -> &'static [Beep] {
+		match self {
+			Alert::Fault => &[FAULT_BEEP; 3],
+			Alert::TestComplete => &[
+				Beep {
+					hz: 2_000,
+					on_ms: 150,
+					off_ms: 100,
+				},
+				Beep {
+					hz: 3_000,
+					on_ms: 300,
+					off_ms: 0,
+				},
+			],
+		}
+	}
+}
+
+/// Queues `alert` for the speaker task, it's dropped if the queue is full
+pub fn alert(alert: Alert) {
+	let _ = ALERTS.try_send(alert);
+}
+
+pub struct Speaker {
+	pwm: SimplePwm<'static>,
+}
+
+impl Speaker {
+	pub fn new(pwm: SimplePwm<'static>) -> Self {
+		// 1 MHz clock, 15 bits of it reach down to 31 Hz
+		pwm.set_prescaler(Prescaler::Div16);
+		let mut speaker = Self { pwm };
+		speaker.silence();
+		speaker
+	}
+
+	/// Plays each alert as it's queued
+	pub async fn run(&mut self) -> ! {
+		loop {
+			let alert = ALERTS.receive().await;
+			for beep in alert.pattern() {
+				self.pwm.set_period(beep.hz);
+				let half = self.pwm.max_duty() / 2;
+				self.pwm.set_duty(0, half);
+				Timer::after_millis(beep.on_ms).await;
+				self.silence();
+				Timer::after_millis(beep.off_ms).await;
+			}
+		}
+	}
+
+	/// Holds the pin low, the speaker shouldn't sit with DC across it
+	fn silence(&mut self) {
+		let max = self.pwm.max_duty();
+		self.pwm.set_duty(0, max);
+	}
+}
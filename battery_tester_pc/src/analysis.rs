@@ -0,0 +1,427 @@
+//! Discharge curve analysis of a saved test, `battery-tester-client analyze`.
+//!
+//! Reads TSV, CSV, and JSON Lines files in any `--columns` config, only `sample_start_ms`,
+//! `sample_duration_ms`, the voltage, and the current are needed, older files' `dt` and
+//! `duration` are read as them. The energy is from the power column when there is
+//! one, V × I of the averaged readings otherwise. A test split over several files is read
+//! with its parts in order.
+
+use std::path::Path;
+
+use serde::{Serialize, Serializer};
+use thiserror::Error;
+
+/// Default voltages to report the capacity to. The measurement at the cutoff isn't saved,
+/// the time and capacity to it are the whole test's.
+pub const DEFAULT_THRESHOLDS_MILLIV: [u16; 3] = [12_000, 11_800, 11_500];
+
+#[derive(Debug, Error)]
+pub enum AnalysisError {
+	#[error("can't read test file: {0:?}")]
+	Read(Box<Path>, #[source] std::io::Error),
+	#[error("test file: {0:?} has no {1} column")]
+	MissingColumn(Box<Path>, &'static str),
+	#[error("test file: {0:?} line {1} isn't a sample")]
+	BadLine(Box<Path>, usize),
+	#[error("test file: {0:?} has no samples")]
+	NoSamples(Box<Path>),
+}
+
+/// One row of a test file, in the units the battery interface measures in
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Sample {
+	sample_start_ms: u64,
+	sample_duration_ms: u64,
+	millivolts: f64,
+	milliamps: f64,
+	/// `None` for files saved without the power
+	milliwatts: Option<f64>,
+}
+
+/// How far the test got by the time the battery first reached a voltage
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub struct ThresholdReached {
+	pub millivolts: u16,
+	/// Time testing
+	pub elapsed_ms: u64,
+	pub mah: f64,
+	pub wh: f64,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Analysis {
+	pub samples: usize,
+	/// Time testing, not counting gaps where the test was interrupted
+	pub elapsed_ms: u64,
+	pub mah: f64,
+	pub wh: f64,
+	pub average_milliamps: f64,
+	pub peak_milliamps: f64,
+	pub min_millivolts: f64,
+	/// `None` for thresholds the test never got down to
+	#[serde(serialize_with = "by_millivolts")]
+	pub thresholds: Vec<(u16, Option<ThresholdReached>)>,
+}
+
+/// `{"12000": {...}, "11500": null}` in JSON
+fn by_millivolts<S: Serializer>(
+	thresholds: &[(u16, Option<ThresholdReached>)],
+	serializer: S,
+) -> Result<S::Ok, S::Error> {
+	serializer.collect_map(
+		thresholds
+			.iter()
+			.map(|(millivolts, reached)| (millivolts, reached)),
+	)
+}
+
+/// Analyze a test saved in `paths`, its parts in order, reporting the capacity to each of
+/// `thresholds_milliv`
+pub fn analyze(paths: &[&Path], thresholds_milliv: &[u16]) -> Result<Analysis, AnalysisError> {
+	let mut samples = Vec::new();
+	for path in paths {
+		let table = read_table(path)?;
+		let layout = Layout::new(path, &table.names)?;
+		for (i, row) in table.rows.iter().enumerate() {
+			let sample = layout
+				.sample(|column| row.get(column)?.as_f64())
+				.ok_or_else(|| AnalysisError::BadLine(table.path.clone(), table.lines[i]))?;
+			samples.push(sample);
+		}
+	}
+	Ok(integrate(&samples, thresholds_milliv))
+}
+
+/// Same integration as the mAh column of saved files
+fn integrate(samples: &[Sample], thresholds_milliv: &[u16]) -> Analysis {
+	let mut thresholds: Vec<(u16, Option<ThresholdReached>)> =
+		thresholds_milliv.iter().map(|&mv| (mv, None)).collect();
+	let mut last_dt = None;
+	let (mut elapsed_ms, mut mah, mut wh) = (0, 0.0, 0.0);
+	let (mut peak_milliamps, mut min_millivolts) = (f64::MIN, f64::MAX);
+	for sample in samples {
+		// the battery interface restarts its clock when it's reset, e.g. on a resume
+		let interval_ms = match last_dt {
+			Some(last_dt) if sample.sample_start_ms > last_dt => sample.sample_start_ms - last_dt,
+			_ => sample.sample_duration_ms,
+		};
+		last_dt = Some(sample.sample_start_ms);
+		let hours = interval_ms as f64 / 3_600_000.0;
+		elapsed_ms += interval_ms;
+		mah += sample.milliamps * hours;
+		let milliwatts = sample
+			.milliwatts
+			.unwrap_or(sample.millivolts * sample.milliamps / 1000.0);
+		wh += milliwatts / 1000.0 * hours;
+		peak_milliamps = peak_milliamps.max(sample.milliamps);
+		min_millivolts = min_millivolts.min(sample.millivolts);
+		for (millivolts, reached) in &mut thresholds {
+			if reached.is_none() && sample.millivolts <= f64::from(*millivolts) {
+				*reached = Some(ThresholdReached {
+					millivolts: *millivolts,
+					elapsed_ms,
+					mah,
+					wh,
+				});
+			}
+		}
+	}
+	let hours = elapsed_ms as f64 / 3_600_000.0;
+	Analysis {
+		samples: samples.len(),
+		elapsed_ms,
+		mah,
+		wh,
+		average_milliamps: if hours > 0.0 { mah / hours } else { 0.0 },
+		peak_milliamps,
+		min_millivolts,
+		thresholds,
+	}
+}
+
+/// One value in a test file
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Cell {
+	Int(i64),
+	Float(f64),
+	/// e.g. the temperature without a sensor
+	Missing,
+}
+
+impl Cell {
+	fn parse(field: &str) -> Option<Self> {
+		if field.is_empty() {
+			Some(Cell::Missing)
+		} else if let Ok(i) = field.parse() {
+			Some(Cell::Int(i))
+		} else {
+			field.parse().ok().map(Cell::Float)
+		}
+	}
+
+	fn from_json(value: &serde_json::Value) -> Option<Self> {
+		match value {
+			serde_json::Value::Null => Some(Cell::Missing),
+			value => value
+				.as_i64()
+				.map(Cell::Int)
+				.or_else(|| value.as_f64().map(Cell::Float)),
+		}
+	}
+
+	pub fn as_f64(&self) -> Option<f64> {
+		match *self {
+			Cell::Int(i) => Some(i as f64),
+			Cell::Float(x) => Some(x),
+			Cell::Missing => None,
+		}
+	}
+}
+
+/// A test file as it was saved, with its column names
+#[derive(Debug, PartialEq, Clone)]
+pub struct Table {
+	pub path: Box<Path>,
+	/// In the file's order, sorted for JSON Lines
+	pub names: Vec<String>,
+	pub rows: Vec<Vec<Cell>>,
+	/// Line in the file of each row, for errors
+	lines: Vec<usize>,
+}
+
+/// Read a TSV, CSV, or JSON Lines file, by its extension
+pub fn read_table(path: &Path) -> Result<Table, AnalysisError> {
+	let text = std::fs::read_to_string(path).map_err(|e| AnalysisError::Read(path.into(), e))?;
+	let mut table = Table {
+		path: path.into(),
+		names: Vec::new(),
+		rows: Vec::new(),
+		lines: Vec::new(),
+	};
+	match path.extension().and_then(|ext| ext.to_str()) {
+		Some("jsonl") => json_lines(&text, &mut table)?,
+		Some("csv") => separated(&text, ',', &mut table)?,
+		_ => separated(&text, '\t', &mut table)?,
+	}
+	if table.rows.is_empty() {
+		return Err(AnalysisError::NoSamples(path.into()));
+	}
+	Ok(table)
+}
+
+/// Where the needed columns are, and the scale to millivolts and milliamps
+struct Layout {
+	sample_start_ms: usize,
+	sample_duration_ms: usize,
+	voltage: (usize, f64),
+	current: (usize, f64),
+	power: Option<(usize, f64)>,
+}
+
+impl Layout {
+	fn new(path: &Path, names: &[String]) -> Result<Self, AnalysisError> {
+		let find = |name: &'static str| names.iter().position(|n| n == name);
+		let missing = |name| AnalysisError::MissingColumn(path.into(), name);
+		let voltage = match (find("millivolts"), find("volts")) {
+			(Some(i), _) => (i, 1.0),
+			(None, Some(i)) => (i, 1000.0),
+			(None, None) => return Err(missing("millivolts or volts")),
+		};
+		let current = match (find("milliamps"), find("amps")) {
+			(Some(i), _) => (i, 1.0),
+			(None, Some(i)) => (i, 1000.0),
+			(None, None) => return Err(missing("milliamps or amps")),
+		};
+		let power = match (find("milliwatts"), find("watts")) {
+			(Some(i), _) => Some((i, 1.0)),
+			(None, Some(i)) => Some((i, 1000.0)),
+			(None, None) => None,
+		};
+		Ok(Self {
+			sample_start_ms: find("sample_start_ms")
+				.or_else(|| find("dt"))
+				.ok_or_else(|| missing("sample_start_ms"))?,
+			sample_duration_ms: find("sample_duration_ms")
+				.or_else(|| find("duration"))
+				.ok_or_else(|| missing("sample_duration_ms"))?,
+			voltage,
+			current,
+			power,
+		})
+	}
+
+	fn sample(&self, field: impl Fn(usize) -> Option<f64>) -> Option<Sample> {
+		Some(Sample {
+			sample_start_ms: field(self.sample_start_ms)? as u64,
+			sample_duration_ms: field(self.sample_duration_ms)? as u64,
+			millivolts: field(self.voltage.0)? * self.voltage.1,
+			milliamps: field(self.current.0)? * self.current.1,
+			milliwatts: match self.power {
+				Some((column, scale)) => Some(field(column)? * scale),
+				None => None,
+			},
+		})
+	}
+}
+
+fn separated(text: &str, sep: char, table: &mut Table) -> Result<(), AnalysisError> {
+	let mut lines = text.lines().enumerate();
+	let Some((_, header)) = lines.next() else {
+		return Ok(());
+	};
+	table.names = header.split(sep).map(str::to_string).collect();
+	for (i, line) in lines.filter(|(_, line)| !line.is_empty()) {
+		let row: Option<Vec<Cell>> = line.split(sep).map(Cell::parse).collect();
+		match row {
+			Some(row) if row.len() == table.names.len() => {
+				table.rows.push(row);
+				table.lines.push(i + 1);
+			}
+			_ => return Err(AnalysisError::BadLine(table.path.clone(), i + 1)),
+		}
+	}
+	Ok(())
+}
+
+fn json_lines(text: &str, table: &mut Table) -> Result<(), AnalysisError> {
+	for (i, line) in text
+		.lines()
+		.enumerate()
+		.filter(|(_, line)| !line.is_empty())
+	{
+		let bad_line = || AnalysisError::BadLine(table.path.clone(), i + 1);
+		let object: serde_json::Map<String, serde_json::Value> =
+			serde_json::from_str(line).map_err(|_| bad_line())?;
+		// the keys come out sorted, every line has the same ones
+		if table.names.is_empty() {
+			table.names = object.keys().cloned().collect();
+		}
+		if !object.keys().eq(table.names.iter()) {
+			return Err(bad_line());
+		}
+		let row: Option<Vec<Cell>> = object.values().map(Cell::from_json).collect();
+		table.rows.push(row.ok_or_else(bad_line)?);
+		table.lines.push(i + 1);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn close(a: f64, b: f64) -> bool {
+		(a - b).abs() < 1e-9
+	}
+
+	#[test]
+	fn test_analyze_parts() {
+		let dir =
+			std::env::temp_dir().join(format!("battery-tester-analysis-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		// an older file in volts and amps, without the power
+		let first = dir.join("2025-3.tsv");
+		std::fs::write(
+			&first,
+			"dt\tduration\tvolts\tamps\n0\t1000\t12.1\t3.6\n1000\t1000\t11.9\t3.6\n",
+		)
+		.unwrap();
+		// resumed, the battery interface's clock started over
+		let second = dir.join("2025-3-continued-2.csv");
+		std::fs::write(
+			&second,
+			"sample_start_ms,sample_duration_ms,millivolts,milliamps,milliwatts\n0,1000,11700,7200,36000\n",
+		)
+		.unwrap();
+
+		let analysis = analyze(&[&first, &second], &DEFAULT_THRESHOLDS_MILLIV).unwrap();
+		assert_eq!(analysis.samples, 3);
+		assert_eq!(analysis.elapsed_ms, 3_000);
+		assert!(close(analysis.mah, 4.0), "{}", analysis.mah);
+		// 12.1 V × 3.6 A and 11.9 V × 3.6 A for a second each, then 36 W
+		assert!(close(analysis.wh, 0.034), "{}", analysis.wh);
+		assert!(close(analysis.average_milliamps, 4_800.0));
+		assert_eq!(analysis.peak_milliamps, 7_200.0);
+		assert_eq!(analysis.min_millivolts, 11_700.0);
+		let reached: Vec<_> = analysis
+			.thresholds
+			.iter()
+			.map(|(mv, reached)| (*mv, reached.map(|r| (r.elapsed_ms, r.mah.round()))))
+			.collect();
+		assert_eq!(
+			reached,
+			[
+				(12_000, Some((2_000, 2.0))),
+				(11_800, Some((3_000, 4.0))),
+				(11_500, None)
+			]
+		);
+		let json = serde_json::to_value(&analysis).unwrap();
+		assert!(json["thresholds"]["11500"].is_null());
+		assert_eq!(json["thresholds"]["12000"]["elapsed_ms"], 2_000);
+
+		let jsonl = dir.join("2025-4.jsonl");
+		std::fs::write(
+			&jsonl,
+			"{\"sample_start_ms\":0,\"sample_duration_ms\":1000,\"millivolts\":12100,\"milliamps\":3600,\"temp_centi_c\":null}\n",
+		)
+		.unwrap();
+		let table = read_table(&jsonl).unwrap();
+		assert_eq!(
+			table.names,
+			[
+				"milliamps",
+				"millivolts",
+				"sample_duration_ms",
+				"sample_start_ms",
+				"temp_centi_c"
+			]
+		);
+		assert_eq!(table.rows[0][4], Cell::Missing);
+		assert!(close(analyze(&[&jsonl], &[]).unwrap().mah, 1.0));
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn test_analyze_errors() {
+		let dir = std::env::temp_dir().join(format!(
+			"battery-tester-analysis-errors-{}",
+			std::process::id()
+		));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		let file = |name: &str, text: &str| {
+			let path = dir.join(name);
+			std::fs::write(&path, text).unwrap();
+			path
+		};
+
+		let no_current = file(
+			"no-current.csv",
+			"sample_start_ms,sample_duration_ms,millivolts\n0,1000,12100\n",
+		);
+		assert!(matches!(
+			analyze(&[&no_current], &[]),
+			Err(AnalysisError::MissingColumn(_, "milliamps or amps"))
+		));
+		let short = file(
+			"short.csv",
+			"sample_start_ms,sample_duration_ms,millivolts,milliamps\n0,1000,12100,3600\n\n1000,1000\n",
+		);
+		assert!(matches!(
+			analyze(&[&short], &[]),
+			Err(AnalysisError::BadLine(_, 4))
+		));
+		let header_only = file("header-only.tsv", "sample_start_ms\tmillivolts\n");
+		assert!(matches!(
+			analyze(&[&header_only], &[]),
+			Err(AnalysisError::NoSamples(_))
+		));
+		assert!(matches!(
+			analyze(&[&dir.join("missing.tsv")], &[]),
+			Err(AnalysisError::Read(..))
+		));
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
@@ -0,0 +1,266 @@
+//! Corrections for a battery interface's INA260 measured against a reference meter,
+//! so an offset of a few mA doesn't add up over a test.
+//!
+//! Kept in `calibration.toml` in the output directory, one for each battery interface by
+//! the device ID it gives in the handshake, so a calibration stays with its board whichever
+//! port it's plugged into. Points are taken with `battery-tester-client calibrate point`
+//! and fitted by `calibrate save`.
+//! Applied to measurements while testing, before they're checked and saved.
+
+use std::{
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use battery_tester_common::{Measurement, MilliAmp, MilliVolt, MilliWatt};
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+const CALIBRATION_FILE: &str = "calibration.toml";
+
+/// `corrected = raw * scale + offset`
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct Correction {
+	pub scale: f64,
+	pub offset: f64,
+}
+
+impl Default for Correction {
+	/// Leaves readings as they are
+	fn default() -> Self {
+		Self {
+			scale: 1.0,
+			offset: 0.0,
+		}
+	}
+}
+
+impl Correction {
+	fn apply(&self, raw: f64) -> f64 {
+		raw * self.scale + self.offset
+	}
+
+	/// Least squares line through the `(raw, reference)` points, only an offset for a single
+	/// point or points all at the same reading. `None` without points.
+	pub fn fit(points: &[(f64, f64)]) -> Option<Self> {
+		if points.is_empty() {
+			return None;
+		}
+		let n = points.len() as f64;
+		let mean_raw = points.iter().map(|(raw, _)| raw).sum::<f64>() / n;
+		let mean_reference = points.iter().map(|(_, reference)| reference).sum::<f64>() / n;
+		let spread: f64 = points.iter().map(|(raw, _)| (raw - mean_raw).powi(2)).sum();
+		let scale = if spread > 0.0 {
+			points
+				.iter()
+				.map(|(raw, reference)| (raw - mean_raw) * (reference - mean_reference))
+				.sum::<f64>()
+				/ spread
+		} else {
+			1.0
+		};
+		Some(Self {
+			scale,
+			offset: mean_reference - scale * mean_raw,
+		})
+	}
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Calibration {
+	/// The nRF's FICR device ID in hex, as the battery interface reports it in the handshake
+	pub device_id: Box<str>,
+	#[serde(default)]
+	pub millivolts: Correction,
+	#[serde(default)]
+	pub milliamps: Correction,
+}
+
+impl Calibration {
+	pub fn apply(&self, m: &Measurement) -> Measurement {
+		let millivolts = self.millivolts.apply(f64::from(u16::from(m.vbat)));
+		let milliamps = self.milliamps.apply(f64::from(i16::from(m.ibat)));
+		// the offsets are small next to the power, only the scales carry over
+		let milliwatts =
+			f64::from(m.milliwatts.into_inner()) * self.millivolts.scale * self.milliamps.scale;
+		Measurement {
+			// `as` saturates, a correction can't wrap a reading around
+			vbat: MilliVolt::new(millivolts.round() as u16),
+			ibat: MilliAmp::new(milliamps.round() as i16),
+			milliwatts: MilliWatt::new(milliwatts.round() as u32),
+			..*m
+		}
+	}
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum CalibrationChange {
+	/// The reference meter's readings now, at least one of them
+	Point {
+		millivolts: Option<MilliVolt>,
+		milliamps: Option<MilliAmp>,
+	},
+	/// Fit the points taken and save the calibration
+	Save,
+	/// Go back to the readings as they are
+	Clear,
+}
+
+/// What the reference meter read, with what the battery interface measured at the time
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Point {
+	raw: Measurement,
+	millivolts: Option<MilliVolt>,
+	milliamps: Option<MilliAmp>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CalibrationFile {
+	#[serde(default)]
+	devices: Vec<Calibration>,
+}
+
+/// Shared by every channel and the IPC task, saved each time a calibration changes
+#[derive(Debug)]
+pub struct CalibrationStore {
+	path: PathBuf,
+	calibrations: Mutex<Vec<Calibration>>,
+	/// Points taken for each device since its calibration was last saved
+	points: Mutex<Vec<(Box<str>, Point)>>,
+}
+
+impl CalibrationStore {
+	pub fn path(output_dir: &Path) -> PathBuf {
+		output_dir.join(CALIBRATION_FILE)
+	}
+
+	/// No calibrations if the file isn't there yet
+	pub fn load(path: PathBuf) -> Result<Self, Error> {
+		let file: CalibrationFile = match std::fs::read_to_string(&path) {
+			Ok(text) => toml::from_str(&text)
+				.map_err(|e| Error::CalibrationParse(path.clone().into_boxed_path(), e))?,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => CalibrationFile::default(),
+			Err(e) => return Err(Error::CalibrationRead(path.into_boxed_path(), e)),
+		};
+		Ok(Self {
+			path,
+			calibrations: Mutex::new(file.devices),
+			points: Mutex::new(Vec::new()),
+		})
+	}
+
+	pub fn get(&self, device_id: &str) -> Option<Calibration> {
+		lock(&self.calibrations)
+			.iter()
+			.find(|calibration| calibration.device_id.as_ref() == device_id)
+			.cloned()
+	}
+
+	/// Pair the reference meter's readings with the raw measurement `raw`,
+	/// returns how many points the device has
+	pub fn add_point(
+		&self,
+		device_id: &str,
+		raw: Measurement,
+		millivolts: Option<MilliVolt>,
+		milliamps: Option<MilliAmp>,
+	) -> usize {
+		let mut points = lock(&self.points);
+		points.push((
+			device_id.into(),
+			Point {
+				raw,
+				millivolts,
+				milliamps,
+			},
+		));
+		points
+			.iter()
+			.filter(|(d, _)| d.as_ref() == device_id)
+			.count()
+	}
+
+	/// Fit the device's points and save its calibration, a reading without points keeps
+	/// its correction. `None` when there are no points to fit.
+	pub fn save(&self, device_id: &str) -> std::io::Result<Option<Calibration>> {
+		let mut points = lock(&self.points);
+		let (taken, others): (Vec<_>, Vec<_>) =
+			points.drain(..).partition(|(d, _)| d.as_ref() == device_id);
+		*points = others;
+		if taken.is_empty() {
+			return Ok(None);
+		}
+		let mut calibrations = lock(&self.calibrations);
+		let mut calibration = self.get_locked(&calibrations, device_id);
+		let millivolts: Vec<(f64, f64)> = taken
+			.iter()
+			.filter_map(|(_, point)| {
+				let reference = point.millivolts?;
+				Some((
+					f64::from(u16::from(point.raw.vbat)),
+					f64::from(u16::from(reference)),
+				))
+			})
+			.collect();
+		let milliamps: Vec<(f64, f64)> = taken
+			.iter()
+			.filter_map(|(_, point)| {
+				let reference = point.milliamps?;
+				Some((
+					f64::from(i16::from(point.raw.ibat)),
+					f64::from(i16::from(reference)),
+				))
+			})
+			.collect();
+		if let Some(correction) = Correction::fit(&millivolts) {
+			calibration.millivolts = correction;
+		}
+		if let Some(correction) = Correction::fit(&milliamps) {
+			calibration.milliamps = correction;
+		}
+		calibrations.retain(|known| known.device_id.as_ref() != device_id);
+		calibrations.push(calibration.clone());
+		self.write(&calibrations)?;
+		Ok(Some(calibration))
+	}
+
+	/// Drop the device's calibration and the points taken for it
+	pub fn clear(&self, device_id: &str) -> std::io::Result<()> {
+		lock(&self.points).retain(|(d, _)| d.as_ref() != device_id);
+		let mut calibrations = lock(&self.calibrations);
+		calibrations.retain(|known| known.device_id.as_ref() != device_id);
+		self.write(&calibrations)
+	}
+
+	fn get_locked(&self, calibrations: &[Calibration], device_id: &str) -> Calibration {
+		calibrations
+			.iter()
+			.find(|known| known.device_id.as_ref() == device_id)
+			.cloned()
+			.unwrap_or_else(|| Calibration {
+				device_id: device_id.into(),
+				millivolts: Correction::default(),
+				milliamps: Correction::default(),
+			})
+	}
+
+	/// Replaces the file all at once, a crash while saving leaves the old one
+	fn write(&self, calibrations: &[Calibration]) -> std::io::Result<()> {
+		let file = CalibrationFile {
+			devices: calibrations.to_vec(),
+		};
+		// only fails for types toml can't represent, none are used here
+		let text = toml::to_string_pretty(&file).unwrap();
+		let tmp = self.path.with_extension("toml.tmp");
+		std::fs::write(&tmp, text)?;
+		std::fs::rename(tmp, &self.path)
+	}
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+	// the lists are valid between every change, a panic mid-change can't leave one half done
+	mutex
+		.lock()
+		.unwrap_or_else(std::sync::PoisonError::into_inner)
+}
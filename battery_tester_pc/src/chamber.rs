@@ -0,0 +1,291 @@
+//! Environmental chamber control for [`ProfileStep::Chamber`](crate::profile::ProfileStep) steps.
+
+use std::io::Write;
+
+use thiserror::Error;
+use tokio::{
+	io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+	net::TcpStream,
+	select,
+	sync::mpsc::{Receiver, Sender},
+	time::{Duration, Instant, MissedTickBehavior},
+};
+
+use crate::{ChamberCmd, Event, Printer, TaskError, profile::ChamberStep};
+
+/// How often the chamber temperature is read while waiting for it to stabilize
+const CHAMBER_POLL_MS: u64 = 5_000;
+
+/// Anything that can set and read a chamber temperature in °C
+pub trait ChamberDriver: Send {
+	fn set_temperature(
+		&mut self,
+		celsius: f32,
+	) -> impl Future<Output = Result<(), ChamberError>> + Send;
+
+	fn read_temperature(&mut self) -> impl Future<Output = Result<f32, ChamberError>> + Send;
+}
+
+#[derive(Debug, Error)]
+pub enum ChamberError {
+	#[error("chamber connection error: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("chamber closed the connection")]
+	Closed,
+	#[error("chamber sent an unreadable temperature: {0:?}")]
+	BadReply(Box<str>),
+}
+
+/// A chamber controller taking SCPI commands on a raw TCP socket, usually port 5025
+pub struct ScpiChamber {
+	stream: BufReader<TcpStream>,
+}
+
+impl ScpiChamber {
+	const SET_TEMPERATURE: &str = "SOUR:TEMP";
+	const READ_TEMPERATURE: &str = "MEAS:TEMP?";
+
+	pub async fn connect(addr: &str) -> Result<Self, ChamberError> {
+		let stream = TcpStream::connect(addr).await?;
+		Ok(Self {
+			stream: BufReader::new(stream),
+		})
+	}
+
+	async fn send_line(&mut self, line: &str) -> Result<(), ChamberError> {
+		let stream = self.stream.get_mut();
+		stream.write_all(line.as_bytes()).await?;
+		stream.write_all(b"\n").await?;
+		stream.flush().await?;
+		Ok(())
+	}
+}
+
+impl ChamberDriver for ScpiChamber {
+	async fn set_temperature(&mut self, celsius: f32) -> Result<(), ChamberError> {
+		let line = format!("{} {celsius:.2}", Self::SET_TEMPERATURE);
+		self.send_line(&line).await
+	}
+
+	async fn read_temperature(&mut self) -> Result<f32, ChamberError> {
+		self.send_line(Self::READ_TEMPERATURE).await?;
+		let mut reply = String::new();
+		if self.stream.read_line(&mut reply).await? == 0 {
+			return Err(ChamberError::Closed);
+		}
+		let reply = reply.trim();
+		reply
+			.parse()
+			.map_err(|_| ChamberError::BadReply(reply.into()))
+	}
+}
+
+enum Stabilize {
+	Stable,
+	/// Profile step was canceled
+	Stopped,
+	/// A new step arrived before this one finished
+	Restart(ChamberStep),
+	Shutdown,
+	TimedOut,
+	Failed(ChamberError),
+}
+
+pub async fn chamber_task<C: ChamberDriver>(
+	mut chamber: C,
+	event_tx: Sender<Event>,
+	mut chamber_cmd_rx: Receiver<ChamberCmd>,
+	mut printer: Printer,
+) -> Result<(), TaskError> {
+	loop {
+		let mut step = match chamber_cmd_rx.recv().await {
+			Some(ChamberCmd::Stabilize(step)) => step,
+			Some(ChamberCmd::Stop) => continue,
+			Some(ChamberCmd::Shutdown) | None => break,
+		};
+		let outcome = loop {
+			match stabilize(&mut chamber, step, &mut chamber_cmd_rx, &mut printer).await {
+				Stabilize::Restart(new_step) => step = new_step,
+				outcome => break outcome,
+			}
+		};
+		match outcome {
+			Stabilize::Stable => {
+				printer
+					.buf(|tv| write!(tv, "chamber stable at: {}°C", step.setpoint_c))
+					.await;
+				event_tx.send(Event::ChamberStable).await?;
+			}
+			Stabilize::Stopped | Stabilize::Restart(_) => {}
+			Stabilize::Shutdown => break,
+			Stabilize::TimedOut => {
+				printer
+					.buf(|tv| {
+						write!(
+							tv,
+							"chamber didn't stabilize at: {}°C in time",
+							step.setpoint_c
+						)
+					})
+					.await;
+				event_tx.send(Event::ChamberError).await?;
+			}
+			Stabilize::Failed(e) => {
+				printer.error(|tv| write!(tv, "chamber error:\n{e}")).await;
+				event_tx.send(Event::ChamberError).await?;
+			}
+		}
+	}
+	println!("exiting chamber_task");
+	Ok(())
+}
+
+/// Set the chamber and poll it until it has been within tolerance for the soak time
+async fn stabilize<C: ChamberDriver>(
+	chamber: &mut C,
+	step: ChamberStep,
+	chamber_cmd_rx: &mut Receiver<ChamberCmd>,
+	printer: &mut Printer,
+) -> Stabilize {
+	printer
+		.buf(|tv| write!(tv, "setting chamber to: {}°C", step.setpoint_c))
+		.await;
+	if let Err(e) = chamber.set_temperature(step.setpoint_c).await {
+		return Stabilize::Failed(e);
+	}
+	let started = Instant::now();
+	let timeout = step.timeout_s.map(Duration::from_secs);
+	let soak = Duration::from_secs(step.soak_s);
+	let mut in_band_since: Option<Instant> = None;
+	let mut poll = tokio::time::interval(Duration::from_millis(CHAMBER_POLL_MS));
+	poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	loop {
+		select! {
+			_ = poll.tick() => {
+				let celsius = match chamber.read_temperature().await {
+					Ok(c) => c,
+					Err(e) => return Stabilize::Failed(e),
+				};
+				let now = Instant::now();
+				if (celsius - step.setpoint_c).abs() <= step.tolerance_c {
+					let since = *in_band_since.get_or_insert(now);
+					if now - since >= soak {
+						return Stabilize::Stable;
+					}
+				} else {
+					in_band_since = None;
+				}
+				if timeout.is_some_and(|t| now - started >= t) {
+					return Stabilize::TimedOut;
+				}
+			}
+			cmd = chamber_cmd_rx.recv() => match cmd {
+				Some(ChamberCmd::Stop) => return Stabilize::Stopped,
+				Some(ChamberCmd::Shutdown) | None => return Stabilize::Shutdown,
+				Some(ChamberCmd::Stabilize(new_step)) => return Stabilize::Restart(new_step),
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::Print;
+	use std::collections::VecDeque;
+	use tokio::sync::mpsc;
+
+	/// Reads back `readings` in order, then fails like a dropped connection
+	struct FakeChamber {
+		readings: VecDeque<f32>,
+		setpoint: Option<f32>,
+	}
+
+	impl FakeChamber {
+		fn new(readings: Vec<f32>) -> Self {
+			Self {
+				readings: readings.into(),
+				setpoint: None,
+			}
+		}
+	}
+
+	impl ChamberDriver for FakeChamber {
+		async fn set_temperature(&mut self, celsius: f32) -> Result<(), ChamberError> {
+			self.setpoint = Some(celsius);
+			Ok(())
+		}
+
+		async fn read_temperature(&mut self) -> Result<f32, ChamberError> {
+			self.readings.pop_front().ok_or(ChamberError::Closed)
+		}
+	}
+
+	fn printer() -> Printer {
+		let (print_tx, mut print_rx) = mpsc::channel::<Print>(64);
+		tokio::spawn(async move { while print_rx.recv().await.is_some() {} });
+		Printer::new(print_tx)
+	}
+
+	/// 40°C ±1, a minute's soak
+	const STEP: ChamberStep = ChamberStep {
+		setpoint_c: 40.0,
+		tolerance_c: 1.0,
+		soak_s: 60,
+		timeout_s: Some(600),
+	};
+
+	async fn stabilize_at(chamber: &mut FakeChamber, step: ChamberStep) -> (Stabilize, Duration) {
+		let (_chamber_cmd_tx, mut chamber_cmd_rx) = mpsc::channel(1);
+		let started = Instant::now();
+		let outcome = stabilize(chamber, step, &mut chamber_cmd_rx, &mut printer()).await;
+		(outcome, started.elapsed())
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_stable_after_soaking() {
+		// warming up, then in the band except for one overshoot
+		let mut readings = vec![25.0, 35.0, 39.5, 41.5];
+		readings.extend([40.2; 13]);
+		let mut chamber = FakeChamber::new(readings);
+		let (outcome, took) = stabilize_at(&mut chamber, STEP).await;
+		assert!(matches!(outcome, Stabilize::Stable));
+		assert_eq!(chamber.setpoint, Some(40.0));
+		// the overshoot restarts the soak, at the 5th reading
+		let soak_start = Duration::from_millis(4 * CHAMBER_POLL_MS);
+		assert_eq!(took, soak_start + Duration::from_secs(60));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_times_out() {
+		let mut chamber = FakeChamber::new(vec![30.0; 200]);
+		let (outcome, took) = stabilize_at(&mut chamber, STEP).await;
+		assert!(matches!(outcome, Stabilize::TimedOut));
+		assert_eq!(took, Duration::from_secs(600));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_chamber_task_reports_the_outcome() {
+		let (event_tx, mut event_rx) = mpsc::channel(4);
+		let (chamber_cmd_tx, chamber_cmd_rx) = mpsc::channel(4);
+		let task = tokio::spawn(chamber_task(
+			FakeChamber::new(vec![40.0; 13]),
+			event_tx,
+			chamber_cmd_rx,
+			printer(),
+		));
+		chamber_cmd_tx
+			.send(ChamberCmd::Stabilize(STEP))
+			.await
+			.unwrap();
+		assert_eq!(event_rx.recv().await, Some(Event::ChamberStable));
+		// every reading was used up, the chamber stopped answering
+		chamber_cmd_tx
+			.send(ChamberCmd::Stabilize(STEP))
+			.await
+			.unwrap();
+		assert_eq!(event_rx.recv().await, Some(Event::ChamberError));
+		chamber_cmd_tx.send(ChamberCmd::Shutdown).await.unwrap();
+		task.await.unwrap().unwrap();
+	}
+}
@@ -0,0 +1,78 @@
+//! Limits for each kind of battery the tester is used with.
+//!
+//! Picking a chemistry sets the cutoff, which can still be changed on its own after,
+//! along with the most current expected under the load, the voltage below which
+//! the battery is taken to be disconnected, and the most it can read before it's taken
+//! to be the wrong battery.
+
+use battery_tester_common::{MilliAmp, MilliVolt};
+use serde::{Deserialize, Serialize};
+
+use crate::{DEFAULT_CUTOFF_MILLIV, DEFAULT_DISCONNECT_MILLIV};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Chemistry {
+	/// 12 V lead-acid, 6 cells
+	#[default]
+	LeadAcid6,
+	/// 12.8 V LiFePO4, 4 cells in series
+	LiFePo4S4,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Limits {
+	/// End of the test
+	pub cutoff: MilliVolt,
+	/// Anything over this under the load is a fault in the load or wiring
+	pub max_current: MilliAmp,
+	/// Anything under this isn't a battery
+	pub disconnect: MilliVolt,
+	/// Anything over this is the wrong battery or one still on its charger
+	pub max_voltage: MilliVolt,
+}
+
+impl Chemistry {
+	pub fn limits(self) -> Limits {
+		match self {
+			Chemistry::LeadAcid6 => Limits {
+				cutoff: MilliVolt::new(DEFAULT_CUTOFF_MILLIV),
+				max_current: MilliAmp::new(15_000),
+				disconnect: MilliVolt::new(DEFAULT_DISCONNECT_MILLIV),
+				// a fresh one with its surface charge is under 13.5 V
+				max_voltage: MilliVolt::new(14_000),
+			},
+			// 2.5 V a cell
+			Chemistry::LiFePo4S4 => Limits {
+				cutoff: MilliVolt::new(10_000),
+				max_current: MilliAmp::new(15_000),
+				disconnect: MilliVolt::new(DEFAULT_DISCONNECT_MILLIV),
+				// 3.65 V a cell, fully charged
+				max_voltage: MilliVolt::new(14_600),
+			},
+		}
+	}
+}
+
+impl std::fmt::Display for Chemistry {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			Chemistry::LeadAcid6 => "lead-acid-6",
+			Chemistry::LiFePo4S4 => "lifepo4-4s",
+		})
+	}
+}
+
+impl std::str::FromStr for Chemistry {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"lead-acid-6" => Ok(Chemistry::LeadAcid6),
+			"lifepo4-4s" => Ok(Chemistry::LiFePo4S4),
+			_ => Err(format!(
+				"unknown chemistry: {s}, expected lead-acid-6 or lifepo4-4s"
+			)),
+		}
+	}
+}
@@ -1,55 +1,1428 @@
-use argh::FromArgs;
+use std::{
+	ffi::OsString,
+	ops::RangeInclusive,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use argh::{ArgsInfo, EarlyExit, FromArgs};
+use battery_tester_common::{
+	BAUDS, LoadModel, Measurement, MilliAmp, MilliVolt, Polarity, baud_supported,
+	window::WindowFilter,
+};
 use bytes::BytesMut;
-use pc_common::{SERVER_NAME, ServerCmd, write_ipc};
+use pc_common::{
+	Ack, BatteryDetectChange, BatteryID, Capabilities, ChannelId, ChannelStatus, CurrentMode,
+	IPC_VERSION, IpcStream, LastMeasurement, Mode, OutputFormat, Reading, ReplyError, Request,
+	ServerCmd, TrimChange,
+	analysis::{self, AnalysisError, DEFAULT_THRESHOLDS_MILLIV},
+	calibration::{Calibration, CalibrationChange},
+	chemistry::Chemistry,
+	completions::{self, Shell},
+	dashboard::Dashboard,
+	dump::{Decoded, DumpDecoder, read_dump},
+	ipc::{read_token, server_id, server_names},
+	queue::{QueueChange, QueuedTest},
+	read_reply,
+	registry::RegisteredBattery,
+	serial::PortListing,
+	write_ipc,
+};
+use ratatui::{
+	DefaultTerminal,
+	crossterm::event::{self, KeyCode, KeyEventKind, KeyModifiers},
+};
+use rustyline::{DefaultEditor, ExternalPrinter, error::ReadlineError};
 use thiserror::Error;
-use tipsy::{Endpoint, ServerId};
+use tipsy::Endpoint;
+use tokio::select;
 
 #[tokio::main]
-pub async fn main() -> Result<(), Error> {
-	let cli: Cli = argh::from_env();
-	let server_cmd: ServerCmd = cli.cmd.into();
-	let mut client = Endpoint::connect(ServerId::new(SERVER_NAME))
+pub async fn main() -> std::process::ExitCode {
+	let Err(e) = run(argh::from_env()).await else {
+		return std::process::ExitCode::SUCCESS;
+	};
+	// the messages with their causes, Debug is for bug reports
+	eprintln!("error: {e}");
+	let mut source = std::error::Error::source(&e);
+	while let Some(cause) = source {
+		eprintln!("  {cause}");
+		source = cause.source();
+	}
+	std::process::ExitCode::FAILURE
+}
+
+async fn run(cli: Cli) -> Result<(), Error> {
+	let token = cli.token_file.as_deref().map(read_token).transpose()?;
+	let config = ClientConfig::load()?;
+	let server = config.server(cli.remote.as_deref(), cli.name.as_deref());
+	match cli.cmd {
+		// the dashboard isn't something a script can read
+		Subcommands::Watch(watch_cmd) if watch_cmd.plain || cli.json => {
+			return watch(watch_cmd, server, cli.channel, cli.json).await;
+		}
+		Subcommands::Watch(watch_cmd) => return dashboard(watch_cmd, server, cli.channel).await,
+		Subcommands::List(_list_cmd) => return list(cli.json).await,
+		Subcommands::Analyze(analyze_cmd) => return analyze(&analyze_cmd, cli.json),
+		Subcommands::DecodeDump(decode_cmd) => return decode_dump(&decode_cmd, cli.json),
+		#[cfg(feature = "parquet")]
+		Subcommands::Export(export_cmd) => return export(&export_cmd, cli.json),
+		Subcommands::Repl(_repl_cmd) => {
+			return repl(server, cli.channel, cli.json, token, &config).await;
+		}
+		Subcommands::Completions(completions_cmd) => {
+			print!("{}", completions(completions_cmd));
+			return Ok(());
+		}
+		_ => {}
+	}
+	let request = Request::new(cli.channel, server_cmd(cli.cmd, server, &config)?, token);
+	setup_from_config(&request, server, &config, cli.json).await?;
+	let mut client = connect(server).await?;
+	send(&mut client, &request, cli.json).await
+}
+
+/// Set a `start`'s channel up from the config first when it hasn't got a device, one that
+/// has was set up by hand
+async fn setup_from_config(
+	request: &Request,
+	server: Server<'_>,
+	config: &ClientConfig,
+	json: bool,
+) -> Result<(), Error> {
+	let starting = matches!(
+		request.cmd,
+		ServerCmd::StartTest | ServerCmd::StartAt(_) | ServerCmd::StartAfter(_)
+	);
+	if !starting || config.device.is_none() {
+		return Ok(());
+	}
+	// the server answers one request for each connection outside a session
+	let mut status_client = connect(server).await?;
+	let status = Request::new(request.channel, ServerCmd::Status, None);
+	write_ipc(BytesMut::with_capacity(64), &mut status_client, &status)
+		.await
+		.map_err(Error::IPCWrite)?;
+	let reports: Vec<ChannelStatus> = read_reply(&mut status_client, status.id)
 		.await
-		.map_err(|ioe| Error::Connect(ioe))?;
+		.map_err(Error::IPCRead)?;
+	let channel = request.channel.unwrap_or(0);
+	if !reports
+		.iter()
+		.any(|report| report.channel == channel && report.server.device_name.is_none())
+	{
+		return Ok(());
+	}
+	let mut setup = vec![Subcommands::SerialDev(SerialDevCmd {
+		device_name: None,
+		baud: None,
+	})];
+	if config.cutoff_mv.is_some() {
+		setup.push(Subcommands::SetCutoff(CutoffCmd { millivolts: None }));
+	}
+	for cmd in setup {
+		let cmd = server_cmd(cmd, server, config)?;
+		match &cmd {
+			_ if json => {}
+			ServerCmd::SetSerialDev(device, _) => {
+				println!("setting the device to {device}, from the client config");
+			}
+			ServerCmd::SetCutoffMillis(cutoff) => {
+				println!("setting the cutoff to {cutoff} mV, from the client config");
+			}
+			_ => {}
+		}
+		let setup_request = Request::new(request.channel, cmd, request.token.clone());
+		send(&mut connect(server).await?, &setup_request, json).await?;
+	}
+	wait_ready_to_start(request.channel, server).await
+}
+
+/// How long a channel given its device has to connect to it and see the battery
+const READY_TO_START_WAIT: Duration = Duration::from_secs(10);
+
+/// Wait for a channel that was just given its device to be ready for a `start`, it's
+/// refused until then
+async fn wait_ready_to_start(channel: Option<ChannelId>, server: Server<'_>) -> Result<(), Error> {
+	let deadline = tokio::time::Instant::now() + READY_TO_START_WAIT;
+	loop {
+		let mut client = connect(server).await?;
+		let request = Request::new(channel, ServerCmd::GetMode, None);
+		write_ipc(BytesMut::with_capacity(64), &mut client, &request)
+			.await
+			.map_err(Error::IPCWrite)?;
+		let reply: CurrentMode = read_reply(&mut client, request.id)
+			.await
+			.map_err(Error::IPCRead)?;
+		match reply.mode {
+			Mode::WaitForUsrStart => return Ok(()),
+			mode if tokio::time::Instant::now() >= deadline => {
+				return Err(Error::NotReady(mode));
+			}
+			_ => tokio::time::sleep(Duration::from_millis(250)).await,
+		}
+	}
+}
+
+/// Send a request and print the reply, if there is one
+async fn send(client: &mut Box<dyn IpcStream>, request: &Request, json: bool) -> Result<(), Error> {
 	let buf = BytesMut::with_capacity(512);
-	let _buf = write_ipc(buf, &mut client, &server_cmd)
+	let _buf = write_ipc(buf, client, request)
+		.await
+		.map_err(Error::IPCWrite)?;
+	match request.cmd {
+		ServerCmd::Status => {
+			let reports: Vec<ChannelStatus> = read_reply(client, request.id)
+				.await
+				.map_err(Error::IPCRead)?;
+			if json {
+				print_json(&reports);
+			} else {
+				let label = reports.len() > 1;
+				for report in &reports {
+					if label {
+						println!("channel {}:", report.channel);
+					}
+					println!("{report}");
+				}
+			}
+		}
+		ServerCmd::GetCapabilities => {
+			let capabilities: Capabilities = read_reply(client, request.id)
+				.await
+				.map_err(Error::IPCRead)?;
+			if json {
+				print_json(&capabilities);
+			} else {
+				println!("{capabilities:#?}");
+			}
+		}
+		ServerCmd::GetLastMeasurement => {
+			let reply: LastMeasurement = read_reply(client, request.id)
+				.await
+				.map_err(Error::IPCRead)?;
+			print_json(&reply);
+		}
+		ServerCmd::GetMode => {
+			let reply: CurrentMode = read_reply(client, request.id)
+				.await
+				.map_err(Error::IPCRead)?;
+			print_json(&reply);
+		}
+		ServerCmd::ListBatteries => {
+			let batteries: Vec<RegisteredBattery> = read_reply(client, request.id)
+				.await
+				.map_err(Error::IPCRead)?;
+			if json {
+				print_json(&batteries);
+			} else {
+				print_batteries(&batteries);
+			}
+		}
+		ServerCmd::ListSerialPorts => {
+			let ports: Result<Vec<PortListing>, Box<str>> = read_reply(client, request.id)
+				.await
+				.map_err(Error::IPCRead)?;
+			match ports {
+				_ if json => print_json(&ports),
+				Ok(ports) => print_ports(&ports),
+				Err(error) => return Err(Error::Refused(error)),
+			}
+		}
+		ServerCmd::GetCalibration => {
+			let calibration: Option<Calibration> = read_reply(client, request.id)
+				.await
+				.map_err(Error::IPCRead)?;
+			match calibration {
+				_ if json => print_json(&calibration),
+				Some(Calibration {
+					device_id,
+					millivolts,
+					milliamps,
+				}) => {
+					println!("device ID: {device_id}");
+					println!("  mV x {:.5} + {:.1}", millivolts.scale, millivolts.offset);
+					println!("  mA x {:.5} + {:.1}", milliamps.scale, milliamps.offset);
+				}
+				None => println!("not calibrated"),
+			}
+		}
+		ServerCmd::SubscribeReadings => {}
+		_ => {
+			let ack: Ack = read_reply(client, request.id)
+				.await
+				.map_err(Error::IPCRead)?;
+			if json {
+				print_json(&ack);
+			}
+			if let Some(error) = ack.error {
+				return Err(Error::Refused(error));
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Cutoffs and the voltage tests won't start over, from a deeply discharged 12 V battery
+/// to one on its charger
+const PLAUSIBLE_MILLIVOLTS: RangeInclusive<u16> = 6_000..=15_000;
+/// Under the lowest plausible cutoff, or batteries count as removed before they're discharged
+const PLAUSIBLE_DISCONNECT_MILLIVOLTS: RangeInclusive<u16> = 100..=5_000;
+/// Battery IDs start with the year the battery was bought
+const FIRST_BATTERY_YEAR: u16 = 1990;
+
+/// What to ask the server for, with any file it needs read here
+fn server_cmd(
+	cmd: Subcommands,
+	server: Server<'_>,
+	config: &ClientConfig,
+) -> Result<ServerCmd, Error> {
+	let cmd = config.fill(cmd)?;
+	validate(&cmd, server)?;
+	cmd.try_into()
+}
+
+/// Refuse settings the server would take but that can't be what was meant, before they
+/// turn up as a test that ends at once or never does
+fn validate(cmd: &Subcommands, server: Server<'_>) -> Result<(), Error> {
+	let millivolts = |setting, millivolts, range: RangeInclusive<u16>| {
+		if range.contains(&millivolts) {
+			Ok(())
+		} else {
+			Err(Error::Implausible {
+				setting,
+				millivolts,
+				range,
+			})
+		}
+	};
+	match cmd {
+		Subcommands::SetCutoff(CutoffCmd {
+			millivolts: Some(cutoff),
+		}) => millivolts("cutoff", *cutoff, PLAUSIBLE_MILLIVOLTS),
+		Subcommands::MaxVoltage(max_voltage_cmd) => millivolts(
+			"max voltage",
+			max_voltage_cmd.millivolts,
+			PLAUSIBLE_MILLIVOLTS,
+		),
+		Subcommands::Disconnect(disconnect_cmd) => millivolts(
+			"disconnect voltage",
+			disconnect_cmd.millivolts,
+			PLAUSIBLE_DISCONNECT_MILLIVOLTS,
+		),
+		Subcommands::BatteryID(BatteryIdCmd { year, .. })
+		| Subcommands::Battery(BatteryCmd {
+			cmd: BatterySubcommands::Add(BatteryAddCmd { year, .. }),
+		}) => battery_year(*year),
+		Subcommands::MaxDuration(MaxDurationCmd { minutes: Some(0) }) => {
+			Err(Error::ZeroLimit("max duration"))
+		}
+		Subcommands::MaxCapacity(MaxCapacityCmd { mah: Some(0) }) => {
+			Err(Error::ZeroLimit("max capacity"))
+		}
+		Subcommands::SerialDev(SerialDevCmd {
+			device_name: Some(device),
+			baud,
+		}) => {
+			if let Some(baud) = baud.filter(|baud| !baud_supported(*baud)) {
+				return Err(Error::Baud(baud));
+			}
+			serial_dev_exists(device, server)
+		}
+		_ => Ok(()),
+	}
+}
+
+fn battery_year(year: u16) -> Result<(), Error> {
+	use chrono::Datelike;
+
+	let this_year = chrono::Local::now().year() as u16;
+	if (FIRST_BATTERY_YEAR..=this_year).contains(&year) {
+		Ok(())
+	} else {
+		Err(Error::BatteryYear(year, this_year))
+	}
+}
+
+/// Only a local server's devices can be looked for, and only paths, the simulator's and
+/// Windows' COM ports aren't files
+fn serial_dev_exists(device: &str, server: Server<'_>) -> Result<(), Error> {
+	let path = std::path::Path::new(device);
+	if cfg!(unix) && matches!(server, Server::Local(_)) && path.is_absolute() && !path.exists() {
+		return Err(Error::NoSerialDev(device.into()));
+	}
+	Ok(())
+}
+
+fn completions(completions_cmd: CompletionsCmd) -> String {
+	completions::script(
+		completions_cmd.shell,
+		"battery-tester-client",
+		&Cli::get_args_info(),
+	)
+}
+
+/// One line, for scripts
+fn print_json(reply: &impl serde::Serialize) {
+	println!("{}", json_line(reply));
+}
+
+/// The line [`print_json`] prints
+fn json_line(reply: &impl serde::Serialize) -> String {
+	// only fails for maps with keys that aren't strings, none are used here
+	serde_json::to_string(reply).unwrap()
+}
+
+/// Take subcommands from the terminal until it's closed, sent over one connection to the
+/// server, and print the mode each time it changes on another
+async fn repl(
+	server: Server<'_>,
+	channel: Option<ChannelId>,
+	json: bool,
+	token: Option<Box<str>>,
+	config: &ClientConfig,
+) -> Result<(), Error> {
+	let mut client = connect(server).await?;
+	let session = Request::new(channel, ServerCmd::Session, token.clone());
+	send(&mut client, &session, false).await?;
+	let mut watcher = connect(server).await?;
+	let subscribe = Request::new(channel, ServerCmd::SubscribeReadings, None);
+	write_ipc(BytesMut::with_capacity(64), &mut watcher, &subscribe)
 		.await
-		.map_err(|ipce| Error::IPCWrite(ipce))?;
+		.map_err(Error::IPCWrite)?;
+	let mut editor = DefaultEditor::new().map_err(Error::Editor)?;
+	// only for terminals, piped lines are printed as they are
+	let printer = editor.create_external_printer().ok();
+	tokio::spawn(print_modes(
+		watcher,
+		subscribe.id,
+		channel.unwrap_or(0),
+		json,
+		printer,
+	));
+	// waiting on the terminal blocks this thread, the mode is watched on the runtime's others
+	loop {
+		let line = match editor.readline("battery-tester> ") {
+			Ok(line) => line,
+			// Ctrl-C drops the line, Ctrl-D quits
+			Err(ReadlineError::Interrupted) => continue,
+			Err(ReadlineError::Eof) => return Ok(()),
+			Err(e) => return Err(Error::Editor(e)),
+		};
+		let Some(words) = shlex::split(&line) else {
+			println!("unmatched quote");
+			continue;
+		};
+		if words.is_empty() {
+			continue;
+		}
+		// only fails when the history is saved to a file, it isn't
+		let _ = editor.add_history_entry(line.as_str());
+		let args: Vec<&str> = words.iter().map(String::as_str).collect();
+		let line_cli = match Cli::from_args(&["battery-tester-client"], &args) {
+			Ok(line_cli) => line_cli,
+			// help or a mistake
+			Err(EarlyExit { output, .. }) => {
+				println!("{}", output.trim_end());
+				continue;
+			}
+		};
+		// --name, --remote and --token-file are for the repl's own server
+		let json = line_cli.json || json;
+		let res = match line_cli.cmd {
+			Subcommands::Watch(_) | Subcommands::Repl(_) => {
+				println!("watch and repl can't run in the repl");
+				continue;
+			}
+			Subcommands::List(_list_cmd) => list(json).await,
+			Subcommands::Analyze(analyze_cmd) => analyze(&analyze_cmd, json),
+			Subcommands::DecodeDump(decode_cmd) => decode_dump(&decode_cmd, json),
+			Subcommands::Completions(completions_cmd) => {
+				print!("{}", completions(completions_cmd));
+				Ok(())
+			}
+			#[cfg(feature = "parquet")]
+			Subcommands::Export(export_cmd) => export(&export_cmd, json),
+			cmd => match server_cmd(cmd, server, config) {
+				Ok(cmd) => {
+					let request = Request::new(line_cli.channel.or(channel), cmd, token.clone());
+					match setup_from_config(&request, server, config, json).await {
+						Ok(()) => send(&mut client, &request, json).await,
+						Err(e) => Err(e),
+					}
+				}
+				Err(e) => Err(e),
+			},
+		};
+		match res {
+			Ok(()) => {}
+			// the server's gone
+			Err(e @ (Error::IPCWrite(_) | Error::IPCRead(_))) => return Err(e),
+			Err(e) => println!("{e}"),
+		}
+	}
+}
+
+/// Print the channel's mode each time it changes, e.g. to a fault, above the line being typed
+async fn print_modes(
+	mut watcher: Box<dyn IpcStream>,
+	id: u32,
+	channel: ChannelId,
+	json: bool,
+	mut printer: Option<impl ExternalPrinter>,
+) {
+	let mut last = None;
+	while let Ok(reading) = read_reply::<Reading>(&mut watcher, id).await {
+		if last == Some(reading.mode) {
+			continue;
+		}
+		last = Some(reading.mode);
+		let line = if json {
+			json_line(&CurrentMode {
+				channel,
+				mode: reading.mode,
+			})
+		} else {
+			format!("channel {channel}: {:?}", reading.mode)
+		};
+		match &mut printer {
+			Some(printer) => {
+				if printer.print(line).is_err() {
+					break;
+				}
+			}
+			None => println!("{line}"),
+		}
+	}
+}
+
+/// Runs on the client's machine, no server needed
+fn analyze(analyze_cmd: &AnalyzeCmd, json: bool) -> Result<(), Error> {
+	let paths: Vec<&std::path::Path> = analyze_cmd
+		.files
+		.iter()
+		.map(|path| path.as_path())
+		.collect();
+	let thresholds = match analyze_cmd.at.as_slice() {
+		[] => &DEFAULT_THRESHOLDS_MILLIV[..],
+		at => at,
+	};
+	let analysis = analysis::analyze(&paths, thresholds)?;
+	if json {
+		print_json(&analysis);
+		return Ok(());
+	}
+	let hms = |ms: u64| {
+		let s = ms / 1000;
+		format!("{}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+	};
+	println!("samples: {}", analysis.samples);
+	println!("time testing: {}", hms(analysis.elapsed_ms));
+	println!("capacity: {:.1} mAh", analysis.mah);
+	println!("energy: {:.2} Wh", analysis.wh);
+	println!(
+		"current: {:.0} mA average, {:.0} mA peak",
+		analysis.average_milliamps, analysis.peak_milliamps
+	);
+	println!("lowest voltage: {:.0} mV", analysis.min_millivolts);
+	for (millivolts, reached) in &analysis.thresholds {
+		match reached {
+			Some(reached) => println!(
+				"to {millivolts} mV: {} {:.1} mAh {:.2} Wh",
+				hms(reached.elapsed_ms),
+				reached.mah,
+				reached.wh
+			),
+			None => println!("to {millivolts} mV: not reached"),
+		}
+	}
+	Ok(())
+}
+
+/// Runs on the client's machine, no server needed
+fn decode_dump(decode_cmd: &DecodeDumpCmd, json: bool) -> Result<(), Error> {
+	let mut decoder = DumpDecoder::default();
+	for record in read_dump(&decode_cmd.file)? {
+		let hex: Vec<String> = record.bytes.iter().map(|b| format!("{b:02x}")).collect();
+		let frames: Vec<String> = decoder
+			.decode(&record)
+			.into_iter()
+			.map(|frame| match frame {
+				Decoded::Command(command) => format!("{command:?}"),
+				Decoded::Reply(reply) => format!("{reply:?}"),
+				Decoded::Bad(e) => format!("bad frame: {e:?}"),
+			})
+			.collect();
+		if json {
+			print_json(&serde_json::json!({
+				"channel": record.channel,
+				"elapsed_ms": record.elapsed_ms,
+				"direction": record.direction,
+				"bytes": hex.join(" "),
+				"frames": frames,
+			}));
+			continue;
+		}
+		println!(
+			"{:>4}.{:03} s channel {} {} {}",
+			record.elapsed_ms / 1000,
+			record.elapsed_ms % 1000,
+			record.channel,
+			record.direction,
+			hex.join(" ")
+		);
+		for frame in frames {
+			println!("    {frame}");
+		}
+	}
 	Ok(())
 }
 
+#[cfg(feature = "parquet")]
+fn export(export_cmd: &ExportCmd, json: bool) -> Result<(), Error> {
+	let paths: Vec<&std::path::Path> = export_cmd.files.iter().map(|path| path.as_path()).collect();
+	let rows = pc_common::export::export_parquet(&paths, &export_cmd.parquet)?;
+	if json {
+		print_json(&serde_json::json!({ "rows": rows, "parquet": export_cmd.parquet }));
+	} else {
+		println!("wrote {rows} rows to: {:?}", export_cmd.parquet);
+	}
+	Ok(())
+}
+
+fn print_batteries(batteries: &[RegisteredBattery]) {
+	if batteries.is_empty() {
+		println!("no batteries, add one with: battery add");
+	}
+	for battery in batteries {
+		let BatteryID { year, index } = battery.id;
+		let nominal = battery
+			.nominal_mah
+			.map(|mah| format!(", {mah} mAh"))
+			.unwrap_or_default();
+		let manufactured = battery
+			.manufactured
+			.as_deref()
+			.map(|made| format!(", made {made}"))
+			.unwrap_or_default();
+		println!(
+			"{year}-{index}: {}{nominal}{manufactured}, tested {} time(s)",
+			battery.chemistry, battery.tests
+		);
+	}
+}
+
+/// A table, with the battery interface's own USB ports marked
+fn print_ports(ports: &[PortListing]) {
+	if ports.is_empty() {
+		println!("no serial ports on the server's machine");
+	} else {
+		print!("{}", ports_table(ports));
+	}
+}
+
+/// A line for each port under a header, the battery interface's marked
+fn ports_table(ports: &[PortListing]) -> String {
+	let rows: Vec<[String; 5]> = ports
+		.iter()
+		.map(|port| match &port.usb {
+			Some(usb) => [
+				port.name.to_string(),
+				format!("{:04x}:{:04x}", usb.vid, usb.pid),
+				usb.manufacturer.as_deref().unwrap_or("").to_string(),
+				match usb.product.as_deref() {
+					Some(product) if usb.battery_interface() => {
+						format!("{product} (battery interface)")
+					}
+					Some(product) => product.to_string(),
+					None if usb.battery_interface() => "(battery interface)".to_string(),
+					None => String::new(),
+				},
+				usb.serial_number.as_deref().unwrap_or("").to_string(),
+			],
+			None => [
+				port.name.to_string(),
+				"-".to_string(),
+				String::new(),
+				String::new(),
+				String::new(),
+			],
+		})
+		.collect();
+	let header = ["port", "usb id", "manufacturer", "product", "serial number"];
+	let mut widths = header.map(str::len);
+	for row in &rows {
+		for (width, cell) in widths.iter_mut().zip(row) {
+			*width = (*width).max(cell.chars().count());
+		}
+	}
+	let mut table = String::new();
+	let mut push_row = |cells: [&str; 5]| {
+		let line: Vec<String> = cells
+			.iter()
+			.zip(widths)
+			.map(|(cell, width)| format!("{cell:width$}"))
+			.collect();
+		table.push_str(line.join("  ").trim_end());
+		table.push('\n');
+	};
+	push_row(header);
+	for row in &rows {
+		push_row(row.each_ref().map(String::as_str));
+	}
+	table
+}
+
+/// Which server to talk to
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Server<'a> {
+	/// On this machine, by the name it was started with
+	Local(Option<&'a str>),
+	/// On another machine, by the host:port it's listening on
+	Remote(&'a str),
+}
+
+async fn connect(server: Server<'_>) -> Result<Box<dyn IpcStream>, Error> {
+	match server {
+		Server::Local(name) => Endpoint::connect(server_id(name))
+			.await
+			.map(|conn| Box::new(conn) as Box<dyn IpcStream>),
+		Server::Remote(addr) => tokio::net::TcpStream::connect(addr)
+			.await
+			.and_then(|stream| {
+				stream.set_nodelay(true)?;
+				Ok(Box::new(stream) as Box<dyn IpcStream>)
+			}),
+	}
+	.map_err(Error::Connect)
+}
+
+/// Print every server on this machine and whether it's running
+async fn list(json: bool) -> Result<(), Error> {
+	let names = server_names().map_err(Error::List)?;
+	if json {
+		// capabilities is null for servers that aren't running
+		let mut servers = Vec::with_capacity(names.len());
+		for name in names {
+			let capabilities = capabilities(Server::Local(name.as_deref())).await.ok();
+			servers.push(serde_json::json!({ "name": name, "capabilities": capabilities }));
+		}
+		print_json(&servers);
+		return Ok(());
+	}
+	if names.is_empty() {
+		println!("no servers");
+	}
+	for name in names {
+		let label = name.as_deref().unwrap_or("(no name)");
+		match capabilities(Server::Local(name.as_deref())).await {
+			Ok(capabilities) => println!(
+				"{label}: running, server version {}, features: {:?}",
+				capabilities.server_version, capabilities.features
+			),
+			Err(Error::IPCRead(ReplyError::Version(version))) => println!(
+				"{label}: running, on IPC version {version} where this client is on {IPC_VERSION}"
+			),
+			Err(_) => println!("{label}: not running, left behind by a server that was killed"),
+		}
+	}
+	Ok(())
+}
+
+async fn capabilities(server: Server<'_>) -> Result<Capabilities, Error> {
+	let mut client = connect(server).await?;
+	let request = Request::new(None, ServerCmd::GetCapabilities, None);
+	write_ipc(BytesMut::with_capacity(64), &mut client, &request)
+		.await
+		.map_err(Error::IPCWrite)?;
+	read_reply(&mut client, request.id)
+		.await
+		.map_err(Error::IPCRead)
+}
+
+/// Print a reading every interval until the server goes away
+async fn watch(
+	watch_cmd: WatchCmd,
+	server: Server<'_>,
+	channel: Option<ChannelId>,
+	json: bool,
+) -> Result<(), Error> {
+	let alarms = Alarms::from(watch_cmd);
+	let mut interval = tokio::time::interval(Duration::from_secs(watch_cmd.interval_s.max(1)));
+	loop {
+		interval.tick().await;
+		let mut client = connect(server).await?;
+		let request = Request::new(channel, ServerCmd::GetReading, None);
+		write_ipc(BytesMut::with_capacity(64), &mut client, &request)
+			.await
+			.map_err(Error::IPCWrite)?;
+		let reading: Reading = read_reply(&mut client, request.id)
+			.await
+			.map_err(Error::IPCRead)?;
+		let alarm = alarms.check(&reading);
+		if json {
+			// alarm is null while nothing's wrong
+			print_json(&serde_json::json!({ "reading": reading, "alarm": alarm }));
+			continue;
+		}
+		print_reading(&reading);
+		if let Some(alarm) = alarm {
+			let bell = if watch_cmd.quiet { "" } else { "\x07" };
+			// bold white on red
+			println!("\x1b[1;37;41m ALARM: {alarm} \x1b[0m{bell}");
+		}
+	}
+}
+
+/// Subscribe to readings and show them on the dashboard until the user quits
+async fn dashboard(
+	watch_cmd: WatchCmd,
+	server: Server<'_>,
+	channel: Option<ChannelId>,
+) -> Result<(), Error> {
+	let mut client = connect(server).await?;
+	let request = Request::new(channel, ServerCmd::SubscribeReadings, None);
+	write_ipc(BytesMut::with_capacity(64), &mut client, &request)
+		.await
+		.map_err(Error::IPCWrite)?;
+	// reading isn't cancel safe, read in a task of its own instead of selecting on it
+	let (reading_tx, reading_rx) = tokio::sync::mpsc::channel(8);
+	tokio::spawn(async move {
+		loop {
+			let reading = read_reply::<Reading>(&mut client, request.id).await;
+			let failed = reading.is_err();
+			if reading_tx.send(reading).await.is_err() || failed {
+				break;
+			}
+		}
+	});
+	let mut terminal = ratatui::try_init().map_err(Error::Terminal)?;
+	let res = run_dashboard(&mut terminal, reading_rx, watch_cmd).await;
+	ratatui::restore();
+	res
+}
+
+async fn run_dashboard(
+	terminal: &mut DefaultTerminal,
+	mut reading_rx: tokio::sync::mpsc::Receiver<Result<Reading, ReplyError>>,
+	watch_cmd: WatchCmd,
+) -> Result<(), Error> {
+	let alarms = Alarms::from(watch_cmd);
+	let mut dashboard = Dashboard::default();
+	let mut alarm = None;
+	// how often to look for key presses
+	let mut keys = tokio::time::interval(Duration::from_millis(100));
+	loop {
+		terminal
+			.draw(|frame| dashboard.draw(frame, alarm.as_deref()))
+			.map_err(Error::Terminal)?;
+		select! {
+			reading = reading_rx.recv() => {
+				let Some(reading) = reading else {
+					return Ok(());
+				};
+				let reading = reading.map_err(Error::IPCRead)?;
+				dashboard.push(reading);
+				let new_alarm = alarms.check(&reading);
+				// ring once when an alarm goes off, not for every reading while it's on
+				if new_alarm.is_some() && alarm.is_none() && !watch_cmd.quiet {
+					print!("\x07");
+				}
+				alarm = new_alarm;
+			}
+			_ = keys.tick() => {
+				if quit_pressed().map_err(Error::Terminal)? {
+					return Ok(());
+				}
+			}
+		}
+	}
+}
+
+/// Drain the key presses waiting, q, Esc, or Ctrl-C quit
+fn quit_pressed() -> std::io::Result<bool> {
+	while event::poll(Duration::ZERO)? {
+		if let event::Event::Key(key) = event::read()?
+			&& key.kind == KeyEventKind::Press
+			&& (matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+				|| (key.code == KeyCode::Char('c')
+					&& key.modifiers.contains(KeyModifiers::CONTROL)))
+		{
+			return Ok(true);
+		}
+	}
+	Ok(false)
+}
+
+fn print_reading(reading: &Reading) {
+	let battery = match reading.battery_id {
+		Some(id) => format!("{}-{}", id.year, id.index),
+		None => "-".into(),
+	};
+	match reading.measurement {
+		Some(Measurement {
+			vbat,
+			ibat,
+			milliwatts,
+			temp_centi_c,
+			load_temp_centi_c,
+			fan_on,
+			load,
+			..
+		}) => {
+			let temp = match temp_centi_c {
+				Some(t) => format!(", {:.1} °C", f32::from(t) / 100.0),
+				None => String::new(),
+			};
+			let load_temp = match load_temp_centi_c {
+				Some(t) => format!(", heater: {:.1} °C", f32::from(t) / 100.0),
+				None => String::new(),
+			};
+			let fan = if fan_on { ", fan on" } else { "" };
+			let load = match load {
+				Some(load) => format!(", load: {} mV, {} mA", load.millivolts, load.milliamps),
+				None => String::new(),
+			};
+			println!(
+				"{:?}, battery: {battery}, {vbat} mV, {ibat} mA, {milliwatts} mW{load}{temp}{load_temp}{fan}",
+				reading.mode
+			);
+		}
+		None => println!("{:?}, battery: {battery}, no measurement yet", reading.mode),
+	}
+}
+
+/// Local thresholds for `watch`, separate from the server's faults and cutoff
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Alarms {
+	min_voltage: Option<MilliVolt>,
+	min_current: Option<MilliAmp>,
+	max_current: Option<MilliAmp>,
+}
+
+impl From<WatchCmd> for Alarms {
+	fn from(watch_cmd: WatchCmd) -> Self {
+		Self {
+			min_voltage: watch_cmd.min_mv.map(MilliVolt::new),
+			min_current: watch_cmd.min_ma.map(MilliAmp::new),
+			max_current: watch_cmd.max_ma.map(MilliAmp::new),
+		}
+	}
+}
+
+impl Alarms {
+	/// What's wrong with the reading, if anything.
+	/// The current only matters while testing, with the load off it's near 0.
+	fn check(&self, reading: &Reading) -> Option<String> {
+		let m = reading.measurement?;
+		if let Some(min) = self.min_voltage
+			&& m.vbat < min
+		{
+			return Some(format!("voltage {} mV below {min} mV", m.vbat));
+		}
+		if reading.mode != Mode::Testing {
+			return None;
+		}
+		if let Some(min) = self.min_current
+			&& m.ibat < min
+		{
+			return Some(format!("current {} mA below {min} mA", m.ibat));
+		}
+		if let Some(max) = self.max_current
+			&& m.ibat > max
+		{
+			return Some(format!("current {} mA above {max} mA", m.ibat));
+		}
+		None
+	}
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
 	#[error("can't connect to battery tester server")]
 	Connect(#[source] std::io::Error),
-	#[error("can't send message to server:\n{0:?}")]
+	#[error("can't send message to server")]
 	IPCWrite(#[source] tokio::io::Error),
+	#[error("can't read reply from server")]
+	IPCRead(#[source] ReplyError),
+	#[error("the server didn't take the command: {0}")]
+	Refused(Box<str>),
+	#[error(
+		"a {setting} of {millivolts} mV can't be right for a 12 V battery, it's from {} to {} mV",
+		range.start(),
+		range.end()
+	)]
+	Implausible {
+		setting: &'static str,
+		millivolts: u16,
+		range: RangeInclusive<u16>,
+	},
+	#[error("battery year {0} can't be right, it's from {FIRST_BATTERY_YEAR} to {1}")]
+	BatteryYear(u16, u16),
+	#[error("a {0} of 0 ends tests as soon as they start, leave it out for no limit")]
+	ZeroLimit(&'static str),
+	#[error("the battery interface can't run at {0} baud, it runs at one of {BAUDS:?}")]
+	Baud(u32),
+	#[error("there's no serial device {0}, the ports command lists the server's")]
+	NoSerialDev(Box<str>),
+	#[error("can't read the client config: {0:?}")]
+	ConfigRead(Box<Path>, #[source] std::io::Error),
+	#[error("can't parse the client config: {0:?}")]
+	ConfigParse(Box<Path>, #[source] toml::de::Error),
+	#[error("give a {0}, or set {1} in the client config, {CONFIG_PATH}")]
+	NoDefault(&'static str, &'static str),
+	#[error("{0} is done by the client, there's nothing to send the server")]
+	ClientOnly(&'static str),
+	#[error(
+		"the channel isn't ready to start, it's in {0:?}, is the battery ID set and the battery connected?"
+	)]
+	NotReady(Mode),
+	#[error("can't look for servers")]
+	List(#[source] std::io::Error),
+	#[error("can't draw the dashboard")]
+	Terminal(#[source] std::io::Error),
+	#[error("can't read from the terminal")]
+	Editor(#[source] ReadlineError),
+	#[error(transparent)]
+	Analyze(#[from] AnalysisError),
+	#[error(transparent)]
+	Dump(#[from] pc_common::Error),
+	#[cfg(feature = "parquet")]
+	#[error(transparent)]
+	Export(#[from] pc_common::export::ExportError),
 }
 
-#[derive(FromArgs, PartialEq, Eq, Clone)]
+/// Where [`ClientConfig`] is looked for on Linux, see [`ClientConfig::path`]
+const CONFIG_PATH: &str = "~/.config/battery-tester/client.toml";
+
+/// Defaults for a fixed bench, so they needn't be given every time, e.g.
+/// ```toml
+/// name = "bench-2"
+/// device = "/dev/ttyACM0"
+/// baud = 115200
+/// cutoff_mv = 10500
+/// ```
+/// Options and arguments given to the client win over these.
+#[derive(Debug, Default, PartialEq, Eq, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ClientConfig {
+	/// Server to talk to without --name or --remote
+	name: Option<String>,
+	/// For `device` on its own, and for `start` on a channel without one
+	device: Option<String>,
+	/// To open the config's `device` at, the server's --baud if left out
+	baud: Option<u32>,
+	/// For `cutoff` on its own, and for `start` on a channel given the config's `device`
+	cutoff_mv: Option<u16>,
+}
+
+impl ClientConfig {
+	/// The defaults when there's no config, it's optional
+	fn load() -> Result<Self, Error> {
+		match Self::path(|var| std::env::var_os(var)) {
+			Some(path) => Self::load_from(&path),
+			None => Ok(Self::default()),
+		}
+	}
+
+	fn load_from(path: &Path) -> Result<Self, Error> {
+		let text = match std::fs::read_to_string(path) {
+			Ok(text) => text,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+			Err(e) => return Err(Error::ConfigRead(path.into(), e)),
+		};
+		toml::from_str(&text).map_err(|e| Error::ConfigParse(path.into(), e))
+	}
+
+	/// [`CONFIG_PATH`], under `$XDG_CONFIG_HOME` when it's set, or `%APPDATA%` on Windows,
+	/// with the environment's variables from `var`
+	fn path(var: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+		let dir = if cfg!(windows) {
+			var("APPDATA").map(PathBuf::from)
+		} else {
+			var("XDG_CONFIG_HOME")
+				.filter(|dir| !dir.is_empty())
+				.map(PathBuf::from)
+				.or_else(|| var("HOME").map(|home| Path::new(&home).join(".config")))
+		};
+		Some(dir?.join("battery-tester").join("client.toml"))
+	}
+
+	/// `remote`, or the server named `name`, or the config's name
+	fn server<'a>(&'a self, remote: Option<&'a str>, name: Option<&'a str>) -> Server<'a> {
+		match remote {
+			Some(addr) => Server::Remote(addr),
+			None => Server::Local(name.or(self.name.as_deref())),
+		}
+	}
+
+	/// The config's device and cutoff for `device` and `cutoff` on their own
+	fn fill(&self, cmd: Subcommands) -> Result<Subcommands, Error> {
+		match cmd {
+			Subcommands::SerialDev(SerialDevCmd {
+				device_name: None,
+				baud,
+			}) => {
+				let device = self
+					.device
+					.clone()
+					.ok_or(Error::NoDefault("device", "device"))?;
+				Ok(Subcommands::SerialDev(SerialDevCmd {
+					device_name: Some(device),
+					baud: baud.or(self.baud),
+				}))
+			}
+			Subcommands::SetCutoff(CutoffCmd { millivolts: None }) => {
+				let cutoff = self
+					.cutoff_mv
+					.ok_or(Error::NoDefault("cutoff", "cutoff_mv"))?;
+				Ok(Subcommands::SetCutoff(CutoffCmd {
+					millivolts: Some(cutoff),
+				}))
+			}
+			cmd => Ok(cmd),
+		}
+	}
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Eq, Clone)]
 /// Battery tester client
 pub struct Cli {
+	/// name of the server to talk to, the one started without a --name by default
+	#[argh(option, short = 'n')]
+	name: Option<String>,
+	/// host:port of a server on another machine started with --listen, instead of a local one
+	#[argh(option, short = 'r')]
+	remote: Option<String>,
+	/// channel of a server testing several batteries at once, 0 by default
+	#[argh(option, short = 'c')]
+	channel: Option<ChannelId>,
+	/// print replies and results as JSON instead of text, a line for each, for scripts
+	#[argh(switch)]
+	json: bool,
+	/// file with the token of a server started with --token-file, to send it commands
+	#[argh(option)]
+	token_file: Option<std::path::PathBuf>,
 	#[argh(subcommand)]
 	cmd: Subcommands,
 }
 
-#[derive(Debug, PartialEq, FromArgs, Eq, Clone)]
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
 #[argh(subcommand)]
 enum Subcommands {
 	BatteryID(BatteryIdCmd),
 	SerialDev(SerialDevCmd),
 	SetCutoff(CutoffCmd),
+	Disconnect(DisconnectCmd),
+	MaxVoltage(MaxVoltageCmd),
+	Chemistry(ChemistryCmd),
+	MaxDuration(MaxDurationCmd),
+	MaxCapacity(MaxCapacityCmd),
+	LoadModel(LoadModelCmd),
+	ConstantCurrent(ConstantCurrentCmd),
+	WindowSamples(WindowSamplesCmd),
+	Filter(FilterCmd),
+	Trim(TrimCmd),
+	BatteryDetect(BatteryDetectCmd),
+	SelfTest(SelfTestCmd),
 	Start(StartCmd),
+	Unschedule(UnscheduleCmd),
 	/// cancel the test
 	Cancel(CancelCmd),
 	/// shutdown the server
 	Shutdown(ShutdownCmd),
 	ClearFault(ClearFaultCmd),
 	AllowUndercurrent(UndercurrentResponse),
+	Status(StatusCmd),
+	Capabilities(CapabilitiesCmd),
+	Charge(ChargeCmd),
+	Autonomous(AutonomousCmd),
+	Format(FormatCmd),
+	Note(NoteCmd),
+	Operator(OperatorCmd),
+	Battery(BatteryCmd),
+	Queue(QueueCmd),
+	Calibrate(CalibrateCmd),
+	Watch(WatchCmd),
+	Measurement(MeasurementCmd),
+	Mode(ModeCmd),
+	Ports(PortsCmd),
+	List(ListCmd),
+	Analyze(AnalyzeCmd),
+	DecodeDump(DecodeDumpCmd),
+	#[cfg(feature = "parquet")]
+	Export(ExportCmd),
+	Repl(ReplCmd),
+	Completions(CompletionsCmd),
+}
+
+/// print a tab completion script for the client, e.g. `battery-tester-client completions bash
+/// > /etc/bash_completion.d/battery-tester-client`
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "completions")]
+struct CompletionsCmd {
+	/// bash, zsh, or fish
+	#[argh(positional)]
+	shell: Shell,
+}
+
+/// type subcommands one after another over one connection, with line editing and history,
+/// and see the mode as it changes
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "repl")]
+struct ReplCmd {}
+
+/// convert a saved test to another format, for tests too big to handle as they are
+#[cfg(feature = "parquet")]
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand, name = "export")]
+struct ExportCmd {
+	/// the Parquet file to write
+	#[argh(option)]
+	parquet: std::path::PathBuf,
+	/// the test's TSV, CSV, or JSON Lines file, then its -continued- files in order if it
+	/// was split over several
+	#[argh(positional)]
+	files: Vec<std::path::PathBuf>,
+}
+
+/// report the capacity, energy, and current of a saved test, and the capacity to each voltage
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand, name = "analyze")]
+struct AnalyzeCmd {
+	/// the test's TSV, CSV, or JSON Lines file, then its -continued- files in order if it
+	/// was split over several
+	#[argh(positional)]
+	files: Vec<std::path::PathBuf>,
+	/// millivolts to report the capacity to, can be given more than once.
+	/// 12000, 11800, and 11500 by default.
+	#[argh(option)]
+	at: Vec<u16>,
+}
+
+/// print a server's --dump-serial, each chunk sent or read in hex with the frames it ends decoded
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand, name = "decode-dump")]
+struct DecodeDumpCmd {
+	/// the dump file
+	#[argh(positional)]
+	file: std::path::PathBuf,
+}
+
+/// print the latest measurement as a JSON object, measurement is null before the first one
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "measurement")]
+struct MeasurementCmd {}
+
+/// print the mode as a JSON object
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "mode")]
+struct ModeCmd {}
+
+/// list the serial ports on the server's machine with their USB IDs and names, to find the
+/// battery interface's for `device`
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "ports")]
+struct PortsCmd {}
+
+/// list the servers on this machine, pick one with --name (remote servers aren't listed)
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "list")]
+struct ListCmd {}
+
+/// show a live dashboard of the test, with local alarms that don't change the server's faults
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "watch")]
+struct WatchCmd {
+	/// alarm when the battery is below this many millivolts
+	#[argh(option)]
+	min_mv: Option<u16>,
+	/// alarm when the current is below this many milliamps while testing
+	#[argh(option)]
+	min_ma: Option<i16>,
+	/// alarm when the current is above this many milliamps while testing
+	#[argh(option)]
+	max_ma: Option<i16>,
+	/// print a line every interval instead of showing the dashboard, for logs and dumb terminals
+	#[argh(switch)]
+	plain: bool,
+	/// seconds between readings with --plain
+	#[argh(option, default = "1")]
+	interval_s: u64,
+	/// don't ring the terminal bell on alarms
+	#[argh(switch)]
+	quiet: bool,
+}
+
+/// manage the registry of batteries, checked when a battery ID is set
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand, name = "battery")]
+struct BatteryCmd {
+	#[argh(subcommand)]
+	cmd: BatterySubcommands,
+}
+
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand)]
+enum BatterySubcommands {
+	Add(BatteryAddCmd),
+	List(BatteryListCmd),
+}
+
+/// add a battery, or replace what's known about one
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand, name = "add")]
+struct BatteryAddCmd {
+	/// battery year
+	#[argh(option, short = 'y')]
+	year: u16,
+	/// battery index
+	#[argh(option, short = 'i')]
+	index: u8,
+	/// e.g. SLA or LiFePO4
+	#[argh(option)]
+	chemistry: String,
+	/// capacity the manufacturer gives in mAh
+	#[argh(option)]
+	nominal_mah: Option<u32>,
+	/// when the battery was made, as it's written on the battery
+	#[argh(option)]
+	manufactured: Option<String>,
+}
+
+/// list the batteries in the registry
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "list")]
+struct BatteryListCmd {}
+
+/// test batteries back to back, each set up when the one before it ends
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand, name = "queue")]
+struct QueueCmd {
+	#[argh(subcommand)]
+	cmd: QueueSubcommands,
+}
+
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand)]
+enum QueueSubcommands {
+	Add(QueueAddCmd),
+	Clear(QueueClearCmd),
+}
+
+/// add batteries to the end of the queue, see them with status
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand, name = "add")]
+struct QueueAddCmd {
+	/// year-index, e.g. 2024-7, or year-index:millivolts to test that battery to its own cutoff
+	#[argh(positional)]
+	batteries: Vec<QueuedTest>,
+}
+
+/// empty the queue, the battery being tested carries on
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "clear")]
+struct QueueClearCmd {}
+
+/// calibrate the serial device's INA260 against a reference meter, applied while testing
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand, name = "calibrate")]
+struct CalibrateCmd {
+	#[argh(subcommand)]
+	cmd: CalibrateSubcommands,
+}
+
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand)]
+enum CalibrateSubcommands {
+	Point(CalibratePointCmd),
+	Save(CalibrateSaveCmd),
+	Clear(CalibrateClearCmd),
+	Show(CalibrateShowCmd),
+}
+
+/// pair what the reference meter reads now with the latest measurement, take a few
+/// across the range, e.g. with and without the load
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "point")]
+struct CalibratePointCmd {
+	/// battery voltage on the reference meter in mV
+	#[argh(option)]
+	millivolts: Option<u16>,
+	/// battery current on the reference meter in mA
+	#[argh(option)]
+	milliamps: Option<i16>,
+}
+
+/// fit the points taken and save the calibration for the device
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "save")]
+struct CalibrateSaveCmd {}
+
+/// forget the device's calibration, measurements are used as they are
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "clear")]
+struct CalibrateClearCmd {}
+
+/// show the device's calibration
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "show")]
+struct CalibrateShowCmd {}
+
+/// add a note to the test, e.g. the cell chemistry, lot number, or ambient temperature
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand, name = "note")]
+struct NoteCmd {
+	/// the note, quoted if it has spaces
+	#[argh(positional)]
+	text: String,
+}
+
+/// set who's running the tests, saved with each test until it's changed
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
+#[argh(subcommand, name = "operator")]
+struct OperatorCmd {
+	/// the operator's name, quoted if it has spaces
+	#[argh(positional)]
+	name: String,
+}
+
+/// set how tests from the next battery ID on are saved
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "format")]
+struct FormatCmd {
+	/// tsv, csv, jsonl, or sqlite (needs the server started with --db)
+	#[argh(positional)]
+	format: OutputFormat,
 }
 
+/// charge the battery to full, then start the test
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "charge")]
+struct ChargeCmd {}
+
+/// have the battery interface run the test on its own and log it to its flash, so it goes on
+/// if this PC sleeps or crashes, or with --fetch save that log as the test set up
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "autonomous")]
+struct AutonomousCmd {
+	/// save the log of the last test the battery interface ran on its own
+	#[argh(switch)]
+	fetch: bool,
+}
+
+/// print what this server and the connected battery interface support
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "capabilities")]
+struct CapabilitiesCmd {}
+
+/// print the status of every channel, or the one given with --channel
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "status")]
+struct StatusCmd {}
+
 /// Undercurrent fault behavior
-#[derive(Debug, PartialEq, FromArgs, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
 #[argh(subcommand, name = "undercurrent")]
 struct UndercurrentResponse {
 	/// allow undercurrent
@@ -58,36 +1431,246 @@ struct UndercurrentResponse {
 }
 
 /// Clear any faults
-#[derive(Debug, PartialEq, FromArgs, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
 #[argh(subcommand, name = "clear")]
 struct ClearFaultCmd {}
 
-/// start the test
-#[derive(Debug, PartialEq, FromArgs, Eq, Clone, Copy)]
+/// start the test, now or at a set time once the battery is connected
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
 #[argh(subcommand, name = "start")]
-struct StartCmd {}
+struct StartCmd {
+	/// local time to start at instead of now, HH:MM for the next time it's that time,
+	/// or YYYY-MM-DD HH:MM
+	#[argh(option)]
+	at: Option<StartTime>,
+	/// minutes from now to start at instead of now
+	#[argh(option)]
+	after_min: Option<u64>,
+}
+
+/// A local time to start the test at, for `start --at`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct StartTime(std::time::SystemTime);
+
+impl std::str::FromStr for StartTime {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		use chrono::{Local, NaiveDateTime, NaiveTime, TimeDelta};
+		let now = Local::now();
+		let local = match NaiveTime::parse_from_str(s, "%H:%M") {
+			Ok(time) => {
+				let today = now.date_naive().and_time(time);
+				// a time already gone today is tomorrow's
+				if today <= now.naive_local() {
+					today + TimeDelta::days(1)
+				} else {
+					today
+				}
+			}
+			Err(_) => NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+				.map_err(|_| format!("unknown time: {s}, expected HH:MM or YYYY-MM-DD HH:MM"))?,
+		};
+		local
+			.and_local_timezone(Local)
+			.earliest()
+			.map(|start_at| StartTime(start_at.into()))
+			.ok_or_else(|| format!("{s} is skipped by a clock change"))
+	}
+}
+
+/// cancel a start set with start --at or --after-min, the battery stays set up
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "unschedule")]
+struct UnscheduleCmd {}
 
 /// cancel the test
-#[derive(Debug, PartialEq, FromArgs, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
 #[argh(subcommand, name = "cancel")]
 struct CancelCmd {}
 
 /// cancel the test and shutdown the server
-#[derive(Debug, PartialEq, FromArgs, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
 #[argh(subcommand, name = "shutdown")]
 struct ShutdownCmd {}
 
 /// set the voltage cutoff
-#[derive(Debug, PartialEq, FromArgs, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
 #[argh(subcommand, name = "cutoff")]
 struct CutoffCmd {
-	/// test cutoff voltage in millivolts
+	/// test cutoff voltage in millivolts, the client config's cutoff_mv if left out
+	#[argh(positional)]
+	millivolts: Option<u16>,
+}
+
+/// set the voltage under which the battery counts as removed rather than discharged
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "disconnect")]
+struct DisconnectCmd {
+	/// removed battery voltage in millivolts
+	#[argh(positional)]
+	millivolts: u16,
+}
+
+/// set the voltage over which a test won't start, the wrong battery or one on its charger
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "max-voltage")]
+struct MaxVoltageCmd {
+	/// highest battery voltage in millivolts
 	#[argh(positional)]
 	millivolts: u16,
 }
 
+/// set the kind of battery, which sets the cutoff and the limits on current and voltage
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "chemistry")]
+struct ChemistryCmd {
+	/// lead-acid-6 or lifepo4-4s
+	#[argh(positional)]
+	chemistry: Chemistry,
+}
+
+/// end tests after testing this long, e.g. for partial discharges
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "max-duration")]
+struct MaxDurationCmd {
+	/// minutes of testing, left out to test until the cutoff
+	#[argh(positional)]
+	minutes: Option<u64>,
+}
+
+/// end tests once this much is taken out of the battery, e.g. for partial discharges
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "max-capacity")]
+struct MaxCapacityCmd {
+	/// milliamp hours, left out to test until the cutoff
+	#[argh(positional)]
+	mah: Option<u32>,
+}
+
+/// set what the load draws for the battery interface's under and overcurrent faults, so a
+/// different load doesn't need the firmware rebuilt. All left out for the firmware's own,
+/// 8.4 A at 12 V within 200 mA.
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "set-load-model")]
+struct LoadModelCmd {
+	/// resistance of the load, the expected current is the battery voltage over it
+	#[argh(option, short = 'r')]
+	milliohms: Option<u32>,
+	/// mA added to the expected current, what the rig draws alongside the load
+	#[argh(option, short = 'o')]
+	offset_ma: Option<i16>,
+	/// mA the current can be from the expected before it's a fault
+	#[argh(option, short = 'd')]
+	max_deviation: Option<i16>,
+}
+
+/// discharge at a constant current, the battery interface steps the load's PWM to hold it
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "constant-current")]
+struct ConstantCurrentCmd {
+	/// milliamps, left out for the load's full current
+	#[argh(positional)]
+	milliamps: Option<i16>,
+}
+
+/// set how many samples the battery interface averages into each measurement while testing,
+/// fewer for finer-grained data and more for slow trend tests
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "window-samples")]
+struct WindowSamplesCmd {
+	/// samples from 1 to 100, about 100 ms each, left out for the firmware's 10
+	#[argh(positional)]
+	samples: Option<u8>,
+}
+
+/// set whether the battery interface drops each measurement's highest and lowest samples
+/// while testing, so a single spike from contact bounce or PWM switching isn't averaged in
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "filter")]
+struct FilterCmd {
+	/// mean or trimmed-mean
+	#[argh(positional)]
+	filter: MeasurementFilter,
+}
+
+/// How each measurement's samples are combined, for `filter`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct MeasurementFilter(WindowFilter);
+
+impl std::str::FromStr for MeasurementFilter {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"mean" => Ok(Self(WindowFilter::Mean)),
+			"trimmed-mean" => Ok(Self(WindowFilter::TrimmedMean)),
+			_ => Err(format!(
+				"unknown filter: {s}, expected mean or trimmed-mean"
+			)),
+		}
+	}
+}
+
+/// change the battery interface's trim and save it in its flash, without a test set up;
+/// status shows the trim it's using, options left out keep what it has
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "trim")]
+struct TrimCmd {
+	/// parts per million to scale current readings by, 1000000 leaves them
+	#[argh(option)]
+	milliamps_ppm: Option<u32>,
+	/// mA added to current readings after scaling
+	#[argh(option)]
+	milliamps_offset: Option<i16>,
+	/// parts per million to scale voltage readings by, 1000000 leaves them
+	#[argh(option)]
+	millivolts_ppm: Option<u32>,
+	/// mV added to voltage readings after scaling
+	#[argh(option)]
+	millivolts_offset: Option<i16>,
+	/// µs added to the PWM pulse width, measured with a scope
+	#[argh(option)]
+	pwm_us: Option<u16>,
+}
+
+/// change how the battery interface tells a battery is connected and save it in its flash,
+/// without a test set up; status shows what it's using, options left out keep what it has
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "battery-detect")]
+struct BatteryDetectCmd {
+	/// ms the input has to read present before the battery counts as connected
+	#[argh(option)]
+	debounce_ms: Option<u16>,
+	/// level of the input with a battery: high or low
+	#[argh(option)]
+	polarity: Option<InputPolarity>,
+}
+
+/// The battery-present input's level with a battery, for `battery-detect --polarity`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct InputPolarity(Polarity);
+
+impl std::str::FromStr for InputPolarity {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"high" => Ok(Self(Polarity::ActiveHigh)),
+			"low" => Ok(Self(Polarity::ActiveLow)),
+			_ => Err(format!("unknown polarity: {s}, expected high or low")),
+		}
+	}
+}
+
+/// have the battery interface check its INA260s and pulse the load, without a test set up;
+/// the server prints the result and status shows it
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
+#[argh(subcommand, name = "self-test")]
+struct SelfTestCmd {}
+
 /// set the battery ID
-#[derive(Debug, PartialEq, FromArgs, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone, Copy)]
 #[argh(subcommand, name = "id")]
 struct BatteryIdCmd {
 	/// battery year
@@ -99,33 +1682,638 @@ struct BatteryIdCmd {
 }
 
 /// set the name of the serial device.
-#[derive(Debug, PartialEq, FromArgs, Eq, Clone)]
+#[derive(Debug, PartialEq, FromArgs, ArgsInfo, Eq, Clone)]
 #[argh(subcommand, name = "device")]
 struct SerialDevCmd {
-	/// the name of the serical device /dev/tty-something or COM-something.
+	/// the name of the serical device /dev/tty-something or COM-something, the client
+	/// config's device if left out.
 	#[argh(positional)]
-	device_name: String,
+	device_name: Option<String>,
+	/// baud to open it at, the server's --baud by default
+	#[argh(option)]
+	baud: Option<u32>,
 }
 
-impl From<Subcommands> for ServerCmd {
-	fn from(value: Subcommands) -> Self {
-		match value {
-			Subcommands::BatteryID(battery_id_cmd) => Self::SetBatteryId(pc_common::BatteryID {
+impl TryFrom<Subcommands> for ServerCmd {
+	type Error = Error;
+
+	fn try_from(value: Subcommands) -> Result<Self, Error> {
+		Ok(match value {
+			Subcommands::BatteryID(battery_id_cmd) => Self::SetBatteryId(BatteryID {
 				year: battery_id_cmd.year,
 				index: battery_id_cmd.index,
 			}),
-			Subcommands::SerialDev(serial_dev_cmd) => {
-				Self::SetSerialDev(serial_dev_cmd.device_name.into_boxed_str())
+			Subcommands::SerialDev(SerialDevCmd {
+				device_name: Some(device_name),
+				baud,
+			}) => Self::SetSerialDev(device_name.into_boxed_str(), baud),
+			Subcommands::SetCutoff(CutoffCmd {
+				millivolts: Some(millivolts),
+			}) => Self::SetCutoffMillis(millivolts.into()),
+			// the client config's are filled in first, see server_cmd
+			Subcommands::SerialDev(_serial_dev_cmd) => {
+				return Err(Error::NoDefault("device", "device"));
+			}
+			Subcommands::SetCutoff(_cutoff_cmd) => {
+				return Err(Error::NoDefault("cutoff", "cutoff_mv"));
+			}
+			Subcommands::Disconnect(disconnect_cmd) => {
+				Self::SetDisconnectMillis(disconnect_cmd.millivolts.into())
 			}
-			Subcommands::SetCutoff(cutoff_cmd) => {
-				Self::SetCutoffMillis(cutoff_cmd.millivolts.into())
+			Subcommands::MaxVoltage(max_voltage_cmd) => {
+				Self::SetMaxVoltageMillis(max_voltage_cmd.millivolts.into())
 			}
+			Subcommands::Chemistry(chemistry_cmd) => Self::SetChemistry(chemistry_cmd.chemistry),
+			Subcommands::MaxDuration(max_duration_cmd) => Self::SetMaxDuration(
+				max_duration_cmd
+					.minutes
+					.map(|minutes| std::time::Duration::from_secs(minutes * 60)),
+			),
+			Subcommands::MaxCapacity(max_capacity_cmd) => {
+				Self::SetMaxCapacity(max_capacity_cmd.mah)
+			}
+			Subcommands::LoadModel(LoadModelCmd {
+				milliohms: None,
+				offset_ma: None,
+				max_deviation: None,
+			}) => Self::SetLoadModel(None),
+			Subcommands::LoadModel(load_model_cmd) => {
+				let default = LoadModel::default();
+				Self::SetLoadModel(Some(LoadModel {
+					milliohms: load_model_cmd.milliohms.unwrap_or(default.milliohms),
+					offset: load_model_cmd
+						.offset_ma
+						.map_or(default.offset, MilliAmp::new),
+					max_deviation: load_model_cmd
+						.max_deviation
+						.map_or(default.max_deviation, MilliAmp::new),
+				}))
+			}
+			Subcommands::ConstantCurrent(constant_current_cmd) => {
+				Self::SetCurrentSetpoint(constant_current_cmd.milliamps.map(MilliAmp::new))
+			}
+			Subcommands::WindowSamples(window_samples_cmd) => {
+				Self::SetWindowSamples(window_samples_cmd.samples)
+			}
+			Subcommands::Filter(filter_cmd) => Self::SetFilter(filter_cmd.filter.0),
+			Subcommands::Trim(trim_cmd) => Self::SetTrim(TrimChange {
+				milliamps_ppm: trim_cmd.milliamps_ppm,
+				milliamps_offset: trim_cmd.milliamps_offset,
+				millivolts_ppm: trim_cmd.millivolts_ppm,
+				millivolts_offset: trim_cmd.millivolts_offset,
+				pwm_us: trim_cmd.pwm_us,
+			}),
+			Subcommands::BatteryDetect(detect_cmd) => Self::SetBatteryDetect(BatteryDetectChange {
+				debounce_ms: detect_cmd.debounce_ms,
+				polarity: detect_cmd.polarity.map(|polarity| polarity.0),
+			}),
+			Subcommands::Start(StartCmd {
+				at: Some(at),
+				after_min: _,
+			}) => Self::StartAt(at.0),
+			Subcommands::Start(StartCmd {
+				at: None,
+				after_min: Some(minutes),
+			}) => Self::StartAfter(std::time::Duration::from_secs(minutes * 60)),
 			Subcommands::Start(_start_cmd) => Self::StartTest,
+			Subcommands::Unschedule(_unschedule_cmd) => Self::UnscheduleStart,
 			Subcommands::Cancel(_cancel_cmd) => Self::CancelTest,
 			Subcommands::Shutdown(_shutdown_cmd) => Self::ShutDown,
 			Subcommands::ClearFault(_clear_fault_cmd) => Self::ClearFault,
 			Subcommands::AllowUndercurrent(resp) if resp.allow => Self::AllowUndercurrent,
 			Subcommands::AllowUndercurrent(_resp) => Self::DisallowUndercurrent,
+			Subcommands::Status(_status_cmd) => Self::Status,
+			Subcommands::Capabilities(_capabilities_cmd) => Self::GetCapabilities,
+			Subcommands::Charge(_charge_cmd) => Self::Charge,
+			Subcommands::Autonomous(autonomous_cmd) if autonomous_cmd.fetch => {
+				Self::FetchAutonomousLog
+			}
+			Subcommands::Autonomous(_autonomous_cmd) => Self::StartAutonomous,
+			Subcommands::Format(format_cmd) => Self::SetOutputFormat(format_cmd.format),
+			Subcommands::Note(note_cmd) => Self::SetNote(note_cmd.text.into_boxed_str()),
+			Subcommands::Operator(operator_cmd) => {
+				Self::SetOperator(operator_cmd.name.into_boxed_str())
+			}
+			Subcommands::Battery(BatteryCmd {
+				cmd: BatterySubcommands::Add(add_cmd),
+			}) => Self::AddBattery(RegisteredBattery {
+				id: BatteryID {
+					year: add_cmd.year,
+					index: add_cmd.index,
+				},
+				chemistry: add_cmd.chemistry.into_boxed_str(),
+				nominal_mah: add_cmd.nominal_mah,
+				manufactured: add_cmd.manufactured.map(String::into_boxed_str),
+				tests: 0,
+			}),
+			Subcommands::Battery(BatteryCmd {
+				cmd: BatterySubcommands::List(_list_cmd),
+			}) => Self::ListBatteries,
+			Subcommands::Queue(QueueCmd {
+				cmd: QueueSubcommands::Add(add_cmd),
+			}) => Self::Queue(QueueChange::Add(add_cmd.batteries)),
+			Subcommands::Queue(QueueCmd {
+				cmd: QueueSubcommands::Clear(_clear_cmd),
+			}) => Self::Queue(QueueChange::Clear),
+			Subcommands::Calibrate(CalibrateCmd {
+				cmd: CalibrateSubcommands::Point(point_cmd),
+			}) => Self::Calibrate(CalibrationChange::Point {
+				millivolts: point_cmd.millivolts.map(MilliVolt::new),
+				milliamps: point_cmd.milliamps.map(MilliAmp::new),
+			}),
+			Subcommands::Calibrate(CalibrateCmd {
+				cmd: CalibrateSubcommands::Save(_save_cmd),
+			}) => Self::Calibrate(CalibrationChange::Save),
+			Subcommands::Calibrate(CalibrateCmd {
+				cmd: CalibrateSubcommands::Clear(_clear_cmd),
+			}) => Self::Calibrate(CalibrationChange::Clear),
+			Subcommands::Calibrate(CalibrateCmd {
+				cmd: CalibrateSubcommands::Show(_show_cmd),
+			}) => Self::GetCalibration,
+			Subcommands::Watch(_watch_cmd) => Self::GetReading,
+			Subcommands::Measurement(_measurement_cmd) => Self::GetLastMeasurement,
+			Subcommands::Mode(_mode_cmd) => Self::GetMode,
+			Subcommands::Ports(_ports_cmd) => Self::ListSerialPorts,
+			Subcommands::List(_list_cmd) => return Err(Error::ClientOnly("list")),
+			Subcommands::Analyze(_analyze_cmd) => return Err(Error::ClientOnly("analyze")),
+			Subcommands::Completions(_cmd) => return Err(Error::ClientOnly("completions")),
+			Subcommands::DecodeDump(_decode_cmd) => return Err(Error::ClientOnly("decode-dump")),
+			#[cfg(feature = "parquet")]
+			Subcommands::Export(_export_cmd) => return Err(Error::ClientOnly("export")),
+			Subcommands::Repl(_repl_cmd) => Self::Session,
+			Subcommands::SelfTest(_self_test_cmd) => Self::SelfTest,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use battery_tester_common::{USB_PID, USB_VID};
+	use pc_common::{serial::UsbListing, write_reply};
+	use tokio::io::AsyncReadExt;
+
+	fn config_file(test: &str, text: &str) -> PathBuf {
+		let path = std::env::temp_dir().join(format!(
+			"battery-tester-client-{test}-{}.toml",
+			std::process::id()
+		));
+		std::fs::write(&path, text).unwrap();
+		path
+	}
+
+	fn bench() -> ClientConfig {
+		ClientConfig {
+			name: Some("bench-2".to_string()),
+			device: Some("/dev/ttyACM0".to_string()),
+			baud: Some(115_200),
+			cutoff_mv: Some(10_500),
+		}
+	}
+
+	#[test]
+	fn test_config_loaded() {
+		let path = config_file(
+			"loaded",
+			"name = \"bench-2\"\ndevice = \"/dev/ttyACM0\"\nbaud = 115200\ncutoff_mv = 10500\n",
+		);
+		let config = ClientConfig::load_from(&path);
+		let _ = std::fs::remove_file(path);
+		assert_eq!(config.unwrap(), bench());
+	}
+
+	#[test]
+	fn test_config_missing_is_the_defaults() {
+		let path = std::env::temp_dir().join("battery-tester-client-no-such-config.toml");
+		assert_eq!(
+			ClientConfig::load_from(&path).unwrap(),
+			ClientConfig::default()
+		);
+	}
+
+	#[test]
+	fn test_config_malformed() {
+		for (test, text) in [
+			("unparsable", "device = /dev/ttyACM0\n"),
+			("wrong-type", "cutoff_mv = \"10.5 V\"\n"),
+			// a typo would otherwise be ignored without a word
+			("unknown-field", "cutof_mv = 10500\n"),
+		] {
+			let path = config_file(test, text);
+			let config = ClientConfig::load_from(&path);
+			let _ = std::fs::remove_file(path);
+			assert!(matches!(config, Err(Error::ConfigParse(..))), "{test}");
+		}
+	}
+
+	#[cfg(not(windows))]
+	#[test]
+	fn test_config_path() {
+		let env = |vars: &'static [(&str, &str)]| {
+			move |var: &str| {
+				vars.iter()
+					.find(|(name, _)| *name == var)
+					.map(|(_, value)| OsString::from(value))
+			}
+		};
+		assert_eq!(
+			ClientConfig::path(env(&[("XDG_CONFIG_HOME", "/xdg"), ("HOME", "/home/me")])),
+			Some(PathBuf::from("/xdg/battery-tester/client.toml"))
+		);
+		assert_eq!(
+			ClientConfig::path(env(&[("XDG_CONFIG_HOME", ""), ("HOME", "/home/me")])),
+			Some(PathBuf::from("/home/me/.config/battery-tester/client.toml"))
+		);
+		assert_eq!(ClientConfig::path(env(&[])), None);
+	}
+
+	#[test]
+	fn test_cli_wins_over_config() {
+		let config = bench();
+		let given = Subcommands::SerialDev(SerialDevCmd {
+			device_name: Some("/dev/ttyUSB1".to_string()),
+			baud: None,
+		});
+		// the config's baud is for the config's device
+		assert_eq!(config.fill(given.clone()).unwrap(), given);
+		let given = Subcommands::SetCutoff(CutoffCmd {
+			millivolts: Some(11_000),
+		});
+		assert_eq!(config.fill(given.clone()).unwrap(), given);
+
+		assert_eq!(
+			config.server(None, Some("bench-3")),
+			Server::Local(Some("bench-3"))
+		);
+		assert_eq!(
+			config.server(Some("10.0.0.2:7070"), Some("bench-3")),
+			Server::Remote("10.0.0.2:7070")
+		);
+	}
+
+	#[test]
+	fn test_config_fills_what_isnt_given() {
+		let config = bench();
+		let device = config.fill(Subcommands::SerialDev(SerialDevCmd {
+			device_name: None,
+			baud: None,
+		}));
+		assert_eq!(
+			device.unwrap(),
+			Subcommands::SerialDev(SerialDevCmd {
+				device_name: Some("/dev/ttyACM0".to_string()),
+				baud: Some(115_200),
+			})
+		);
+		let cutoff = config.fill(Subcommands::SetCutoff(CutoffCmd { millivolts: None }));
+		assert_eq!(
+			cutoff.unwrap(),
+			Subcommands::SetCutoff(CutoffCmd {
+				millivolts: Some(10_500),
+			})
+		);
+		assert_eq!(config.server(None, None), Server::Local(Some("bench-2")));
+	}
+
+	#[test]
+	fn test_nothing_to_fill_from() {
+		let config = ClientConfig::default();
+		let device = Subcommands::SerialDev(SerialDevCmd {
+			device_name: None,
+			baud: None,
+		});
+		assert!(matches!(
+			config.fill(device.clone()),
+			Err(Error::NoDefault("device", "device"))
+		));
+		assert!(matches!(
+			ServerCmd::try_from(device),
+			Err(Error::NoDefault("device", "device"))
+		));
+		let cutoff = Subcommands::SetCutoff(CutoffCmd { millivolts: None });
+		assert!(matches!(
+			config.fill(cutoff.clone()),
+			Err(Error::NoDefault("cutoff", "cutoff_mv"))
+		));
+		assert!(matches!(
+			ServerCmd::try_from(cutoff),
+			Err(Error::NoDefault("cutoff", "cutoff_mv"))
+		));
+		assert_eq!(config.server(None, None), Server::Local(None));
+	}
+
+	fn reading(mode: Mode, millivolts: u16, milliamps: i16) -> Reading {
+		Reading {
+			mode,
+			battery_id: None,
+			cutoff: MilliVolt::new(10_500),
+			measurement: Some(Measurement {
+				vbat: MilliVolt::new(millivolts),
+				ibat: MilliAmp::new(milliamps),
+				milliwatts: battery_tester_common::MilliWatt::new(0),
+				sample_index: 0,
+				sample_start_ms: 0,
+				sample_duration_ms: 900,
+				temp_centi_c: None,
+				load_temp_centi_c: None,
+				fan_on: false,
+				load: None,
+			}),
+			live: None,
+		}
+	}
+
+	#[test]
+	fn test_watch_alarms() {
+		let watch_cmd = WatchCmd::from_args(
+			&["watch"],
+			&["--min-mv", "11000", "--min-ma", "1500", "--max-ma", "2500"],
+		)
+		.unwrap();
+		let alarms = Alarms::from(watch_cmd);
+		assert_eq!(alarms.check(&reading(Mode::Testing, 11_500, 2_000)), None);
+		assert_eq!(
+			alarms
+				.check(&reading(Mode::Testing, 10_900, 2_000))
+				.as_deref(),
+			Some("voltage 10900 mV below 11000 mV")
+		);
+		assert_eq!(
+			alarms
+				.check(&reading(Mode::Testing, 11_500, 2_600))
+				.as_deref(),
+			Some("current 2600 mA above 2500 mA")
+		);
+		// the load is off, the current should be near 0
+		assert_eq!(
+			alarms.check(&reading(Mode::WaitForUsrStart, 11_500, 0)),
+			None
+		);
+		assert_eq!(
+			alarms.check(&reading(Mode::Testing, 11_500, 0)).as_deref(),
+			Some("current 0 mA below 1500 mA")
+		);
+		let nothing_yet = Reading {
+			measurement: None,
+			..reading(Mode::Testing, 0, 0)
+		};
+		assert_eq!(alarms.check(&nothing_yet), None);
+	}
+
+	#[tokio::test]
+	async fn test_json_output() {
+		let cli = Cli::from_args(&["battery-tester-client"], &["--json", "status"]).unwrap();
+		assert!(cli.json && matches!(cli.cmd, Subcommands::Status(_)));
+
+		// a line for each reply, read back as it was sent
+		let status = ChannelStatus {
+			channel: 1,
+			server: Default::default(),
+			measurement: reading(Mode::Testing, 12_000, 2_000).measurement,
+			link: Default::default(),
+			trim: None,
+			battery_detect: None,
+			bat_present: Some(true),
+			load: None,
+			fault_log: Vec::new(),
+			self_test: None,
+		};
+		let line = json_line(&[&status]);
+		assert!(status.to_string().contains('\n') && !line.contains('\n'));
+		assert_eq!(
+			serde_json::from_str::<Vec<ChannelStatus>>(&line).unwrap(),
+			[status]
+		);
+
+		// a refused command is still an error for scripts to see
+		let (client, mut server) = tokio::io::duplex(1024);
+		let answered = tokio::spawn(async move {
+			let mut buf = vec![0; server.read_u32().await.unwrap() as usize];
+			server.read_exact(&mut buf).await.unwrap();
+			let request: Request = postcard::from_bytes(&buf).unwrap();
+			let ack = Ack {
+				channel: 0,
+				error: Some("no battery".into()),
+			};
+			write_reply(BytesMut::new(), &mut server, request.id, &ack)
+				.await
+				.unwrap();
+		});
+		let mut client: Box<dyn IpcStream> = Box::new(client);
+		let request = Request::new(None, ServerCmd::StartTest, None);
+		let sent = send(&mut client, &request, true).await;
+		assert!(matches!(sent, Err(Error::Refused(error)) if &*error == "no battery"));
+		answered.await.unwrap();
+	}
+
+	/// Collects what the repl prints above the line being typed
+	struct Printed(std::sync::mpsc::Sender<String>);
+
+	impl ExternalPrinter for Printed {
+		fn print(&mut self, msg: String) -> rustyline::Result<()> {
+			self.0.send(msg).map_err(|_| ReadlineError::Eof)
+		}
+	}
+
+	#[tokio::test]
+	async fn test_repl_prints_mode_changes() {
+		for (json, expected) in [
+			(false, ["channel 2: Testing", "channel 2: Fault"]),
+			(
+				true,
+				[
+					r#"{"channel":2,"mode":"Testing"}"#,
+					r#"{"channel":2,"mode":"Fault"}"#,
+				],
+			),
+		] {
+			let (watcher, mut server) = tokio::io::duplex(4096);
+			let id = 7;
+			for mode in [Mode::Testing, Mode::Testing, Mode::Fault] {
+				write_reply(
+					BytesMut::new(),
+					&mut server,
+					id,
+					&reading(mode, 12_000, 2_000),
+				)
+				.await
+				.unwrap();
+			}
+			// the subscription ends with the server
+			drop(server);
+			let (lines_tx, lines_rx) = std::sync::mpsc::channel();
+			print_modes(Box::new(watcher), id, 2, json, Some(Printed(lines_tx))).await;
+			assert_eq!(lines_rx.try_iter().collect::<Vec<_>>(), expected);
+		}
+	}
+
+	#[test]
+	fn test_ports_table() {
+		let usb = |vid, pid, product: Option<&str>| UsbListing {
+			vid,
+			pid,
+			manufacturer: Some("Acme".into()),
+			product: product.map(Into::into),
+			serial_number: None,
+		};
+		let ports = [
+			PortListing {
+				name: "/dev/ttyS0".into(),
+				usb: None,
+			},
+			PortListing {
+				name: "/dev/ttyACM0".into(),
+				usb: Some(usb(USB_VID, USB_PID, Some("Battery Tester"))),
+			},
+			PortListing {
+				name: "/dev/ttyUSB0".into(),
+				usb: Some(usb(0x0403, 0x6001, None)),
+			},
+		];
+		assert_eq!(
+			ports_table(&ports),
+			format!(
+				"port          usb id     manufacturer  product                             serial number\n\
+				 /dev/ttyS0    -\n\
+				 /dev/ttyACM0  {USB_VID:04x}:{USB_PID:04x}  Acme          Battery Tester (battery interface)\n\
+				 /dev/ttyUSB0  0403:6001  Acme\n"
+			)
+		);
+	}
+
+	#[test]
+	fn test_completions_arent_sent() {
+		let cmd = Subcommands::Completions(CompletionsCmd { shell: Shell::Bash });
+		assert!(matches!(
+			ServerCmd::try_from(cmd),
+			Err(Error::ClientOnly("completions"))
+		));
+	}
+
+	#[test]
+	fn test_implausible_millivolts_refused() {
+		let cutoff = |millivolts| {
+			Subcommands::SetCutoff(CutoffCmd {
+				millivolts: Some(millivolts),
+			})
+		};
+		let max_voltage = |millivolts| Subcommands::MaxVoltage(MaxVoltageCmd { millivolts });
+		let disconnect = |millivolts| Subcommands::Disconnect(DisconnectCmd { millivolts });
+		for (cmd, refused) in [
+			(cutoff(5_999), true),
+			(cutoff(6_000), false),
+			(cutoff(15_000), false),
+			(cutoff(15_001), true),
+			(max_voltage(5_999), true),
+			(max_voltage(6_000), false),
+			(max_voltage(15_000), false),
+			(max_voltage(15_001), true),
+			(disconnect(99), true),
+			(disconnect(100), false),
+			(disconnect(5_000), false),
+			(disconnect(5_001), true),
+		] {
+			match validate(&cmd, Server::Local(None)) {
+				Ok(()) => assert!(!refused, "{cmd:?} taken"),
+				Err(Error::Implausible { .. }) => assert!(refused, "{cmd:?} refused"),
+				Err(e) => panic!("{cmd:?}: {e}"),
+			}
+		}
+		// left out, the config's is sent
+		validate(
+			&Subcommands::SetCutoff(CutoffCmd { millivolts: None }),
+			Server::Local(None),
+		)
+		.unwrap();
+	}
+
+	#[test]
+	fn test_battery_year() {
+		use chrono::Datelike;
+
+		let this_year = chrono::Local::now().year() as u16;
+		for (year, taken) in [
+			(FIRST_BATTERY_YEAR - 1, false),
+			(FIRST_BATTERY_YEAR, true),
+			(this_year, true),
+			(this_year + 1, false),
+		] {
+			match battery_year(year) {
+				Ok(()) => assert!(taken, "{year} taken"),
+				Err(Error::BatteryYear(refused, latest)) => {
+					assert!(!taken, "{year} refused");
+					assert_eq!((refused, latest), (year, this_year));
+				}
+				Err(e) => panic!("{year}: {e}"),
+			}
+		}
+		assert!(matches!(
+			validate(
+				&Subcommands::BatteryID(BatteryIdCmd {
+					year: this_year + 1,
+					index: 1
+				}),
+				Server::Local(None)
+			),
+			Err(Error::BatteryYear(..))
+		));
+	}
+
+	#[test]
+	fn test_zero_limits_refused() {
+		for (cmd, setting) in [
+			(
+				Subcommands::MaxDuration(MaxDurationCmd { minutes: Some(0) }),
+				"max duration",
+			),
+			(
+				Subcommands::MaxCapacity(MaxCapacityCmd { mah: Some(0) }),
+				"max capacity",
+			),
+		] {
+			assert!(matches!(
+				validate(&cmd, Server::Local(None)),
+				Err(Error::ZeroLimit(refused)) if refused == setting
+			));
+		}
+		for cmd in [
+			Subcommands::MaxDuration(MaxDurationCmd { minutes: Some(1) }),
+			Subcommands::MaxDuration(MaxDurationCmd { minutes: None }),
+			Subcommands::MaxCapacity(MaxCapacityCmd { mah: Some(1) }),
+			Subcommands::MaxCapacity(MaxCapacityCmd { mah: None }),
+		] {
+			validate(&cmd, Server::Local(None)).unwrap();
+		}
+	}
+
+	#[test]
+	fn test_serial_dev_checked() {
+		let serial_dev = |device: &str, baud| {
+			Subcommands::SerialDev(SerialDevCmd {
+				device_name: Some(device.to_string()),
+				baud,
+			})
+		};
+		assert!(matches!(
+			validate(&serial_dev("simulator", Some(12_345)), Server::Local(None)),
+			Err(Error::Baud(12_345))
+		));
+		validate(
+			&serial_dev("simulator", Some(BAUDS[0])),
+			Server::Local(None),
+		)
+		.unwrap();
+		// not a path, so not looked for
+		validate(&serial_dev("simulator", None), Server::Local(None)).unwrap();
+		if cfg!(unix) {
+			let missing = "/dev/battery-tester-no-such-device";
+			assert!(matches!(
+				validate(&serial_dev(missing, None), Server::Local(None)),
+				Err(Error::NoSerialDev(device)) if &*device == missing
+			));
+			validate(&serial_dev("/dev/null", None), Server::Local(None)).unwrap();
+			// another machine's devices aren't here to look for
+			validate(&serial_dev(missing, None), Server::Remote("bench-2:7878")).unwrap();
 		}
 	}
 }
@@ -0,0 +1,468 @@
+//! Which columns the file writers save, their units, and their precision.
+//!
+//! Loaded from TOML with `--columns`, e.g. for a pipeline working in volts and amps:
+//! ```toml
+//! columns = ["temperature", "load_temperature", "power", "load_voltage", "load_current", "mah", "wh", "soc"]
+//! voltage = "v"
+//! current = "a"
+//! power = "w"
+//! temperature = "c"
+//! precision = 3
+//! capacity_mah = 2500
+//! ```
+//! `sample_index`, `sample_start_ms`, `sample_duration_ms`, voltage, and current are always
+//! written first, then `columns` in the order given. Without a config the files have the
+//! power, the load channel, and the temperatures as the extra columns, in the units the
+//! battery interface reports them in.
+//! The load channel and temperatures are left empty without their sensors.
+//! The power is the INA260's own, the energy columns are integrated from it.
+//! The SQLite database always stores the raw measurements.
+
+use std::{num::NonZeroU32, path::Path};
+
+use serde::Deserialize;
+
+use crate::{Error, SaveData};
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColumnConfig {
+	/// Columns after the measured ones
+	pub columns: Vec<Column>,
+	pub voltage: VoltageUnit,
+	pub current: CurrentUnit,
+	pub power: PowerUnit,
+	pub temperature: TemperatureUnit,
+	/// Decimal places of every value that isn't a whole number in its unit
+	pub precision: u8,
+	/// Rated capacity, needed for [`Column::Soc`]
+	pub capacity_mah: Option<NonZeroU32>,
+}
+
+impl Default for ColumnConfig {
+	fn default() -> Self {
+		Self {
+			columns: vec![
+				Column::Power,
+				Column::LoadVoltage,
+				Column::LoadCurrent,
+				Column::Temperature,
+				Column::LoadTemperature,
+			],
+			voltage: VoltageUnit::Mv,
+			current: CurrentUnit::Ma,
+			power: PowerUnit::Mw,
+			temperature: TemperatureUnit::CentiC,
+			precision: 3,
+			capacity_mah: None,
+		}
+	}
+}
+
+impl ColumnConfig {
+	pub fn load(path: &Path) -> Result<Self, Error> {
+		let text = std::fs::read_to_string(path)
+			.map_err(|e| Error::ColumnsRead(path.to_path_buf().into_boxed_path(), e))?;
+		let config: Self = toml::from_str(&text)
+			.map_err(|e| Error::ColumnsParse(path.to_path_buf().into_boxed_path(), e))?;
+		if config.columns.contains(&Column::Soc) && config.capacity_mah.is_none() {
+			return Err(Error::SocNeedsCapacity);
+		}
+		Ok(config)
+	}
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Column {
+	/// From the battery interface, negative while charging
+	Power,
+	/// State of charge, percent of `capacity_mah` left
+	Soc,
+	/// Charge taken out of the battery since the test started, charging counts down
+	Mah,
+	/// Energy taken out of the battery since the test started, charging counts down
+	Wh,
+	/// Heater side voltage from the second INA260, in `voltage` units
+	LoadVoltage,
+	/// Heater side current from the second INA260, in `current` units
+	LoadCurrent,
+	Temperature,
+	/// The heater's thermistor, in `temperature` units
+	LoadTemperature,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VoltageUnit {
+	Mv,
+	V,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CurrentUnit {
+	Ma,
+	A,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerUnit {
+	Mw,
+	W,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemperatureUnit {
+	CentiC,
+	C,
+}
+
+/// One value in a row
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Value {
+	Int(i64),
+	/// Written with this many decimal places
+	Fixed(f64, u8),
+	/// Nothing to write, e.g. the temperature without a sensor
+	Missing,
+}
+
+impl std::fmt::Display for Value {
+	/// [`Value::Missing`] writes nothing, writers that need a placeholder check for it
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Value::Int(i) => write!(f, "{i}"),
+			Value::Fixed(x, precision) => write!(f, "{x:.*}", *precision as usize),
+			Value::Missing => Ok(()),
+		}
+	}
+}
+
+/// Turns one test's samples into rows, keeping the running totals
+#[derive(Debug, PartialEq, Clone)]
+pub struct Columns {
+	config: ColumnConfig,
+	names: Vec<&'static str>,
+	last_dt: Option<u64>,
+	mah: f64,
+	wh: f64,
+}
+
+impl Columns {
+	pub fn new(config: ColumnConfig) -> Self {
+		let mut names = vec![
+			"sample_index",
+			"sample_start_ms",
+			"sample_duration_ms",
+			match config.voltage {
+				VoltageUnit::Mv => "millivolts",
+				VoltageUnit::V => "volts",
+			},
+			match config.current {
+				CurrentUnit::Ma => "milliamps",
+				CurrentUnit::A => "amps",
+			},
+		];
+		names.extend(config.columns.iter().map(|column| match column {
+			Column::Power => match config.power {
+				PowerUnit::Mw => "milliwatts",
+				PowerUnit::W => "watts",
+			},
+			Column::Soc => "soc_percent",
+			Column::Mah => "milliamp_hours",
+			Column::Wh => "watt_hours",
+			Column::LoadVoltage => match config.voltage {
+				VoltageUnit::Mv => "load_millivolts",
+				VoltageUnit::V => "load_volts",
+			},
+			Column::LoadCurrent => match config.current {
+				CurrentUnit::Ma => "load_milliamps",
+				CurrentUnit::A => "load_amps",
+			},
+			Column::Temperature => match config.temperature {
+				TemperatureUnit::CentiC => "temp_centi_c",
+				TemperatureUnit::C => "temp_c",
+			},
+			Column::LoadTemperature => match config.temperature {
+				TemperatureUnit::CentiC => "load_temp_centi_c",
+				TemperatureUnit::C => "load_temp_c",
+			},
+		}));
+		Self {
+			config,
+			names,
+			last_dt: None,
+			mah: 0.0,
+			wh: 0.0,
+		}
+	}
+
+	/// Carry on from the last row a test saved, `saved` gives its value in a column by name.
+	/// `false` if a running total that's written isn't in it, it starts over.
+	pub fn carry_on(&mut self, saved: impl Fn(&str) -> Option<f64>) -> bool {
+		let mah = saved("milliamp_hours").or_else(|| {
+			let capacity = f64::from(self.config.capacity_mah?.get());
+			saved("soc_percent").map(|soc| (1.0 - soc / 100.0) * capacity)
+		});
+		let wh = saved("watt_hours");
+		self.last_dt = saved("sample_start_ms").map(|ms| ms as u64);
+		self.mah = mah.unwrap_or(0.0);
+		self.wh = wh.unwrap_or(0.0);
+		let written = |column| self.config.columns.contains(&column);
+		(mah.is_some() || !(written(Column::Mah) || written(Column::Soc)))
+			&& (wh.is_some() || !written(Column::Wh))
+	}
+
+	/// Column names in the order [`Columns::row`] fills them
+	pub fn names(&self) -> &[&'static str] {
+		&self.names
+	}
+
+	pub fn row(&mut self, data: &SaveData, row: &mut Vec<Value>) {
+		let mv = u16::from(data.millivolts);
+		let ma = i16::from(data.milliamps);
+		let volts = f64::from(mv) / 1000.0;
+		// the power register reads the same either way, it takes the current's sign
+		let mw = i64::from(data.milliwatts.into_inner()) * i64::from(ma.signum());
+		let watts = mw as f64 / 1000.0;
+		// the current between windows is taken to be this window's,
+		// the first window, and one after the battery interface restarted,
+		// only counts for its own duration
+		let interval_ms = match self.last_dt {
+			Some(last_dt) if data.sample_start_ms >= last_dt => data.sample_start_ms - last_dt,
+			_ => data.sample_duration_ms,
+		};
+		self.last_dt = Some(data.sample_start_ms);
+		let hours = interval_ms as f64 / 3_600_000.0;
+		self.mah += f64::from(ma) * hours;
+		self.wh += watts * hours;
+
+		let precision = self.config.precision;
+		row.clear();
+		row.push(Value::Int(data.sample_index.into()));
+		row.push(Value::Int(data.sample_start_ms as i64));
+		row.push(Value::Int(data.sample_duration_ms as i64));
+		row.push(match self.config.voltage {
+			VoltageUnit::Mv => Value::Int(mv.into()),
+			VoltageUnit::V => Value::Fixed(volts, precision),
+		});
+		row.push(match self.config.current {
+			CurrentUnit::Ma => Value::Int(ma.into()),
+			CurrentUnit::A => Value::Fixed(f64::from(ma) / 1000.0, precision),
+		});
+		for column in &self.config.columns {
+			row.push(match column {
+				Column::Power => match self.config.power {
+					PowerUnit::Mw => Value::Int(mw),
+					PowerUnit::W => Value::Fixed(watts, precision),
+				},
+				Column::Soc => match self.config.capacity_mah {
+					Some(capacity) => Value::Fixed(
+						100.0 * (1.0 - self.mah / f64::from(capacity.get())),
+						precision,
+					),
+					None => Value::Missing,
+				},
+				Column::Mah => Value::Fixed(self.mah, precision),
+				Column::Wh => Value::Fixed(self.wh, precision),
+				Column::LoadVoltage => match (data.load, self.config.voltage) {
+					(None, _) => Value::Missing,
+					(Some(load), VoltageUnit::Mv) => Value::Int(u16::from(load.millivolts).into()),
+					(Some(load), VoltageUnit::V) => {
+						Value::Fixed(f64::from(u16::from(load.millivolts)) / 1000.0, precision)
+					}
+				},
+				Column::LoadCurrent => match (data.load, self.config.current) {
+					(None, _) => Value::Missing,
+					(Some(load), CurrentUnit::Ma) => Value::Int(i16::from(load.milliamps).into()),
+					(Some(load), CurrentUnit::A) => {
+						Value::Fixed(f64::from(i16::from(load.milliamps)) / 1000.0, precision)
+					}
+				},
+				Column::Temperature => temperature(data.temp_centi_c, &self.config),
+				Column::LoadTemperature => temperature(data.load_temp_centi_c, &self.config),
+			});
+		}
+	}
+}
+
+fn temperature(centi_c: Option<i16>, config: &ColumnConfig) -> Value {
+	match (centi_c, config.temperature) {
+		(None, _) => Value::Missing,
+		(Some(temp), TemperatureUnit::CentiC) => Value::Int(temp.into()),
+		(Some(temp), TemperatureUnit::C) => Value::Fixed(f64::from(temp) / 100.0, config.precision),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use battery_tester_common::{LoadChannel, MilliAmp, MilliVolt, MilliWatt};
+
+	/// `index` seconds in, a 900 ms window at 12 V and `milliamps`
+	fn sample(index: u32, milliamps: i16) -> SaveData {
+		SaveData {
+			millivolts: MilliVolt::new(12_000),
+			milliamps: MilliAmp::new(milliamps),
+			milliwatts: MilliWatt::new(12 * u32::from(milliamps.unsigned_abs())),
+			load: Some(LoadChannel {
+				millivolts: MilliVolt::new(11_500),
+				milliamps: MilliAmp::new(1_990),
+			}),
+			sample_index: index,
+			sample_start_ms: u64::from(index) * 1_000,
+			sample_duration_ms: 900,
+			temp_centi_c: Some(2_512),
+			load_temp_centi_c: None,
+		}
+	}
+
+	fn config_file(test: &str, text: &str) -> std::path::PathBuf {
+		let path = std::env::temp_dir().join(format!(
+			"battery-tester-columns-{test}-{}.toml",
+			std::process::id()
+		));
+		std::fs::write(&path, text).unwrap();
+		path
+	}
+
+	#[test]
+	fn test_default_columns() {
+		let mut columns = Columns::new(ColumnConfig::default());
+		assert_eq!(
+			columns.names(),
+			[
+				"sample_index",
+				"sample_start_ms",
+				"sample_duration_ms",
+				"millivolts",
+				"milliamps",
+				"milliwatts",
+				"load_millivolts",
+				"load_milliamps",
+				"temp_centi_c",
+				"load_temp_centi_c"
+			]
+		);
+		let mut row = Vec::new();
+		columns.row(&sample(0, 2_000), &mut row);
+		assert_eq!(
+			row,
+			[
+				Value::Int(0),
+				Value::Int(0),
+				Value::Int(900),
+				Value::Int(12_000),
+				Value::Int(2_000),
+				Value::Int(24_000),
+				Value::Int(11_500),
+				Value::Int(1_990),
+				Value::Int(2_512),
+				Value::Missing
+			]
+		);
+	}
+
+	#[test]
+	fn test_units_and_totals() {
+		let path = config_file(
+			"units",
+			r#"
+columns = ["power", "mah", "wh", "soc", "temperature"]
+voltage = "v"
+current = "a"
+power = "w"
+temperature = "c"
+precision = 2
+capacity_mah = 2000
+"#,
+		);
+		let config = ColumnConfig::load(&path).unwrap();
+		std::fs::remove_file(path).unwrap();
+		let mut columns = Columns::new(config);
+		assert_eq!(
+			&columns.names()[3..],
+			[
+				"volts",
+				"amps",
+				"watts",
+				"milliamp_hours",
+				"watt_hours",
+				"soc_percent",
+				"temp_c"
+			]
+		);
+		let mut row = Vec::new();
+		// the first window counts for its own 900 ms, then an hour at 2 A
+		columns.row(&sample(0, 2_000), &mut row);
+		columns.row(&sample(3_600, 2_000), &mut row);
+		let Value::Fixed(mah, 2) = row[6] else {
+			panic!("{row:?}");
+		};
+		assert!((mah - 2_000.5).abs() < 1e-6, "{mah}");
+		assert_eq!(row[3].to_string(), "12.00");
+		assert_eq!(row[4].to_string(), "2.00");
+		assert_eq!(row[5].to_string(), "24.00");
+		assert_eq!(row[7].to_string(), "24.01");
+		assert_eq!(row[8].to_string(), "-0.03");
+		assert_eq!(row[9].to_string(), "25.12");
+
+		// charging takes it back
+		columns.row(&sample(5_400, -2_000), &mut row);
+		assert_eq!(row[5].to_string(), "-24.00");
+		assert_eq!(row[6].to_string(), "1000.50");
+	}
+
+	#[test]
+	fn test_carry_on() {
+		let config = ColumnConfig {
+			columns: vec![Column::Soc],
+			capacity_mah: NonZeroU32::new(2_000),
+			..ColumnConfig::default()
+		};
+		let mut columns = Columns::new(config.clone());
+		// only the state of charge was saved, 1 Ah out
+		let saved = |name: &str| match name {
+			"sample_start_ms" => Some(10_000.0),
+			"soc_percent" => Some(50.0),
+			_ => None,
+		};
+		assert!(columns.carry_on(saved));
+		let mut row = Vec::new();
+		columns.row(&sample(3_610, 2_000), &mut row);
+		// from the saved sample on, an hour at 2 A
+		assert_eq!(row[5].to_string(), "-50.000");
+
+		// the battery interface restarted, its samples start over
+		columns.row(&sample(1, 2_000), &mut row);
+		assert_eq!(row[5].to_string(), "-50.025");
+
+		let mut columns = Columns::new(config);
+		assert!(!columns.carry_on(|_| None));
+		// nothing's saved that could be missing
+		let mut columns = Columns::new(ColumnConfig::default());
+		assert!(columns.carry_on(|_| None));
+	}
+
+	#[test]
+	fn test_soc_needs_capacity() {
+		let path = config_file("soc", r#"columns = ["soc"]"#);
+		let res = ColumnConfig::load(&path);
+		std::fs::remove_file(path).unwrap();
+		assert!(matches!(res, Err(Error::SocNeedsCapacity)));
+
+		let path = config_file("unknown", r#"columns = ["ohms"]"#);
+		let res = ColumnConfig::load(&path);
+		std::fs::remove_file(path).unwrap();
+		assert!(matches!(res, Err(Error::ColumnsParse(..))));
+
+		// the state of charge would divide by it
+		let path = config_file("empty", "columns = [\"soc\"]\ncapacity_mah = 0");
+		let res = ColumnConfig::load(&path);
+		std::fs::remove_file(path).unwrap();
+		assert!(matches!(res, Err(Error::ColumnsParse(..))), "{res:?}");
+	}
+}
@@ -0,0 +1,385 @@
+//! Tab completion scripts for `battery-tester-client completions`, from argh's description of
+//! the client's subcommands so they can't fall behind them.
+//!
+//! Each script walks the words typed so far down the subcommand tree, skipping the values of
+//! options, then offers the subcommands and flags where it ends up, and files for option
+//! values and positional arguments.
+
+use argh::{CommandInfoWithArgs, FlagInfo, FlagInfoKind};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Shell {
+	Bash,
+	Zsh,
+	Fish,
+}
+
+impl std::str::FromStr for Shell {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"bash" => Ok(Shell::Bash),
+			"zsh" => Ok(Shell::Zsh),
+			"fish" => Ok(Shell::Fish),
+			_ => Err(format!("unknown shell: {s}, expected bash, zsh, or fish")),
+		}
+	}
+}
+
+/// A subcommand, by the words that lead to it from the program's name
+struct Node<'a> {
+	path: String,
+	info: &'a CommandInfoWithArgs,
+}
+
+impl Node<'_> {
+	fn flags(&self) -> impl Iterator<Item = &FlagInfo<'static>> {
+		self.info.flags.iter().filter(|flag| !flag.hidden)
+	}
+
+	/// `--long` and `-s` of each of the node's flags, `takes_value` for only the options
+	fn flag_names(&self, takes_value: bool) -> Vec<String> {
+		self.flags()
+			.filter(|flag| !takes_value || matches!(flag.kind, FlagInfoKind::Option { .. }))
+			.flat_map(|flag| {
+				std::iter::once(flag.long.to_string())
+					.chain(flag.short.map(|short| format!("-{short}")))
+			})
+			.collect()
+	}
+
+	fn sub_names(&self) -> Vec<&str> {
+		self.info.commands.iter().map(|sub| sub.name).collect()
+	}
+
+	fn positionals(&self) -> bool {
+		self.info
+			.positionals
+			.iter()
+			.any(|positional| !positional.hidden)
+	}
+}
+
+/// The program and every subcommand under it
+fn nodes<'a>(path: String, info: &'a CommandInfoWithArgs, out: &mut Vec<Node<'a>>) {
+	for sub in &info.commands {
+		nodes(format!("{path} {}", sub.name), &sub.command, out);
+	}
+	out.push(Node { path, info });
+}
+
+/// `bin`'s completion script for `shell`
+pub fn script(shell: Shell, bin: &str, info: &CommandInfoWithArgs) -> String {
+	let mut tree = Vec::new();
+	nodes(bin.to_string(), info, &mut tree);
+	tree.sort_by(|a, b| a.path.cmp(&b.path));
+	match shell {
+		Shell::Bash => bash(bin, &tree),
+		Shell::Zsh => zsh(bin, &tree),
+		Shell::Fish => fish(bin, &tree),
+	}
+}
+
+/// Single quoted for bash and zsh, fish escapes quotes differently, see [`fish_quote`]
+fn quote(s: &str) -> String {
+	format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// One line, the way help shows it
+fn description(description: &str) -> String {
+	description.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn bash(bin: &str, tree: &[Node]) -> String {
+	let func = format!("_{}", bin.replace('-', "_"));
+	let mut out = format!("{func}_node() {{\n\tcase \"$1\" in\n");
+	for node in tree {
+		out += &format!(
+			"\t\t{})\n\t\t\tsubs={}\n\t\t\tflags={}\n\t\t\ttakes={}\n\t\t\tfiles={}\n\t\t\t;;\n",
+			quote(&node.path),
+			quote(&node.sub_names().join(" ")),
+			quote(&node.flag_names(false).join(" ")),
+			quote(&node.flag_names(true).join(" ")),
+			u8::from(node.positionals()),
+		);
+	}
+	out += "\tesac\n}\n\n";
+	out += &format!(
+		r#"{func}() {{
+	local cur=${{COMP_WORDS[COMP_CWORD]}}
+	local node={bin} subs flags takes files skip=0 i w
+	{func}_node "$node"
+	for ((i = 1; i < COMP_CWORD; i++)); do
+		w=${{COMP_WORDS[i]}}
+		if ((skip)); then
+			skip=0
+		elif [[ " $takes " == *" $w "* ]]; then
+			skip=1
+		elif [[ " $subs " == *" $w "* ]]; then
+			node="$node $w"
+			{func}_node "$node"
+		fi
+	done
+	if ((skip)); then
+		COMPREPLY=($(compgen -f -- "$cur"))
+	elif [[ $cur == -* ]]; then
+		COMPREPLY=($(compgen -W "$flags" -- "$cur"))
+	else
+		COMPREPLY=($(compgen -W "$subs" -- "$cur"))
+		if ((files)); then
+			COMPREPLY+=($(compgen -f -- "$cur"))
+		fi
+	fi
+}}
+
+complete -F {func} {bin}
+"#
+	);
+	out
+}
+
+fn zsh(bin: &str, tree: &[Node]) -> String {
+	let func = format!("_{bin}");
+	let mut out = format!("#compdef {bin}\n\n{func}_node() {{\n\tcase \"$1\" in\n");
+	for node in tree {
+		let subs: Vec<String> = node
+			.info
+			.commands
+			.iter()
+			.map(|sub| quote(&zsh_entry(sub.name, sub.command.description)))
+			.collect();
+		let list = |names: Vec<String>| {
+			names
+				.iter()
+				.map(|name| quote(name))
+				.collect::<Vec<_>>()
+				.join(" ")
+		};
+		out += &format!(
+			"\t\t{})\n\t\t\tsubs=({})\n\t\t\tflags=({})\n\t\t\ttakes=({})\n\t\t\tfiles={}\n\t\t\t;;\n",
+			quote(&node.path),
+			subs.join(" "),
+			list(node.flag_names(false)),
+			list(node.flag_names(true)),
+			u8::from(node.positionals()),
+		);
+	}
+	out += "\tesac\n}\n\n";
+	out += &format!(
+		r#"{func}() {{
+	local node={bin} skip=0 files i w
+	local -a subs flags takes
+	{func}_node "$node"
+	for ((i = 2; i < CURRENT; i++)); do
+		w=${{words[i]}}
+		if ((skip)); then
+			skip=0
+		elif ((${{takes[(Ie)$w]}})); then
+			skip=1
+		elif ((${{subs[(I)${{(b)w}}:*]}})); then
+			node="$node $w"
+			{func}_node "$node"
+		fi
+	done
+	if ((skip)); then
+		_files
+	elif [[ $PREFIX == -* ]]; then
+		compadd -a flags
+	else
+		_describe -t commands subcommand subs
+		if ((files)); then
+			_files
+		fi
+	fi
+}}
+
+if [ "$funcstack[1]" = "{func}" ]; then
+	{func} "$@"
+else
+	compdef {func} {bin}
+fi
+"#
+	);
+	out
+}
+
+/// `name:description` for `_describe`, it splits at the first colon that isn't escaped
+fn zsh_entry(name: &str, about: &str) -> String {
+	let name = name.replace('\\', r"\\").replace(':', r"\:");
+	format!("{name}:{}", description(about))
+}
+
+/// fish escapes quotes in single quotes with a backslash
+fn fish_quote(s: &str) -> String {
+	format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+fn fish(bin: &str, tree: &[Node]) -> String {
+	let func = format!("__{}", bin.replace('-', "_"));
+	let mut out = format!(
+		r#"function {func}_path
+	set -l node {bin}
+	set -l skip 0
+	set -l subs
+	set -l takes
+	set -l tokens (commandline -opc)
+	set -e tokens[1]
+	for w in $tokens
+		if test $skip = 1
+			set skip 0
+			continue
+		end
+		switch "$node"
+"#
+	);
+	for node in tree {
+		out += &format!(
+			"\t\t\tcase {}\n\t\t\t\tset subs{}\n\t\t\t\tset takes{}\n",
+			fish_quote(&node.path),
+			node.sub_names()
+				.iter()
+				.map(|name| format!(" {}", fish_quote(name)))
+				.collect::<String>(),
+			node.flag_names(true)
+				.iter()
+				.map(|name| format!(" {}", fish_quote(name)))
+				.collect::<String>(),
+		);
+	}
+	out += &format!(
+		r#"		end
+		if contains -- $w $takes
+			set skip 1
+		else if contains -- $w $subs
+			set node "$node $w"
+		end
+	end
+	echo $node
+end
+
+function {func}_at
+	test ({func}_path) = "$argv[1]"
+end
+
+"#
+	);
+	for node in tree {
+		let at = fish_quote(&format!("{func}_at {}", fish_quote(&node.path)));
+		if !node.positionals() {
+			out += &format!("complete -c {bin} -n {at} -f\n");
+		}
+		for sub in &node.info.commands {
+			out += &format!(
+				"complete -c {bin} -n {at} -a {} -d {}\n",
+				fish_quote(sub.name),
+				fish_quote(&description(sub.command.description)),
+			);
+		}
+		for flag in node.flags() {
+			let mut line = format!("complete -c {bin} -n {at} -l {}", &flag.long[2..]);
+			if let Some(short) = flag.short {
+				line += &format!(" -s {short}");
+			}
+			if matches!(flag.kind, FlagInfoKind::Option { .. }) {
+				line += " -r";
+			}
+			out += &format!("{line} -d {}\n", fish_quote(&description(flag.description)));
+		}
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use argh::{ArgsInfo, FromArgs};
+
+	use super::*;
+
+	#[derive(FromArgs, ArgsInfo)]
+	/// Test client
+	struct Cli {
+		/// server's name
+		#[argh(option, short = 'n')]
+		_name: Option<String>,
+		#[argh(subcommand)]
+		_cmd: Subcommands,
+	}
+
+	// only described, never parsed
+	#[allow(dead_code)]
+	#[derive(FromArgs, ArgsInfo)]
+	#[argh(subcommand)]
+	enum Subcommands {
+		Cutoff(CutoffCmd),
+		Flash(FlashCmd),
+	}
+
+	#[derive(FromArgs, ArgsInfo)]
+	/// set the cutoff: in mV, the battery's own
+	#[argh(subcommand, name = "cutoff")]
+	struct CutoffCmd {
+		/// leave it to the chemistry
+		#[argh(switch)]
+		_auto: bool,
+	}
+
+	#[derive(FromArgs, ArgsInfo)]
+	/// flash the battery interface's firmware
+	#[argh(subcommand, name = "flash")]
+	struct FlashCmd {
+		/// raw image
+		#[argh(positional)]
+		_image: String,
+	}
+
+	fn script_for(shell: Shell) -> String {
+		script(shell, "bt", &Cli::get_args_info())
+	}
+
+	#[test]
+	fn test_bash() {
+		let script = script_for(Shell::Bash);
+		assert!(script.contains("\t\t'bt')\n\t\t\tsubs='cutoff flash'\n\t\t\tflags='--help --name -n'\n\t\t\ttakes='--name -n'\n\t\t\tfiles=0\n"));
+		assert!(script.contains("\t\t'bt cutoff')\n\t\t\tsubs=''\n\t\t\tflags='--help --auto'\n"));
+		// files for the image
+		assert!(script.contains(
+			"\t\t'bt flash')\n\t\t\tsubs=''\n\t\t\tflags='--help'\n\t\t\ttakes=''\n\t\t\tfiles=1\n"
+		));
+		assert!(script.ends_with("complete -F _bt bt\n"));
+	}
+
+	#[test]
+	fn test_zsh() {
+		let script = script_for(Shell::Zsh);
+		assert!(script.starts_with("#compdef bt\n"));
+		assert!(script.contains(
+			"\t\t'bt')\n\t\t\tsubs=('cutoff:set the cutoff: in mV, the battery'\\''s own' 'flash:flash the battery interface'\\''s firmware')\n\t\t\tflags=('--help' '--name' '-n')\n\t\t\ttakes=('--name' '-n')\n"
+		));
+		assert!(
+			script.contains("\t\t'bt cutoff')\n\t\t\tsubs=()\n\t\t\tflags=('--help' '--auto')\n")
+		);
+	}
+
+	#[test]
+	fn test_zsh_entry_escapes_the_name() {
+		assert_eq!(zsh_entry("a:b", "c:\n d"), r"a\:b:c: d");
+	}
+
+	#[test]
+	fn test_fish() {
+		let script = script_for(Shell::Fish);
+		assert!(script.contains(
+			"\t\t\tcase 'bt'\n\t\t\t\tset subs 'cutoff' 'flash'\n\t\t\t\tset takes '--name' '-n'\n"
+		));
+		assert!(script.contains(
+			"complete -c bt -n '__bt_at \\'bt\\'' -a 'cutoff' -d 'set the cutoff: in mV, the battery\\'s own'\n"
+		));
+		assert!(script.contains(
+			"complete -c bt -n '__bt_at \\'bt\\'' -l name -s n -r -d 'server\\'s name'\n"
+		));
+		assert!(script.contains("complete -c bt -n '__bt_at \\'bt cutoff\\'' -f\n"));
+		// files for the image
+		assert!(!script.contains("complete -c bt -n '__bt_at \\'bt flash\\'' -f\n"));
+	}
+}
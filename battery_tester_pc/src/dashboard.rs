@@ -0,0 +1,284 @@
+//! Terminal dashboard for `battery-tester-client watch`, fed by [`ServerCmd::SubscribeReadings`](crate::ServerCmd::SubscribeReadings).
+//!
+//! The elapsed time and mAh count from the first measurement the dashboard sees while testing,
+//! a dashboard opened partway through a test only counts the part it saw, and only
+//! as finely as the server's `--live-every-s`.
+//! The saved test has the whole thing.
+
+use battery_tester_common::Measurement;
+use ratatui::{
+	Frame,
+	layout::{Constraint, Layout},
+	style::{Color, Modifier, Style},
+	text::Line,
+	widgets::{Block, Paragraph, Sparkline},
+};
+
+use crate::{Mode, Reading};
+
+/// Measurements kept for the sparklines, more than fit across most terminals
+const HISTORY: usize = 512;
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Dashboard {
+	reading: Option<Reading>,
+	millivolts: Vec<u64>,
+	milliamps: Vec<u64>,
+	/// When the first measurement of this test was taken, ms since the interface booted
+	test_start_dt: Option<u64>,
+	last_dt: Option<u64>,
+	/// Elapsed before the battery interface last restarted, its clock starts over
+	earlier_ms: u64,
+	mah: f64,
+}
+
+impl Dashboard {
+	pub fn push(&mut self, reading: Reading) {
+		let was_testing = self.reading.is_some_and(|r| r.mode == Mode::Testing);
+		self.reading = Some(reading);
+		if reading.mode == Mode::Testing && !was_testing {
+			self.test_start_dt = None;
+			self.earlier_ms = 0;
+			self.mah = 0.0;
+		}
+		let Some(m) = reading.measurement else {
+			return;
+		};
+		match self.last_dt {
+			// the subscription also sends a reading when only the mode changed
+			Some(dt) if m.sample_start_ms == dt => return,
+			Some(dt) if m.sample_start_ms < dt => self.restarted(),
+			_ => {}
+		}
+		if reading.mode == Mode::Testing {
+			self.count(&m);
+		}
+		self.last_dt = Some(m.sample_start_ms);
+		push_bounded(&mut self.millivolts, u16::from(m.vbat).into());
+		push_bounded(&mut self.milliamps, i16::from(m.ibat).unsigned_abs().into());
+	}
+
+	/// The battery interface's clock went back, it restarted. The sparklines start over,
+	/// a test carries on counting from where it was.
+	fn restarted(&mut self) {
+		self.earlier_ms = self.elapsed_ms();
+		self.test_start_dt = None;
+		self.last_dt = None;
+		self.millivolts.clear();
+		self.milliamps.clear();
+	}
+
+	/// Same integration as the mAh column of saved files
+	fn count(&mut self, m: &Measurement) {
+		let interval_ms = match (self.test_start_dt, self.last_dt) {
+			(Some(_), Some(last_dt)) => m.sample_start_ms - last_dt,
+			_ => m.sample_duration_ms,
+		};
+		self.test_start_dt.get_or_insert(m.sample_start_ms);
+		self.mah += f64::from(i16::from(m.ibat)) * interval_ms as f64 / 3_600_000.0;
+	}
+
+	fn elapsed_ms(&self) -> u64 {
+		match (self.test_start_dt, self.last_dt) {
+			(Some(start), Some(last)) => self.earlier_ms + (last - start),
+			_ => self.earlier_ms,
+		}
+	}
+
+	fn elapsed_s(&self) -> u64 {
+		self.elapsed_ms() / 1000
+	}
+
+	/// `alarm` is shown in the banner along with any fault
+	pub fn draw(&self, frame: &mut Frame, alarm: Option<&str>) {
+		let banner = self.banner(alarm);
+		let [summary, banner_area, voltage, current, help] = Layout::vertical([
+			Constraint::Length(4),
+			Constraint::Length(if banner.is_some() { 3 } else { 0 }),
+			Constraint::Fill(1),
+			Constraint::Fill(1),
+			Constraint::Length(1),
+		])
+		.areas(frame.area());
+
+		frame.render_widget(
+			Paragraph::new(self.summary()).block(Block::bordered().title(" battery tester ")),
+			summary,
+		);
+		if let Some(banner) = banner {
+			frame.render_widget(
+				Paragraph::new(banner)
+					.style(
+						Style::new()
+							.fg(Color::White)
+							.bg(Color::Red)
+							.add_modifier(Modifier::BOLD),
+					)
+					.block(Block::bordered()),
+				banner_area,
+			);
+		}
+		let latest = self.reading.and_then(|r| r.measurement);
+		let voltage_title = match latest {
+			Some(m) => format!(" voltage {} mV ", m.vbat),
+			None => " voltage ".into(),
+		};
+		let current_title = match latest {
+			Some(m) => format!(" current {} mA ", m.ibat),
+			None => " current ".into(),
+		};
+		// the newest measurements on the right, as many as fit inside the borders
+		let width = usize::from(voltage.width.saturating_sub(2));
+		let millivolts = last(&self.millivolts, width);
+		// the voltage only moves a few percent over a test, start the scale at its minimum
+		let floor = millivolts
+			.iter()
+			.min()
+			.copied()
+			.unwrap_or(0)
+			.saturating_sub(1);
+		frame.render_widget(
+			Sparkline::default()
+				.block(Block::bordered().title(voltage_title))
+				.data(millivolts.iter().map(|mv| mv - floor))
+				.style(Style::new().fg(Color::Green)),
+			voltage,
+		);
+		frame.render_widget(
+			Sparkline::default()
+				.block(Block::bordered().title(current_title))
+				.data(last(&self.milliamps, width))
+				.style(Style::new().fg(Color::Yellow)),
+			current,
+		);
+		frame.render_widget(Line::from(" q to quit"), help);
+	}
+
+	fn summary(&self) -> Vec<Line<'static>> {
+		let Some(reading) = self.reading else {
+			return vec![Line::from("waiting for the server")];
+		};
+		let battery = match reading.battery_id {
+			Some(id) => format!("{}-{}", id.year, id.index),
+			None => "-".into(),
+		};
+		let temp = match reading.measurement.and_then(|m| m.temp_centi_c) {
+			Some(t) => format!("{:.1} °C", f32::from(t) / 100.0),
+			None => "-".into(),
+		};
+		let load_temp = match reading.measurement.and_then(|m| m.load_temp_centi_c) {
+			Some(t) => format!("{:.1} °C", f32::from(t) / 100.0),
+			None => "-".into(),
+		};
+		let fan = match reading.measurement {
+			Some(m) if m.fan_on => "on",
+			Some(_) => "off",
+			None => "-",
+		};
+		let elapsed = self.elapsed_s();
+		vec![
+			Line::from(format!(
+				"mode: {:?}   battery: {battery}   cutoff: {} mV",
+				reading.mode, reading.cutoff
+			)),
+			Line::from(format!(
+				"elapsed: {}:{:02}:{:02}   discharged: {:.1} mAh   temperature: {temp}   heater: {load_temp}   fan: {fan}",
+				elapsed / 3600,
+				elapsed / 60 % 60,
+				elapsed % 60,
+				self.mah
+			)),
+		]
+	}
+
+	fn banner(&self, alarm: Option<&str>) -> Option<String> {
+		let fault = match self.reading.map(|r| r.mode) {
+			Some(Mode::Fault) => Some("FAULT: the test is stopped, clear the fault to go on"),
+			Some(Mode::CommDC) => Some("lost the battery interface"),
+			_ => None,
+		};
+		match (fault, alarm) {
+			(Some(fault), Some(alarm)) => Some(format!("{fault}   ALARM: {alarm}")),
+			(Some(fault), None) => Some(fault.into()),
+			(None, Some(alarm)) => Some(format!("ALARM: {alarm}")),
+			(None, None) => None,
+		}
+	}
+}
+
+fn push_bounded(history: &mut Vec<u64>, value: u64) {
+	if history.len() == HISTORY {
+		history.remove(0);
+	}
+	history.push(value);
+}
+
+fn last(history: &[u64], n: usize) -> &[u64] {
+	&history[history.len().saturating_sub(n)..]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use battery_tester_common::{MilliAmp, MilliVolt, MilliWatt};
+
+	fn reading(mode: Mode, sample_start_ms: u64, millivolts: u16) -> Reading {
+		Reading {
+			mode,
+			battery_id: None,
+			cutoff: MilliVolt::new(10_500),
+			measurement: Some(Measurement {
+				vbat: MilliVolt::new(millivolts),
+				ibat: MilliAmp::new(3_600),
+				milliwatts: MilliWatt::new(u32::from(millivolts) * 3_600 / 1000),
+				sample_index: (sample_start_ms / 1000) as u32,
+				sample_start_ms,
+				sample_duration_ms: 1_000,
+				temp_centi_c: None,
+				load_temp_centi_c: None,
+				fan_on: false,
+				load: None,
+			}),
+			live: None,
+		}
+	}
+
+	#[test]
+	fn test_push_counts_while_testing() {
+		let mut dashboard = Dashboard::default();
+		dashboard.push(reading(Mode::WaitForUsrStart, 1_000, 12_600));
+		assert_eq!(dashboard.mah, 0.0);
+		for dt in [2_000, 3_000, 4_000] {
+			dashboard.push(reading(Mode::Testing, dt, 12_500));
+		}
+		// only a mode change, the same measurement
+		dashboard.push(reading(Mode::Resting, 4_000, 12_500));
+		assert_eq!(dashboard.millivolts, [12_600, 12_500, 12_500, 12_500]);
+		// 3.6 A for a second each
+		assert!((dashboard.mah - 3.0).abs() < 1e-9, "{}", dashboard.mah);
+		assert_eq!(dashboard.elapsed_s(), 2);
+
+		// a new test counts from 0
+		dashboard.push(reading(Mode::Setup, 5_000, 12_600));
+		dashboard.push(reading(Mode::Testing, 6_000, 12_500));
+		assert!((dashboard.mah - 1.0).abs() < 1e-9, "{}", dashboard.mah);
+		assert_eq!(dashboard.elapsed_s(), 0);
+	}
+
+	#[test]
+	fn test_push_after_a_restart() {
+		let mut dashboard = Dashboard::default();
+		for dt in [100_000, 101_000, 102_000] {
+			dashboard.push(reading(Mode::Testing, dt, 12_500));
+		}
+		// the battery interface restarted, its clock too
+		dashboard.push(reading(Mode::Testing, 1_000, 12_400));
+		assert_eq!(dashboard.millivolts, [12_400]);
+		assert_eq!(dashboard.milliamps, [3_600]);
+		dashboard.push(reading(Mode::Testing, 2_000, 12_400));
+		assert_eq!(dashboard.millivolts, [12_400, 12_400]);
+		// the test goes on
+		assert!((dashboard.mah - 5.0).abs() < 1e-9, "{}", dashboard.mah);
+		assert_eq!(dashboard.elapsed_s(), 3);
+	}
+}
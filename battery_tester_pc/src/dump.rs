@@ -0,0 +1,359 @@
+//! Raw serial traffic, for working out framing problems between the PC and the battery interface.
+//!
+//! `--dump-serial` wraps each channel's [`Transport`] in a [`DumpTransport`], which copies every
+//! chunk written to or read from the battery interface into the dump as it passes, before any
+//! framing. `battery-tester-client decode-dump` prints it with the frames decoded.
+//!
+//! Records are postcard, each prefixed with its length as a big endian `u32` like a trace's,
+//! and flushed as they're written so a crash loses nothing.
+
+use std::{
+	collections::HashMap,
+	path::Path,
+	pin::Pin,
+	task::{Context, Poll, ready},
+};
+
+use battery_tester_common::{
+	BIReply, BiCommand,
+	frame::{FrameBuffer, FrameError},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+	fs::File,
+	io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf},
+	sync::mpsc::{UnboundedReceiver, UnboundedSender},
+	time::Instant,
+};
+
+use crate::{ChannelId, Error, INCOMING_MAX_SIZE, OUTGOING_MAX_SIZE, Printer, serial::Transport};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum Direction {
+	ToDevice,
+	FromDevice,
+}
+
+impl std::fmt::Display for Direction {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Direction::ToDevice => write!(f, "->"),
+			Direction::FromDevice => write!(f, "<-"),
+		}
+	}
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct DumpRecord {
+	pub channel: ChannelId,
+	/// Time since the server started
+	pub elapsed_ms: u64,
+	pub direction: Direction,
+	/// As written or read, a frame or part of one, or several
+	pub bytes: Box<[u8]>,
+}
+
+/// Opens `T`'s links with every byte copied to the dump task
+#[derive(Debug, Clone)]
+pub struct DumpTransport<T> {
+	inner: T,
+	channel: ChannelId,
+	started: Instant,
+	dump_tx: UnboundedSender<DumpRecord>,
+}
+
+impl<T: Transport> DumpTransport<T> {
+	pub fn new(
+		inner: T,
+		channel: ChannelId,
+		started: Instant,
+		dump_tx: UnboundedSender<DumpRecord>,
+	) -> Self {
+		Self {
+			inner,
+			channel,
+			started,
+			dump_tx,
+		}
+	}
+}
+
+impl<T: Transport> Transport for DumpTransport<T> {
+	type Link = DumpLink<T::Link>;
+
+	async fn open(&self, device: &str, baud: u32) -> std::io::Result<Self::Link> {
+		let link = self.inner.open(device, baud).await?;
+		Ok(DumpLink {
+			link,
+			channel: self.channel,
+			started: self.started,
+			dump_tx: self.dump_tx.clone(),
+		})
+	}
+
+	fn uses_baud(&self, device: &str) -> bool {
+		self.inner.uses_baud(device)
+	}
+}
+
+/// A link to a battery interface, see [`DumpTransport`]
+pub struct DumpLink<L> {
+	link: L,
+	channel: ChannelId,
+	started: Instant,
+	dump_tx: UnboundedSender<DumpRecord>,
+}
+
+impl<L> DumpLink<L> {
+	fn dump(&self, direction: Direction, bytes: &[u8]) {
+		if bytes.is_empty() {
+			return;
+		}
+		// the dump task is gone at shutdown, the link carries on without it
+		let _ = self.dump_tx.send(DumpRecord {
+			channel: self.channel,
+			elapsed_ms: self.started.elapsed().as_millis() as u64,
+			direction,
+			bytes: bytes.into(),
+		});
+	}
+}
+
+impl<L: AsyncRead + Unpin> AsyncRead for DumpLink<L> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<std::io::Result<()>> {
+		let this = self.get_mut();
+		let before = buf.filled().len();
+		ready!(Pin::new(&mut this.link).poll_read(cx, buf))?;
+		this.dump(Direction::FromDevice, &buf.filled()[before..]);
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<L: AsyncWrite + Unpin> AsyncWrite for DumpLink<L> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		data: &[u8],
+	) -> Poll<std::io::Result<usize>> {
+		let this = self.get_mut();
+		let written = ready!(Pin::new(&mut this.link).poll_write(cx, data))?;
+		this.dump(Direction::ToDevice, &data[..written]);
+		Poll::Ready(Ok(written))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.get_mut().link).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.get_mut().link).poll_shutdown(cx)
+	}
+}
+
+/// Writes what every channel's [`DumpLink`]s pass on, until they're all gone
+pub async fn dump_task(file: File, mut dump_rx: UnboundedReceiver<DumpRecord>, printer: Printer) {
+	let mut dump = BufWriter::new(file);
+	let mut write_ok = true;
+	while let Some(record) = dump_rx.recv().await {
+		// report once, not for every record after the disk fills up
+		if write_ok && !write_record(&mut dump, &record).await {
+			write_ok = false;
+			printer
+				.error_stat("can't write to the serial dump, dumping stopped")
+				.await;
+		}
+	}
+	println!("exiting dump_task");
+}
+
+async fn write_record(dump: &mut BufWriter<File>, record: &DumpRecord) -> bool {
+	// only fails for types postcard can't represent, none are used here
+	let bytes = postcard::to_extend(record, Vec::new()).unwrap();
+	let written = async {
+		dump.write_u32(bytes.len() as u32).await?;
+		dump.write_all(&bytes).await?;
+		dump.flush().await
+	};
+	written.await.is_ok()
+}
+
+/// Read a whole dump, a record cut off by a crash ends it
+pub fn read_dump(path: &Path) -> Result<Vec<DumpRecord>, Error> {
+	let bytes = std::fs::read(path)
+		.map_err(|e| Error::DumpRead(path.to_path_buf().into_boxed_path(), e))?;
+	let mut records = Vec::new();
+	let mut rest = bytes.as_slice();
+	while let Some((len, after_len)) = rest.split_first_chunk::<4>() {
+		let len = u32::from_be_bytes(*len) as usize;
+		let Some((record, after_record)) = after_len.split_at_checked(len) else {
+			break;
+		};
+		let record = postcard::from_bytes(record)
+			.map_err(|e| Error::DumpParse(path.to_path_buf().into_boxed_path(), e))?;
+		records.push(record);
+		rest = after_record;
+	}
+	Ok(records)
+}
+
+/// A frame that ended in a record, see [`DumpDecoder`]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Decoded {
+	Command(BiCommand),
+	Reply(BIReply),
+	/// Dropped the way the server or the battery interface would have
+	Bad(FrameError),
+}
+
+/// Puts the frames in a dump's records back together, each channel and direction on its own
+#[derive(Default)]
+pub struct DumpDecoder {
+	commands: HashMap<ChannelId, FrameBuffer<OUTGOING_MAX_SIZE>>,
+	replies: HashMap<ChannelId, FrameBuffer<INCOMING_MAX_SIZE>>,
+}
+
+impl DumpDecoder {
+	/// The frames `record` ends, a frame split over several records ends in the last of them
+	pub fn decode(&mut self, record: &DumpRecord) -> Vec<Decoded> {
+		let decoded = match record.direction {
+			Direction::ToDevice => {
+				let frame_buf = self.commands.entry(record.channel).or_default();
+				record
+					.bytes
+					.iter()
+					.filter_map(|byte| frame_buf.push::<BiCommand>(*byte))
+					.map(|command| command.map(Decoded::Command))
+					.collect::<Vec<_>>()
+			}
+			Direction::FromDevice => {
+				let frame_buf = self.replies.entry(record.channel).or_default();
+				record
+					.bytes
+					.iter()
+					.filter_map(|byte| frame_buf.push::<BIReply>(*byte))
+					.map(|reply| reply.map(Decoded::Reply))
+					.collect()
+			}
+		};
+		decoded
+			.into_iter()
+			.map(|frame| frame.unwrap_or_else(Decoded::Bad))
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::Print;
+	use battery_tester_common::{
+		CommandKind, DEFAULT_BAUD, FirmwareVersion, LoadState, PROTOCOL_VERSION, ReplyKind,
+		frame::{self, FRAME_DELIMITER},
+	};
+	use tokio::{
+		io::{AsyncReadExt, DuplexStream, duplex},
+		sync::mpsc::{self, unbounded_channel},
+	};
+
+	/// In-memory links, the test is handed the battery interface's end of each one opened
+	#[derive(Clone)]
+	struct FakeTransport(UnboundedSender<DuplexStream>);
+
+	impl Transport for FakeTransport {
+		type Link = DuplexStream;
+
+		async fn open(&self, _device: &str, _baud: u32) -> std::io::Result<Self::Link> {
+			let (link, battery_interface) = duplex(1024);
+			self.0.send(battery_interface).unwrap();
+			Ok(link)
+		}
+	}
+
+	#[tokio::test]
+	async fn test_traffic_dumped_and_decoded() {
+		let (opened_tx, mut opened_rx) = unbounded_channel();
+		let (dump_tx, mut dump_rx) = unbounded_channel();
+		let transport = DumpTransport::new(FakeTransport(opened_tx), 1, Instant::now(), dump_tx);
+		let mut link = transport.open("/dev/ttyACM1", DEFAULT_BAUD).await.unwrap();
+		let mut battery_interface = opened_rx.recv().await.unwrap();
+
+		let command = BiCommand {
+			seq: 4,
+			kind: CommandKind::Hello,
+		};
+		let mut buf = [0u8; 64];
+		let command_frame = frame::encode(&command, &mut buf[..]).unwrap().to_vec();
+		// a frame split over two writes
+		let (start, end) = command_frame.split_at(2);
+		link.write_all(start).await.unwrap();
+		link.write_all(end).await.unwrap();
+		let mut passed = vec![0; command_frame.len()];
+		battery_interface.read_exact(&mut passed).await.unwrap();
+		assert_eq!(passed, command_frame);
+
+		let reply = BIReply {
+			seq: 4,
+			bat_present: true,
+			load: LoadState::Off,
+			kind: ReplyKind::Version {
+				protocol: PROTOCOL_VERSION,
+				firmware: FirmwareVersion {
+					major: 1,
+					minor: 2,
+					patch: 3,
+				},
+				device_id: 1,
+				self_test: None,
+				baud: DEFAULT_BAUD,
+			},
+		};
+		let mut reply_frame = vec![0xff, 0xff, FRAME_DELIMITER];
+		reply_frame.extend_from_slice(frame::encode(&reply, &mut buf[..]).unwrap());
+		battery_interface.write_all(&reply_frame).await.unwrap();
+		let mut read = vec![0; reply_frame.len()];
+		link.read_exact(&mut read).await.unwrap();
+		drop((transport, link));
+
+		// written out and read back as the client would
+		let path =
+			std::env::temp_dir().join(format!("battery-tester-dump-{}.dump", std::process::id()));
+		let mut records = Vec::new();
+		let (record_tx, record_rx) = unbounded_channel();
+		while let Some(record) = dump_rx.recv().await {
+			records.push(record.clone());
+			record_tx.send(record).unwrap();
+		}
+		drop(record_tx);
+		let (print_tx, mut print_rx) = mpsc::channel::<Print>(8);
+		dump_task(
+			File::create(&path).await.unwrap(),
+			record_rx,
+			Printer::new(print_tx),
+		)
+		.await;
+		assert!(print_rx.try_recv().is_err());
+		assert_eq!(read_dump(&path).unwrap(), records);
+		std::fs::remove_file(path).unwrap();
+
+		let directions: Vec<(ChannelId, Direction)> = records
+			.iter()
+			.map(|record| (record.channel, record.direction))
+			.collect();
+		assert_eq!(directions[..2], [(1, Direction::ToDevice); 2]);
+		assert!(
+			directions[2..]
+				.iter()
+				.all(|d| *d == (1, Direction::FromDevice))
+		);
+		let mut decoder = DumpDecoder::default();
+		let decoded: Vec<Vec<Decoded>> = records.iter().map(|r| decoder.decode(r)).collect();
+		// the command ends in the second write
+		assert_eq!(decoded[..2], [vec![], vec![Decoded::Command(command)]]);
+		let replies: Vec<Decoded> = decoded[2..].iter().flatten().cloned().collect();
+		assert!(matches!(replies[..], [Decoded::Bad(_), Decoded::Reply(r)] if r == reply));
+	}
+}
@@ -0,0 +1,1574 @@
+//! Running the battery tester inside another program.
+//!
+//! [`EngineBuilder`] starts the same tasks `battery-tester-server` runs, the server binary
+//! is only the command line around it. An embedding program talks to the running
+//! [`Engine`] directly instead of over IPC:
+//! ```no_run
+//! # async fn example() -> Result<(), pc_common::Error> {
+//! use pc_common::{BatteryID, Event, engine::EngineBuilder};
+//!
+//! let engine = EngineBuilder::new("/tmp/tests".into())
+//!     .ipc(false)
+//!     .spawn()
+//!     .await?;
+//! let mut status = engine.status(0).unwrap();
+//! engine.send(0, Event::SetSerialDevice("/dev/ttyACM0".into(), None)).await?;
+//! engine.send(0, Event::BattID(BatteryID { year: 2025, index: 1 })).await?;
+//! while status.server.changed().await.is_ok() {
+//!     println!("{:?}", status.server.borrow_and_update().mode);
+//! }
+//! engine.join().await;
+//! # Ok(())
+//! # }
+//! ```
+//! The link to each battery interface comes from a [`Transport`], serial ports by default.
+//! [`EngineBuilder::sink`] replaces the file task for programs saving tests their own way.
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use battery_tester_common::{
+	BatteryDetect, DEFAULT_BAUD, DEFAULT_POLL_MS, LoadState, LoggedFault, MAX_POLL_MS, MIN_POLL_MS,
+	Measurement, SelfTestReport, Trim,
+};
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::{
+	fs::File,
+	net::TcpListener,
+	select,
+	sync::{
+		mpsc::{self, Receiver, Sender},
+		oneshot, watch,
+	},
+	task::JoinHandle,
+	time::Instant,
+};
+
+use crate::{
+	ChamberCmd, ChannelEvent, ChannelId, ComCmd, Error, Event, Feature, FileCmd, LinkStats, Mode,
+	OutputFormat, Print, Printer, ServerStatus, StatusWatch, Task, TaskError,
+	calibration::CalibrationStore,
+	chamber::{ScpiChamber, chamber_task},
+	columns::ColumnConfig,
+	dump::{DumpRecord, DumpTransport, dump_task},
+	files::{FileNameTemplate, FlushPolicy, Output, Rotation, SavedTo, file_task},
+	ipc::{InstanceLock, IpcAccess, IpcSettings, Stores, ipc_task, valid_server_name},
+	journal::Journal,
+	live::{DEFAULT_LIVE_EVERY, LiveSummary, LiveView},
+	notify::{NotifyConfig, notify_task},
+	print_task,
+	profile::{ProfileRun, TestProfile},
+	program::{ProgramLinks, TestSettings, program_event_task},
+	registry::BatteryRegistry,
+	serial::{LinkSettings, Reported, SerialTransport, Transport, serial_com_task},
+	signal::{TestSignal, signal_task},
+	termination::TerminationRule,
+	trace::{TraceRecord, read_trace, trace_task},
+	warmup::WarmupRule,
+};
+
+/// A spawned server task, it returns an error when it stops before being shut down
+pub type TaskHandle = JoinHandle<Result<(), TaskError>>;
+
+/// Starts a channel's replacement for the file task, see [`EngineBuilder::sink`]
+pub type SinkSpawner =
+	Box<dyn FnMut(ChannelId, Receiver<FileCmd>, Sender<Event>) -> TaskHandle + Send>;
+
+/// Everything the server can be started with, [`EngineBuilder::spawn`] starts it
+pub struct EngineBuilder<T = SerialTransport> {
+	output_dir: PathBuf,
+	channels: u8,
+	transport: T,
+	profile: Option<TestProfile>,
+	chamber: Option<ScpiChamber>,
+	signal_port: Option<Box<str>>,
+	db: Option<PathBuf>,
+	registry: Option<PathBuf>,
+	fallback_dir: Option<PathBuf>,
+	format: Option<OutputFormat>,
+	columns: ColumnConfig,
+	flush: FlushPolicy,
+	rotation: Rotation,
+	file_name: FileNameTemplate,
+	termination: TerminationRule,
+	ir_pulse: bool,
+	warmup: Option<WarmupRule>,
+	ocv_rest: Option<Duration>,
+	live_every: Duration,
+	poll_ms: u16,
+	baud: u32,
+	trace: Option<PathBuf>,
+	dump_serial: Option<PathBuf>,
+	notify: NotifyConfig,
+	ipc: bool,
+	ipc_name: Option<Box<str>>,
+	ipc_access: IpcAccess,
+	token: Option<Box<str>>,
+	listen: Option<SocketAddr>,
+	sink: Option<SinkSpawner>,
+	print_tx: Option<Sender<Print>>,
+	extra_features: Vec<Feature>,
+}
+
+impl EngineBuilder {
+	/// One channel on a serial port, saving TSV files to `output_dir`, controlled over IPC
+	pub fn new(output_dir: PathBuf) -> Self {
+		Self {
+			output_dir,
+			channels: 1,
+			transport: SerialTransport,
+			profile: None,
+			chamber: None,
+			signal_port: None,
+			db: None,
+			registry: None,
+			fallback_dir: None,
+			format: None,
+			columns: ColumnConfig::default(),
+			flush: FlushPolicy::default(),
+			rotation: Rotation::default(),
+			file_name: FileNameTemplate::default(),
+			termination: TerminationRule::default(),
+			ir_pulse: true,
+			warmup: None,
+			ocv_rest: None,
+			live_every: DEFAULT_LIVE_EVERY,
+			poll_ms: DEFAULT_POLL_MS,
+			baud: DEFAULT_BAUD,
+			trace: None,
+			dump_serial: None,
+			notify: NotifyConfig::default(),
+			ipc: true,
+			ipc_name: None,
+			ipc_access: IpcAccess::default(),
+			token: None,
+			listen: None,
+			sink: None,
+			print_tx: None,
+			extra_features: Vec::new(),
+		}
+	}
+}
+
+impl<T: Transport> EngineBuilder<T> {
+	/// Battery interfaces tested with at once, see [`crate::Cli::channels`]
+	pub fn channels(mut self, channels: u8) -> Self {
+		self.channels = channels;
+		self
+	}
+
+	/// How every channel opens its battery interface
+	pub fn transport<U: Transport>(self, transport: U) -> EngineBuilder<U> {
+		EngineBuilder {
+			output_dir: self.output_dir,
+			channels: self.channels,
+			transport,
+			profile: self.profile,
+			chamber: self.chamber,
+			signal_port: self.signal_port,
+			db: self.db,
+			registry: self.registry,
+			fallback_dir: self.fallback_dir,
+			format: self.format,
+			columns: self.columns,
+			flush: self.flush,
+			rotation: self.rotation,
+			file_name: self.file_name,
+			termination: self.termination,
+			ir_pulse: self.ir_pulse,
+			warmup: self.warmup,
+			ocv_rest: self.ocv_rest,
+			live_every: self.live_every,
+			poll_ms: self.poll_ms,
+			baud: self.baud,
+			trace: self.trace,
+			dump_serial: self.dump_serial,
+			notify: self.notify,
+			ipc: self.ipc,
+			ipc_name: self.ipc_name,
+			ipc_access: self.ipc_access,
+			token: self.token,
+			listen: self.listen,
+			sink: self.sink,
+			print_tx: self.print_tx,
+			extra_features: self.extra_features,
+		}
+	}
+
+	/// Worked through by channel 0 each time a test is started
+	pub fn profile(mut self, profile: TestProfile) -> Self {
+		self.profile = Some(profile);
+		self
+	}
+
+	/// Environmental chamber for channel 0's profile steps
+	pub fn chamber(mut self, chamber: ScpiChamber) -> Self {
+		self.chamber = Some(chamber);
+		self
+	}
+
+	/// Serial port for channel 0's DTR/RTS test state lines
+	pub fn signal_port(mut self, port_name: Box<str>) -> Self {
+		self.signal_port = Some(port_name);
+		self
+	}
+
+	/// SQLite database every channel can save tests in, the default format when given
+	pub fn db(mut self, path: PathBuf) -> Self {
+		self.db = Some(path);
+		self
+	}
+
+	/// Battery registry file, `battery-registry.toml` in the output directory by default
+	pub fn registry(mut self, path: PathBuf) -> Self {
+		self.registry = Some(path);
+		self
+	}
+
+	/// Where a test carries on when the output directory can't be written,
+	/// each channel has its own directory in it like in the output directory
+	pub fn fallback_dir(mut self, path: PathBuf) -> Self {
+		self.fallback_dir = Some(path);
+		self
+	}
+
+	pub fn format(mut self, format: OutputFormat) -> Self {
+		self.format = Some(format);
+		self
+	}
+
+	pub fn columns(mut self, columns: ColumnConfig) -> Self {
+		self.columns = columns;
+		self
+	}
+
+	/// When every channel writes its samples out, every 10 by default
+	pub fn flush(mut self, flush: FlushPolicy) -> Self {
+		self.flush = flush;
+		self
+	}
+
+	/// When a long test on any channel carries on in a new file, never by default
+	pub fn rotation(mut self, rotation: Rotation) -> Self {
+		self.rotation = rotation;
+		self
+	}
+
+	/// What every channel's new files are called
+	pub fn file_name(mut self, template: FileNameTemplate) -> Self {
+		self.file_name = template;
+		self
+	}
+
+	/// When a test on any channel has reached its cutoff, the first measurement at it by default
+	pub fn termination(mut self, rule: TerminationRule) -> Self {
+		self.termination = rule;
+		self
+	}
+
+	/// Whether tests on every channel start with a load pulse estimating the battery's
+	/// internal resistance, they do by default
+	pub fn ir_pulse(mut self, ir_pulse: bool) -> Self {
+		self.ir_pulse = ir_pulse;
+		self
+	}
+
+	/// Time under the load before tests on every channel are recorded, they're recorded
+	/// from the first measurement by default
+	pub fn warmup(mut self, warmup: WarmupRule) -> Self {
+		self.warmup = Some(warmup);
+		self
+	}
+
+	/// Time tests on every channel rest with the load off for the battery's open circuit
+	/// voltage, before the load is turned on and after they end on their own
+	pub fn ocv_rest(mut self, rest: Duration) -> Self {
+		self.ocv_rest = Some(rest);
+		self
+	}
+
+	/// How often every channel prints a measurement and sends one to `watch` clients,
+	/// [`DEFAULT_LIVE_EVERY`] by default. Every measurement is still saved.
+	pub fn live_every(mut self, every: Duration) -> Self {
+		self.live_every = every;
+		self
+	}
+
+	/// ms between the commands sent to every channel's battery interface, [`DEFAULT_POLL_MS`]
+	/// by default and clamped to [`MIN_POLL_MS`]..=[`MAX_POLL_MS`]. The battery interface is
+	/// sent it too, and turns the load off after
+	/// [`com_timeout_ms`](battery_tester_common::com_timeout_ms) without a command.
+	pub fn poll_ms(mut self, poll_ms: u16) -> Self {
+		self.poll_ms = poll_ms.clamp(MIN_POLL_MS, MAX_POLL_MS);
+		self
+	}
+
+	/// Baud the serial ports are opened at, [`DEFAULT_BAUD`] by default. A device set with
+	/// its own baud uses that. The battery interface's firmware has to be built for the same
+	/// one, which the server checks as it connects; its own USB port ignores it.
+	pub fn baud(mut self, baud: u32) -> Self {
+		self.baud = baud;
+		self
+	}
+
+	/// Record every event and mode change of channel 0 to this file
+	pub fn trace(mut self, path: PathBuf) -> Self {
+		self.trace = Some(path);
+		self
+	}
+
+	/// Copy every byte to and from each channel's battery interface to this file,
+	/// see [`crate::dump`]
+	pub fn dump_serial(mut self, path: PathBuf) -> Self {
+		self.dump_serial = Some(path);
+		self
+	}
+
+	/// Tell operators when a test on any channel ends, faults, or loses its battery interface
+	pub fn notify(mut self, config: NotifyConfig) -> Self {
+		self.notify = config;
+		self
+	}
+
+	/// Take commands from `battery-tester-client`, on by default.
+	/// Only one engine per machine can have IPC on under each name.
+	pub fn ipc(mut self, ipc: bool) -> Self {
+		self.ipc = ipc;
+		self
+	}
+
+	/// Name clients pick this server by, see [`crate::ipc::server_id`]
+	pub fn ipc_name(mut self, name: Box<str>) -> Self {
+		self.ipc_name = Some(name);
+		self
+	}
+
+	/// Who on this machine can connect, only the user running the engine by default
+	pub fn ipc_access(mut self, access: IpcAccess) -> Self {
+		self.ipc_access = access;
+		self
+	}
+
+	/// Refuse client commands that change anything without `token`, over IPC and TCP alike
+	pub fn token(mut self, token: Box<str>) -> Self {
+		self.token = Some(token);
+		self
+	}
+
+	/// Also take client commands over TCP on `addr`, see [`crate::Cli::listen`].
+	/// Nothing is listened on with IPC off.
+	pub fn listen(mut self, addr: SocketAddr) -> Self {
+		self.listen = Some(addr);
+		self
+	}
+
+	/// Save tests with tasks started by `spawn_sink` instead of the file task.
+	/// Each one gets its channel's [`FileCmd`]s and has to answer [`FileCmd::NewTest`]
+	/// and [`FileCmd::Resume`] or the channel waits forever. It can send the channel
+	/// [`Event::FileError`] when saving fails, returning an error shuts the server down.
+	pub fn sink(mut self, spawn_sink: SinkSpawner) -> Self {
+		self.sink = Some(spawn_sink);
+		self
+	}
+
+	/// Send what the server prints here instead of to stdout.
+	/// [`Print::Shutdown`] is the last thing sent.
+	pub fn print_to(mut self, print_tx: Sender<Print>) -> Self {
+		self.print_tx = Some(print_tx);
+		self
+	}
+
+	/// Report a feature the embedding program provides in every channel's [`crate::Capabilities`]
+	pub fn advertise(mut self, feature: Feature) -> Self {
+		self.extra_features.push(feature);
+		self
+	}
+
+	/// Start every task, the engine runs until it's sent [`Event::Shutdown`]
+	pub async fn spawn(mut self) -> Result<Engine, Error> {
+		if !self.output_dir.is_dir() {
+			return Err(Error::OutputPathIsDir(self.output_dir.into_boxed_path()));
+		}
+		if self.channels == 0 {
+			return Err(Error::NoChannels);
+		}
+		if let Some(name) = self.ipc_name.take_if(|name| !valid_server_name(name)) {
+			return Err(Error::BadServerName(name));
+		}
+		// first, so a second server stops before it touches anything the first one has
+		let instance_lock = self
+			.ipc
+			.then(|| InstanceLock::acquire(self.ipc_name.as_deref()))
+			.transpose()?;
+		let output_format = match self.format {
+			Some(OutputFormat::Sqlite) if self.db.is_none() => return Err(Error::DatabaseRequired),
+			Some(format) => format,
+			None if self.db.is_some() => OutputFormat::Sqlite,
+			None => OutputFormat::Tsv,
+		};
+		if self.chamber.is_none()
+			&& self
+				.profile
+				.as_ref()
+				.is_some_and(TestProfile::needs_chamber)
+		{
+			return Err(Error::ChamberRequired);
+		}
+		let registry = Arc::new(BatteryRegistry::load(
+			self.registry
+				.take()
+				.unwrap_or_else(|| BatteryRegistry::path(&self.output_dir)),
+		)?);
+		let calibrations = Arc::new(CalibrationStore::load(CalibrationStore::path(
+			&self.output_dir,
+		))?);
+		// bound now so a port that's taken stops the engine from starting
+		let listener = match self.listen.filter(|_| self.ipc) {
+			Some(addr) => Some(
+				TcpListener::bind(addr)
+					.await
+					.map_err(|e| Error::Listen(addr, e))?,
+			),
+			None => None,
+		};
+
+		// cross task comms
+		let (supervisor_tx, supervisor_rx) = mpsc::channel::<ChannelEvent>(8);
+		let (ipc_shutdown_tx, ipc_shutdown_rx) = oneshot::channel();
+		let (signal_tx, signal_rx) = mpsc::channel::<TestSignal>(4);
+		let (chamber_cmd_tx, chamber_cmd_rx) = mpsc::channel::<ChamberCmd>(4);
+
+		let mut output_formats = vec![
+			OutputFormat::Tsv,
+			OutputFormat::Csv,
+			OutputFormat::JsonLines,
+		];
+		if self.db.is_some() {
+			output_formats.push(OutputFormat::Sqlite);
+		}
+		let features = [
+			(self.profile.is_some(), Feature::Profile),
+			(self.chamber.is_some(), Feature::Chamber),
+			(self.signal_port.is_some(), Feature::SignalLines),
+		];
+		let features: Arc<[Feature]> = features
+			.into_iter()
+			.filter_map(|(enabled, feature)| enabled.then_some(feature))
+			.chain(self.extra_features)
+			.collect();
+		let output_formats: Arc<[OutputFormat]> = output_formats.into();
+		let mut channels = Vec::with_capacity(self.channels.into());
+		for id in 0..self.channels {
+			let output = ChannelOutput {
+				output_dir: &self.output_dir,
+				count: self.channels,
+				columns: self.columns.clone(),
+				flush: self.flush,
+				rotation: self.rotation,
+				file_name: self.file_name.clone(),
+				db: self.db.as_deref(),
+				fallback_dir: self.fallback_dir.as_deref(),
+			};
+			channels.push(Channel::new(
+				id,
+				output,
+				features.clone(),
+				output_formats.clone(),
+			)?);
+		}
+
+		// println!() replacement
+		let (print_tx, print_task_handle) = match self.print_tx {
+			Some(print_tx) => (print_tx, None),
+			None => {
+				let (print_tx, print_rx) = mpsc::channel::<Print>(16);
+				(print_tx, Some(tokio::spawn(print_task(print_rx))))
+			}
+		};
+		let printer = Printer::new(print_tx);
+
+		// optional record of everything channel 0's program task is sent, in between it and the senders
+		let (mode_tx, trace_task_handle) = match &self.trace {
+			Some(path) => {
+				let file = File::create(path)
+					.await
+					.map_err(|e| Error::TraceCreate(path.clone().into_boxed_path(), e))?;
+				let (traced_event_tx, traced_event_rx) = mpsc::channel::<Event>(8);
+				let (mode_tx, mode_rx) = mpsc::channel::<Mode>(8);
+				let event_rx = std::mem::replace(&mut channels[0].event_rx, traced_event_rx);
+				let handle = tokio::spawn(trace_task(
+					file,
+					event_rx,
+					traced_event_tx,
+					mode_rx,
+					printer.task(Task::Trace),
+				));
+				(Some(mode_tx), Some(handle))
+			}
+			None => (None, None),
+		};
+
+		// optional copy of every channel's serial traffic
+		let (dump_tx, dump_task_handle) = match &self.dump_serial {
+			Some(path) => {
+				let file = File::create(path)
+					.await
+					.map_err(|e| Error::DumpCreate(path.clone().into_boxed_path(), e))?;
+				let (dump_tx, dump_rx) = mpsc::unbounded_channel::<DumpRecord>();
+				let handle = tokio::spawn(dump_task(file, dump_rx, printer.task(Task::Dump)));
+				(Some(dump_tx), Some(handle))
+			}
+			None => (None, None),
+		};
+		let started = Instant::now();
+
+		// optional relay/lamp outputs
+		let mut tasks = Vec::new();
+		let signal_tx = self.signal_port.map(|port_name| {
+			tasks.push(Supervised::spawn(
+				Task::Signal,
+				Some(0),
+				infallible(signal_task(
+					port_name,
+					signal_rx,
+					printer.task(Task::Signal),
+				)),
+			));
+			signal_tx
+		});
+
+		// optional environmental chamber for profile steps
+		let chamber_cmd_tx = self.chamber.map(|chamber| {
+			tasks.push(Supervised::spawn(
+				Task::Chamber,
+				Some(0),
+				chamber_task(
+					chamber,
+					channels[0].event_tx.clone(),
+					chamber_cmd_rx,
+					printer.task(Task::Chamber),
+				),
+			));
+			chamber_cmd_tx
+		});
+
+		// a state machine per channel, with its own serial link and files
+		let mut channel_0_extras = Some((
+			signal_tx,
+			chamber_cmd_tx,
+			ProfileRun::new(self.profile),
+			mode_tx,
+		));
+		let mut program_tasks = Vec::with_capacity(channels.len());
+		let mut views = Vec::with_capacity(channels.len());
+		for (id, channel) in (0..).zip(channels) {
+			let (signal_tx, chamber_cmd_tx, profile, mode_tx) = channel_0_extras
+				.take()
+				.unwrap_or_else(|| (None, None, ProfileRun::new(None), None));
+			let (file_cmd_tx, file_cmd_rx) = mpsc::channel::<FileCmd>(8);
+			let (com_cmd_tx, com_cmd_rx) = mpsc::channel::<ComCmd>(8);
+			// which channel a line is about only matters with more than one
+			let channel_printer = if self.channels > 1 {
+				printer.channel(id)
+			} else {
+				printer.clone()
+			};
+			let notify_tx = (!self.notify.is_empty()).then(|| {
+				let (notify_tx, notify_rx) = mpsc::channel::<ServerStatus>(4);
+				tasks.push(Supervised::spawn(
+					Task::Notify,
+					Some(id),
+					infallible(notify_task(
+						id,
+						self.notify.clone(),
+						notify_rx,
+						channel.status.measurement.clone(),
+						channel_printer.task(Task::Notify),
+					)),
+				));
+				notify_tx
+			});
+			let links = ProgramLinks {
+				rx: channel.event_rx,
+				file_cmd_tx,
+				com_cmd_tx,
+				printer: channel_printer.task(Task::Program),
+				signal_tx,
+				chamber_cmd_tx,
+				status_tx: channel.status_tx,
+				mode_tx,
+				notify_tx,
+				registry: Some(registry.clone()),
+				calibrations: Some(calibrations.clone()),
+				timer_tx: Some(channel.event_tx.downgrade()),
+			};
+			let program_task_handle = tokio::spawn(program_event_task(
+				links,
+				profile,
+				TestSettings {
+					output_format,
+					termination: self.termination,
+					ir_pulse: self.ir_pulse,
+					warmup: self.warmup,
+					ocv_rest: self.ocv_rest,
+					poll_ms: self.poll_ms,
+				},
+				Some(channel.journal_path),
+				channel.interrupted,
+			));
+			program_tasks.push((channel.event_tx.clone(), program_task_handle));
+			views.push((channel.event_tx.clone(), channel.status));
+			let reported = Reported {
+				measurement_tx: channel.measurement_tx,
+				live_tx: channel.live_tx,
+				live: LiveView::new(self.live_every),
+				trim_tx: channel.trim_tx,
+				battery_detect_tx: channel.battery_detect_tx,
+				bat_present_tx: channel.bat_present_tx,
+				load_tx: channel.load_tx,
+				fault_log_tx: channel.fault_log_tx,
+				self_test_tx: channel.self_test_tx,
+				link_baud: None,
+			};
+			let link = LinkSettings {
+				poll_ms: self.poll_ms,
+				baud: self.baud,
+			};
+			let serial_printer = channel_printer.task(Task::Serial);
+			let serial_event_tx = channel.event_tx.clone();
+			// the dump copies whatever the transport opens
+			tasks.push(match &dump_tx {
+				Some(dump_tx) => Supervised::spawn(
+					Task::Serial,
+					Some(id),
+					serial_com_task(
+						DumpTransport::new(self.transport.clone(), id, started, dump_tx.clone()),
+						serial_event_tx,
+						com_cmd_rx,
+						channel.link_stats_tx,
+						reported,
+						link,
+						serial_printer,
+					),
+				),
+				None => Supervised::spawn(
+					Task::Serial,
+					Some(id),
+					serial_com_task(
+						self.transport.clone(),
+						serial_event_tx,
+						com_cmd_rx,
+						channel.link_stats_tx,
+						reported,
+						link,
+						serial_printer,
+					),
+				),
+			});
+			tasks.push(match &mut self.sink {
+				Some(spawn_sink) => Supervised {
+					task: Task::File,
+					channel: Some(id),
+					handle: spawn_sink(id, file_cmd_rx, channel.event_tx),
+				},
+				None => Supervised::spawn(
+					Task::File,
+					Some(id),
+					file_task(
+						channel.event_tx,
+						file_cmd_rx,
+						channel.output,
+						channel_printer.task(Task::File),
+					),
+				),
+			});
+		}
+
+		if self.ipc {
+			tasks.push(Supervised::spawn(
+				Task::Ipc,
+				None,
+				ipc_task(
+					IpcSettings {
+						name: self.ipc_name,
+						access: self.ipc_access,
+						token: self.token,
+					},
+					listener,
+					supervisor_tx.clone(),
+					views.iter().map(|(_, status)| status.clone()).collect(),
+					Stores {
+						registry,
+						calibrations,
+					},
+					printer.task(Task::Ipc),
+					ipc_shutdown_rx,
+				),
+			));
+		}
+
+		// main control loop
+		let supervisor_task_handle = tokio::spawn(supervisor_task(
+			supervisor_rx,
+			program_tasks,
+			tasks,
+			ipc_shutdown_tx,
+			printer.clone(),
+		));
+		Ok(Engine {
+			supervisor_tx,
+			channels: views,
+			printer,
+			supervisor_task_handle,
+			print_task_handle,
+			trace_task_handle,
+			dump_task_handle,
+			instance_lock,
+		})
+	}
+}
+
+/// The running server, see [`EngineBuilder`]
+pub struct Engine {
+	supervisor_tx: Sender<ChannelEvent>,
+	/// Each channel's events, straight to its program task, and its status
+	channels: Vec<(Sender<Event>, StatusWatch)>,
+	printer: Printer,
+	/// Owns every other task, see [`supervisor_task`]
+	supervisor_task_handle: JoinHandle<()>,
+	print_task_handle: Option<JoinHandle<()>>,
+	trace_task_handle: Option<JoinHandle<()>>,
+	dump_task_handle: Option<JoinHandle<()>>,
+	/// `None` without IPC
+	instance_lock: Option<InstanceLock>,
+}
+
+impl Engine {
+	pub fn channels(&self) -> u8 {
+		self.channels.len() as u8
+	}
+
+	/// Watch the channel's mode, measurements, and serial link
+	pub fn status(&self, channel: ChannelId) -> Option<StatusWatch> {
+		self.channels
+			.get(usize::from(channel))
+			.map(|(_, status)| status.clone())
+	}
+
+	/// Send the channel an event like a client command would,
+	/// [`Event::Shutdown`] shuts every channel down
+	pub async fn send(&self, channel: ChannelId, event: Event) -> Result<(), Error> {
+		if usize::from(channel) >= self.channels.len() {
+			return Err(Error::NoSuchChannel(channel));
+		}
+		self.supervisor_tx
+			.send(ChannelEvent { channel, event })
+			.await
+			.map_err(|_| Error::EngineStopped)
+	}
+
+	/// Sender straight to the channel's program task, for tasks standing in for a user like the kiosk.
+	/// An [`Event::Shutdown`] sent here still shuts every channel down.
+	pub fn event_sender(&self, channel: ChannelId) -> Option<Sender<Event>> {
+		self.channels
+			.get(usize::from(channel))
+			.map(|(event_tx, _)| event_tx.clone())
+	}
+
+	/// Print along with the server
+	pub fn printer(&self) -> Printer {
+		self.printer.clone()
+	}
+
+	/// Wait for the engine to shut down and every task to exit
+	pub async fn join(self) {
+		let Self {
+			supervisor_tx,
+			channels,
+			printer,
+			supervisor_task_handle,
+			print_task_handle,
+			trace_task_handle,
+			dump_task_handle,
+			instance_lock,
+		} = self;
+		drop(supervisor_tx);
+		drop(printer);
+		// the supervisor reports how the other tasks stopped, it doesn't fail itself
+		let _supervisor_res = supervisor_task_handle.await;
+		if let Some(handle) = print_task_handle {
+			let _print_res = handle.await;
+		}
+		// the trace task runs until every event sender is gone
+		drop(channels);
+		if let Some(handle) = trace_task_handle {
+			let _trace_res = handle.await;
+		}
+		// the serial tasks are gone with the supervisor, and the dump's senders with them
+		if let Some(handle) = dump_task_handle {
+			let _dump_res = handle.await;
+		}
+		// another server can start once the battery interfaces are idle
+		drop(instance_lock);
+	}
+}
+
+/// Where one channel saves its tests
+struct ChannelOutput<'a> {
+	/// The server's output directory
+	output_dir: &'a Path,
+	/// Channels the server has
+	count: u8,
+	columns: ColumnConfig,
+	flush: FlushPolicy,
+	rotation: Rotation,
+	file_name: FileNameTemplate,
+	db: Option<&'a Path>,
+	/// The server's fallback directory, only made once it's needed
+	fallback_dir: Option<&'a Path>,
+}
+
+/// One battery interface and the battery on it, what its tasks start with
+struct Channel {
+	event_tx: Sender<Event>,
+	event_rx: Receiver<Event>,
+	status_tx: watch::Sender<ServerStatus>,
+	link_stats_tx: watch::Sender<LinkStats>,
+	measurement_tx: watch::Sender<Option<Measurement>>,
+	live_tx: watch::Sender<Option<LiveSummary>>,
+	trim_tx: watch::Sender<Option<Trim>>,
+	battery_detect_tx: watch::Sender<Option<BatteryDetect>>,
+	bat_present_tx: watch::Sender<Option<bool>>,
+	load_tx: watch::Sender<Option<LoadState>>,
+	fault_log_tx: watch::Sender<Vec<LoggedFault>>,
+	self_test_tx: watch::Sender<Option<SelfTestReport>>,
+	status: StatusWatch,
+	output: Output,
+	journal_path: PathBuf,
+	interrupted: Option<Journal>,
+}
+
+impl Channel {
+	fn new(
+		id: ChannelId,
+		output: ChannelOutput,
+		features: Arc<[Feature]>,
+		output_formats: Arc<[OutputFormat]>,
+	) -> Result<Self, Error> {
+		// a single channel saves straight to the output directory
+		let output_dir = if output.count == 1 {
+			output.output_dir.to_path_buf()
+		} else {
+			let dir = output.output_dir.join(format!("channel-{id}"));
+			std::fs::create_dir_all(&dir)
+				.map_err(|e| Error::ChannelOutput(dir.clone().into_boxed_path(), e))?;
+			dir
+		};
+		let fallback_dir = output.fallback_dir.map(|dir| {
+			if output.count == 1 {
+				dir.to_path_buf()
+			} else {
+				dir.join(format!("channel-{id}"))
+			}
+		});
+		let journal_path = Journal::path(&output_dir);
+		let interrupted = Journal::load(&journal_path)?;
+		let output = Output {
+			output_dir,
+			fallback_dir,
+			columns: output.columns,
+			db: output.db.map(Output::open_db).transpose()?,
+			flush: output.flush,
+			rotation: output.rotation,
+			file_name: output.file_name,
+		};
+		let (event_tx, event_rx) = mpsc::channel::<Event>(8);
+		let (link_stats_tx, link_stats_rx) = watch::channel(LinkStats::default());
+		let (measurement_tx, measurement_rx) = watch::channel(None);
+		let (live_tx, live_rx) = watch::channel(None);
+		let (trim_tx, trim_rx) = watch::channel(None);
+		let (battery_detect_tx, battery_detect_rx) = watch::channel(None);
+		let (bat_present_tx, bat_present_rx) = watch::channel(None);
+		let (load_tx, load_rx) = watch::channel(None);
+		let (fault_log_tx, fault_log_rx) = watch::channel(Vec::new());
+		let (self_test_tx, self_test_rx) = watch::channel(None);
+		let (status_tx, status_rx) = watch::channel(ServerStatus::default());
+		Ok(Self {
+			event_tx,
+			event_rx,
+			status_tx,
+			link_stats_tx,
+			measurement_tx,
+			live_tx,
+			trim_tx,
+			battery_detect_tx,
+			bat_present_tx,
+			load_tx,
+			fault_log_tx,
+			self_test_tx,
+			status: StatusWatch {
+				server: status_rx,
+				link: link_stats_rx,
+				measurement: measurement_rx,
+				live: live_rx,
+				trim: trim_rx,
+				battery_detect: battery_detect_rx,
+				bat_present: bat_present_rx,
+				load: load_rx,
+				fault_log: fault_log_rx,
+				self_test: self_test_rx,
+				features,
+				output_formats,
+			},
+			output,
+			journal_path,
+			interrupted,
+		})
+	}
+}
+
+/// A task the supervisor waits on, `channel` is `None` for one serving every channel
+struct Supervised {
+	task: Task,
+	channel: Option<ChannelId>,
+	handle: TaskHandle,
+}
+
+impl Supervised {
+	fn spawn(
+		task: Task,
+		channel: Option<ChannelId>,
+		future: impl Future<Output = Result<(), TaskError>> + Send + 'static,
+	) -> Self {
+		Self {
+			task,
+			channel,
+			handle: tokio::spawn(future),
+		}
+	}
+
+	/// Once the task stops, a panic counts as failing
+	async fn stopped(self) -> (Task, Option<ChannelId>, Result<(), TaskError>) {
+		let result = self.handle.await.unwrap_or(Err(TaskError::Panicked));
+		(self.task, self.channel, result)
+	}
+}
+
+/// For the tasks with nothing to fail on, they can still panic
+async fn infallible(task: impl Future<Output = ()>) -> Result<(), TaskError> {
+	task.await;
+	Ok(())
+}
+
+/// Hands each command to the channel it's for and shuts the server down.
+/// Channels only stop when shutting down, once one stops the rest are shut down too.
+/// Any other task failing sends its channel, or every channel, [`Event::InternalError`]
+/// so the test is stopped safely before everything else is shut down.
+async fn supervisor_task(
+	mut rx: Receiver<ChannelEvent>,
+	channels: Vec<(Sender<Event>, TaskHandle)>,
+	tasks: Vec<Supervised>,
+	ipc_shutdown_tx: oneshot::Sender<()>,
+	printer: Printer,
+) {
+	let (event_txs, program_tasks): (Vec<_>, Vec<_>) = channels.into_iter().unzip();
+	let mut running: FuturesUnordered<_> = (0..)
+		.zip(program_tasks)
+		.map(|(id, handle)| {
+			let program = Supervised {
+				task: Task::Program,
+				channel: Some(id),
+				handle,
+			};
+			program.stopped()
+		})
+		.collect();
+	let mut others: FuturesUnordered<_> = tasks.into_iter().map(Supervised::stopped).collect();
+	// which channel a task is on only matters with more than one
+	let label = event_txs.len() > 1;
+	let mut commands_open = true;
+	loop {
+		select! {
+			channel_event = rx.recv(), if commands_open => match channel_event {
+				Some(ChannelEvent {
+					event: Event::Shutdown,
+					..
+				}) => break,
+				Some(ChannelEvent { channel, event }) => match event_txs.get(usize::from(channel)) {
+					// a channel that stopped is picked up below
+					Some(event_tx) => {
+						let _ = event_tx.send(event).await;
+					}
+					None => {
+						printer
+							.warn(|tv| write!(tv, "no channel {channel} to send {event:?} to"))
+							.await
+					}
+				},
+				// the engine and IPC are gone, only the channels' own senders are left
+				None => commands_open = false,
+			},
+			Some((task, channel, result)) = running.next() => {
+				if let Err(e) = result {
+					task_failed(&printer, task, channel.filter(|_| label), &e).await;
+				}
+				break;
+			}
+			Some((task, channel, result)) = others.next() => {
+				if let Err(e) = result {
+					task_failed(&printer, task, channel.filter(|_| label), &e).await;
+					let failed = match channel {
+						Some(channel) => &event_txs[usize::from(channel)..=usize::from(channel)],
+						None => &event_txs[..],
+					};
+					for event_tx in failed {
+						let _ = event_tx.send(Event::InternalError).await;
+					}
+					break;
+				}
+			}
+			else => break,
+		}
+	}
+	for event_tx in &event_txs {
+		// a channel that already stopped can't be sent anything
+		let _ = event_tx.send(Event::Shutdown).await;
+	}
+	while let Some((task, channel, result)) = running.next().await {
+		if let Err(e) = result {
+			task_failed(&printer, task, channel.filter(|_| label), &e).await;
+		}
+	}
+	// IPC may be off
+	let _ = ipc_shutdown_tx.send(());
+	// the rest stop once the channels and IPC have
+	while let Some((task, channel, result)) = others.next().await {
+		if let Err(e) = result {
+			task_failed(&printer, task, channel.filter(|_| label), &e).await;
+		}
+	}
+	printer.shutdown().await;
+	println!("exiting supervisor_task");
+}
+
+async fn task_failed(printer: &Printer, task: Task, channel: Option<ChannelId>, e: &TaskError) {
+	let printer = match channel {
+		Some(channel) => printer.task(task).channel(channel),
+		None => printer.task(task),
+	};
+	printer
+		.error(|tv| write!(tv, "task stopped: {e}, shutting down"))
+		.await;
+}
+
+/// Feed a recorded trace through the program task and check it goes through the same modes,
+/// [`Error::ReplayDiverged`] if it doesn't. A trace cut off by a crash can have fewer.
+pub async fn replay(
+	trace_path: &Path,
+	profile: Option<TestProfile>,
+	termination: TerminationRule,
+	ir_pulse: bool,
+	ocv_rest: Option<Duration>,
+) -> Result<(), Error> {
+	let records = read_trace(trace_path)?;
+	if let Some(TraceRecord::Start {
+		started,
+		server_version,
+	}) = records.first()
+	{
+		println!("replaying trace started: {started}, by server version: {server_version}");
+	}
+	let recorded_modes: Vec<Mode> = records
+		.iter()
+		.filter_map(|record| match record {
+			TraceRecord::Mode(mode) => Some(*mode),
+			_ => None,
+		})
+		.collect();
+
+	let (print_tx, print_rx) = mpsc::channel::<Print>(16);
+	let print_task_handle = tokio::spawn(print_task(print_rx));
+	let printer = Printer::new(print_tx);
+	let (event_tx, event_rx) = mpsc::channel::<Event>(8);
+	let (file_cmd_tx, file_cmd_rx) = mpsc::channel::<FileCmd>(8);
+	let (com_cmd_tx, com_cmd_rx) = mpsc::channel::<ComCmd>(8);
+	let (chamber_cmd_tx, chamber_cmd_rx) = mpsc::channel::<ChamberCmd>(4);
+	let (mode_tx, mut mode_rx) = mpsc::channel::<Mode>(8);
+	let (status_tx, _status_rx) = watch::channel(ServerStatus::default());
+	// only the program task runs, whatever it tells the other tasks goes nowhere
+	tokio::spawn(drain_files(file_cmd_rx));
+	tokio::spawn(drain(com_cmd_rx));
+	tokio::spawn(drain(chamber_cmd_rx));
+	let chamber_cmd_tx = profile
+		.as_ref()
+		.is_some_and(TestProfile::needs_chamber)
+		.then_some(chamber_cmd_tx);
+
+	let links = ProgramLinks {
+		rx: event_rx,
+		file_cmd_tx,
+		com_cmd_tx,
+		printer: printer.task(Task::Program),
+		signal_tx: None,
+		chamber_cmd_tx,
+		status_tx,
+		mode_tx: Some(mode_tx),
+		notify_tx: None,
+		registry: None,
+		calibrations: None,
+		timer_tx: None,
+	};
+	let program_task_handle = tokio::spawn(program_event_task(
+		links,
+		ProfileRun::new(profile),
+		// nothing is saved, every format replays the same, warming up or not
+		TestSettings {
+			output_format: OutputFormat::default(),
+			termination,
+			ir_pulse,
+			warmup: None,
+			ocv_rest,
+			poll_ms: DEFAULT_POLL_MS,
+		},
+		None,
+		None,
+	));
+	let mode_collector = tokio::spawn(async move {
+		let mut modes = Vec::new();
+		while let Some(mode) = mode_rx.recv().await {
+			modes.push(mode);
+		}
+		modes
+	});
+	for record in records {
+		if let TraceRecord::Event { event, .. } = record
+			&& event_tx.send(event).await.is_err()
+		{
+			// the program task shut down, anything after is the other tasks winding down
+			break;
+		}
+	}
+	drop(event_tx);
+	let _prog_res = program_task_handle.await;
+	printer.shutdown().await;
+	let _print_res = print_task_handle.await;
+	let replayed_modes = mode_collector.await.unwrap_or_default();
+
+	println!("modes: {replayed_modes:?}");
+	match recorded_modes
+		.iter()
+		.zip(&replayed_modes)
+		.position(|(recorded, replayed)| recorded != replayed)
+	{
+		None if recorded_modes.len() == replayed_modes.len() => {
+			println!(
+				"replay went through the same {} modes as the recording",
+				replayed_modes.len()
+			);
+		}
+		None => {
+			println!(
+				"replay went through {} modes, the recording: {}",
+				replayed_modes.len(),
+				recorded_modes.len()
+			);
+		}
+		Some(i) => {
+			return Err(Error::ReplayDiverged(
+				i,
+				recorded_modes[i],
+				replayed_modes[i],
+			));
+		}
+	}
+	Ok(())
+}
+
+async fn drain<T>(mut rx: Receiver<T>) {
+	while rx.recv().await.is_some() {}
+}
+
+/// Like [`drain`] but every new test is ready straight away, nothing is saved
+async fn drain_files(mut rx: Receiver<FileCmd>) {
+	while let Some(cmd) = rx.recv().await {
+		match cmd {
+			FileCmd::NewTest(_battery_id, _format, reply_tx) => {
+				let _ = reply_tx.send(Ok(SavedTo::File("replay".into())));
+			}
+			FileCmd::Resume(saved_to, _format, reply_tx) => {
+				let _ = reply_tx.send(Ok(saved_to));
+			}
+			_ => {}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		profile::{ChamberStep, ProfileStep},
+		sim::{SimConfig, SimTransport, demo_task},
+	};
+	use battery_tester_common::{
+		BIReply, BiCommand, CommandKind, FirmwareVersion, PROTOCOL_VERSION, ReplyKind,
+		frame::{self, COMMAND_FRAME_MAX_SIZE, FrameBuffer},
+	};
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt, DuplexStream, duplex},
+		sync::mpsc::{UnboundedSender, unbounded_channel},
+		time::timeout,
+	};
+
+	/// In-memory links, the test is handed the device and the battery interface's end of each one opened
+	#[derive(Clone)]
+	struct FakeTransport(UnboundedSender<(Box<str>, DuplexStream)>);
+
+	impl Transport for FakeTransport {
+		type Link = DuplexStream;
+
+		async fn open(&self, device: &str, _baud: u32) -> std::io::Result<DuplexStream> {
+			let (link, battery_interface) = duplex(4096);
+			let _ = self.0.send((device.into(), battery_interface));
+			Ok(link)
+		}
+	}
+
+	fn output_dir(test: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!(
+			"battery-tester-engine-{test}-{}",
+			std::process::id()
+		));
+		std::fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	/// Saves nothing, the tests here never start one
+	fn no_sink() -> SinkSpawner {
+		Box::new(|_, mut file_cmd_rx, _| {
+			tokio::spawn(async move {
+				while file_cmd_rx.recv().await.is_some() {}
+				Ok(())
+			})
+		})
+	}
+
+	/// Everything printed until the engine shuts down, errors first
+	async fn printed(mut print_rx: Receiver<Print>) -> (Vec<String>, Vec<String>) {
+		let (mut errors, mut others) = (Vec::new(), Vec::new());
+		loop {
+			let print = timeout(Duration::from_secs(10), print_rx.recv())
+				.await
+				.expect("engine didn't shut down");
+			let (lines, line) = match print {
+				Some(Print::Error(line)) => (&mut errors, line),
+				Some(Print::Info(line) | Print::Warn(line)) => (&mut others, line),
+				Some(Print::Shutdown) | None => return (errors, others),
+			};
+			lines.push(String::from_utf8_lossy(line.text.as_bytes()).into_owned());
+		}
+	}
+
+	/// `None` once the link is closed
+	async fn command(
+		link: &mut DuplexStream,
+		frame_buf: &mut FrameBuffer<COMMAND_FRAME_MAX_SIZE>,
+	) -> Option<BiCommand> {
+		loop {
+			let byte = timeout(Duration::from_secs(10), link.read_u8())
+				.await
+				.expect("no command")
+				.ok()?;
+			if let Some(command) = frame_buf.push::<BiCommand>(byte) {
+				return Some(command.unwrap());
+			}
+		}
+	}
+
+	/// The stop confirmation only looks at the load, the handshake only at the version
+	async fn answer(link: &mut DuplexStream, seq: u32) {
+		let reply = BIReply {
+			seq,
+			bat_present: false,
+			load: LoadState::Off,
+			kind: ReplyKind::Version {
+				protocol: PROTOCOL_VERSION,
+				firmware: FirmwareVersion {
+					major: 1,
+					minor: 2,
+					patch: 3,
+				},
+				device_id: 1,
+				self_test: None,
+				baud: DEFAULT_BAUD,
+			},
+		};
+		let mut buf = [0u8; 64];
+		link.write_all(frame::encode(&reply, &mut buf[..]).unwrap())
+			.await
+			.unwrap();
+	}
+
+	/// The load is confirmed off before the engine stops
+	async fn confirm_idle(
+		link: &mut DuplexStream,
+		frame_buf: &mut FrameBuffer<COMMAND_FRAME_MAX_SIZE>,
+	) {
+		while let Some(next) = command(link, frame_buf).await {
+			if let CommandKind::Control(control) = next.kind {
+				assert_eq!(control.load, LoadState::Off);
+				answer(link, next.seq).await;
+			}
+		}
+	}
+
+	/// Runs the demo against the simulator, returns the trace of it
+	async fn record_demo(dir: &Path) -> PathBuf {
+		let trace = dir.join("trace");
+		let (print_tx, print_rx) = mpsc::channel(64);
+		let engine = EngineBuilder::new(dir.to_path_buf())
+			.ipc(false)
+			.transport(SimTransport::new(SimConfig::default()))
+			.trace(trace.clone())
+			.print_to(print_tx)
+			.spawn()
+			.await
+			.unwrap();
+		let printed = tokio::spawn(printed(print_rx));
+		let demo = tokio::spawn(demo_task(
+			engine.event_sender(0).unwrap(),
+			engine.status(0).unwrap().server,
+			engine.printer().task(Task::Demo),
+		));
+		timeout(Duration::from_secs(3_600), engine.join())
+			.await
+			.expect("demo didn't shut down");
+		demo.await.unwrap();
+		let (errors, _) = printed.await.unwrap();
+		assert!(errors.is_empty(), "{errors:?}");
+		trace
+	}
+
+	fn write_trace(path: &Path, records: &[TraceRecord]) {
+		let mut bytes = Vec::new();
+		for record in records {
+			let record = postcard::to_extend(record, Vec::new()).unwrap();
+			bytes.extend((record.len() as u32).to_be_bytes());
+			bytes.extend(record);
+		}
+		std::fs::write(path, bytes).unwrap();
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_replay_goes_through_the_recorded_modes() {
+		let dir = output_dir("replay");
+		let trace = record_demo(&dir).await;
+		let recorded: Vec<Mode> = read_trace(&trace)
+			.unwrap()
+			.into_iter()
+			.filter_map(|record| match record {
+				TraceRecord::Mode(mode) => Some(mode),
+				_ => None,
+			})
+			.collect();
+		assert!(recorded.contains(&Mode::Testing), "{recorded:?}");
+		assert!(recorded.contains(&Mode::EndTest), "{recorded:?}");
+
+		replay(&trace, None, TerminationRule::default(), true, None)
+			.await
+			.unwrap();
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_replay_reports_a_divergence() {
+		let dir = output_dir("diverged");
+		let trace = record_demo(&dir).await;
+		// as if the user never started the test
+		let records: Vec<TraceRecord> = read_trace(&trace)
+			.unwrap()
+			.into_iter()
+			.filter(|record| {
+				!matches!(
+					record,
+					TraceRecord::Event {
+						event: Event::StartTest,
+						..
+					}
+				)
+			})
+			.collect();
+		let edited = dir.join("edited-trace");
+		write_trace(&edited, &records);
+
+		let res = replay(&edited, None, TerminationRule::default(), true, None).await;
+		assert!(
+			matches!(res, Err(Error::ReplayDiverged(_, Mode::Testing, replayed)) if replayed != Mode::Testing),
+			"{res:?}"
+		);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_spawn_refuses_bad_settings() {
+		let dir = output_dir("refused");
+		let missing = dir.join("missing");
+		let res = EngineBuilder::new(missing).ipc(false).spawn().await;
+		assert!(matches!(res, Err(Error::OutputPathIsDir(_))));
+
+		let res = EngineBuilder::new(dir.clone())
+			.ipc(false)
+			.channels(0)
+			.spawn()
+			.await;
+		assert!(matches!(res, Err(Error::NoChannels)));
+
+		let res = EngineBuilder::new(dir.clone())
+			.ipc_name("bench/2".into())
+			.spawn()
+			.await;
+		assert!(matches!(res, Err(Error::BadServerName(name)) if &*name == "bench/2"));
+
+		let res = EngineBuilder::new(dir.clone())
+			.ipc(false)
+			.format(OutputFormat::Sqlite)
+			.spawn()
+			.await;
+		assert!(matches!(res, Err(Error::DatabaseRequired)));
+
+		let soak = ProfileStep::Chamber(ChamberStep {
+			setpoint_c: 40.0,
+			tolerance_c: 0.5,
+			soak_s: 600,
+			timeout_s: None,
+		});
+		let res = EngineBuilder::new(dir.clone())
+			.ipc(false)
+			.profile(TestProfile { steps: vec![soak] })
+			.spawn()
+			.await;
+		assert!(matches!(res, Err(Error::ChamberRequired)));
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_engine_reports_the_link_and_shuts_down() {
+		let dir = output_dir("runs");
+		let (link_tx, mut link_rx) = unbounded_channel();
+		let (print_tx, print_rx) = mpsc::channel(64);
+		let engine = EngineBuilder::new(dir.clone())
+			.ipc(false)
+			.transport(FakeTransport(link_tx))
+			.sink(no_sink())
+			.print_to(print_tx)
+			.spawn()
+			.await
+			.unwrap();
+		let printed = tokio::spawn(printed(print_rx));
+		let mut status = engine.status(0).unwrap();
+		assert!(engine.status(1).is_none());
+		assert!(matches!(
+			engine.send(1, Event::Shutdown).await,
+			Err(Error::NoSuchChannel(1))
+		));
+
+		engine
+			.send(0, Event::SetSerialDevice("/dev/ttyACM0".into(), None))
+			.await
+			.unwrap();
+		let (_device, mut link) = link_rx.recv().await.unwrap();
+		let mut frame_buf = FrameBuffer::<COMMAND_FRAME_MAX_SIZE>::new();
+		let hello = command(&mut link, &mut frame_buf).await.unwrap();
+		assert_eq!(hello.kind, CommandKind::Hello);
+		answer(&mut link, hello.seq).await;
+		// what the serial task hears is on the channel's status
+		let bat_present = status.bat_present.wait_for(Option::is_some).await.unwrap();
+		assert_eq!(*bat_present, Some(false));
+		drop(bat_present);
+		assert_eq!(status.link.borrow().received, 1);
+
+		engine.send(0, Event::Shutdown).await.unwrap();
+		confirm_idle(&mut link, &mut frame_buf).await;
+		timeout(Duration::from_secs(10), engine.join())
+			.await
+			.expect("engine didn't shut down");
+		let (errors, _) = printed.await.unwrap();
+		assert!(errors.is_empty(), "{errors:?}");
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_failed_task_shuts_the_engine_down() {
+		let dir = output_dir("failed");
+		let (link_tx, _link_rx) = unbounded_channel();
+		let (print_tx, print_rx) = mpsc::channel(64);
+		let engine = EngineBuilder::new(dir.clone())
+			.ipc(false)
+			.transport(FakeTransport(link_tx))
+			.sink(Box::new(|_, _, _| {
+				tokio::spawn(async { Err(TaskError::Panicked) })
+			}))
+			.print_to(print_tx)
+			.spawn()
+			.await
+			.unwrap();
+		let printed = tokio::spawn(printed(print_rx));
+		// nothing was sent Shutdown
+		timeout(Duration::from_secs(10), engine.join())
+			.await
+			.expect("engine didn't shut down");
+		let (errors, _) = printed.await.unwrap();
+		assert!(
+			errors.iter().any(|e| e.starts_with("task stopped: ")),
+			"{errors:?}"
+		);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_channels_kept_apart() {
+		let dir = output_dir("channels");
+		let (link_tx, mut link_rx) = unbounded_channel();
+		let (print_tx, print_rx) = mpsc::channel(64);
+		let engine = EngineBuilder::new(dir.clone())
+			.ipc(false)
+			.channels(2)
+			.transport(FakeTransport(link_tx))
+			.sink(no_sink())
+			.print_to(print_tx)
+			.spawn()
+			.await
+			.unwrap();
+		let printed = tokio::spawn(printed(print_rx));
+		assert_eq!(engine.channels(), 2);
+		// each saves to its own directory
+		assert!(dir.join("channel-0").is_dir());
+		assert!(dir.join("channel-1").is_dir());
+
+		engine
+			.send(1, Event::SetSerialDevice("/dev/ttyACM1".into(), None))
+			.await
+			.unwrap();
+		let (device, mut link) = link_rx.recv().await.unwrap();
+		assert_eq!(&*device, "/dev/ttyACM1");
+		let mut frame_buf = FrameBuffer::<COMMAND_FRAME_MAX_SIZE>::new();
+		let hello = command(&mut link, &mut frame_buf).await.unwrap();
+		answer(&mut link, hello.seq).await;
+		let mut status = engine.status(1).unwrap();
+		status.bat_present.wait_for(Option::is_some).await.unwrap();
+		assert_eq!(*engine.status(0).unwrap().bat_present.borrow(), None);
+
+		// any channel's shutdown is every channel's
+		engine.send(0, Event::Shutdown).await.unwrap();
+		confirm_idle(&mut link, &mut frame_buf).await;
+		timeout(Duration::from_secs(10), engine.join())
+			.await
+			.expect("engine didn't shut down");
+		let (errors, _) = printed.await.unwrap();
+		assert!(errors.is_empty(), "{errors:?}");
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
@@ -0,0 +1,166 @@
+//! Converting saved tests to Parquet, `battery-tester-client export --parquet`,
+//! for tests logged long enough that TSV is unwieldy. Built with the `parquet` feature.
+//!
+//! Whole number columns are Int64 and the rest Float64, missing values are null.
+
+use std::{path::Path, sync::Arc};
+
+use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch};
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+use parquet::{
+	arrow::ArrowWriter, basic::Compression, errors::ParquetError,
+	file::properties::WriterProperties,
+};
+use thiserror::Error;
+
+use crate::analysis::{AnalysisError, Cell, Table, read_table};
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+	#[error(transparent)]
+	Read(#[from] AnalysisError),
+	#[error("test file: {0:?} doesn't have the same columns as the first")]
+	Columns(Box<Path>),
+	#[error("can't create: {0:?}")]
+	Create(Box<Path>, #[source] std::io::Error),
+	#[error("can't convert to Arrow:\n{0}")]
+	Arrow(#[from] ArrowError),
+	#[error("can't write Parquet:\n{0}")]
+	Parquet(#[from] ParquetError),
+}
+
+/// Write the test saved in `paths`, its parts in order, to one Parquet file at `out`.
+/// Returns the rows written.
+pub fn export_parquet(paths: &[&Path], out: &Path) -> Result<usize, ExportError> {
+	let tables = paths
+		.iter()
+		.map(|path| read_table(path))
+		.collect::<Result<Vec<Table>, _>>()?;
+	let Some(first) = tables.first() else {
+		return Ok(0);
+	};
+	if let Some(other) = tables.iter().find(|table| table.names != first.names) {
+		return Err(ExportError::Columns(other.path.clone()));
+	}
+	let fields: Vec<Field> = first
+		.names
+		.iter()
+		.enumerate()
+		.map(|(column, name)| {
+			let whole = tables
+				.iter()
+				.flat_map(|table| &table.rows)
+				.all(|row| !matches!(row[column], Cell::Float(_)));
+			let data_type = if whole {
+				DataType::Int64
+			} else {
+				DataType::Float64
+			};
+			Field::new(name, data_type, true)
+		})
+		.collect();
+	let schema = Arc::new(Schema::new(fields));
+
+	let file = std::fs::File::create(out).map_err(|e| ExportError::Create(out.into(), e))?;
+	let properties = WriterProperties::builder()
+		.set_compression(Compression::SNAPPY)
+		.build();
+	let mut writer = ArrowWriter::try_new(file, schema.clone(), Some(properties))?;
+	let mut rows = 0;
+	// a batch per part keeps memory to one part at a time on top of the parsed tables
+	for table in &tables {
+		let columns: Vec<ArrayRef> = schema
+			.fields()
+			.iter()
+			.enumerate()
+			.map(|(column, field)| -> ArrayRef {
+				let cells = table.rows.iter().map(|row| row[column]);
+				match field.data_type() {
+					DataType::Int64 => Arc::new(
+						cells
+							.map(|cell| match cell {
+								Cell::Int(i) => Some(i),
+								_ => None,
+							})
+							.collect::<Int64Array>(),
+					),
+					_ => Arc::new(cells.map(|cell| cell.as_f64()).collect::<Float64Array>()),
+				}
+			})
+			.collect();
+		writer.write(&RecordBatch::try_new(schema.clone(), columns)?)?;
+		rows += table.rows.len();
+	}
+	writer.close()?;
+	Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use arrow_array::Array;
+	use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+	#[test]
+	fn test_export_parquet() {
+		let dir =
+			std::env::temp_dir().join(format!("battery-tester-export-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		let header = "sample_start_ms\tvolts\ttemp_c\n";
+		let first = dir.join("2025-3.tsv");
+		std::fs::write(&first, format!("{header}0\t12.100\t25.0\n1000\t12\t\n")).unwrap();
+		let second = dir.join("2025-3-continued-2.tsv");
+		std::fs::write(&second, format!("{header}2000\t11.900\t\n")).unwrap();
+		let out = dir.join("2025-3.parquet");
+
+		assert_eq!(export_parquet(&[&first, &second], &out).unwrap(), 3);
+		let batches: Vec<RecordBatch> =
+			ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&out).unwrap())
+				.unwrap()
+				.build()
+				.unwrap()
+				.collect::<Result<_, _>>()
+				.unwrap();
+		let schema = batches[0].schema();
+		let types: Vec<(&str, &DataType)> = schema
+			.fields()
+			.iter()
+			.map(|field| (field.name().as_str(), field.data_type()))
+			.collect();
+		// a whole number of volts in one row doesn't make it an integer column
+		assert_eq!(
+			types,
+			[
+				("sample_start_ms", &DataType::Int64),
+				("volts", &DataType::Float64),
+				("temp_c", &DataType::Float64)
+			]
+		);
+		let volts: Vec<f64> = batches
+			.iter()
+			.flat_map(|batch| {
+				let column = batch
+					.column(1)
+					.as_any()
+					.downcast_ref::<Float64Array>()
+					.unwrap();
+				column.values().to_vec()
+			})
+			.collect();
+		assert_eq!(volts, [12.1, 12.0, 11.9]);
+		let temps_missing: usize = batches
+			.iter()
+			.map(|batch| batch.column(2).null_count())
+			.sum();
+		assert_eq!(temps_missing, 2);
+
+		let other = dir.join("other.tsv");
+		std::fs::write(&other, "sample_start_ms\tvolts\n3000\t11.8\n").unwrap();
+		assert!(matches!(
+			export_parquet(&[&first, &other], &out),
+			Err(ExportError::Columns(path)) if *path == *other
+		));
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
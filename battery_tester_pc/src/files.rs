@@ -1,98 +1,1784 @@
-use std::io::Write;
+//! Where test data is saved, a file per test or one SQLite database for every test.
+
+use std::{
+	io::Write,
+	num::NonZeroU16,
+	path::{Path, PathBuf},
+	time::{Duration, Instant},
+};
+
+use battery_tester_common::{Fault, FirmwareVersion};
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
 use tokio::{
-	fs::File,
+	fs::{File, OpenOptions},
 	io::AsyncWriteExt,
+	select,
 	sync::mpsc::{Receiver, Sender},
 };
 
-use crate::{Event, FileCmd, SaveData};
+use crate::{
+	BatteryID, Error, Event, FileCmd, OutputFormat, Printer, SaveData, TaskError,
+	columns::{ColumnConfig, Columns, Value},
+	termination::EndReason,
+};
 
-const HEADER_NL: &[u8] = b"dt\tduration\tmillivolts\tmilliamps\n";
+/// Most of a test kept in memory while it can't be written, hours of samples in any format
+const MAX_UNWRITTEN: usize = 1024 * 1024;
+const DB_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Between a test's first file name and the number of the file, see [`Rotation`]
+const CONTINUED: &str = "-continued-";
 
-pub async fn file_task(event_tx: Sender<Event>, mut file_cmd_rx: Receiver<FileCmd>) {
-	let mut persistance: Option<DataPersistance> = None;
-	loop {
-		let cmd = match file_cmd_rx.recv().await {
-			Some(cmd) => cmd,
-			None => break,
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tests (
+	id INTEGER PRIMARY KEY,
+	battery_year INTEGER NOT NULL,
+	battery_index INTEGER NOT NULL,
+	created TEXT NOT NULL,
+	closed TEXT
+);
+CREATE TABLE IF NOT EXISTS samples (
+	test_id INTEGER NOT NULL REFERENCES tests(id),
+	dt INTEGER NOT NULL,
+	duration INTEGER NOT NULL,
+	millivolts INTEGER NOT NULL,
+	milliamps INTEGER NOT NULL,
+	temp_centi_c INTEGER
+);
+CREATE INDEX IF NOT EXISTS samples_test_id ON samples(test_id);
+CREATE TABLE IF NOT EXISTS faults (
+	test_id INTEGER NOT NULL REFERENCES tests(id),
+	time INTEGER NOT NULL,
+	kind TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS notes (
+	test_id INTEGER NOT NULL REFERENCES tests(id),
+	time TEXT NOT NULL,
+	kind TEXT NOT NULL,
+	text TEXT NOT NULL
+);
+";
+
+#[derive(Debug, thiserror::Error)]
+pub enum OutputError {
+	#[error("can't create new output file:\n{0}")]
+	File(#[from] std::io::Error),
+	#[error("can't add test to the database:\n{0}")]
+	Database(#[from] rusqlite::Error),
+	#[error("can't save the test to a database, start the server with --db")]
+	NoDatabase,
+	#[error("test: {0} isn't in the database")]
+	NoSuchTest(i64),
+	#[error("the journal says the test was saved as {1:?} to: {0:?}, it can't be carried on")]
+	JournalMismatch(SavedTo, OutputFormat),
+}
+
+/// Why test data is being dropped, sent with [`Event::FileError`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum FileErrorKind {
+	/// Data arrived without a test to save it to
+	NoTest,
+	/// The disk (and the fallback directory's, if there is one) is full
+	StorageFull,
+	/// Writing the file failed some other way
+	Write,
+	/// The database can't save samples
+	Database,
+}
+
+impl FileErrorKind {
+	fn of(e: &std::io::Error) -> Self {
+		match e.kind() {
+			std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+				FileErrorKind::StorageFull
+			}
+			_ => FileErrorKind::Write,
+		}
+	}
+}
+
+impl std::fmt::Display for FileErrorKind {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			FileErrorKind::NoTest => "there's no test to save to",
+			FileErrorKind::StorageFull => "the disk is full",
+			FileErrorKind::Write => "the test can't be written",
+			FileErrorKind::Database => "the database can't save the test",
+		})
+	}
+}
+
+/// When buffered samples are written out, `--flush`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FlushPolicy {
+	/// After this many samples, a crash loses at most that many
+	Records(NonZeroU16),
+	/// On a timer, a crash loses at most that long of the test
+	Every(Duration),
+	/// Each sample, synced to disk before the next one is taken
+	Immediate,
+}
+
+impl Default for FlushPolicy {
+	/// Every 10 samples, 5 seconds of the test at 2 samples a second
+	fn default() -> Self {
+		FlushPolicy::Records(NonZeroU16::new(10).unwrap())
+	}
+}
+
+impl std::str::FromStr for FlushPolicy {
+	type Err = String;
+
+	/// `10` for every 10 samples, `5s` for every 5 seconds, or `sync`
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let expected = || {
+			format!(
+				"unknown flush policy: {s}, expected a number of samples (10), seconds (5s), or sync"
+			)
 		};
-		match cmd {
-			FileCmd::Push(data) => match &mut persistance {
-				Some(dp) => dp.new_data(&data).await,
-				None => {
-					println!("No output file setup for battery data!");
-					event_tx.send(Event::FileError).await.unwrap()
+		if s == "sync" {
+			return Ok(FlushPolicy::Immediate);
+		}
+		if let Some(secs) = s.strip_suffix('s') {
+			return match secs.parse::<u64>() {
+				Ok(secs @ 1..) => Ok(FlushPolicy::Every(Duration::from_secs(secs))),
+				_ => Err(expected()),
+			};
+		}
+		s.parse().map(FlushPolicy::Records).map_err(|_| expected())
+	}
+}
+
+/// When a long test carries on in a new file, so one bad file doesn't lose all of it.
+/// The new file is named like the first with `-continued-2`, `-continued-3`, and so on after it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Rotation {
+	/// Time since the file was started, `--rotate-hours`
+	pub every: Option<Duration>,
+	/// Size of the file, `--rotate-mb`
+	pub max_bytes: Option<u64>,
+}
+
+impl Rotation {
+	fn due(&self, dp: &DataPersistance) -> bool {
+		self.every
+			.is_some_and(|every| dp.started.elapsed() >= every)
+			|| self.max_bytes.is_some_and(|max| dp.file_bytes >= max)
+	}
+}
+
+/// How new files are named, `--file-name`. The extension is added after it.
+///
+/// - `{year}` and `{index}`: the battery ID
+/// - `{local}`: local time the test started, with its offset from UTC
+/// - `{utc}`: UTC time the test started, like `20240131T154502Z`
+/// - `{seq}`: the first number from 1 that makes the name one that isn't taken
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FileNameTemplate(Vec<NamePart>);
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum NamePart {
+	Text(Box<str>),
+	Year,
+	Index,
+	Local,
+	Utc,
+	Seq,
+}
+
+impl Default for FileNameTemplate {
+	fn default() -> Self {
+		"{year}-{index}-{local}".parse().unwrap()
+	}
+}
+
+impl std::str::FromStr for FileNameTemplate {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s.contains(['/', '\\']) {
+			return Err(format!(
+				"file name template: {s} can't have a directory in it"
+			));
+		}
+		let mut parts = Vec::new();
+		let mut rest = s;
+		while let Some((text, after_open)) = rest.split_once('{') {
+			let Some((placeholder, after_close)) = after_open.split_once('}') else {
+				return Err(format!("file name template: {s} has a {{ without a }}"));
+			};
+			if !text.is_empty() {
+				parts.push(NamePart::Text(text.into()));
+			}
+			parts.push(match placeholder {
+				"year" => NamePart::Year,
+				"index" => NamePart::Index,
+				"local" => NamePart::Local,
+				"utc" => NamePart::Utc,
+				"seq" => NamePart::Seq,
+				_ => {
+					return Err(format!(
+						"unknown placeholder in file name template: {{{placeholder}}}, expected {{year}}, {{index}}, {{local}}, {{utc}}, or {{seq}}"
+					));
 				}
+			});
+			rest = after_close;
+		}
+		if !rest.is_empty() {
+			parts.push(NamePart::Text(rest.into()));
+		}
+		if parts.is_empty() {
+			return Err("the file name template is empty".to_string());
+		}
+		Ok(Self(parts))
+	}
+}
+
+impl FileNameTemplate {
+	fn has_seq(&self) -> bool {
+		self.0.contains(&NamePart::Seq)
+	}
+
+	fn name(
+		&self,
+		battery_id: BatteryID,
+		now: chrono::DateTime<chrono::Local>,
+		seq: u32,
+		extension: &str,
+	) -> String {
+		use std::fmt::Write as _;
+		let mut name = String::new();
+		for part in &self.0 {
+			// writing to a String can't fail
+			let _ = match part {
+				NamePart::Text(text) => write!(name, "{text}"),
+				NamePart::Year => write!(name, "{}", battery_id.year),
+				NamePart::Index => write!(name, "{}", battery_id.index),
+				NamePart::Local => write!(name, "{}", now.format("%Y%m%d_%TUTC%Z")),
+				NamePart::Utc => write!(name, "{}", now.to_utc().format("%Y%m%dT%H%M%SZ")),
+				NamePart::Seq => write!(name, "{seq}"),
+			};
+		}
+		name.push('.');
+		name.push_str(extension);
+		name
+	}
+}
+
+/// Who ran a test and what they noted about it, e.g. the cell chemistry or lot number.
+/// Saved beside a file as `<test>.notes.toml`, or in the database's `notes` table.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct TestNotes {
+	pub battery_id: BatteryID,
+	/// Local time the test first started testing, `None` before it has
+	pub started: Option<Box<str>>,
+	pub operator: Option<Box<str>>,
+	pub notes: Vec<Note>,
+	/// Why the test ended on its own, e.g. it reached the maximum duration
+	#[serde(default)]
+	pub ended: Option<EndReason>,
+	/// Estimated from the load pulse at the start of the test
+	#[serde(default)]
+	pub internal_resistance_mohm: Option<u32>,
+	/// Open circuit voltage rested before the load was turned on
+	#[serde(default)]
+	pub ocv_before_mv: Option<u16>,
+	/// Open circuit voltage rested after the test ended
+	#[serde(default)]
+	pub ocv_after_mv: Option<u16>,
+	/// The battery interface the test was run on, see [`crate::DeviceVersion::hex_device_id`]
+	#[serde(default)]
+	pub device_id: Option<Box<str>>,
+	#[serde(default)]
+	pub firmware: Option<FirmwareVersion>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Note {
+	/// Local time the note was added
+	pub time: Box<str>,
+	pub text: Box<str>,
+}
+
+/// Where a new test is being saved
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SavedTo {
+	File(PathBuf),
+	/// Test id in the database
+	Database(i64),
+}
+
+impl std::fmt::Display for SavedTo {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			SavedTo::File(path) => write!(f, "created new file at: {path:?}"),
+			SavedTo::Database(test_id) => write!(f, "added test: {test_id} to the database"),
+		}
+	}
+}
+
+/// Where the file task saves test data
+pub struct Output {
+	/// New files for each test go here
+	pub output_dir: PathBuf,
+	/// A test moves here when `output_dir` can't be written, e.g. the disk is full
+	pub fallback_dir: Option<PathBuf>,
+	/// What the files have in them
+	pub columns: ColumnConfig,
+	/// Database for tests saved as [`OutputFormat::Sqlite`], from `--db`
+	pub db: Option<Connection>,
+	/// When samples are written to the file or database
+	pub flush: FlushPolicy,
+	/// When a test carries on in a new file, files only
+	pub rotation: Rotation,
+	/// What new files are called
+	pub file_name: FileNameTemplate,
+}
+
+impl Output {
+	/// Open or create the database and its tables, for [`Output::db`]
+	pub fn open_db(path: &Path) -> Result<Connection, Error> {
+		let db_error = |e| Error::Database(path.to_path_buf().into_boxed_path(), e);
+		let conn = Connection::open(path).map_err(db_error)?;
+		// every channel has its own connection, wait out another channel's write
+		conn.busy_timeout(DB_BUSY_TIMEOUT).map_err(db_error)?;
+		conn.execute_batch(SCHEMA).map_err(db_error)?;
+		Ok(conn)
+	}
+}
+
+/// A test that can't be written is moved to the fallback directory or kept in memory,
+/// once neither works the test is ended with [`Event::FileError`].
+/// The task only fails when the program task is gone.
+pub async fn file_task(
+	event_tx: Sender<Event>,
+	mut file_cmd_rx: Receiver<FileCmd>,
+	output: Output,
+	printer: Printer,
+) -> Result<(), TaskError> {
+	let flush = output.flush;
+	let mut sink = Sink::new(output, printer);
+	let timed = match flush {
+		FlushPolicy::Every(period) => Some(period),
+		_ => None,
+	};
+	// never ticks unless the policy is timed
+	let mut flush_timer = tokio::time::interval(timed.unwrap_or(Duration::from_secs(3600)));
+	loop {
+		let cmd = select! {
+			cmd = file_cmd_rx.recv() => match cmd {
+				Some(cmd) => cmd,
+				None => break,
 			},
-			FileCmd::NewFile(file) => match &mut persistance {
-				Some(p) => p.new_file(file).await,
-				None => {
-					persistance = Some(DataPersistance::new(file).await);
+			_ = flush_timer.tick(), if timed.is_some() => {
+				if let Some(kind) = sink.write_out().await {
+					event_tx.send(Event::FileError(kind)).await?
 				}
-			},
-			FileCmd::CloseFile => {
-				if let Some(mut dp) = persistance.take() {
-					dp.flush_reset().await;
+				continue;
+			}
+		};
+		match cmd {
+			FileCmd::Push(data) => {
+				if let Some(kind) = sink.push(data).await {
+					event_tx.send(Event::FileError(kind)).await?
 				}
 			}
+			FileCmd::Fault(fault) => sink.fault(fault).await,
+			FileCmd::Notes(notes) => sink.notes(&notes).await,
+			FileCmd::NewTest(battery_id, format, reply_tx) => {
+				// the program task waits for this
+				let _ = reply_tx.send(sink.new_test(battery_id, format).await);
+			}
+			FileCmd::Resume(saved_to, format, reply_tx) => {
+				let _ = reply_tx.send(sink.resume(saved_to, format).await);
+			}
+			FileCmd::CloseFile => sink.close().await,
 			FileCmd::Shutdown => {
-				if let Some(mut dp) = persistance.take() {
-					dp.flush_reset().await;
-				}
+				sink.close().await;
 				break;
 			}
 		}
 	}
 	println!("exiting file_task");
+	Ok(())
+}
+
+/// At most one of `persistance` or the database has a test open
+struct Sink {
+	printer: Printer,
+	output_dir: PathBuf,
+	fallback_dir: Option<PathBuf>,
+	columns: ColumnConfig,
+	flush: FlushPolicy,
+	rotation: Rotation,
+	file_name: FileNameTemplate,
+	persistance: Option<DataPersistance>,
+	/// Beside the open test's first file
+	notes_path: Option<PathBuf>,
+	db: Option<Database>,
+}
+
+impl Sink {
+	fn new(output: Output, printer: Printer) -> Self {
+		Self {
+			printer,
+			output_dir: output.output_dir,
+			fallback_dir: output.fallback_dir,
+			columns: output.columns,
+			flush: output.flush,
+			rotation: output.rotation,
+			file_name: output.file_name,
+			persistance: None,
+			notes_path: None,
+			db: output.db.map(|conn| Database {
+				conn,
+				test_id: None,
+				pending: Vec::new(),
+			}),
+		}
+	}
+
+	async fn new_test(
+		&mut self,
+		battery_id: BatteryID,
+		format: OutputFormat,
+	) -> Result<SavedTo, OutputError> {
+		self.close().await;
+		let Some(writer) = file_writer(format) else {
+			let db = self.db.as_mut().ok_or(OutputError::NoDatabase)?;
+			return Ok(SavedTo::Database(db.new_test(battery_id)?));
+		};
+		let extension = writer.extension();
+		let (file, path) =
+			match new_file(&self.file_name, battery_id, &self.output_dir, extension).await {
+				Ok(created) => created,
+				Err(e) => {
+					let Some(fallback_dir) = &self.fallback_dir else {
+						return Err(e.into());
+					};
+					self.printer
+						.warn(|tv| write!(tv, "can't create a file in the output directory:\n{e}"))
+						.await;
+					tokio::fs::create_dir_all(fallback_dir).await?;
+					new_file(&self.file_name, battery_id, fallback_dir, extension).await?
+				}
+			};
+		let columns = Columns::new(self.columns.clone());
+		self.persistance = Some(DataPersistance::new(file, path.clone(), writer, columns));
+		self.notes_path = Some(notes_path(&path));
+		Ok(SavedTo::File(path))
+	}
+
+	/// Carry on saving an interrupted test where it left off
+	async fn resume(
+		&mut self,
+		saved_to: SavedTo,
+		format: OutputFormat,
+	) -> Result<SavedTo, OutputError> {
+		self.close().await;
+		match (&saved_to, file_writer(format)) {
+			(SavedTo::File(path), Some(writer)) => {
+				self.notes_path = Some(notes_path(path));
+				// the journal has the test's first file
+				let path = last_part(path, self.fallback_dir.as_deref()).await;
+				let mut columns = Columns::new(self.columns.clone());
+				let saved = tokio::fs::read_to_string(&path).await?;
+				// a line cut off as the server stopped isn't a record
+				let records = &saved[..saved.rfind('\n').unwrap_or(0)];
+				let header = records.lines().next().unwrap_or_default();
+				let last = records.lines().last().unwrap_or_default();
+				if !columns.carry_on(|name| writer.value(header, last, name)) {
+					self.printer
+						.warn_stat("can't read the last saved totals, they start over from 0")
+						.await;
+				}
+				let file = OpenOptions::new().append(true).open(&path).await?;
+				let file_bytes = file.metadata().await?.len();
+				let mut dp = DataPersistance::reopen(file, path, writer, columns);
+				dp.file_bytes = file_bytes;
+				self.persistance = Some(dp);
+			}
+			(SavedTo::Database(test_id), None) => {
+				let db = self.db.as_mut().ok_or(OutputError::NoDatabase)?;
+				db.resume(*test_id)?;
+			}
+			// edited by hand or from another version
+			_ => return Err(OutputError::JournalMismatch(saved_to, format)),
+		}
+		Ok(saved_to)
+	}
+
+	/// Why the data was dropped, if it was
+	async fn push(&mut self, data: SaveData) -> Option<FileErrorKind> {
+		let buffered = if let Some(dp) = &mut self.persistance {
+			dp.new_data(&data)
+		} else if let Some(db) = self.db.as_mut().filter(|db| db.test_id.is_some()) {
+			db.pending.push(data);
+			db.pending.len()
+		} else {
+			self.printer
+				.warn_stat("No output file setup for battery data!")
+				.await;
+			return Some(FileErrorKind::NoTest);
+		};
+		let due = match self.flush {
+			FlushPolicy::Records(records) => buffered >= usize::from(records.get()),
+			FlushPolicy::Every(_) => false,
+			FlushPolicy::Immediate => true,
+		};
+		if due { self.write_out().await } else { None }
+	}
+
+	/// Write out the buffered samples, why they were dropped if they were
+	async fn write_out(&mut self) -> Option<FileErrorKind> {
+		if let Some(dp) = &mut self.persistance {
+			if dp.buffered_records() == 0 {
+				return None;
+			}
+			let written = match self.flush {
+				FlushPolicy::Immediate => dp.flush_sync().await,
+				_ => dp.flush_reset().await,
+			};
+			return match written {
+				Ok(()) => {
+					self.printer.stat("writing to outfile").await;
+					if self.rotation.due(dp) {
+						rotate(dp, &mut self.printer).await;
+					}
+					None
+				}
+				Err(e) => write_failed(dp, self.fallback_dir.as_deref(), e, &self.printer).await,
+			};
+		}
+		let db = self.db.as_mut().filter(|db| !db.pending.is_empty())?;
+		match db.flush() {
+			Ok(()) => None,
+			Err(e) => {
+				self.printer
+					.error(|tv| write!(tv, "can't write samples to the database:\n{e}"))
+					.await;
+				Some(FileErrorKind::Database)
+			}
+		}
+	}
+
+	/// Only the database keeps faults, the file columns are all measurements
+	async fn fault(&mut self, fault: Fault) {
+		if let Some(db) = &mut self.db
+			&& let Err(e) = db.fault(fault)
+		{
+			self.printer
+				.error(|tv| write!(tv, "can't write fault to the database:\n{e}"))
+				.await;
+		}
+	}
+
+	async fn notes(&mut self, notes: &TestNotes) {
+		if let Some(path) = &self.notes_path {
+			// only fails for types toml can't represent, none are used here
+			let text = toml::to_string_pretty(notes).unwrap();
+			if let Err(e) = tokio::fs::write(path, text).await {
+				self.printer
+					.error(|tv| write!(tv, "can't save the test's notes to: {path:?}\n{e}"))
+					.await;
+			}
+		} else if let Some(db) = &mut self.db
+			&& let Err(e) = db.notes(notes)
+		{
+			self.printer
+				.error(|tv| write!(tv, "can't save the test's notes to the database:\n{e}"))
+				.await;
+		}
+	}
+
+	async fn close(&mut self) {
+		self.notes_path = None;
+		if let Some(mut dp) = self.persistance.take() {
+			self.printer.stat("flushing out file buffer").await;
+			if let Err(e) = dp.flush_reset().await {
+				// there's no later to keep the end of the test for
+				let moved = match &self.fallback_dir {
+					Some(fallback_dir) if !dp.path().starts_with(fallback_dir) => {
+						move_to_fallback(&mut dp, fallback_dir).await
+					}
+					_ => Err(e),
+				};
+				match moved {
+					Ok(()) => {
+						let path = dp.path().to_path_buf();
+						self.printer
+							.warn(|tv| write!(tv, "saved the end of the test to: {path:?}"))
+							.await
+					}
+					Err(e) => {
+						let lost = dp.unwritten();
+						self.printer
+							.error(|tv| {
+								write!(
+									tv,
+									"can't write the end of the test, {lost} bytes are lost:\n{e}"
+								)
+							})
+							.await
+					}
+				}
+			}
+		}
+		if let Some(db) = &mut self.db
+			&& let Some(test_id) = db.test_id
+			&& let Err(e) = db.close()
+		{
+			self.printer
+				.error(|tv| write!(tv, "can't close test: {test_id} in the database:\n{e}"))
+				.await;
+		}
+	}
+}
+
+/// Move the test to the fallback directory, or keep its data in memory until there's too much.
+/// Why the data is being dropped, once it is.
+async fn write_failed(
+	dp: &mut DataPersistance,
+	fallback_dir: Option<&Path>,
+	e: std::io::Error,
+	printer: &Printer,
+) -> Option<FileErrorKind> {
+	let path = dp.path().to_path_buf();
+	printer
+		.warn(|tv| write!(tv, "can't write to: {path:?}\n{e}"))
+		.await;
+	if let Some(fallback_dir) = fallback_dir
+		&& !dp.path().starts_with(fallback_dir)
+	{
+		match move_to_fallback(dp, fallback_dir).await {
+			Ok(()) => {
+				let path = dp.path().to_path_buf();
+				printer
+					.warn(|tv| write!(tv, "saving the rest of the test to: {path:?}"))
+					.await;
+				return None;
+			}
+			Err(e) => {
+				printer
+					.warn(|tv| write!(tv, "can't save to the fallback directory either:\n{e}"))
+					.await
+			}
+		}
+	}
+	let unwritten = dp.unwritten();
+	if unwritten <= MAX_UNWRITTEN {
+		printer
+			.warn(|tv| {
+				write!(
+					tv,
+					"keeping {unwritten} bytes in memory until they can be written"
+				)
+			})
+			.await;
+		return None;
+	}
+	printer
+		.error_stat("too much of the test is waiting to be written, ending it")
+		.await;
+	Some(FileErrorKind::of(&e))
+}
+
+/// Carry on in the test's next file, or the same one if it can't be made
+async fn rotate(dp: &mut DataPersistance, printer: &mut Printer) {
+	let path = next_part(dp.path());
+	let file = match OpenOptions::new()
+		.append(true)
+		.create_new(true)
+		.open(&path)
+		.await
+	{
+		Ok(file) => file,
+		Err(e) => {
+			printer
+				.warn(|tv| write!(tv, "can't start the test's next file: {path:?}\n{e}"))
+				.await;
+			return;
+		}
+	};
+	// writing the header failing is handled with the next samples
+	let _ = dp.move_to(file, path.clone()).await;
+	printer
+		.buf(|tv| write!(tv, "continuing the test in: {path:?}"))
+		.await;
+}
+
+/// `test.tsv`'s notes are in `test.notes.toml`
+fn notes_path(path: &Path) -> PathBuf {
+	path.with_extension("notes.toml")
+}
+
+/// `test.tsv` is continued in `test-continued-2.tsv`, then `test-continued-3.tsv`
+fn next_part(path: &Path) -> PathBuf {
+	let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+	let (first, part) = stem
+		.rsplit_once(CONTINUED)
+		.and_then(|(first, part)| Some((first, part.parse::<u32>().ok()?)))
+		.unwrap_or((&stem, 1));
+	let mut name = format!("{first}{CONTINUED}{}", part + 1);
+	if let Some(extension) = path.extension() {
+		name.push('.');
+		name.push_str(&extension.to_string_lossy());
+	}
+	path.with_file_name(name)
+}
+
+/// The last file a test was continued in, `first` when it never was. A part moved to
+/// `fallback_dir` carries on there under the same name, so that copy is the later one.
+async fn last_part(first: &Path, fallback_dir: Option<&Path>) -> PathBuf {
+	let exists = |path: PathBuf| async move {
+		tokio::fs::try_exists(&path)
+			.await
+			.unwrap_or(false)
+			.then_some(path)
+	};
+	let mut last = first.to_path_buf();
+	let mut part = first.to_path_buf();
+	loop {
+		let moved = match (fallback_dir, part.file_name()) {
+			(Some(fallback_dir), Some(name)) => exists(fallback_dir.join(name)).await,
+			_ => None,
+		};
+		let found = match moved {
+			Some(moved) => moved,
+			None => match exists(part.clone()).await {
+				Some(found) => found,
+				None => return last,
+			},
+		};
+		last = found;
+		part = next_part(&part);
+	}
+}
+
+/// Carry on in a file with the same name in `fallback_dir`
+async fn move_to_fallback(dp: &mut DataPersistance, fallback_dir: &Path) -> std::io::Result<()> {
+	tokio::fs::create_dir_all(fallback_dir).await?;
+	let path = fallback_dir.join(dp.path().file_name().unwrap_or_default());
+	let file = OpenOptions::new()
+		.append(true)
+		.create_new(true)
+		.open(&path)
+		.await?;
+	dp.move_to(file, path).await
+}
+
+/// `None` for [`OutputFormat::Sqlite`], it isn't a file
+fn file_writer(format: OutputFormat) -> Option<Box<dyn RecordWriter + Send>> {
+	match format {
+		OutputFormat::Tsv => Some(Box::new(Tsv)),
+		OutputFormat::Csv => Some(Box::new(Csv)),
+		OutputFormat::JsonLines => Some(Box::new(JsonLines)),
+		OutputFormat::Sqlite => None,
+	}
+}
+
+async fn new_file(
+	file_name: &FileNameTemplate,
+	battery_id: BatteryID,
+	output_dir: &Path,
+	extension: &str,
+) -> tokio::io::Result<(File, PathBuf)> {
+	let now = chrono::Local::now();
+	let mut seq = 1;
+	loop {
+		let path = output_dir.join(file_name.name(battery_id, now, seq, extension));
+		let created = OpenOptions::new()
+			.write(true)
+			.read(true)
+			.append(true)
+			.create_new(true)
+			.open(&path)
+			.await;
+		match created {
+			Ok(file) => return Ok((file, path)),
+			Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && file_name.has_seq() => {
+				seq += 1
+			}
+			Err(e) => return Err(e),
+		}
+	}
+}
+
+/// Lays out the rows of one file format
+pub trait RecordWriter {
+	/// File extension, without the `.`
+	fn extension(&self) -> &'static str;
+	/// Written once at the start of the file, if the format has one
+	fn header(&self, out: &mut Vec<u8>, names: &[&'static str]);
+	/// One row, ending with a newline
+	fn record(&self, out: &mut Vec<u8>, names: &[&'static str], row: &[Value]);
+	/// A number from a `record` in a file starting with `header`, by its column's name
+	fn value(&self, header: &str, record: &str, name: &str) -> Option<f64>;
+}
+
+/// Tab separated, missing values are left empty
+pub struct Tsv;
+
+impl RecordWriter for Tsv {
+	fn extension(&self) -> &'static str {
+		"tsv"
+	}
+
+	fn header(&self, out: &mut Vec<u8>, names: &[&'static str]) {
+		writeln!(out, "{}", names.join("\t")).unwrap();
+	}
+
+	fn record(&self, out: &mut Vec<u8>, _names: &[&'static str], row: &[Value]) {
+		separated_record(out, row, '\t');
+	}
+
+	fn value(&self, header: &str, record: &str, name: &str) -> Option<f64> {
+		separated_value(header, record, name, '\t')
+	}
+}
+
+/// Comma separated, missing values are left empty
+pub struct Csv;
+
+impl RecordWriter for Csv {
+	fn extension(&self) -> &'static str {
+		"csv"
+	}
+
+	fn header(&self, out: &mut Vec<u8>, names: &[&'static str]) {
+		writeln!(out, "{}", names.join(",")).unwrap();
+	}
+
+	fn record(&self, out: &mut Vec<u8>, _names: &[&'static str], row: &[Value]) {
+		separated_record(out, row, ',');
+	}
+
+	fn value(&self, header: &str, record: &str, name: &str) -> Option<f64> {
+		separated_value(header, record, name, ',')
+	}
+}
+
+fn separated_record(out: &mut Vec<u8>, row: &[Value], sep: char) {
+	for (i, value) in row.iter().enumerate() {
+		if i > 0 {
+			write!(out, "{sep}").unwrap();
+		}
+		write!(out, "{value}").unwrap();
+	}
+	writeln!(out).unwrap();
+}
+
+fn separated_value(header: &str, record: &str, name: &str, sep: char) -> Option<f64> {
+	let column = header.split(sep).position(|column| column == name)?;
+	record.split(sep).nth(column)?.parse().ok()
+}
+
+/// A JSON object per line, missing values are `null`
+pub struct JsonLines;
+
+impl RecordWriter for JsonLines {
+	fn extension(&self) -> &'static str {
+		"jsonl"
+	}
+
+	fn header(&self, _out: &mut Vec<u8>, _names: &[&'static str]) {}
+
+	fn record(&self, out: &mut Vec<u8>, names: &[&'static str], row: &[Value]) {
+		write!(out, "{{").unwrap();
+		for (i, (name, value)) in names.iter().zip(row).enumerate() {
+			if i > 0 {
+				write!(out, ",").unwrap();
+			}
+			match value {
+				Value::Missing => write!(out, r#""{name}":null"#).unwrap(),
+				value => write!(out, r#""{name}":{value}"#).unwrap(),
+			}
+		}
+		writeln!(out, "}}").unwrap();
+	}
+
+	fn value(&self, _header: &str, record: &str, name: &str) -> Option<f64> {
+		let record: serde_json::Map<String, serde_json::Value> =
+			serde_json::from_str(record).ok()?;
+		record.get(name)?.as_f64()
+	}
+}
+
+/// rusqlite blocks, the writes are small and batched so it's done right on the file task
+struct Database {
+	conn: Connection,
+	test_id: Option<i64>,
+	/// Samples written in one transaction when the [`FlushPolicy`] says to
+	pending: Vec<SaveData>,
+}
+
+impl Database {
+	fn new_test(&mut self, battery_id: BatteryID) -> rusqlite::Result<i64> {
+		self.close()?;
+		self.conn.execute(
+			"INSERT INTO tests (battery_year, battery_index, created) VALUES (?1, ?2, ?3)",
+			params![
+				battery_id.year,
+				battery_id.index,
+				chrono::Local::now().to_rfc3339()
+			],
+		)?;
+		let test_id = self.conn.last_insert_rowid();
+		self.test_id = Some(test_id);
+		Ok(test_id)
+	}
+
+	fn resume(&mut self, test_id: i64) -> Result<(), OutputError> {
+		self.close()?;
+		let reopened = self.conn.execute(
+			"UPDATE tests SET closed = NULL WHERE id = ?1",
+			params![test_id],
+		)?;
+		if reopened == 0 {
+			return Err(OutputError::NoSuchTest(test_id));
+		}
+		self.test_id = Some(test_id);
+		Ok(())
+	}
+
+	fn flush(&mut self) -> rusqlite::Result<()> {
+		let Some(test_id) = self.test_id else {
+			self.pending.clear();
+			return Ok(());
+		};
+		let tx = self.conn.transaction()?;
+		{
+			let mut insert = tx.prepare_cached(
+				"INSERT INTO samples (test_id, dt, duration, millivolts, milliamps, temp_centi_c)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+			)?;
+			for data in self.pending.drain(..) {
+				insert.execute(params![
+					test_id,
+					data.sample_start_ms,
+					data.sample_duration_ms,
+					u16::from(data.millivolts),
+					i16::from(data.milliamps),
+					data.temp_centi_c
+				])?;
+			}
+		}
+		tx.commit()
+	}
+
+	fn fault(&mut self, fault: Fault) -> rusqlite::Result<()> {
+		let Some(test_id) = self.test_id else {
+			return Ok(());
+		};
+		self.conn.execute(
+			"INSERT INTO faults (test_id, time, kind) VALUES (?1, ?2, ?3)",
+			params![test_id, fault.time, format!("{:?}", fault.kind)],
+		)?;
+		Ok(())
+	}
+
+	/// Replaces the test's notes
+	fn notes(&mut self, notes: &TestNotes) -> rusqlite::Result<()> {
+		let Some(test_id) = self.test_id else {
+			return Ok(());
+		};
+		let tx = self.conn.transaction()?;
+		tx.execute("DELETE FROM notes WHERE test_id = ?1", params![test_id])?;
+		{
+			let mut insert = tx.prepare_cached(
+				"INSERT INTO notes (test_id, time, kind, text) VALUES (?1, ?2, ?3, ?4)",
+			)?;
+			let now = chrono::Local::now().to_rfc3339();
+			// the operator, pulse, and rest before are from when the test started
+			let started = notes.started.as_deref().unwrap_or(&now);
+			if let Some(operator) = &notes.operator {
+				insert.execute(params![test_id, started, "operator", operator])?;
+			}
+			if let Some(device_id) = &notes.device_id {
+				insert.execute(params![test_id, started, "device_id", device_id])?;
+			}
+			if let Some(firmware) = notes.firmware {
+				let text = firmware.to_string();
+				insert.execute(params![test_id, started, "firmware", text])?;
+			}
+			for note in &notes.notes {
+				insert.execute(params![test_id, note.time, "note", note.text])?;
+			}
+			if let Some(milliohms) = notes.internal_resistance_mohm {
+				let text = format!("{milliohms} mOhm");
+				insert.execute(params![test_id, started, "internal_resistance", text])?;
+			}
+			if let Some(millivolts) = notes.ocv_before_mv {
+				let text = format!("{millivolts} mV");
+				insert.execute(params![test_id, started, "ocv_before", text])?;
+			}
+			if let Some(millivolts) = notes.ocv_after_mv {
+				let text = format!("{millivolts} mV");
+				insert.execute(params![test_id, now, "ocv_after", text])?;
+			}
+			if let Some(reason) = notes.ended {
+				insert.execute(params![test_id, now, "ended", reason.to_string()])?;
+			}
+		}
+		tx.commit()
+	}
+
+	/// The test is done with even when closing it fails
+	fn close(&mut self) -> rusqlite::Result<()> {
+		let Some(test_id) = self.test_id else {
+			return Ok(());
+		};
+		let closed = self.flush().and_then(|()| {
+			self.conn.execute(
+				"UPDATE tests SET closed = ?1 WHERE id = ?2",
+				params![chrono::Local::now().to_rfc3339(), test_id],
+			)
+		});
+		self.test_id = None;
+		closed.map(|_| ())
+	}
 }
 
 pub struct DataPersistance {
+	/// Everything not written yet, it's kept when writing fails
 	out_buf: Vec<u8>,
-	buffered_records: u8,
+	buffered_records: usize,
 	out_file: File,
+	path: PathBuf,
+	/// Part of the test is in `out_file`, a file it moves to needs the header again
+	wrote_any: bool,
+	/// Size of `out_file`
+	file_bytes: u64,
+	/// When `out_file` was started
+	started: Instant,
+	writer: Box<dyn RecordWriter + Send>,
+	columns: Columns,
+	row: Vec<Value>,
 }
 
 impl DataPersistance {
-	pub async fn new(mut out_file: File) -> Self {
-		out_file.write_all(HEADER_NL).await.unwrap();
-		out_file.flush().await.unwrap();
+	/// The header is written with the first records
+	pub fn new(
+		out_file: File,
+		path: PathBuf,
+		writer: Box<dyn RecordWriter + Send>,
+		columns: Columns,
+	) -> Self {
+		let mut dp = Self::reopen(out_file, path, writer, columns);
+		dp.wrote_any = false;
+		dp.writer.header(&mut dp.out_buf, dp.columns.names());
+		dp
+	}
+
+	/// Append to a file that already has its header, `columns` already
+	/// carry on its running totals, see [`Columns::carry_on`]
+	pub fn reopen(
+		out_file: File,
+		path: PathBuf,
+		writer: Box<dyn RecordWriter + Send>,
+		columns: Columns,
+	) -> Self {
 		Self {
 			out_buf: Vec::with_capacity(512),
 			buffered_records: 0,
 			out_file,
+			path,
+			wrote_any: true,
+			file_bytes: 0,
+			started: Instant::now(),
+			writer,
+			row: Vec::with_capacity(columns.names().len()),
+			columns,
 		}
 	}
 
-	pub async fn new_file(&mut self, out_file: File) {
-		self.write_all().await;
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Bytes waiting to be written
+	pub fn unwritten(&self) -> usize {
+		self.out_buf.len()
+	}
+
+	/// Carry on saving the test to `out_file`, starting with what couldn't be written
+	pub async fn move_to(&mut self, out_file: File, path: PathBuf) -> tokio::io::Result<()> {
+		if self.wrote_any {
+			let mut out_buf = Vec::with_capacity(self.out_buf.len() + 128);
+			self.writer.header(&mut out_buf, self.columns.names());
+			out_buf.append(&mut self.out_buf);
+			self.out_buf = out_buf;
+		}
 		self.out_file = out_file;
-		Write::write(&mut self.out_buf, HEADER_NL).unwrap();
-		self.write_all().await;
+		self.path = path;
+		self.wrote_any = false;
+		self.file_bytes = 0;
+		self.started = Instant::now();
+		self.write_all().await
 	}
 
-	pub async fn flush_reset(&mut self) {
-		println!("flushing out file buffer");
+	/// Records added since the buffer was last written out
+	pub fn buffered_records(&self) -> usize {
+		self.buffered_records
+	}
+
+	pub async fn flush_reset(&mut self) -> tokio::io::Result<()> {
 		self.buffered_records = 0;
-		self.write_all().await;
+		self.write_all().await
+	}
+
+	/// Write out the buffer and wait for it to reach the disk
+	pub async fn flush_sync(&mut self) -> tokio::io::Result<()> {
+		self.flush_reset().await?;
+		self.out_file.sync_data().await
 	}
 
-	pub async fn new_data(&mut self, data: &SaveData) {
-		let mv = data.millivolts;
-		let ma = data.milliamps;
-		let dt = data.dt;
-		let duration = data.duration;
-		write!(&mut self.out_buf, "{dt}\t{duration}\t{mv}\t{ma}\n",).unwrap();
+	/// Buffer a record, the number of records buffered
+	pub fn new_data(&mut self, data: &SaveData) -> usize {
+		self.columns.row(data, &mut self.row);
+		self.writer
+			.record(&mut self.out_buf, self.columns.names(), &self.row);
 		self.buffered_records += 1;
-		if self.buffered_records == 10 {
-			self.buffered_records = 0;
-			self.write_all().await;
-			println!("writing to outfile");
+		self.buffered_records
+	}
+
+	/// What was written is taken off the buffer, the rest is tried again next time
+	async fn write_all(&mut self) -> tokio::io::Result<()> {
+		while !self.out_buf.is_empty() {
+			let written = self.out_file.write(&self.out_buf).await?;
+			if written == 0 {
+				return Err(std::io::ErrorKind::WriteZero.into());
+			}
+			// tokio writes in the background, a failure only shows up once it's flushed
+			self.out_file.flush().await?;
+			self.out_buf.drain(..written);
+			self.wrote_any = true;
+			self.file_bytes += written as u64;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{Print, columns::Column};
+	use battery_tester_common::{FaultKind, MilliAmp, MilliVolt, MilliWatt};
+	use std::num::NonZeroU32;
+	use tokio::sync::mpsc;
+
+	const BATTERY: BatteryID = BatteryID {
+		year: 2025,
+		index: 3,
+	};
+
+	/// An empty directory for each test
+	fn test_dir(test: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!(
+			"battery-tester-files-{test}-{}",
+			std::process::id()
+		));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	/// TSV files in `dir`, named and written out the default way
+	fn output(dir: &Path) -> Output {
+		Output {
+			output_dir: dir.to_path_buf(),
+			fallback_dir: None,
+			columns: ColumnConfig::default(),
+			db: None,
+			flush: FlushPolicy::default(),
+			rotation: Rotation::default(),
+			file_name: FileNameTemplate::default(),
 		}
 	}
 
-	async fn write_all(&mut self) {
-		self.out_file.write_all(&self.out_buf).await.unwrap();
-		self.out_file.flush().await.unwrap();
-		self.out_buf.clear();
+	fn sink(output: Output) -> Sink {
+		let (print_tx, mut print_rx) = mpsc::channel::<Print>(64);
+		tokio::spawn(async move { while print_rx.recv().await.is_some() {} });
+		Sink::new(output, Printer::new(print_tx))
+	}
+
+	/// A second a sample at 2 A, losing 10 mV each
+	fn sample(index: u32) -> SaveData {
+		let millivolts = 12_000 - 10 * index as u16;
+		SaveData {
+			millivolts: MilliVolt::new(millivolts),
+			milliamps: MilliAmp::new(2_000),
+			milliwatts: MilliWatt::new(u32::from(millivolts) * 2),
+			load: None,
+			sample_index: index,
+			sample_start_ms: u64::from(index) * 1_000,
+			sample_duration_ms: 900,
+			temp_centi_c: Some(2_500),
+			load_temp_centi_c: None,
+		}
+	}
+
+	#[test]
+	fn test_record_writers() {
+		let names = ["sample_index", "volts", "temp_c"];
+		let row = [Value::Int(7), Value::Fixed(11.98, 3), Value::Missing];
+		let written = |writer: &dyn RecordWriter| {
+			let mut out = Vec::new();
+			writer.header(&mut out, &names);
+			writer.record(&mut out, &names, &row);
+			String::from_utf8(out).unwrap()
+		};
+		assert_eq!(written(&Tsv), "sample_index\tvolts\ttemp_c\n7\t11.980\t\n");
+		assert_eq!(written(&Csv), "sample_index,volts,temp_c\n7,11.980,\n");
+		// every line stands on its own, there's no header
+		assert_eq!(
+			written(&JsonLines),
+			"{\"sample_index\":7,\"volts\":11.980,\"temp_c\":null}\n"
+		);
+	}
+
+	#[tokio::test]
+	async fn test_saved_in_each_format() {
+		let dir = test_dir("formats");
+		let mut sink = sink(output(&dir));
+		for (format, extension) in [
+			(OutputFormat::Tsv, "tsv"),
+			(OutputFormat::Csv, "csv"),
+			(OutputFormat::JsonLines, "jsonl"),
+		] {
+			let Ok(SavedTo::File(path)) = sink.new_test(BATTERY, format).await else {
+				panic!("no {format:?} file");
+			};
+			assert_eq!(path.extension().unwrap(), extension);
+			sink.push(sample(0)).await;
+			sink.push(sample(1)).await;
+			sink.close().await;
+			let text = std::fs::read_to_string(&path).unwrap();
+			let lines: Vec<&str> = text.lines().collect();
+			match format {
+				OutputFormat::JsonLines => {
+					assert_eq!(lines.len(), 2);
+					let last: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+					assert_eq!(last["millivolts"], 11_990);
+					assert_eq!(last["temp_centi_c"], 2_500);
+					assert!(last["load_millivolts"].is_null());
+				}
+				_ => {
+					let sep = if format == OutputFormat::Csv {
+						','
+					} else {
+						'\t'
+					};
+					assert_eq!(lines.len(), 3);
+					assert!(lines[0].starts_with(&format!(
+						"sample_index{sep}sample_start_ms{sep}sample_duration_ms{sep}millivolts"
+					)));
+					assert!(lines[2].starts_with(&format!("1{sep}1000{sep}900{sep}11990{sep}")));
+				}
+			}
+		}
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_resume_carries_on_the_totals() {
+		let dir = test_dir("resume-totals");
+		let columns = ColumnConfig {
+			columns: vec![Column::Mah, Column::Wh, Column::Soc],
+			capacity_mah: NonZeroU32::new(2_500),
+			..ColumnConfig::default()
+		};
+		let output = |dir: &Path| Output {
+			columns: columns.clone(),
+			..output(dir)
+		};
+		for format in [
+			OutputFormat::Tsv,
+			OutputFormat::Csv,
+			OutputFormat::JsonLines,
+		] {
+			let whole_dir = dir.join(format!("{format:?}-whole"));
+			let resumed_dir = dir.join(format!("{format:?}-resumed"));
+			std::fs::create_dir_all(&whole_dir).unwrap();
+			std::fs::create_dir_all(&resumed_dir).unwrap();
+			let mut whole = sink(output(&whole_dir));
+			let Ok(SavedTo::File(whole_path)) = whole.new_test(BATTERY, format).await else {
+				panic!("no {format:?} file");
+			};
+			for index in 0..20 {
+				whole.push(sample(index)).await;
+			}
+			whole.close().await;
+
+			let mut interrupted = sink(output(&resumed_dir));
+			let saved_to = interrupted.new_test(BATTERY, format).await.unwrap();
+			for index in 0..10 {
+				interrupted.push(sample(index)).await;
+			}
+			interrupted.close().await;
+			// a new server picks it up from the journal
+			let mut resumed = sink(output(&resumed_dir));
+			let SavedTo::File(resumed_path) = resumed.resume(saved_to, format).await.unwrap()
+			else {
+				panic!("{format:?} test wasn't resumed to a file");
+			};
+			for index in 10..20 {
+				resumed.push(sample(index)).await;
+			}
+			resumed.close().await;
+
+			// carried on from what was saved, so off by at most one in its last decimal place
+			let whole = std::fs::read_to_string(whole_path).unwrap();
+			let resumed = std::fs::read_to_string(resumed_path).unwrap();
+			assert_eq!(resumed.lines().count(), whole.lines().count(), "{format:?}");
+			let writer = file_writer(format).unwrap();
+			let header = whole.lines().next().unwrap();
+			for (whole, resumed) in whole.lines().zip(resumed.lines()).skip(1) {
+				for name in ["milliamp_hours", "watt_hours", "soc_percent"] {
+					let total = |record| writer.value(header, record, name).unwrap();
+					let error = (total(resumed) - total(whole)).abs();
+					assert!(error < 0.0011, "{format:?} {name}: {resumed} isn't {whole}");
+				}
+			}
+		}
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn test_flush_policy_parse() {
+		let records = |n| FlushPolicy::Records(NonZeroU16::new(n).unwrap());
+		assert_eq!("10".parse(), Ok(records(10)));
+		assert_eq!("5s".parse(), Ok(FlushPolicy::Every(Duration::from_secs(5))));
+		assert_eq!("sync".parse(), Ok(FlushPolicy::Immediate));
+		for bad in ["0", "0s", "5m", "-1", ""] {
+			assert!(bad.parse::<FlushPolicy>().is_err(), "{bad}");
+		}
+	}
+
+	#[tokio::test]
+	async fn test_flush_policy() {
+		let dir = test_dir("flush");
+		let lines = |path: &Path| std::fs::read_to_string(path).unwrap().lines().count();
+		for (flush, written) in [
+			// nothing until the third, then the header with all three
+			(FlushPolicy::Records(NonZeroU16::new(3).unwrap()), [0, 0, 4]),
+			(FlushPolicy::Immediate, [2, 3, 4]),
+			// only when the timer calls for it
+			(FlushPolicy::Every(Duration::from_secs(5)), [0, 0, 0]),
+		] {
+			let mut sink = sink(Output {
+				flush,
+				..output(&dir)
+			});
+			let Ok(SavedTo::File(path)) = sink.new_test(BATTERY, OutputFormat::Tsv).await else {
+				panic!("no file");
+			};
+			for (index, written) in written.into_iter().enumerate() {
+				assert_eq!(sink.push(sample(index as u32)).await, None);
+				assert_eq!(lines(&path), written, "{flush:?} sample {index}");
+			}
+			assert_eq!(sink.write_out().await, None);
+			assert_eq!(lines(&path), 4, "{flush:?}");
+			sink.close().await;
+			std::fs::remove_file(path).unwrap();
+		}
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	/// Swaps the open file for a read-only one, so writing to it fails like a full disk would
+	async fn fail_writes(sink: &mut Sink) {
+		let dp = sink.persistance.as_mut().unwrap();
+		dp.out_file = File::open(&dp.path).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_write_failure_moved_to_fallback() {
+		let dir = test_dir("fallback");
+		let fallback_dir = dir.join("fallback");
+		let mut sink = sink(Output {
+			output_dir: dir.join("output"),
+			fallback_dir: Some(fallback_dir.clone()),
+			flush: FlushPolicy::Records(NonZeroU16::new(1).unwrap()),
+			..output(&dir)
+		});
+		std::fs::create_dir(dir.join("output")).unwrap();
+		let Ok(SavedTo::File(path)) = sink.new_test(BATTERY, OutputFormat::Tsv).await else {
+			panic!("no file");
+		};
+		assert_eq!(sink.push(sample(0)).await, None);
+		fail_writes(&mut sink).await;
+		assert_eq!(sink.push(sample(1)).await, None);
+		assert_eq!(sink.push(sample(2)).await, None);
+		sink.close().await;
+
+		let first = std::fs::read_to_string(&path).unwrap();
+		assert_eq!(first.lines().count(), 2);
+		// the rest of the test has its own header
+		let rest = std::fs::read_to_string(fallback_dir.join(path.file_name().unwrap())).unwrap();
+		let lines: Vec<&str> = rest.lines().collect();
+		assert_eq!(lines.len(), 3);
+		assert_eq!(lines[0], first.lines().next().unwrap());
+		assert!(lines[1].starts_with("1\t"));
+		assert!(lines[2].starts_with("2\t"));
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_resume_after_a_move_to_fallback() {
+		let dir = test_dir("fallback-resume");
+		let fallback_dir = dir.join("fallback");
+		let fallback_output = || Output {
+			output_dir: dir.join("output"),
+			fallback_dir: Some(fallback_dir.clone()),
+			flush: FlushPolicy::Records(NonZeroU16::new(1).unwrap()),
+			..output(&dir)
+		};
+		std::fs::create_dir(dir.join("output")).unwrap();
+		let mut interrupted = sink(fallback_output());
+		let saved_to = interrupted
+			.new_test(BATTERY, OutputFormat::Tsv)
+			.await
+			.unwrap();
+		let SavedTo::File(path) = saved_to.clone() else {
+			panic!("{saved_to:?}");
+		};
+		interrupted.push(sample(0)).await;
+		fail_writes(&mut interrupted).await;
+		interrupted.push(sample(1)).await;
+		interrupted.close().await;
+		let moved = fallback_dir.join(path.file_name().unwrap());
+		assert_eq!(last_part(&path, Some(&fallback_dir)).await, moved);
+
+		// the journal still has the first file, the test carries on where it was moved to
+		let mut resumed = sink(fallback_output());
+		assert_eq!(
+			resumed
+				.resume(saved_to.clone(), OutputFormat::Tsv)
+				.await
+				.unwrap(),
+			saved_to
+		);
+		resumed.push(sample(2)).await;
+		resumed.close().await;
+		assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+		let rest = std::fs::read_to_string(&moved).unwrap();
+		let lines: Vec<&str> = rest.lines().collect();
+		assert_eq!(lines.len(), 3);
+		assert!(lines[1].starts_with("1\t"));
+		assert!(lines[2].starts_with("2\t"));
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_write_failure_kept_in_memory() {
+		let dir = test_dir("unwritten");
+		let mut sink = sink(Output {
+			flush: FlushPolicy::Records(NonZeroU16::new(1).unwrap()),
+			..output(&dir)
+		});
+		let Ok(SavedTo::File(path)) = sink.new_test(BATTERY, OutputFormat::Tsv).await else {
+			panic!("no file");
+		};
+		assert_eq!(sink.push(sample(0)).await, None);
+		fail_writes(&mut sink).await;
+		assert_eq!(sink.push(sample(1)).await, None);
+		assert!(sink.persistance.as_ref().unwrap().unwritten() > 0);
+
+		// once the file can be written again nothing was lost
+		sink.persistance.as_mut().unwrap().out_file =
+			OpenOptions::new().append(true).open(&path).await.unwrap();
+		assert_eq!(sink.push(sample(2)).await, None);
+		assert_eq!(sink.persistance.as_ref().unwrap().unwritten(), 0);
+		let text = std::fs::read_to_string(&path).unwrap();
+		assert_eq!(text.lines().count(), 4);
+
+		// until too much is waiting
+		fail_writes(&mut sink).await;
+		let mut index = 3;
+		let dropped = loop {
+			if let Some(kind) = sink.push(sample(index % 1_000)).await {
+				break kind;
+			}
+			index += 1;
+		};
+		assert_eq!(dropped, FileErrorKind::Write);
+		assert!(sink.persistance.as_ref().unwrap().unwritten() > MAX_UNWRITTEN);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn test_next_part() {
+		let next = |path: &str| next_part(Path::new(path));
+		assert_eq!(
+			next("out/2025-3.tsv"),
+			Path::new("out/2025-3-continued-2.tsv")
+		);
+		assert_eq!(
+			next("out/2025-3-continued-2.tsv"),
+			Path::new("out/2025-3-continued-3.tsv")
+		);
+		assert_eq!(
+			next("out/run-continued-x.csv"),
+			Path::new("out/run-continued-x-continued-2.csv")
+		);
+		assert_eq!(next("out/2025-3"), Path::new("out/2025-3-continued-2"));
+	}
+
+	#[tokio::test]
+	async fn test_rotated_by_size() {
+		let dir = test_dir("rotation");
+		// every write out fills the file
+		let mut sink = sink(Output {
+			flush: FlushPolicy::Records(NonZeroU16::new(1).unwrap()),
+			rotation: Rotation {
+				every: None,
+				max_bytes: Some(1),
+			},
+			..output(&dir)
+		});
+		let saved_to = sink.new_test(BATTERY, OutputFormat::Tsv).await.unwrap();
+		let SavedTo::File(path) = saved_to.clone() else {
+			panic!("{saved_to:?}");
+		};
+		sink.push(sample(0)).await;
+		sink.push(sample(1)).await;
+		sink.close().await;
+
+		let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+		let first = read(&path);
+		let header = first.lines().next().unwrap();
+		let second = read(&next_part(&path));
+		assert_eq!(second.lines().collect::<Vec<_>>()[0], header);
+		assert!(second.lines().nth(1).unwrap().starts_with("1\t"));
+		let third = next_part(&next_part(&path));
+		assert_eq!(read(&third).lines().collect::<Vec<_>>(), [header]);
+		assert_eq!(last_part(&path, None).await, third);
+
+		// an interrupted test carries on in its last file
+		assert_eq!(
+			sink.resume(saved_to.clone(), OutputFormat::Tsv)
+				.await
+				.unwrap(),
+			saved_to
+		);
+		sink.rotation = Rotation::default();
+		sink.push(sample(2)).await;
+		sink.close().await;
+		let lines: Vec<String> = read(&third).lines().map(String::from).collect();
+		assert_eq!(lines.len(), 2);
+		assert!(lines[1].starts_with("2\t"));
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn test_file_name_template() {
+		let now = chrono::DateTime::parse_from_rfc3339("2025-01-31T16:45:02+01:00")
+			.unwrap()
+			.with_timezone(&chrono::Local);
+		let name = |template: &str, seq| {
+			template
+				.parse::<FileNameTemplate>()
+				.unwrap()
+				.name(BATTERY, now, seq, "tsv")
+		};
+		assert_eq!(
+			name("bench_{year}-{index}_{utc}_{seq}", 2),
+			"bench_2025-3_20250131T154502Z_2.tsv"
+		);
+		assert_eq!(name("cells", 1), "cells.tsv");
+		assert!(name("{local}", 1).starts_with(&now.format("%Y%m%d_").to_string()));
+		for bad in ["", "out/{year}", "{year", "{month}"] {
+			assert!(bad.parse::<FileNameTemplate>().is_err(), "{bad}");
+		}
+	}
+
+	#[tokio::test]
+	async fn test_file_name_seq() {
+		let dir = test_dir("file-name");
+		let mut numbered = sink(Output {
+			file_name: "{year}-{index}_{seq}".parse().unwrap(),
+			..output(&dir)
+		});
+		for seq in 1..=3 {
+			let saved_to = numbered.new_test(BATTERY, OutputFormat::Csv).await.unwrap();
+			assert_eq!(
+				saved_to,
+				SavedTo::File(dir.join(format!("2025-3_{seq}.csv")))
+			);
+		}
+		numbered.close().await;
+
+		// without {seq} a name that's taken isn't overwritten
+		let mut fixed = sink(Output {
+			file_name: "{year}-{index}_1".parse().unwrap(),
+			..output(&dir)
+		});
+		let taken = fixed.new_test(BATTERY, OutputFormat::Csv).await;
+		assert!(
+			matches!(taken, Err(OutputError::File(e)) if e.kind() == std::io::ErrorKind::AlreadyExists)
+		);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_database() {
+		let dir = test_dir("database");
+		let db_path = dir.join("results.db");
+		let mut sink = sink(Output {
+			db: Some(Output::open_db(&db_path).unwrap()),
+			flush: FlushPolicy::Records(NonZeroU16::new(2).unwrap()),
+			..output(&dir)
+		});
+		let test_id = match sink.new_test(BATTERY, OutputFormat::Sqlite).await {
+			Ok(SavedTo::Database(test_id)) => test_id,
+			saved => panic!("{saved:?}"),
+		};
+		for index in 0..3 {
+			assert_eq!(sink.push(sample(index)).await, None);
+		}
+		let fault = Fault {
+			kind: FaultKind::Overcurrent,
+			time: 2_500,
+		};
+		sink.fault(fault).await;
+		let notes = TestNotes {
+			battery_id: BATTERY,
+			started: Some("2025-06-01T12:00:00+02:00".into()),
+			operator: Some("lab".into()),
+			notes: Vec::new(),
+			ended: None,
+			internal_resistance_mohm: Some(42),
+			ocv_before_mv: None,
+			ocv_after_mv: None,
+			device_id: None,
+			firmware: None,
+		};
+		sink.notes(&notes).await;
+		sink.close().await;
+
+		// read back like another program would
+		let conn = Connection::open(&db_path).unwrap();
+		let (year, index, closed): (u16, u8, Option<String>) = conn
+			.query_row(
+				"SELECT battery_year, battery_index, closed FROM tests WHERE id = ?1",
+				params![test_id],
+				|row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+			)
+			.unwrap();
+		assert_eq!((year, index), (2025, 3));
+		assert!(closed.is_some());
+		let mut samples = conn
+			.prepare(
+				"SELECT dt, millivolts, temp_centi_c FROM samples WHERE test_id = ?1 ORDER BY dt",
+			)
+			.unwrap();
+		let samples: Vec<(u64, u16, Option<i16>)> = samples
+			.query_map(params![test_id], |row| {
+				Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+			})
+			.unwrap()
+			.collect::<Result<_, _>>()
+			.unwrap();
+		// the last one was written when the test was closed
+		assert_eq!(
+			samples,
+			[
+				(0, 12_000, Some(2_500)),
+				(1_000, 11_990, Some(2_500)),
+				(2_000, 11_980, Some(2_500))
+			]
+		);
+		let fault_kind: String = conn
+			.query_row(
+				"SELECT kind FROM faults WHERE test_id = ?1",
+				params![test_id],
+				|row| row.get(0),
+			)
+			.unwrap();
+		assert_eq!(fault_kind, "Overcurrent");
+		let mut saved_notes = conn
+			.prepare("SELECT kind, text FROM notes WHERE test_id = ?1 ORDER BY kind")
+			.unwrap();
+		let saved_notes: Vec<(String, String)> = saved_notes
+			.query_map(params![test_id], |row| Ok((row.get(0)?, row.get(1)?)))
+			.unwrap()
+			.collect::<Result<_, _>>()
+			.unwrap();
+		assert_eq!(
+			saved_notes,
+			[
+				("internal_resistance".into(), "42 mOhm".into()),
+				("operator".into(), "lab".into())
+			]
+		);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_database_resume() {
+		let dir = test_dir("database-resume");
+		let db_path = dir.join("results.db");
+		let mut sink = sink(Output {
+			db: Some(Output::open_db(&db_path).unwrap()),
+			..output(&dir)
+		});
+		let saved_to = sink.new_test(BATTERY, OutputFormat::Sqlite).await.unwrap();
+		sink.push(sample(0)).await;
+		sink.close().await;
+		assert_eq!(
+			sink.resume(saved_to.clone(), OutputFormat::Sqlite)
+				.await
+				.unwrap(),
+			saved_to
+		);
+		sink.push(sample(1)).await;
+		sink.close().await;
+		let SavedTo::Database(test_id) = saved_to else {
+			unreachable!()
+		};
+		let conn = Connection::open(&db_path).unwrap();
+		let samples: u32 = conn
+			.query_row(
+				"SELECT COUNT(*) FROM samples WHERE test_id = ?1",
+				params![test_id],
+				|row| row.get(0),
+			)
+			.unwrap();
+		assert_eq!(samples, 2);
+
+		let missing = sink
+			.resume(SavedTo::Database(test_id + 1), OutputFormat::Sqlite)
+			.await;
+		assert!(matches!(missing, Err(OutputError::NoSuchTest(id)) if id == test_id + 1));
+
+		// a journal that doesn't add up is an error, not a crash
+		let mismatched = sink.resume(saved_to, OutputFormat::Tsv).await;
+		assert!(matches!(
+			mismatched,
+			Err(OutputError::JournalMismatch(
+				SavedTo::Database(_),
+				OutputFormat::Tsv
+			))
+		));
+		let file = SavedTo::File(dir.join("test.tsv"));
+		let mismatched = sink.resume(file, OutputFormat::Sqlite).await;
+		assert!(matches!(
+			mismatched,
+			Err(OutputError::JournalMismatch(
+				SavedTo::File(_),
+				OutputFormat::Sqlite
+			))
+		));
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_database_needs_db() {
+		let dir = test_dir("no-database");
+		let mut sink = sink(output(&dir));
+		let res = sink.new_test(BATTERY, OutputFormat::Sqlite).await;
+		assert!(matches!(res, Err(OutputError::NoDatabase)));
+		assert_eq!(sink.push(sample(0)).await, Some(FileErrorKind::NoTest));
+		std::fs::remove_dir_all(dir).unwrap();
 	}
 }
@@ -1,90 +1,904 @@
 use battery_tester_common::AllowUndercurrent;
+use bytes::BytesMut;
+use std::fs::TryLockError;
 use std::io::Write;
-use tipsy::{Connection, Endpoint, ServerId};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tipsy::{Endpoint, IntoIpcPath, SecurityAttributes, ServerId};
 use tokio::{
 	io::AsyncReadExt,
+	net::{TcpListener, TcpStream},
 	select,
 	sync::{mpsc::Sender, oneshot::Receiver},
+	task::JoinSet,
+	time::{Duration, timeout},
 };
 
 use futures::{pin_mut, stream::StreamExt};
 
-use crate::{Event, Printer, SERVER_NAME, ServerCmd};
+use crate::{
+	Ack, BatteryID, ChannelEvent, ChannelId, ChannelStatus, CurrentMode, EnvelopeHead, Error,
+	Event, IPC_VERSION, IpcStream, LastMeasurement, Printer, Reply, Request, SERVER_NAME,
+	ServerCmd, StatusWatch, TaskError,
+	calibration::{CalibrationChange, CalibrationStore},
+	registry::BatteryRegistry,
+	serial, write_ipc, write_reply,
+};
+
+/// Requests are a few bytes, anything this big is a client that isn't ours
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+/// How long a client has to send its request once it's connected, or the rest of one once it's
+/// started, its connection is then dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Connections waiting on their request or its answer at once, more are dropped.
+/// Each is answered on a task of its own so one that stalls doesn't hold up the others.
+const MAX_PENDING_CONNS: usize = 64;
+
+/// Files kept by the server for every channel
+#[derive(Debug, Clone)]
+pub struct Stores {
+	pub registry: Arc<BatteryRegistry>,
+	pub calibrations: Arc<CalibrationStore>,
+}
+
+/// Who on the server's machine can connect to its endpoint, TCP clients aren't affected
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum IpcAccess {
+	/// The user running the server
+	#[default]
+	Owner,
+	/// Its group too, on Windows only the owner
+	Group,
+	Everyone,
+}
+
+impl IpcAccess {
+	fn security_attributes(self) -> std::io::Result<SecurityAttributes> {
+		match self {
+			IpcAccess::Owner => Ok(SecurityAttributes::empty()),
+			// the mode is ignored on Windows, where empty is the owner's
+			IpcAccess::Group => SecurityAttributes::empty().mode(0o660),
+			IpcAccess::Everyone => SecurityAttributes::allow_everyone_connect(),
+		}
+	}
+}
+
+impl std::str::FromStr for IpcAccess {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"owner" => Ok(Self::Owner),
+			"group" => Ok(Self::Group),
+			"everyone" => Ok(Self::Everyone),
+			_ => Err(format!(
+				"unknown access: {s}, expected owner, group, or everyone"
+			)),
+		}
+	}
+}
+
+/// How clients reach the server and what they need to command it
+#[derive(Debug, Clone)]
+pub struct IpcSettings {
+	/// See [`server_id`]
+	pub name: Option<Box<str>>,
+	pub access: IpcAccess,
+	/// Commands that change anything are refused without it, see [`Request::token`]
+	pub token: Option<Box<str>>,
+}
+
+/// The shared secret in `path`, surrounding whitespace like the newline an editor adds is left off
+pub fn read_token(path: &Path) -> Result<Box<str>, Error> {
+	let token = std::fs::read_to_string(path)
+		.map_err(|e| Error::TokenRead(path.to_path_buf().into_boxed_path(), e))?;
+	match token.trim() {
+		"" => Err(Error::EmptyToken(path.to_path_buf().into_boxed_path())),
+		token => Ok(token.into()),
+	}
+}
 
+/// Compared in the same time however much of `given` matches, so it can't be guessed a byte at a time
+pub(crate) fn token_matches(expected: Option<&str>, given: Option<&str>) -> bool {
+	let Some(expected) = expected else {
+		return true;
+	};
+	let Some(given) = given else {
+		return false;
+	};
+	expected.len() == given.len()
+		&& expected
+			.bytes()
+			.zip(given.bytes())
+			.fold(0, |diff, (a, b)| diff | (a ^ b))
+			== 0
+}
+
+/// What's left to do with a connection once a request is answered
+enum Answered {
+	Done,
+	/// Send readings from this channel until the client goes away
+	Subscribe(StatusWatch, u32),
+	/// Keep answering requests until the client goes away
+	Session,
+}
+
+/// One client's request, a bad one is printed and dropped.
+/// Fails only when the supervisor is gone.
 async fn for_each_conn(
-	conn_res: Result<Connection, std::io::Error>,
-	event_tx: &Sender<Event>,
+	conn_res: Result<impl IpcStream + 'static, std::io::Error>,
+	event_tx: &Sender<ChannelEvent>,
+	channels: &[StatusWatch],
+	stores: &Stores,
+	token: Option<&str>,
 	mut printer: Printer,
-) {
-	const STATIC_BUF_SIZE: usize = 512;
+) -> Result<(), TaskError> {
 	match conn_res {
 		Ok(mut stream) => {
-			let cmd: ServerCmd = {
-				let to_read = match stream.read_u32().await.map(|n| n as usize) {
-					Ok(r) => r,
-					Err(e) => {
-						printer.buf(|tv| write!(tv, "bad command: {e:?}")).await;
-						return;
-					}
-				};
-				if to_read > STATIC_BUF_SIZE {
-					let mut buf = Vec::with_capacity(to_read);
-					let _ = stream.read_to_end(&mut buf).await.unwrap();
-					postcard::from_bytes(&buf[..to_read]).unwrap()
-				} else {
-					let mut stat_buf = [0u8; STATIC_BUF_SIZE];
-					let mut buf = &mut stat_buf[..to_read];
-					let _ = stream.read_exact(&mut buf).await.unwrap();
-					postcard::from_bytes(&buf).unwrap()
+			let request = match timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+				.await
+				.unwrap_or_else(|_elapsed| Err(timed_out()))
+			{
+				Ok(Ok(request)) => request,
+				Ok(Err(unreadable)) => {
+					reject(&mut stream, unreadable, &printer).await;
+					return Ok(());
 				}
-			};
-			match cmd {
-				ServerCmd::SetBatteryId(battery_id) => event_tx.send(Event::BattID(battery_id)),
-				ServerCmd::SetSerialDev(dev) => event_tx.send(Event::SetSerialDevice(dev)),
-				ServerCmd::SetCutoffMillis(millivolts) => {
-					event_tx.send(Event::SetCutoff(millivolts))
+				Err(e) => {
+					printer.warn(|tv| write!(tv, "bad command: {e:?}")).await;
+					return Ok(());
 				}
-				ServerCmd::StartTest => event_tx.send(Event::StartTest),
-				ServerCmd::CancelTest => event_tx.send(Event::CancelTest),
-				ServerCmd::ShutDown => event_tx.send(Event::Shutdown),
-				ServerCmd::ClearFault => event_tx.send(Event::ClearFault),
-				ServerCmd::AllowUndercurrent => {
-					event_tx.send(Event::UnderCurrentResponse(AllowUndercurrent::Yes))
+			};
+			match answer(
+				&mut stream,
+				request,
+				event_tx,
+				channels,
+				stores,
+				token,
+				&mut printer,
+			)
+			.await?
+			{
+				Answered::Done => {}
+				// both run as long as the client is there, don't hold up other clients
+				Answered::Subscribe(status, id) => {
+					tokio::spawn(subscribe(stream, status, id, printer));
 				}
-				ServerCmd::DisallowUndercurrent => {
-					event_tx.send(Event::UnderCurrentResponse(AllowUndercurrent::No))
+				Answered::Session => {
+					tokio::spawn(session(
+						stream,
+						event_tx.clone(),
+						channels.to_vec(),
+						stores.clone(),
+						token.map(Box::from),
+						printer,
+					));
 				}
 			}
-			.await
-			.unwrap();
 		}
 		Err(e) => {
 			printer
-				.buf(|tv| write!(tv, "Error receiving connection: {:?}", e))
+				.warn(|tv| write!(tv, "Error receiving connection: {:?}", e))
 				.await
 		}
 	}
+	Ok(())
+}
+
+/// Answer requests one after the other on a client's connection, e.g. `battery-tester-client repl`
+async fn session(
+	mut stream: impl IpcStream,
+	event_tx: Sender<ChannelEvent>,
+	channels: Vec<StatusWatch>,
+	stores: Stores,
+	token: Option<Box<str>>,
+	mut printer: Printer,
+) {
+	loop {
+		let request = match read_request(&mut stream).await {
+			Ok(Ok(request)) => request,
+			Ok(Err(unreadable)) => {
+				reject(&mut stream, unreadable, &printer).await;
+				break;
+			}
+			// closed between requests
+			Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+			Err(e) => {
+				printer.warn(|tv| write!(tv, "bad command: {e:?}")).await;
+				break;
+			}
+		};
+		match answer(
+			&mut stream,
+			request,
+			&event_tx,
+			&channels,
+			&stores,
+			token.as_deref(),
+			&mut printer,
+		)
+		.await
+		{
+			Ok(Answered::Done | Answered::Session) => {}
+			Ok(Answered::Subscribe(status, id)) => {
+				subscribe(stream, status, id, printer).await;
+				break;
+			}
+			// the server is shutting down
+			Err(_) => break,
+		}
+	}
+}
+
+/// Reply to a request, or pass it on to its channel's program task
+async fn answer(
+	stream: &mut impl IpcStream,
+	request: Request,
+	event_tx: &Sender<ChannelEvent>,
+	channels: &[StatusWatch],
+	stores: &Stores,
+	token: Option<&str>,
+	printer: &mut Printer,
+) -> Result<Answered, TaskError> {
+	let Request {
+		version: _,
+		id,
+		channel: selected,
+		cmd,
+		token: given,
+	} = request;
+	let channel = selected.unwrap_or(0);
+	if cmd.acked() && !token_matches(token, given.as_deref()) {
+		printer
+			.warn_stat("refused a command without the server's token")
+			.await;
+		let error =
+			Some("wrong or missing token, give the client the server's --token-file".into());
+		ack(stream, id, Ack { channel, error }, printer).await;
+		return Ok(Answered::Done);
+	}
+	let Some(status) = channels.get(usize::from(channel)) else {
+		let msg = format!(
+			"no channel {channel}, the server has {} channel(s)",
+			channels.len()
+		);
+		printer.buf(|tv| write!(tv, "{msg}")).await;
+		if cmd.acked() {
+			let error = Some(msg.into_boxed_str());
+			ack(stream, id, Ack { channel, error }, printer).await;
+		}
+		return Ok(Answered::Done);
+	};
+	let event = match cmd {
+		ServerCmd::SetBatteryId(battery_id) => Event::BattID(battery_id),
+		ServerCmd::SetSerialDev(dev, baud) => Event::SetSerialDevice(dev, baud),
+		ServerCmd::SetCutoffMillis(millivolts) => Event::SetCutoff(millivolts),
+		ServerCmd::SetDisconnectMillis(millivolts) => Event::SetDisconnect(millivolts),
+		ServerCmd::SetMaxVoltageMillis(millivolts) => Event::SetMaxVoltage(millivolts),
+		ServerCmd::SetChemistry(chemistry) => Event::SetChemistry(chemistry),
+		ServerCmd::SetMaxDuration(max) => Event::SetMaxDuration(max),
+		ServerCmd::SetMaxCapacity(max) => Event::SetMaxCapacity(max),
+		ServerCmd::SetLoadModel(model) => Event::SetLoadModel(model),
+		ServerCmd::SetCurrentSetpoint(setpoint) => Event::SetCurrentSetpoint(setpoint),
+		ServerCmd::SetWindowSamples(samples) => Event::SetWindowSamples(samples),
+		ServerCmd::SetFilter(filter) => Event::SetFilter(filter),
+		ServerCmd::Queue(change) => Event::Queue(change),
+		ServerCmd::StartAt(at) => Event::ScheduleStart(Some(at)),
+		// from when the server got it, the client's clock may be off
+		ServerCmd::StartAfter(after) => {
+			Event::ScheduleStart(Some(std::time::SystemTime::now() + after))
+		}
+		ServerCmd::UnscheduleStart => Event::ScheduleStart(None),
+		ServerCmd::SetOutputFormat(format) => Event::SetOutputFormat(format),
+		ServerCmd::SetNote(text) => Event::SetNote(text),
+		ServerCmd::SetOperator(name) => Event::SetOperator(name),
+		ServerCmd::StartTest => Event::StartTest,
+		ServerCmd::Charge => Event::Charge,
+		ServerCmd::StartAutonomous => Event::StartAutonomous,
+		ServerCmd::FetchAutonomousLog => Event::FetchAutonomousLog,
+		ServerCmd::CancelTest => Event::CancelTest,
+		// the supervisor shuts every channel down
+		ServerCmd::ShutDown => Event::Shutdown,
+		ServerCmd::ClearFault => Event::ClearFault,
+		ServerCmd::AllowUndercurrent => Event::UnderCurrentResponse(AllowUndercurrent::Yes),
+		ServerCmd::DisallowUndercurrent => Event::UnderCurrentResponse(AllowUndercurrent::No),
+		ServerCmd::Status => {
+			let shown = match selected {
+				Some(channel) => channel..=channel,
+				None => 0..=(channels.len() - 1) as ChannelId,
+			};
+			let reports: Vec<ChannelStatus> = shown
+				.map(|channel| channels[usize::from(channel)].status(channel))
+				.collect();
+			let buf = BytesMut::with_capacity(256 * reports.len());
+			if let Err(e) = write_reply(buf, stream, id, &reports).await {
+				printer
+					.warn(|tv| write!(tv, "can't send status: {e:?}"))
+					.await;
+			}
+			return Ok(Answered::Done);
+		}
+		ServerCmd::GetCapabilities => {
+			let buf = BytesMut::with_capacity(256);
+			if let Err(e) = write_reply(buf, stream, id, &status.capabilities()).await {
+				printer
+					.warn(|tv| write!(tv, "can't send capabilities: {e:?}"))
+					.await;
+			}
+			return Ok(Answered::Done);
+		}
+		ServerCmd::GetReading => {
+			let buf = BytesMut::with_capacity(64);
+			if let Err(e) = write_reply(buf, stream, id, &status.reading()).await {
+				printer
+					.warn(|tv| write!(tv, "can't send reading: {e:?}"))
+					.await;
+			}
+			return Ok(Answered::Done);
+		}
+		ServerCmd::GetLastMeasurement => {
+			let reply = LastMeasurement {
+				channel,
+				measurement: *status.measurement.borrow(),
+			};
+			let buf = BytesMut::with_capacity(64);
+			if let Err(e) = write_reply(buf, stream, id, &reply).await {
+				printer
+					.warn(|tv| write!(tv, "can't send measurement: {e:?}"))
+					.await;
+			}
+			return Ok(Answered::Done);
+		}
+		ServerCmd::GetMode => {
+			let reply = CurrentMode {
+				channel,
+				mode: status.server.borrow().mode,
+			};
+			let buf = BytesMut::with_capacity(16);
+			if let Err(e) = write_reply(buf, stream, id, &reply).await {
+				printer
+					.warn(|tv| write!(tv, "can't send mode: {e:?}"))
+					.await;
+			}
+			return Ok(Answered::Done);
+		}
+		ServerCmd::AddBattery(battery) => {
+			let BatteryID { year, index } = battery.id;
+			let error = match stores.registry.add(battery) {
+				Ok(()) => {
+					printer
+						.buf(|tv| write!(tv, "added battery {year}-{index} to the registry"))
+						.await;
+					None
+				}
+				Err(e) => {
+					printer
+						.error(|tv| write!(tv, "can't save the battery registry:\n{e}"))
+						.await;
+					Some(format!("can't save the battery registry: {e}").into_boxed_str())
+				}
+			};
+			ack(stream, id, Ack { channel, error }, printer).await;
+			return Ok(Answered::Done);
+		}
+		ServerCmd::ListBatteries => {
+			let buf = BytesMut::with_capacity(512);
+			if let Err(e) = write_reply(buf, stream, id, &stores.registry.list()).await {
+				printer
+					.warn(|tv| write!(tv, "can't send the battery registry: {e:?}"))
+					.await;
+			}
+			return Ok(Answered::Done);
+		}
+		ServerCmd::ListSerialPorts => {
+			// the client can't tell no ports from the OS not listing them otherwise
+			let ports = serial::list_ports().map_err(|e| e.to_string().into_boxed_str());
+			let buf = BytesMut::with_capacity(512);
+			if let Err(e) = write_reply(buf, stream, id, &ports).await {
+				printer
+					.warn(|tv| write!(tv, "can't send the serial ports: {e:?}"))
+					.await;
+			}
+			return Ok(Answered::Done);
+		}
+		ServerCmd::SetTrim(change) => {
+			// unset parts keep what the battery interface has, it reports it as it connects
+			let reported = *status.trim.borrow();
+			let Some(trim) = reported else {
+				let error = Some("the battery interface hasn't reported its trim yet".into());
+				ack(stream, id, Ack { channel, error }, printer).await;
+				return Ok(Answered::Done);
+			};
+			Event::SetTrim(change.apply(trim))
+		}
+		ServerCmd::SetBatteryDetect(change) => {
+			let reported = *status.battery_detect.borrow();
+			let Some(detect) = reported else {
+				let error =
+					Some("the battery interface hasn't reported its battery detection yet".into());
+				ack(stream, id, Ack { channel, error }, printer).await;
+				return Ok(Answered::Done);
+			};
+			Event::SetBatteryDetect(change.apply(detect))
+		}
+		ServerCmd::SelfTest => Event::RunSelfTest,
+		ServerCmd::Calibrate(change) => {
+			let device_id = status
+				.server
+				.borrow()
+				.device_version
+				.map(|v| v.hex_device_id());
+			let error = match device_id {
+				Some(device_id) => {
+					calibrate(&stores.calibrations, &device_id, change, status, printer).await
+				}
+				None => Some(
+					"the battery interface hasn't said which it is yet, calibrate it once it's connected"
+						.into(),
+				),
+			};
+			ack(stream, id, Ack { channel, error }, printer).await;
+			return Ok(Answered::Done);
+		}
+		ServerCmd::GetCalibration => {
+			let device_id = status
+				.server
+				.borrow()
+				.device_version
+				.map(|v| v.hex_device_id());
+			let calibration = device_id.and_then(|device_id| stores.calibrations.get(&device_id));
+			let buf = BytesMut::with_capacity(128);
+			if let Err(e) = write_reply(buf, stream, id, &calibration).await {
+				printer
+					.warn(|tv| write!(tv, "can't send the calibration: {e:?}"))
+					.await;
+			}
+			return Ok(Answered::Done);
+		}
+		ServerCmd::SubscribeReadings => return Ok(Answered::Subscribe(status.clone(), id)),
+		ServerCmd::Session => {
+			ack(
+				stream,
+				id,
+				Ack {
+					channel,
+					error: None,
+				},
+				printer,
+			)
+			.await;
+			return Ok(Answered::Session);
+		}
+	};
+	event_tx.send(ChannelEvent { channel, event }).await?;
+	ack(
+		stream,
+		id,
+		Ack {
+			channel,
+			error: None,
+		},
+		printer,
+	)
+	.await;
+	Ok(Answered::Done)
+}
+
+/// Change the calibration of the battery interface with `device_id`, the error for the client
+/// if it can't be
+async fn calibrate(
+	calibrations: &CalibrationStore,
+	device_id: &str,
+	change: CalibrationChange,
+	status: &StatusWatch,
+	printer: &mut Printer,
+) -> Option<Box<str>> {
+	match change {
+		CalibrationChange::Point {
+			millivolts: None,
+			milliamps: None,
+		} => Some("give the reference meter's millivolts, milliamps, or both".into()),
+		CalibrationChange::Point {
+			millivolts,
+			milliamps,
+		} => {
+			// the measurement watch has the readings before any calibration
+			let Some(raw) = *status.measurement.borrow() else {
+				return Some("no measurement yet to calibrate against".into());
+			};
+			let points = calibrations.add_point(device_id, raw, millivolts, milliamps);
+			printer
+				.buf(|tv| {
+					write!(
+						tv,
+						"calibration point {points} for device ID: {device_id}: measured {} mV {} mA",
+						raw.vbat, raw.ibat
+					)
+				})
+				.await;
+			None
+		}
+		CalibrationChange::Save => match calibrations.save(device_id) {
+			Ok(Some(calibration)) => {
+				printer
+					.buf(|tv| {
+						write!(
+							tv,
+							"saved calibration for device ID: {device_id}: mV x {:.5} + {:.1}, mA x {:.5} + {:.1}",
+							calibration.millivolts.scale,
+							calibration.millivolts.offset,
+							calibration.milliamps.scale,
+							calibration.milliamps.offset
+						)
+					})
+					.await;
+				None
+			}
+			Ok(None) => Some(
+				format!(
+					"no calibration points for device ID: {device_id}, take them with: calibrate point"
+				)
+				.into_boxed_str(),
+			),
+			Err(e) => {
+				printer
+					.error(|tv| write!(tv, "can't save the calibrations:\n{e}"))
+					.await;
+				Some(format!("can't save the calibrations: {e}").into_boxed_str())
+			}
+		},
+		CalibrationChange::Clear => match calibrations.clear(device_id) {
+			Ok(()) => {
+				printer
+					.buf(|tv| write!(tv, "cleared calibration for device ID: {device_id}"))
+					.await;
+				None
+			}
+			Err(e) => {
+				printer
+					.error(|tv| write!(tv, "can't save the calibrations:\n{e}"))
+					.await;
+				Some(format!("can't save the calibrations: {e}").into_boxed_str())
+			}
+		},
+	}
+}
+
+/// A request that was read but can't be answered, the client is told why
+struct Unreadable {
+	/// `0` when not even the [`EnvelopeHead`] could be read, see [`Reply::id`]
+	id: u32,
+	reason: Box<str>,
 }
 
+/// Read a length prefixed request, small ones without allocating.
+/// Waits as long as it takes for the request to start, then [`REQUEST_TIMEOUT`] for the rest.
+async fn read_request(stream: &mut impl IpcStream) -> std::io::Result<Result<Request, Unreadable>> {
+	const STATIC_BUF_SIZE: usize = 512;
+	/// Longest [`EnvelopeHead`], both are varints
+	const MAX_HEAD_SIZE: usize = 3 + 5;
+	let to_read = stream.read_u32().await? as usize;
+	if to_read > MAX_REQUEST_SIZE {
+		// only the head is read, to answer with the request's id, so the connection can't be
+		// used for another
+		let mut head = [0u8; MAX_HEAD_SIZE];
+		let id = match timeout(REQUEST_TIMEOUT, stream.read_exact(&mut head)).await {
+			Ok(Ok(_)) => postcard::from_bytes::<EnvelopeHead>(&head).map_or(0, |head| head.id),
+			Ok(Err(_)) | Err(_) => 0,
+		};
+		return Ok(Err(Unreadable {
+			id,
+			reason: format!("request is {to_read} bytes, they can be {MAX_REQUEST_SIZE} at most")
+				.into(),
+		}));
+	}
+	let body = async {
+		if to_read > STATIC_BUF_SIZE {
+			let mut buf = vec![0u8; to_read];
+			stream.read_exact(&mut buf).await?;
+			Ok(parse_request(&buf))
+		} else {
+			let mut stat_buf = [0u8; STATIC_BUF_SIZE];
+			let buf = &mut stat_buf[..to_read];
+			stream.read_exact(buf).await?;
+			Ok(parse_request(buf))
+		}
+	};
+	timeout(REQUEST_TIMEOUT, body)
+		.await
+		.unwrap_or_else(|_elapsed| Err(timed_out()))
+}
+
+fn timed_out() -> std::io::Error {
+	std::io::Error::new(
+		std::io::ErrorKind::TimedOut,
+		format!("no request within {} s", REQUEST_TIMEOUT.as_secs()),
+	)
+}
+
+/// The version is checked first, the rest of a request from another version may not be readable
+fn parse_request(buf: &[u8]) -> Result<Request, Unreadable> {
+	let head: EnvelopeHead = postcard::from_bytes(buf).map_err(|e| Unreadable {
+		id: 0,
+		reason: format!("not a request: {e}").into(),
+	})?;
+	if head.version != IPC_VERSION {
+		return Err(Unreadable {
+			id: head.id,
+			reason: format!(
+				"the client speaks IPC version {} and this server {IPC_VERSION}, update the older one",
+				head.version
+			)
+			.into(),
+		});
+	}
+	postcard::from_bytes(buf).map_err(|e| Unreadable {
+		id: head.id,
+		reason: format!("{e}").into(),
+	})
+}
+
+/// Tell the client its request couldn't be read, with a [`Reply`] any version can read
+async fn reject(stream: &mut impl IpcStream, unreadable: Unreadable, printer: &Printer) {
+	let Unreadable { id, reason } = unreadable;
+	printer
+		.warn(|tv| write!(tv, "can't read a request: {reason}"))
+		.await;
+	let reply = Reply::<()> {
+		version: IPC_VERSION,
+		id,
+		body: Err(reason),
+	};
+	if let Err(e) = write_ipc(BytesMut::with_capacity(128), stream, &reply).await {
+		printer
+			.warn(|tv| write!(tv, "can't send rejection: {e:?}"))
+			.await;
+	}
+}
+
+/// Send a reading now and after every change until the client hangs up or the server shuts down.
+/// A client that hung up is only noticed on the next change.
+async fn subscribe(mut stream: impl IpcStream, mut status: StatusWatch, id: u32, printer: Printer) {
+	let mut buf = BytesMut::with_capacity(64);
+	loop {
+		status.server.mark_unchanged();
+		status.live.mark_unchanged();
+		buf = match write_reply(buf, &mut stream, id, &status.reading()).await {
+			Ok(buf) => buf,
+			Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => break,
+			Err(e) => {
+				printer
+					.warn(|tv| write!(tv, "can't send reading: {e:?}"))
+					.await;
+				break;
+			}
+		};
+		let changed = select! {
+			changed = status.server.changed() => changed,
+			changed = status.live.changed() => changed,
+		};
+		if changed.is_err() {
+			break;
+		}
+	}
+}
+
+/// Tell the client whether its command was taken
+async fn ack(stream: &mut impl IpcStream, id: u32, ack: Ack, printer: &Printer) {
+	let buf = BytesMut::with_capacity(64);
+	if let Err(e) = write_reply(buf, stream, id, &ack).await {
+		printer.warn(|tv| write!(tv, "can't send ack: {e:?}")).await;
+	}
+}
+
+/// IPC endpoint of the server called `name`, `None` for a server started without a `--name`
+pub fn server_id(name: Option<&str>) -> ServerId<String> {
+	match name {
+		Some(name) => ServerId::new(format!("{SERVER_NAME}.{name}")),
+		None => ServerId::new(SERVER_NAME.to_string()),
+	}
+}
+
+/// Held for as long as the server called `name` runs, so a second one can't take its endpoint
+/// and split control of its battery interfaces. The OS lets go of it however the server exits,
+/// the file with the last holder's pid is left behind.
+#[derive(Debug)]
+pub struct InstanceLock {
+	_file: std::fs::File,
+}
+
+impl InstanceLock {
+	/// [`Error::AlreadyRunning`] while another server called `name` holds it
+	pub fn acquire(name: Option<&str>) -> Result<Self, Error> {
+		use std::io::{Read, Seek};
+		let path = Self::path(name);
+		let lock_err = |e| Error::InstanceLock(path.clone().into_boxed_path(), e);
+		let mut file = std::fs::OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(&path)
+			.map_err(lock_err)?;
+		match file.try_lock() {
+			Ok(()) => {}
+			Err(TryLockError::WouldBlock) => {
+				let mut pid = String::new();
+				let _ = file.read_to_string(&mut pid);
+				let pid = match pid.trim() {
+					"" => "unknown",
+					pid => pid,
+				};
+				return Err(Error::AlreadyRunning(path.into_boxed_path(), pid.into()));
+			}
+			Err(TryLockError::Error(e)) => return Err(lock_err(e)),
+		}
+		let written = file
+			.set_len(0)
+			.and_then(|()| file.rewind())
+			.and_then(|()| write!(file, "{}", std::process::id()));
+		written.map_err(lock_err)?;
+		Ok(Self { _file: file })
+	}
+
+	/// Next to the socket, named pipes aren't files so on Windows it's in the temp directory
+	fn path(name: Option<&str>) -> PathBuf {
+		let file_name = match name {
+			Some(name) => format!("{SERVER_NAME}.{name}.lock"),
+			None => format!("{SERVER_NAME}.lock"),
+		};
+		#[cfg(unix)]
+		if let Some(dir) = server_id(name)
+			.into_ipc_path()
+			.ok()
+			.as_deref()
+			.and_then(Path::parent)
+		{
+			return dir.join(file_name);
+		}
+		std::env::temp_dir().join(file_name)
+	}
+}
+
+/// Names can't have path separators or anything else a socket or pipe name can't
+pub fn valid_server_name(name: &str) -> bool {
+	!name.is_empty()
+		&& name
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Names of the servers with an endpoint on this machine, `None` for the unnamed one.
+/// A server that was killed can leave its endpoint behind, connect to see if it's running.
+pub fn server_names() -> std::io::Result<Vec<Option<String>>> {
+	let unnamed = server_id(None).into_ipc_path()?;
+	// sockets and named pipes alike are files in a directory
+	let Some(dir) = unnamed.parent() else {
+		return Ok(Vec::new());
+	};
+	let mut names = Vec::new();
+	for entry in std::fs::read_dir(dir)? {
+		let file_name = entry?.file_name();
+		let Some(file_name) = file_name.to_str() else {
+			continue;
+		};
+		#[cfg(unix)]
+		let Some(file_name) = file_name.strip_suffix(".sock") else {
+			continue;
+		};
+		if file_name == SERVER_NAME {
+			names.push(None);
+		} else if let Some(name) = file_name
+			.strip_prefix(SERVER_NAME)
+			.and_then(|rest| rest.strip_prefix('.'))
+		{
+			names.push(Some(name.to_string()));
+		}
+	}
+	names.sort();
+	Ok(names)
+}
+
+/// Answer a client on a task of its own, unless too many are waiting already
+async fn spawn_conn(
+	conns: &mut JoinSet<Result<(), TaskError>>,
+	conn_res: Result<impl IpcStream + 'static, std::io::Error>,
+	event_tx: &Sender<ChannelEvent>,
+	channels: &[StatusWatch],
+	stores: &Stores,
+	token: Option<&str>,
+	printer: &Printer,
+) {
+	if conns.len() >= MAX_PENDING_CONNS {
+		printer
+			.warn_stat("dropped a client, too many are waiting on an answer")
+			.await;
+		return;
+	}
+	let event_tx = event_tx.clone();
+	let channels = channels.to_vec();
+	let stores = stores.clone();
+	let token: Option<Box<str>> = token.map(Box::from);
+	let printer = printer.clone();
+	conns.spawn(async move {
+		for_each_conn(
+			conn_res,
+			&event_tx,
+			&channels,
+			&stores,
+			token.as_deref(),
+			printer,
+		)
+		.await
+	});
+}
+
+/// Next client from the network, never comes without a listener
+async fn accept_tcp(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+	match listener {
+		Some(listener) => listener.accept().await,
+		None => std::future::pending().await,
+	}
+}
+
+/// `channels` has each channel's status, in channel order.
+/// Listens on [`server_id`]`(settings.name)`, and on `listener` when there is one.
 pub async fn ipc_task(
-	event_tx: Sender<Event>,
+	settings: IpcSettings,
+	listener: Option<TcpListener>,
+	event_tx: Sender<ChannelEvent>,
+	channels: Vec<StatusWatch>,
+	stores: Stores,
 	printer: Printer,
 	mut ipc_shutdown_rx: Receiver<()>,
-) -> Result<(), std::io::Error> {
-	let id = ServerId::new(SERVER_NAME);
-	let incoming_stream = Endpoint::new(id, tipsy::OnConflict::Overwrite)?.incoming()?;
-	// .for_each(|conn_res| for_each_conn(conn_res, &event_tx, &print_tx));
+) -> Result<(), TaskError> {
+	let IpcSettings {
+		name,
+		access,
+		token,
+	} = settings;
+	let id = server_id(name.as_deref());
+	// the instance lock is held, so nothing is listening on one that's already there
+	#[cfg(unix)]
+	if id.clone().into_ipc_path().is_ok_and(|path| path.exists()) {
+		printer
+			.warn_stat("removing the endpoint left by a server that didn't shut down")
+			.await;
+	}
+	let incoming_stream = Endpoint::new(id, tipsy::OnConflict::Overwrite)
+		.and_then(|endpoint| {
+			let attributes = access.security_attributes()?;
+			endpoint.security_attributes(attributes).incoming()
+		})
+		.map_err(TaskError::Ipc)?;
 	pin_mut!(incoming_stream);
+	let mut conns = JoinSet::new();
 	loop {
 		select! {
 			conn_op = incoming_stream.next() => {
 				match conn_op {
 					Some(conn_res) => {
-						for_each_conn(conn_res, &event_tx, printer.clone()).await
+						spawn_conn(&mut conns, conn_res, &event_tx, &channels, &stores, token.as_deref(), &printer).await
 					}
 					None => break,
 				}
 			}
+			accepted = accept_tcp(listener.as_ref()) => {
+				let conn_res = accepted.and_then(|(stream, _addr)| {
+					// commands and readings are a few bytes each, don't hold them back
+					stream.set_nodelay(true)?;
+					Ok(stream)
+				});
+				spawn_conn(&mut conns, conn_res, &event_tx, &channels, &stores, token.as_deref(), &printer).await
+			}
+			Some(answered) = conns.join_next() => match answered {
+				Ok(Ok(())) => {}
+				// the supervisor is gone
+				Ok(Err(e)) => return Err(e),
+				Err(e) => printer.warn(|tv| write!(tv, "client's task failed: {e}")).await,
+			},
 			_ = &mut ipc_shutdown_rx => {
 				break;
 			}
@@ -93,3 +907,423 @@ pub async fn ipc_task(
 	println!("exiting ipc_task");
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		CurrentMode, DeviceVersion, LastMeasurement, LinkStats, Mode, Print, ReplyError,
+		ServerStatus, read_reply,
+	};
+	use battery_tester_common::{
+		FirmwareVersion, Measurement, MilliAmp, MilliVolt, MilliWatt, PROTOCOL_VERSION,
+	};
+	use tokio::{
+		io::{AsyncWriteExt, DuplexStream, duplex},
+		sync::{mpsc, watch},
+	};
+
+	/// The server's end of a connection, answered like one accepted by [`ipc_task`], with no
+	/// channels to pass requests on to
+	fn serve_one(server: DuplexStream) -> tokio::task::JoinHandle<Result<(), TaskError>> {
+		let (print_tx, mut print_rx) = mpsc::channel::<Print>(8);
+		tokio::spawn(async move { while print_rx.recv().await.is_some() {} });
+		serve_with(server, Vec::new(), Printer::new(print_tx))
+	}
+
+	fn serve_with(
+		server: DuplexStream,
+		channels: Vec<StatusWatch>,
+		printer: Printer,
+	) -> tokio::task::JoinHandle<Result<(), TaskError>> {
+		tokio::spawn(async move {
+			let (event_tx, _event_rx) = mpsc::channel(8);
+			for_each_conn(Ok(server), &event_tx, &channels, &stores(), None, printer).await
+		})
+	}
+
+	/// Empty, neither file is there
+	fn stores() -> Stores {
+		Stores {
+			registry: Arc::new(BatteryRegistry::load(PathBuf::from("no-such-registry")).unwrap()),
+			calibrations: Arc::new(
+				CalibrationStore::load(PathBuf::from("no-such-calibrations")).unwrap(),
+			),
+		}
+	}
+
+	/// Send a request on a connection of its own, like the client does, and read its reply
+	async fn ask<T: serde::de::DeserializeOwned>(
+		channels: &[StatusWatch],
+		channel: Option<ChannelId>,
+		cmd: ServerCmd,
+	) -> T {
+		let (print_tx, mut print_rx) = mpsc::channel::<Print>(8);
+		tokio::spawn(async move { while print_rx.recv().await.is_some() {} });
+		let (mut client, server) = duplex(4096);
+		let served = serve_with(server, channels.to_vec(), Printer::new(print_tx));
+		let request = Request::new(channel, cmd, None);
+		write_ipc(BytesMut::new(), &mut client, &request)
+			.await
+			.unwrap();
+		let reply = read_reply(&mut client, request.id).await.unwrap();
+		served.await.unwrap().unwrap();
+		reply
+	}
+
+	#[test]
+	fn test_instance_lock() {
+		let name = format!("test-lock-{}", std::process::id());
+		assert!(valid_server_name(&name));
+		let lock = InstanceLock::acquire(Some(&name)).unwrap();
+		match InstanceLock::acquire(Some(&name)) {
+			Err(Error::AlreadyRunning(path, pid)) => {
+				assert_eq!(*path, *InstanceLock::path(Some(&name)));
+				assert_eq!(*pid, *std::process::id().to_string());
+			}
+			second => panic!("{second:?}"),
+		}
+		// another name is another server
+		let other = format!("{name}-2");
+		drop(InstanceLock::acquire(Some(&other)).unwrap());
+		// free once the server's gone, the file is left behind
+		drop(lock);
+		drop(InstanceLock::acquire(Some(&name)).unwrap());
+		for name in [name, other] {
+			std::fs::remove_file(InstanceLock::path(Some(&name))).unwrap();
+		}
+	}
+
+	#[tokio::test]
+	async fn test_stalled_client_doesnt_hold_up_others() {
+		let name = format!("test-stall-{}", std::process::id());
+		let (print_tx, mut print_rx) = mpsc::channel::<Print>(8);
+		tokio::spawn(async move { while print_rx.recv().await.is_some() {} });
+		let (event_tx, _event_rx) = mpsc::channel(8);
+		let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let settings = IpcSettings {
+			name: Some(name.clone().into()),
+			access: IpcAccess::Owner,
+			token: None,
+		};
+		let channels = vec![StatusWatch::fixed(
+			ServerStatus::default(),
+			LinkStats::default(),
+		)];
+		let server = tokio::spawn(ipc_task(
+			settings,
+			Some(listener),
+			event_tx,
+			channels,
+			stores(),
+			Printer::new(print_tx),
+			shutdown_rx,
+		));
+		// a request's length, then nothing
+		let mut stalled = TcpStream::connect(addr).await.unwrap();
+		stalled.write_u32(100).await.unwrap();
+		tokio::time::sleep(Duration::from_millis(100)).await;
+
+		let mut local = Endpoint::connect(server_id(Some(&name))).await.unwrap();
+		let request = Request::new(None, ServerCmd::GetMode, None);
+		write_ipc(BytesMut::new(), &mut local, &request)
+			.await
+			.unwrap();
+		// well before the stalled one times out
+		let mode: CurrentMode = timeout(Duration::from_secs(1), read_reply(&mut local, request.id))
+			.await
+			.expect("held up by the stalled client")
+			.unwrap();
+		assert_eq!(mode.mode, Mode::Setup);
+
+		shutdown_tx.send(()).unwrap();
+		server.await.unwrap().unwrap();
+	}
+
+	/// A channel that's never been connected, but for its serial link's counters
+	#[tokio::test(start_paused = true)]
+	async fn test_stalled_request_times_out() {
+		let (mut client, mut server) = duplex(1024);
+		client.write_u32(100).await.unwrap();
+		client.write_all(&[0; 10]).await.unwrap();
+		// the rest never comes, but the connection stays open
+		let e = read_request(&mut server).await.err().unwrap();
+		assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+		assert_eq!(e.to_string(), "no request within 5 s");
+	}
+
+	#[tokio::test]
+	async fn test_oversized_request_is_answered() {
+		let (mut client, server) = duplex(1024);
+		let served = serve_one(server);
+		let request = Request::new(None, ServerCmd::Status, None);
+		let head = postcard::to_extend(&request, Vec::new()).unwrap();
+		client.write_u32(MAX_REQUEST_SIZE as u32 + 1).await.unwrap();
+		client.write_all(&head).await.unwrap();
+		client.write_all(&[0; 8]).await.unwrap();
+		let mut buf = vec![0; client.read_u32().await.unwrap() as usize];
+		client.read_exact(&mut buf).await.unwrap();
+		let reply: Reply<()> = postcard::from_bytes(&buf).unwrap();
+		// with the request's id, not the 0 of one that couldn't be read
+		assert_eq!(reply.id, request.id);
+		assert!(
+			matches!(&reply.body, Err(reason) if reason.contains("bytes")),
+			"{reply:?}"
+		);
+		served.await.unwrap().unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_unreadable_request_is_answered() {
+		let (mut client, server) = duplex(1024);
+		let served = serve_one(server);
+		client.write_u32(4).await.unwrap();
+		client.write_all(&[0xff; 4]).await.unwrap();
+		// the id can't be read, the reply's is 0
+		let reply = read_reply::<()>(&mut client, 1).await;
+		assert!(
+			matches!(&reply, Err(ReplyError::Rejected(reason)) if reason.starts_with("not a request")),
+			"{reply:?}"
+		);
+		served.await.unwrap().unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_last_measurement_and_mode() {
+		let measurement = Measurement {
+			vbat: MilliVolt::new(12_340),
+			ibat: MilliAmp::new(2_000),
+			milliwatts: MilliWatt::new(24_680),
+			sample_index: 7,
+			sample_start_ms: 7_000,
+			sample_duration_ms: 900,
+			temp_centi_c: None,
+			load_temp_centi_c: None,
+			fan_on: false,
+			load: None,
+		};
+		let idle = StatusWatch::fixed(ServerStatus::default(), LinkStats::default());
+		let mut testing = StatusWatch::fixed(
+			ServerStatus {
+				mode: Mode::Testing,
+				..ServerStatus::default()
+			},
+			LinkStats::default(),
+		);
+		testing.measurement = watch::channel(Some(measurement)).1;
+		let channels = [idle, testing];
+		let last: LastMeasurement = ask(&channels, Some(1), ServerCmd::GetLastMeasurement).await;
+		assert_eq!(
+			last,
+			LastMeasurement {
+				channel: 1,
+				measurement: Some(measurement)
+			}
+		);
+		let mode: CurrentMode = ask(&channels, Some(1), ServerCmd::GetMode).await;
+		assert_eq!(
+			mode,
+			CurrentMode {
+				channel: 1,
+				mode: Mode::Testing
+			}
+		);
+		// the first channel unless one is picked
+		let last: LastMeasurement = ask(&channels, None, ServerCmd::GetLastMeasurement).await;
+		assert_eq!(last.measurement, None);
+		let mode: CurrentMode = ask(&channels, None, ServerCmd::GetMode).await;
+		assert_eq!((mode.channel, mode.mode), (0, Mode::Setup));
+	}
+
+	#[tokio::test]
+	async fn test_calibration_needs_the_device_id() {
+		let measurement = Measurement {
+			vbat: MilliVolt::new(12_000),
+			ibat: MilliAmp::new(2_000),
+			milliwatts: MilliWatt::new(24_000),
+			sample_index: 0,
+			sample_start_ms: 0,
+			sample_duration_ms: 900,
+			temp_centi_c: None,
+			load_temp_centi_c: None,
+			fan_on: false,
+			load: None,
+		};
+		let measuring = |device_version| {
+			let mut status = StatusWatch::fixed(
+				ServerStatus {
+					device_name: Some("/dev/ttyACM0".into()),
+					device_version,
+					..ServerStatus::default()
+				},
+				LinkStats::default(),
+			);
+			status.measurement = watch::channel(Some(measurement)).1;
+			status
+		};
+		let point = || {
+			ServerCmd::Calibrate(CalibrationChange::Point {
+				millivolts: Some(MilliVolt::new(12_050)),
+				milliamps: None,
+			})
+		};
+		// the port is known but not which board is on it
+		let ack: Ack = ask(&[measuring(None)], None, point()).await;
+		assert!(
+			ack.error
+				.as_deref()
+				.is_some_and(|e| e.starts_with("the battery interface hasn't said which it is")),
+			"{:?}",
+			ack.error
+		);
+		let handshake = DeviceVersion {
+			protocol: PROTOCOL_VERSION,
+			firmware: FirmwareVersion {
+				major: 0,
+				minor: 0,
+				patch: 0,
+			},
+			device_id: 0x0123_4567_89ab_cdef,
+		};
+		let ack: Ack = ask(&[measuring(Some(handshake))], None, point()).await;
+		assert_eq!(ack.error, None);
+	}
+
+	#[tokio::test]
+	async fn test_commands_need_the_token() {
+		let channels = [StatusWatch::fixed(
+			ServerStatus::default(),
+			LinkStats::default(),
+		)];
+		let (event_tx, mut event_rx) = mpsc::channel(8);
+		let (print_tx, mut print_rx) = mpsc::channel::<Print>(8);
+		tokio::spawn(async move { while print_rx.recv().await.is_some() {} });
+		let printer = Printer::new(print_tx);
+		let send = async |cmd, token: Option<&str>| {
+			let (mut client, server) = duplex(4096);
+			let request = Request::new(None, cmd, token.map(Box::from));
+			write_ipc(BytesMut::new(), &mut client, &request)
+				.await
+				.unwrap();
+			let stores = stores();
+			for_each_conn(
+				Ok(server),
+				&event_tx,
+				&channels,
+				&stores,
+				Some("s3cret"),
+				printer.clone(),
+			)
+			.await
+			.unwrap();
+			read_reply::<Ack>(&mut client, request.id)
+				.await
+				.unwrap()
+				.error
+		};
+
+		for token in [None, Some("s3cre"), Some("s3creT")] {
+			let error = send(ServerCmd::StartTest, token).await;
+			assert!(
+				error.is_some_and(|e| e.starts_with("wrong or missing token")),
+				"{token:?}"
+			);
+		}
+		assert!(event_rx.try_recv().is_err());
+		assert_eq!(send(ServerCmd::StartTest, Some("s3cret")).await, None);
+		assert_eq!(
+			event_rx.try_recv().unwrap(),
+			ChannelEvent {
+				channel: 0,
+				event: Event::StartTest
+			}
+		);
+
+		// asking isn't changing anything
+		let (mut client, server) = duplex(4096);
+		let request = Request::new(None, ServerCmd::GetMode, None);
+		write_ipc(BytesMut::new(), &mut client, &request)
+			.await
+			.unwrap();
+		for_each_conn(
+			Ok(server),
+			&event_tx,
+			&channels,
+			&stores(),
+			Some("s3cret"),
+			printer,
+		)
+		.await
+		.unwrap();
+		let mode: CurrentMode = read_reply(&mut client, request.id).await.unwrap();
+		assert_eq!(mode.mode, Mode::Setup);
+	}
+
+	#[tokio::test]
+	async fn test_other_versions_fail_cleanly() {
+		// a newer client's request, with a command this server doesn't know
+		let (mut client, server) = duplex(1024);
+		let served = serve_one(server);
+		let id = 42_u32;
+		let mut request = postcard::to_extend(&(IPC_VERSION + 1, id), Vec::new()).unwrap();
+		request.extend_from_slice(&[0xfe, 0x01, 0x07]);
+		client.write_u32(request.len() as u32).await.unwrap();
+		client.write_all(&request).await.unwrap();
+		let reply = read_reply::<Ack>(&mut client, id).await;
+		assert!(
+			matches!(&reply, Err(ReplyError::Rejected(reason)) if reason.contains("IPC version")),
+			"{reply:?}"
+		);
+		served.await.unwrap().unwrap();
+
+		// and a newer server's reply
+		let (mut client, mut server) = duplex(1024);
+		let reply = Reply {
+			version: IPC_VERSION + 1,
+			id: 43,
+			body: Ok([0xfe_u8, 0x01]),
+		};
+		write_ipc(BytesMut::new(), &mut server, &reply)
+			.await
+			.unwrap();
+		let read = read_reply::<Ack>(&mut client, 43).await;
+		assert!(
+			matches!(read, Err(ReplyError::Version(version)) if version == IPC_VERSION + 1),
+			"{read:?}"
+		);
+	}
+
+	#[tokio::test]
+	async fn test_status_replies_with_the_link_counters() {
+		let link = LinkStats {
+			sent: 10,
+			acked: 8,
+			lost: 1,
+			duplicate: 1,
+			bad_frames: 2,
+			received: 9,
+			reconnects: 1,
+			..LinkStats::default()
+		};
+		let (print_tx, mut print_rx) = mpsc::channel::<Print>(64);
+		let (mut client, server) = duplex(4096);
+		let served = serve_with(
+			server,
+			vec![StatusWatch::fixed(ServerStatus::default(), link)],
+			Printer::new(print_tx),
+		);
+		let request = Request::new(None, ServerCmd::Status, None);
+		write_ipc(BytesMut::new(), &mut client, &request)
+			.await
+			.unwrap();
+		let reports: Vec<ChannelStatus> = read_reply(&mut client, request.id).await.unwrap();
+		served.await.unwrap().unwrap();
+
+		assert_eq!(reports.len(), 1);
+		assert_eq!(reports[0].link, link);
+		// printed by the client that asked, not on the server
+		assert!(print_rx.try_recv().is_err());
+	}
+}
@@ -0,0 +1,80 @@
+//! Journal of the running test so it can be resumed if the server is killed mid-test.
+//!
+//! Written to the output directory each time a test starts testing and removed when the
+//! test ends. A journal left over at startup means the last test never ended,
+//! the server starts in [`Mode::Resume`](crate::Mode::Resume) to pick it back up.
+
+use std::path::{Path, PathBuf};
+
+use battery_tester_common::{AllowUndercurrent, MilliVolt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	BatteryID, Error, OutputFormat,
+	chemistry::Chemistry,
+	files::{Note, SavedTo},
+};
+
+const JOURNAL_FILE: &str = "battery-tester-journal.toml";
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Journal {
+	pub battery_id: BatteryID,
+	pub cutoff: MilliVolt,
+	#[serde(default)]
+	pub chemistry: Chemistry,
+	pub allow_undercurrent: AllowUndercurrent,
+	pub device_name: Option<Box<str>>,
+	#[serde(default)]
+	pub device_baud: Option<u32>,
+	pub format: OutputFormat,
+	pub saved_to: SavedTo,
+	/// Local time the test first started testing
+	pub started: Box<str>,
+	#[serde(default)]
+	pub operator: Option<Box<str>>,
+	#[serde(default)]
+	pub notes: Vec<Note>,
+	#[serde(default)]
+	pub max_duration_s: Option<u64>,
+	#[serde(default)]
+	pub max_mah: Option<u32>,
+	#[serde(default)]
+	pub internal_resistance_mohm: Option<u32>,
+	#[serde(default)]
+	pub ocv_before_mv: Option<u16>,
+}
+
+impl Journal {
+	pub fn path(output_dir: &Path) -> PathBuf {
+		output_dir.join(JOURNAL_FILE)
+	}
+
+	/// `None` if there's no journal, the last test ended
+	pub fn load(path: &Path) -> Result<Option<Self>, Error> {
+		let text = match std::fs::read_to_string(path) {
+			Ok(text) => text,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(e) => return Err(Error::JournalRead(path.to_path_buf().into_boxed_path(), e)),
+		};
+		toml::from_str(&text)
+			.map(Some)
+			.map_err(|e| Error::JournalParse(path.to_path_buf().into_boxed_path(), e))
+	}
+
+	/// Replaces the journal all at once, a crash while saving leaves the old one
+	pub fn save(&self, path: &Path) -> std::io::Result<()> {
+		// only fails for types toml can't represent, none are used here
+		let text = toml::to_string_pretty(self).unwrap();
+		let tmp = path.with_extension("toml.tmp");
+		std::fs::write(&tmp, text)?;
+		std::fs::rename(tmp, path)
+	}
+
+	pub fn remove(path: &Path) -> std::io::Result<()> {
+		match std::fs::remove_file(path) {
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+			res => res,
+		}
+	}
+}
@@ -0,0 +1,205 @@
+//! Headless appliance mode, e.g. a Raspberry Pi wired to the rig.
+//!
+//! Finds the battery interface on its own, applies the saved settings, and
+//! takes all input from a small web dashboard instead of the client.
+//! Settings changed from the dashboard are written back to the config file
+//! so they survive a reboot.
+
+use std::{
+	io::Write,
+	path::{Path, PathBuf},
+};
+
+use battery_tester_common::AllowUndercurrent;
+use serde::{Deserialize, Serialize};
+use tokio::{
+	net::TcpListener,
+	select,
+	sync::mpsc::Sender,
+	time::{self, Duration, MissedTickBehavior},
+};
+
+use crate::{DEFAULT_CUTOFF_MILLIV, Error, Event, Mode, Printer, StatusWatch};
+
+mod web;
+
+/// How often to look for the battery interface while it's missing
+const DISCOVERY_MS: u64 = 2_000;
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KioskConfig {
+	/// Address the dashboard listens on, only this machine by default
+	pub listen: String,
+	pub cutoff_mv: u16,
+	pub allow_undercurrent: bool,
+	pub start: StartPolicy,
+	pub device: DeviceMatch,
+}
+
+impl Default for KioskConfig {
+	fn default() -> Self {
+		Self {
+			listen: "127.0.0.1:8080".into(),
+			cutoff_mv: DEFAULT_CUTOFF_MILLIV,
+			allow_undercurrent: false,
+			start: StartPolicy::default(),
+			device: DeviceMatch::default(),
+		}
+	}
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StartPolicy {
+	/// Wait for the start button on the dashboard
+	#[default]
+	Manual,
+	/// Start as soon as a charged battery is connected
+	OnBattery,
+}
+
+/// USB IDs of the battery interface's serial port
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct DeviceMatch {
+	pub usb_vid: u16,
+	pub usb_pid: u16,
+}
+
+impl Default for DeviceMatch {
+	/// micro:bit v2 DAPLink
+	fn default() -> Self {
+		Self {
+			usb_vid: 0x0D28,
+			usb_pid: 0x0204,
+		}
+	}
+}
+
+impl KioskConfig {
+	/// Load the config, a missing file is created with the defaults
+	pub fn load(path: &Path) -> Result<Self, Error> {
+		let config_path = || path.to_path_buf().into_boxed_path();
+		match std::fs::read_to_string(path) {
+			Ok(text) => {
+				toml::from_str(&text).map_err(|e| Error::KioskConfigParse(config_path(), e))
+			}
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+				let config = Self::default();
+				config
+					.save(path)
+					.map_err(|e| Error::KioskConfigWrite(config_path(), e))?;
+				Ok(config)
+			}
+			Err(e) => Err(Error::KioskConfigRead(config_path(), e)),
+		}
+	}
+
+	pub fn save(&self, path: &Path) -> std::io::Result<()> {
+		// only fails for types toml can't represent, none are used here
+		let text = toml::to_string_pretty(self).unwrap();
+		std::fs::write(path, text)
+	}
+
+	fn allow_undercurrent(&self) -> AllowUndercurrent {
+		if self.allow_undercurrent {
+			AllowUndercurrent::Yes
+		} else {
+			AllowUndercurrent::No
+		}
+	}
+}
+
+/// First serial port with the configured USB IDs
+fn discover_device(device: DeviceMatch) -> Option<Box<str>> {
+	let ports = tokio_serial::available_ports().ok()?;
+	ports
+		.into_iter()
+		.find(|port| match &port.port_type {
+			tokio_serial::SerialPortType::UsbPort(usb) => {
+				usb.vid == device.usb_vid && usb.pid == device.usb_pid
+			}
+			_ => false,
+		})
+		.map(|port| port.port_name.into_boxed_str())
+}
+
+pub async fn kiosk_task(
+	config_path: PathBuf,
+	mut config: KioskConfig,
+	token: Box<str>,
+	event_tx: Sender<Event>,
+	mut status: StatusWatch,
+	mut printer: Printer,
+) {
+	let listener = match TcpListener::bind(&config.listen).await {
+		Ok(l) => l,
+		Err(e) => {
+			let listen = &config.listen;
+			printer
+				.error(|tv| write!(tv, "can't start dashboard on: {listen}\n{e}"))
+				.await;
+			return;
+		}
+	};
+	{
+		let listen = &config.listen;
+		printer
+			.buf(|tv| write!(tv, "dashboard at: http://{listen}"))
+			.await;
+	}
+	event_tx
+		.send(Event::SetCutoff(config.cutoff_mv.into()))
+		.await
+		.unwrap();
+	event_tx
+		.send(Event::UnderCurrentResponse(config.allow_undercurrent()))
+		.await
+		.unwrap();
+
+	let mut discovery = time::interval(Duration::from_millis(DISCOVERY_MS));
+	discovery.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	let mut last_mode = Mode::default();
+	// status only updates on mode changes so remember what we sent
+	let mut last_found: Option<Box<str>> = None;
+	loop {
+		select! {
+			conn = listener.accept() => match conn {
+				Ok((stream, _addr)) => {
+					web::handle_conn(stream, &config_path, &mut config, &token, &event_tx, &status, &mut printer).await
+				}
+				Err(e) => printer.warn(|tv| write!(tv, "dashboard connection error: {e}")).await,
+			},
+			_ = discovery.tick() => {
+				let mode = status.server.borrow().mode;
+				if matches!(mode, Mode::Setup | Mode::CommDC | Mode::Resume)
+					&& let Some(found) = discover_device(config.device)
+					&& last_found.as_ref() != Some(&found)
+				{
+					printer.buf(|tv| write!(tv, "found battery interface at: {found}")).await;
+					event_tx.send(Event::SetSerialDevice(found.clone(), None)).await.unwrap();
+					last_found = Some(found);
+				}
+			}
+			changed = status.server.changed() => {
+				if changed.is_err() {
+					// program task is gone
+					break;
+				}
+				let mode = status.server.borrow_and_update().mode;
+				if mode == Mode::Shutdown {
+					break;
+				}
+				if mode == Mode::WaitForUsrStart
+					&& last_mode != Mode::WaitForUsrStart
+					&& config.start == StartPolicy::OnBattery
+				{
+					printer.stat("battery connected, starting test").await;
+					event_tx.send(Event::StartTest).await.unwrap();
+				}
+				last_mode = mode;
+			}
+		}
+	}
+	println!("exiting kiosk_task");
+}
@@ -0,0 +1,457 @@
+//! Just enough HTTP/1.1 for the dashboard, one request per connection.
+//!
+//! Anything can be read, but a POST needs the server's token in an `X-Token` header or a
+//! `token` cookie, and one from a browser has to come from the dashboard's own page.
+
+use std::{io::Write, path::Path};
+
+use battery_tester_common::AllowUndercurrent;
+use serde_json::json;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+	sync::mpsc::Sender,
+	time::{Duration, timeout},
+};
+
+use super::{KioskConfig, StartPolicy};
+use crate::{BatteryID, Event, Printer, StatusWatch, ipc::token_matches};
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+/// Requests are a line and a few headers, anything bigger isn't from the dashboard
+const MAX_REQUEST: usize = 4096;
+const READ_TIMEOUT_MS: u64 = 2_000;
+
+struct Response {
+	status: &'static str,
+	content_type: &'static str,
+	body: String,
+}
+
+impl Response {
+	fn ok(content_type: &'static str, body: String) -> Self {
+		Self {
+			status: "200 OK",
+			content_type,
+			body,
+		}
+	}
+
+	fn no_content() -> Self {
+		Self {
+			status: "204 No Content",
+			content_type: "text/plain",
+			body: String::new(),
+		}
+	}
+
+	fn error(status: &'static str, body: &str) -> Self {
+		Self {
+			status,
+			content_type: "text/plain",
+			body: body.into(),
+		}
+	}
+}
+
+pub async fn handle_conn(
+	mut stream: TcpStream,
+	config_path: &Path,
+	config: &mut KioskConfig,
+	token: &str,
+	event_tx: &Sender<Event>,
+	status: &StatusWatch,
+	printer: &mut Printer,
+) {
+	let response = match timeout(
+		Duration::from_millis(READ_TIMEOUT_MS),
+		read_request(&mut stream),
+	)
+	.await
+	{
+		Ok(Some(request)) => {
+			route(
+				&request,
+				config_path,
+				config,
+				token,
+				event_tx,
+				status,
+				printer,
+			)
+			.await
+		}
+		Ok(None) => Response::error("400 Bad Request", "bad request"),
+		Err(_) => return,
+	};
+	let head = format!(
+		"HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+		response.status,
+		response.content_type,
+		response.body.len()
+	);
+	// a dashboard that went away mid reply is nothing to act on
+	let _ = stream.write_all(head.as_bytes()).await;
+	let _ = stream.write_all(response.body.as_bytes()).await;
+	let _ = stream.shutdown().await;
+}
+
+/// Read up to the end of the headers, the dashboard never sends a body
+async fn read_request(stream: &mut TcpStream) -> Option<String> {
+	let mut buf = Vec::with_capacity(512);
+	let mut chunk = [0u8; 512];
+	while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+		if buf.len() >= MAX_REQUEST {
+			return None;
+		}
+		let n = stream.read(&mut chunk).await.ok()?;
+		if n == 0 {
+			return None;
+		}
+		buf.extend_from_slice(&chunk[..n]);
+	}
+	String::from_utf8(buf).ok()
+}
+
+/// A header's value, `None` if it wasn't sent
+fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+	request
+		.lines()
+		.skip(1)
+		.take_while(|line| !line.is_empty())
+		.filter_map(|line| line.split_once(':'))
+		.find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+		.map(|(_, value)| value.trim())
+}
+
+/// The token from the `X-Token` header, or else the `token` cookie
+fn given_token(request: &str) -> Option<&str> {
+	header(request, "X-Token").or_else(|| {
+		header(request, "Cookie")?
+			.split(';')
+			.filter_map(|cookie| cookie.trim().split_once('='))
+			.find(|(key, _)| *key == "token")
+			.map(|(_, value)| value)
+	})
+}
+
+/// Browsers say which page sent a request, it has to be the dashboard so another site
+/// open in the same browser can't drive the rig. Other clients don't send an origin.
+fn same_origin(request: &str) -> bool {
+	match (header(request, "Origin"), header(request, "Host")) {
+		(None, _) => true,
+		(Some(origin), Some(host)) => origin
+			.strip_prefix("http://")
+			.is_some_and(|origin| origin.eq_ignore_ascii_case(host)),
+		(Some(_), None) => false,
+	}
+}
+
+async fn route(
+	request: &str,
+	config_path: &Path,
+	config: &mut KioskConfig,
+	token: &str,
+	event_tx: &Sender<Event>,
+	status: &StatusWatch,
+	printer: &mut Printer,
+) -> Response {
+	let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+	let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+		return Response::error("400 Bad Request", "bad request");
+	};
+	let (path, query) = target.split_once('?').unwrap_or((target, ""));
+	let param = |key: &str| {
+		query
+			.split('&')
+			.filter_map(|pair| pair.split_once('='))
+			.find(|(k, _)| *k == key)
+			.map(|(_, v)| v)
+	};
+
+	if method == "POST" {
+		if !same_origin(request) {
+			printer
+				.warn_stat("refused a dashboard command from another site")
+				.await;
+			return Response::error("403 Forbidden", "commands only come from the dashboard");
+		}
+		if !token_matches(Some(token), given_token(request)) {
+			return Response::error("401 Unauthorized", "wrong or missing token");
+		}
+	}
+
+	let event = match (method, path) {
+		("GET", "/") => return Response::ok("text/html; charset=utf-8", DASHBOARD.into()),
+		("GET", "/status") => {
+			return Response::ok("application/json", status_json(status, config));
+		}
+		("GET", "/capabilities") => {
+			let capabilities = serde_json::to_string(&status.capabilities()).unwrap();
+			return Response::ok("application/json", capabilities);
+		}
+		("POST", "/start") => Event::StartTest,
+		("POST", "/charge") => Event::Charge,
+		("POST", "/cancel") => Event::CancelTest,
+		("POST", "/clear") => Event::ClearFault,
+		("POST", "/battery") => {
+			let year = param("year").and_then(|y| y.parse().ok());
+			let index = param("index").and_then(|i| i.parse().ok());
+			let (Some(year), Some(index)) = (year, index) else {
+				return Response::error("400 Bad Request", "year and index are required");
+			};
+			Event::BattID(BatteryID { year, index })
+		}
+		("POST", "/cutoff") => {
+			let Some(millivolts) = param("millivolts").and_then(|m| m.parse::<u16>().ok()) else {
+				return Response::error("400 Bad Request", "millivolts is required");
+			};
+			config.cutoff_mv = millivolts;
+			save(config_path, config, printer).await;
+			Event::SetCutoff(millivolts.into())
+		}
+		("POST", "/undercurrent") => {
+			config.allow_undercurrent = match param("allow") {
+				Some("true") => true,
+				Some("false") => false,
+				_ => return Response::error("400 Bad Request", "allow must be true or false"),
+			};
+			save(config_path, config, printer).await;
+			Event::UnderCurrentResponse(if config.allow_undercurrent {
+				AllowUndercurrent::Yes
+			} else {
+				AllowUndercurrent::No
+			})
+		}
+		("POST", "/start-policy") => {
+			config.start = match param("start") {
+				Some("manual") => StartPolicy::Manual,
+				Some("on_battery") => StartPolicy::OnBattery,
+				_ => {
+					return Response::error(
+						"400 Bad Request",
+						"start must be manual or on_battery",
+					);
+				}
+			};
+			save(config_path, config, printer).await;
+			return Response::no_content();
+		}
+		("GET" | "POST", _) => return Response::error("404 Not Found", "not found"),
+		_ => return Response::error("405 Method Not Allowed", "method not allowed"),
+	};
+	event_tx.send(event).await.unwrap();
+	Response::no_content()
+}
+
+async fn save(config_path: &Path, config: &KioskConfig, printer: &mut Printer) {
+	if let Err(e) = config.save(config_path) {
+		printer
+			.warn(|tv| write!(tv, "can't save kiosk config: {config_path:?}\n{e}"))
+			.await;
+	}
+}
+
+fn status_json(status: &StatusWatch, config: &KioskConfig) -> String {
+	let server = status.server.borrow().clone();
+	let measurement = *status.measurement.borrow();
+	let link = *status.link.borrow();
+	json!({
+		"mode": format!("{:?}", server.mode),
+		"battery": server.battery_id,
+		"cutoff_mv": u16::from(server.cutoff),
+		"device": server.device_name,
+		"firmware": server.device_version.map(|v| v.firmware.to_string()),
+		"device_id": server.device_version.map(|v| v.hex_device_id()),
+		"measurement": measurement,
+		"link": {
+			"sent": link.sent,
+			"received": link.received,
+			"acked": link.acked,
+			"lost": link.lost,
+			"duplicate": link.duplicate,
+			"bad_frames": link.bad_frames,
+			"reconnects": link.reconnects,
+			"last_rtt_ms": link.last_rtt.map(|rtt| rtt.as_secs_f64() * 1_000.0),
+			"average_rtt_ms": link.average_rtt().map(|rtt| rtt.as_secs_f64() * 1_000.0),
+		},
+		"config": config,
+	})
+	.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{LinkStats, Mode, Print, ServerStatus};
+	use tokio::sync::mpsc::{self, Receiver};
+
+	const TOKEN: &str = "s3cret";
+
+	/// The dashboard's server, with the config at a file of its own
+	struct Dashboard {
+		config_path: std::path::PathBuf,
+		config: KioskConfig,
+		event_tx: Sender<Event>,
+		event_rx: Receiver<Event>,
+		status: StatusWatch,
+		printer: Printer,
+	}
+
+	impl Dashboard {
+		fn new(test: &str) -> Self {
+			let config_path = std::env::temp_dir().join(format!(
+				"battery-tester-kiosk-{test}-{}.toml",
+				std::process::id()
+			));
+			let (event_tx, event_rx) = mpsc::channel(4);
+			let (print_tx, mut print_rx) = mpsc::channel::<Print>(8);
+			tokio::spawn(async move { while print_rx.recv().await.is_some() {} });
+			let server = ServerStatus {
+				mode: Mode::WaitForUsrStart,
+				..ServerStatus::default()
+			};
+			Self {
+				config_path,
+				config: KioskConfig::default(),
+				event_tx,
+				event_rx,
+				status: StatusWatch::fixed(server, LinkStats::default()),
+				printer: Printer::new(print_tx),
+			}
+		}
+
+		/// The status line and body of the reply, and the event sent if there was one
+		async fn request(&mut self, request_line: &str) -> (&'static str, String, Option<Event>) {
+			let headers = format!("X-Token: {TOKEN}\r\n");
+			self.request_with(request_line, &headers).await
+		}
+
+		/// Like [`Dashboard::request`] with these headers instead of the token
+		async fn request_with(
+			&mut self,
+			request_line: &str,
+			headers: &str,
+		) -> (&'static str, String, Option<Event>) {
+			let request = format!("{request_line} HTTP/1.1\r\nHost: rig:8080\r\n{headers}\r\n");
+			let response = route(
+				&request,
+				&self.config_path,
+				&mut self.config,
+				TOKEN,
+				&self.event_tx,
+				&self.status,
+				&mut self.printer,
+			)
+			.await;
+			(
+				response.status,
+				response.body,
+				self.event_rx.try_recv().ok(),
+			)
+		}
+	}
+
+	impl Drop for Dashboard {
+		fn drop(&mut self) {
+			let _ = std::fs::remove_file(&self.config_path);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_commands_sent_to_the_program() {
+		let mut dashboard = Dashboard::new("commands");
+		let (status, _, event) = dashboard.request("POST /start").await;
+		assert_eq!(status, "204 No Content");
+		assert_eq!(event, Some(Event::StartTest));
+		let (_, _, event) = dashboard.request("POST /battery?year=2025&index=7").await;
+		assert_eq!(
+			event,
+			Some(Event::BattID(BatteryID {
+				year: 2025,
+				index: 7
+			}))
+		);
+
+		let (status, body, event) = dashboard.request("POST /battery?year=2025").await;
+		assert_eq!(status, "400 Bad Request");
+		assert_eq!(body, "year and index are required");
+		assert_eq!(event, None);
+		assert_eq!(dashboard.request("GET /nothing").await.0, "404 Not Found");
+		assert_eq!(
+			dashboard.request("DELETE /start").await.0,
+			"405 Method Not Allowed"
+		);
+	}
+
+	#[tokio::test]
+	async fn test_settings_saved() {
+		let mut dashboard = Dashboard::new("settings");
+		let (_, _, event) = dashboard.request("POST /cutoff?millivolts=11200").await;
+		assert_eq!(event, Some(Event::SetCutoff(11_200.into())));
+		let (_, _, event) = dashboard.request("POST /undercurrent?allow=true").await;
+		assert_eq!(
+			event,
+			Some(Event::UnderCurrentResponse(AllowUndercurrent::Yes))
+		);
+		// only the kiosk task acts on it
+		let (status, _, event) = dashboard
+			.request("POST /start-policy?start=on_battery")
+			.await;
+		assert_eq!(status, "204 No Content");
+		assert_eq!(event, None);
+
+		let saved = KioskConfig::load(&dashboard.config_path).unwrap();
+		assert_eq!(saved, dashboard.config);
+		assert_eq!(saved.cutoff_mv, 11_200);
+		assert!(saved.allow_undercurrent);
+		assert_eq!(saved.start, StartPolicy::OnBattery);
+	}
+
+	#[tokio::test]
+	async fn test_commands_need_the_token() {
+		let mut dashboard = Dashboard::new("token");
+		for headers in ["", "X-Token: s3cre\r\n", "Cookie: token=S3cret\r\n"] {
+			let (status, _, event) = dashboard.request_with("POST /start", headers).await;
+			assert_eq!(status, "401 Unauthorized", "{headers:?}");
+			assert_eq!(event, None);
+		}
+		let cookie = format!("Cookie: theme=dark; token={TOKEN}\r\n");
+		let (_, _, event) = dashboard.request_with("POST /start", &cookie).await;
+		assert_eq!(event, Some(Event::StartTest));
+		// reading doesn't need it
+		assert_eq!(dashboard.request_with("GET /status", "").await.0, "200 OK");
+		assert_eq!(KioskConfig::default().listen, "127.0.0.1:8080");
+	}
+
+	#[tokio::test]
+	async fn test_commands_only_from_the_dashboard() {
+		let mut dashboard = Dashboard::new("origin");
+		let token = format!("X-Token: {TOKEN}\r\n");
+		for origin in [
+			"http://evil.example",
+			"https://rig:8080",
+			"http://rig:8081",
+			"null",
+		] {
+			let headers = format!("Origin: {origin}\r\n{token}");
+			let (status, _, event) = dashboard.request_with("POST /cancel", &headers).await;
+			assert_eq!(status, "403 Forbidden", "{origin}");
+			assert_eq!(event, None);
+		}
+		let headers = format!("origin: http://RIG:8080\r\n{token}");
+		let (_, _, event) = dashboard.request_with("POST /cancel", &headers).await;
+		assert_eq!(event, Some(Event::CancelTest));
+	}
+
+	#[tokio::test]
+	async fn test_status() {
+		let mut dashboard = Dashboard::new("status");
+		let (status, body, _) = dashboard.request("GET /status").await;
+		assert_eq!(status, "200 OK");
+		let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+		assert_eq!(json["mode"], "WaitForUsrStart");
+		assert_eq!(json["link"]["sent"], 0);
+		assert_eq!(json["config"]["start"], "manual");
+	}
+}
@@ -1,66 +1,169 @@
 use argh::FromArgs;
 use battery_tester_common::{
-	AllowUndercurrent, BIReply, BiCommand, ClearFault, LoadState, MilliAmp, MilliVolt, Reset,
+	AllowUndercurrent, AutonomousTest, BAUDS, BatteryDetect, ClearFault, ControlWord,
+	DEFAULT_POLL_MS, FirmwareVersion, LoadChannel, LoadModel, LoadState, LoggedFault, LoggedSample,
+	Measurement, MilliAmp, MilliVolt, MilliWatt, PROTOCOL_VERSION, Polarity, Reset, SelfTestReport,
+	Status, Trim,
+	frame::{COMMAND_FRAME_MAX_SIZE, REPLY_FRAME_MAX_SIZE},
+	protocol_compatible,
+	window::{WINDOW_SAMPLES, WindowFilter},
 };
 use bytes::BytesMut;
 use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, Ordering};
 use thiserror::Error;
 use tinyvec::{ArrayVec, TinyVec, tiny_vec};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{
+	mpsc::{Receiver, Sender},
+	watch,
+};
 
+pub mod analysis;
+pub mod calibration;
+pub mod chamber;
+pub mod chemistry;
+pub mod columns;
+pub mod completions;
+pub mod dashboard;
+pub mod dump;
+pub mod engine;
+#[cfg(feature = "parquet")]
+pub mod export;
 pub mod files;
 pub mod ipc;
+pub mod journal;
+#[cfg(feature = "kiosk")]
+pub mod kiosk;
+pub mod live;
+pub mod notify;
+pub mod ocv;
+pub mod profile;
+mod program;
+pub mod pulse;
+pub mod queue;
+pub mod registry;
 pub mod serial;
+pub mod signal;
+pub mod sim;
+pub mod termination;
+pub mod trace;
+pub mod warmup;
 
-pub const OUTGOING_MAX_SIZE: usize = BiCommand::POSTCARD_MAX_SIZE;
-pub const INCOMING_MAX_SIZE: usize = BIReply::POSTCARD_MAX_SIZE;
-pub const DEFALT_BAUD: u32 = 230400;
+pub const OUTGOING_MAX_SIZE: usize = COMMAND_FRAME_MAX_SIZE;
+pub const INCOMING_MAX_SIZE: usize = REPLY_FRAME_MAX_SIZE;
 pub const DEFAULT_CUTOFF_MILLIV: u16 = 11_000;
 pub const DEFAULT_DISCONNECT_MILLIV: u16 = 1_000;
 pub const SERVER_NAME: &str = "battery-tester-server";
+/// Replies in a row without a new measurement before the DAQ is considered hung,
+/// at the default poll rate. The device averages a measurement every ~1 s and we poll at 2 Hz.
+pub const STALE_REPLY_LIMIT: u32 = 10;
+/// A charge is done once the charge current stays below this
+pub const CHARGE_FULL_MILLIAMPS: i16 = 500;
+/// Measurements in a row below [`CHARGE_FULL_MILLIAMPS`] before the battery counts as full,
+/// about a minute with one measurement a second
+pub const CHARGE_FULL_MEASUREMENTS: u32 = 60;
+/// Measurements after the charge started before it's given up on, about 12 hours with one
+/// measurement a second. A charger that never tapers off would hold up the test for good.
+pub const CHARGE_TIMEOUT_MEASUREMENTS: u32 = 12 * 60 * 60;
 
+/// Sends lines to the [`print_task`], each stamped with when and where it came from
 #[derive(Debug, Clone)]
 pub struct Printer {
 	sender: Sender<Print>,
+	task: Task,
+	channel: Option<ChannelId>,
 }
 
 impl Printer {
 	pub fn new(sender: Sender<Print>) -> Self {
-		Self { sender: sender }
+		Self {
+			sender,
+			task: Task::Server,
+			channel: None,
+		}
+	}
+
+	/// A printer for lines from `task`
+	pub fn task(&self, task: Task) -> Self {
+		Self {
+			task,
+			..self.clone()
+		}
+	}
+
+	/// A printer for lines about `channel`, for servers with more than one
+	pub fn channel(&self, channel: ChannelId) -> Self {
+		Self {
+			channel: Some(channel),
+			..self.clone()
+		}
 	}
 
 	pub async fn shutdown(self) {
-		self.sender.send(Print::Shutdown).await.unwrap();
+		let _ = self.sender.send(Print::Shutdown).await;
 	}
 
 	pub async fn stat(&self, msg: &'static str) {
-		self.sender.send(Print::Static(msg)).await.unwrap()
+		self.send(Print::Info, Text::Static(msg)).await
 	}
 
-	pub async fn buf<F>(&mut self, mut f: F)
+	pub async fn buf<F>(&mut self, f: F)
 	where
 		F: FnMut(&mut TinyVec<[u8; 128]>) -> Result<(), std::io::Error>,
 	{
-		let mut buf = tiny_vec!([u8; 128]);
-		let _ = f(&mut buf);
-		match buf {
-			TinyVec::Inline(array_vec) => self.sender.send(Print::Dyn(array_vec)).await.unwrap(),
-			TinyVec::Heap(items) => self
-				.sender
-				.send(Print::Aloc(items.into_boxed_slice()))
-				.await
-				.unwrap(),
-		}
+		self.send(Print::Info, Text::format(f)).await
+	}
+
+	/// [`Printer::warn`] for a message that doesn't need formatting
+	pub async fn warn_stat(&self, msg: &'static str) {
+		self.send(Print::Warn, Text::Static(msg)).await
+	}
+
+	/// [`Printer::error`] for a message that doesn't need formatting
+	pub async fn error_stat(&self, msg: &'static str) {
+		self.send(Print::Error, Text::Static(msg)).await
+	}
+
+	/// Something went wrong but testing carries on
+	pub async fn warn<F>(&self, f: F)
+	where
+		F: FnMut(&mut TinyVec<[u8; 128]>) -> Result<(), std::io::Error>,
+	{
+		self.send(Print::Warn, Text::format(f)).await
+	}
+
+	/// Something went wrong that stops a test or a task
+	pub async fn error<F>(&self, f: F)
+	where
+		F: FnMut(&mut TinyVec<[u8; 128]>) -> Result<(), std::io::Error>,
+	{
+		self.send(Print::Error, Text::format(f)).await
+	}
+
+	async fn send(&self, severity: fn(Line) -> Print, text: Text) {
+		let line = Line {
+			time: chrono::Local::now(),
+			task: self.task,
+			channel: self.channel,
+			text,
+		};
+		// nothing can be printed once the print task is gone, that's no reason to stop a task
+		let _ = self.sender.send(severity(line)).await;
 	}
 }
 
+/// A local IPC connection or a TCP one from `--listen`, both carry the same messages
+pub trait IpcStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> IpcStream for T {}
+
 /// Due to how postcard::to_extend works, we return the buffer after clearing it
 /// instead of just takeing a mutable reference.
 pub async fn write_ipc<T>(
 	out_buf: BytesMut,
-	stream: &mut tipsy::Connection,
+	stream: &mut (impl AsyncWrite + Unpin),
 	cmd: &T,
 ) -> Result<BytesMut, tokio::io::Error>
 where
@@ -82,52 +185,484 @@ where
 	Ok(serialized)
 }
 
+/// Answer the request `id` with `body`, see [`Reply`]
+pub async fn write_reply<T>(
+	out_buf: BytesMut,
+	stream: &mut (impl AsyncWrite + Unpin),
+	id: u32,
+	body: &T,
+) -> Result<BytesMut, tokio::io::Error>
+where
+	T: serde::Serialize + ?Sized,
+{
+	let reply = Reply {
+		version: IPC_VERSION,
+		id,
+		body: Ok(body),
+	};
+	write_ipc(out_buf, stream, &reply).await
+}
+
+/// Far more than any reply, a status for each of 255 channels or a long battery list,
+/// anything longer isn't from a server of ours
+const MAX_REPLY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Read the server's reply to the request `id`, written by [`write_reply`]
+pub async fn read_reply<T>(stream: &mut (impl AsyncRead + Unpin), id: u32) -> Result<T, ReplyError>
+where
+	T: serde::de::DeserializeOwned,
+{
+	let invalid = |e| tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, e);
+	let len = stream.read_u32().await? as usize;
+	if len > MAX_REPLY_SIZE {
+		let error = format!("reply is {len} bytes");
+		return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, error).into());
+	}
+	let mut buf = vec![0u8; len];
+	stream.read_exact(&mut buf).await?;
+	// the rest may not be readable by this client, these come first in every version
+	let head: EnvelopeHead = postcard::from_bytes(&buf).map_err(invalid)?;
+	if head.version != IPC_VERSION {
+		return Err(ReplyError::Version(head.version));
+	}
+	let reply: Reply<T> = postcard::from_bytes(&buf).map_err(invalid)?;
+	if let (0, Err(reason)) = (reply.id, &reply.body) {
+		// this request's, the server only answers one at a time
+		return Err(ReplyError::Rejected(reason.clone()));
+	}
+	if reply.id != id {
+		return Err(ReplyError::Id(reply.id, id));
+	}
+	reply.body.map_err(ReplyError::Rejected)
+}
+
+#[derive(Debug, Error)]
+pub enum ReplyError {
+	#[error(transparent)]
+	Io(#[from] tokio::io::Error),
+	#[error(
+		"the server speaks IPC version {0} and this client {IPC_VERSION}, update the older one"
+	)]
+	Version(u16),
+	#[error("the server answered request {0} instead of {1}")]
+	Id(u32, u32),
+	#[error("the server couldn't read the request: {0}")]
+	Rejected(Box<str>),
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Print {
+	Info(Line),
+	/// Yellow on a terminal
+	Warn(Line),
+	/// Red on a terminal
+	Error(Line),
+	Shutdown,
+}
+
+/// Which task printed a line
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Task {
+	/// Starting up, shutting down, and the supervisor
+	Server,
+	Program,
+	Serial,
+	File,
+	Ipc,
+	Signal,
+	Chamber,
+	Trace,
+	Dump,
+	Notify,
+	Kiosk,
+	Demo,
+}
+
+impl std::fmt::Display for Task {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let name = match self {
+			Task::Server => "server",
+			Task::Program => "program",
+			Task::Serial => "serial",
+			Task::File => "file",
+			Task::Ipc => "ipc",
+			Task::Signal => "signal",
+			Task::Chamber => "chamber",
+			Task::Trace => "trace",
+			Task::Dump => "dump",
+			Task::Notify => "notify",
+			Task::Kiosk => "kiosk",
+			Task::Demo => "demo",
+		};
+		// padded so the messages line up
+		f.pad(name)
+	}
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Line {
+	/// When it was sent, not when it was printed
+	pub time: chrono::DateTime<chrono::Local>,
+	pub task: Task,
+	/// Only on servers with more than one channel
+	pub channel: Option<ChannelId>,
+	pub text: Text,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Text {
 	Static(&'static str),
 	Dyn(ArrayVec<[u8; 128]>),
 	Aloc(Box<[u8]>),
-	Shutdown,
 }
 
-impl Print {
+impl Text {
+	fn format<F>(mut f: F) -> Self
+	where
+		F: FnMut(&mut TinyVec<[u8; 128]>) -> Result<(), std::io::Error>,
+	{
+		let mut buf = tiny_vec!([u8; 128]);
+		let _ = f(&mut buf);
+		match buf {
+			TinyVec::Inline(array_vec) => Text::Dyn(array_vec),
+			TinyVec::Heap(items) => Text::Aloc(items.into_boxed_slice()),
+		}
+	}
+
 	pub fn as_bytes(&self) -> &[u8] {
 		match self {
-			Print::Static(sstr) => sstr.as_bytes(),
-			Print::Aloc(bstr) => bstr.as_ref(),
-			Print::Shutdown => b"\n",
-			Print::Dyn(array_vec) => array_vec.as_ref(),
+			Text::Static(sstr) => sstr.as_bytes(),
+			Text::Aloc(bstr) => bstr.as_ref(),
+			Text::Dyn(array_vec) => array_vec.as_ref(),
 		}
 	}
 }
 
+impl Line {
+	/// `2025-01-31 23:59:59.999 serial  ` or `... serial 1 ` before each line of the text
+	pub fn prefix(&self) -> String {
+		let time = self.time.format("%Y-%m-%d %H:%M:%S%.3f");
+		match self.channel {
+			Some(channel) => format!("{time} {:<7} {channel} ", self.task),
+			None => format!("{time} {:<7} ", self.task),
+		}
+	}
+}
+
+/// Prints every line with its [`Line::prefix`], in color when stdout is a terminal
 pub async fn print_task(mut print_rx: Receiver<Print>) {
+	let color = std::io::IsTerminal::is_terminal(&std::io::stdout());
 	let mut stdout = tokio::io::stdout();
 	while let Some(msg) = print_rx.recv().await {
-		if let Print::Shutdown = msg {
+		let Some(out) = printed(&msg, color) else {
+			break;
+		};
+		let written = async {
+			stdout.write_all(&out).await?;
+			stdout.flush().await
+		};
+		// stdout was closed, e.g. piped to a program that exited
+		if written.await.is_err() {
 			break;
 		}
-		stdout.write_all(msg.as_bytes()).await.unwrap();
-		stdout.write_u8(b'\n').await.unwrap();
-		stdout.flush().await.unwrap();
 	}
 	println!("exiting print_task");
 }
 
+/// What [`print_task`] writes for `msg`, `None` for [`Print::Shutdown`]. Each line of a
+/// multi-line message gets the prefix, so grepping for a task finds all of it.
+fn printed(msg: &Print, color: bool) -> Option<Vec<u8>> {
+	let (line, start) = match msg {
+		Print::Info(line) => (line, ""),
+		// yellow, red
+		Print::Warn(line) => (line, "\x1b[33m"),
+		Print::Error(line) => (line, "\x1b[31m"),
+		Print::Shutdown => return None,
+	};
+	let prefix = line.prefix();
+	let mut out = Vec::new();
+	for text in line.text.as_bytes().split(|byte| *byte == b'\n') {
+		out.extend_from_slice(prefix.as_bytes());
+		if color && !start.is_empty() {
+			out.extend_from_slice(start.as_bytes());
+			out.extend_from_slice(text);
+			out.extend_from_slice(b"\x1b[0m");
+		} else {
+			out.extend_from_slice(text);
+		}
+		out.push(b'\n');
+	}
+	Some(out)
+}
+
 #[derive(FromArgs, PartialEq, Eq, Clone)]
 /// Battery tester server
 pub struct Cli {
+	/// where tests are saved, needed unless running a subcommand
 	#[argh(positional)]
-	pub output_directory: std::path::PathBuf,
+	pub output_directory: Option<std::path::PathBuf>,
+	/// serial port for external equipment, DTR is set while testing and RTS on a fault
+	#[argh(option)]
+	pub signal_port: Option<String>,
+	/// TOML test profile worked through each time a test is started
+	#[argh(option)]
+	pub profile: Option<std::path::PathBuf>,
+	/// address (host:port) of an environmental chamber taking SCPI commands over TCP
+	#[argh(option)]
+	pub chamber: Option<String>,
+	/// SQLite database to save tests in, used by default when given
+	#[argh(option)]
+	pub db: Option<std::path::PathBuf>,
+	/// TOML registry of the batteries, battery-registry.toml in the output directory by default
+	#[argh(option)]
+	pub registry: Option<std::path::PathBuf>,
+	/// directory a test moves to when the output directory can't be written, e.g. the disk is full
+	#[argh(option)]
+	pub fallback_dir: Option<std::path::PathBuf>,
+	/// how tests are saved: tsv, csv, jsonl, or sqlite (needs --db)
+	#[argh(option)]
+	pub format: Option<OutputFormat>,
+	/// TOML config of the columns, units, and precision in saved files
+	#[argh(option)]
+	pub columns: Option<std::path::PathBuf>,
+	/// when samples are written out: every N samples (10 by default), every N seconds (5s),
+	/// or sync to sync each one to disk
+	#[argh(option)]
+	pub flush: Option<files::FlushPolicy>,
+	/// start a new file for the test every N hours, for tests lasting days
+	#[argh(option)]
+	pub rotate_hours: Option<u64>,
+	/// start a new file for the test once it's N megabytes
+	#[argh(option)]
+	pub rotate_mb: Option<u64>,
+	/// what new files are called, with {year}, {index}, {local} (local time), {utc}, and
+	/// {seq} (a number making the name unique). {year}-{index}-{local} by default.
+	#[argh(option)]
+	pub file_name: Option<files::FileNameTemplate>,
+	/// when a test has reached the cutoff: N measurements in a row at or under it (1 by
+	/// default), or the average over N seconds (30s). The battery interface's own cutoff is
+	/// put 300 mV lower when it's more than the first measurement.
+	#[argh(option)]
+	pub terminate: Option<termination::TerminationRule>,
+	/// start tests without the short load pulse estimating the battery's internal resistance
+	#[argh(switch)]
+	pub no_ir_pulse: bool,
+	/// time under the load before a test is recorded, so connecting the load doesn't count:
+	/// N seconds (30s), or until the current settles (settled). Recorded from the start by
+	/// default.
+	#[argh(option)]
+	pub warmup: Option<warmup::WarmupRule>,
+	/// seconds to rest the battery with the load off for its open circuit voltage, before
+	/// the load is turned on and again after the test ends on its own. Not measured by
+	/// default.
+	#[argh(option)]
+	pub ocv_rest_s: Option<u64>,
+	/// seconds between the lines printed and the readings sent to `watch` while measuring,
+	/// with the lowest and highest in between; every measurement is still saved. 5 by
+	/// default, 0 for every measurement.
+	#[argh(option)]
+	pub live_every_s: Option<u64>,
+	/// ms between the commands sent to each battery interface, 100 to 5000, 500 by default.
+	/// The battery interface turns the load off after two and a half of them without one.
+	#[argh(option)]
+	pub poll_ms: Option<u16>,
+	/// baud the serial ports are opened at, 230400 by default. The battery interface's
+	/// firmware has to be built for the same one, its own USB port ignores it.
+	#[argh(option)]
+	pub baud: Option<u32>,
+	/// record every event and mode change to this file
+	#[argh(option)]
+	pub trace: Option<std::path::PathBuf>,
+	/// copy every byte to and from each battery interface to this file, with the time and
+	/// channel, for `battery-tester-client decode-dump`
+	#[argh(option)]
+	pub dump_serial: Option<std::path::PathBuf>,
+	/// TOML config of the webhook and/or desktop notifications sent when a test ends or faults
+	#[argh(option)]
+	pub notify: Option<std::path::PathBuf>,
+	/// name clients pick this server by (letters, digits, - and _), to run more than one
+	#[argh(option)]
+	pub name: Option<String>,
+	/// also take commands over TCP on this address (e.g. 0.0.0.0:7878), for clients on other
+	/// machines. Without a --token-file anyone who can reach the port can run the tester.
+	#[argh(option)]
+	pub listen: Option<std::net::SocketAddr>,
+	/// who on this machine can connect: owner (the default), group, or everyone. Windows
+	/// doesn't have a group, it's the same as owner there.
+	#[argh(option)]
+	pub ipc_access: Option<ipc::IpcAccess>,
+	/// file with a shared secret clients have to send with commands that change anything,
+	/// given to them with their own --token-file. Status and readings don't need it.
+	#[argh(option)]
+	pub token_file: Option<std::path::PathBuf>,
+	/// battery interfaces to test with at once, each channel saves to its own channel-N
+	/// subdirectory when there's more than one. The profile, chamber, signal port, kiosk,
+	/// and trace are channel 0's.
+	#[argh(option, default = "1")]
+	pub channels: u8,
+	/// test against a simulated battery interface instead of a serial port, any device name
+	/// connects to it
+	#[argh(switch)]
+	pub simulate: bool,
+	/// TOML config of the simulated battery and faults to inject, implies --simulate
+	#[argh(option)]
+	pub sim_config: Option<std::path::PathBuf>,
+	/// feed a recorded trace through the state machine instead of running the tester,
+	/// pass the --profile, --terminate, and --no-ir-pulse the trace was recorded with
+	#[argh(option)]
+	pub replay: Option<std::path::PathBuf>,
+	/// run as a kiosk appliance with this config file, controlled from its web dashboard.
+	/// Needs a --token-file, the dashboard asks for the token before its first command.
+	#[cfg(feature = "kiosk")]
+	#[argh(option)]
+	pub kiosk: Option<std::path::PathBuf>,
+	#[argh(subcommand)]
+	pub cmd: Option<ServerSubcommands>,
+}
+
+#[derive(FromArgs, PartialEq, Eq, Clone)]
+#[argh(subcommand)]
+pub enum ServerSubcommands {
+	Demo(DemoCli),
+}
+
+#[derive(FromArgs, PartialEq, Eq, Clone)]
+/// run a short test against a simulated battery interface, printing everything the server does
+#[argh(subcommand, name = "demo")]
+pub struct DemoCli {
+	/// where to save the test, a new directory in the system's temporary directory by default
+	#[argh(option)]
+	pub output_directory: Option<std::path::PathBuf>,
+	/// TOML config of the simulated battery and faults to inject
+	#[argh(option)]
+	pub sim_config: Option<std::path::PathBuf>,
 }
 
 #[derive(Debug, Error)]
 pub enum Error {
 	#[error("given output directory: {0:?} isn't a directory (folder)")]
 	OutputPathIsDir(Box<std::path::Path>),
+	#[error("can't read test profile: {0:?}")]
+	ProfileRead(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("invalid test profile: {0:?}\n{1}")]
+	ProfileParse(Box<std::path::Path>, #[source] toml::de::Error),
+	#[error("test profile has chamber steps but no --chamber was given")]
+	ChamberRequired,
+	#[error("can't connect to the environmental chamber:\n{0}")]
+	Chamber(#[source] chamber::ChamberError),
+	#[error("can't read columns config: {0:?}")]
+	ColumnsRead(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("invalid columns config: {0:?}\n{1}")]
+	ColumnsParse(Box<std::path::Path>, #[source] toml::de::Error),
+	#[error("the soc column needs the battery's capacity_mah")]
+	SocNeedsCapacity,
+	#[error("can't read notify config: {0:?}")]
+	NotifyRead(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("invalid notify config: {0:?}\n{1}")]
+	NotifyParse(Box<std::path::Path>, #[source] toml::de::Error),
+	#[error("webhooks have to be http://host[:port]/path, not: {0:?}")]
+	BadWebhook(Box<str>),
+	#[error("--channels must be at least 1")]
+	NoChannels,
+	#[error("server names can only have letters, digits, - and _, not: {0:?}")]
+	BadServerName(Box<str>),
+	#[error("can't listen for clients on {0}")]
+	Listen(std::net::SocketAddr, #[source] std::io::Error),
+	#[error("can't lock {0:?} to check for another server")]
+	InstanceLock(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("a server with this --name is already running as pid {1}, it holds {0:?}")]
+	AlreadyRunning(Box<std::path::Path>, Box<str>),
+	#[error("can't read the token: {0:?}")]
+	TokenRead(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("the token file is empty: {0:?}")]
+	EmptyToken(Box<std::path::Path>),
+	#[error("no channel {0}")]
+	NoSuchChannel(ChannelId),
+	#[error("the engine has shut down")]
+	EngineStopped,
+	#[error("can't create channel output directory: {0:?}")]
+	ChannelOutput(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("--format sqlite needs a --db to save tests in")]
+	DatabaseRequired,
+	#[error("can't open results database: {0:?}\n{1}")]
+	Database(Box<std::path::Path>, #[source] rusqlite::Error),
+	#[error("can't read simulator config: {0:?}")]
+	SimConfigRead(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("invalid simulator config: {0:?}\n{1}")]
+	SimConfigParse(Box<std::path::Path>, #[source] toml::de::Error),
+	#[error("invalid simulator config: {0:?}, {1}")]
+	SimConfigInvalid(Box<std::path::Path>, &'static str),
+	#[error("no output directory given to save tests in")]
+	NoOutputDirectory,
+	#[error("can't create demo output directory: {0:?}")]
+	DemoOutput(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("can't read test journal: {0:?}")]
+	JournalRead(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("invalid test journal: {0:?}, remove it to start without resuming\n{1}")]
+	JournalParse(Box<std::path::Path>, #[source] toml::de::Error),
+	#[error("can't read the battery registry: {0:?}")]
+	RegistryRead(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("invalid battery registry: {0:?}\n{1}")]
+	RegistryParse(Box<std::path::Path>, #[source] toml::de::Error),
+	#[error("can't read the calibrations: {0:?}")]
+	CalibrationRead(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("invalid calibrations: {0:?}\n{1}")]
+	CalibrationParse(Box<std::path::Path>, #[source] toml::de::Error),
+	#[error("can't create trace file: {0:?}")]
+	TraceCreate(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("can't read trace: {0:?}")]
+	TraceRead(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("invalid trace: {0:?}\n{1}")]
+	TraceParse(Box<std::path::Path>, #[source] postcard::Error),
+	#[error("replay diverged at mode change {0}, recorded: {1:?}, replayed: {2:?}")]
+	ReplayDiverged(usize, Mode, Mode),
+	#[error("can't create serial dump: {0:?}")]
+	DumpCreate(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("can't read serial dump: {0:?}")]
+	DumpRead(Box<std::path::Path>, #[source] std::io::Error),
+	#[error("invalid serial dump: {0:?}\n{1}")]
+	DumpParse(Box<std::path::Path>, #[source] postcard::Error),
+	#[error("the battery interface can't run at {0} baud, it takes one of: {BAUDS:?}")]
+	UnsupportedBaud(u32),
+	#[cfg(feature = "kiosk")]
+	#[error("can't read kiosk config: {0:?}")]
+	KioskConfigRead(Box<std::path::Path>, #[source] std::io::Error),
+	#[cfg(feature = "kiosk")]
+	#[error("invalid kiosk config: {0:?}\n{1}")]
+	KioskConfigParse(Box<std::path::Path>, #[source] toml::de::Error),
+	#[cfg(feature = "kiosk")]
+	#[error("can't write kiosk config: {0:?}")]
+	KioskConfigWrite(Box<std::path::Path>, #[source] std::io::Error),
+	#[cfg(feature = "kiosk")]
+	#[error("the kiosk's dashboard needs a --token-file to check its commands against")]
+	KioskToken,
+}
+
+/// Why a server task stopped before it was shut down,
+/// the supervisor in [`engine`] shuts the server down when one does
+#[derive(Debug, Error)]
+pub enum TaskError {
+	#[error("a task it sends to has stopped")]
+	Disconnected,
+	#[error("can't take client connections:\n{0}")]
+	Ipc(#[source] std::io::Error),
+	#[error("panicked")]
+	Panicked,
+}
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for TaskError {
+	fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self {
+		TaskError::Disconnected
+	}
+}
+
+impl From<tokio::sync::oneshot::error::RecvError> for TaskError {
+	fn from(_: tokio::sync::oneshot::error::RecvError) -> Self {
+		TaskError::Disconnected
+	}
 }
 
-#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
 pub enum Mode {
 	#[default]
 	/// Wait for device ID, batt ID, BI replies start
@@ -136,36 +671,145 @@ pub enum Mode {
 	WaitForBattery,
 	/// Wait for user to send start command
 	WaitForUsrStart,
+	/// Waiting for the environmental chamber to reach a profile step's temperature
+	Conditioning,
+	/// Watching a charger fill the battery, the test starts when it's full
+	Charging,
 	/// Testing, waiting for voltage <= cutoff
 	Testing,
 	/// User paused test
 	Paused,
 	/// User shutdown server
 	Shutdown,
+	/// Test ended on its own, resting the battery with the load off for its open circuit voltage
+	Resting,
 	/// Test ended
 	EndTest,
 	/// Serial comms not working
 	CommDC,
+	/// The battery interface is running the test on its own, until its log is saved
+	Autonomous,
 	Fault,
+	/// A test was interrupted by the server stopping, waiting for the user to resume it
+	Resume,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct TestState {
 	cutoff: MilliVolt,
+	chemistry: chemistry::Chemistry,
+	/// Under this the battery was removed, `None` for the chemistry's, kept from test to test
+	disconnect: Option<MilliVolt>,
+	/// Over this the battery isn't tested, `None` for the chemistry's, kept from test to test
+	max_voltage: Option<MilliVolt>,
 	battery_id: Option<BatteryID>,
 	device_name: Option<Box<str>>,
+	/// Set with the device name, `None` for the server's `--baud`
+	device_baud: Option<u32>,
 	first_reply: bool,
 	allow_undercurrent: AllowUndercurrent,
+	device_version: Option<DeviceVersion>,
+	/// timestamp of the newest measurement this test
+	last_measurement_t: Option<u64>,
+	replies_without_measurement: u32,
+	output_format: OutputFormat,
+	/// Where this test is saved, and in what format
+	saved_to: Option<(files::SavedTo, OutputFormat)>,
+	/// Local time this test first started testing
+	started: Option<Box<str>>,
+	operator: Option<Box<str>>,
+	/// Notes for this test, or the next one before there's a test
+	notes: Vec<files::Note>,
+	termination: termination::Termination,
+	/// Why the test ended on its own, saved with its notes
+	end_reason: Option<termination::EndReason>,
+	/// Tests start with an internal resistance pulse
+	ir_pulse: bool,
+	/// The test's internal resistance pulse is done, or won't be done
+	pulsed: bool,
+	/// Estimated from the pulse, in milliohms
+	internal_resistance: Option<u32>,
+	/// Time under the load before tests are recorded, `None` to record from the start
+	warmup: Option<warmup::WarmupRule>,
+	/// The test's warmup is done, or won't be done
+	warmed_up: bool,
+	/// Battery interface timestamp (ms) of the end of the warmup, saved times start from it
+	warmup_end_dt: u64,
+	/// Time resting for the open circuit voltage, `None` to not measure it
+	ocv_rest: Option<std::time::Duration>,
+	/// The rest before the load is turned on is done, or won't be done
+	rested: bool,
+	/// ms between the commands the serial task sends
+	poll_ms: u16,
+	ocv_before: Option<MilliVolt>,
+	ocv_after: Option<MilliVolt>,
+	/// Batteries to test after this one
+	queue: std::collections::VecDeque<queue::QueuedTest>,
+	/// The channel's cutoff, while a queued battery's test has its own
+	channel_cutoff: Option<MilliVolt>,
+	/// The next battery in the queue is set up, the one tested before it hasn't been
+	/// disconnected yet
+	swap_pending: bool,
+	/// When the test starts on its own once the battery is connected
+	start_at: Option<std::time::SystemTime>,
+	/// What the load should draw, `None` for the firmware's own, kept from test to test
+	load_model: Option<LoadModel>,
+	/// Current to discharge at, `None` for the load's full current, kept from test to test
+	current_setpoint: Option<MilliAmp>,
+	/// Samples averaged into each measurement while testing, `None` for the firmware's,
+	/// kept from test to test
+	window_samples: Option<u8>,
+	/// How each measurement's samples are combined while testing, kept from test to test
+	filter: WindowFilter,
+	/// The battery interface took the test to run on its own, see [`Mode::Autonomous`]
+	autonomous: bool,
+	/// The battery interface's battery-present input, `None` until it's replied
+	bat_present: Option<bool>,
 }
 
 impl Default for TestState {
 	fn default() -> Self {
 		Self {
 			cutoff: DEFAULT_CUTOFF_MILLIV.into(),
+			chemistry: Default::default(),
+			disconnect: None,
+			max_voltage: None,
 			battery_id: Default::default(),
 			device_name: Default::default(),
+			device_baud: None,
 			first_reply: false,
 			allow_undercurrent: Default::default(),
+			device_version: None,
+			last_measurement_t: None,
+			replies_without_measurement: 0,
+			output_format: Default::default(),
+			saved_to: None,
+			started: None,
+			operator: None,
+			notes: Vec::new(),
+			termination: Default::default(),
+			end_reason: None,
+			ir_pulse: true,
+			pulsed: false,
+			internal_resistance: None,
+			warmup: None,
+			warmed_up: false,
+			warmup_end_dt: 0,
+			ocv_rest: None,
+			rested: false,
+			poll_ms: DEFAULT_POLL_MS,
+			ocv_before: None,
+			ocv_after: None,
+			queue: Default::default(),
+			channel_cutoff: None,
+			swap_pending: false,
+			start_at: None,
+			load_model: None,
+			current_setpoint: None,
+			window_samples: None,
+			filter: WindowFilter::default(),
+			autonomous: false,
+			bat_present: None,
 		}
 	}
 }
@@ -173,14 +817,380 @@ impl Default for TestState {
 impl TestState {
 	pub fn new_cutoff(&mut self, millivolts: MilliVolt) {
 		self.cutoff = millivolts;
+		self.channel_cutoff = None;
+	}
+
+	/// The cutoff goes back to the chemistry's
+	pub fn set_chemistry(&mut self, chemistry: chemistry::Chemistry) {
+		self.chemistry = chemistry;
+		self.cutoff = chemistry.limits().cutoff;
+		self.channel_cutoff = None;
+	}
+
+	pub fn change_queue(&mut self, change: queue::QueueChange) {
+		match change {
+			queue::QueueChange::Add(tests) => self.queue.extend(tests),
+			queue::QueueChange::Clear => self.queue.clear(),
+		}
+	}
+
+	pub fn queued(&self) -> usize {
+		self.queue.len()
+	}
+
+	/// Take the next battery off the queue, its cutoff is used until the test ends
+	pub fn next_queued(&mut self) -> Option<queue::QueuedTest> {
+		let next = self.queue.pop_front()?;
+		if let Some(cutoff) = next.cutoff {
+			self.channel_cutoff.get_or_insert(self.cutoff);
+			self.cutoff = cutoff;
+		}
+		Some(next)
+	}
+
+	/// Wait for the tested battery to be disconnected before taking the next one
+	pub fn set_swap_pending(&mut self) {
+		self.swap_pending = true;
+	}
+
+	pub fn swap_pending(&self) -> bool {
+		self.swap_pending
+	}
+
+	/// The battery interface ran, or is running, this test on its own
+	pub fn set_autonomous(&mut self) {
+		self.autonomous = true;
+	}
+
+	pub fn autonomous(&self) -> bool {
+		self.autonomous
+	}
+
+	pub fn battery_swapped(&mut self) {
+		self.swap_pending = false;
+	}
+
+	pub fn set_bat_present(&mut self, present: bool) {
+		self.bat_present = Some(present);
+	}
+
+	pub fn bat_present(&self) -> Option<bool> {
+		self.bat_present
+	}
+
+	/// `None` to start when the user says
+	pub fn set_start_at(&mut self, start_at: Option<std::time::SystemTime>) {
+		self.start_at = start_at;
+	}
+
+	pub fn start_at(&self) -> Option<std::time::SystemTime> {
+		self.start_at
+	}
+
+	pub fn chemistry(&self) -> chemistry::Chemistry {
+		self.chemistry
+	}
+
+	/// The chemistry's limits, with the cutoff, disconnect threshold, and max voltage
+	/// the user set
+	pub fn limits(&self) -> chemistry::Limits {
+		let limits = self.chemistry.limits();
+		chemistry::Limits {
+			cutoff: self.cutoff,
+			disconnect: self.disconnect.unwrap_or(limits.disconnect),
+			max_voltage: self.max_voltage.unwrap_or(limits.max_voltage),
+			..limits
+		}
+	}
+
+	pub fn set_disconnect(&mut self, millivolts: MilliVolt) {
+		self.disconnect = Some(millivolts);
+	}
+
+	pub fn set_max_voltage(&mut self, millivolts: MilliVolt) {
+		self.max_voltage = Some(millivolts);
+	}
+
+	pub fn set_termination_rule(&mut self, rule: termination::TerminationRule) {
+		self.termination.set_rule(rule);
+	}
+
+	/// Longest to test for, kept from test to test
+	pub fn set_max_duration(&mut self, max_duration: Option<std::time::Duration>) {
+		self.termination.set_max_duration(max_duration);
+	}
+
+	/// Most to take out of the battery, kept from test to test
+	pub fn set_max_mah(&mut self, max_mah: Option<u32>) {
+		self.termination.set_max_mah(max_mah);
+	}
+
+	pub fn set_load_model(&mut self, load_model: Option<LoadModel>) {
+		self.load_model = load_model;
+	}
+
+	pub fn load_model(&self) -> Option<LoadModel> {
+		self.load_model
+	}
+
+	pub fn set_current_setpoint(&mut self, current_setpoint: Option<MilliAmp>) {
+		self.current_setpoint = current_setpoint;
+	}
+
+	pub fn set_window_samples(&mut self, window_samples: Option<u8>) {
+		self.window_samples = window_samples;
+	}
+
+	pub fn set_filter(&mut self, filter: WindowFilter) {
+		self.filter = filter;
+	}
+
+	/// What the battery interface is told while testing, with the load on
+	pub fn testing_command(&self) -> ControlWord {
+		testing_command(
+			self.allow_undercurrent,
+			self.backstop_cutoff(),
+			self.load_model,
+			self.current_setpoint,
+			self.limits().max_voltage,
+			self.window_samples,
+			self.filter,
+		)
+	}
+
+	/// What the battery interface runs when it tests on its own, it can't debounce the cutoff
+	/// or take a profile
+	pub fn autonomous_test(&self) -> AutonomousTest {
+		AutonomousTest {
+			cutoff: self.cutoff,
+			allow_undercurrent: self.allow_undercurrent,
+			load_model: self.load_model,
+			current_setpoint: self.current_setpoint,
+			max_duration_s: self
+				.termination
+				.max_duration()
+				.map(|max| u32::try_from(max.as_secs()).unwrap_or(u32::MAX)),
+			max_voltage: Some(self.limits().max_voltage),
+			window_samples: self.window_samples,
+			filter: self.filter,
+		}
+	}
+
+	/// Where the battery interface turns off the load on its own, under the cutoff when
+	/// the PC debounces it
+	pub fn backstop_cutoff(&self) -> MilliVolt {
+		self.termination.backstop(self.cutoff)
+	}
+
+	/// Take in a fresh measurement while testing, `Some` once the test should end
+	pub fn reached_end(&mut self, measurement: &Measurement) -> Option<termination::EndReason> {
+		self.termination.reached(measurement, self.cutoff)
+	}
+
+	pub fn set_end_reason(&mut self, reason: termination::EndReason) {
+		self.end_reason = Some(reason);
+	}
+
+	pub fn set_ir_pulse(&mut self, ir_pulse: bool) {
+		self.ir_pulse = ir_pulse;
+	}
+
+	/// The test hasn't had its internal resistance pulse yet
+	pub fn pulse_due(&self) -> bool {
+		self.ir_pulse && !self.pulsed
+	}
+
+	/// The pulse is done, with the estimate if there is one
+	pub fn set_internal_resistance(&mut self, milliohms: Option<u32>) {
+		self.pulsed = true;
+		self.internal_resistance = milliohms;
+	}
+
+	pub fn set_warmup(&mut self, warmup: Option<warmup::WarmupRule>) {
+		self.warmup = warmup;
+	}
+
+	/// The test hasn't warmed up yet
+	pub fn warmup_due(&self) -> Option<warmup::WarmupRule> {
+		self.warmup.filter(|_| !self.warmed_up)
+	}
+
+	/// Record from the measurement at `dt` on, as time 0
+	pub fn set_warmed_up(&mut self, dt: u64) {
+		self.warmed_up = true;
+		self.warmup_end_dt = dt;
+	}
+
+	/// Battery interface timestamp as it's saved, from the end of the warmup
+	pub fn recorded_dt(&self, dt: u64) -> u64 {
+		dt.saturating_sub(self.warmup_end_dt)
+	}
+
+	pub fn set_ocv_rest(&mut self, rest: Option<std::time::Duration>) {
+		self.ocv_rest = rest;
+	}
+
+	/// How often the serial task sends commands, see [`crate::engine::EngineBuilder::poll_ms`]
+	pub fn set_poll_ms(&mut self, poll_ms: u16) {
+		self.poll_ms = poll_ms;
+	}
+
+	/// The test hasn't rested before turning on the load yet
+	pub fn ocv_rest_due(&self) -> Option<std::time::Duration> {
+		self.ocv_rest.filter(|_| !self.rested)
+	}
+
+	/// The rest before the load is turned on is done, with the voltage if there is one
+	pub fn set_ocv_before(&mut self, ocv: Option<MilliVolt>) {
+		self.rested = true;
+		self.ocv_before = ocv;
+	}
+
+	/// The test ended on its own and hasn't rested after yet
+	pub fn ocv_rest_after(&self) -> Option<std::time::Duration> {
+		self.ocv_rest
+			// there's nothing to rest once the battery is gone
+			.filter(|_| {
+				self.end_reason
+					.is_some_and(|reason| reason != termination::EndReason::Removed)
+					&& self.ocv_after.is_none()
+			})
+	}
+
+	pub fn set_ocv_after(&mut self, ocv: MilliVolt) {
+		self.ocv_after = Some(ocv);
+	}
+
+	pub fn reset_termination(&mut self) {
+		self.termination.reset();
+	}
+
+	pub fn set_output_format(&mut self, format: OutputFormat) {
+		self.output_format = format;
+	}
+
+	pub fn output_format(&self) -> OutputFormat {
+		self.output_format
 	}
 
 	pub fn new_batt_id(&mut self, battery_id: BatteryID) {
 		self.battery_id = Some(battery_id)
 	}
 
-	pub fn new_device_name(&mut self, device_name: Box<str>) {
-		self.device_name = Some(device_name)
+	pub fn set_saved_to(&mut self, saved_to: files::SavedTo, format: OutputFormat) {
+		self.saved_to = Some((saved_to, format));
+	}
+
+	/// What to journal now that the test is testing, `None` until it's saved somewhere
+	pub fn journal(&mut self) -> Option<journal::Journal> {
+		let battery_id = self.battery_id?;
+		let (saved_to, format) = self.saved_to.clone()?;
+		let started = self.start_testing().into();
+		Some(journal::Journal {
+			battery_id,
+			cutoff: self.cutoff,
+			chemistry: self.chemistry,
+			allow_undercurrent: self.allow_undercurrent,
+			device_name: self.device_name.clone(),
+			device_baud: self.device_baud,
+			format,
+			saved_to,
+			started,
+			operator: self.operator.clone(),
+			notes: self.notes.clone(),
+			max_duration_s: self.termination.max_duration().map(|d| d.as_secs()),
+			max_mah: self.termination.max_mah(),
+			internal_resistance_mohm: self.internal_resistance,
+			ocv_before_mv: self.ocv_before.map(u16::from),
+		})
+	}
+
+	/// Pick up an interrupted test from its journal
+	pub fn resume(&mut self, journal: journal::Journal) {
+		self.battery_id = Some(journal.battery_id);
+		self.cutoff = journal.cutoff;
+		self.chemistry = journal.chemistry;
+		self.allow_undercurrent = journal.allow_undercurrent;
+		self.device_name = journal.device_name;
+		self.device_baud = journal.device_baud;
+		self.saved_to = Some((journal.saved_to, journal.format));
+		self.started = Some(journal.started);
+		self.operator = journal.operator;
+		self.notes = journal.notes;
+		self.set_max_duration(journal.max_duration_s.map(std::time::Duration::from_secs));
+		self.set_max_mah(journal.max_mah);
+		// the battery's been discharged since, a pulse now wouldn't be the test's
+		self.set_internal_resistance(journal.internal_resistance_mohm);
+		// it was recorded from the end of its warmup, the battery interface's clock restarted since
+		self.set_warmed_up(0);
+		self.set_ocv_before(journal.ocv_before_mv.map(MilliVolt::new));
+	}
+
+	pub fn add_note(&mut self, text: Box<str>) {
+		self.notes.push(files::Note {
+			time: chrono::Local::now().to_rfc3339().into_boxed_str(),
+			text,
+		});
+	}
+
+	pub fn set_operator(&mut self, operator: Box<str>) {
+		self.operator = Some(operator);
+	}
+
+	/// What to save with the open test, `None` when there's no test or nothing to save
+	pub fn notes(&self) -> Option<files::TestNotes> {
+		let battery_id = self.battery_id?;
+		self.saved_to.as_ref()?;
+		if self.operator.is_none()
+			&& self.notes.is_empty()
+			&& self.end_reason.is_none()
+			&& self.internal_resistance.is_none()
+			&& self.ocv_before.is_none()
+			&& self.device_version.is_none()
+		{
+			return None;
+		}
+		Some(files::TestNotes {
+			battery_id,
+			started: self.started.clone(),
+			operator: self.operator.clone(),
+			notes: self.notes.clone(),
+			ended: self.end_reason,
+			internal_resistance_mohm: self.internal_resistance,
+			ocv_before_mv: self.ocv_before.map(u16::from),
+			ocv_after_mv: self.ocv_after.map(u16::from),
+			device_id: self.device_version.map(|v| v.hex_device_id()),
+			firmware: self.device_version.map(|v| v.firmware),
+		})
+	}
+
+	/// The test is testing, the local time it first did is kept until it ends
+	pub fn start_testing(&mut self) -> &str {
+		self.started
+			.get_or_insert_with(|| chrono::Local::now().to_rfc3339().into_boxed_str())
+	}
+
+	/// The test got as far as testing
+	pub fn tested(&self) -> bool {
+		self.started.is_some()
+	}
+
+	pub fn saved_to(&self) -> Option<&(files::SavedTo, OutputFormat)> {
+		self.saved_to.as_ref()
+	}
+
+	pub fn new_device_name(&mut self, device_name: Box<str>, baud: Option<u32>) {
+		self.device_name = Some(device_name);
+		self.device_baud = baud;
+	}
+
+	pub fn device_name(&self) -> Option<&str> {
+		self.device_name.as_deref()
+	}
+
+	/// The battery interface's device ID in hex, `None` until it's answered the handshake
+	pub fn device_id(&self) -> Option<Box<str>> {
+		self.device_version.map(|v| v.hex_device_id())
 	}
 
 	pub fn set_first_reply(&mut self) {
@@ -205,11 +1215,86 @@ impl TestState {
 
 	pub fn end_test(&mut self) {
 		self.battery_id = None;
+		self.saved_to = None;
+		self.started = None;
+		self.notes.clear();
+		self.termination.new_test();
+		self.end_reason = None;
+		self.pulsed = false;
+		self.internal_resistance = None;
+		self.warmed_up = false;
+		self.warmup_end_dt = 0;
+		self.rested = false;
+		self.ocv_before = None;
+		self.ocv_after = None;
 		self.first_reply = false;
+		self.swap_pending = false;
+		self.start_at = None;
+		self.autonomous = false;
+		if let Some(cutoff) = self.channel_cutoff.take() {
+			self.cutoff = cutoff;
+		}
 	}
 
 	pub fn ready_for_battery(&self) -> bool {
-		self.battery_id.is_some() && self.first_reply && self.device_name.is_some()
+		self.battery_id.is_some()
+			&& self.first_reply
+			&& self.device_name.is_some()
+			&& self.device_compatible()
+	}
+
+	pub fn new_device_version(&mut self, device_version: DeviceVersion) {
+		self.device_version = Some(device_version)
+	}
+
+	/// Forget the version of a device we lost the connection to
+	pub fn unset_device_version(&mut self) {
+		self.device_version = None
+	}
+
+	/// Start over tracking measurement timestamps
+	pub fn reset_staleness(&mut self) {
+		self.last_measurement_t = None;
+		self.replies_without_measurement = 0;
+	}
+
+	/// Check that the measurement in a reply is newer than the ones before it
+	pub fn check_staleness(&mut self, measurement: Option<&Measurement>) -> Staleness {
+		let staleness = match measurement {
+			Some(m)
+				if self
+					.last_measurement_t
+					.is_some_and(|t| m.sample_start_ms <= t) =>
+			{
+				Staleness::Repeated
+			}
+			Some(m) => {
+				self.last_measurement_t = Some(m.sample_start_ms);
+				self.replies_without_measurement = 0;
+				return Staleness::Fresh;
+			}
+			None => Staleness::Waiting,
+		};
+		self.replies_without_measurement += 1;
+		// longer windows take longer to fill
+		let windows = self
+			.window_samples
+			.map_or(1, |samples| u32::from(samples).div_ceil(WINDOW_SAMPLES))
+			.max(1);
+		// and more replies come in the same time at a quicker poll rate
+		let limit =
+			STALE_REPLY_LIMIT * windows * u32::from(DEFAULT_POLL_MS) / u32::from(self.poll_ms);
+		if self.replies_without_measurement > limit.max(STALE_REPLY_LIMIT) {
+			Staleness::Stalled
+		} else {
+			staleness
+		}
+	}
+
+	/// The connected firmware answered the handshake with a protocol we speak
+	pub fn device_compatible(&self) -> bool {
+		self.device_version
+			.is_some_and(|v| protocol_compatible(v.protocol))
 	}
 
 	pub fn get_allow_undercurrent(&self) -> AllowUndercurrent {
@@ -218,13 +1303,575 @@ impl TestState {
 	pub fn set_allow_undercurrent(&mut self, allow_undercurrent: AllowUndercurrent) {
 		self.allow_undercurrent = allow_undercurrent
 	}
+
+	pub fn status(&self, mode: Mode) -> ServerStatus {
+		ServerStatus {
+			mode,
+			battery_id: self.battery_id,
+			cutoff: self.cutoff,
+			chemistry: self.chemistry,
+			disconnect: self.limits().disconnect,
+			max_voltage: self.limits().max_voltage,
+			max_duration: self.termination.max_duration(),
+			max_mah: self.termination.max_mah(),
+			device_name: self.device_name.clone(),
+			device_version: self.device_version,
+			output_format: self.output_format,
+			queue: self.queue.iter().copied().collect(),
+			start_at: self.start_at,
+			load_model: self.load_model,
+			current_setpoint: self.current_setpoint,
+			window_samples: self.window_samples,
+			filter: self.filter,
+		}
+	}
+}
+
+/// Read only views of the running server for status reports and dashboards
+#[derive(Debug, Clone)]
+pub struct StatusWatch {
+	pub server: watch::Receiver<ServerStatus>,
+	pub link: watch::Receiver<LinkStats>,
+	pub measurement: watch::Receiver<Option<Measurement>>,
+	/// Updated every `--live-every-s`, what subscribers and the console are sent
+	pub live: watch::Receiver<Option<live::LiveSummary>>,
+	/// Reported by the battery interface each time it connects
+	pub trim: watch::Receiver<Option<Trim>>,
+	/// Reported by the battery interface each time it connects
+	pub battery_detect: watch::Receiver<Option<BatteryDetect>>,
+	/// Read by the battery interface's input with every reply, `None` before the first
+	pub bat_present: watch::Receiver<Option<bool>>,
+	/// Sent with every reply the same way
+	pub load: watch::Receiver<Option<LoadState>>,
+	/// Read from the battery interface's flash each time it connects
+	pub fault_log: watch::Receiver<Vec<LoggedFault>>,
+	/// The battery interface's last self test
+	pub self_test: watch::Receiver<Option<SelfTestReport>>,
+	/// Optional features this server was built or started with, fixed for its lifetime
+	pub features: std::sync::Arc<[Feature]>,
+	/// Formats tests can be saved in, [`OutputFormat::Sqlite`] needs `--db`
+	pub output_formats: std::sync::Arc<[OutputFormat]>,
+}
+
+impl StatusWatch {
+	pub fn capabilities(&self) -> Capabilities {
+		let firmware = self
+			.server
+			.borrow()
+			.device_version
+			.map(|version| FirmwareCapabilities {
+				protocol: version.protocol,
+				firmware: version.firmware,
+				temperature: (*self.measurement.borrow()).map(|m| m.temp_centi_c.is_some()),
+			});
+		Capabilities {
+			server_version: env!("CARGO_PKG_VERSION").into(),
+			protocol: PROTOCOL_VERSION,
+			features: self.features.to_vec(),
+			output_formats: self.output_formats.to_vec(),
+			firmware,
+		}
+	}
+
+	pub fn status(&self, channel: ChannelId) -> ChannelStatus {
+		ChannelStatus {
+			channel,
+			server: self.server.borrow().clone(),
+			measurement: *self.measurement.borrow(),
+			link: *self.link.borrow(),
+			trim: *self.trim.borrow(),
+			battery_detect: *self.battery_detect.borrow(),
+			bat_present: *self.bat_present.borrow(),
+			load: *self.load.borrow(),
+			fault_log: self.fault_log.borrow().clone(),
+			self_test: *self.self_test.borrow(),
+		}
+	}
+
+	pub fn reading(&self) -> Reading {
+		let server = self.server.borrow();
+		Reading {
+			mode: server.mode,
+			battery_id: server.battery_id,
+			cutoff: server.cutoff,
+			measurement: *self.measurement.borrow(),
+			live: *self.live.borrow(),
+		}
+	}
+}
+
+#[cfg(test)]
+impl StatusWatch {
+	/// A channel that stays as `server` and `link` say
+	pub(crate) fn fixed(server: ServerStatus, link: LinkStats) -> Self {
+		Self {
+			server: watch::channel(server).1,
+			link: watch::channel(link).1,
+			measurement: watch::channel(None).1,
+			live: watch::channel(None).1,
+			trim: watch::channel(None).1,
+			battery_detect: watch::channel(None).1,
+			bat_present: watch::channel(None).1,
+			load: watch::channel(None).1,
+			fault_log: watch::channel(Vec::new()).1,
+			self_test: watch::channel(None).1,
+			features: std::sync::Arc::new([]),
+			output_formats: std::sync::Arc::new([]),
+		}
+	}
+}
+
+/// Reply to [`ServerCmd::GetReading`] and [`ServerCmd::SubscribeReadings`],
+/// what a client watching the test needs
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct Reading {
+	pub mode: Mode,
+	pub battery_id: Option<BatteryID>,
+	pub cutoff: MilliVolt,
+	/// Latest from the battery interface, `None` before the first one
+	pub measurement: Option<Measurement>,
+	/// The lowest and highest since the last reading, `None` before the first
+	#[serde(default)]
+	pub live: Option<live::LiveSummary>,
+}
+
+/// Reply to [`ServerCmd::GetLastMeasurement`], printed as JSON for scripts
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct LastMeasurement {
+	pub channel: ChannelId,
+	/// `None` before the first one
+	pub measurement: Option<Measurement>,
+}
+
+/// Reply to [`ServerCmd::GetMode`], printed as JSON for scripts
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct CurrentMode {
+	pub channel: ChannelId,
+	pub mode: Mode,
+}
+
+/// Reply to [`ServerCmd::Status`], one per channel reported on
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ChannelStatus {
+	pub channel: ChannelId,
+	pub server: ServerStatus,
+	/// `None` before the first one
+	pub measurement: Option<Measurement>,
+	pub link: LinkStats,
+	/// `None` until the battery interface reports it
+	#[serde(default)]
+	pub trim: Option<Trim>,
+	/// `None` until the battery interface reports it
+	#[serde(default)]
+	pub battery_detect: Option<BatteryDetect>,
+	/// What the battery interface's battery-present input last read, not debounced
+	#[serde(default)]
+	pub bat_present: Option<bool>,
+	/// What the battery interface has the load set to
+	#[serde(default)]
+	pub load: Option<LoadState>,
+	/// Oldest first, kept by the battery interface across restarts
+	#[serde(default)]
+	pub fault_log: Vec<LoggedFault>,
+	/// `None` until the battery interface has run one since it started
+	#[serde(default)]
+	pub self_test: Option<SelfTestReport>,
+}
+
+impl std::fmt::Display for ChannelStatus {
+	/// A line each, as the client prints it
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let server = &self.server;
+		write!(
+			f,
+			"mode: {:?}, battery: {:?}, chemistry: {}, cutoff: {} mV, removed below: {} mV, max voltage: {} mV, device: {:?}, output format: {:?}",
+			server.mode,
+			server.battery_id,
+			server.chemistry,
+			server.cutoff,
+			server.disconnect,
+			server.max_voltage,
+			server.device_name,
+			server.output_format
+		)?;
+		if let Some(max) = server.max_duration {
+			write!(
+				f,
+				"\ntests end after testing for: {} min",
+				max.as_secs() / 60
+			)?;
+		}
+		if let Some(max) = server.max_mah {
+			write!(f, "\ntests end after taking out: {max} mAh")?;
+		}
+		if let Some(model) = server.load_model {
+			write!(
+				f,
+				"\nload: {} mOhm plus {} mA, within {} mA",
+				model.milliohms, model.offset, model.max_deviation
+			)?;
+		}
+		if let Some(setpoint) = server.current_setpoint {
+			write!(f, "\nconstant current: {setpoint} mA")?;
+		}
+		if let Some(samples) = server.window_samples {
+			write!(f, "\nsamples averaged into each measurement: {samples}")?;
+		}
+		if server.filter == WindowFilter::TrimmedMean {
+			write!(f, "\neach measurement drops its highest and lowest samples")?;
+		}
+		if let Some(start_at) = server.start_at {
+			let start_at = chrono::DateTime::<chrono::Local>::from(start_at);
+			write!(
+				f,
+				"\ntest starts at: {}",
+				start_at.format("%Y-%m-%d %H:%M:%S")
+			)?;
+		}
+		if !server.queue.is_empty() {
+			write!(f, "\nqueue:")?;
+			for (i, queued) in server.queue.iter().enumerate() {
+				let sep = if i == 0 { " " } else { ", " };
+				write!(f, "{sep}{queued}")?;
+			}
+		}
+		if let Some(version) = server.device_version {
+			write!(
+				f,
+				"\nbattery interface: {}, firmware: {}, protocol: {}",
+				version.hex_device_id(),
+				version.firmware,
+				version.protocol
+			)?;
+		}
+		write!(f, "\nlast measurement: {:?}", self.measurement)?;
+		if let Some(trim) = self.trim {
+			write!(
+				f,
+				"\nbattery interface trim: mA x {} ppm {:+}, mV x {} ppm {:+}, PWM {:+} us",
+				trim.milliamps_ppm,
+				trim.milliamps_offset,
+				trim.millivolts_ppm,
+				trim.millivolts_offset,
+				trim.pwm_us
+			)?;
+		}
+		if let Some(detect) = self.battery_detect {
+			let polarity = match detect.polarity {
+				Polarity::ActiveHigh => "high",
+				Polarity::ActiveLow => "low",
+			};
+			write!(
+				f,
+				"\nbattery present when its input is {polarity}, for {} ms",
+				detect.debounce_ms
+			)?;
+		}
+		if let Some(present) = self.bat_present {
+			let present = if present { "yes" } else { "no" };
+			write!(f, "\nbattery present: {present}")?;
+		}
+		if let Some(load) = self.load {
+			write!(f, "\nload: {load:?}")?;
+		}
+		for logged in &self.fault_log {
+			write!(
+				f,
+				"\nlogged fault: {:?}, {} ms after the battery interface started",
+				logged.fault.kind, logged.fault.time
+			)?;
+			if let Some(snapshot) = logged.snapshot {
+				write!(f, ", at {} mV, {} mA", snapshot.vbat, snapshot.ibat)?;
+			}
+		}
+		if let Some(report) = self.self_test {
+			let result = if report.passed() { "passed" } else { "failed" };
+			write!(f, "\nself test {result}: {report:?}")?;
+		}
+		write!(f, "\nserial link: {}", self.link)
+	}
+}
+
+/// Reply to the commands that don't have anything else to say
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Ack {
+	pub channel: ChannelId,
+	/// Why the server didn't take the command, `None` once it's passed on
+	pub error: Option<Box<str>>,
+}
+
+/// Reply to [`ServerCmd::GetCapabilities`], lets clients and dashboards
+/// show only what this server can do
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Capabilities {
+	pub server_version: Box<str>,
+	/// Battery interface protocol the server speaks
+	pub protocol: u16,
+	pub features: Vec<Feature>,
+	pub output_formats: Vec<OutputFormat>,
+	/// `None` until a battery interface answers the version handshake
+	pub firmware: Option<FirmwareCapabilities>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+	/// A test profile was loaded
+	Profile,
+	/// An environmental chamber is connected for profile steps
+	Chamber,
+	/// DTR/RTS test state lines on a serial port
+	SignalLines,
+	/// Running as a kiosk with a web dashboard
+	Kiosk,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+	/// Tab separated values, one file per test
+	#[default]
+	Tsv,
+	/// Comma separated values, one file per test
+	Csv,
+	/// A JSON object per sample, one file per test
+	JsonLines,
+	/// Every test in one SQLite database, `--db`
+	Sqlite,
+}
+
+impl std::str::FromStr for OutputFormat {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"tsv" => Ok(Self::Tsv),
+			"csv" => Ok(Self::Csv),
+			"jsonl" => Ok(Self::JsonLines),
+			"sqlite" => Ok(Self::Sqlite),
+			_ => Err(format!(
+				"unknown output format: {s}, expected tsv, csv, jsonl, or sqlite"
+			)),
+		}
+	}
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct FirmwareCapabilities {
+	pub protocol: u16,
+	pub firmware: FirmwareVersion,
+	/// Whether measurements include temperature, `None` before the first measurement
+	pub temperature: Option<bool>,
+}
+
+/// What the program task is doing, published each time the mode changes
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ServerStatus {
+	pub mode: Mode,
+	pub battery_id: Option<BatteryID>,
+	pub cutoff: MilliVolt,
+	pub chemistry: chemistry::Chemistry,
+	/// Under this the battery was removed, separate from the cutoff
+	#[serde(default)]
+	pub disconnect: MilliVolt,
+	/// Over this a test won't start
+	#[serde(default)]
+	pub max_voltage: MilliVolt,
+	/// Longest to test for, `None` until the cutoff
+	pub max_duration: Option<std::time::Duration>,
+	/// Most mAh to take out of the battery, `None` until the cutoff
+	pub max_mah: Option<u32>,
+	pub device_name: Option<Box<str>>,
+	pub device_version: Option<DeviceVersion>,
+	pub output_format: OutputFormat,
+	/// Batteries to test after this one, in order
+	pub queue: Vec<queue::QueuedTest>,
+	/// When the test starts on its own, `None` when the user starts it
+	pub start_at: Option<std::time::SystemTime>,
+	/// What the load should draw, `None` for the firmware's own
+	#[serde(default)]
+	pub load_model: Option<LoadModel>,
+	/// Current to discharge at, `None` for the load's full current
+	#[serde(default)]
+	pub current_setpoint: Option<MilliAmp>,
+	/// Samples averaged into each measurement while testing, `None` for the firmware's
+	#[serde(default)]
+	pub window_samples: Option<u8>,
+	/// How each measurement's samples are combined while testing
+	#[serde(default)]
+	pub filter: WindowFilter,
+}
+
+/// How far along a charge is
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ChargeState {
+	/// No charge current yet, the charger may not be on
+	Waiting,
+	Charging,
+	/// Charge current tapered off for [`CHARGE_FULL_MEASUREMENTS`]
+	Full,
+	/// Still not full after [`CHARGE_TIMEOUT_MEASUREMENTS`]
+	TimedOut,
+}
+
+/// Watches the charge current taper off as the charger finishes
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct ChargeMonitor {
+	charge_seen: bool,
+	tapered: u32,
+	/// Measurements since the charge current was first seen
+	charging_for: u32,
+}
+
+impl ChargeMonitor {
+	pub fn update(&mut self, measurement: &Measurement) -> ChargeState {
+		let charging = measurement.ibat < MilliAmp::new(-CHARGE_FULL_MILLIAMPS);
+		if self.charge_seen {
+			self.charging_for += 1;
+			if self.charging_for >= CHARGE_TIMEOUT_MEASUREMENTS {
+				return ChargeState::TimedOut;
+			}
+		}
+		if charging {
+			self.charge_seen = true;
+			self.tapered = 0;
+			return ChargeState::Charging;
+		}
+		if !self.charge_seen {
+			// a battery that isn't charging isn't full
+			return ChargeState::Waiting;
+		}
+		self.tapered += 1;
+		if self.tapered >= CHARGE_FULL_MEASUREMENTS {
+			ChargeState::Full
+		} else {
+			ChargeState::Charging
+		}
+	}
+}
+
+/// How the measurement in a reply compares to the ones before it
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Staleness {
+	/// Newer than the last measurement
+	Fresh,
+	/// No measurement in this reply, normal between averaging windows
+	Waiting,
+	/// Timestamp didn't advance, the same measurement was sent again
+	Repeated,
+	/// Replies keep coming but the measurements stopped
+	Stalled,
+}
+
+/// Index of one of the server's channels, each tests its own battery on its own battery interface
+pub type ChannelId = u8;
+
+/// Bumped whenever a [`Request`] or [`Reply`] changes, clients and servers on different
+/// versions tell each other instead of misreading what they're sent
+pub const IPC_VERSION: u16 = 1;
+
+/// What a client sends the server
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Request {
+	/// [`IPC_VERSION`] of the client
+	pub version: u16,
+	/// Picked by the client, the [`Reply`] has the same. Never `0`, see [`Reply::id`]
+	pub id: u32,
+	/// `None` for channel 0, commands for the whole server ignore it
+	pub channel: Option<ChannelId>,
+	pub cmd: ServerCmd,
+	/// The server's `--token-file`, without it a server started with one only answers queries
+	pub token: Option<Box<str>>,
+}
+
+impl Request {
+	/// With the next id of this client's
+	pub fn new(channel: Option<ChannelId>, cmd: ServerCmd, token: Option<Box<str>>) -> Self {
+		static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+		Self {
+			version: IPC_VERSION,
+			id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+			channel,
+			cmd,
+			token,
+		}
+	}
+}
+
+/// Everything the server sends back, an [`Ack`] or a reply of the request's own.
+/// A subscription's readings all have the id of the request that started it.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Reply<T> {
+	/// [`IPC_VERSION`] of the server
+	pub version: u16,
+	/// The [`Request`]'s, or `0` when the server couldn't read even that of one it rejects
+	pub id: u32,
+	/// `Err` when the request couldn't be read, e.g. from a client on another version
+	pub body: Result<T, Box<str>>,
+}
+
+/// The start of both a [`Request`] and a [`Reply`], the same in every version
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+pub struct EnvelopeHead {
+	pub version: u16,
+	pub id: u32,
+}
+
+/// An event for the supervisor to pass on to one channel's program task
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ChannelEvent {
+	pub channel: ChannelId,
+	pub event: Event,
+}
+
+/// Parts of the [`Trim`] to change, the rest stay as the battery interface reported them
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct TrimChange {
+	pub milliamps_ppm: Option<u32>,
+	pub milliamps_offset: Option<i16>,
+	pub millivolts_ppm: Option<u32>,
+	pub millivolts_offset: Option<i16>,
+	pub pwm_us: Option<u16>,
+}
+
+impl TrimChange {
+	pub fn apply(&self, trim: Trim) -> Trim {
+		Trim {
+			milliamps_ppm: self.milliamps_ppm.unwrap_or(trim.milliamps_ppm),
+			milliamps_offset: self.milliamps_offset.unwrap_or(trim.milliamps_offset),
+			millivolts_ppm: self.millivolts_ppm.unwrap_or(trim.millivolts_ppm),
+			millivolts_offset: self.millivolts_offset.unwrap_or(trim.millivolts_offset),
+			pwm_us: self.pwm_us.unwrap_or(trim.pwm_us),
+		}
+	}
+}
+
+/// Parts of the [`BatteryDetect`] to change, the rest stay as the battery interface reported them
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct BatteryDetectChange {
+	pub debounce_ms: Option<u16>,
+	pub polarity: Option<Polarity>,
+}
+
+impl BatteryDetectChange {
+	pub fn apply(&self, detect: BatteryDetect) -> BatteryDetect {
+		BatteryDetect {
+			debounce_ms: self.debounce_ms.unwrap_or(detect.debounce_ms),
+			polarity: self.polarity.unwrap_or(detect.polarity),
+		}
+	}
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum ServerCmd {
 	SetBatteryId(BatteryID),
-	SetSerialDev(Box<str>),
+	/// The device and the baud to open it at, `None` for the server's `--baud`
+	SetSerialDev(Box<str>, Option<u32>),
 	SetCutoffMillis(MilliVolt),
+	/// Voltage under which the battery counts as removed rather than at the cutoff
+	SetDisconnectMillis(MilliVolt),
+	/// Voltage over which a test won't start, the wrong battery or one on its charger
+	SetMaxVoltageMillis(MilliVolt),
 	StartTest,
 	//TODO: PauseTest,
 	CancelTest,
@@ -232,102 +1879,405 @@ pub enum ServerCmd {
 	ClearFault,
 	AllowUndercurrent,
 	DisallowUndercurrent,
+	/// Reply with a [`ChannelStatus`] for each channel reported on, every channel unless one
+	/// is given
+	Status,
+	/// Reply with the server's [`Capabilities`]
+	GetCapabilities,
+	/// Charge the battery to full, then start the test
+	Charge,
+	/// Have the battery interface run the test without the PC
+	StartAutonomous,
+	/// Save the battery interface's autonomous log as the test set up
+	FetchAutonomousLog,
+	/// Save tests from the next battery ID on in this format
+	SetOutputFormat(OutputFormat),
+	/// Reply with the latest [`Reading`]
+	GetReading,
+	/// Reply with the [`LastMeasurement`]
+	GetLastMeasurement,
+	/// Reply with the [`CurrentMode`]
+	GetMode,
+	/// Keep the connection open and send a [`Reading`] each time the mode or measurement changes
+	SubscribeReadings,
+	/// Note about the test, e.g. the cell chemistry, lot number, or ambient temperature
+	SetNote(Box<str>),
+	/// Who's running the tests, kept from test to test
+	SetOperator(Box<str>),
+	/// Set the cutoff and limits for this kind of battery
+	SetChemistry(chemistry::Chemistry),
+	/// End tests after this long testing, `None` to test until the cutoff
+	SetMaxDuration(Option<std::time::Duration>),
+	/// End tests once this many mAh are taken out of the battery, `None` to test until the cutoff
+	SetMaxCapacity(Option<u32>),
+	/// Set what the load should draw, `None` for the firmware's own
+	SetLoadModel(Option<LoadModel>),
+	/// Discharge at a constant current, `None` for the load's full current
+	SetCurrentSetpoint(Option<MilliAmp>),
+	/// Samples the battery interface averages into each measurement while testing,
+	/// `None` for its own
+	SetWindowSamples(Option<u8>),
+	/// How the battery interface combines each measurement's samples while testing
+	SetFilter(WindowFilter),
+	/// Change the battery interface's trim and have it saved, only without a test set up
+	SetTrim(TrimChange),
+	/// Change how the battery interface detects a battery and have it saved,
+	/// only without a test set up
+	SetBatteryDetect(BatteryDetectChange),
+	/// Have the battery interface check its sensors and pulse the load, only without a test set up
+	SelfTest,
+	/// Start the test at this time once the battery is connected
+	StartAt(std::time::SystemTime),
+	/// Start the test this long from now once the battery is connected
+	StartAfter(std::time::Duration),
+	/// Forget the time set by [`ServerCmd::StartAt`] or [`ServerCmd::StartAfter`]
+	UnscheduleStart,
+	/// Add to or clear the batteries to test after this one
+	Queue(queue::QueueChange),
+	/// Add a battery to the registry, or replace what's known about it
+	AddBattery(registry::RegisteredBattery),
+	/// Reply with every battery in the registry
+	ListBatteries,
+	/// Reply with the serial ports on the server's machine, see [`serial::PortListing`]
+	ListSerialPorts,
+	/// Take a point against the reference meter for the channel's device, or fit or clear its calibration
+	Calibrate(calibration::CalibrationChange),
+	/// Reply with the channel's device's [`calibration::Calibration`], if it has one
+	GetCalibration,
+	/// Keep the connection open and answer requests on it until the client closes it
+	Session,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+impl ServerCmd {
+	/// Whether the server replies with an [`Ack`], not a reply of its own
+	pub fn acked(&self) -> bool {
+		!matches!(
+			self,
+			ServerCmd::Status
+				| ServerCmd::GetCapabilities
+				| ServerCmd::GetReading
+				| ServerCmd::GetLastMeasurement
+				| ServerCmd::GetMode
+				| ServerCmd::SubscribeReadings
+				| ServerCmd::ListBatteries
+				| ServerCmd::ListSerialPorts
+				| ServerCmd::GetCalibration
+		)
+	}
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum Event {
 	/// User sent battery ID
 	BattID(BatteryID),
-	/// User set device name
-	SetSerialDevice(Box<str>),
+	/// User set device name, and the baud if not the server's
+	SetSerialDevice(Box<str>, Option<u32>),
 	/// User set cutoff voltage
 	SetCutoff(MilliVolt),
+	/// User set the voltage the battery counts as removed under
+	SetDisconnect(MilliVolt),
+	/// User set the voltage a test won't start over
+	SetMaxVoltage(MilliVolt),
+	/// User picked the kind of battery, which sets the cutoff too
+	SetChemistry(chemistry::Chemistry),
+	/// User set the longest to test for
+	SetMaxDuration(Option<std::time::Duration>),
+	/// User set the most mAh to take out of the battery
+	SetMaxCapacity(Option<u32>),
+	/// User set what the load should draw
+	SetLoadModel(Option<LoadModel>),
+	/// User set the current to discharge at
+	SetCurrentSetpoint(Option<MilliAmp>),
+	/// User set how many samples go into each measurement while testing
+	SetWindowSamples(Option<u8>),
+	/// User set how each measurement's samples are combined
+	SetFilter(WindowFilter),
+	/// User set the battery interface's trim
+	SetTrim(Trim),
+	/// User set how the battery interface detects a battery
+	SetBatteryDetect(BatteryDetect),
+	/// User wants the battery interface's self test run
+	RunSelfTest,
+	/// Battery interface ran its self test, as it started or when asked
+	SelfTestReport(SelfTestReport),
+	/// User added to or cleared the batteries to test after this one
+	Queue(queue::QueueChange),
+	/// User set when the test starts on its own, `None` to start it themselves
+	ScheduleStart(Option<std::time::SystemTime>),
+	/// The scheduled start came, sent by the program task's own timer
+	ScheduledStart(std::time::SystemTime),
+	/// User picked the output format for the next test
+	SetOutputFormat(OutputFormat),
+	/// User added a note to the test
+	SetNote(Box<str>),
+	/// User said who's running the tests
+	SetOperator(Box<str>),
 	/// User wants to start test
 	StartTest,
+	/// User wants to charge the battery before the test
+	Charge,
+	/// User wants the battery interface to run the test on its own
+	StartAutonomous,
+	/// User wants the battery interface's autonomous log saved as this test
+	FetchAutonomousLog,
+	/// One of the samples of the battery interface's autonomous log, `None` once they're all sent
+	AutonomousLog(Option<LoggedSample>),
 	/// Com not getting replies
 	CommDc,
+	/// Serial device was re-opened after a `CommDc`
+	ComReconnected,
 	/// Com reply
-	ComReply(BIReply),
+	ComReply(Status),
+	/// The battery interface's battery-present input changed, or it replied for the first time
+	BatteryPresence(bool),
+	/// Measurement the battery interface took while it wasn't getting commands, replayed oldest first
+	Replayed(Measurement),
+	/// A reply frame was dropped, running total of bad frames
+	ComDecodeError(u64),
+	/// Battery interface answered the version handshake
+	DeviceVersion(DeviceVersion),
 	/// User canceled battery ID
 	CancelTest,
 	/// User sent shutdown command
 	Shutdown,
-	/// Updates can't be written to the file  
-	FileError,
+	/// Test data can't be saved and is being dropped
+	FileError(files::FileErrorKind),
 	// /// IPC dissconnected
 	// IpcError,
 	/// Clear fault
 	ClearFault,
 	/// Allow current to be below expected or not
 	UnderCurrentResponse(AllowUndercurrent),
+	/// Chamber held the profile step temperature for the soak time
+	ChamberStable,
+	/// Chamber couldn't be set, read, or didn't stabilize in time
+	ChamberError,
+	/// Another of the server's tasks failed, stop testing and shut down
+	InternalError,
 }
 
 #[derive(Debug)]
 pub enum FileCmd {
-	NewFile(tokio::fs::File),
+	/// Start saving to a new test, answers once it's ready
+	NewTest(
+		BatteryID,
+		OutputFormat,
+		tokio::sync::oneshot::Sender<Result<files::SavedTo, files::OutputError>>,
+	),
+	/// Carry on saving an interrupted test, answers once it's ready
+	Resume(
+		files::SavedTo,
+		OutputFormat,
+		tokio::sync::oneshot::Sender<Result<files::SavedTo, files::OutputError>>,
+	),
 	CloseFile,
 	Shutdown,
 	Push(SaveData),
+	/// Fault during the test
+	Fault(battery_tester_common::Fault),
+	/// Save the open test's notes, replacing the ones saved before
+	Notes(files::TestNotes),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ChamberCmd {
+	Stabilize(profile::ChamberStep),
+	/// Stop waiting on the current step, the chamber is left at its setpoint
+	Stop,
+	Shutdown,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct SaveData {
 	pub millivolts: MilliVolt,
 	pub milliamps: MilliAmp,
-	pub dt: u64,
-	pub duration: u64,
+	/// A magnitude, like [`Measurement::milliwatts`]
+	pub milliwatts: MilliWatt,
+	/// The heater branch, `None` without the second INA260
+	pub load: Option<LoadChannel>,
+	/// The battery interface's [`Measurement::sample_index`],
+	/// or the sample's place in an autonomous test's log
+	pub sample_index: u32,
+	/// ms since the recording started
+	pub sample_start_ms: u64,
+	pub sample_duration_ms: u64,
+	pub temp_centi_c: Option<i16>,
+	/// The heater's thermistor, `None` without one
+	pub load_temp_centi_c: Option<i16>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct DeviceVersion {
+	pub protocol: u16,
+	pub firmware: FirmwareVersion,
+	/// Tells the battery interfaces apart when there's more than one rig
+	#[serde(default)]
+	pub device_id: u64,
+}
+
+impl DeviceVersion {
+	/// How the device ID is shown and saved, too big for a TOML integer
+	pub fn hex_device_id(&self) -> Box<str> {
+		format!("{:016x}", self.device_id).into()
+	}
+}
+
+/// Command/reply counters for the serial link, matched up by sequence number
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct LinkStats {
+	/// Commands written to the battery interface
+	pub sent: u64,
+	/// Replies matched to a command
+	pub acked: u64,
+	/// Commands that never got a reply
+	pub lost: u64,
+	/// Replies to a command that was already answered or never sent
+	pub duplicate: u64,
+	/// Reply frames dropped for a bad checksum, encoding or length
+	pub bad_frames: u64,
+	/// Round trip time of the latest acked command
+	pub last_rtt: Option<std::time::Duration>,
+	/// Reply frames that decoded, acked or not
+	#[serde(default)]
+	pub received: u64,
+	/// Times the serial device was opened again after the link went down
+	#[serde(default)]
+	pub reconnects: u64,
+	/// Round trip times of every acked command added up, see [`LinkStats::average_rtt`]
+	#[serde(default)]
+	pub total_rtt: std::time::Duration,
+}
+
+impl LinkStats {
+	/// `None` before the first acked command
+	pub fn average_rtt(&self) -> Option<std::time::Duration> {
+		let acked = u32::try_from(self.acked).unwrap_or(u32::MAX);
+		(acked > 0).then(|| self.total_rtt / acked)
+	}
+}
+
+impl std::fmt::Display for LinkStats {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"sent: {}, received: {}, acked: {}, lost: {}, duplicate: {}, bad frames: {}, reconnects: {}, last round trip: {:?}, average round trip: {:?}",
+			self.sent,
+			self.received,
+			self.acked,
+			self.lost,
+			self.duplicate,
+			self.bad_frames,
+			self.reconnects,
+			self.last_rtt,
+			self.average_rtt()
+		)
+	}
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ComCmd {
-	NewDeviceName(Box<str>),
-	BICommand(BiCommand),
+	/// `None` for [`crate::serial::LinkSettings::baud`]
+	NewDeviceName(Box<str>, Option<u32>),
+	BICommand(ControlWord),
+	/// Have the battery interface use and save this trim
+	SetTrim(Trim),
+	/// Have the battery interface use and save this battery detection
+	SetBatteryDetect(BatteryDetect),
+	/// Have the battery interface run its self test
+	SelfTest,
+	/// Have the battery interface run this test on its own
+	StartAutonomous(AutonomousTest),
+	/// Ask for the battery interface's autonomous log
+	DumpAutonomousLog,
 	Shutdown,
 	ClearFault,
 }
 
-pub fn idle_command() -> BiCommand {
-	BiCommand {
+pub fn idle_command() -> ControlWord {
+	ControlWord {
 		load: LoadState::Off,
 		clear_fault: ClearFault::No,
 		reset: Reset::No,
 		allow_undercurrent: AllowUndercurrent::No,
+		cutoff: None,
+		load_model: None,
+		current_setpoint: None,
+		max_voltage: None,
+		window_samples: None,
+		filter: WindowFilter::Mean,
+		poll_ms: None,
 	}
 }
 
-pub fn end_test_command() -> BiCommand {
-	BiCommand {
+pub fn end_test_command() -> ControlWord {
+	ControlWord {
 		load: LoadState::Off,
 		clear_fault: ClearFault::No,
 		reset: Reset::Yes,
 		allow_undercurrent: AllowUndercurrent::No,
+		cutoff: None,
+		load_model: None,
+		current_setpoint: None,
+		max_voltage: None,
+		window_samples: None,
+		filter: WindowFilter::Mean,
+		poll_ms: None,
 	}
 }
 
-pub fn volts_command() -> BiCommand {
-	BiCommand {
+pub fn volts_command() -> ControlWord {
+	ControlWord {
 		load: LoadState::Off,
 		clear_fault: ClearFault::No,
 		reset: Reset::No,
 		allow_undercurrent: AllowUndercurrent::No,
+		cutoff: None,
+		load_model: None,
+		current_setpoint: None,
+		max_voltage: None,
+		window_samples: None,
+		filter: WindowFilter::Mean,
+		poll_ms: None,
 	}
 }
 
-pub fn testing_command(allow_undercurrent: AllowUndercurrent) -> BiCommand {
-	BiCommand {
+pub fn testing_command(
+	allow_undercurrent: AllowUndercurrent,
+	cutoff: MilliVolt,
+	load_model: Option<LoadModel>,
+	current_setpoint: Option<MilliAmp>,
+	max_voltage: MilliVolt,
+	window_samples: Option<u8>,
+	filter: WindowFilter,
+) -> ControlWord {
+	ControlWord {
 		load: LoadState::On,
 		clear_fault: ClearFault::No,
 		reset: Reset::No,
 		allow_undercurrent,
+		cutoff: Some(cutoff),
+		load_model,
+		current_setpoint,
+		max_voltage: Some(max_voltage),
+		window_samples,
+		filter,
+		poll_ms: None,
 	}
 }
 
-fn clear_fault_command() -> BiCommand {
-	BiCommand {
+fn clear_fault_command() -> ControlWord {
+	ControlWord {
 		load: LoadState::Off,
 		clear_fault: ClearFault::Yes,
 		reset: Reset::No,
 		allow_undercurrent: AllowUndercurrent::No,
+		cutoff: None,
+		load_model: None,
+		current_setpoint: None,
+		max_voltage: None,
+		window_samples: None,
+		filter: WindowFilter::Mean,
+		poll_ms: None,
 	}
 }
 
@@ -336,3 +2286,207 @@ pub struct BatteryID {
 	pub year: u16,
 	pub index: u8,
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::{TimeZone, Timelike};
+
+	fn line(channel: Option<ChannelId>, text: &'static str) -> Line {
+		Line {
+			time: chrono::Local
+				.with_ymd_and_hms(2025, 1, 31, 23, 59, 59)
+				.unwrap()
+				.with_nanosecond(999_000_000)
+				.unwrap(),
+			task: Task::Serial,
+			channel,
+			text: Text::Static(text),
+		}
+	}
+
+	#[test]
+	fn test_prefix() {
+		assert_eq!(line(None, "").prefix(), "2025-01-31 23:59:59.999 serial  ");
+		assert_eq!(
+			line(Some(1), "").prefix(),
+			"2025-01-31 23:59:59.999 serial  1 "
+		);
+	}
+
+	#[test]
+	fn test_every_line_prefixed() {
+		let text = |msg| String::from_utf8(printed(&msg, false).unwrap()).unwrap();
+		let prefix = line(Some(2), "").prefix();
+		assert_eq!(
+			text(Print::Info(line(Some(2), "ok"))),
+			format!("{prefix}ok\n")
+		);
+		assert_eq!(
+			text(Print::Warn(line(Some(2), "can't write:\nno space"))),
+			format!("{prefix}can't write:\n{prefix}no space\n")
+		);
+		assert_eq!(printed(&Print::Shutdown, false), None);
+	}
+
+	#[test]
+	fn test_only_warnings_and_errors_colored() {
+		let prefix = line(None, "").prefix();
+		for (msg, start) in [
+			(Print::Info(line(None, "a\nb")), None),
+			(Print::Warn(line(None, "a\nb")), Some("\x1b[33m")),
+			(Print::Error(line(None, "a\nb")), Some("\x1b[31m")),
+		] {
+			let colored = String::from_utf8(printed(&msg, true).unwrap()).unwrap();
+			let expected = match start {
+				Some(start) => format!("{prefix}{start}a\x1b[0m\n{prefix}{start}b\x1b[0m\n"),
+				None => format!("{prefix}a\n{prefix}b\n"),
+			};
+			assert_eq!(colored, expected, "{msg:?}");
+			// not on a terminal
+			let plain = String::from_utf8(printed(&msg, false).unwrap()).unwrap();
+			assert_eq!(plain, format!("{prefix}a\n{prefix}b\n"), "{msg:?}");
+		}
+	}
+
+	/// Measured at 12 V and 2 A, `sample_start_ms` after the battery interface booted
+	fn measurement(sample_start_ms: u64) -> Measurement {
+		Measurement {
+			vbat: MilliVolt::new(12_000),
+			ibat: MilliAmp::new(2_000),
+			milliwatts: MilliWatt::new(24_000),
+			sample_index: 0,
+			sample_start_ms,
+			sample_duration_ms: 450,
+			temp_centi_c: None,
+			load_temp_centi_c: None,
+			fan_on: false,
+			load: None,
+		}
+	}
+
+	#[test]
+	fn test_staleness_fresh() {
+		let mut state = TestState::default();
+		assert_eq!(
+			state.check_staleness(Some(&measurement(500))),
+			Staleness::Fresh
+		);
+		assert_eq!(state.check_staleness(None), Staleness::Waiting);
+		assert_eq!(
+			state.check_staleness(Some(&measurement(1_000))),
+			Staleness::Fresh
+		);
+		// sent again, or from before the last one
+		assert_eq!(
+			state.check_staleness(Some(&measurement(1_000))),
+			Staleness::Repeated
+		);
+		assert_eq!(
+			state.check_staleness(Some(&measurement(500))),
+			Staleness::Repeated
+		);
+		// a rebooted battery interface counts from 0 again
+		state.reset_staleness();
+		assert_eq!(
+			state.check_staleness(Some(&measurement(500))),
+			Staleness::Fresh
+		);
+	}
+
+	#[test]
+	fn test_staleness_stalls_and_recovers() {
+		let mut state = TestState::default();
+		state.check_staleness(Some(&measurement(500)));
+		for reply in 1..=STALE_REPLY_LIMIT {
+			let stale = if reply % 2 == 0 {
+				state.check_staleness(None)
+			} else {
+				state.check_staleness(Some(&measurement(500)))
+			};
+			assert_ne!(stale, Staleness::Stalled, "reply {reply}");
+		}
+		assert_eq!(state.check_staleness(None), Staleness::Stalled);
+		assert_eq!(
+			state.check_staleness(Some(&measurement(500))),
+			Staleness::Stalled
+		);
+		// measurements coming again start the count over
+		assert_eq!(
+			state.check_staleness(Some(&measurement(1_000))),
+			Staleness::Fresh
+		);
+		for _ in 0..STALE_REPLY_LIMIT {
+			assert_eq!(state.check_staleness(None), Staleness::Waiting);
+		}
+		assert_eq!(state.check_staleness(None), Staleness::Stalled);
+	}
+
+	#[test]
+	fn test_stale_limit_follows_window_and_poll() {
+		let replies_until_stalled = |window_samples, poll_ms| {
+			let mut state = TestState::default();
+			state.set_window_samples(window_samples);
+			state.set_poll_ms(poll_ms);
+			state.check_staleness(Some(&measurement(500)));
+			(1..)
+				.find(|_| state.check_staleness(None) == Staleness::Stalled)
+				.unwrap()
+		};
+		let limit = STALE_REPLY_LIMIT + 1;
+		assert_eq!(replies_until_stalled(None, DEFAULT_POLL_MS), limit);
+		// twice the samples to a window
+		assert_eq!(
+			replies_until_stalled(Some(2 * WINDOW_SAMPLES as u8), DEFAULT_POLL_MS),
+			2 * STALE_REPLY_LIMIT + 1
+		);
+		// replies twice as often
+		assert_eq!(
+			replies_until_stalled(None, DEFAULT_POLL_MS / 2),
+			2 * STALE_REPLY_LIMIT + 1
+		);
+		// never less patient than the default
+		assert_eq!(replies_until_stalled(Some(1), DEFAULT_POLL_MS * 2), limit);
+	}
+
+	/// Into the battery when `milliamps` is negative
+	fn current(milliamps: i16) -> Measurement {
+		Measurement {
+			ibat: MilliAmp::new(milliamps),
+			..measurement(0)
+		}
+	}
+
+	#[test]
+	fn test_charge_full() {
+		let mut monitor = ChargeMonitor::default();
+		// discharging or no current isn't a charge
+		assert_eq!(monitor.update(&current(2_000)), ChargeState::Waiting);
+		assert_eq!(monitor.update(&current(0)), ChargeState::Waiting);
+		assert_eq!(monitor.update(&current(-2_000)), ChargeState::Charging);
+		for _ in 1..CHARGE_FULL_MEASUREMENTS / 2 {
+			assert_eq!(monitor.update(&current(-100)), ChargeState::Charging);
+		}
+		// the charger picking up again starts the taper over
+		assert_eq!(monitor.update(&current(-1_000)), ChargeState::Charging);
+		for _ in 1..CHARGE_FULL_MEASUREMENTS {
+			assert_eq!(monitor.update(&current(-100)), ChargeState::Charging);
+		}
+		assert_eq!(monitor.update(&current(-100)), ChargeState::Full);
+	}
+
+	#[test]
+	fn test_charge_timed_out() {
+		let mut monitor = ChargeMonitor::default();
+		// waiting on a charger that isn't on is up to the operator
+		for _ in 0..CHARGE_TIMEOUT_MEASUREMENTS {
+			assert_eq!(monitor.update(&current(0)), ChargeState::Waiting);
+		}
+		for _ in 0..CHARGE_TIMEOUT_MEASUREMENTS {
+			assert_eq!(monitor.update(&current(-2_000)), ChargeState::Charging);
+		}
+		assert_eq!(monitor.update(&current(-2_000)), ChargeState::TimedOut);
+		// however little it charges by then
+		assert_eq!(monitor.update(&current(-100)), ChargeState::TimedOut);
+	}
+}
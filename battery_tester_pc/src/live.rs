@@ -0,0 +1,110 @@
+//! What's shown of a running test, apart from what's saved.
+//!
+//! The file task saves every measurement, while the console and `watch` clients get one
+//! [`LiveSummary`] per `--live-every-s`, with the lowest and highest since the last one.
+//! Printing every measurement floods the console on long tests.
+
+use std::time::Duration;
+
+use battery_tester_common::{Measurement, MilliAmp, MilliVolt};
+use serde::{Deserialize, Serialize};
+
+/// How often the live view is updated when the server isn't started with `--live-every-s`
+pub const DEFAULT_LIVE_EVERY: Duration = Duration::from_secs(5);
+
+/// The measurements taken since the last summary
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct LiveSummary {
+	pub samples: u32,
+	/// From the first measurement's start to the last one's
+	pub span_ms: u64,
+	pub vbat: MilliVolt,
+	pub min_vbat: MilliVolt,
+	pub max_vbat: MilliVolt,
+	pub ibat: MilliAmp,
+	pub min_ibat: MilliAmp,
+	pub max_ibat: MilliAmp,
+}
+
+impl LiveSummary {
+	fn new(m: &Measurement) -> Self {
+		Self {
+			samples: 1,
+			span_ms: 0,
+			vbat: m.vbat,
+			min_vbat: m.vbat,
+			max_vbat: m.vbat,
+			ibat: m.ibat,
+			min_ibat: m.ibat,
+			max_ibat: m.ibat,
+		}
+	}
+
+	fn push(&mut self, m: &Measurement, span_ms: u64) {
+		self.samples += 1;
+		self.span_ms = span_ms;
+		self.vbat = m.vbat;
+		self.min_vbat = self.min_vbat.min(m.vbat);
+		self.max_vbat = self.max_vbat.max(m.vbat);
+		self.ibat = m.ibat;
+		self.min_ibat = self.min_ibat.min(m.ibat);
+		self.max_ibat = self.max_ibat.max(m.ibat);
+	}
+}
+
+impl std::fmt::Display for LiveSummary {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{} mV ({}..{}), {} mA ({}..{}), {} measurements over {} s",
+			self.vbat,
+			self.min_vbat,
+			self.max_vbat,
+			self.ibat,
+			self.min_ibat,
+			self.max_ibat,
+			self.samples,
+			self.span_ms / 1000
+		)
+	}
+}
+
+/// Folds measurements into a [`LiveSummary`] every so often of the battery interface's clock
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LiveView {
+	every_ms: u64,
+	/// `sample_start_ms` of the summary's first measurement
+	started: u64,
+	summary: Option<LiveSummary>,
+}
+
+impl LiveView {
+	/// `every` of 0 summarizes each measurement on its own
+	pub fn new(every: Duration) -> Self {
+		Self {
+			every_ms: every.as_millis() as u64,
+			started: 0,
+			summary: None,
+		}
+	}
+
+	/// The summary once `every` has passed since its first measurement
+	pub fn push(&mut self, m: &Measurement) -> Option<LiveSummary> {
+		match &mut self.summary {
+			Some(summary) if m.sample_start_ms >= self.started => {
+				summary.push(m, m.sample_start_ms - self.started);
+			}
+			// the first since the last summary, or the battery interface restarted
+			_ => {
+				self.started = m.sample_start_ms;
+				self.summary = Some(LiveSummary::new(m));
+			}
+		}
+		let span_ms = m.sample_start_ms - self.started;
+		if span_ms >= self.every_ms {
+			self.summary.take()
+		} else {
+			None
+		}
+	}
+}
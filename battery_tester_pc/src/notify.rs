@@ -0,0 +1,377 @@
+//! Tells operators a test needs them, so nobody has to watch a terminal for hours.
+//!
+//! When a channel's program task gets to [`Mode::EndTest`], [`Mode::Fault`], or [`Mode::CommDC`]
+//! the notify task posts a JSON [`Notice`] to a webhook and/or shows a desktop notification.
+//! Failing to notify is printed and otherwise ignored, it never holds up a test.
+
+use std::{io::Write, path::Path, time::Duration};
+
+use battery_tester_common::Measurement;
+use serde::{Deserialize, Serialize};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+	process::Command,
+	sync::{mpsc::Receiver, watch},
+	time::timeout,
+};
+
+use crate::{ChannelId, Error, Mode, Printer, ServerStatus};
+
+/// Longest a webhook or the desktop notifier gets before it's given up on
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+	/// `http://host[:port]/path` to POST each [`Notice`] to, https isn't supported
+	pub webhook: Option<String>,
+	/// Show a desktop notification with `notify-send`, or `osascript` on macOS
+	pub desktop: bool,
+}
+
+impl NotifyConfig {
+	pub fn load(path: &Path) -> Result<Self, Error> {
+		let text = std::fs::read_to_string(path)
+			.map_err(|e| Error::NotifyRead(path.to_path_buf().into_boxed_path(), e))?;
+		let config: Self = toml::from_str(&text)
+			.map_err(|e| Error::NotifyParse(path.to_path_buf().into_boxed_path(), e))?;
+		if let Some(url) = &config.webhook
+			&& Webhook::parse(url).is_none()
+		{
+			return Err(Error::BadWebhook(url.as_str().into()));
+		}
+		Ok(config)
+	}
+
+	/// Nothing to notify with
+	pub fn is_empty(&self) -> bool {
+		self.webhook.is_none() && !self.desktop
+	}
+}
+
+/// The modes worth telling someone about
+pub fn notifies(mode: Mode) -> bool {
+	matches!(mode, Mode::EndTest | Mode::Fault | Mode::CommDC)
+}
+
+/// What's posted to the webhook
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct Notice {
+	/// `end_test`, `fault`, or `comm_dc`
+	pub event: &'static str,
+	pub channel: ChannelId,
+	/// `year-index`
+	pub battery: Option<String>,
+	pub device: Option<String>,
+	pub millivolts: Option<u16>,
+	pub milliamps: Option<i16>,
+	pub temp_centi_c: Option<i16>,
+	/// RFC 3339 local time the server noticed
+	pub time: String,
+}
+
+impl Notice {
+	fn new(channel: ChannelId, status: &ServerStatus, measurement: Option<Measurement>) -> Self {
+		Self {
+			event: match status.mode {
+				Mode::EndTest => "end_test",
+				Mode::Fault => "fault",
+				_ => "comm_dc",
+			},
+			channel,
+			battery: status
+				.battery_id
+				.map(|id| format!("{}-{}", id.year, id.index)),
+			device: status.device_name.as_deref().map(String::from),
+			millivolts: measurement.map(|m| m.vbat.into()),
+			milliamps: measurement.map(|m| m.ibat.into()),
+			temp_centi_c: measurement.and_then(|m| m.temp_centi_c),
+			time: chrono::Local::now().to_rfc3339(),
+		}
+	}
+
+	/// One line for the desktop notification
+	fn summary(&self) -> String {
+		let what = match self.event {
+			"end_test" => "test ended",
+			"fault" => "FAULT, the test is stopped",
+			_ => "lost the battery interface",
+		};
+		let battery = self.battery.as_deref().unwrap_or("-");
+		format!("channel {}, battery {battery}: {what}", self.channel)
+	}
+}
+
+/// Sends a notice for each status the channel's program task sends, until it stops
+pub async fn notify_task(
+	channel: ChannelId,
+	config: NotifyConfig,
+	mut status_rx: Receiver<ServerStatus>,
+	measurement: watch::Receiver<Option<Measurement>>,
+	printer: Printer,
+) {
+	let webhook = config.webhook.as_deref().and_then(Webhook::parse);
+	while let Some(status) = status_rx.recv().await {
+		let notice = Notice::new(channel, &status, *measurement.borrow());
+		if let Some(webhook) = &webhook {
+			match timeout(NOTIFY_TIMEOUT, webhook.post(&notice)).await {
+				Ok(Ok(())) => {}
+				Ok(Err(e)) => printer.warn(|tv| write!(tv, "webhook failed: {e}")).await,
+				Err(_) => printer.warn_stat("webhook timed out").await,
+			}
+		}
+		if config.desktop {
+			match timeout(NOTIFY_TIMEOUT, desktop(&notice.summary())).await {
+				Ok(Ok(())) => {}
+				Ok(Err(e)) => {
+					printer
+						.warn(|tv| write!(tv, "desktop notification failed: {e}"))
+						.await
+				}
+				Err(_) => printer.warn_stat("desktop notification timed out").await,
+			}
+		}
+	}
+	println!("exiting notify_task");
+}
+
+/// A plain http URL split up for the request
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Webhook {
+	/// `host:port`, port 80 when the URL doesn't have one
+	addr: String,
+	host: String,
+	path: String,
+}
+
+impl Webhook {
+	fn parse(url: &str) -> Option<Self> {
+		let rest = url.strip_prefix("http://")?;
+		let (host, path) = match rest.find('/') {
+			Some(i) => rest.split_at(i),
+			None => (rest, "/"),
+		};
+		if host.is_empty() {
+			return None;
+		}
+		let addr = if host.contains(':') {
+			host.to_string()
+		} else {
+			format!("{host}:80")
+		};
+		Some(Self {
+			addr,
+			host: host.to_string(),
+			path: path.to_string(),
+		})
+	}
+
+	/// Any 2xx reply is success
+	async fn post(&self, notice: &Notice) -> std::io::Result<()> {
+		let body = serde_json::to_string(notice).map_err(std::io::Error::other)?;
+		let mut stream = TcpStream::connect(&self.addr).await?;
+		let head = format!(
+			"POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+			self.path,
+			self.host,
+			body.len()
+		);
+		stream.write_all(head.as_bytes()).await?;
+		stream.write_all(body.as_bytes()).await?;
+		// only the status line matters, e.g. "HTTP/1.1 204 No Content"
+		let mut reply = [0u8; 32];
+		let mut len = 0;
+		while len < reply.len() {
+			match stream.read(&mut reply[len..]).await? {
+				0 => break,
+				n => len += n,
+			}
+		}
+		let status_line = String::from_utf8_lossy(&reply[..len]);
+		match status_line.split_whitespace().nth(1) {
+			Some(code) if code.starts_with('2') => Ok(()),
+			Some(code) => Err(std::io::Error::other(format!("server replied {code}"))),
+			None => Err(std::io::Error::other("no reply")),
+		}
+	}
+}
+
+async fn desktop(summary: &str) -> std::io::Result<()> {
+	#[cfg(target_os = "macos")]
+	let status = Command::new("osascript")
+		.arg("-e")
+		.arg(format!(
+			"display notification {summary:?} with title \"battery tester\""
+		))
+		.status()
+		.await?;
+	#[cfg(not(target_os = "macos"))]
+	let status = Command::new("notify-send")
+		.arg("battery tester")
+		.arg(summary)
+		.status()
+		.await?;
+	if status.success() {
+		Ok(())
+	} else {
+		Err(std::io::Error::other(format!(
+			"notifier exited with {status}"
+		)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{BatteryID, Print};
+	use battery_tester_common::{MilliAmp, MilliVolt, MilliWatt};
+	use tokio::{
+		io::{AsyncBufReadExt, BufReader},
+		net::TcpListener,
+		sync::mpsc::{self, channel},
+	};
+
+	/// Answers each POST with `replies` in turn, sending on the bodies it got
+	async fn webhook_server(replies: &'static [&'static str]) -> (String, mpsc::Receiver<String>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/hooks/bench", listener.local_addr().unwrap());
+		let (body_tx, body_rx) = channel(4);
+		tokio::spawn(async move {
+			for reply in replies {
+				let (stream, _) = listener.accept().await.unwrap();
+				let mut stream = BufReader::new(stream);
+				let mut content_length = 0;
+				let mut line = String::new();
+				loop {
+					line.clear();
+					stream.read_line(&mut line).await.unwrap();
+					if line == "\r\n" {
+						break;
+					}
+					if let Some(len) = line.strip_prefix("Content-Length: ") {
+						content_length = len.trim().parse().unwrap();
+					}
+				}
+				let mut body = vec![0; content_length];
+				stream.read_exact(&mut body).await.unwrap();
+				stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+				body_tx
+					.send(String::from_utf8(body).unwrap())
+					.await
+					.unwrap();
+			}
+		});
+		(url, body_rx)
+	}
+
+	#[test]
+	fn test_webhook_parse() {
+		assert_eq!(
+			Webhook::parse("http://bench.local/hooks"),
+			Some(Webhook {
+				addr: "bench.local:80".into(),
+				host: "bench.local".into(),
+				path: "/hooks".into(),
+			})
+		);
+		assert_eq!(
+			Webhook::parse("http://10.0.0.2:8080").map(|w| (w.addr, w.path)),
+			Some(("10.0.0.2:8080".into(), "/".into()))
+		);
+		assert_eq!(Webhook::parse("https://bench.local/hooks"), None);
+		assert_eq!(Webhook::parse("http:///hooks"), None);
+
+		let path = std::env::temp_dir().join(format!(
+			"battery-tester-notify-parse-{}.toml",
+			std::process::id()
+		));
+		std::fs::write(&path, "webhook = \"https://bench.local/hooks\"\n").unwrap();
+		assert!(matches!(
+			NotifyConfig::load(&path),
+			Err(Error::BadWebhook(_))
+		));
+		std::fs::write(&path, "desktop = true\n").unwrap();
+		let config = NotifyConfig::load(&path).unwrap();
+		assert!(config.desktop && !config.is_empty());
+		std::fs::write(&path, "email = \"me@bench.local\"\n").unwrap();
+		assert!(matches!(
+			NotifyConfig::load(&path),
+			Err(Error::NotifyParse(..))
+		));
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_notice_posted() {
+		let (webhook, mut bodies) = webhook_server(&[
+			"HTTP/1.1 204 No Content\r\n\r\n",
+			"HTTP/1.1 500 Internal Server Error\r\n\r\n",
+		])
+		.await;
+		let config = NotifyConfig {
+			webhook: Some(webhook),
+			desktop: false,
+		};
+		let (status_tx, status_rx) = channel(4);
+		let (_measurement_tx, measurement_rx) = watch::channel(Some(Measurement {
+			vbat: MilliVolt::new(10_480),
+			ibat: MilliAmp::new(-2_000),
+			milliwatts: MilliWatt::new(20_960),
+			sample_index: 0,
+			sample_start_ms: 0,
+			sample_duration_ms: 900,
+			temp_centi_c: Some(2_350),
+			load_temp_centi_c: None,
+			fan_on: false,
+			load: None,
+		}));
+		let (print_tx, mut print_rx) = channel::<Print>(8);
+		let task = tokio::spawn(notify_task(
+			2,
+			config,
+			status_rx,
+			measurement_rx,
+			Printer::new(print_tx),
+		));
+
+		let status = ServerStatus {
+			mode: Mode::EndTest,
+			battery_id: Some(BatteryID {
+				year: 2025,
+				index: 3,
+			}),
+			..ServerStatus::default()
+		};
+		status_tx.send(status.clone()).await.unwrap();
+		let notice: serde_json::Value =
+			serde_json::from_str(&bodies.recv().await.unwrap()).unwrap();
+		assert_eq!(notice["event"], "end_test");
+		assert_eq!(notice["channel"], 2);
+		assert_eq!(notice["battery"], "2025-3");
+		assert_eq!(notice["millivolts"], 10_480);
+		assert_eq!(notice["milliamps"], -2_000);
+		assert_eq!(notice["temp_centi_c"], 2_350);
+
+		// a failed post is only printed
+		status_tx
+			.send(ServerStatus {
+				mode: Mode::Fault,
+				..status
+			})
+			.await
+			.unwrap();
+		let notice: serde_json::Value =
+			serde_json::from_str(&bodies.recv().await.unwrap()).unwrap();
+		assert_eq!(notice["event"], "fault");
+		let Some(Print::Warn(line)) = print_rx.recv().await else {
+			panic!("the failed post wasn't printed");
+		};
+		assert_eq!(
+			String::from_utf8_lossy(line.text.as_bytes()),
+			"webhook failed: server replied 500"
+		);
+		drop(status_tx);
+		task.await.unwrap();
+	}
+}
@@ -0,0 +1,32 @@
+//! Open circuit voltage of the battery rested with the load off, before a test turns the
+//! load on and again after the test ends on its own, e.g. at the cutoff.
+//!
+//! Both are saved with the test's notes. `--ocv-rest-s` turns them on.
+
+use std::time::Duration;
+
+use battery_tester_common::{Measurement, MilliVolt};
+
+/// Waits out the rest on the battery interface's clock
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Rest {
+	period: Duration,
+	/// Timestamp (ms) of the first measurement resting
+	started: Option<u64>,
+}
+
+impl Rest {
+	pub fn new(period: Duration) -> Self {
+		Self {
+			period,
+			started: None,
+		}
+	}
+
+	/// Take in a measurement with the load off, the open circuit voltage once it's rested
+	pub fn measured(&mut self, m: &Measurement) -> Option<MilliVolt> {
+		let started = *self.started.get_or_insert(m.sample_start_ms);
+		(m.sample_start_ms.saturating_sub(started) >= self.period.as_millis() as u64)
+			.then_some(m.vbat)
+	}
+}
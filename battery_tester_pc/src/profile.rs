@@ -0,0 +1,187 @@
+//! Test profiles, a list of steps worked through each time the user starts a test.
+//!
+//! Loaded from TOML, e.g. a capacity test at three temperatures:
+//! ```toml
+//! [[steps]]
+//! step = "chamber"
+//! setpoint_c = 25.0
+//! tolerance_c = 0.5
+//! soak_s = 1800
+//!
+//! [[steps]]
+//! step = "discharge"
+//!
+//! [[steps]]
+//! step = "chamber"
+//! setpoint_c = 0.0
+//! tolerance_c = 0.5
+//! soak_s = 3600
+//! timeout_s = 7200
+//!
+//! [[steps]]
+//! step = "discharge"
+//! cutoff_mv = 10800
+//! ```
+//! Starting a test runs every step up to and including the next discharge,
+//! after the last discharge the profile starts over.
+
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::Error;
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct TestProfile {
+	pub steps: Vec<ProfileStep>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum ProfileStep {
+	/// Set the environmental chamber temperature and wait for it to stabilize
+	Chamber(ChamberStep),
+	/// Run a discharge test, with its own cutoff voltage if given
+	Discharge { cutoff_mv: Option<u16> },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+pub struct ChamberStep {
+	pub setpoint_c: f32,
+	/// Chamber is stable once it stays this close to the setpoint...
+	pub tolerance_c: f32,
+	/// ...for this many seconds
+	pub soak_s: u64,
+	/// Give up if the chamber isn't stable after this many seconds
+	pub timeout_s: Option<u64>,
+}
+
+impl TestProfile {
+	pub fn load(path: &Path) -> Result<Self, Error> {
+		let text = std::fs::read_to_string(path)
+			.map_err(|e| Error::ProfileRead(path.to_path_buf().into_boxed_path(), e))?;
+		toml::from_str(&text)
+			.map_err(|e| Error::ProfileParse(path.to_path_buf().into_boxed_path(), e))
+	}
+
+	pub fn needs_chamber(&self) -> bool {
+		self.steps
+			.iter()
+			.any(|step| matches!(step, ProfileStep::Chamber(_)))
+	}
+}
+
+/// Where we are in the profile, if there is one
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ProfileRun {
+	profile: Option<TestProfile>,
+	next: usize,
+}
+
+impl ProfileRun {
+	pub fn new(profile: Option<TestProfile>) -> Self {
+		Self { profile, next: 0 }
+	}
+
+	/// Take the next step, `None` without a profile
+	pub fn next_step(&mut self) -> Option<ProfileStep> {
+		let steps = &self.profile.as_ref()?.steps;
+		let step = *steps.get(self.next)?;
+		self.next = (self.next + 1) % steps.len();
+		Some(step)
+	}
+
+	/// Run the step just taken again on the next start
+	pub fn retry_step(&mut self) {
+		if let Some(profile) = &self.profile
+			&& !profile.steps.is_empty()
+		{
+			let len = profile.steps.len();
+			self.next = (self.next + len - 1) % len;
+		}
+	}
+
+	/// True if the next step is the first one
+	pub fn at_start(&self) -> bool {
+		self.next == 0
+	}
+
+	pub fn restart(&mut self) {
+		self.next = 0;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TWO_TEMPERATURES: &str = r#"
+[[steps]]
+step = "chamber"
+setpoint_c = 25.0
+tolerance_c = 0.5
+soak_s = 1800
+
+[[steps]]
+step = "discharge"
+
+[[steps]]
+step = "chamber"
+setpoint_c = 0.0
+tolerance_c = 0.5
+soak_s = 3600
+timeout_s = 7200
+
+[[steps]]
+step = "discharge"
+cutoff_mv = 10800
+"#;
+
+	#[test]
+	fn test_steps_repeat() {
+		let profile: TestProfile = toml::from_str(TWO_TEMPERATURES).unwrap();
+		assert!(profile.needs_chamber());
+		let mut run = ProfileRun::new(Some(profile));
+		assert!(run.at_start());
+		let steps: Vec<_> = std::iter::from_fn(|| run.next_step()).take(5).collect();
+		assert!(matches!(steps[0], ProfileStep::Chamber(c) if c.setpoint_c == 25.0));
+		assert_eq!(steps[1], ProfileStep::Discharge { cutoff_mv: None });
+		assert!(matches!(
+			steps[2],
+			ProfileStep::Chamber(ChamberStep {
+				timeout_s: Some(7200),
+				..
+			})
+		));
+		assert_eq!(
+			steps[3],
+			ProfileStep::Discharge {
+				cutoff_mv: Some(10800)
+			}
+		);
+		// after the last discharge it starts over
+		assert_eq!(steps[4], steps[0]);
+	}
+
+	#[test]
+	fn test_retry_and_restart() {
+		let profile: TestProfile = toml::from_str(TWO_TEMPERATURES).unwrap();
+		let mut run = ProfileRun::new(Some(profile));
+		let first = run.next_step();
+		run.retry_step();
+		assert!(run.at_start());
+		assert_eq!(run.next_step(), first);
+		run.next_step();
+		run.next_step();
+		run.restart();
+		assert_eq!(run.next_step(), first);
+	}
+
+	#[test]
+	fn test_no_profile() {
+		let mut run = ProfileRun::new(None);
+		assert_eq!(run.next_step(), None);
+		run.retry_step();
+		assert!(run.at_start());
+	}
+}
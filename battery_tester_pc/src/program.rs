@@ -0,0 +1,4150 @@
+//! The program task, the state machine running one channel's tests.
+//!
+//! It has an async fn per [`Mode`], each takes events until it's time for the next mode.
+//! Everything else (the serial link, files, chamber, signal lines) is another task
+//! the program task sends commands to.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use battery_tester_common::{
+	BatteryDetect, FaultKind, LoadModel, LoadState, Measurement, MilliAmp, MilliVolt, MilliWatt,
+	PROTOCOL_VERSION, SelfTestReport, Trim,
+	window::{MAX_WINDOW_SAMPLES, WindowFilter},
+};
+use tokio::sync::{
+	mpsc::{Receiver, Sender, WeakSender},
+	oneshot, watch,
+};
+
+use crate::{
+	BatteryID, ChamberCmd, ChargeMonitor, ChargeState, ComCmd, DeviceVersion, Event, FileCmd, Mode,
+	OutputFormat, Printer, SaveData, ServerStatus, Staleness, TaskError, TestState,
+	calibration::{Calibration, CalibrationStore},
+	chemistry::{Chemistry, Limits},
+	end_test_command,
+	files::OutputError,
+	idle_command,
+	journal::Journal,
+	notify::notifies,
+	ocv::Rest,
+	profile::{ProfileRun, ProfileStep},
+	pulse::{Pulse, PulseAction},
+	queue::QueueChange,
+	registry::{BatteryRegistry, RegisteredBattery},
+	signal::TestSignal,
+	termination::{EndReason, TerminationRule},
+	volts_command,
+	warmup::{Warmup, WarmupRule},
+};
+
+/// Everything the program task talks to, the other ends belong to the other tasks
+pub(crate) struct ProgramLinks {
+	pub rx: Receiver<Event>,
+	pub file_cmd_tx: Sender<FileCmd>,
+	pub com_cmd_tx: Sender<ComCmd>,
+	pub printer: Printer,
+	pub signal_tx: Option<Sender<TestSignal>>,
+	pub chamber_cmd_tx: Option<Sender<ChamberCmd>>,
+	pub status_tx: watch::Sender<ServerStatus>,
+	/// Every mode as it's entered, for the trace
+	pub mode_tx: Option<Sender<Mode>>,
+	pub notify_tx: Option<Sender<ServerStatus>>,
+	/// Checked for each new battery ID, `None` when replaying
+	pub registry: Option<Arc<BatteryRegistry>>,
+	/// Applied to measurements while testing, `None` when replaying
+	pub calibrations: Option<Arc<CalibrationStore>>,
+	/// Where the program task's own timers send their events, through the trace like any other.
+	/// `None` when replaying, the trace has them.
+	pub timer_tx: Option<WeakSender<Event>>,
+}
+
+/// The program task's events, and where its own timers add to them
+struct Inbox {
+	rx: Receiver<Event>,
+	timer_tx: Option<WeakSender<Event>>,
+}
+
+impl Inbox {
+	/// Sends [`Event::ScheduledStart`] when the scheduled start comes, right away if it's
+	/// passed. It isn't canceled, the program task ignores it if the start was canceled or
+	/// moved since.
+	fn start_timer(&self, start_at: SystemTime) {
+		let Some(timer_tx) = self.timer_tx.clone() else {
+			return;
+		};
+		tokio::spawn(async move {
+			// on the wall clock
+			let left = start_at
+				.duration_since(SystemTime::now())
+				.unwrap_or_default();
+			tokio::time::sleep(left).await;
+			// weak so a timer doesn't keep the program task running, it's gone if it shut down
+			if let Some(timer_tx) = timer_tx.upgrade() {
+				let _ = timer_tx.send(Event::ScheduledStart(start_at)).await;
+			}
+		});
+	}
+}
+
+/// How the channel's tests are run and saved, the same for every test
+pub(crate) struct TestSettings {
+	pub output_format: OutputFormat,
+	pub termination: TerminationRule,
+	/// Start tests with the internal resistance pulse
+	pub ir_pulse: bool,
+	pub warmup: Option<WarmupRule>,
+	/// Time resting for the open circuit voltage before and after tests
+	pub ocv_rest: Option<Duration>,
+	/// ms between the commands the serial task sends
+	pub poll_ms: u16,
+}
+
+/// `journal_path` is where the test in progress is kept, `interrupted` the test to resume.
+/// Fails when a task it sends commands to has stopped, after idling the battery interface if it can.
+pub(crate) async fn program_event_task(
+	links: ProgramLinks,
+	mut profile: ProfileRun,
+	settings: TestSettings,
+	journal_path: Option<PathBuf>,
+	mut interrupted: Option<Journal>,
+) -> Result<(), TaskError> {
+	let ProgramLinks {
+		rx,
+		file_cmd_tx,
+		com_cmd_tx,
+		mut printer,
+		signal_tx,
+		chamber_cmd_tx,
+		status_tx,
+		mode_tx,
+		notify_tx,
+		registry,
+		calibrations,
+		timer_tx,
+	} = links;
+	let registry = registry.as_deref();
+	let calibrations = calibrations.as_deref();
+	printer.stat("program started...").await;
+	let mut state = TestState::default();
+	state.set_output_format(settings.output_format);
+	state.set_termination_rule(settings.termination);
+	state.set_ir_pulse(settings.ir_pulse);
+	state.set_warmup(settings.warmup);
+	state.set_ocv_rest(settings.ocv_rest);
+	state.set_poll_ms(settings.poll_ms);
+	let mut mode = match &interrupted {
+		Some(journal) => {
+			state.resume(journal.clone());
+			Mode::Resume
+		}
+		None => Mode::default(),
+	};
+	let mut inbox = Inbox { rx, timer_tx };
+	let mut signal = TestSignal::default();
+	let mut last_mode = None;
+	loop {
+		status_tx.send_replace(state.status(mode));
+		if let Some(notify_tx) = &notify_tx
+			&& notifies(mode)
+			&& last_mode != Some(mode)
+		{
+			// a slow webhook mustn't hold up the test, drop the notice instead
+			let _ = notify_tx.try_send(state.status(mode));
+		}
+		last_mode = Some(mode);
+		if let Some(mode_tx) = &mode_tx {
+			// tracing stopping isn't a reason to stop testing
+			let _ = mode_tx.send(mode).await;
+		}
+		if let Some(journal_path) = &journal_path {
+			update_journal(&mut state, mode, journal_path, &mut printer).await;
+		}
+		let next_mode = match mode {
+			Mode::Setup => {
+				setup(
+					&mut state,
+					&mut inbox.rx,
+					&com_cmd_tx,
+					&file_cmd_tx,
+					registry,
+					&status_tx,
+					&mut printer,
+				)
+				.await
+			}
+			Mode::WaitForBattery => {
+				wait_for_battery(
+					&mut state,
+					&mut inbox.rx,
+					&com_cmd_tx,
+					&file_cmd_tx,
+					registry,
+					calibrations,
+					&mut printer,
+				)
+				.await
+			}
+			Mode::WaitForUsrStart => {
+				wait_for_usr_start(
+					&mut state,
+					&mut inbox,
+					&file_cmd_tx,
+					registry,
+					&chamber_cmd_tx,
+					&mut profile,
+					&mut printer,
+				)
+				.await
+			}
+			Mode::Conditioning => {
+				conditioning(
+					&mut state,
+					&mut inbox.rx,
+					&file_cmd_tx,
+					&chamber_cmd_tx,
+					&mut profile,
+					&mut printer,
+				)
+				.await
+			}
+			Mode::Charging => {
+				charging(
+					&mut state,
+					&mut inbox.rx,
+					&com_cmd_tx,
+					&file_cmd_tx,
+					&chamber_cmd_tx,
+					&mut profile,
+					&mut printer,
+				)
+				.await
+			}
+			Mode::Testing => {
+				testing(
+					&mut state,
+					&mut inbox.rx,
+					&com_cmd_tx,
+					&file_cmd_tx,
+					calibrations,
+					&mut printer,
+				)
+				.await
+			}
+			Mode::Resting => {
+				resting(
+					&mut state,
+					&mut inbox.rx,
+					&com_cmd_tx,
+					&file_cmd_tx,
+					&mut printer,
+				)
+				.await
+			}
+			Mode::Autonomous => {
+				autonomous(
+					&mut state,
+					&mut inbox.rx,
+					&com_cmd_tx,
+					&file_cmd_tx,
+					calibrations,
+					&mut printer,
+				)
+				.await
+			}
+			Mode::EndTest => {
+				end_test(
+					&mut state,
+					&com_cmd_tx,
+					&file_cmd_tx,
+					registry,
+					&mut printer,
+				)
+				.await
+			}
+			Mode::Paused => todo!(),
+			Mode::Shutdown => {
+				shutdown(&com_cmd_tx, &file_cmd_tx, &chamber_cmd_tx).await;
+				break;
+			}
+			Mode::CommDC => {
+				comm_dc(
+					&mut state,
+					&mut inbox.rx,
+					&com_cmd_tx,
+					&file_cmd_tx,
+					&mut printer,
+				)
+				.await
+			}
+			Mode::Fault => {
+				fault(
+					&mut state,
+					&mut inbox.rx,
+					&com_cmd_tx,
+					&file_cmd_tx,
+					registry,
+					&mut printer,
+				)
+				.await
+			}
+			Mode::Resume => match interrupted.take() {
+				Some(journal) => {
+					resume(
+						&mut state,
+						&mut inbox.rx,
+						&com_cmd_tx,
+						&file_cmd_tx,
+						journal,
+						&mut printer,
+					)
+					.await
+				}
+				// only the first mode can be Resume
+				None => Ok(Mode::Setup),
+			},
+		};
+		mode = match next_mode {
+			Ok(mode) => mode,
+			Err(e) => {
+				shutdown(&com_cmd_tx, &file_cmd_tx, &chamber_cmd_tx).await;
+				return Err(e);
+			}
+		};
+		if let Some(signal_tx) = &signal_tx
+			&& signal != TestSignal::from(mode)
+		{
+			signal = TestSignal::from(mode);
+			// the signal task drains until it's dropped, it can only be gone if it panicked
+			let _ = signal_tx.send(signal).await;
+		}
+	}
+	Ok(())
+}
+
+/// Tells every task to stop, the ones that already have are skipped
+/// so the load is still turned off when the file task is gone
+async fn shutdown(
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	chamber_cmd_tx: &Option<Sender<ChamberCmd>>,
+) {
+	let _ = com_cmd_tx.send(ComCmd::BICommand(idle_command())).await;
+	let _ = file_cmd_tx.send(FileCmd::CloseFile).await;
+	let _ = file_cmd_tx.send(FileCmd::Shutdown).await;
+	let _ = com_cmd_tx.send(ComCmd::Shutdown).await;
+	if let Some(chamber_cmd_tx) = chamber_cmd_tx {
+		let _ = chamber_cmd_tx.send(ChamberCmd::Shutdown).await;
+	}
+}
+
+async fn comm_dc(
+	state: &mut TestState,
+	event_rx: &mut Receiver<Event>,
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	printer
+		.stat("serial comms disconnected, waiting for reconnect...")
+		.await;
+	// the device could be swapped before it comes back
+	state.unset_device_version();
+	Ok(loop {
+		let event = match event_rx.recv().await {
+			Some(e) => e,
+			None => return Ok(Mode::Shutdown),
+		};
+		match event {
+			Event::ComReconnected => {
+				printer
+					.stat("serial comms reconnected, checking firmware version...")
+					.await;
+			}
+			Event::DeviceVersion(device_version) => {
+				new_device_version(state, device_version, printer).await;
+				if state.ready_for_battery() {
+					// keep the battery ID and output file, the test can be restarted
+					break Mode::WaitForBattery;
+				}
+				break Mode::Setup;
+			}
+			Event::SetSerialDevice(dev_id, baud) => {
+				printer
+					.buf(|tv| write!(tv, "setting device name to: {}", dev_id))
+					.await;
+				com_cmd_tx
+					.send(ComCmd::NewDeviceName(dev_id.clone(), baud))
+					.await?;
+				state.new_device_name(dev_id, baud);
+			}
+			Event::SetOutputFormat(format) => new_output_format(state, format, printer).await,
+			Event::SetNote(text) => new_note(state, text, file_cmd_tx, printer).await?,
+			Event::SetOperator(name) => new_operator(state, name, file_cmd_tx, printer).await?,
+			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
+			Event::SetDisconnect(millivolts) => new_disconnect(state, millivolts, printer).await,
+			Event::SetMaxVoltage(millivolts) => new_max_voltage(state, millivolts, printer).await,
+			Event::SetChemistry(chemistry) => new_chemistry(state, chemistry, printer).await,
+			Event::SetMaxDuration(max) => new_max_duration(state, max, printer).await,
+			Event::SetMaxCapacity(max) => new_max_capacity(state, max, printer).await,
+			Event::SetTrim(_trim) => {
+				printer
+					.stat("can't set the battery interface's trim, it isn't connected")
+					.await;
+			}
+			Event::SetBatteryDetect(_detect) => {
+				printer
+					.stat("can't set the battery interface's battery detection, it isn't connected")
+					.await;
+			}
+			Event::RunSelfTest => {
+				printer
+					.stat("can't run the battery interface's self test, it isn't connected")
+					.await;
+			}
+			Event::SelfTestReport(report) => self_test_reported(report, printer).await,
+			Event::SetLoadModel(model) => new_load_model(state, model, printer).await,
+			Event::SetCurrentSetpoint(setpoint) => {
+				new_current_setpoint(state, setpoint, printer).await
+			}
+			Event::SetWindowSamples(samples) => new_window_samples(state, samples, printer).await,
+			Event::SetFilter(filter) => new_filter(state, filter, printer).await,
+			Event::ScheduleStart(at) => schedule_start(state, at, printer).await,
+			// the test only starts waiting for the user, a new timer is set then
+			Event::ScheduledStart(_) => {}
+			Event::Queue(change) => new_queue(state, change, printer).await,
+			Event::CancelTest => {
+				file_cmd_tx.send(FileCmd::CloseFile).await?;
+				state.end_test();
+				break Mode::Setup;
+			}
+			Event::Shutdown | Event::InternalError => break Mode::Shutdown,
+			Event::FileError(_) => {
+				file_cmd_tx.send(FileCmd::CloseFile).await?;
+				state.end_test();
+				break Mode::Setup;
+			}
+			Event::BattID(_battery_id) => {
+				printer
+					.stat("can't change battery ID while serial comms are disconnected")
+					.await;
+			}
+			Event::StartTest => {
+				printer
+					.stat("can't start test while serial comms are disconnected")
+					.await;
+			}
+			Event::Charge => {
+				printer
+					.stat("can't charge while serial comms are disconnected")
+					.await;
+			}
+			Event::ClearFault => {
+				printer.stat("no fault to clear").await;
+			}
+			Event::UnderCurrentResponse(allow_undercurrent) => {
+				state.set_allow_undercurrent(allow_undercurrent)
+			}
+			// left over from a canceled profile step
+			Event::ChamberStable | Event::ChamberError => {}
+			Event::ComDecodeError(bad_frames) => com_decode_error(bad_frames, printer).await,
+			Event::BatteryPresence(present) => state.set_bat_present(present),
+			Event::StartAutonomous | Event::FetchAutonomousLog => {
+				printer
+					.stat("can't reach the battery interface, serial comms are disconnected")
+					.await;
+			}
+			// asked for by a test that's since ended
+			Event::AutonomousLog(_) => {}
+			// still disconnected or a reply left over from before the disconnect
+			Event::CommDc | Event::ComReply(_) | Event::Replayed(_) => {}
+		}
+	})
+}
+
+/// The calibration for the device the test is on, if it has one
+fn device_calibration(
+	state: &TestState,
+	calibrations: Option<&CalibrationStore>,
+) -> Option<Calibration> {
+	calibrations
+		.zip(state.device_id())
+		.and_then(|(calibrations, device_id)| calibrations.get(&device_id))
+}
+
+/// Save a measurement the battery interface buffered while it wasn't getting commands,
+/// if the test it was taken in is recording. They arrive in order before the live ones.
+/// The load was off for it unless button B held it on, so it isn't checked against the limits.
+async fn replayed(
+	state: &mut TestState,
+	m: Measurement,
+	calibration: Option<&Calibration>,
+	file_cmd_tx: &Sender<FileCmd>,
+) -> Result<(), TaskError> {
+	let recording = state.tested() && state.saved_to().is_some() && state.warmup_due().is_none();
+	if !recording || state.check_staleness(Some(&m)) != Staleness::Fresh {
+		return Ok(());
+	}
+	let m = calibration.map_or(m, |calibration| calibration.apply(&m));
+	file_cmd_tx
+		.send(FileCmd::Push(SaveData {
+			millivolts: m.vbat,
+			milliamps: m.ibat,
+			milliwatts: m.milliwatts,
+			load: m.load,
+			sample_index: m.sample_index,
+			sample_start_ms: state.recorded_dt(m.sample_start_ms),
+			sample_duration_ms: m.sample_duration_ms,
+			temp_centi_c: m.temp_centi_c,
+			load_temp_centi_c: m.load_temp_centi_c,
+		}))
+		.await?;
+	Ok(())
+}
+
+async fn end_test(
+	state: &mut TestState,
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	registry: Option<&BatteryRegistry>,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	com_cmd_tx
+		.send(ComCmd::BICommand(end_test_command()))
+		.await?;
+	if let Some(notes) = state.notes().filter(|notes| notes.ended.is_some()) {
+		file_cmd_tx.send(FileCmd::Notes(notes)).await?;
+	}
+	file_cmd_tx.send(FileCmd::CloseFile).await?;
+	printer.stat("ending test...").await;
+	if let Some(registry) = registry
+		&& let Some(battery_id) = state.battery_id().filter(|_| state.tested())
+		&& let Err(e) = registry.record_test(battery_id)
+	{
+		printer
+			.warn(|tv| write!(tv, "can't count the test in the battery registry:\n{e}"))
+			.await;
+	}
+	state.end_test();
+	if next_in_queue(state, file_cmd_tx, registry, printer).await? {
+		state.set_swap_pending();
+	}
+	Ok(Mode::Setup)
+}
+
+async fn testing(
+	state: &mut TestState,
+	event_rx: &mut Receiver<Event>,
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	calibrations: Option<&CalibrationStore>,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	printer.stat("starting test...").await;
+	state.start_testing();
+	state.reset_staleness();
+	state.reset_termination();
+	let calibration = device_calibration(state, calibrations);
+	if let Some(calibration) = &calibration {
+		let device_id = &calibration.device_id;
+		printer
+			.buf(|tv| write!(tv, "applying the calibration for device ID: {device_id}"))
+			.await;
+	}
+	let mut pulse = state.pulse_due().then(Pulse::default);
+	let mut warmup = state.warmup_due().map(Warmup::new);
+	let mut rest = state.ocv_rest_due().map(Rest::new);
+	let command = if rest.is_some() {
+		printer
+			.stat("resting the battery for its open circuit voltage...")
+			.await;
+		volts_command()
+	} else if pulse.is_some() {
+		printer
+			.stat("pulsing the load to estimate the internal resistance...")
+			.await;
+		volts_command()
+	} else {
+		state.testing_command()
+	};
+	com_cmd_tx.send(ComCmd::BICommand(command)).await?;
+	let next = loop {
+		let event = match event_rx.recv().await {
+			Some(e) => e,
+			None => return Ok(Mode::Shutdown),
+		};
+		match event {
+			Event::SetOutputFormat(format) => new_output_format(state, format, printer).await,
+			Event::SetNote(text) => new_note(state, text, file_cmd_tx, printer).await?,
+			Event::SetOperator(name) => new_operator(state, name, file_cmd_tx, printer).await?,
+			Event::SetCutoff(millivolts) => {
+				new_cutoff(state, millivolts, printer).await;
+				// the battery interface enforces the cutoff too, it's sent once the load is on
+				if rest.is_none() && pulse.is_none() {
+					com_cmd_tx
+						.send(ComCmd::BICommand(state.testing_command()))
+						.await?;
+				}
+			}
+			Event::SetDisconnect(millivolts) => new_disconnect(state, millivolts, printer).await,
+			Event::SetMaxVoltage(millivolts) => new_max_voltage(state, millivolts, printer).await,
+			Event::SetMaxDuration(max) => new_max_duration(state, max, printer).await,
+			Event::SetMaxCapacity(max) => new_max_capacity(state, max, printer).await,
+			Event::SetTrim(_trim) => {
+				printer
+					.stat("can't set the battery interface's trim with a test set up")
+					.await;
+			}
+			Event::SetBatteryDetect(_detect) => {
+				printer
+					.stat("can't set the battery interface's battery detection with a test set up")
+					.await;
+			}
+			Event::RunSelfTest => {
+				printer
+					.stat("can't run the battery interface's self test with a test set up")
+					.await;
+			}
+			Event::SelfTestReport(report) => self_test_reported(report, printer).await,
+			Event::SetLoadModel(model) => {
+				new_load_model(state, model, printer).await;
+				if rest.is_none() && pulse.is_none() {
+					com_cmd_tx
+						.send(ComCmd::BICommand(state.testing_command()))
+						.await?;
+				}
+			}
+			Event::SetCurrentSetpoint(setpoint) => {
+				new_current_setpoint(state, setpoint, printer).await;
+				if rest.is_none() && pulse.is_none() {
+					com_cmd_tx
+						.send(ComCmd::BICommand(state.testing_command()))
+						.await?;
+				}
+			}
+			Event::SetWindowSamples(samples) => {
+				new_window_samples(state, samples, printer).await;
+				if rest.is_none() && pulse.is_none() {
+					com_cmd_tx
+						.send(ComCmd::BICommand(state.testing_command()))
+						.await?;
+				}
+			}
+			Event::SetFilter(filter) => {
+				new_filter(state, filter, printer).await;
+				if rest.is_none() && pulse.is_none() {
+					com_cmd_tx
+						.send(ComCmd::BICommand(state.testing_command()))
+						.await?;
+				}
+			}
+			Event::ScheduleStart(_) => {
+				printer
+					.stat("can't schedule a start, testing already")
+					.await;
+			}
+			Event::ScheduledStart(_) => {}
+			Event::Queue(change) => new_queue(state, change, printer).await,
+			Event::SetChemistry(chemistry) => {
+				new_chemistry(state, chemistry, printer).await;
+				if rest.is_none() && pulse.is_none() {
+					com_cmd_tx
+						.send(ComCmd::BICommand(state.testing_command()))
+						.await?;
+				}
+			}
+			Event::ComReply(mut reply) => {
+				// logged and checked against the limits as the reference meter would read it
+				if let Some(calibration) = &calibration {
+					reply.measurement = reply.measurement.map(|m| calibration.apply(&m));
+				}
+				match reply.fault {
+					Err(f) => {
+						match f.kind {
+							FaultKind::I2C(i2ce) => {
+								printer.error(|b| write!(b, "I2C Fault:\n{i2ce:?}")).await;
+							}
+							FaultKind::Undercurrent => {
+								printer.error_stat("Heater undercurret/not present!").await;
+							}
+							FaultKind::NoBattery => {
+								printer.error_stat("Battery Disconnected!").await;
+							}
+							FaultKind::Overcurrent => {
+								printer.error_stat("Heater overcurrent!").await;
+							}
+							FaultKind::SensorIntegrity => {
+								printer
+									.error_stat(
+										"INA260 power doesn't match voltage x current, readings can't be trusted!",
+									)
+									.await;
+							}
+							FaultKind::OverTemperature => {
+								printer.error_stat("Over temperature!").await;
+							}
+							FaultKind::LoadOverTemperature => {
+								printer.error_stat("Heater over temperature!").await;
+							}
+							FaultKind::LoadThermistor => {
+								printer
+									.error_stat(
+										"Heater thermistor open, its temperature can't be watched!",
+									)
+									.await;
+							}
+							FaultKind::Overvoltage => {
+								printer
+									.error_stat(
+										"Over voltage, the wrong battery or one still charging!",
+									)
+									.await;
+							}
+							FaultKind::LoadStuckOn => {
+								printer
+									.error_stat("Heater stuck on, current with the load off!")
+									.await;
+							}
+						}
+						file_cmd_tx.send(FileCmd::Fault(f)).await?;
+						break Mode::Fault;
+					}
+					Ok(()) if reply.cutoff_reached => {
+						// a pulled battery reads under the cutoff too
+						if reply
+							.measurement
+							.is_some_and(|m| m.vbat < state.limits().disconnect)
+						{
+							printer
+								.error_stat(
+									"battery removed, the battery interface stopped at its cutoff",
+								)
+								.await;
+							state.set_end_reason(EndReason::Removed);
+							break Mode::EndTest;
+						}
+						// we may have missed the measurement at cutoff, the load is off either way
+						printer.stat("battery interface reached cutoff").await;
+						state.set_end_reason(EndReason::Cutoff);
+						break Mode::EndTest;
+					}
+					Ok(()) if reply.local_load == Some(LoadState::Off) => {
+						printer
+							.stat("button B turned the load off, ending the test")
+							.await;
+						break Mode::EndTest;
+					}
+					Ok(()) => match state.check_staleness(reply.measurement.as_ref()) {
+						Staleness::Stalled => {
+							printer
+								.error_stat(
+									"measurements stopped arriving from the battery interface!",
+								)
+								.await;
+							break Mode::EndTest;
+						}
+						Staleness::Repeated => {
+							printer.stat("repeated measurement, not logged").await;
+						}
+						Staleness::Fresh | Staleness::Waiting => match reply.measurement {
+							Some(m) if m.vbat < state.limits().disconnect => {
+								printer
+									.error(|tv| {
+										write!(tv, "battery removed, it's at: {} mV", m.vbat)
+									})
+									.await;
+								state.set_end_reason(EndReason::Removed);
+								break Mode::EndTest;
+							}
+							Some(m) if m.vbat > state.limits().max_voltage => {
+								printer
+									.error(|tv| {
+										write!(
+											tv,
+											"battery is at: {} mV, over the {} mV limit, ending the test",
+											m.vbat,
+											state.limits().max_voltage
+										)
+									})
+									.await;
+								break Mode::EndTest;
+							}
+							Some(m) if rest.is_some() => {
+								rest_measured(
+									state,
+									&mut rest,
+									pulse.is_some(),
+									&m,
+									com_cmd_tx,
+									file_cmd_tx,
+									printer,
+								)
+								.await?;
+							}
+							Some(m) if pulse.is_some() => {
+								pulse_measured(
+									state,
+									&mut pulse,
+									&m,
+									com_cmd_tx,
+									file_cmd_tx,
+									printer,
+								)
+								.await?;
+							}
+							Some(m) => {
+								let reached = state.reached_end(&m);
+								if let Some(reason) = reached {
+									state.set_end_reason(reason);
+								}
+								if reached == Some(EndReason::Cutoff) {
+									// at cutoff, stop testing
+									break Mode::EndTest;
+								}
+								if let Some(w) = &mut warmup
+									&& w.measured(&m)
+								{
+									warmup = None;
+									state.set_warmed_up(m.sample_start_ms);
+									// the limits count from the start of the recording
+									state.reset_termination();
+									printer.stat("warmed up, recording...").await;
+								}
+								if warmup.is_none() {
+									file_cmd_tx
+										.send(FileCmd::Push(SaveData {
+											millivolts: m.vbat,
+											milliamps: m.ibat,
+											milliwatts: m.milliwatts,
+											load: m.load,
+											sample_index: m.sample_index,
+											sample_start_ms: state.recorded_dt(m.sample_start_ms),
+											sample_duration_ms: m.sample_duration_ms,
+											temp_centi_c: m.temp_centi_c,
+											load_temp_centi_c: m.load_temp_centi_c,
+										}))
+										.await?;
+								}
+								let max_current = state.limits().max_current;
+								if m.ibat > max_current {
+									printer
+										.error(|tv| {
+											write!(
+												tv,
+												"current is: {} mA, over the {max_current} mA expected, ending the test",
+												m.ibat
+											)
+										})
+										.await;
+									break Mode::EndTest;
+								}
+								if let Some(reason) = reached {
+									printer
+										.buf(|tv| write!(tv, "ending the test, it {reason}"))
+										.await;
+									break Mode::EndTest;
+								}
+							}
+							None => {
+								// no new data this time, keep testing
+							}
+						},
+					},
+				}
+			}
+			Event::CommDc => break Mode::CommDC,
+			Event::ComReconnected => {}
+			Event::DeviceVersion(device_version) => {
+				new_device_version(state, device_version, printer).await;
+				if !state.device_compatible() {
+					break Mode::EndTest;
+				}
+			}
+			Event::StartTest => {
+				printer.stat("already testing").await;
+			}
+			Event::Charge => {
+				printer.stat("can't charge while testing").await;
+			}
+			Event::CancelTest => break Mode::EndTest,
+			Event::Shutdown | Event::InternalError => break Mode::Shutdown,
+			Event::SetSerialDevice(..) => {
+				printer
+					.stat("can't change serial device while testing")
+					.await;
+			}
+			Event::BattID(_battery_id) => {
+				printer.stat("can't change battery ID while testing").await;
+			}
+			Event::FileError(kind) => {
+				printer
+					.error(|tv| write!(tv, "ending the test, {kind}"))
+					.await;
+				break Mode::EndTest;
+			}
+			Event::ClearFault => {
+				printer.stat("no fault to clear").await;
+			}
+			Event::UnderCurrentResponse(allow_undercurrent) => {
+				state.set_allow_undercurrent(allow_undercurrent)
+			}
+			// left over from a canceled profile step
+			Event::ChamberStable | Event::ChamberError => {}
+			Event::ComDecodeError(bad_frames) => com_decode_error(bad_frames, printer).await,
+			Event::BatteryPresence(present) => state.set_bat_present(present),
+			Event::StartAutonomous | Event::FetchAutonomousLog => {
+				printer.stat("already testing").await;
+			}
+			// asked for by a test that's since ended
+			Event::AutonomousLog(_) => {}
+			Event::Replayed(m) => replayed(state, m, calibration.as_ref(), file_cmd_tx).await?,
+		}
+	};
+	Ok(match next {
+		Mode::EndTest if state.ocv_rest_after().is_some() => Mode::Resting,
+		next => next,
+	})
+}
+
+/// The battery's open circuit voltage after the test, the test's file is left open to save it
+async fn resting(
+	state: &mut TestState,
+	event_rx: &mut Receiver<Event>,
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	let Some(period) = state.ocv_rest_after() else {
+		return Ok(Mode::EndTest);
+	};
+	printer
+		.stat("resting the battery for its open circuit voltage...")
+		.await;
+	com_cmd_tx.send(ComCmd::BICommand(volts_command())).await?;
+	let mut rest = Rest::new(period);
+	Ok(loop {
+		let event = match event_rx.recv().await {
+			Some(e) => e,
+			None => return Ok(Mode::Shutdown),
+		};
+		match event {
+			Event::SetOutputFormat(format) => new_output_format(state, format, printer).await,
+			Event::SetNote(text) => new_note(state, text, file_cmd_tx, printer).await?,
+			Event::SetOperator(name) => new_operator(state, name, file_cmd_tx, printer).await?,
+			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
+			Event::SetDisconnect(millivolts) => new_disconnect(state, millivolts, printer).await,
+			Event::SetMaxVoltage(millivolts) => new_max_voltage(state, millivolts, printer).await,
+			Event::SetChemistry(chemistry) => new_chemistry(state, chemistry, printer).await,
+			Event::SetMaxDuration(max) => new_max_duration(state, max, printer).await,
+			Event::SetMaxCapacity(max) => new_max_capacity(state, max, printer).await,
+			Event::SetTrim(_trim) => {
+				printer
+					.stat("can't set the battery interface's trim with a test set up")
+					.await;
+			}
+			Event::SetBatteryDetect(_detect) => {
+				printer
+					.stat("can't set the battery interface's battery detection with a test set up")
+					.await;
+			}
+			Event::RunSelfTest => {
+				printer
+					.stat("can't run the battery interface's self test with a test set up")
+					.await;
+			}
+			Event::SelfTestReport(report) => self_test_reported(report, printer).await,
+			Event::SetLoadModel(model) => new_load_model(state, model, printer).await,
+			Event::SetCurrentSetpoint(setpoint) => {
+				new_current_setpoint(state, setpoint, printer).await
+			}
+			Event::SetWindowSamples(samples) => new_window_samples(state, samples, printer).await,
+			Event::SetFilter(filter) => new_filter(state, filter, printer).await,
+			Event::ScheduleStart(_) => {
+				printer
+					.stat("can't schedule a start, the test is resting")
+					.await;
+			}
+			Event::ScheduledStart(_) => {}
+			Event::Queue(change) => new_queue(state, change, printer).await,
+			Event::ComReply(reply) => match reply.fault {
+				Err(f) => {
+					file_cmd_tx.send(FileCmd::Fault(f)).await?;
+					break Mode::Fault;
+				}
+				Ok(()) => match state.check_staleness(reply.measurement.as_ref()) {
+					Staleness::Stalled => {
+						printer
+							.warn_stat(
+								"measurements stopped arriving while resting, no voltage after",
+							)
+							.await;
+						break Mode::EndTest;
+					}
+					Staleness::Fresh => match reply.measurement {
+						Some(m) if m.vbat < state.limits().disconnect => {
+							printer
+								.warn_stat("battery disconnected while resting, no voltage after")
+								.await;
+							break Mode::EndTest;
+						}
+						Some(m) => {
+							if let Some(ocv) = rest.measured(&m) {
+								state.set_ocv_after(ocv);
+								printer
+									.buf(|tv| write!(tv, "open circuit voltage after: {ocv} mV"))
+									.await;
+								break Mode::EndTest;
+							}
+						}
+						None => {}
+					},
+					Staleness::Repeated | Staleness::Waiting => {}
+				},
+			},
+			Event::CommDc => {
+				printer
+					.warn_stat("lost serial comms while resting, no voltage after")
+					.await;
+				break Mode::EndTest;
+			}
+			Event::ComReconnected => {}
+			Event::DeviceVersion(device_version) => {
+				new_device_version(state, device_version, printer).await;
+			}
+			Event::StartTest => {
+				printer
+					.stat("can't start a test, the last is resting")
+					.await;
+			}
+			Event::Charge => {
+				printer.stat("can't charge, the test is resting").await;
+			}
+			Event::CancelTest => break Mode::EndTest,
+			Event::Shutdown | Event::InternalError => break Mode::Shutdown,
+			Event::SetSerialDevice(..) => {
+				printer
+					.stat("can't change serial device while resting")
+					.await;
+			}
+			Event::BattID(_battery_id) => {
+				printer.stat("can't change battery ID while resting").await;
+			}
+			Event::FileError(kind) => {
+				printer
+					.error(|tv| write!(tv, "ending the test, {kind}"))
+					.await;
+				break Mode::EndTest;
+			}
+			Event::ClearFault => {
+				printer.stat("no fault to clear").await;
+			}
+			Event::UnderCurrentResponse(allow_undercurrent) => {
+				state.set_allow_undercurrent(allow_undercurrent)
+			}
+			Event::ChamberStable | Event::ChamberError => {}
+			Event::ComDecodeError(bad_frames) => com_decode_error(bad_frames, printer).await,
+			Event::BatteryPresence(present) => state.set_bat_present(present),
+			Event::StartAutonomous | Event::FetchAutonomousLog => {
+				printer
+					.stat("test already ended, resting the battery")
+					.await;
+			}
+			// asked for by a test that's since ended
+			Event::AutonomousLog(_) => {}
+			// the test they belong to isn't recording
+			Event::Replayed(_) => {}
+		}
+	})
+}
+
+/// The battery interface runs the test without the PC and logs it to its flash,
+/// so the test goes on if this PC sleeps or crashes. Its log is saved as the test
+/// once the user asks for it, which ends the test.
+async fn autonomous(
+	state: &mut TestState,
+	event_rx: &mut Receiver<Event>,
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	calibrations: Option<&CalibrationStore>,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	let calibration = device_calibration(state, calibrations);
+	if state.autonomous() {
+		printer
+			.stat("saving the battery interface's autonomous log...")
+			.await;
+		com_cmd_tx.send(ComCmd::DumpAutonomousLog).await?;
+	} else {
+		com_cmd_tx
+			.send(ComCmd::StartAutonomous(state.autonomous_test()))
+			.await?;
+		state.set_autonomous();
+		printer
+			.stat(
+				"the battery interface is testing on its own, save its log with `battery-tester-client autonomous --fetch` once it's done",
+			)
+			.await;
+	}
+	state.start_testing();
+	let mut saved: u32 = 0;
+	let mut faulted = false;
+	Ok(loop {
+		let event = match event_rx.recv().await {
+			Some(e) => e,
+			None => return Ok(Mode::Shutdown),
+		};
+		match event {
+			Event::FetchAutonomousLog => {
+				printer
+					.stat("saving the battery interface's autonomous log...")
+					.await;
+				com_cmd_tx.send(ComCmd::DumpAutonomousLog).await?;
+			}
+			Event::AutonomousLog(Some(sample)) => {
+				let m = Measurement {
+					vbat: sample.vbat,
+					ibat: sample.ibat,
+					milliwatts: MilliWatt::new(
+						u32::from(u16::from(sample.vbat))
+							* u32::from(i16::from(sample.ibat).unsigned_abs())
+							/ 1000,
+					),
+					sample_index: saved,
+					sample_start_ms: u64::from(sample.sample_start_ms),
+					sample_duration_ms: u64::from(sample.sample_duration_ms),
+					temp_centi_c: None,
+					load_temp_centi_c: None,
+					fan_on: false,
+					load: None,
+				};
+				let m = calibration
+					.as_ref()
+					.map_or(m, |calibration| calibration.apply(&m));
+				file_cmd_tx
+					.send(FileCmd::Push(SaveData {
+						millivolts: m.vbat,
+						milliamps: m.ibat,
+						milliwatts: m.milliwatts,
+						load: m.load,
+						sample_index: m.sample_index,
+						sample_start_ms: m.sample_start_ms,
+						sample_duration_ms: m.sample_duration_ms,
+						temp_centi_c: m.temp_centi_c,
+						load_temp_centi_c: m.load_temp_centi_c,
+					}))
+					.await?;
+				saved += 1;
+			}
+			Event::AutonomousLog(None) if saved == 0 => {
+				printer
+					.stat("the battery interface hasn't logged anything yet")
+					.await;
+			}
+			Event::AutonomousLog(None) => {
+				printer
+					.buf(|tv| write!(tv, "saved {saved} samples from the battery interface"))
+					.await;
+				break Mode::EndTest;
+			}
+			Event::ComReply(reply) => {
+				if let Err(f) = reply.fault
+					&& !faulted
+				{
+					faulted = true;
+					printer
+						.error(|tv| {
+							write!(
+								tv,
+								"the autonomous test ended on a fault, its log up to it can still be saved:\n{f:?}"
+							)
+						})
+						.await;
+				}
+			}
+			Event::SetOutputFormat(format) => new_output_format(state, format, printer).await,
+			Event::SetNote(text) => new_note(state, text, file_cmd_tx, printer).await?,
+			Event::SetOperator(name) => new_operator(state, name, file_cmd_tx, printer).await?,
+			Event::SetCutoff(_)
+			| Event::SetDisconnect(_)
+			| Event::SetMaxVoltage(_)
+			| Event::SetChemistry(_)
+			| Event::SetMaxDuration(_)
+			| Event::SetMaxCapacity(_)
+			| Event::SetLoadModel(_)
+			| Event::SetCurrentSetpoint(_)
+			| Event::SetWindowSamples(_)
+			| Event::SetFilter(_) => {
+				printer
+					.stat("the battery interface has the test, it can't be changed")
+					.await;
+			}
+			Event::SetTrim(_trim) => {
+				printer
+					.stat("can't set the battery interface's trim with a test set up")
+					.await;
+			}
+			Event::SetBatteryDetect(_detect) => {
+				printer
+					.stat("can't set the battery interface's battery detection with a test set up")
+					.await;
+			}
+			Event::RunSelfTest => {
+				printer
+					.stat("can't run the battery interface's self test with a test set up")
+					.await;
+			}
+			Event::SelfTestReport(report) => self_test_reported(report, printer).await,
+			Event::ScheduleStart(_) => {
+				printer.stat("test already started").await;
+			}
+			Event::ScheduledStart(_) => {}
+			Event::Queue(change) => new_queue(state, change, printer).await,
+			Event::StartTest | Event::Charge | Event::StartAutonomous => {
+				printer
+					.stat("the battery interface is already testing on its own")
+					.await;
+			}
+			Event::CommDc => break Mode::CommDC,
+			Event::ComReconnected => {}
+			Event::DeviceVersion(device_version) => {
+				new_device_version(state, device_version, printer).await;
+				if !state.device_compatible() {
+					break Mode::EndTest;
+				}
+			}
+			Event::CancelTest => break Mode::EndTest,
+			Event::SetSerialDevice(..) => {
+				printer
+					.stat("can't change serial device while the battery interface is testing")
+					.await;
+			}
+			Event::BattID(_battery_id) => {
+				printer.stat("test already started").await;
+			}
+			Event::Shutdown | Event::InternalError => break Mode::Shutdown,
+			Event::FileError(_) => break Mode::EndTest,
+			Event::ClearFault => com_cmd_tx.send(ComCmd::ClearFault).await?,
+			Event::UnderCurrentResponse(allow_undercurrent) => {
+				state.set_allow_undercurrent(allow_undercurrent)
+			}
+			Event::ChamberStable | Event::ChamberError => {}
+			Event::ComDecodeError(bad_frames) => com_decode_error(bad_frames, printer).await,
+			Event::BatteryPresence(present) => state.set_bat_present(present),
+			// the measurements are in the autonomous log
+			Event::Replayed(_) => {}
+		}
+	})
+}
+
+async fn wait_for_usr_start(
+	state: &mut TestState,
+	inbox: &mut Inbox,
+	file_cmd_tx: &Sender<FileCmd>,
+	registry: Option<&BatteryRegistry>,
+	chamber_cmd_tx: &Option<Sender<ChamberCmd>>,
+	profile: &mut ProfileRun,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	printer.stat("waiting for user to start test...").await;
+	// scheduled while setting up, or it came while waiting for the battery
+	if let Some(start_at) = state.start_at() {
+		inbox.start_timer(start_at);
+	}
+	// the newest measurement, the test won't start over the max voltage
+	let mut vbat: Option<MilliVolt> = None;
+	Ok(loop {
+		let event = match inbox.rx.recv().await {
+			Some(e) => e,
+			None => return Ok(Mode::Shutdown),
+		};
+		match event {
+			Event::BattID(battery_id) => {
+				if let Err(e) = new_test(state, battery_id, file_cmd_tx, registry, printer).await? {
+					printer.buf(|tv| write!(tv, "{e}")).await;
+					break Mode::EndTest;
+				}
+			}
+			Event::StartTest | Event::Charge | Event::StartAutonomous if state.autonomous() => {
+				printer
+					.stat(
+						"the battery interface is testing on its own, save its log with `battery-tester-client autonomous --fetch` first",
+					)
+					.await;
+			}
+			Event::StartTest => {
+				state.set_start_at(None);
+				if refused_over_voltage(state, vbat, printer).await {
+					continue;
+				}
+				break start_profile_step(state, chamber_cmd_tx, profile, printer).await?;
+			}
+			Event::Charge => break Mode::Charging,
+			Event::ComReply(reply) => match reply.fault {
+				Ok(()) => {
+					if let Some(m) = reply.measurement {
+						vbat = Some(m.vbat);
+						if m.vbat < state.limits().disconnect {
+							printer
+								.stat("battery removed before the test started")
+								.await;
+							break Mode::WaitForBattery;
+						}
+						// double check that the battery is over cutoff
+						if !(m.vbat > state.cutoff()) {
+							break Mode::WaitForBattery;
+						}
+					}
+					if reply.local_load == Some(LoadState::On) {
+						state.set_start_at(None);
+						// the next command turns the load back off
+						if refused_over_voltage(state, vbat, printer).await {
+							continue;
+						}
+						printer.stat("button B started the test").await;
+						break start_profile_step(state, chamber_cmd_tx, profile, printer).await?;
+					}
+				}
+				Err(f) => {
+					printer.error(|tv| write!(tv, "fault:\n{f:?}")).await;
+					break Mode::Fault;
+				}
+			},
+			Event::SetOutputFormat(format) => new_output_format(state, format, printer).await,
+			Event::SetNote(text) => new_note(state, text, file_cmd_tx, printer).await?,
+			Event::SetOperator(name) => new_operator(state, name, file_cmd_tx, printer).await?,
+			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
+			Event::SetDisconnect(millivolts) => new_disconnect(state, millivolts, printer).await,
+			Event::SetMaxVoltage(millivolts) => new_max_voltage(state, millivolts, printer).await,
+			Event::SetChemistry(chemistry) => new_chemistry(state, chemistry, printer).await,
+			Event::SetMaxDuration(max) => new_max_duration(state, max, printer).await,
+			Event::SetMaxCapacity(max) => new_max_capacity(state, max, printer).await,
+			Event::SetTrim(_trim) => {
+				printer
+					.stat("can't set the battery interface's trim with a test set up")
+					.await;
+			}
+			Event::SetBatteryDetect(_detect) => {
+				printer
+					.stat("can't set the battery interface's battery detection with a test set up")
+					.await;
+			}
+			Event::RunSelfTest => {
+				printer
+					.stat("can't run the battery interface's self test with a test set up")
+					.await;
+			}
+			Event::SelfTestReport(report) => self_test_reported(report, printer).await,
+			Event::SetLoadModel(model) => new_load_model(state, model, printer).await,
+			Event::SetCurrentSetpoint(setpoint) => {
+				new_current_setpoint(state, setpoint, printer).await
+			}
+			Event::SetWindowSamples(samples) => new_window_samples(state, samples, printer).await,
+			Event::SetFilter(filter) => new_filter(state, filter, printer).await,
+			Event::ScheduleStart(at) => {
+				schedule_start(state, at, printer).await;
+				if let Some(at) = at {
+					inbox.start_timer(at);
+				}
+			}
+			Event::ScheduledStart(at) if state.start_at() == Some(at) => {
+				state.set_start_at(None);
+				if refused_over_voltage(state, vbat, printer).await {
+					continue;
+				}
+				printer.stat("starting the scheduled test...").await;
+				break start_profile_step(state, chamber_cmd_tx, profile, printer).await?;
+			}
+			// canceled or moved since
+			Event::ScheduledStart(_) => {}
+			Event::Queue(change) => new_queue(state, change, printer).await,
+			Event::CommDc => break Mode::CommDC,
+			Event::ComReconnected => {}
+			Event::DeviceVersion(device_version) => {
+				new_device_version(state, device_version, printer).await;
+				if !state.device_compatible() {
+					break Mode::EndTest;
+				}
+			}
+			Event::CancelTest => break Mode::EndTest,
+			Event::SetSerialDevice(..) => {
+				// TODO: warn user
+			}
+			Event::Shutdown | Event::InternalError => break Mode::Shutdown,
+			Event::FileError(_) => break Mode::EndTest,
+			Event::ClearFault => {
+				printer.stat("no fault to clear").await;
+			}
+			Event::UnderCurrentResponse(allow_undercurrent) => {
+				state.set_allow_undercurrent(allow_undercurrent)
+			}
+			// left over from a canceled profile step
+			Event::ChamberStable | Event::ChamberError => {}
+			Event::ComDecodeError(bad_frames) => com_decode_error(bad_frames, printer).await,
+			Event::BatteryPresence(present) => state.set_bat_present(present),
+			Event::StartAutonomous => break Mode::Autonomous,
+			Event::FetchAutonomousLog => {
+				state.set_autonomous();
+				break Mode::Autonomous;
+			}
+			// asked for by a test that's since ended
+			Event::AutonomousLog(_) => {}
+			// the test they belong to isn't recording
+			Event::Replayed(_) => {}
+		}
+	})
+}
+
+/// Take the next profile step, without a profile the test just starts
+async fn start_profile_step(
+	state: &mut TestState,
+	chamber_cmd_tx: &Option<Sender<ChamberCmd>>,
+	profile: &mut ProfileRun,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	let next_mode = match profile.next_step() {
+		None => Mode::Testing,
+		Some(ProfileStep::Discharge { cutoff_mv }) => {
+			if let Some(millivolts) = cutoff_mv {
+				new_cutoff(state, millivolts.into(), printer).await;
+			}
+			if profile.at_start() {
+				printer
+					.stat("last profile step, the profile starts over after this test")
+					.await;
+			}
+			Mode::Testing
+		}
+		Some(ProfileStep::Chamber(step)) => match chamber_cmd_tx {
+			Some(chamber_cmd_tx) => {
+				chamber_cmd_tx.send(ChamberCmd::Stabilize(step)).await?;
+				Mode::Conditioning
+			}
+			None => {
+				printer
+					.stat("profile has a chamber step but there's no chamber")
+					.await;
+				profile.retry_step();
+				Mode::WaitForUsrStart
+			}
+		},
+	};
+	Ok(next_mode)
+}
+
+/// Battery is connected and idle while the chamber gets to temperature
+async fn conditioning(
+	state: &mut TestState,
+	event_rx: &mut Receiver<Event>,
+	file_cmd_tx: &Sender<FileCmd>,
+	chamber_cmd_tx: &Option<Sender<ChamberCmd>>,
+	profile: &mut ProfileRun,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	printer
+		.stat("waiting for the chamber to stabilize...")
+		.await;
+	let next_mode = loop {
+		let event = match event_rx.recv().await {
+			Some(e) => e,
+			None => return Ok(Mode::Shutdown),
+		};
+		match event {
+			Event::ChamberStable => {
+				return start_profile_step(state, chamber_cmd_tx, profile, printer).await;
+			}
+			Event::ChamberError => {
+				// the chamber task already gave up, start again from this step
+				profile.retry_step();
+				return Ok(Mode::WaitForUsrStart);
+			}
+			Event::ComReply(reply) => match reply.fault {
+				Ok(()) => {
+					if let Some(m) = reply.measurement
+						&& !(m.vbat > state.cutoff())
+					{
+						break Mode::WaitForBattery;
+					}
+				}
+				Err(f) => {
+					printer.error(|tv| write!(tv, "fault:\n{f:?}")).await;
+					break Mode::Fault;
+				}
+			},
+			Event::SetOutputFormat(format) => new_output_format(state, format, printer).await,
+			Event::SetNote(text) => new_note(state, text, file_cmd_tx, printer).await?,
+			Event::SetOperator(name) => new_operator(state, name, file_cmd_tx, printer).await?,
+			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
+			Event::SetDisconnect(millivolts) => new_disconnect(state, millivolts, printer).await,
+			Event::SetMaxVoltage(millivolts) => new_max_voltage(state, millivolts, printer).await,
+			Event::SetChemistry(chemistry) => new_chemistry(state, chemistry, printer).await,
+			Event::SetMaxDuration(max) => new_max_duration(state, max, printer).await,
+			Event::SetMaxCapacity(max) => new_max_capacity(state, max, printer).await,
+			Event::SetTrim(_trim) => {
+				printer
+					.stat("can't set the battery interface's trim with a test set up")
+					.await;
+			}
+			Event::SetBatteryDetect(_detect) => {
+				printer
+					.stat("can't set the battery interface's battery detection with a test set up")
+					.await;
+			}
+			Event::RunSelfTest => {
+				printer
+					.stat("can't run the battery interface's self test with a test set up")
+					.await;
+			}
+			Event::SelfTestReport(report) => self_test_reported(report, printer).await,
+			Event::SetLoadModel(model) => new_load_model(state, model, printer).await,
+			Event::SetCurrentSetpoint(setpoint) => {
+				new_current_setpoint(state, setpoint, printer).await
+			}
+			Event::SetWindowSamples(samples) => new_window_samples(state, samples, printer).await,
+			Event::SetFilter(filter) => new_filter(state, filter, printer).await,
+			Event::ScheduleStart(at) => schedule_start(state, at, printer).await,
+			Event::ScheduledStart(_) => {}
+			Event::Queue(change) => new_queue(state, change, printer).await,
+			Event::CommDc => break Mode::CommDC,
+			Event::ComReconnected => {}
+			Event::DeviceVersion(device_version) => {
+				new_device_version(state, device_version, printer).await;
+				if !state.device_compatible() {
+					break Mode::EndTest;
+				}
+			}
+			Event::StartTest => {
+				printer
+					.stat("already starting, waiting for the chamber")
+					.await;
+			}
+			Event::Charge => {
+				printer
+					.stat("can't charge while waiting for the chamber")
+					.await;
+			}
+			Event::CancelTest => {
+				if let Some(chamber_cmd_tx) = chamber_cmd_tx {
+					chamber_cmd_tx.send(ChamberCmd::Stop).await?;
+				}
+				profile.restart();
+				return Ok(Mode::EndTest);
+			}
+			Event::SetSerialDevice(..) => {
+				printer
+					.stat("can't change serial device while waiting for the chamber")
+					.await;
+			}
+			Event::BattID(_battery_id) => {
+				printer
+					.stat("can't change battery ID while waiting for the chamber")
+					.await;
+			}
+			Event::Shutdown | Event::InternalError => return Ok(Mode::Shutdown),
+			Event::FileError(_) => break Mode::EndTest,
+			Event::ClearFault => {
+				printer.stat("no fault to clear").await;
+			}
+			Event::UnderCurrentResponse(allow_undercurrent) => {
+				state.set_allow_undercurrent(allow_undercurrent)
+			}
+			Event::ComDecodeError(bad_frames) => com_decode_error(bad_frames, printer).await,
+			Event::BatteryPresence(present) => state.set_bat_present(present),
+			Event::StartAutonomous | Event::FetchAutonomousLog => {
+				printer.stat("test already started").await;
+			}
+			// asked for by a test that's since ended
+			Event::AutonomousLog(_) => {}
+			// the test they belong to isn't recording
+			Event::Replayed(_) => {}
+		}
+	};
+	// leaving early, the chamber step has to be run again
+	if let Some(chamber_cmd_tx) = chamber_cmd_tx {
+		chamber_cmd_tx.send(ChamberCmd::Stop).await?;
+	}
+	profile.retry_step();
+	Ok(next_mode)
+}
+
+/// Load is off while a charger fills the battery, then the test starts
+async fn charging(
+	state: &mut TestState,
+	event_rx: &mut Receiver<Event>,
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	chamber_cmd_tx: &Option<Sender<ChamberCmd>>,
+	profile: &mut ProfileRun,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	printer
+		.stat("waiting for the charger, the test starts once the battery is full...")
+		.await;
+	com_cmd_tx.send(ComCmd::BICommand(volts_command())).await?;
+	let mut monitor = ChargeMonitor::default();
+	let mut charge_state = ChargeState::Waiting;
+	Ok(loop {
+		let event = match event_rx.recv().await {
+			Some(e) => e,
+			None => return Ok(Mode::Shutdown),
+		};
+		match event {
+			Event::ComReply(reply) => match (reply.fault, reply.measurement) {
+				(Err(f), _) => {
+					printer.error(|tv| write!(tv, "fault:\n{f:?}")).await;
+					break Mode::Fault;
+				}
+				(Ok(()), Some(m)) => match (charge_state, monitor.update(&m)) {
+					(_, ChargeState::Full) => {
+						printer
+							.buf(|tv| write!(tv, "battery full at: {} mV", m.vbat))
+							.await;
+						break start_profile_step(state, chamber_cmd_tx, profile, printer).await?;
+					}
+					(_, ChargeState::TimedOut) => {
+						printer
+							.error(|tv| {
+								write!(
+									tv,
+									"the battery still isn't full at: {} mV, check the charger",
+									m.vbat
+								)
+							})
+							.await;
+						break Mode::EndTest;
+					}
+					(ChargeState::Waiting, ChargeState::Charging) => {
+						printer
+							.buf(|tv| {
+								write!(tv, "charging at: {} mA", i16::from(m.ibat).unsigned_abs())
+							})
+							.await;
+						charge_state = ChargeState::Charging;
+					}
+					(_, new_state) => charge_state = new_state,
+				},
+				(Ok(()), None) => {}
+			},
+			Event::StartTest => {
+				printer.stat("skipping the rest of the charge").await;
+				break start_profile_step(state, chamber_cmd_tx, profile, printer).await?;
+			}
+			Event::Charge => {
+				printer.stat("already charging").await;
+			}
+			// stop watching the charge, the battery may be below cutoff
+			Event::CancelTest => break Mode::WaitForBattery,
+			Event::SetOutputFormat(format) => new_output_format(state, format, printer).await,
+			Event::SetNote(text) => new_note(state, text, file_cmd_tx, printer).await?,
+			Event::SetOperator(name) => new_operator(state, name, file_cmd_tx, printer).await?,
+			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
+			Event::SetDisconnect(millivolts) => new_disconnect(state, millivolts, printer).await,
+			Event::SetMaxVoltage(millivolts) => new_max_voltage(state, millivolts, printer).await,
+			Event::SetChemistry(chemistry) => new_chemistry(state, chemistry, printer).await,
+			Event::SetMaxDuration(max) => new_max_duration(state, max, printer).await,
+			Event::SetMaxCapacity(max) => new_max_capacity(state, max, printer).await,
+			Event::SetTrim(_trim) => {
+				printer
+					.stat("can't set the battery interface's trim with a test set up")
+					.await;
+			}
+			Event::SetBatteryDetect(_detect) => {
+				printer
+					.stat("can't set the battery interface's battery detection with a test set up")
+					.await;
+			}
+			Event::RunSelfTest => {
+				printer
+					.stat("can't run the battery interface's self test with a test set up")
+					.await;
+			}
+			Event::SelfTestReport(report) => self_test_reported(report, printer).await,
+			Event::SetLoadModel(model) => new_load_model(state, model, printer).await,
+			Event::SetCurrentSetpoint(setpoint) => {
+				new_current_setpoint(state, setpoint, printer).await
+			}
+			Event::SetWindowSamples(samples) => new_window_samples(state, samples, printer).await,
+			Event::SetFilter(filter) => new_filter(state, filter, printer).await,
+			Event::ScheduleStart(at) => schedule_start(state, at, printer).await,
+			Event::ScheduledStart(_) => {}
+			Event::Queue(change) => new_queue(state, change, printer).await,
+			Event::CommDc => break Mode::CommDC,
+			Event::ComReconnected => {}
+			Event::DeviceVersion(device_version) => {
+				new_device_version(state, device_version, printer).await;
+				if !state.device_compatible() {
+					break Mode::EndTest;
+				}
+			}
+			Event::SetSerialDevice(..) => {
+				printer
+					.stat("can't change serial device while charging")
+					.await;
+			}
+			Event::BattID(_battery_id) => {
+				printer.stat("can't change battery ID while charging").await;
+			}
+			Event::Shutdown | Event::InternalError => break Mode::Shutdown,
+			Event::FileError(_) => break Mode::EndTest,
+			Event::ClearFault => {
+				printer.stat("no fault to clear").await;
+			}
+			Event::UnderCurrentResponse(allow_undercurrent) => {
+				state.set_allow_undercurrent(allow_undercurrent)
+			}
+			// left over from a canceled profile step
+			Event::ChamberStable | Event::ChamberError => {}
+			Event::ComDecodeError(bad_frames) => com_decode_error(bad_frames, printer).await,
+			Event::BatteryPresence(present) => state.set_bat_present(present),
+			Event::StartAutonomous | Event::FetchAutonomousLog => {
+				printer
+					.stat("already charging the battery for the test")
+					.await;
+			}
+			// asked for by a test that's since ended
+			Event::AutonomousLog(_) => {}
+			// the test they belong to isn't recording
+			Event::Replayed(_) => {}
+		}
+	})
+}
+
+async fn wait_for_battery(
+	state: &mut TestState,
+	event_rx: &mut Receiver<Event>,
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	registry: Option<&BatteryRegistry>,
+	calibrations: Option<&CalibrationStore>,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	let calibration = device_calibration(state, calibrations);
+	if state.swap_pending() {
+		printer
+			.stat("swap the tested battery for the next one in the queue...")
+			.await;
+	} else {
+		printer.stat("waiting for battery connection...").await;
+	}
+	com_cmd_tx.send(ComCmd::BICommand(volts_command())).await?;
+	// once for each battery too flat to test
+	let mut warned_flat = false;
+	Ok(loop {
+		let event = match event_rx.recv().await {
+			Some(e) => e,
+			None => return Ok(Mode::Shutdown),
+		};
+		match event {
+			Event::BattID(battery_id) => {
+				if let Err(e) = new_test(state, battery_id, file_cmd_tx, registry, printer).await? {
+					printer.buf(|tv| write!(tv, "{e}")).await;
+					break Mode::EndTest;
+				}
+			}
+			Event::SetOutputFormat(format) => new_output_format(state, format, printer).await,
+			Event::SetNote(text) => new_note(state, text, file_cmd_tx, printer).await?,
+			Event::SetOperator(name) => new_operator(state, name, file_cmd_tx, printer).await?,
+			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
+			Event::SetDisconnect(millivolts) => new_disconnect(state, millivolts, printer).await,
+			Event::SetMaxVoltage(millivolts) => new_max_voltage(state, millivolts, printer).await,
+			Event::SetChemistry(chemistry) => new_chemistry(state, chemistry, printer).await,
+			Event::SetMaxDuration(max) => new_max_duration(state, max, printer).await,
+			Event::SetMaxCapacity(max) => new_max_capacity(state, max, printer).await,
+			Event::SetTrim(trim) => new_trim(trim, com_cmd_tx, printer).await?,
+			Event::SetBatteryDetect(detect) => {
+				new_battery_detect(detect, com_cmd_tx, printer).await?
+			}
+			Event::RunSelfTest => com_cmd_tx.send(ComCmd::SelfTest).await?,
+			Event::SelfTestReport(report) => self_test_reported(report, printer).await,
+			Event::SetLoadModel(model) => new_load_model(state, model, printer).await,
+			Event::SetCurrentSetpoint(setpoint) => {
+				new_current_setpoint(state, setpoint, printer).await
+			}
+			Event::SetWindowSamples(samples) => new_window_samples(state, samples, printer).await,
+			Event::SetFilter(filter) => new_filter(state, filter, printer).await,
+			Event::ScheduleStart(at) => schedule_start(state, at, printer).await,
+			Event::ScheduledStart(_) => {}
+			Event::Queue(change) => new_queue(state, change, printer).await,
+			Event::StartTest => {
+				printer
+					.stat("can't start test while waiting for battery")
+					.await;
+			}
+			Event::Charge => break Mode::Charging,
+			Event::CommDc => {
+				break Mode::CommDC;
+			}
+			Event::ComReconnected => {}
+			Event::DeviceVersion(device_version) => {
+				new_device_version(state, device_version, printer).await;
+				if !state.device_compatible() {
+					break Mode::EndTest;
+				}
+			}
+			Event::ComReply(reply) => match reply.fault {
+				Ok(()) => {
+					if let Some(m) = reply.measurement {
+						if state.swap_pending() {
+							// the battery tested before recovers over the cutoff,
+							// it's there until its voltage drops out
+							if m.vbat < state.limits().disconnect {
+								state.battery_swapped();
+								printer
+									.stat("battery disconnected, connect the next one")
+									.await;
+							}
+						} else if m.vbat > state.cutoff() {
+							// battery connected, wait for user to start
+							break Mode::WaitForUsrStart;
+						} else if state.bat_present() == Some(true) && !warned_flat {
+							// connected, but too flat to test
+							warned_flat = true;
+							let cutoff = state.cutoff();
+							printer
+								.warn(|tv| {
+									write!(
+										tv,
+										"battery connected at {} mV, at or below the {cutoff} mV cutoff, it can't be tested",
+										m.vbat
+									)
+								})
+								.await;
+						} else {
+							// battery not connected yet
+						}
+					}
+				}
+				Err(f) => {
+					printer.error(|tv| write!(tv, "fault:\n{f:?}")).await;
+					break Mode::Fault;
+				}
+			},
+			Event::CancelTest => break Mode::EndTest,
+			Event::SetSerialDevice(..) => {
+				printer
+					.stat("can't change serial device while waiting for battery")
+					.await;
+			}
+			Event::Shutdown | Event::InternalError => break Mode::Shutdown,
+			Event::FileError(_) => break Mode::EndTest,
+			Event::ClearFault => {
+				printer.stat("no fault to clear").await;
+			}
+			Event::UnderCurrentResponse(allow_undercurrent) => {
+				state.set_allow_undercurrent(allow_undercurrent)
+			}
+			// left over from a canceled profile step
+			Event::ChamberStable | Event::ChamberError => {}
+			Event::ComDecodeError(bad_frames) => com_decode_error(bad_frames, printer).await,
+			Event::BatteryPresence(present) => {
+				state.set_bat_present(present);
+				if present {
+					printer.stat("battery connected").await;
+				} else {
+					warned_flat = false;
+					// the battery interface's input knows before the voltage drops out
+					if state.swap_pending() {
+						state.battery_swapped();
+						printer
+							.stat("battery disconnected, connect the next one")
+							.await;
+					}
+				}
+			}
+			Event::StartAutonomous => {
+				printer.stat("connect the battery first").await;
+			}
+			Event::FetchAutonomousLog => {
+				state.set_autonomous();
+				break Mode::Autonomous;
+			}
+			// asked for by a test that's since ended
+			Event::AutonomousLog(_) => {}
+			// a test cut off by the link going down is still recording
+			Event::Replayed(m) => replayed(state, m, calibration.as_ref(), file_cmd_tx).await?,
+		}
+	})
+}
+
+async fn fault(
+	state: &mut TestState,
+	event_rx: &mut Receiver<Event>,
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	registry: Option<&BatteryRegistry>,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	com_cmd_tx.send(ComCmd::BICommand(idle_command())).await?;
+	printer.stat("ending test, clear fault to continue").await;
+	if state.bat_present() == Some(false) {
+		printer.stat("the battery isn't connected").await;
+	}
+	file_cmd_tx.send(FileCmd::CloseFile).await?;
+	state.end_test();
+	loop {
+		let event = match event_rx.recv().await {
+			Some(e) => e,
+			None => return Ok(Mode::Shutdown),
+		};
+		match event {
+			Event::BattID(battery_id) => {
+				if let Err(e) = new_test(state, battery_id, file_cmd_tx, registry, printer).await? {
+					printer.buf(|tv| write!(tv, "{e}")).await;
+					state.end_test();
+				}
+			}
+			Event::SetSerialDevice(dev_id, baud) => {
+				printer
+					.buf(|tv| write!(tv, "setting device name to: {}", dev_id))
+					.await;
+				com_cmd_tx.send(ComCmd::NewDeviceName(dev_id, baud)).await?;
+			}
+			Event::SetOutputFormat(format) => new_output_format(state, format, printer).await,
+			Event::SetNote(text) => new_note(state, text, file_cmd_tx, printer).await?,
+			Event::SetOperator(name) => new_operator(state, name, file_cmd_tx, printer).await?,
+			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
+			Event::SetDisconnect(millivolts) => new_disconnect(state, millivolts, printer).await,
+			Event::SetMaxVoltage(millivolts) => new_max_voltage(state, millivolts, printer).await,
+			Event::SetChemistry(chemistry) => new_chemistry(state, chemistry, printer).await,
+			Event::SetMaxDuration(max) => new_max_duration(state, max, printer).await,
+			Event::SetMaxCapacity(max) => new_max_capacity(state, max, printer).await,
+			Event::SetTrim(trim) => new_trim(trim, com_cmd_tx, printer).await?,
+			Event::SetBatteryDetect(detect) => {
+				new_battery_detect(detect, com_cmd_tx, printer).await?
+			}
+			Event::RunSelfTest => com_cmd_tx.send(ComCmd::SelfTest).await?,
+			Event::SelfTestReport(report) => self_test_reported(report, printer).await,
+			Event::SetLoadModel(model) => new_load_model(state, model, printer).await,
+			Event::SetCurrentSetpoint(setpoint) => {
+				new_current_setpoint(state, setpoint, printer).await
+			}
+			Event::SetWindowSamples(samples) => new_window_samples(state, samples, printer).await,
+			Event::SetFilter(filter) => new_filter(state, filter, printer).await,
+			Event::ScheduleStart(at) => schedule_start(state, at, printer).await,
+			Event::ScheduledStart(_) => {}
+			Event::Queue(change) => new_queue(state, change, printer).await,
+			Event::ComReply(reply) => match reply.fault {
+				Ok(()) => {
+					printer.stat("fault cleared").await;
+					break;
+				}
+				Err(_f) => {
+					// still getting a fault
+				}
+			},
+			Event::Shutdown | Event::InternalError => return Ok(Mode::Shutdown),
+			Event::CommDc => {
+				printer
+					.error_stat("lost serial comms with battery interface")
+					.await;
+				return Ok(Mode::Setup);
+			}
+			Event::ComReconnected => {}
+			Event::DeviceVersion(device_version) => {
+				new_device_version(state, device_version, printer).await;
+			}
+			Event::StartTest => {
+				printer
+					.stat("cant't start test until fault is cleared")
+					.await;
+			}
+			Event::Charge => {
+				printer.stat("cant't charge until fault is cleared").await;
+			}
+			Event::CancelTest => {
+				// TODO: warn user
+			}
+			Event::FileError(_) => {}
+			Event::ClearFault => {
+				com_cmd_tx.send(ComCmd::ClearFault).await?;
+				// dont break or return because we want an OK(()) reply from BI
+			}
+			Event::UnderCurrentResponse(allow_undercurrent) => {
+				state.set_allow_undercurrent(allow_undercurrent)
+			}
+			// left over from a canceled profile step
+			Event::ChamberStable | Event::ChamberError => {}
+			Event::ComDecodeError(bad_frames) => com_decode_error(bad_frames, printer).await,
+			Event::BatteryPresence(present) => {
+				state.set_bat_present(present);
+				if present {
+					printer.stat("battery connected").await;
+				} else {
+					printer.stat("battery disconnected").await;
+				}
+			}
+			Event::StartAutonomous | Event::FetchAutonomousLog => {
+				printer.stat("clear the fault first").await;
+			}
+			// asked for by a test that's since ended
+			Event::AutonomousLog(_) => {}
+			// the test they belong to isn't recording
+			Event::Replayed(_) => {}
+		}
+	}
+	Ok(Mode::Setup)
+}
+async fn setup(
+	state: &mut TestState,
+	event_rx: &mut Receiver<Event>,
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	registry: Option<&BatteryRegistry>,
+	status_tx: &watch::Sender<ServerStatus>,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	printer
+		.stat("setup: please set battery ID and tester serial port device name")
+		.await;
+	com_cmd_tx.send(ComCmd::BICommand(idle_command())).await?;
+	printer.buf(|tv| write!(tv, "{:?}", state)).await;
+	Ok(loop {
+		let event = match event_rx.recv().await {
+			Some(e) => e,
+			None => return Ok(Mode::Shutdown),
+		};
+		match event {
+			Event::BattID(battery_id) => {
+				match new_test(state, battery_id, file_cmd_tx, registry, printer).await? {
+					Ok(()) => {
+						if state.ready_for_battery() {
+							break Mode::WaitForBattery;
+						} else {
+							printer.buf(|tv| write!(tv, "{:?}", state)).await;
+						}
+					}
+					Err(e) => {
+						printer.buf(|tv| write!(tv, "{e}")).await;
+						state.end_test();
+					}
+				}
+			}
+			Event::SetSerialDevice(dev_id, baud) => {
+				printer
+					.buf(|tv| write!(tv, "setting device name to: {}", dev_id))
+					.await;
+				com_cmd_tx
+					.send(ComCmd::NewDeviceName(dev_id.clone(), baud))
+					.await?;
+				state.new_device_name(dev_id, baud);
+				// the device can be calibrated before there's a battery ID to leave setup
+				status_tx.send_replace(state.status(Mode::Setup));
+				printer.buf(|tv| write!(tv, "{:?}", state)).await;
+			}
+			Event::SetOutputFormat(format) => new_output_format(state, format, printer).await,
+			Event::SetNote(text) => new_note(state, text, file_cmd_tx, printer).await?,
+			Event::SetOperator(name) => new_operator(state, name, file_cmd_tx, printer).await?,
+			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
+			Event::SetDisconnect(millivolts) => new_disconnect(state, millivolts, printer).await,
+			Event::SetMaxVoltage(millivolts) => new_max_voltage(state, millivolts, printer).await,
+			Event::SetChemistry(chemistry) => new_chemistry(state, chemistry, printer).await,
+			Event::SetMaxDuration(max) => new_max_duration(state, max, printer).await,
+			Event::SetMaxCapacity(max) => new_max_capacity(state, max, printer).await,
+			Event::SetTrim(trim) => new_trim(trim, com_cmd_tx, printer).await?,
+			Event::SetBatteryDetect(detect) => {
+				new_battery_detect(detect, com_cmd_tx, printer).await?
+			}
+			Event::RunSelfTest => com_cmd_tx.send(ComCmd::SelfTest).await?,
+			Event::SelfTestReport(report) => self_test_reported(report, printer).await,
+			Event::SetLoadModel(model) => new_load_model(state, model, printer).await,
+			Event::SetCurrentSetpoint(setpoint) => {
+				new_current_setpoint(state, setpoint, printer).await
+			}
+			Event::SetWindowSamples(samples) => new_window_samples(state, samples, printer).await,
+			Event::SetFilter(filter) => new_filter(state, filter, printer).await,
+			Event::ScheduleStart(at) => schedule_start(state, at, printer).await,
+			Event::ScheduledStart(_) => {}
+			Event::Queue(change) => {
+				new_queue(state, change, printer).await;
+				// a channel without a battery takes the first one straight away
+				if state.battery_id().is_none()
+					&& next_in_queue(state, file_cmd_tx, registry, printer).await?
+					&& state.ready_for_battery()
+				{
+					break Mode::WaitForBattery;
+				}
+			}
+			Event::ComReply(reply) => match reply.fault {
+				Ok(()) => {
+					if !state.got_first_reply() {
+						state.set_first_reply();
+						printer.buf(|tv| write!(tv, "{:?}", state)).await;
+					}
+					if state.ready_for_battery() {
+						break Mode::WaitForBattery;
+					}
+				}
+				Err(f) => {
+					// got_ok_reply = false;
+					match f.kind {
+						FaultKind::I2C(i2ce) => {
+							printer.error(|b| write!(b, "I2C Fault:\n{i2ce:?}")).await;
+						}
+						FaultKind::Undercurrent => {
+							printer.error_stat("Heater undercurret/not present!").await;
+						}
+						FaultKind::NoBattery => {
+							printer.error_stat("Battery Disconnected!").await;
+						}
+						FaultKind::Overcurrent => {
+							printer.error_stat("Heater overcurrent!").await;
+						}
+						FaultKind::SensorIntegrity => {
+							printer
+								.error_stat(
+									"INA260 power doesn't match voltage x current, readings can't be trusted!",
+								)
+								.await;
+						}
+						FaultKind::OverTemperature => {
+							printer.error_stat("Over temperature!").await;
+						}
+						FaultKind::LoadOverTemperature => {
+							printer.error_stat("Heater over temperature!").await;
+						}
+						FaultKind::LoadThermistor => {
+							printer
+								.error_stat(
+									"Heater thermistor open, its temperature can't be watched!",
+								)
+								.await;
+						}
+						FaultKind::Overvoltage => {
+							printer
+								.error_stat(
+									"Over voltage, the wrong battery or one still charging!",
+								)
+								.await;
+						}
+						FaultKind::LoadStuckOn => {
+							printer
+								.error_stat("Heater stuck on, current with the load off!")
+								.await;
+						}
+					}
+					break Mode::Fault;
+				}
+			},
+			Event::Shutdown | Event::InternalError => break Mode::Shutdown,
+			Event::CommDc => {
+				state.unset_first_reply();
+				state.unset_device_version();
+			}
+			// wait for the first reply from the re-opened device
+			Event::ComReconnected => {}
+			Event::DeviceVersion(device_version) => {
+				new_device_version(state, device_version, printer).await;
+				if state.ready_for_battery() {
+					break Mode::WaitForBattery;
+				}
+				printer.buf(|tv| write!(tv, "{:?}", state)).await;
+			}
+			Event::StartTest => {
+				printer.stat("cant't start test during setup").await;
+			}
+			Event::Charge => {
+				printer.stat("cant't charge during setup").await;
+			}
+			Event::CancelTest => {}
+			Event::FileError(_) => state.end_test(),
+			Event::ClearFault => {
+				printer.stat("no fault to clear").await;
+			}
+			Event::UnderCurrentResponse(allow_undercurrent) => {
+				state.set_allow_undercurrent(allow_undercurrent)
+			}
+			// left over from a canceled profile step
+			Event::ChamberStable | Event::ChamberError => {}
+			Event::ComDecodeError(bad_frames) => com_decode_error(bad_frames, printer).await,
+			Event::BatteryPresence(present) => state.set_bat_present(present),
+			Event::StartAutonomous | Event::FetchAutonomousLog => {
+				printer.stat("set up a test first").await;
+			}
+			// asked for by a test that's since ended
+			Event::AutonomousLog(_) => {}
+			// the test they belong to isn't recording
+			Event::Replayed(_) => {}
+		}
+	})
+}
+
+async fn resume(
+	state: &mut TestState,
+	event_rx: &mut Receiver<Event>,
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	journal: Journal,
+	printer: &mut Printer,
+) -> Result<Mode, TaskError> {
+	printer
+		.buf(|tv| {
+			write!(
+				tv,
+				"test of battery: {:?} started: {} was interrupted\nif the battery is still connected start to resume it, or cancel to end it",
+				journal.battery_id, journal.started
+			)
+		})
+		.await;
+	com_cmd_tx.send(ComCmd::BICommand(idle_command())).await?;
+	if let Some(dev_id) = &journal.device_name {
+		com_cmd_tx
+			.send(ComCmd::NewDeviceName(dev_id.clone(), journal.device_baud))
+			.await?;
+	}
+	Ok(loop {
+		let event = match event_rx.recv().await {
+			Some(e) => e,
+			None => return Ok(Mode::Shutdown),
+		};
+		match event {
+			Event::StartTest => {
+				if !state.ready_for_battery() {
+					printer
+						.stat("can't resume until the battery interface replies")
+						.await;
+					continue;
+				}
+				// ready_for_battery() means there's a battery ID, which came with where it's saved
+				let (saved_to, format) = state.saved_to().cloned().unwrap();
+				let (reply_tx, reply_rx) = oneshot::channel();
+				file_cmd_tx
+					.send(FileCmd::Resume(saved_to, format, reply_tx))
+					.await?;
+				match reply_rx.await? {
+					Ok(saved_to) => {
+						printer.buf(|tv| write!(tv, "resuming, {saved_to}")).await;
+						break Mode::Testing;
+					}
+					Err(e) => {
+						printer
+							.error(|tv| write!(tv, "can't resume the test:\n{e}"))
+							.await;
+						break Mode::EndTest;
+					}
+				}
+			}
+			Event::CancelTest => break Mode::EndTest,
+			Event::SetSerialDevice(dev_id, baud) => {
+				printer
+					.buf(|tv| write!(tv, "setting device name to: {}", dev_id))
+					.await;
+				com_cmd_tx
+					.send(ComCmd::NewDeviceName(dev_id.clone(), baud))
+					.await?;
+				state.new_device_name(dev_id, baud);
+			}
+			Event::ComReply(reply) => match reply.fault {
+				Ok(()) => {
+					if !state.got_first_reply() {
+						state.set_first_reply();
+						printer.stat("battery interface is replying").await;
+					}
+					if let Some(m) = reply.measurement
+						&& !(m.vbat > state.cutoff())
+					{
+						printer
+							.buf(|tv| write!(tv, "battery is at: {} mV, is it connected?", m.vbat))
+							.await;
+					}
+				}
+				// the operator decides, clear it to resume
+				Err(f) => printer.error(|tv| write!(tv, "fault:\n{f:?}")).await,
+			},
+			Event::DeviceVersion(device_version) => {
+				new_device_version(state, device_version, printer).await;
+			}
+			Event::CommDc => {
+				state.unset_first_reply();
+				state.unset_device_version();
+			}
+			Event::ComReconnected => {}
+			Event::SetOutputFormat(format) => new_output_format(state, format, printer).await,
+			Event::SetNote(text) => new_note(state, text, file_cmd_tx, printer).await?,
+			Event::SetOperator(name) => new_operator(state, name, file_cmd_tx, printer).await?,
+			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
+			Event::SetDisconnect(millivolts) => new_disconnect(state, millivolts, printer).await,
+			Event::SetMaxVoltage(millivolts) => new_max_voltage(state, millivolts, printer).await,
+			Event::SetChemistry(chemistry) => new_chemistry(state, chemistry, printer).await,
+			Event::SetMaxDuration(max) => new_max_duration(state, max, printer).await,
+			Event::SetMaxCapacity(max) => new_max_capacity(state, max, printer).await,
+			Event::SetTrim(_trim) => {
+				printer
+					.stat("can't set the battery interface's trim with a test set up")
+					.await;
+			}
+			Event::SetBatteryDetect(_detect) => {
+				printer
+					.stat("can't set the battery interface's battery detection with a test set up")
+					.await;
+			}
+			Event::RunSelfTest => {
+				printer
+					.stat("can't run the battery interface's self test with a test set up")
+					.await;
+			}
+			Event::SelfTestReport(report) => self_test_reported(report, printer).await,
+			Event::SetLoadModel(model) => new_load_model(state, model, printer).await,
+			Event::SetCurrentSetpoint(setpoint) => {
+				new_current_setpoint(state, setpoint, printer).await
+			}
+			Event::SetWindowSamples(samples) => new_window_samples(state, samples, printer).await,
+			Event::SetFilter(filter) => new_filter(state, filter, printer).await,
+			Event::ScheduleStart(at) => schedule_start(state, at, printer).await,
+			Event::ScheduledStart(_) => {}
+			Event::Queue(change) => new_queue(state, change, printer).await,
+			Event::UnderCurrentResponse(allow_undercurrent) => {
+				state.set_allow_undercurrent(allow_undercurrent)
+			}
+			Event::BattID(_) => {
+				printer
+					.stat("resume or cancel the interrupted test before setting a new battery")
+					.await;
+			}
+			Event::Charge => {
+				printer.stat("can't charge an interrupted test").await;
+			}
+			Event::ClearFault => com_cmd_tx.send(ComCmd::ClearFault).await?,
+			Event::Shutdown | Event::InternalError => break Mode::Shutdown,
+			Event::FileError(_) => break Mode::EndTest,
+			Event::ChamberStable | Event::ChamberError => {}
+			Event::ComDecodeError(bad_frames) => com_decode_error(bad_frames, printer).await,
+			Event::BatteryPresence(present) => state.set_bat_present(present),
+			Event::StartAutonomous | Event::FetchAutonomousLog => {
+				printer
+					.stat("resume or cancel the interrupted test first")
+					.await;
+			}
+			// asked for by a test that's since ended
+			Event::AutonomousLog(_) => {}
+			// the test they belong to isn't recording
+			Event::Replayed(_) => {}
+		}
+	})
+}
+
+async fn new_cutoff(state: &mut TestState, millivolts: MilliVolt, printer: &mut Printer) {
+	state.new_cutoff(millivolts);
+	printer
+		.buf(|tv| write!(tv, "new cutoff voltage (millivolts): {millivolts}"))
+		.await;
+}
+
+async fn new_disconnect(state: &mut TestState, millivolts: MilliVolt, printer: &mut Printer) {
+	state.set_disconnect(millivolts);
+	printer
+		.buf(|tv| {
+			write!(
+				tv,
+				"battery counts as removed below (millivolts): {millivolts}"
+			)
+		})
+		.await;
+}
+
+async fn new_max_voltage(state: &mut TestState, millivolts: MilliVolt, printer: &mut Printer) {
+	state.set_max_voltage(millivolts);
+	printer
+		.buf(|tv| write!(tv, "tests won't start over (millivolts): {millivolts}"))
+		.await;
+}
+
+/// Tell the user a test won't start while the battery reads over the limit
+async fn refused_over_voltage(
+	state: &TestState,
+	vbat: Option<MilliVolt>,
+	printer: &mut Printer,
+) -> bool {
+	let max_voltage = state.limits().max_voltage;
+	let Some(vbat) = vbat.filter(|vbat| *vbat > max_voltage) else {
+		return false;
+	};
+	printer
+		.error(|tv| {
+			write!(
+				tv,
+				"the battery is at: {vbat} mV, over the {max_voltage} mV limit, the test won't start"
+			)
+		})
+		.await;
+	true
+}
+
+async fn new_chemistry(state: &mut TestState, chemistry: Chemistry, printer: &mut Printer) {
+	state.set_chemistry(chemistry);
+	let Limits {
+		cutoff,
+		max_current,
+		disconnect,
+		max_voltage,
+	} = chemistry.limits();
+	printer
+		.buf(|tv| {
+			write!(
+				tv,
+				"chemistry: {chemistry}, cutoff: {cutoff} mV, most current: {max_current} mA, disconnected below: {disconnect} mV, most voltage: {max_voltage} mV"
+			)
+		})
+		.await;
+}
+
+/// Carry on the internal resistance pulse, the test proper starts once it's done
+/// Once the battery has rested, turns the load on or starts the pulse, which starts with it off
+async fn rest_measured(
+	state: &mut TestState,
+	rest: &mut Option<Rest>,
+	pulse: bool,
+	m: &Measurement,
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	printer: &mut Printer,
+) -> Result<(), TaskError> {
+	let Some(ocv) = rest.as_mut().and_then(|rest| rest.measured(m)) else {
+		return Ok(());
+	};
+	*rest = None;
+	state.set_ocv_before(Some(ocv));
+	printer
+		.buf(|tv| write!(tv, "open circuit voltage: {ocv} mV"))
+		.await;
+	if let Some(notes) = state.notes() {
+		file_cmd_tx.send(FileCmd::Notes(notes)).await?;
+	}
+	if pulse {
+		printer
+			.stat("pulsing the load to estimate the internal resistance...")
+			.await;
+	} else {
+		com_cmd_tx
+			.send(ComCmd::BICommand(state.testing_command()))
+			.await?;
+	}
+	Ok(())
+}
+
+async fn pulse_measured(
+	state: &mut TestState,
+	pulse: &mut Option<Pulse>,
+	m: &Measurement,
+	com_cmd_tx: &Sender<ComCmd>,
+	file_cmd_tx: &Sender<FileCmd>,
+	printer: &mut Printer,
+) -> Result<(), TaskError> {
+	let Some(step) = pulse.as_mut() else {
+		return Ok(());
+	};
+	let testing = state.testing_command();
+	let milliohms = match step.measured(m) {
+		PulseAction::Wait => return Ok(()),
+		PulseAction::LoadOn => {
+			com_cmd_tx.send(ComCmd::BICommand(testing)).await?;
+			return Ok(());
+		}
+		PulseAction::LoadOff => {
+			com_cmd_tx.send(ComCmd::BICommand(volts_command())).await?;
+			return Ok(());
+		}
+		PulseAction::Done(milliohms) => milliohms,
+	};
+	*pulse = None;
+	com_cmd_tx.send(ComCmd::BICommand(testing)).await?;
+	state.set_internal_resistance(milliohms);
+	match milliohms {
+		Some(milliohms) => {
+			printer
+				.buf(|tv| write!(tv, "internal resistance: {milliohms} mOhm"))
+				.await
+		}
+		None => {
+			printer
+				.warn_stat(
+					"can't estimate the internal resistance, the load pulse changed too little",
+				)
+				.await
+		}
+	}
+	if let Some(notes) = state.notes() {
+		file_cmd_tx.send(FileCmd::Notes(notes)).await?;
+	}
+	Ok(())
+}
+
+async fn new_max_duration(
+	state: &mut TestState,
+	max_duration: Option<Duration>,
+	printer: &mut Printer,
+) {
+	state.set_max_duration(max_duration);
+	match max_duration {
+		Some(max) => {
+			printer
+				.buf(|tv| {
+					write!(
+						tv,
+						"tests end after testing for: {} min",
+						max.as_secs() / 60
+					)
+				})
+				.await
+		}
+		None => printer.stat("tests run until the cutoff").await,
+	}
+}
+
+async fn new_max_capacity(state: &mut TestState, max_mah: Option<u32>, printer: &mut Printer) {
+	state.set_max_mah(max_mah);
+	match max_mah {
+		Some(max) => {
+			printer
+				.buf(|tv| write!(tv, "tests end after taking out: {max} mAh"))
+				.await
+		}
+		None => printer.stat("tests run until the cutoff").await,
+	}
+}
+
+async fn new_load_model(
+	state: &mut TestState,
+	load_model: Option<LoadModel>,
+	printer: &mut Printer,
+) {
+	state.set_load_model(load_model);
+	match load_model {
+		Some(model) => {
+			printer
+				.buf(|tv| {
+					write!(
+						tv,
+						"the load draws vbat over: {} mOhm plus {} mA, within {} mA",
+						model.milliohms, model.offset, model.max_deviation
+					)
+				})
+				.await
+		}
+		None => printer.stat("the load is the firmware's own").await,
+	}
+}
+
+async fn new_window_samples(state: &mut TestState, samples: Option<u8>, printer: &mut Printer) {
+	match samples {
+		Some(samples) if !(1..=MAX_WINDOW_SAMPLES).contains(&u32::from(samples)) => {
+			printer
+				.buf(|tv| {
+					write!(
+						tv,
+						"a measurement averages 1 to {MAX_WINDOW_SAMPLES} samples"
+					)
+				})
+				.await
+		}
+		Some(samples) => {
+			state.set_window_samples(Some(samples));
+			printer
+				.buf(|tv| write!(tv, "samples averaged into each measurement: {samples}"))
+				.await
+		}
+		None => {
+			state.set_window_samples(None);
+			printer
+				.stat("averaging the firmware's samples into each measurement")
+				.await
+		}
+	}
+}
+
+async fn new_filter(state: &mut TestState, filter: WindowFilter, printer: &mut Printer) {
+	state.set_filter(filter);
+	match filter {
+		WindowFilter::Mean => printer.stat("averaging every sample").await,
+		WindowFilter::TrimmedMean => {
+			printer
+				.stat("dropping each measurement's highest and lowest samples")
+				.await
+		}
+	}
+}
+
+async fn new_current_setpoint(
+	state: &mut TestState,
+	setpoint: Option<MilliAmp>,
+	printer: &mut Printer,
+) {
+	match setpoint {
+		Some(milliamps) if i16::from(milliamps) <= 0 => {
+			printer
+				.stat("the constant current has to be more than 0 mA")
+				.await
+		}
+		Some(milliamps) => {
+			state.set_current_setpoint(setpoint);
+			printer
+				.buf(|tv| write!(tv, "discharging at a constant: {milliamps} mA"))
+				.await
+		}
+		None => {
+			state.set_current_setpoint(None);
+			printer.stat("discharging at the load's full current").await
+		}
+	}
+}
+
+async fn new_trim(
+	trim: Trim,
+	com_cmd_tx: &Sender<ComCmd>,
+	printer: &mut Printer,
+) -> Result<(), TaskError> {
+	printer
+		.buf(|tv| write!(tv, "setting the battery interface's trim to: {trim:?}"))
+		.await;
+	com_cmd_tx.send(ComCmd::SetTrim(trim)).await?;
+	Ok(())
+}
+
+async fn new_battery_detect(
+	detect: BatteryDetect,
+	com_cmd_tx: &Sender<ComCmd>,
+	printer: &mut Printer,
+) -> Result<(), TaskError> {
+	printer
+		.buf(|tv| {
+			write!(
+				tv,
+				"setting the battery interface's battery detection to: {detect:?}"
+			)
+		})
+		.await;
+	com_cmd_tx.send(ComCmd::SetBatteryDetect(detect)).await?;
+	Ok(())
+}
+
+/// Failures are warned about, the report is in `status` too
+async fn self_test_reported(report: SelfTestReport, printer: &mut Printer) {
+	if report.passed() {
+		printer
+			.buf(|tv| write!(tv, "battery interface self test passed: {report:?}"))
+			.await;
+	} else {
+		printer
+			.warn(|tv| write!(tv, "battery interface self test failed: {report:?}"))
+			.await;
+	}
+}
+
+async fn new_output_format(state: &mut TestState, format: OutputFormat, printer: &mut Printer) {
+	state.set_output_format(format);
+	printer
+		.buf(|tv| {
+			write!(
+				tv,
+				"tests from the next battery ID on are saved as: {format:?}"
+			)
+		})
+		.await;
+}
+
+/// Saved with the open test, or the next one when there isn't one
+async fn new_note(
+	state: &mut TestState,
+	text: Box<str>,
+	file_cmd_tx: &Sender<FileCmd>,
+	printer: &mut Printer,
+) -> Result<(), TaskError> {
+	state.add_note(text);
+	save_notes(state, file_cmd_tx, printer).await
+}
+
+async fn new_operator(
+	state: &mut TestState,
+	name: Box<str>,
+	file_cmd_tx: &Sender<FileCmd>,
+	printer: &mut Printer,
+) -> Result<(), TaskError> {
+	printer.buf(|tv| write!(tv, "operator: {name}")).await;
+	state.set_operator(name);
+	save_notes(state, file_cmd_tx, printer).await
+}
+
+async fn save_notes(
+	state: &TestState,
+	file_cmd_tx: &Sender<FileCmd>,
+	printer: &mut Printer,
+) -> Result<(), TaskError> {
+	match state.notes() {
+		Some(notes) => {
+			file_cmd_tx.send(FileCmd::Notes(notes)).await?;
+			printer.stat("saved with the test").await;
+		}
+		None => printer.stat("saved with the next test").await,
+	}
+	Ok(())
+}
+
+async fn com_decode_error(bad_frames: u64, printer: &mut Printer) {
+	printer
+		.warn(|tv| {
+			write!(
+				tv,
+				"dropped a bad reply from the battery interface, {bad_frames} total"
+			)
+		})
+		.await;
+}
+
+async fn new_device_version(
+	state: &mut TestState,
+	device_version: DeviceVersion,
+	printer: &mut Printer,
+) {
+	state.new_device_version(device_version);
+	let DeviceVersion {
+		protocol, firmware, ..
+	} = device_version;
+	let device_id = device_version.hex_device_id();
+	if state.device_compatible() {
+		printer
+			.buf(|tv| {
+				write!(
+					tv,
+					"battery interface: {device_id}, firmware: {firmware}, protocol: {protocol}"
+				)
+			})
+			.await;
+	} else {
+		printer
+			.buf(|tv| {
+				write!(
+					tv,
+					"battery interface firmware: {firmware} uses protocol: {protocol} but the server uses: {PROTOCOL_VERSION}\nupdate the firmware or server, tests are disabled"
+				)
+			})
+			.await;
+	}
+}
+
+/// Have the file task start saving a new test for `battery_id`, waits until it's ready for data.
+/// The outer error is the file task having stopped, the inner one the test not being saved.
+async fn new_test(
+	state: &mut TestState,
+	battery_id: BatteryID,
+	file_cmd_tx: &Sender<FileCmd>,
+	registry: Option<&BatteryRegistry>,
+	printer: &mut Printer,
+) -> Result<Result<(), OutputError>, TaskError> {
+	let format = state.output_format();
+	let (reply_tx, reply_rx) = oneshot::channel();
+	file_cmd_tx
+		.send(FileCmd::NewTest(battery_id, format, reply_tx))
+		.await?;
+	let saved_to = match reply_rx.await? {
+		Ok(saved_to) => saved_to,
+		Err(e) => return Ok(Err(e)),
+	};
+	printer.buf(|tv| write!(tv, "{saved_to}")).await;
+	state.set_saved_to(saved_to, format);
+	state.new_batt_id(battery_id);
+	if let Some(notes) = state.notes() {
+		file_cmd_tx.send(FileCmd::Notes(notes)).await?;
+	}
+	if let Some(registry) = registry {
+		check_registry(registry, battery_id, printer).await;
+	}
+	Ok(Ok(()))
+}
+
+/// Set up the next battery in the queue, `false` when there isn't one
+async fn next_in_queue(
+	state: &mut TestState,
+	file_cmd_tx: &Sender<FileCmd>,
+	registry: Option<&BatteryRegistry>,
+	printer: &mut Printer,
+) -> Result<bool, TaskError> {
+	let Some(next) = state.next_queued() else {
+		return Ok(false);
+	};
+	if let Err(e) = new_test(state, next.battery_id, file_cmd_tx, registry, printer).await? {
+		printer.buf(|tv| write!(tv, "{e}")).await;
+		printer
+			.warn(|tv| write!(tv, "can't set up {next} from the queue"))
+			.await;
+		state.end_test();
+		return Ok(false);
+	}
+	let left = state.queued();
+	printer
+		.buf(|tv| write!(tv, "next in the queue: {next}, {left} more after it"))
+		.await;
+	Ok(true)
+}
+
+async fn schedule_start(
+	state: &mut TestState,
+	start_at: Option<SystemTime>,
+	printer: &mut Printer,
+) {
+	state.set_start_at(start_at);
+	match start_at {
+		Some(start_at) => {
+			let start_at = chrono::DateTime::<chrono::Local>::from(start_at);
+			printer
+				.buf(|tv| {
+					write!(
+						tv,
+						"the test starts at {} once the battery is connected",
+						start_at.format("%Y-%m-%d %H:%M:%S")
+					)
+				})
+				.await;
+		}
+		None => printer.stat("scheduled start canceled").await,
+	}
+}
+
+async fn new_queue(state: &mut TestState, change: QueueChange, printer: &mut Printer) {
+	state.change_queue(change);
+	let queued = state.queued();
+	printer
+		.buf(|tv| write!(tv, "{queued} battery(s) in the queue"))
+		.await;
+}
+
+/// Say what the battery should be, and warn when it's unknown or was tested before
+async fn check_registry(registry: &BatteryRegistry, battery_id: BatteryID, printer: &mut Printer) {
+	let BatteryID { year, index } = battery_id;
+	match registry.get(battery_id) {
+		Some(battery) => {
+			let RegisteredBattery {
+				chemistry,
+				nominal_mah,
+				manufactured,
+				tests,
+				..
+			} = battery;
+			printer
+				.buf(|tv| {
+					write!(tv, "battery {year}-{index}: {chemistry}")?;
+					if let Some(nominal_mah) = nominal_mah {
+						write!(tv, ", {nominal_mah} mAh")?;
+					}
+					if let Some(manufactured) = &manufactured {
+						write!(tv, ", made {manufactured}")?;
+					}
+					Ok(())
+				})
+				.await;
+			if tests > 0 {
+				printer
+					.warn(|tv| {
+						write!(
+							tv,
+							"battery {year}-{index} was already tested {tests} time(s)"
+						)
+					})
+					.await;
+			}
+		}
+		// a team that doesn't keep a registry doesn't need telling for every battery
+		None if registry.is_empty() => {}
+		None => {
+			printer
+				.warn(|tv| {
+					write!(
+						tv,
+						"battery {year}-{index} isn't in the registry, add it with: battery-tester-client battery add"
+					)
+				})
+				.await
+		}
+	}
+}
+
+/// Keep the journal in step with the test, see [`crate::journal`]
+async fn update_journal(state: &mut TestState, mode: Mode, path: &Path, printer: &mut Printer) {
+	let updated = match mode {
+		Mode::Testing => match state.journal() {
+			Some(journal) => journal.save(path),
+			None => Ok(()),
+		},
+		Mode::Setup | Mode::EndTest => Journal::remove(path),
+		_ => Ok(()),
+	};
+	if let Err(e) = updated {
+		printer
+			.warn(|tv| write!(tv, "can't update the test journal: {path:?}\n{e}"))
+			.await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		DeviceVersion, Print,
+		files::{FileErrorKind, SavedTo, TestNotes},
+		queue::QueuedTest,
+	};
+	use battery_tester_common::{
+		AllowUndercurrent, DEFAULT_POLL_MS, Fault, FirmwareVersion, LoggedSample, Measurement,
+		MilliAmp, MilliWatt, Polarity, Status,
+	};
+	use std::{num::NonZeroU16, time::Duration};
+	use tokio::{
+		sync::mpsc::{self, error::TryRecvError},
+		task::JoinHandle,
+		time::timeout,
+	};
+
+	const BATTERY: BatteryID = BatteryID {
+		year: 2024,
+		index: 1,
+	};
+	const DEVICE_ID: u64 = 0x0123_4567_89ab_cdef;
+
+	/// The program task on in-memory channels, in place of the serial, file, and print tasks
+	struct Harness {
+		event_tx: Sender<Event>,
+		mode_rx: Receiver<Mode>,
+		com_rx: Receiver<ComCmd>,
+		/// Everything but [`FileCmd::NewTest`], which is answered right away
+		file_rx: Receiver<FileCmd>,
+		task: JoinHandle<Result<(), TaskError>>,
+		/// Simulated ms since the battery interface booted
+		dt: u64,
+	}
+
+	impl Harness {
+		fn start() -> Self {
+			Self::spawn(Self::settings())
+		}
+
+		fn start_with(termination: TerminationRule) -> Self {
+			Self::spawn(TestSettings {
+				termination,
+				..Self::settings()
+			})
+		}
+
+		fn start_warming_up(warmup: WarmupRule) -> Self {
+			Self::spawn(TestSettings {
+				warmup: Some(warmup),
+				..Self::settings()
+			})
+		}
+
+		fn start_resting(rest: Duration) -> Self {
+			Self::spawn(TestSettings {
+				ocv_rest: Some(rest),
+				..Self::settings()
+			})
+		}
+
+		/// Tests start with the pulse and end at the first measurement under the cutoff
+		fn settings() -> TestSettings {
+			TestSettings {
+				output_format: OutputFormat::default(),
+				termination: TerminationRule::default(),
+				ir_pulse: true,
+				warmup: None,
+				ocv_rest: None,
+				poll_ms: DEFAULT_POLL_MS,
+			}
+		}
+
+		/// Tests on the battery interface with [`DEVICE_ID`] are corrected by its calibration
+		/// in `calibrations`
+		fn start_calibrated(calibrations: Arc<CalibrationStore>) -> Self {
+			Self::spawn_with(Self::settings(), Some(calibrations), None, None)
+		}
+
+		/// Keeping the journal at `journal_path`, starting with the `interrupted` test if there is one
+		fn start_journaled(journal_path: PathBuf, interrupted: Option<Journal>) -> Self {
+			Self::spawn_with(Self::settings(), None, Some(journal_path), interrupted)
+		}
+
+		fn spawn(settings: TestSettings) -> Self {
+			Self::spawn_with(settings, None, None, None)
+		}
+
+		fn spawn_with(
+			settings: TestSettings,
+			calibrations: Option<Arc<CalibrationStore>>,
+			journal_path: Option<PathBuf>,
+			interrupted: Option<Journal>,
+		) -> Self {
+			let (event_tx, rx) = mpsc::channel(64);
+			let (file_cmd_tx, file_cmd_rx) = mpsc::channel(64);
+			let (com_cmd_tx, com_rx) = mpsc::channel(64);
+			let (mode_tx, mode_rx) = mpsc::channel(64);
+			let (print_tx, mut print_rx) = mpsc::channel::<Print>(64);
+			let (status_tx, _status_rx) = watch::channel(ServerStatus::default());
+			tokio::spawn(async move { while print_rx.recv().await.is_some() {} });
+			let file_rx = fake_file_task(file_cmd_rx);
+			let links = ProgramLinks {
+				rx,
+				file_cmd_tx,
+				com_cmd_tx,
+				printer: Printer::new(print_tx),
+				signal_tx: None,
+				chamber_cmd_tx: None,
+				status_tx,
+				mode_tx: Some(mode_tx),
+				notify_tx: None,
+				registry: None,
+				calibrations,
+				timer_tx: Some(event_tx.downgrade()),
+			};
+			let task = tokio::spawn(program_event_task(
+				links,
+				ProfileRun::new(None),
+				settings,
+				journal_path,
+				interrupted,
+			));
+			Self {
+				event_tx,
+				mode_rx,
+				com_rx,
+				file_rx,
+				task,
+				dt: 0,
+			}
+		}
+
+		async fn send(&self, event: Event) {
+			self.event_tx.send(event).await.unwrap();
+		}
+
+		/// A reply with a fresh measurement
+		async fn measure(&mut self, millivolts: u16) {
+			let reply = self.measured(millivolts);
+			self.send(reply).await;
+		}
+
+		fn measured(&mut self, millivolts: u16) -> Event {
+			self.measured_at(millivolts, 2_000)
+		}
+
+		fn measured_at(&mut self, millivolts: u16, milliamps: i16) -> Event {
+			self.dt += 1_000;
+			Event::ComReply(Status {
+				measurement: Some(Measurement {
+					vbat: MilliVolt::new(millivolts),
+					ibat: MilliAmp::new(milliamps),
+					milliwatts: MilliWatt::new(
+						u32::from(millivolts) * u32::from(milliamps.unsigned_abs()) / 1000,
+					),
+					sample_index: (self.dt / 1_000) as u32,
+					sample_start_ms: self.dt,
+					sample_duration_ms: 900,
+					temp_centi_c: None,
+					load_temp_centi_c: None,
+					fan_on: false,
+					load: None,
+				}),
+				fault: Ok(()),
+				cutoff_reached: false,
+				local_load: None,
+				reset_reason: None,
+				wants_control: false,
+			})
+		}
+
+		async fn expect_mode(&mut self, mode: Mode) {
+			let next = timeout(Duration::from_secs(1), self.mode_rx.recv())
+				.await
+				.unwrap_or_else(|_| panic!("still waiting for {mode:?}"));
+			assert_eq!(next, Some(mode));
+		}
+
+		/// Commands sent to the battery interface so far
+		fn com_cmds(&mut self) -> Vec<ComCmd> {
+			drain(&mut self.com_rx)
+		}
+
+		fn file_cmds(&mut self) -> Vec<FileCmd> {
+			drain(&mut self.file_rx)
+		}
+
+		/// From Setup to a battery connected and waiting for the user to start
+		async fn set_up(&mut self) {
+			self.expect_mode(Mode::Setup).await;
+			self.send(Event::SetSerialDevice("sim".into(), None)).await;
+			self.send(Event::DeviceVersion(DeviceVersion {
+				protocol: PROTOCOL_VERSION,
+				firmware: FirmwareVersion {
+					major: 0,
+					minor: 0,
+					patch: 0,
+				},
+				device_id: DEVICE_ID,
+			}))
+			.await;
+			self.send(Event::BattID(BATTERY)).await;
+			self.measure(12_000).await;
+			self.expect_mode(Mode::WaitForBattery).await;
+			self.measure(12_000).await;
+			self.expect_mode(Mode::WaitForUsrStart).await;
+		}
+
+		async fn start_test(&mut self) {
+			self.set_up().await;
+			self.send(Event::StartTest).await;
+			self.expect_mode(Mode::Testing).await;
+			self.pulse().await;
+		}
+
+		/// Through the internal resistance pulse, 100 mOhm, each step's first measurement
+		/// is skipped. Returns once the estimate is saved, dropping what was saved before it.
+		async fn pulse(&mut self) {
+			for (millivolts, milliamps) in [(12_000, 0), (11_800, 2_000), (12_000, 0)] {
+				for _ in 0..2 {
+					let reply = self.measured_at(millivolts, milliamps);
+					self.send(reply).await;
+				}
+			}
+			loop {
+				let cmd = timeout(Duration::from_secs(1), self.file_rx.recv())
+					.await
+					.expect("still waiting for the internal resistance")
+					.unwrap();
+				// the notes saved with the new test come first
+				if let FileCmd::Notes(notes) = cmd
+					&& notes.internal_resistance_mohm.is_some()
+				{
+					assert_eq!(notes.internal_resistance_mohm, Some(100));
+					return;
+				}
+			}
+		}
+	}
+
+	impl Drop for Harness {
+		fn drop(&mut self) {
+			self.task.abort();
+		}
+	}
+
+	/// Answers new tests and passes every other command on, stops once it can't
+	fn fake_file_task(mut file_cmd_rx: Receiver<FileCmd>) -> Receiver<FileCmd> {
+		let (tx, rx) = mpsc::channel(64);
+		tokio::spawn(async move {
+			while let Some(cmd) = file_cmd_rx.recv().await {
+				match cmd {
+					FileCmd::NewTest(battery_id, _format, reply_tx) => {
+						let path = format!("{}-{}.tsv", battery_id.year, battery_id.index);
+						let _ = reply_tx.send(Ok(SavedTo::File(path.into())));
+					}
+					cmd => {
+						if tx.send(cmd).await.is_err() {
+							break;
+						}
+					}
+				}
+			}
+		});
+		rx
+	}
+
+	fn drain<T>(rx: &mut Receiver<T>) -> Vec<T> {
+		let mut all = Vec::new();
+		loop {
+			match rx.try_recv() {
+				Ok(item) => all.push(item),
+				Err(TryRecvError::Empty | TryRecvError::Disconnected) => return all,
+			}
+		}
+	}
+
+	fn load_on(cmd: &ComCmd) -> bool {
+		matches!(cmd, ComCmd::BICommand(control) if control.load == battery_tester_common::LoadState::On)
+	}
+
+	#[tokio::test]
+	async fn test_discharge_to_cutoff() {
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		harness.measure(11_500).await;
+		harness.measure(10_900).await;
+		harness.expect_mode(Mode::EndTest).await;
+		harness.expect_mode(Mode::Setup).await;
+
+		let com_cmds = harness.com_cmds();
+		assert!(com_cmds.iter().any(load_on));
+		assert!(!load_on(com_cmds.last().unwrap()));
+		let file_cmds = harness.file_cmds();
+		// only the measurement above the cutoff is saved
+		let saved: Vec<u16> = file_cmds
+			.iter()
+			.filter_map(|cmd| match cmd {
+				FileCmd::Push(data) => Some(data.millivolts.into()),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(saved, [11_500]);
+		assert!(matches!(file_cmds.last(), Some(FileCmd::CloseFile)));
+	}
+
+	#[tokio::test]
+	async fn test_incompatible_firmware_isnt_tested() {
+		let version = |protocol| {
+			Event::DeviceVersion(DeviceVersion {
+				protocol,
+				firmware: FirmwareVersion {
+					major: 0,
+					minor: 0,
+					patch: 0,
+				},
+				device_id: DEVICE_ID,
+			})
+		};
+		let mut harness = Harness::start();
+		harness.expect_mode(Mode::Setup).await;
+		harness
+			.send(Event::SetSerialDevice("sim".into(), None))
+			.await;
+		harness.send(version(PROTOCOL_VERSION + 1)).await;
+		harness.send(Event::BattID(BATTERY)).await;
+		harness.measure(12_000).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		// the control word may not mean the same to it
+		assert!(matches!(
+			harness.mode_rx.try_recv(),
+			Err(TryRecvError::Empty)
+		));
+
+		// its firmware updated
+		harness.send(version(PROTOCOL_VERSION)).await;
+		harness.expect_mode(Mode::WaitForBattery).await;
+	}
+
+	#[tokio::test]
+	async fn test_queue_sets_up_the_next_battery() {
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		let next = QueuedTest {
+			battery_id: BatteryID {
+				year: 2024,
+				index: 2,
+			},
+			cutoff: Some(MilliVolt::new(11_500)),
+		};
+		harness
+			.send(Event::Queue(QueueChange::Add(vec![next])))
+			.await;
+		harness.measure(10_900).await;
+		harness.expect_mode(Mode::EndTest).await;
+		harness.expect_mode(Mode::Setup).await;
+		harness.measure(11_800).await;
+		harness.expect_mode(Mode::WaitForBattery).await;
+		// the tested battery recovered over the cutoff, it has to be disconnected first
+		harness.measure(12_000).await;
+		harness.measure(0).await;
+		harness.measure(12_000).await;
+		harness.expect_mode(Mode::WaitForUsrStart).await;
+		harness.com_cmds();
+		harness.file_cmds();
+
+		harness.send(Event::StartTest).await;
+		harness.expect_mode(Mode::Testing).await;
+		harness.pulse().await;
+		// tested to the queued battery's own cutoff
+		assert!(harness.com_cmds().iter().any(|cmd| matches!(
+			cmd,
+			ComCmd::BICommand(control) if control.cutoff == Some(MilliVolt::new(11_500))
+		)));
+	}
+
+	#[tokio::test]
+	async fn test_queued_battery_swap_seen_by_the_input() {
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		let next = QueuedTest {
+			battery_id: BatteryID {
+				year: 2024,
+				index: 2,
+			},
+			cutoff: None,
+		};
+		harness
+			.send(Event::Queue(QueueChange::Add(vec![next])))
+			.await;
+		harness.measure(10_900).await;
+		harness.expect_mode(Mode::EndTest).await;
+		harness.expect_mode(Mode::Setup).await;
+		harness.measure(11_800).await;
+		harness.expect_mode(Mode::WaitForBattery).await;
+		// swapped faster than the voltage reading dropped out
+		harness.send(Event::BatteryPresence(false)).await;
+		harness.send(Event::BatteryPresence(true)).await;
+		harness.measure(12_000).await;
+		harness.expect_mode(Mode::WaitForUsrStart).await;
+	}
+
+	#[tokio::test]
+	async fn test_scheduled_start() {
+		let mut harness = Harness::start();
+		harness.set_up().await;
+		let soon = || Some(SystemTime::now() + Duration::from_millis(50));
+		harness.send(Event::ScheduleStart(soon())).await;
+		harness.send(Event::ScheduleStart(None)).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		// canceled, still waiting for the user
+		assert!(matches!(
+			harness.mode_rx.try_recv(),
+			Err(TryRecvError::Empty)
+		));
+
+		harness.send(Event::ScheduleStart(soon())).await;
+		harness.expect_mode(Mode::Testing).await;
+	}
+
+	#[tokio::test]
+	async fn test_scheduled_start_is_an_event() {
+		let mut harness = Harness::start();
+		harness.set_up().await;
+		let later = SystemTime::now() + Duration::from_secs(3_600);
+		harness.send(Event::ScheduleStart(Some(later))).await;
+		// one from before it was moved, replayed from a trace
+		let earlier = later - Duration::from_secs(60);
+		harness.send(Event::ScheduledStart(earlier)).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(matches!(
+			harness.mode_rx.try_recv(),
+			Err(TryRecvError::Empty)
+		));
+
+		// started by the event, not the time
+		harness.send(Event::ScheduledStart(later)).await;
+		harness.expect_mode(Mode::Testing).await;
+	}
+
+	#[tokio::test]
+	async fn test_over_voltage_wont_start() {
+		let mut harness = Harness::start();
+		harness.set_up().await;
+		// a 24 V pack wired in by mistake
+		harness.measure(25_600).await;
+		harness.send(Event::StartTest).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(matches!(
+			harness.mode_rx.try_recv(),
+			Err(TryRecvError::Empty)
+		));
+
+		harness.measure(12_800).await;
+		harness.send(Event::StartTest).await;
+		harness.expect_mode(Mode::Testing).await;
+	}
+
+	#[tokio::test]
+	async fn test_cutoff_debounced() {
+		let mut harness =
+			Harness::start_with(TerminationRule::Consecutive(NonZeroU16::new(2).unwrap()));
+		harness.start_test().await;
+		// the battery interface is left to turn the load off under the cutoff
+		assert!(harness.com_cmds().iter().any(|cmd| matches!(
+			cmd,
+			ComCmd::BICommand(control) if control.cutoff == Some(MilliVolt::new(10_700))
+		)));
+		harness.measure(11_500).await;
+		// one noisy measurement doesn't end the test
+		harness.measure(10_900).await;
+		harness.measure(11_400).await;
+		harness.measure(10_900).await;
+		harness.measure(10_800).await;
+		harness.expect_mode(Mode::EndTest).await;
+
+		let saved: Vec<u16> = harness
+			.file_cmds()
+			.iter()
+			.filter_map(|cmd| match cmd {
+				FileCmd::Push(data) => Some(data.millivolts.into()),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(saved, [11_500, 10_900, 11_400, 10_900]);
+	}
+
+	#[tokio::test]
+	async fn test_trim_is_set_without_a_test() {
+		let mut harness = Harness::start();
+		harness.expect_mode(Mode::Setup).await;
+		let trim = Trim {
+			milliamps_offset: -12,
+			..Trim::default()
+		};
+		harness.send(Event::SetTrim(trim)).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(harness.com_cmds().contains(&ComCmd::SetTrim(trim)));
+
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		harness.com_cmds();
+		harness.send(Event::SetTrim(trim)).await;
+		harness.measure(11_900).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		// changing it mid test would skew what's saved
+		assert!(!harness.com_cmds().contains(&ComCmd::SetTrim(trim)));
+	}
+
+	#[tokio::test]
+	async fn test_battery_detect_is_set_without_a_test() {
+		let detect = BatteryDetect {
+			debounce_ms: 1_000,
+			polarity: Polarity::ActiveLow,
+		};
+		let mut harness = Harness::start();
+		harness.expect_mode(Mode::Setup).await;
+		harness.send(Event::SetBatteryDetect(detect)).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(
+			harness
+				.com_cmds()
+				.contains(&ComCmd::SetBatteryDetect(detect))
+		);
+
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		harness.com_cmds();
+		harness.send(Event::SetBatteryDetect(detect)).await;
+		harness.measure(11_900).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		// a different input could end the test as a disconnect
+		assert!(
+			!harness
+				.com_cmds()
+				.contains(&ComCmd::SetBatteryDetect(detect))
+		);
+	}
+
+	#[tokio::test]
+	async fn test_self_test_runs_without_a_test() {
+		let mut harness = Harness::start();
+		harness.expect_mode(Mode::Setup).await;
+		harness.send(Event::RunSelfTest).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(harness.com_cmds().contains(&ComCmd::SelfTest));
+
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		harness.com_cmds();
+		harness.send(Event::RunSelfTest).await;
+		harness.measure(11_900).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		// pulsing the load mid test would show up in the measurements
+		assert!(!harness.com_cmds().contains(&ComCmd::SelfTest));
+	}
+
+	#[tokio::test]
+	async fn test_constant_current_sent_with_the_load() {
+		let setpoint = |cmd: &ComCmd| match cmd {
+			ComCmd::BICommand(control) if load_on(cmd) => Some(control.current_setpoint),
+			_ => None,
+		};
+		let mut harness = Harness::start();
+		harness
+			.send(Event::SetCurrentSetpoint(Some(MilliAmp::new(5_000))))
+			.await;
+		harness.start_test().await;
+		let sent: Vec<_> = harness.com_cmds().iter().filter_map(setpoint).collect();
+		assert_eq!(sent.last(), Some(&Some(MilliAmp::new(5_000))));
+
+		// changed mid test, the battery interface is told straight away
+		harness.send(Event::SetCurrentSetpoint(None)).await;
+		harness.measure(11_900).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		let sent: Vec<_> = harness.com_cmds().iter().filter_map(setpoint).collect();
+		assert_eq!(sent.first(), Some(&None));
+	}
+
+	#[tokio::test]
+	async fn test_window_samples_sent_with_the_load() {
+		let window = |cmd: &ComCmd| match cmd {
+			ComCmd::BICommand(control) if load_on(cmd) => Some(control.window_samples),
+			_ => None,
+		};
+		let mut harness = Harness::start();
+		harness.send(Event::SetWindowSamples(Some(50))).await;
+		// out of range, the last one stays
+		harness.send(Event::SetWindowSamples(Some(0))).await;
+		harness.start_test().await;
+		let sent: Vec<_> = harness.com_cmds().iter().filter_map(window).collect();
+		assert_eq!(sent.last(), Some(&Some(50)));
+	}
+
+	#[tokio::test]
+	async fn test_warmup_isnt_saved() {
+		let mut harness = Harness::start_warming_up(WarmupRule::For(Duration::from_secs(3)));
+		harness.start_test().await;
+		harness.file_cmds();
+		for millivolts in [12_300, 12_200, 12_100, 12_000, 11_900] {
+			harness.measure(millivolts).await;
+		}
+		tokio::time::sleep(Duration::from_millis(100)).await;
+
+		let saved: Vec<(u16, u64)> = harness
+			.file_cmds()
+			.iter()
+			.filter_map(|cmd| match cmd {
+				FileCmd::Push(data) => Some((data.millivolts.into(), data.sample_start_ms)),
+				_ => None,
+			})
+			.collect();
+		// recorded from 3 s after the first measurement under the load
+		assert_eq!(saved, [(12_000, 0), (11_900, 1_000)]);
+	}
+
+	#[tokio::test]
+	async fn test_calibration_corrects_saved_measurements() {
+		let path = std::env::temp_dir().join(format!(
+			"battery-tester-calibration-{}.toml",
+			std::process::id()
+		));
+		let calibrations = Arc::new(CalibrationStore::load(path.clone()).unwrap());
+		let raw = Measurement {
+			vbat: MilliVolt::new(12_000),
+			ibat: MilliAmp::new(2_000),
+			milliwatts: MilliWatt::new(24_000),
+			sample_index: 0,
+			sample_start_ms: 0,
+			sample_duration_ms: 900,
+			temp_centi_c: None,
+			load_temp_centi_c: None,
+			fan_on: false,
+			load: None,
+		};
+		// offsets only, so the internal resistance from the pulse is the same
+		let device_id = DeviceVersion {
+			protocol: PROTOCOL_VERSION,
+			firmware: FirmwareVersion {
+				major: 0,
+				minor: 0,
+				patch: 0,
+			},
+			device_id: DEVICE_ID,
+		}
+		.hex_device_id();
+		calibrations.add_point(
+			&device_id,
+			raw,
+			Some(MilliVolt::new(12_050)),
+			Some(MilliAmp::new(1_970)),
+		);
+		calibrations.save(&device_id).unwrap();
+		let mut harness = Harness::start_calibrated(calibrations);
+		harness.start_test().await;
+		harness.measure(11_900).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		let _ = std::fs::remove_file(path);
+
+		let saved: Vec<(u16, i16)> = harness
+			.file_cmds()
+			.iter()
+			.filter_map(|cmd| match cmd {
+				FileCmd::Push(data) => Some((data.millivolts.into(), data.milliamps.into())),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(saved, [(11_950, 1_970)]);
+	}
+
+	#[tokio::test]
+	async fn test_open_circuit_voltage_before_and_after() {
+		let mut harness = Harness::start_resting(Duration::from_secs(2));
+		harness.set_up().await;
+		harness.send(Event::StartTest).await;
+		harness.expect_mode(Mode::Testing).await;
+		for _ in 0..3 {
+			harness.measure(12_600).await;
+		}
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		// the load stays off resting
+		assert!(!harness.com_cmds().iter().any(load_on));
+		let ocv_before = |cmd: &FileCmd| matches!(cmd, FileCmd::Notes(notes) if notes.ocv_before_mv == Some(12_600));
+		assert!(harness.file_cmds().iter().any(ocv_before));
+
+		harness.pulse().await;
+		harness.measure(10_900).await;
+		harness.expect_mode(Mode::Resting).await;
+		assert!(!load_on(harness.com_cmds().last().unwrap()));
+		for _ in 0..3 {
+			harness.measure(11_900).await;
+		}
+		harness.expect_mode(Mode::EndTest).await;
+		harness.expect_mode(Mode::Setup).await;
+		let ended = harness
+			.file_cmds()
+			.into_iter()
+			.find_map(|cmd| match cmd {
+				FileCmd::Notes(notes) if notes.ended.is_some() => Some(notes),
+				_ => None,
+			})
+			.unwrap();
+		assert_eq!(ended.ocv_before_mv, Some(12_600));
+		assert_eq!(ended.ocv_after_mv, Some(11_900));
+	}
+
+	#[tokio::test]
+	async fn test_max_duration_ends_the_test() {
+		let mut harness = Harness::start();
+		harness
+			.send(Event::SetMaxDuration(Some(Duration::from_secs(3))))
+			.await;
+		harness.start_test().await;
+		// 900 ms of the first measurement, then 1000 ms each
+		for _ in 0..4 {
+			harness.measure(12_000).await;
+		}
+		harness.expect_mode(Mode::EndTest).await;
+
+		let file_cmds = harness.file_cmds();
+		let saved = file_cmds
+			.iter()
+			.filter(|cmd| matches!(cmd, FileCmd::Push(_)))
+			.count();
+		assert_eq!(saved, 4);
+		let ended = file_cmds.iter().find_map(|cmd| match cmd {
+			FileCmd::Notes(notes) => notes.ended,
+			_ => None,
+		});
+		assert_eq!(ended, Some(EndReason::MaxDuration));
+	}
+
+	#[tokio::test]
+	async fn test_battery_removed_isnt_a_cutoff() {
+		let mut harness = Harness::start();
+		harness
+			.send(Event::SetDisconnect(MilliVolt::new(2_000)))
+			.await;
+		harness.start_test().await;
+		harness.measure(12_000).await;
+		// the battery interface stops at its cutoff either way
+		let Event::ComReply(mut pulled) = harness.measured(1_500) else {
+			unreachable!()
+		};
+		pulled.cutoff_reached = true;
+		harness.send(Event::ComReply(pulled)).await;
+		harness.expect_mode(Mode::EndTest).await;
+
+		let ended = harness.file_cmds().iter().find_map(|cmd| match cmd {
+			FileCmd::Notes(notes) => notes.ended,
+			_ => None,
+		});
+		assert_eq!(ended, Some(EndReason::Removed));
+	}
+
+	#[tokio::test]
+	async fn test_notes_saved_with_the_test() {
+		let notes = |cmds: Vec<FileCmd>| -> Vec<TestNotes> {
+			cmds.into_iter()
+				.filter_map(|cmd| match cmd {
+					FileCmd::Notes(notes) => Some(notes),
+					_ => None,
+				})
+				.collect()
+		};
+		let mut harness = Harness::start();
+		// kept until there's a test to save it with
+		harness.send(Event::SetOperator("A. Tester".into())).await;
+		harness.set_up().await;
+		let saved = notes(harness.file_cmds());
+		assert_eq!(saved.len(), 1);
+		assert_eq!(saved[0].battery_id, BATTERY);
+		assert_eq!(saved[0].operator.as_deref(), Some("A. Tester"));
+		assert!(saved[0].notes.is_empty());
+		assert_eq!(saved[0].device_id.as_deref(), Some("0123456789abcdef"));
+
+		harness.send(Event::SetNote("lot 42".into())).await;
+		harness.send(Event::StartTest).await;
+		harness.expect_mode(Mode::Testing).await;
+		let saved = notes(harness.file_cmds());
+		assert_eq!(saved.len(), 1);
+		assert_eq!(saved[0].operator.as_deref(), Some("A. Tester"));
+		assert_eq!(&*saved[0].notes[0].text, "lot 42");
+	}
+
+	#[tokio::test]
+	async fn test_chemistry_sets_the_cutoff() {
+		let mut harness = Harness::start();
+		harness
+			.send(Event::SetChemistry(Chemistry::LiFePo4S4))
+			.await;
+		harness.start_test().await;
+		// under lead-acid's cutoff, over LiFePO4's
+		harness.measure(10_500).await;
+		harness.measure(9_900).await;
+		harness.expect_mode(Mode::EndTest).await;
+
+		let saved: Vec<u16> = harness
+			.file_cmds()
+			.iter()
+			.filter_map(|cmd| match cmd {
+				FileCmd::Push(data) => Some(data.millivolts.into()),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(saved, [10_500]);
+	}
+
+	#[tokio::test]
+	async fn test_cancel_while_testing() {
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		harness.send(Event::CancelTest).await;
+		harness.expect_mode(Mode::EndTest).await;
+		harness.expect_mode(Mode::Setup).await;
+		assert!(
+			harness
+				.file_cmds()
+				.iter()
+				.any(|cmd| matches!(cmd, FileCmd::CloseFile))
+		);
+	}
+
+	#[tokio::test]
+	async fn test_file_error_ends_the_test() {
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		harness
+			.send(Event::FileError(FileErrorKind::StorageFull))
+			.await;
+		harness.expect_mode(Mode::EndTest).await;
+		harness.expect_mode(Mode::Setup).await;
+		assert!(!load_on(harness.com_cmds().last().unwrap()));
+	}
+
+	#[tokio::test]
+	async fn test_journal_kept_while_testing() {
+		let path = std::env::temp_dir().join(format!(
+			"battery-tester-journal-kept-{}.toml",
+			std::process::id()
+		));
+		let mut harness = Harness::start_journaled(path.clone(), None);
+		harness.start_test().await;
+		let journal = Journal::load(&path)
+			.unwrap()
+			.expect("no journal while testing");
+		assert_eq!(journal.battery_id, BATTERY);
+		assert_eq!(journal.saved_to, SavedTo::File("2024-1.tsv".into()));
+		assert_eq!(journal.format, OutputFormat::Tsv);
+
+		harness.send(Event::CancelTest).await;
+		harness.expect_mode(Mode::EndTest).await;
+		harness.expect_mode(Mode::Setup).await;
+		assert_eq!(Journal::load(&path).unwrap(), None);
+	}
+
+	/// A harness with an interrupted test, the operator has asked to resume it.
+	/// The file task's answer to the resume is left to the test.
+	async fn resume_interrupted(
+		test: &str,
+	) -> (
+		Harness,
+		PathBuf,
+		SavedTo,
+		oneshot::Sender<Result<SavedTo, OutputError>>,
+	) {
+		let path = std::env::temp_dir().join(format!(
+			"battery-tester-journal-{test}-{}.toml",
+			std::process::id()
+		));
+		let saved_to = SavedTo::File("2024-1.tsv".into());
+		let interrupted = Journal {
+			battery_id: BATTERY,
+			cutoff: MilliVolt::new(11_000),
+			chemistry: Default::default(),
+			allow_undercurrent: AllowUndercurrent::No,
+			device_name: Some("sim".into()),
+			device_baud: None,
+			format: OutputFormat::Tsv,
+			saved_to: saved_to.clone(),
+			started: "2025-06-01 12:00:00".into(),
+			operator: None,
+			notes: Vec::new(),
+			max_duration_s: None,
+			max_mah: None,
+			internal_resistance_mohm: Some(100),
+			ocv_before_mv: None,
+		};
+		interrupted.save(&path).unwrap();
+		let mut harness = Harness::start_journaled(path.clone(), Some(interrupted));
+		harness.expect_mode(Mode::Resume).await;
+		// the load stays off on the device the test was on
+		let com_cmds = harness.com_cmds();
+		assert!(!load_on(&com_cmds[0]));
+		assert!(matches!(&com_cmds[1], ComCmd::NewDeviceName(device, None) if &**device == "sim"));
+
+		// once the battery interface replies
+		harness
+			.send(Event::DeviceVersion(DeviceVersion {
+				protocol: PROTOCOL_VERSION,
+				firmware: FirmwareVersion {
+					major: 0,
+					minor: 0,
+					patch: 0,
+				},
+				device_id: DEVICE_ID,
+			}))
+			.await;
+		harness.measure(11_900).await;
+		harness.send(Event::StartTest).await;
+		let Some(FileCmd::Resume(resumed, OutputFormat::Tsv, reply_tx)) =
+			harness.file_rx.recv().await
+		else {
+			panic!("test wasn't resumed");
+		};
+		assert_eq!(resumed, saved_to);
+		(harness, path, saved_to, reply_tx)
+	}
+
+	#[tokio::test]
+	async fn test_interrupted_test_resumed() {
+		let (mut harness, path, saved_to, reply_tx) = resume_interrupted("resumed").await;
+		reply_tx.send(Ok(saved_to)).unwrap();
+		harness.expect_mode(Mode::Testing).await;
+		assert!(Journal::load(&path).unwrap().is_some());
+		Journal::remove(&path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_resume_refused_ends_the_test() {
+		let (mut harness, path, saved_to, reply_tx) = resume_interrupted("mismatch").await;
+		let mismatch = OutputError::JournalMismatch(saved_to, OutputFormat::Sqlite);
+		reply_tx.send(Err(mismatch)).unwrap();
+		harness.expect_mode(Mode::EndTest).await;
+		harness.expect_mode(Mode::Setup).await;
+		assert_eq!(Journal::load(&path).unwrap(), None);
+	}
+
+	#[tokio::test]
+	async fn test_button_b_starts_and_stops_the_test() {
+		let mut harness = Harness::start();
+		harness.set_up().await;
+		for (local_load, mode) in [
+			(LoadState::On, Mode::Testing),
+			(LoadState::Off, Mode::EndTest),
+		] {
+			harness
+				.send(Event::ComReply(Status {
+					measurement: None,
+					fault: Ok(()),
+					cutoff_reached: false,
+					local_load: Some(local_load),
+					reset_reason: None,
+					wants_control: false,
+				}))
+				.await;
+			harness.expect_mode(mode).await;
+			if mode == Mode::Testing {
+				harness.pulse().await;
+			}
+		}
+		harness.expect_mode(Mode::Setup).await;
+	}
+
+	#[tokio::test]
+	async fn test_fault_while_testing() {
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		let fault = Fault {
+			kind: FaultKind::Overcurrent,
+			time: 5_000,
+		};
+		harness
+			.send(Event::ComReply(Status {
+				measurement: None,
+				fault: Err(fault),
+				cutoff_reached: false,
+				local_load: None,
+				reset_reason: None,
+				wants_control: false,
+			}))
+			.await;
+		harness.expect_mode(Mode::Fault).await;
+		// the fault is saved with the test, then the test is closed
+		let file_cmds = harness.file_cmds();
+		assert!(matches!(file_cmds.first(), Some(FileCmd::Fault(f)) if *f == fault));
+		assert!(matches!(file_cmds.last(), Some(FileCmd::CloseFile)));
+		assert!(!load_on(harness.com_cmds().last().unwrap()));
+
+		// faults stay until cleared and the battery interface replies without one
+		harness
+			.send(Event::ComReply(Status {
+				measurement: None,
+				fault: Err(fault),
+				cutoff_reached: false,
+				local_load: None,
+				reset_reason: None,
+				wants_control: false,
+			}))
+			.await;
+		harness.send(Event::ClearFault).await;
+		harness.measure(12_000).await;
+		harness.expect_mode(Mode::Setup).await;
+		assert!(
+			harness
+				.com_cmds()
+				.iter()
+				.any(|cmd| matches!(cmd, ComCmd::ClearFault))
+		);
+	}
+
+	#[tokio::test]
+	async fn test_temperature_saved_until_too_hot() {
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		harness.file_cmds();
+		let mut warm = harness.measured(11_500);
+		if let Event::ComReply(Status {
+			measurement: Some(m),
+			..
+		}) = &mut warm
+		{
+			m.temp_centi_c = Some(4_250);
+		}
+		harness.send(warm).await;
+		let fault = Fault {
+			kind: FaultKind::OverTemperature,
+			time: 2_000,
+		};
+		harness
+			.send(Event::ComReply(Status {
+				measurement: None,
+				fault: Err(fault),
+				cutoff_reached: false,
+				local_load: None,
+				reset_reason: None,
+				wants_control: false,
+			}))
+			.await;
+		harness.expect_mode(Mode::Fault).await;
+		let file_cmds = harness.file_cmds();
+		assert!(matches!(
+			file_cmds.first(),
+			Some(FileCmd::Push(data)) if data.temp_centi_c == Some(4_250)
+		));
+		assert!(matches!(&file_cmds[1], FileCmd::Fault(f) if *f == fault));
+		assert!(!load_on(harness.com_cmds().last().unwrap()));
+	}
+
+	#[tokio::test]
+	async fn test_comm_dc_while_testing() {
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		harness.send(Event::CommDc).await;
+		harness.expect_mode(Mode::CommDC).await;
+		// the battery ID and file are kept, so the battery interface coming back goes
+		// straight to waiting for the battery
+		harness.send(Event::ComReconnected).await;
+		harness
+			.send(Event::DeviceVersion(DeviceVersion {
+				protocol: PROTOCOL_VERSION,
+				firmware: FirmwareVersion {
+					major: 0,
+					minor: 0,
+					patch: 0,
+				},
+				device_id: DEVICE_ID,
+			}))
+			.await;
+		harness.expect_mode(Mode::WaitForBattery).await;
+		harness.measure(12_000).await;
+		harness.expect_mode(Mode::WaitForUsrStart).await;
+	}
+
+	#[tokio::test]
+	async fn test_replayed_measurements_are_saved_after_a_comm_dc() {
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		let replayed = |harness: &mut Harness, millivolts| match harness.measured_at(millivolts, 0)
+		{
+			Event::ComReply(Status {
+				measurement: Some(m),
+				..
+			}) => m,
+			_ => unreachable!(),
+		};
+		let saved = |harness: &mut Harness| -> Vec<u16> {
+			harness
+				.file_cmds()
+				.iter()
+				.filter_map(|cmd| match cmd {
+					FileCmd::Push(data) => Some(data.millivolts.into()),
+					_ => None,
+				})
+				.collect()
+		};
+		let first = replayed(&mut harness, 11_900);
+		harness.send(Event::Replayed(first)).await;
+		harness.send(Event::CommDc).await;
+		harness.expect_mode(Mode::CommDC).await;
+		assert_eq!(saved(&mut harness), [11_900]);
+		harness.send(Event::ComReconnected).await;
+		harness
+			.send(Event::DeviceVersion(DeviceVersion {
+				protocol: PROTOCOL_VERSION,
+				firmware: FirmwareVersion {
+					major: 0,
+					minor: 0,
+					patch: 0,
+				},
+				device_id: DEVICE_ID,
+			}))
+			.await;
+		harness.expect_mode(Mode::WaitForBattery).await;
+		// the one already saved comes again, then the ones taken while the link was down
+		harness.send(Event::Replayed(first)).await;
+		for millivolts in [12_050, 12_060] {
+			let m = replayed(&mut harness, millivolts);
+			harness.send(Event::Replayed(m)).await;
+		}
+		harness.measure(12_070).await;
+		harness.expect_mode(Mode::WaitForUsrStart).await;
+		assert_eq!(saved(&mut harness), [12_050, 12_060]);
+	}
+
+	#[tokio::test]
+	async fn test_autonomous_log_is_saved_as_the_test() {
+		let mut harness = Harness::start();
+		harness.set_up().await;
+		harness.com_cmds();
+		harness.send(Event::StartAutonomous).await;
+		harness.expect_mode(Mode::Autonomous).await;
+		harness.send(Event::FetchAutonomousLog).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		let sent = harness.com_cmds();
+		assert!(matches!(
+			sent.as_slice(),
+			[ComCmd::StartAutonomous(test), ComCmd::DumpAutonomousLog]
+				if test.cutoff == MilliVolt::new(11_000)
+		));
+		harness.file_cmds();
+		for (dt, millivolts) in [(0, 12_100), (10_000, 11_950)] {
+			harness
+				.send(Event::AutonomousLog(Some(LoggedSample {
+					sample_start_ms: dt,
+					sample_duration_ms: 9_900,
+					vbat: MilliVolt::new(millivolts),
+					ibat: MilliAmp::new(2_000),
+				})))
+				.await;
+		}
+		harness.send(Event::AutonomousLog(None)).await;
+		harness.expect_mode(Mode::EndTest).await;
+
+		let saved: Vec<(u16, u64)> = harness
+			.file_cmds()
+			.iter()
+			.filter_map(|cmd| match cmd {
+				FileCmd::Push(data) => Some((data.millivolts.into(), data.sample_start_ms)),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(saved, [(12_100, 0), (11_950, 10_000)]);
+	}
+
+	#[tokio::test]
+	async fn test_stalled_measurements_end_the_test() {
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		for _ in 0..=crate::STALE_REPLY_LIMIT {
+			harness
+				.send(Event::ComReply(Status {
+					measurement: None,
+					fault: Ok(()),
+					cutoff_reached: false,
+					local_load: None,
+					reset_reason: None,
+					wants_control: false,
+				}))
+				.await;
+		}
+		harness.expect_mode(Mode::EndTest).await;
+		harness.expect_mode(Mode::Setup).await;
+	}
+
+	#[tokio::test]
+	async fn test_quicker_polls_wait_longer_for_measurements() {
+		let mut harness = Harness::spawn(TestSettings {
+			poll_ms: DEFAULT_POLL_MS / 5,
+			..Harness::settings()
+		});
+		harness.start_test().await;
+		// the same time as the default rate's limit is five times the replies
+		for _ in 0..=crate::STALE_REPLY_LIMIT {
+			harness
+				.send(Event::ComReply(Status {
+					measurement: None,
+					fault: Ok(()),
+					cutoff_reached: false,
+					local_load: None,
+					reset_reason: None,
+					wants_control: false,
+				}))
+				.await;
+		}
+		harness.measure(12_000).await;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(
+			harness
+				.file_cmds()
+				.iter()
+				.any(|cmd| matches!(cmd, FileCmd::Push(_)))
+		);
+	}
+
+	#[tokio::test]
+	async fn test_shutdown() {
+		let mut harness = Harness::start();
+		harness.expect_mode(Mode::Setup).await;
+		harness.send(Event::Shutdown).await;
+		harness.expect_mode(Mode::Shutdown).await;
+		timeout(Duration::from_secs(1), &mut harness.task)
+			.await
+			.expect("program task didn't stop")
+			.unwrap()
+			.unwrap();
+		assert!(matches!(harness.com_cmds().last(), Some(ComCmd::Shutdown)));
+		assert!(matches!(
+			harness.file_cmds().last(),
+			Some(FileCmd::Shutdown)
+		));
+	}
+
+	#[tokio::test]
+	async fn test_internal_error_while_testing() {
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		harness.send(Event::InternalError).await;
+		harness.expect_mode(Mode::Shutdown).await;
+		timeout(Duration::from_secs(1), &mut harness.task)
+			.await
+			.expect("program task didn't stop")
+			.unwrap()
+			.unwrap();
+		let com_cmds = harness.com_cmds();
+		assert!(matches!(com_cmds.last(), Some(ComCmd::Shutdown)));
+		assert!(!load_on(&com_cmds[com_cmds.len() - 2]));
+	}
+
+	#[tokio::test]
+	async fn test_file_task_stopping_idles_the_load() {
+		let mut harness = Harness::start();
+		harness.start_test().await;
+		// the fake file task stops at the next command it can't pass on
+		harness.file_rx.close();
+		let stopped = timeout(Duration::from_secs(1), async {
+			while !harness.task.is_finished() {
+				let reply = harness.measured(11_500);
+				let _ = harness.event_tx.send(reply).await;
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await;
+		assert!(stopped.is_ok(), "program task didn't stop");
+		let result = (&mut harness.task).await.unwrap();
+		assert!(matches!(result, Err(TaskError::Disconnected)));
+		let com_cmds = harness.com_cmds();
+		assert!(matches!(com_cmds.last(), Some(ComCmd::Shutdown)));
+		assert!(!load_on(&com_cmds[com_cmds.len() - 2]));
+	}
+}
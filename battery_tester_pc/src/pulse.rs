@@ -0,0 +1,148 @@
+//! A short load pulse when a test starts, off → on → off, to estimate the battery's
+//! DC internal resistance from how far the voltage sags under the load and recovers after.
+//!
+//! The estimate is saved with the test's notes. `--no-ir-pulse` starts tests without it.
+
+use battery_tester_common::Measurement;
+
+/// Measurements skipped after each change of the load, they may be averaged over the change
+const SETTLE_MEASUREMENTS: u8 = 1;
+/// Less change in current than this is too little to tell the resistance from noise
+const MIN_PULSE_MILLIAMPS: i32 = 500;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum PulseStep {
+	/// Load off, for the open circuit voltage
+	Rest,
+	/// Load on, for the sag
+	Loaded(Measurement),
+	/// Load off again, for the recovery
+	Recovery(Measurement, Measurement),
+}
+
+/// What the program task does next in the pulse
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PulseAction {
+	/// Keep the load as it is until there's a settled measurement
+	Wait,
+	LoadOn,
+	LoadOff,
+	/// Estimated internal resistance in milliohms,
+	/// `None` if the current or voltage didn't change enough to tell
+	Done(Option<u32>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Pulse {
+	step: PulseStep,
+	/// Measurements left to skip before the next one is used
+	settling: u8,
+}
+
+impl Default for Pulse {
+	/// Starts with the load off
+	fn default() -> Self {
+		Self {
+			step: PulseStep::Rest,
+			settling: SETTLE_MEASUREMENTS,
+		}
+	}
+}
+
+impl Pulse {
+	/// Take in a fresh measurement
+	pub fn measured(&mut self, m: &Measurement) -> PulseAction {
+		if self.settling > 0 {
+			self.settling -= 1;
+			return PulseAction::Wait;
+		}
+		self.settling = SETTLE_MEASUREMENTS;
+		match self.step {
+			PulseStep::Rest => {
+				self.step = PulseStep::Loaded(*m);
+				PulseAction::LoadOn
+			}
+			PulseStep::Loaded(rest) => {
+				self.step = PulseStep::Recovery(rest, *m);
+				PulseAction::LoadOff
+			}
+			PulseStep::Recovery(rest, loaded) => PulseAction::Done(estimate(&rest, &loaded, m)),
+		}
+	}
+}
+
+/// The average of the resistance from the sag and from the recovery
+fn estimate(rest: &Measurement, loaded: &Measurement, recovered: &Measurement) -> Option<u32> {
+	let milliohms = |off: &Measurement| {
+		let millivolts = i32::from(u16::from(off.vbat)) - i32::from(u16::from(loaded.vbat));
+		let milliamps = i32::from(i16::from(loaded.ibat)) - i32::from(i16::from(off.ibat));
+		(milliamps >= MIN_PULSE_MILLIAMPS && millivolts > 0)
+			.then(|| f64::from(millivolts) * 1000.0 / f64::from(milliamps))
+	};
+	let sag = milliohms(rest)?;
+	let recovery = milliohms(recovered)?;
+	Some(((sag + recovery) / 2.0).round() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use battery_tester_common::{MilliAmp, MilliVolt, MilliWatt};
+
+	fn measurement(millivolts: u16, milliamps: i16) -> Measurement {
+		Measurement {
+			vbat: MilliVolt::new(millivolts),
+			ibat: MilliAmp::new(milliamps),
+			milliwatts: MilliWatt::new(0),
+			sample_index: 0,
+			sample_start_ms: 0,
+			sample_duration_ms: 900,
+			temp_centi_c: None,
+			load_temp_centi_c: None,
+			fan_on: false,
+			load: None,
+		}
+	}
+
+	/// What the pulse does with a settling measurement then a settled one for each step
+	fn pulse(rest: Measurement, loaded: Measurement, recovered: Measurement) -> Vec<PulseAction> {
+		let mut pulse = Pulse::default();
+		[rest, loaded, recovered]
+			.iter()
+			.flat_map(|m| [pulse.measured(m), pulse.measured(m)])
+			.collect()
+	}
+
+	#[test]
+	fn test_pulse_estimates_resistance() {
+		// 50 mΩ from the sag, 45 from the recovery
+		assert_eq!(
+			pulse(
+				measurement(12_700, 0),
+				measurement(12_500, 4_000),
+				measurement(12_680, 0),
+			),
+			[
+				PulseAction::Wait,
+				PulseAction::LoadOn,
+				PulseAction::Wait,
+				PulseAction::LoadOff,
+				PulseAction::Wait,
+				PulseAction::Done(Some(48)),
+			]
+		);
+	}
+
+	#[test]
+	fn test_pulse_too_small() {
+		let done = |loaded| {
+			*pulse(measurement(12_700, 0), loaded, measurement(12_700, 0))
+				.last()
+				.unwrap()
+		};
+		// too little current to tell
+		assert_eq!(done(measurement(12_690, 400)), PulseAction::Done(None));
+		// the voltage didn't sag
+		assert_eq!(done(measurement(12_700, 4_000)), PulseAction::Done(None));
+	}
+}
@@ -0,0 +1,62 @@
+//! Batteries to test back to back on a channel, `battery-tester-client queue add`.
+//!
+//! When a test ends the next battery in the queue is set up, and the channel waits for
+//! the tested battery to be swapped for it. The queue is lost when the server stops.
+
+use battery_tester_common::MilliVolt;
+use serde::{Deserialize, Serialize};
+
+use crate::BatteryID;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct QueuedTest {
+	pub battery_id: BatteryID,
+	/// Cutoff for this battery's test only, `None` for the channel's
+	pub cutoff: Option<MilliVolt>,
+}
+
+impl std::str::FromStr for QueuedTest {
+	type Err = String;
+
+	/// `2024-7` for battery 7 of 2024, `2024-7:11500` to test it to 11.5 V
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let expected = || {
+			format!(
+				"unknown battery: {s}, expected year-index (2024-7) or year-index:millivolts (2024-7:11500)"
+			)
+		};
+		let (id, cutoff) = match s.split_once(':') {
+			Some((id, millivolts)) => (
+				id,
+				Some(MilliVolt::new(millivolts.parse().map_err(|_| expected())?)),
+			),
+			None => (s, None),
+		};
+		let (year, index) = id.split_once('-').ok_or_else(expected)?;
+		Ok(QueuedTest {
+			battery_id: BatteryID {
+				year: year.parse().map_err(|_| expected())?,
+				index: index.parse().map_err(|_| expected())?,
+			},
+			cutoff,
+		})
+	}
+}
+
+impl std::fmt::Display for QueuedTest {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let BatteryID { year, index } = self.battery_id;
+		write!(f, "{year}-{index}")?;
+		match self.cutoff {
+			Some(cutoff) => write!(f, " to {cutoff} mV"),
+			None => Ok(()),
+		}
+	}
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum QueueChange {
+	/// Add these to the end of the queue, in order
+	Add(Vec<QueuedTest>),
+	Clear,
+}
@@ -0,0 +1,176 @@
+//! Every battery the team has, so a test can be checked against what the battery should be.
+//!
+//! Kept in `battery-registry.toml` in the output directory unless the server is started
+//! with `--registry`, batteries are added with `battery-tester-client battery add`.
+
+use std::{
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{BatteryID, Error};
+
+const REGISTRY_FILE: &str = "battery-registry.toml";
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct RegisteredBattery {
+	pub id: BatteryID,
+	/// e.g. SLA or LiFePO4
+	pub chemistry: Box<str>,
+	/// Capacity the manufacturer gives
+	pub nominal_mah: Option<u32>,
+	/// When the battery was made, as it's written on the battery
+	pub manufactured: Option<Box<str>>,
+	/// Tests run on the battery since it was added
+	#[serde(default)]
+	pub tests: u32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RegistryFile {
+	#[serde(default)]
+	batteries: Vec<RegisteredBattery>,
+}
+
+/// Shared by every channel and the IPC task, saved each time it changes
+#[derive(Debug)]
+pub struct BatteryRegistry {
+	path: PathBuf,
+	batteries: Mutex<Vec<RegisteredBattery>>,
+}
+
+impl BatteryRegistry {
+	pub fn path(output_dir: &Path) -> PathBuf {
+		output_dir.join(REGISTRY_FILE)
+	}
+
+	/// An empty registry if the file isn't there yet
+	pub fn load(path: PathBuf) -> Result<Self, Error> {
+		let file: RegistryFile = match std::fs::read_to_string(&path) {
+			Ok(text) => toml::from_str(&text)
+				.map_err(|e| Error::RegistryParse(path.clone().into_boxed_path(), e))?,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => RegistryFile::default(),
+			Err(e) => return Err(Error::RegistryRead(path.into_boxed_path(), e)),
+		};
+		Ok(Self {
+			path,
+			batteries: Mutex::new(file.batteries),
+		})
+	}
+
+	pub fn get(&self, id: BatteryID) -> Option<RegisteredBattery> {
+		self.lock().iter().find(|battery| battery.id == id).cloned()
+	}
+
+	/// Nothing's been added, so there's no point warning about unknown batteries
+	pub fn is_empty(&self) -> bool {
+		self.lock().is_empty()
+	}
+
+	pub fn list(&self) -> Vec<RegisteredBattery> {
+		self.lock().clone()
+	}
+
+	/// Add the battery, or replace what's known about it keeping its test count
+	pub fn add(&self, mut battery: RegisteredBattery) -> std::io::Result<()> {
+		let mut batteries = self.lock();
+		match batteries.iter_mut().find(|known| known.id == battery.id) {
+			Some(known) => {
+				battery.tests = known.tests;
+				*known = battery;
+			}
+			None => batteries.push(battery),
+		}
+		self.save(&batteries)
+	}
+
+	/// Count a test run on the battery, batteries that aren't registered aren't added
+	pub fn record_test(&self, id: BatteryID) -> std::io::Result<()> {
+		let mut batteries = self.lock();
+		let Some(battery) = batteries.iter_mut().find(|battery| battery.id == id) else {
+			return Ok(());
+		};
+		battery.tests += 1;
+		self.save(&batteries)
+	}
+
+	fn lock(&self) -> std::sync::MutexGuard<'_, Vec<RegisteredBattery>> {
+		// the list is valid between every change, a panic mid-change can't leave it half done
+		self.batteries
+			.lock()
+			.unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+
+	/// Replaces the file all at once, a crash while saving leaves the old one
+	fn save(&self, batteries: &[RegisteredBattery]) -> std::io::Result<()> {
+		let file = RegistryFile {
+			batteries: batteries.to_vec(),
+		};
+		// only fails for types toml can't represent, none are used here
+		let text = toml::to_string_pretty(&file).unwrap();
+		let tmp = self.path.with_extension("toml.tmp");
+		std::fs::write(&tmp, text)?;
+		std::fs::rename(tmp, &self.path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn battery(index: u8, chemistry: &str) -> RegisteredBattery {
+		RegisteredBattery {
+			id: BatteryID { year: 2025, index },
+			chemistry: chemistry.into(),
+			nominal_mah: Some(7_000),
+			manufactured: None,
+			tests: 0,
+		}
+	}
+
+	#[test]
+	fn test_registry_saved() {
+		let dir =
+			std::env::temp_dir().join(format!("battery-tester-registry-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = BatteryRegistry::path(&dir);
+
+		let registry = BatteryRegistry::load(path.clone()).unwrap();
+		assert!(registry.is_empty());
+		registry.add(battery(1, "SLA")).unwrap();
+		registry.add(battery(2, "SLA")).unwrap();
+		registry.record_test(battery(1, "SLA").id).unwrap();
+		registry.record_test(battery(1, "SLA").id).unwrap();
+		// only registered batteries are counted
+		registry
+			.record_test(BatteryID {
+				year: 2024,
+				index: 9,
+			})
+			.unwrap();
+		// replacing what's known keeps the count
+		registry.add(battery(1, "LiFePO4")).unwrap();
+
+		let reloaded = BatteryRegistry::load(path.clone()).unwrap();
+		assert_eq!(reloaded.list(), registry.list());
+		assert_eq!(
+			reloaded.get(battery(1, "SLA").id),
+			Some(RegisteredBattery {
+				tests: 2,
+				..battery(1, "LiFePO4")
+			})
+		);
+		assert_eq!(reloaded.list().len(), 2);
+		assert!(!path.with_extension("toml.tmp").exists());
+
+		std::fs::write(&path, "[[batteries]]\nchemistry = \"SLA\"\n").unwrap();
+		assert!(matches!(
+			BatteryRegistry::load(path),
+			Err(Error::RegistryParse(..))
+		));
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
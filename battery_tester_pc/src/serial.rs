@@ -1,230 +1,1542 @@
-use battery_tester_common::{BIReply, BiCommand};
+use std::collections::VecDeque;
+
+use battery_tester_common::{
+	BIReply, BatteryDetect, BiCommand, CommandKind, ControlWord, LoadState, LoggedFault,
+	Measurement, ReplyKind, SelfTestReport, Trim, UNSOLICITED_SEQ, USB_PID, USB_VID,
+	baud_supported,
+	frame::{self, FrameBuffer},
+};
+use serde::{Deserialize, Serialize};
 use tokio::{
-	io::AsyncReadExt,
+	io::{AsyncRead, AsyncReadExt, AsyncWrite},
 	select,
-	sync::mpsc::{Receiver, Sender},
-	time::MissedTickBehavior,
+	sync::{
+		mpsc::{Receiver, Sender},
+		watch,
+	},
+	time::{Instant, MissedTickBehavior},
 };
-use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialPortType};
 
 use crate::{
-	ComCmd, DEFALT_BAUD, Event, INCOMING_MAX_SIZE, OUTGOING_MAX_SIZE, Printer, clear_fault_command,
-	idle_command,
+	ComCmd, DeviceVersion, Event, INCOMING_MAX_SIZE, LinkStats, OUTGOING_MAX_SIZE, Printer,
+	TaskError, clear_fault_command, idle_command,
+	live::{LiveSummary, LiveView},
 };
 
-pub async fn serial_com_task(
+/// First retry delay after losing the serial device, doubled after each failure
+const RECONNECT_MIN_MS: u64 = 250;
+/// Slowest retry rate when re-opening a lost serial device
+const RECONNECT_MAX_MS: u64 = 8_000;
+/// How often the link's counters are printed, so a flaky cable shows up in the log
+const LINK_SUMMARY_EVERY: std::time::Duration = std::time::Duration::from_secs(600);
+/// Most commands waiting on a reply, the oldest is counted as lost past this
+const MAX_IN_FLIGHT: usize = 16;
+/// A command without a reply this long after it was sent is counted as lost
+const REPLY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// Hellos without an answer before the baud is blamed, 5 s at the default poll rate
+const UNANSWERED_HELLOS: u32 = 10;
+/// How long the battery interface has to answer each idle command as the serial task stops
+const STOP_CONFIRM_WAIT: std::time::Duration = std::time::Duration::from_millis(500);
+/// Idle commands sent as the serial task stops before giving up on a load off reply
+const STOP_ATTEMPTS: u32 = 3;
+
+/// How the serial task drives its battery interface
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct LinkSettings {
+	/// ms between commands, see [`crate::engine::EngineBuilder::poll_ms`]
+	pub poll_ms: u16,
+	/// For a device set without one, see [`crate::engine::EngineBuilder::baud`]
+	pub baud: u32,
+}
+
+/// The device the link is to, kept to re-open it when it goes down
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Device {
+	name: Box<str>,
+	baud: u32,
+}
+
+impl Device {
+	/// `baud` of `None` for `default_baud`
+	fn new(name: Box<str>, baud: Option<u32>, default_baud: u32) -> Self {
+		Self {
+			name,
+			baud: baud.unwrap_or(default_baud),
+		}
+	}
+
+	/// See [`Reported::link_baud`]
+	fn link_baud(&self, transport: &impl Transport) -> Option<u32> {
+		transport.uses_baud(&self.name).then_some(self.baud)
+	}
+}
+
+/// What the battery interface last told us, for status reports
+pub struct Reported {
+	pub measurement_tx: watch::Sender<Option<Measurement>>,
+	/// A summary of the measurements every so often, see [`crate::live`]
+	pub live_tx: watch::Sender<Option<LiveSummary>>,
+	pub live: LiveView,
+	pub trim_tx: watch::Sender<Option<Trim>>,
+	pub battery_detect_tx: watch::Sender<Option<BatteryDetect>>,
+	/// From every reply
+	pub bat_present_tx: watch::Sender<Option<bool>>,
+	pub load_tx: watch::Sender<Option<LoadState>>,
+	/// The faults kept in its flash, read each time it connects
+	pub fault_log_tx: watch::Sender<Vec<LoggedFault>>,
+	/// From the version it sends as it connects, then each one asked for
+	pub self_test_tx: watch::Sender<Option<SelfTestReport>>,
+	/// The baud the link was opened at, checked against the battery interface's.
+	/// `None` when it doesn't reach its UART, see [`Transport::uses_baud`].
+	pub link_baud: Option<u32>,
+}
+
+/// Asked of the battery interface before it's sent control words, again on each connection
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Handshake {
+	Hello,
+	GetTrim,
+	GetBatteryDetect,
+	/// The faults sent so far, until the answer says they're all sent
+	DumpFaultLog(Vec<LoggedFault>),
+	Done,
+}
+
+/// Opens the link to a battery interface by its device name
+pub trait Transport: Clone + Send + Sync + 'static {
+	type Link: AsyncRead + AsyncWrite + Unpin + Send;
+
+	fn open(
+		&self,
+		device: &str,
+		baud: u32,
+	) -> impl Future<Output = std::io::Result<Self::Link>> + Send;
+
+	/// Whether `open`'s baud has to match the battery interface's UART
+	fn uses_baud(&self, _device: &str) -> bool {
+		true
+	}
+}
+
+/// Serial ports at the baud they're opened at, device names are like /dev/ttyACM0 or COM3.
+/// Either the micro:bit's interface MCU or the battery interface's own USB, see [`is_native_usb`].
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct SerialTransport;
+
+impl Transport for SerialTransport {
+	type Link = tokio_serial::SerialStream;
+
+	async fn open(&self, device: &str, baud: u32) -> std::io::Result<Self::Link> {
+		if !baud_supported(baud) {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				format!("the battery interface can't run at {baud} baud"),
+			));
+		}
+		let mut daq_serial = tokio_serial::new(device, baud)
+			.data_bits(tokio_serial::DataBits::Eight)
+			.stop_bits(tokio_serial::StopBits::One)
+			.open_native_async()?;
+
+		daq_serial.set_exclusive(false)?;
+		if is_native_usb(device) {
+			// CDC-ACM only sends once the host says it's listening, the baud is ignored
+			daq_serial.write_data_terminal_ready(true)?;
+		}
+		daq_serial.clear(tokio_serial::ClearBuffer::All)?;
+		Ok(daq_serial)
+	}
+
+	fn uses_baud(&self, device: &str) -> bool {
+		!is_native_usb(device)
+	}
+}
+
+/// Whether `device` is the battery interface's own USB port rather than the interface MCU's,
+/// by its [`USB_VID`] and [`USB_PID`]
+pub fn is_native_usb(device: &str) -> bool {
+	let Ok(ports) = tokio_serial::available_ports() else {
+		return false;
+	};
+	// /dev/serial/by-id/... links to the port's name
+	let resolved = std::fs::canonicalize(device).ok();
+	ports.iter().any(|port| {
+		(port.port_name == device || resolved.as_deref() == Some(port.port_name.as_ref()))
+			&& matches!(
+				&port.port_type,
+				SerialPortType::UsbPort(usb) if usb.vid == USB_VID && usb.pid == USB_PID
+			)
+	})
+}
+
+/// A serial port on the server's machine, see [`crate::ServerCmd::ListSerialPorts`]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct PortListing {
+	pub name: Box<str>,
+	/// `None` for ports that aren't USB
+	pub usb: Option<UsbListing>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct UsbListing {
+	pub vid: u16,
+	pub pid: u16,
+	pub manufacturer: Option<Box<str>>,
+	pub product: Option<Box<str>>,
+	pub serial_number: Option<Box<str>>,
+}
+
+impl UsbListing {
+	/// The battery interface's own USB port, by its [`USB_VID`] and [`USB_PID`]
+	pub fn battery_interface(&self) -> bool {
+		self.vid == USB_VID && self.pid == USB_PID
+	}
+}
+
+/// Every serial port the OS knows of, what the client's `ports` shows
+pub fn list_ports() -> tokio_serial::Result<Vec<PortListing>> {
+	let ports = tokio_serial::available_ports()?;
+	Ok(ports
+		.into_iter()
+		.map(|port| PortListing {
+			name: port.port_name.into_boxed_str(),
+			usb: match port.port_type {
+				SerialPortType::UsbPort(usb) => Some(UsbListing {
+					vid: usb.vid,
+					pid: usb.pid,
+					manufacturer: usb.manufacturer.map(String::into_boxed_str),
+					product: usb.product.map(String::into_boxed_str),
+					serial_number: usb.serial_number.map(String::into_boxed_str),
+				}),
+				_ => None,
+			},
+		})
+		.collect())
+}
+
+/// Hands out sequence numbers and matches replies to the commands that caused them.
+/// Replies can come out of order, the firmware answers control words and heartbeats as its
+/// power task gets to them and the rest right away, so a command is only lost once it's
+/// waited [`REPLY_TIMEOUT`].
+#[derive(Debug, Default)]
+struct InFlight {
+	next_seq: u32,
+	pending: VecDeque<(u32, Instant)>,
+	stats: LinkStats,
+	control: Control,
+}
+
+/// Whether the battery interface has the control word, once it has the link is kept up
+/// with [`CommandKind::Heartbeat`]s so a lost resend can't change anything
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+enum Control {
+	#[default]
+	Unsent,
+	/// Sent with this `seq`, not answered yet
+	Pending(u32),
+	Held,
+}
+
+enum ReplyMatch {
+	/// Answers a pending command, `lost` others timed out since the last reply
+	Acked { lost: usize },
+	/// Answers a command that was already answered or never sent
+	Duplicate,
+	/// Firmware sent it without being asked
+	Unsolicited,
+}
+
+impl InFlight {
+	fn next_seq(&mut self) -> u32 {
+		let seq = self.next_seq;
+		self.next_seq = match seq.wrapping_add(1) {
+			UNSOLICITED_SEQ => 0,
+			next => next,
+		};
+		seq
+	}
+
+	fn sent(&mut self, seq: u32, at: Instant) {
+		self.stats.sent += 1;
+		if self.pending.len() == MAX_IN_FLIGHT {
+			self.pending.pop_front();
+			self.stats.lost += 1;
+		}
+		self.pending.push_back((seq, at));
+	}
+
+	fn reply(&mut self, seq: u32, at: Instant) -> ReplyMatch {
+		if seq == UNSOLICITED_SEQ {
+			return ReplyMatch::Unsolicited;
+		}
+		let Some(pos) = self.pending.iter().position(|(pending, _)| *pending == seq) else {
+			self.stats.duplicate += 1;
+			return ReplyMatch::Duplicate;
+		};
+		// the rest are still waited on unless they've timed out
+		let (_seq, sent_at) = self.pending.remove(pos).unwrap();
+		let waiting = self.pending.len();
+		self.pending
+			.retain(|(_seq, sent_at)| at.saturating_duration_since(*sent_at) < REPLY_TIMEOUT);
+		let lost = waiting - self.pending.len();
+		self.stats.lost += lost as u64;
+		self.stats.acked += 1;
+		self.stats.last_rtt = Some(at - sent_at);
+		self.stats.total_rtt += at - sent_at;
+		if self.control == Control::Pending(seq) {
+			self.control = Control::Held;
+		}
+		ReplyMatch::Acked { lost }
+	}
+
+	/// Nothing pending will be answered once the link goes down
+	fn link_lost(&mut self) {
+		self.stats.lost += self.pending.len() as u64;
+		self.pending.clear();
+		self.control = Control::Unsent;
+	}
+}
+
+/// Fails when the program task is gone, after leaving the battery interface idle
+pub async fn serial_com_task<T: Transport>(
+	transport: T,
 	mut event_tx: Sender<Event>,
 	mut com_cmd_rx: Receiver<ComCmd>,
+	stats_tx: watch::Sender<LinkStats>,
+	mut reported: Reported,
+	link: LinkSettings,
 	mut printer: Printer,
-) {
+) -> Result<(), TaskError> {
 	use std::io::Write;
-	let mut daq_serial = loop {
+	let LinkSettings { poll_ms, .. } = link;
+	let (mut device, mut daq_serial) = loop {
 		match com_cmd_rx.recv().await {
-			Some(ComCmd::NewDeviceName(dev_name)) => match connect(dev_name.as_ref()).await {
-				Ok(ds) => break ds,
-				Err(e) => {
-					printer
-						.buf(|tv| {
-							write!(
-								tv,
-								"can't make initial connection to: {dev_name} due to:\n{e}"
-							)
-						})
-						.await
+			Some(ComCmd::NewDeviceName(dev_name, baud)) => {
+				let device = Device::new(dev_name, baud, link.baud);
+				match transport.open(device.name.as_ref(), device.baud).await {
+					Ok(ds) => break (device, ds),
+					Err(e) => {
+						let dev_name = &device.name;
+						printer
+							.buf(|tv| {
+								write!(
+									tv,
+									"can't make initial connection to: {dev_name} due to:\n{e}"
+								)
+							})
+							.await
+					}
 				}
-			},
+			}
 			Some(ComCmd::Shutdown) => {
 				println!("exiting serial_com_task");
-				return;
+				return Ok(());
 			}
-			None => return,
+			None => return Ok(()),
 			_ => {}
 		}
 	};
 	use tokio::time::{self, Duration};
-	// we send at 2Hz
-	let mut tx_interval = time::interval(Duration::from_millis(500));
+	// 2 Hz by default, the battery interface is sent the rate with each control word
+	let mut tx_interval = time::interval(Duration::from_millis(poll_ms.into()));
 	tx_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	let mut summary_interval =
+		time::interval_at(Instant::now() + LINK_SUMMARY_EVERY, LINK_SUMMARY_EVERY);
 	let mut incoming_buf: Vec<u8> = Vec::with_capacity(INCOMING_MAX_SIZE * 2);
-	let mut bi_command = BiCommand::default();
+	let mut frame_buf = FrameBuffer::<INCOMING_MAX_SIZE>::new();
+	let mut in_flight = InFlight::default();
+	let mut bi_command = ControlWord::default();
+	// say hello and get the settings instead of sending the control word until the device answers
+	let mut handshake = Handshake::Hello;
+	let mut hellos = 0;
+	reported.link_baud = device.link_baud(&transport);
 	loop {
+		// set when the port errors out, we then try to re-open it
+		let mut link_down = false;
 		let new_cmd: Option<ComCmd> = select! {
 			cmd = com_cmd_rx.recv() => {
-				printer.buf(|tv| write!(tv, "command: {:?}", &cmd)).await;
-				cmd
+				printer.buf(|tv| write!(tv, "command: {:?}", cmd)).await;
+				// the program task is gone, idle the battery interface and stop
+				Some(cmd.unwrap_or(ComCmd::Shutdown))
 			}
 			serial_resp = serial_read_response(&mut daq_serial, &mut incoming_buf) => {
 				match serial_resp {
-					Ok(_reply) => {
-						serial_decode(&mut incoming_buf, &mut event_tx).await;
-						// event_tx.send(Event::ComReply(reply)).await.unwrap();
+					Ok(0) => {
+						printer.stat("serial device closed").await;
+						link_down = true;
+						None
+					}
+					Ok(_num_read) => {
+						let decoded = serial_decode(
+							&mut incoming_buf,
+							&mut frame_buf,
+							&mut in_flight,
+							&mut handshake,
+							&mut reported,
+							&mut event_tx,
+							&mut printer,
+						).await;
+						if let Err(e) = decoded {
+							stop_idle(&mut daq_serial, &mut in_flight, &mut printer).await;
+							return Err(e);
+						}
 						None
 					}
 					Err(e) => {
-						printer.buf(|tv| write!(tv, "serial comm error when reading BI response:\n{e}")).await;
-						event_tx.send(Event::CommDc).await.unwrap();
+						printer.error(|tv| write!(tv, "serial comm error when reading BI response:\n{e}")).await;
+						link_down = true;
 						None
 					}
 				}
 			}
 			_ = tx_interval.tick() => {
-				match serial_write_command(&mut daq_serial, &bi_command).await {
-					Ok(_) => None,
+				if handshake == Handshake::Hello {
+					hellos += 1;
+					if hellos == UNANSWERED_HELLOS {
+						let baud = device.baud;
+						printer.warn(|tv| write!(tv, "no answer at {baud} baud, is the battery interface's firmware built for another?")).await;
+					}
+				}
+				let command = match &handshake {
+					Handshake::Hello => CommandKind::Hello,
+					Handshake::GetTrim => CommandKind::GetTrim,
+					Handshake::GetBatteryDetect => CommandKind::GetBatteryDetect,
+					Handshake::DumpFaultLog(_) => CommandKind::DumpFaultLog,
+					Handshake::Done if in_flight.control == Control::Held => CommandKind::Heartbeat,
+					Handshake::Done => CommandKind::Control(ControlWord { poll_ms: Some(poll_ms), ..bi_command }),
+				};
+				let control = matches!(command, CommandKind::Control(_));
+				match serial_write_command(&mut daq_serial, &mut in_flight, command).await {
+					Ok(seq) => {
+						if control {
+							in_flight.control = Control::Pending(seq);
+						}
+						None
+					}
 					Err(e) => {
-						printer.buf(|tv| write!(tv, "serial comm error when writing BI command on regular interval:\n{e}")).await;
-						event_tx.send(Event::CommDc).await.unwrap();
+						printer.error(|tv| write!(tv, "serial comm error when writing BI command on regular interval:\n{e}")).await;
+						link_down = true;
 						None
 					}
 				}
 			}
+			_ = summary_interval.tick() => {
+				printer.buf(|tv| write!(tv, "serial link: {}", in_flight.stats)).await;
+				None
+			}
 		};
 
 		match new_cmd {
 			Some(ComCmd::BICommand(new_bi_command)) => {
 				bi_command = new_bi_command;
-				if let Err(serial_err) = serial_write_command(&mut daq_serial, &bi_command).await {
-					printer
-						.buf(|tv| {
-							write!(
-								tv,
-								"serial comm error when writing BI command:\n{serial_err}"
-							)
-						})
-						.await;
-					event_tx.send(Event::CommDc).await.unwrap();
+				let command = CommandKind::Control(ControlWord {
+					poll_ms: Some(poll_ms),
+					..bi_command
+				});
+				match serial_write_command(&mut daq_serial, &mut in_flight, command).await {
+					Ok(seq) => in_flight.control = Control::Pending(seq),
+					Err(serial_err) => {
+						printer
+							.error(|tv| {
+								write!(
+									tv,
+									"serial comm error when writing BI command:\n{serial_err}"
+								)
+							})
+							.await;
+						link_down = true;
+					}
 				}
 			}
-			Some(ComCmd::NewDeviceName(dev_name)) => {
-				daq_serial = match connect(dev_name.as_ref()).await {
-					Ok(ds) => ds,
+			Some(ComCmd::NewDeviceName(new_dev_name, baud)) => {
+				let new_device = Device::new(new_dev_name, baud, link.baud);
+				match transport
+					.open(new_device.name.as_ref(), new_device.baud)
+					.await
+				{
+					Ok(ds) => {
+						daq_serial = ds;
+						handshake = Handshake::Hello;
+						hellos = 0;
+						reported.link_baud = new_device.link_baud(&transport);
+						in_flight.link_lost();
+					}
 					Err(tse) => {
 						printer
 							.buf(|tv| {
 								write!(
 									tv,
 									"can't connect to device: {} serical comm error: {tse}",
-									&dev_name
+									new_device.name
 								)
 							})
 							.await;
-						event_tx.send(Event::CommDc).await.unwrap();
-						continue;
+						link_down = true;
 					}
 				};
+				// retry the most recently requested device if the link goes down
+				device = new_device;
 			}
 			Some(ComCmd::Shutdown) => {
-				let command = idle_command();
-				let _ = serial_write_command(&mut daq_serial, &command).await;
+				stop_idle(&mut daq_serial, &mut in_flight, &mut printer).await;
 				break;
 			}
+			Some(ComCmd::SetTrim(trim)) => {
+				let command = CommandKind::SetTrim(trim);
+				if let Err(serial_err) =
+					serial_write_command(&mut daq_serial, &mut in_flight, command).await
+				{
+					printer
+						.error(|tv| {
+							write!(tv, "serial comm error when setting the trim:\n{serial_err}")
+						})
+						.await;
+					link_down = true;
+				}
+			}
+			Some(ComCmd::SetBatteryDetect(detect)) => {
+				let command = CommandKind::SetBatteryDetect(detect);
+				if let Err(serial_err) =
+					serial_write_command(&mut daq_serial, &mut in_flight, command).await
+				{
+					printer
+						.error(|tv| {
+							write!(
+								tv,
+								"serial comm error when setting the battery detection:\n{serial_err}"
+							)
+						})
+						.await;
+					link_down = true;
+				}
+			}
+			Some(ComCmd::StartAutonomous(test)) => {
+				let command = CommandKind::StartAutonomous(test);
+				if let Err(serial_err) =
+					serial_write_command(&mut daq_serial, &mut in_flight, command).await
+				{
+					printer
+						.error(|tv| {
+							write!(
+								tv,
+								"serial comm error when starting the autonomous test:\n{serial_err}"
+							)
+						})
+						.await;
+					link_down = true;
+				}
+			}
+			Some(ComCmd::DumpAutonomousLog) => {
+				let command = CommandKind::DumpAutonomousLog;
+				if let Err(serial_err) =
+					serial_write_command(&mut daq_serial, &mut in_flight, command).await
+				{
+					printer
+						.error(|tv| {
+							write!(
+								tv,
+								"serial comm error when asking for the autonomous log:\n{serial_err}"
+							)
+						})
+						.await;
+					link_down = true;
+				}
+			}
+			Some(ComCmd::SelfTest) => {
+				let command = CommandKind::SelfTest;
+				if let Err(serial_err) =
+					serial_write_command(&mut daq_serial, &mut in_flight, command).await
+				{
+					printer
+						.error(|tv| {
+							write!(
+								tv,
+								"serial comm error when asking for a self test:\n{serial_err}"
+							)
+						})
+						.await;
+					link_down = true;
+				}
+			}
 			Some(ComCmd::ClearFault) => {
-				let command = clear_fault_command();
-				if let Err(serial_err) = serial_write_command(&mut daq_serial, &command).await {
+				// the battery interface starts over from the defaults once it clears
+				in_flight.control = Control::Unsent;
+				let command = CommandKind::Control(clear_fault_command());
+				if let Err(serial_err) =
+					serial_write_command(&mut daq_serial, &mut in_flight, command).await
+				{
 					printer
-						.buf(|tv| {
+						.error(|tv| {
 							write!(tv, "serial comm error when clearing fault:\n{serial_err}")
 						})
 						.await;
-					event_tx.send(Event::CommDc).await.unwrap();
+					link_down = true;
 				}
 			}
 			None => {}
 		}
+
+		if link_down {
+			in_flight.link_lost();
+			stats_tx.send_replace(in_flight.stats);
+			event_tx.send(Event::CommDc).await?;
+			daq_serial = match reconnect(
+				&transport,
+				&mut device,
+				link.baud,
+				&mut com_cmd_rx,
+				&mut bi_command,
+				&mut printer,
+			)
+			.await
+			{
+				Some(ds) => ds,
+				None => break,
+			};
+			in_flight.stats.reconnects += 1;
+			// anything left over belongs to the old connection
+			incoming_buf.clear();
+			frame_buf.clear();
+			handshake = Handshake::Hello;
+			hellos = 0;
+			reported.link_baud = device.link_baud(&transport);
+			if let Err(e) = event_tx.send(Event::ComReconnected).await {
+				stop_idle(&mut daq_serial, &mut in_flight, &mut printer).await;
+				return Err(e.into());
+			}
+		}
+		stats_tx.send_replace(in_flight.stats);
 	}
 	println!("exiting serial_com_task");
+	Ok(())
+}
+
+/// Last command to the battery interface, sent again until a reply says the load is off.
+/// Without one the load stays as it was until the battery interface's comm timeout.
+async fn stop_idle(
+	daq_serial: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	in_flight: &mut InFlight,
+	printer: &mut Printer,
+) {
+	use std::io::Write;
+	let mut incoming_buf: Vec<u8> = Vec::with_capacity(INCOMING_MAX_SIZE * 2);
+	let mut frame_buf = FrameBuffer::<INCOMING_MAX_SIZE>::new();
+	let mut sent = Vec::with_capacity(STOP_ATTEMPTS as usize);
+	let mut load = None;
+	for _attempt in 0..STOP_ATTEMPTS {
+		let command = CommandKind::Control(idle_command());
+		match serial_write_command(daq_serial, in_flight, command).await {
+			Ok(seq) => sent.push(seq),
+			Err(_e) => break,
+		}
+		let answered = idle_reply(daq_serial, &sent, &mut incoming_buf, &mut frame_buf);
+		match tokio::time::timeout(STOP_CONFIRM_WAIT, answered).await {
+			Ok(Ok(LoadState::Off)) => {
+				printer
+					.stat("the battery interface confirmed the load is off")
+					.await;
+				return;
+			}
+			// button B turned it on before the idle command arrived, the next one turns it off
+			Ok(Ok(LoadState::On)) => load = Some(LoadState::On),
+			Ok(Err(_e)) => break,
+			Err(_elapsed) => {}
+		}
+	}
+	let attempts = sent.len();
+	match load {
+		Some(LoadState::On) => {
+			printer
+				.error_stat("the load is still on, the battery interface ignored the idle command, is an autonomous test running?")
+				.await
+		}
+		_ => {
+			printer
+				.error(|tv| write!(tv, "the load may still be on, the battery interface didn't answer {attempts} idle commands, it's turned off once its comm timeout runs out"))
+				.await
+		}
+	}
 }
 
-async fn connect(dev_name: &str) -> Result<SerialStream, tokio_serial::Error> {
-	let mut daq_serial = tokio_serial::new(dev_name, DEFALT_BAUD)
-		.data_bits(tokio_serial::DataBits::Eight)
-		.stop_bits(tokio_serial::StopBits::One)
-		.open_native_async()?;
+/// The load as the battery interface answered one of the commands `sent`
+async fn idle_reply(
+	serial_read: &mut (impl AsyncRead + Unpin),
+	sent: &[u32],
+	incoming_buf: &mut Vec<u8>,
+	frame_buf: &mut FrameBuffer<INCOMING_MAX_SIZE>,
+) -> std::io::Result<LoadState> {
+	loop {
+		if serial_read_response(serial_read, incoming_buf).await? == 0 {
+			return Err(std::io::ErrorKind::UnexpectedEof.into());
+		}
+		for byte in incoming_buf.drain(..) {
+			if let Some(Ok(reply)) = frame_buf.push::<BIReply>(byte)
+				&& sent.contains(&reply.seq)
+			{
+				return Ok(reply.load);
+			}
+		}
+	}
+}
 
-	daq_serial.set_exclusive(false)?;
-	daq_serial.clear(tokio_serial::ClearBuffer::All)?;
-	Ok(daq_serial)
+/// Keep trying to re-open `device` with exponential backoff.
+/// Commands that arrive in the meantime are still handled so the
+/// device can be changed or the server shut down.
+/// Returns `None` if the task should exit.
+async fn reconnect<T: Transport>(
+	transport: &T,
+	device: &mut Device,
+	default_baud: u32,
+	com_cmd_rx: &mut Receiver<ComCmd>,
+	bi_command: &mut ControlWord,
+	printer: &mut Printer,
+) -> Option<T::Link> {
+	use std::io::Write;
+	use tokio::time::{Duration, sleep};
+	let mut backoff_ms = RECONNECT_MIN_MS;
+	loop {
+		select! {
+			_ = sleep(Duration::from_millis(backoff_ms)) => {
+				match transport.open(device.name.as_ref(), device.baud).await {
+					Ok(ds) => {
+						let dev_name = &device.name;
+						printer.buf(|tv| write!(tv, "reconnected to: {dev_name}")).await;
+						return Some(ds);
+					}
+					Err(_e) => {
+						backoff_ms = (backoff_ms * 2).min(RECONNECT_MAX_MS);
+					}
+				}
+			}
+			cmd = com_cmd_rx.recv() => match cmd {
+				Some(ComCmd::NewDeviceName(new_dev_name, baud)) => {
+					*device = Device::new(new_dev_name, baud, default_baud);
+					backoff_ms = RECONNECT_MIN_MS;
+				}
+				Some(ComCmd::BICommand(new_bi_command)) => *bi_command = new_bi_command,
+				Some(ComCmd::SetTrim(_trim)) => {
+					printer.warn_stat("can't set the trim, the battery interface isn't connected").await;
+				}
+				Some(ComCmd::SetBatteryDetect(_detect)) => {
+					printer.warn_stat("can't set the battery detection, the battery interface isn't connected").await;
+				}
+				Some(ComCmd::StartAutonomous(_) | ComCmd::DumpAutonomousLog) => {
+					printer.warn_stat("can't reach the autonomous test, the battery interface isn't connected").await;
+				}
+				Some(ComCmd::SelfTest) => {
+					printer.warn_stat("can't run the self test, the battery interface isn't connected").await;
+				}
+				Some(ComCmd::ClearFault) => {}
+				Some(ComCmd::Shutdown) | None => return None,
+			}
+		}
+	}
 }
 
 async fn serial_write_command(
-	serial_write: &mut SerialStream,
-	ctrl_word: &BiCommand,
-) -> Result<(), tokio_serial::Error> {
-	debug_assert!(OUTGOING_MAX_SIZE < u8::MAX as usize);
+	serial_write: &mut (impl AsyncWrite + Unpin),
+	in_flight: &mut InFlight,
+	kind: CommandKind,
+) -> std::io::Result<u32> {
 	let mut outgoing_buf: [u8; OUTGOING_MAX_SIZE] = [0u8; OUTGOING_MAX_SIZE];
-	let outgoing = postcard::to_slice(ctrl_word, &mut outgoing_buf[..]).unwrap();
-	serial_write_general(&outgoing, serial_write).await
+	let seq = in_flight.next_seq();
+	let command = BiCommand { seq, kind };
+	// the buffer always fits the largest possible command frame
+	let outgoing = frame::encode(&command, &mut outgoing_buf[..]).unwrap();
+	serial_write_general(outgoing, serial_write).await?;
+	in_flight.sent(seq, Instant::now());
+	Ok(seq)
 }
 
 async fn serial_write_general(
 	outgoing: &[u8],
-	serial_write: &mut SerialStream,
-) -> Result<(), tokio_serial::Error> {
+	serial_write: &mut (impl AsyncWrite + Unpin),
+) -> std::io::Result<()> {
 	use tokio::io::AsyncWriteExt;
-	let total = outgoing.len() as u8;
-	serial_write.write_u8(total).await?;
-	let total = total as usize;
-	let mut remaining = total;
-	while remaining > 0 {
-		remaining -= serial_write
-			.write(&outgoing[total - remaining..total])
-			.await?;
-	}
+	serial_write.write_all(outgoing).await?;
 	Ok(())
 }
 
+/// Returns the number of bytes read, 0 means the device went away
 async fn serial_read_response(
-	serial_read: &mut SerialStream,
+	serial_read: &mut (impl AsyncRead + Unpin),
+	incoming_buf: &mut Vec<u8>,
+) -> std::io::Result<usize> {
+	let num_read = serial_read.read_buf(incoming_buf).await?;
+	Ok(num_read)
+}
+
+/// Run everything read so far through the frame buffer.
+/// Good replies go to the program task, bad frames are dropped and counted,
+/// the frame buffer picks up again at the next delimiter.
+async fn serial_decode(
 	incoming_buf: &mut Vec<u8>,
-) -> Result<(), tokio_serial::Error> {
-	let _num_read = serial_read.read_buf(incoming_buf).await?;
+	frame_buf: &mut FrameBuffer<INCOMING_MAX_SIZE>,
+	in_flight: &mut InFlight,
+	handshake: &mut Handshake,
+	reported: &mut Reported,
+	event_tx: &mut Sender<Event>,
+	printer: &mut Printer,
+) -> Result<(), TaskError> {
+	use std::io::Write;
+	for byte in incoming_buf.drain(..) {
+		let reply = match frame_buf.push::<BIReply>(byte) {
+			Some(Ok(reply)) => reply,
+			Some(Err(_e)) => {
+				in_flight.stats.bad_frames += 1;
+				event_tx
+					.send(Event::ComDecodeError(in_flight.stats.bad_frames))
+					.await?;
+				continue;
+			}
+			None => continue,
+		};
+		in_flight.stats.received += 1;
+		let seq = reply.seq;
+		match in_flight.reply(seq, Instant::now()) {
+			ReplyMatch::Acked { lost: 0 } | ReplyMatch::Unsolicited => {}
+			ReplyMatch::Acked { lost } => {
+				printer
+					.warn(|tv| {
+						write!(
+							tv,
+							"{lost} BI replies lost, still waiting on them at seq: {seq}"
+						)
+					})
+					.await
+			}
+			ReplyMatch::Duplicate => {
+				printer
+					.buf(|tv| write!(tv, "duplicate BI reply dropped, seq: {seq}"))
+					.await;
+				continue;
+			}
+		}
+		let present = Some(reply.bat_present);
+		if reported.bat_present_tx.send_replace(present) != present {
+			event_tx
+				.send(Event::BatteryPresence(reply.bat_present))
+				.await?;
+		}
+		reported.load_tx.send_replace(Some(reply.load));
+		match reply.kind {
+			ReplyKind::Status(status) => {
+				match status.reset_reason {
+					Some(reason) if reason.unexpected() => {
+						printer
+							.error(|tv| write!(tv, "battery interface restarted: {reason:?}"))
+							.await
+					}
+					Some(reason) => {
+						printer
+							.buf(|tv| write!(tv, "battery interface started: {reason:?}"))
+							.await
+					}
+					None => {}
+				}
+				if status.wants_control {
+					in_flight.control = Control::Unsent;
+				}
+				if let Some(m) = status.measurement {
+					reported.measurement_tx.send_replace(Some(m));
+					if let Some(summary) = reported.live.push(&m) {
+						printer.buf(|tv| write!(tv, "{summary}")).await;
+						reported.live_tx.send_replace(Some(summary));
+					}
+				}
+				event_tx.send(Event::ComReply(status)).await?
+			}
+			ReplyKind::Version {
+				protocol,
+				firmware,
+				device_id,
+				self_test,
+				baud,
+			} => {
+				*handshake = Handshake::GetTrim;
+				if let Some(link_baud) = reported.link_baud
+					&& link_baud != baud
+				{
+					printer
+						.error(|tv| {
+							write!(
+								tv,
+								"the battery interface's UART runs at {baud} baud, its port is open at {link_baud}"
+							)
+						})
+						.await
+				}
+				event_tx
+					.send(Event::DeviceVersion(DeviceVersion {
+						protocol,
+						firmware,
+						device_id,
+					}))
+					.await?;
+				reported.self_test_tx.send_replace(self_test);
+				if let Some(report) = self_test {
+					event_tx.send(Event::SelfTestReport(report)).await?
+				}
+			}
+			ReplyKind::Trim(Ok(trim)) => {
+				if *handshake == Handshake::GetTrim {
+					*handshake = Handshake::GetBatteryDetect;
+				}
+				printer
+					.buf(|tv| write!(tv, "battery interface trim: {trim:?}"))
+					.await;
+				reported.trim_tx.send_replace(Some(trim));
+			}
+			ReplyKind::Trim(Err(e)) => {
+				printer
+					.error(|tv| {
+						write!(
+							tv,
+							"battery interface couldn't save the trim: {e:?}, it's used until it restarts"
+						)
+					})
+					.await;
+				// ask which trim it's using
+				*handshake = Handshake::GetTrim;
+			}
+			ReplyKind::BatteryDetect(Ok(detect)) => {
+				if *handshake == Handshake::GetBatteryDetect {
+					*handshake = Handshake::DumpFaultLog(Vec::new());
+				}
+				printer
+					.buf(|tv| write!(tv, "battery interface battery detection: {detect:?}"))
+					.await;
+				reported.battery_detect_tx.send_replace(Some(detect));
+			}
+			ReplyKind::BatteryDetect(Err(e)) => {
+				printer
+					.error(|tv| {
+						write!(
+							tv,
+							"battery interface couldn't save the battery detection: {e:?}, it's used until it restarts"
+						)
+					})
+					.await;
+				// ask which one it's using
+				*handshake = Handshake::GetBatteryDetect;
+			}
+			ReplyKind::FaultLog(Some(logged)) => {
+				if let Handshake::DumpFaultLog(faults) = handshake {
+					faults.push(logged);
+				}
+			}
+			ReplyKind::FaultLog(None) => {
+				if let Handshake::DumpFaultLog(faults) =
+					std::mem::replace(handshake, Handshake::Done)
+				{
+					if !faults.is_empty() {
+						printer
+							.warn(|tv| {
+								write!(
+									tv,
+									"battery interface logged {} faults, see status",
+									faults.len()
+								)
+							})
+							.await;
+					}
+					reported.fault_log_tx.send_replace(faults);
+				}
+			}
+			ReplyKind::Replay(measurement) => event_tx.send(Event::Replayed(measurement)).await?,
+			ReplyKind::Autonomous(Ok(())) => {
+				printer
+					.stat("battery interface is running the test on its own")
+					.await
+			}
+			ReplyKind::Autonomous(Err(e)) => {
+				printer
+					.error(|tv| {
+						write!(
+							tv,
+							"battery interface couldn't erase its autonomous log: {e:?}, the test wasn't started"
+						)
+					})
+					.await
+			}
+			ReplyKind::AutonomousLog(sample) => event_tx.send(Event::AutonomousLog(sample)).await?,
+			ReplyKind::SelfTest(report) => {
+				reported.self_test_tx.send_replace(Some(report));
+				event_tx.send(Event::SelfTestReport(report)).await?
+			}
+		}
+	}
 	Ok(())
 }
 
-async fn serial_decode(incoming_buf: &mut Vec<u8>, event_tx: &mut Sender<Event>) {
-	let mut idx = 0;
-	loop {
-		// first byte is message len
-		let msg_len = match incoming_buf.get(idx) {
-			// buffer has a length byte at the front
-			Some(l) => *l as usize,
-			// buffer is empty
-			None => break,
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::Print;
+	use battery_tester_common::{
+		DEFAULT_BAUD, FirmwareVersion, PROTOCOL_VERSION, frame::COMMAND_FRAME_MAX_SIZE,
+	};
+	use std::sync::{Arc, Mutex};
+	use tokio::{
+		io::{AsyncWriteExt, DuplexStream, duplex},
+		sync::mpsc,
+		task::JoinHandle,
+		time::{Duration, timeout},
+	};
+
+	const DEVICE: &str = "/dev/ttyACM0";
+
+	/// In-memory links, the test is handed the battery interface's end of each one opened.
+	/// The first `failures` opens fail like an unplugged device.
+	#[derive(Clone)]
+	struct FakeTransport {
+		failures: Arc<Mutex<u32>>,
+		attempts: Arc<Mutex<Vec<Attempt>>>,
+		link_tx: mpsc::UnboundedSender<DuplexStream>,
+	}
+
+	/// A device the serial task tried to open
+	struct Attempt {
+		device: Box<str>,
+		at: Instant,
+		opened: bool,
+	}
+
+	impl FakeTransport {
+		fn new(failures: u32) -> (Self, mpsc::UnboundedReceiver<DuplexStream>) {
+			let (link_tx, link_rx) = mpsc::unbounded_channel();
+			let transport = Self {
+				failures: Arc::new(Mutex::new(failures)),
+				attempts: Arc::default(),
+				link_tx,
+			};
+			(transport, link_rx)
+		}
+
+		fn fail_next(&self, failures: u32) {
+			*self.failures.lock().unwrap() = failures;
+		}
+
+		/// ms between attempts to open, from `since`
+		fn waits_ms(&self, since: Instant) -> Vec<u128> {
+			let attempts = self.attempts.lock().unwrap();
+			let mut last = since;
+			attempts
+				.iter()
+				.map(|attempt| {
+					let wait = (attempt.at - last).as_millis();
+					last = attempt.at;
+					wait
+				})
+				.collect()
+		}
+	}
+
+	impl Transport for FakeTransport {
+		type Link = DuplexStream;
+
+		async fn open(&self, device: &str, _baud: u32) -> std::io::Result<DuplexStream> {
+			let failing = {
+				let mut failures = self.failures.lock().unwrap();
+				let failing = *failures > 0;
+				*failures = failures.saturating_sub(1);
+				failing
+			};
+			self.attempts.lock().unwrap().push(Attempt {
+				device: device.into(),
+				at: Instant::now(),
+				opened: !failing,
+			});
+			if failing {
+				return Err(std::io::ErrorKind::NotFound.into());
+			}
+			let (link, battery_interface) = duplex(4096);
+			let _ = self.link_tx.send(battery_interface);
+			Ok(link)
+		}
+	}
+
+	fn printer() -> Printer {
+		let (print_tx, mut print_rx) = mpsc::channel::<Print>(64);
+		tokio::spawn(async move { while print_rx.recv().await.is_some() {} });
+		Printer::new(print_tx)
+	}
+
+	/// The serial task on a [`FakeTransport`], the test plays the battery interface
+	struct Bench {
+		com_cmd_tx: Sender<ComCmd>,
+		event_rx: Receiver<Event>,
+		stats_rx: watch::Receiver<LinkStats>,
+		link_rx: mpsc::UnboundedReceiver<DuplexStream>,
+		transport: FakeTransport,
+		task: JoinHandle<Result<(), TaskError>>,
+	}
+
+	impl Bench {
+		/// Set to [`DEVICE`], opened unless it `failures` first
+		async fn start(failures: u32) -> Self {
+			let (transport, link_rx) = FakeTransport::new(failures);
+			let (event_tx, event_rx) = mpsc::channel(64);
+			let (com_cmd_tx, com_cmd_rx) = mpsc::channel(64);
+			let (stats_tx, stats_rx) = watch::channel(LinkStats::default());
+			let reported = Reported {
+				measurement_tx: watch::channel(None).0,
+				live_tx: watch::channel(None).0,
+				live: LiveView::new(Duration::from_secs(60)),
+				trim_tx: watch::channel(None).0,
+				battery_detect_tx: watch::channel(None).0,
+				bat_present_tx: watch::channel(None).0,
+				load_tx: watch::channel(None).0,
+				fault_log_tx: watch::channel(Vec::new()).0,
+				self_test_tx: watch::channel(None).0,
+				link_baud: None,
+			};
+			let link = LinkSettings {
+				poll_ms: 500,
+				baud: DEFAULT_BAUD,
+			};
+			let task = tokio::spawn(serial_com_task(
+				transport.clone(),
+				event_tx,
+				com_cmd_rx,
+				stats_tx,
+				reported,
+				link,
+				printer(),
+			));
+			com_cmd_tx
+				.send(ComCmd::NewDeviceName(DEVICE.into(), None))
+				.await
+				.unwrap();
+			Self {
+				com_cmd_tx,
+				event_rx,
+				stats_rx,
+				link_rx,
+				transport,
+				task,
+			}
+		}
+
+		/// The battery interface's end of the next link the serial task opens
+		async fn link(&mut self) -> DuplexStream {
+			timeout(Duration::from_secs(60), self.link_rx.recv())
+				.await
+				.expect("the serial task didn't open a link")
+				.unwrap()
+		}
+
+		async fn event(&mut self) -> Event {
+			timeout(Duration::from_secs(60), self.event_rx.recv())
+				.await
+				.expect("the serial task didn't send an event")
+				.unwrap()
+		}
+	}
+
+	impl Drop for Bench {
+		fn drop(&mut self) {
+			self.task.abort();
+		}
+	}
+
+	/// The next command sent over `link`
+	async fn command(
+		link: &mut DuplexStream,
+		frame_buf: &mut FrameBuffer<COMMAND_FRAME_MAX_SIZE>,
+	) -> BiCommand {
+		loop {
+			let byte = timeout(Duration::from_secs(60), link.read_u8())
+				.await
+				.expect("no command")
+				.unwrap();
+			if let Some(command) = frame_buf.push::<BiCommand>(byte) {
+				return command.unwrap();
+			}
+		}
+	}
+
+	async fn answer(link: &mut DuplexStream, seq: u32, kind: ReplyKind) {
+		let reply = BIReply {
+			seq,
+			bat_present: false,
+			load: LoadState::Off,
+			kind,
 		};
-		// message starts at first byte after length
-		let msg_start = idx + 1;
-		// calculate where the message would end if it were complete
-		let msg_end = msg_len + msg_start;
-		let raw_msg = match incoming_buf.get(msg_start..msg_end) {
-			// message is complete
-			Some(rm) => rm,
-			// message is not complete
-			None => break,
+		let mut buf = [0u8; INCOMING_MAX_SIZE];
+		let frame = frame::encode(&reply, &mut buf[..]).unwrap();
+		link.write_all(frame).await.unwrap();
+	}
+
+	const FIRMWARE: FirmwareVersion = FirmwareVersion {
+		major: 1,
+		minor: 2,
+		patch: 3,
+	};
+
+	fn version(protocol: u16) -> ReplyKind {
+		ReplyKind::Version {
+			protocol,
+			firmware: FIRMWARE,
+			device_id: 0x0123_4567_89ab_cdef,
+			self_test: None,
+			baud: DEFAULT_BAUD,
+		}
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_hello_until_answered() {
+		let mut bench = Bench::start(0).await;
+		let mut link = bench.link().await;
+		let mut frame_buf = FrameBuffer::new();
+		// not a control word until the battery interface says which protocol it speaks
+		let first = command(&mut link, &mut frame_buf).await;
+		let second = command(&mut link, &mut frame_buf).await;
+		assert_eq!(first.kind, CommandKind::Hello);
+		assert_eq!(second.kind, CommandKind::Hello);
+
+		answer(&mut link, second.seq, version(PROTOCOL_VERSION)).await;
+		assert_eq!(bench.event().await, Event::BatteryPresence(false));
+		assert_eq!(
+			bench.event().await,
+			Event::DeviceVersion(DeviceVersion {
+				protocol: PROTOCOL_VERSION,
+				firmware: FIRMWARE,
+				device_id: 0x0123_4567_89ab_cdef,
+			})
+		);
+		// then the settings it's using
+		let next = command(&mut link, &mut frame_buf).await;
+		assert_eq!(next.kind, CommandKind::GetTrim);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_bad_frame_counted_and_skipped() {
+		let mut bench = Bench::start(0).await;
+		let mut link = bench.link().await;
+		let mut frame_buf = FrameBuffer::new();
+		let hello = command(&mut link, &mut frame_buf).await;
+
+		link.write_all(&[0x55; 8]).await.unwrap();
+		link.write_u8(frame::FRAME_DELIMITER).await.unwrap();
+		assert_eq!(bench.event().await, Event::ComDecodeError(1));
+		// the next frame still gets through
+		answer(&mut link, hello.seq, version(PROTOCOL_VERSION)).await;
+		assert_eq!(bench.event().await, Event::BatteryPresence(false));
+		assert!(matches!(bench.event().await, Event::DeviceVersion(_)));
+
+		command(&mut link, &mut frame_buf).await;
+		let stats = *bench.stats_rx.borrow();
+		assert_eq!(stats.bad_frames, 1);
+		assert_eq!(stats.received, 1);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_replies_out_of_order() {
+		let mut bench = Bench::start(0).await;
+		let mut link = bench.link().await;
+		let mut frame_buf = FrameBuffer::new();
+		let first = command(&mut link, &mut frame_buf).await;
+		let second = command(&mut link, &mut frame_buf).await;
+		// the newer one is answered first, the older is still waited on
+		answer(&mut link, second.seq, version(PROTOCOL_VERSION)).await;
+		answer(&mut link, first.seq, version(PROTOCOL_VERSION)).await;
+		assert_eq!(bench.event().await, Event::BatteryPresence(false));
+		assert!(matches!(bench.event().await, Event::DeviceVersion(_)));
+		assert!(matches!(bench.event().await, Event::DeviceVersion(_)));
+
+		let stats = *bench.stats_rx.borrow();
+		assert_eq!(
+			(stats.received, stats.acked, stats.lost, stats.duplicate),
+			(2, 2, 0, 0)
+		);
+	}
+
+	#[test]
+	fn test_seq_skips_unsolicited() {
+		let mut in_flight = InFlight {
+			next_seq: UNSOLICITED_SEQ - 1,
+			..InFlight::default()
+		};
+		assert_eq!(in_flight.next_seq(), UNSOLICITED_SEQ - 1);
+		// it wraps, replies with UNSOLICITED_SEQ weren't asked for
+		assert_eq!(in_flight.next_seq(), 0);
+		assert_eq!(in_flight.next_seq(), 1);
+	}
+
+	#[test]
+	fn test_replies_matched_by_seq() {
+		let start = Instant::now();
+		let mut in_flight = InFlight::default();
+		for seq in 0..4 {
+			in_flight.sent(seq, start + Duration::from_millis(u64::from(seq) * 10));
+		}
+		let at = start + Duration::from_millis(25);
+		// a heartbeat waits on the power task, the hello after it doesn't
+		for seq in [2, 0] {
+			assert!(matches!(
+				in_flight.reply(seq, at),
+				ReplyMatch::Acked { lost: 0 }
+			));
+		}
+		assert!(matches!(in_flight.reply(2, at), ReplyMatch::Duplicate));
+		assert!(matches!(in_flight.reply(9, at), ReplyMatch::Duplicate));
+		assert!(matches!(
+			in_flight.reply(UNSOLICITED_SEQ, at),
+			ReplyMatch::Unsolicited
+		));
+		assert_eq!(
+			in_flight.stats,
+			LinkStats {
+				sent: 4,
+				acked: 2,
+				duplicate: 2,
+				last_rtt: Some(Duration::from_millis(25)),
+				total_rtt: Duration::from_millis(30),
+				..LinkStats::default()
+			}
+		);
+
+		// 1 is answered, 3 has waited too long by then
+		let late = start + REPLY_TIMEOUT + Duration::from_millis(35);
+		assert!(matches!(
+			in_flight.reply(1, late),
+			ReplyMatch::Acked { lost: 1 }
+		));
+		assert!(matches!(in_flight.reply(3, late), ReplyMatch::Duplicate));
+		assert_eq!((in_flight.stats.acked, in_flight.stats.lost), (3, 1));
+
+		// 4 was sent before the link went down
+		in_flight.sent(4, late);
+		in_flight.link_lost();
+		assert_eq!(in_flight.stats.lost, 2);
+		assert!(matches!(in_flight.reply(4, late), ReplyMatch::Duplicate));
+	}
+
+	#[test]
+	fn test_oldest_unanswered_counted_lost() {
+		let start = Instant::now();
+		let mut in_flight = InFlight::default();
+		for seq in 0..MAX_IN_FLIGHT as u32 + 2 {
+			in_flight.sent(seq, start);
+		}
+		assert_eq!(in_flight.stats.lost, 2);
+		// no longer waited on
+		assert!(matches!(in_flight.reply(1, start), ReplyMatch::Duplicate));
+		assert!(matches!(
+			in_flight.reply(2, start),
+			ReplyMatch::Acked { lost: 0 }
+		));
+	}
+
+	#[test]
+	fn test_control_held_once_acked() {
+		let start = Instant::now();
+		let mut in_flight = InFlight::default();
+		in_flight.sent(0, start);
+		in_flight.control = Control::Pending(0);
+		in_flight.sent(1, start);
+		in_flight.reply(1, start);
+		// not held until it's answered, the next tick sends it again
+		assert_eq!(in_flight.control, Control::Pending(0));
+
+		in_flight.control = Control::Pending(2);
+		in_flight.sent(2, start);
+		in_flight.reply(2, start);
+		assert_eq!(in_flight.control, Control::Held);
+		in_flight.link_lost();
+		assert_eq!(in_flight.control, Control::Unsent);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_reconnect_backs_off() {
+		let (transport, _link_rx) = FakeTransport::new(8);
+		let (_com_cmd_tx, mut com_cmd_rx) = mpsc::channel(8);
+		let mut device = Device::new(DEVICE.into(), None, DEFAULT_BAUD);
+		let since = Instant::now();
+		let link = reconnect(
+			&transport,
+			&mut device,
+			DEFAULT_BAUD,
+			&mut com_cmd_rx,
+			&mut idle_command(),
+			&mut printer(),
+		)
+		.await;
+		assert!(link.is_some());
+		// doubled after each failure, up to the slowest rate
+		assert_eq!(
+			transport.waits_ms(since),
+			[250, 500, 1_000, 2_000, 4_000, 8_000, 8_000, 8_000, 8_000]
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_reconnect_to_a_new_device() {
+		let (transport, _link_rx) = FakeTransport::new(u32::MAX);
+		let (com_cmd_tx, mut com_cmd_rx) = mpsc::channel(8);
+		let mut device = Device::new(DEVICE.into(), None, DEFAULT_BAUD);
+		let since = Instant::now();
+		let reconnecting = tokio::spawn({
+			let transport = transport.clone();
+			async move {
+				let link = reconnect(
+					&transport,
+					&mut device,
+					DEFAULT_BAUD,
+					&mut com_cmd_rx,
+					&mut idle_command(),
+					&mut printer(),
+				)
+				.await;
+				(link.is_some(), device)
+			}
+		});
+		tokio::time::sleep(Duration::from_millis(3_800)).await;
+		transport.fail_next(0);
+		com_cmd_tx
+			.send(ComCmd::NewDeviceName("/dev/ttyUSB1".into(), Some(115_200)))
+			.await
+			.unwrap();
+		let (reconnected, device) = reconnecting.await.unwrap();
+		assert!(reconnected);
+		assert_eq!(
+			device,
+			Device::new("/dev/ttyUSB1".into(), Some(115_200), DEFAULT_BAUD)
+		);
+		// tried right away, not after the 4 s the old device was up to
+		assert_eq!(transport.waits_ms(since), [250, 500, 1_000, 2_000, 300]);
+		let attempts = transport.attempts.lock().unwrap();
+		assert_eq!(attempts.last().unwrap().device.as_ref(), "/dev/ttyUSB1");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_reconnect_stops_on_shutdown() {
+		let (transport, _link_rx) = FakeTransport::new(u32::MAX);
+		let (com_cmd_tx, mut com_cmd_rx) = mpsc::channel(8);
+		com_cmd_tx.send(ComCmd::Shutdown).await.unwrap();
+		let mut device = Device::new(DEVICE.into(), None, DEFAULT_BAUD);
+		let link = reconnect(
+			&transport,
+			&mut device,
+			DEFAULT_BAUD,
+			&mut com_cmd_rx,
+			&mut idle_command(),
+			&mut printer(),
+		)
+		.await;
+		assert!(link.is_none());
+	}
+
+	/// What [`stop_idle`] printed, and how many idle commands it sent.
+	/// `load` answers each idle command, `None` ignores it.
+	async fn stop_idle_with(load: impl Fn(u32) -> Option<LoadState>) -> (Vec<Print>, u32) {
+		let (mut daq_serial, mut link) = duplex(4096);
+		let (print_tx, mut print_rx) = mpsc::channel::<Print>(8);
+		let stopped = tokio::spawn(async move {
+			let mut printer = Printer::new(print_tx);
+			stop_idle(&mut daq_serial, &mut InFlight::default(), &mut printer).await;
+		});
+		let mut frame_buf = FrameBuffer::<COMMAND_FRAME_MAX_SIZE>::new();
+		let mut commands = 0;
+		while let Ok(Ok(byte)) = timeout(Duration::from_secs(60), link.read_u8()).await {
+			let Some(command) = frame_buf.push::<BiCommand>(byte) else {
+				continue;
+			};
+			let command = command.unwrap();
+			assert_eq!(command.kind, CommandKind::Control(idle_command()));
+			commands += 1;
+			if let Some(load) = load(commands) {
+				let reply = BIReply {
+					seq: command.seq,
+					bat_present: true,
+					load,
+					kind: version(PROTOCOL_VERSION),
+				};
+				let mut buf = [0u8; INCOMING_MAX_SIZE];
+				link.write_all(frame::encode(&reply, &mut buf[..]).unwrap())
+					.await
+					.unwrap();
+			}
+		}
+		stopped.await.unwrap();
+		let mut printed = Vec::new();
+		while let Ok(print) = print_rx.try_recv() {
+			printed.push(print);
+		}
+		(printed, commands)
+	}
+
+	fn text(print: &Print) -> (bool, String) {
+		let (error, line) = match print {
+			Print::Error(line) => (true, line),
+			Print::Info(line) | Print::Warn(line) => (false, line),
+			Print::Shutdown => panic!("not a line"),
 		};
-		let reply: BIReply = postcard::from_bytes(raw_msg).unwrap();
-		event_tx.send(Event::ComReply(reply)).await.unwrap();
-		idx = msg_end
-	}
-
-	// if there's an incomplete message in the buffer
-	let new_len = incoming_buf.len() - idx;
-	if new_len != 0 {
-		// move the incomplete message to the front of the buffer
-		// the first byte that gives message length must be at the front,
-		// next time this function is called with the same buffer
-		incoming_buf.copy_within(idx.., 0);
-	}
-
-	// len = 7
-	// [2, a, b, 3, a, b, c]
-	// [0, 1, 2, 3, 4, 5, 6]~7
-	// start = 4, end = 3 (len) + 4 (start) = 7
-	// new_len = 7 - 7 = 0
-	// len = 8
-	// [2, a, b, 3, a, b, c, 2]
-	// [0, 1, 2, 3, 4, 5, 6, 7]~8
-	// start = 4, end = 3 + 4 = 7, buf[7] = 2
-	// new_len = 8 - 7 = 1
-
-	// shrink the length (NOT CAPACITY) of the buffer to fit the incomplete message
-	incoming_buf.truncate(new_len);
+		(
+			error,
+			String::from_utf8_lossy(line.text.as_bytes()).into_owned(),
+		)
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_stop_idle_confirmed() {
+		// the first is lost
+		let (printed, commands) = stop_idle_with(|n| (n > 1).then_some(LoadState::Off)).await;
+		assert_eq!(commands, 2);
+		assert_eq!(
+			printed.iter().map(text).collect::<Vec<_>>(),
+			[(
+				false,
+				"the battery interface confirmed the load is off".to_string()
+			)]
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_stop_idle_unconfirmed() {
+		let (printed, commands) = stop_idle_with(|_| None).await;
+		assert_eq!(commands, STOP_ATTEMPTS);
+		let (error, line) = text(&printed[0]);
+		assert!(
+			error && line.contains(&format!("didn't answer {STOP_ATTEMPTS} idle commands")),
+			"{line}"
+		);
+
+		let (printed, commands) = stop_idle_with(|_| Some(LoadState::On)).await;
+		assert_eq!(commands, STOP_ATTEMPTS);
+		let (error, line) = text(&printed[0]);
+		assert!(error && line.starts_with("the load is still on"), "{line}");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_link_reopened_after_comm_dc() {
+		let mut bench = Bench::start(0).await;
+		let link = bench.link().await;
+		bench.transport.fail_next(2);
+		// unplugged
+		drop(link);
+		assert_eq!(bench.event().await, Event::CommDc);
+		let mut link = bench.link().await;
+		assert_eq!(bench.event().await, Event::ComReconnected);
+		assert_eq!(bench.stats_rx.borrow().reconnects, 1);
+		// the handshake starts over on the new link
+		let mut frame_buf = FrameBuffer::new();
+		let hello = command(&mut link, &mut frame_buf).await;
+		assert_eq!(hello.kind, CommandKind::Hello);
+		let opened: Vec<bool> = bench
+			.transport
+			.attempts
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|attempt| attempt.opened)
+			.collect();
+		assert_eq!(opened, [true, false, false, true]);
+
+		bench.com_cmd_tx.send(ComCmd::Shutdown).await.unwrap();
+		// the battery interface doesn't answer the idle commands, it's stopped anyway
+		(&mut bench.task).await.unwrap().unwrap();
+	}
 }
@@ -1,609 +1,328 @@
-use std::io::Write;
-use std::path::PathBuf;
-
-use battery_tester_common::{FaultKind, MilliVolt};
+use battery_tester_common::baud_supported;
 use pc_common::{
-	BatteryID, Cli, ComCmd, Error, Event, FileCmd, Mode, Print, Printer, SaveData, TestState,
-	end_test_command, files::file_task, idle_command, ipc::ipc_task, print_task,
-	serial::serial_com_task, testing_command, volts_command,
+	Cli, DemoCli, Error, Event, Printer, ServerSubcommands, Task,
+	chamber::ScpiChamber,
+	columns::ColumnConfig,
+	engine::{Engine, EngineBuilder, replay},
+	files::Rotation,
+	ipc::read_token,
+	notify::NotifyConfig,
+	profile::TestProfile,
+	sim::{SimConfig, SimTransport, demo_task},
 };
-use tokio::{
-	fs::{File, OpenOptions},
-	sync::{
-		mpsc::{self, Receiver, Sender},
-		oneshot,
-	},
+#[cfg(feature = "kiosk")]
+use pc_common::{
+	Feature,
+	kiosk::{KioskConfig, kiosk_task},
 };
+use std::path::PathBuf;
+#[cfg(unix)]
+use tokio::signal::unix::SignalKind;
+use tokio::{select, sync::mpsc::Sender, task::JoinHandle};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
 	let cli: Cli = argh::from_env();
-	let output_dir = if cli.output_directory.is_dir() {
-		cli.output_directory
-	} else {
-		return Err(Error::OutputPathIsDir(
-			cli.output_directory.into_boxed_path(),
-		));
-	};
-
-	// cross task comms
-	let (print_tx, print_rx) = mpsc::channel::<Print>(16);
-	let (program_event_tx, program_event_rx) = mpsc::channel::<Event>(8);
-	let (file_cmd_tx, file_cmd_rx) = mpsc::channel::<FileCmd>(8);
-	let (com_cmd_tx, com_cmd_rx) = mpsc::channel::<ComCmd>(8);
-	let (ipc_shutdown_tx, ipc_shutdown_rx) = oneshot::channel();
-
-	// println!() replacement
-	let print_task_hanle = tokio::spawn(print_task(print_rx));
-	let printer = Printer::new(print_tx);
-
-	// main control loop
-	let program_task_handle = tokio::spawn(program_event_task(
-		program_event_rx,
-		file_cmd_tx.clone(),
-		com_cmd_tx.clone(),
-		output_dir,
-		printer.clone(),
-		ipc_shutdown_tx,
-	));
-	let com_task_handle = tokio::spawn(serial_com_task(
-		program_event_tx.clone(),
-		com_cmd_rx,
-		printer.clone(),
-	));
-	let file_task_handle = tokio::spawn(file_task(program_event_tx.clone(), file_cmd_rx));
-	let ipc_task_handle = tokio::spawn(ipc_task(
-		program_event_tx.clone(),
-		printer.clone(),
-		ipc_shutdown_rx,
-	));
-	// TODO; handle JoinErr?
-	let (_prog_res, _com_res, _file_res, _print_res, _ipc_res) = tokio::join!(
-		program_task_handle,
-		com_task_handle,
-		file_task_handle,
-		print_task_hanle,
-		ipc_task_handle
-	);
-	print!("exiting...");
-	Ok(())
-}
-
-async fn program_event_task(
-	mut rx: Receiver<Event>,
-	file_cmd_tx: Sender<FileCmd>,
-	com_cmd_tx: Sender<ComCmd>,
-	mut output_dir: PathBuf,
-	mut printer: Printer,
-	ipc_shutdown_tx: oneshot::Sender<()>,
-) {
-	printer.stat("program started...").await;
-	let mut state = TestState::default();
-	let mut mode = Mode::default();
-	loop {
-		mode = match mode {
-			Mode::Setup => {
-				setup(
-					&mut state,
-					&mut rx,
-					&com_cmd_tx,
-					&file_cmd_tx,
-					&mut output_dir,
-					&mut printer,
-				)
-				.await
-			}
-			Mode::WaitForBattery => {
-				wait_for_battery(
-					&mut state,
-					&mut rx,
-					&com_cmd_tx,
-					&file_cmd_tx,
-					&mut output_dir,
-					&mut printer,
-				)
-				.await
-			}
-			Mode::WaitForUsrStart => {
-				wait_for_usr_start(
-					&mut state,
-					&mut rx,
-					&file_cmd_tx,
-					&mut output_dir,
-					&mut printer,
-				)
-				.await
-			}
-			Mode::Testing => {
-				testing(&mut state, &mut rx, &com_cmd_tx, &file_cmd_tx, &mut printer).await
-			}
-			Mode::EndTest => end_test(&mut state, &com_cmd_tx, &file_cmd_tx, &mut printer).await,
-			Mode::Paused => todo!(),
-			Mode::Shutdown => {
-				shutdown(com_cmd_tx, file_cmd_tx, printer, ipc_shutdown_tx).await;
-				break;
-			}
-			Mode::CommDC => comm_dc(&mut state, &file_cmd_tx, &mut printer).await,
-			Mode::Fault => {
-				fault(
-					&mut state,
-					&mut rx,
-					&com_cmd_tx,
-					&file_cmd_tx,
-					&mut output_dir,
-					&mut printer,
-				)
-				.await
-			}
-		};
+	match cli.cmd {
+		Some(ServerSubcommands::Demo(demo_cli)) => demo(demo_cli).await,
+		None => run(cli).await,
 	}
 }
 
-async fn shutdown(
-	com_cmd_tx: Sender<ComCmd>,
-	file_cmd_tx: Sender<FileCmd>,
-	printer: Printer,
-	ipc_shutdown_tx: oneshot::Sender<()>,
-) {
-	com_cmd_tx
-		.send(ComCmd::BICommand(idle_command()))
-		.await
-		.unwrap();
-	file_cmd_tx.send(FileCmd::CloseFile).await.unwrap();
-	file_cmd_tx.send(FileCmd::Shutdown).await.unwrap();
-	com_cmd_tx.send(ComCmd::Shutdown).await.unwrap();
-	ipc_shutdown_tx.send(()).unwrap();
-	printer.shutdown().await;
-}
-
-async fn comm_dc(
-	state: &mut TestState,
-	file_cmd_tx: &Sender<FileCmd>,
-	printer: &mut Printer,
-) -> Mode {
-	printer.stat("serial comms disconnected").await;
-	file_cmd_tx.send(FileCmd::CloseFile).await.unwrap();
-	state.end_test();
-	Mode::Setup
+/// Run a short test against the simulator, see [`pc_common::sim`]
+async fn demo(demo_cli: DemoCli) -> Result<(), Error> {
+	let (engine, demo_task_handle, output_dir) = start_demo(demo_cli).await?;
+	tokio::spawn(shutdown_on_interrupt(
+		engine.event_sender(0).unwrap(),
+		engine.printer().task(Task::Server),
+	));
+	engine.join().await;
+	let _demo_res = demo_task_handle.await;
+	println!("\ndemo test saved in: {output_dir:?}");
+	Ok(())
 }
 
-async fn end_test(
-	state: &mut TestState,
-	com_cmd_tx: &Sender<ComCmd>,
-	file_cmd_tx: &Sender<FileCmd>,
-	printer: &mut Printer,
-) -> Mode {
-	com_cmd_tx
-		.send(ComCmd::BICommand(end_test_command()))
-		.await
-		.unwrap();
-	file_cmd_tx.send(FileCmd::CloseFile).await.unwrap();
-	printer.stat("ending test...").await;
-	state.end_test();
-	Mode::Setup
+/// The engine against the simulator with [`demo_task`] playing the user, and where it saves the test
+async fn start_demo(demo_cli: DemoCli) -> Result<(Engine, JoinHandle<()>, PathBuf), Error> {
+	let output_dir = demo_cli.output_directory.unwrap_or_else(|| {
+		std::env::temp_dir().join(format!("battery-tester-demo-{}", std::process::id()))
+	});
+	std::fs::create_dir_all(&output_dir)
+		.map_err(|e| Error::DemoOutput(output_dir.clone().into_boxed_path(), e))?;
+	let sim_config = match &demo_cli.sim_config {
+		Some(path) => SimConfig::load(path)?,
+		None => SimConfig::default(),
+	};
+	let engine = EngineBuilder::new(output_dir.clone())
+		.transport(SimTransport::new(sim_config))
+		.spawn()
+		.await?;
+	// both exist, the engine has a channel 0
+	let status = engine.status(0).unwrap();
+	let demo_task_handle = tokio::spawn(demo_task(
+		engine.event_sender(0).unwrap(),
+		status.server,
+		engine.printer().task(Task::Demo),
+	));
+	Ok((engine, demo_task_handle, output_dir))
 }
 
-async fn testing(
-	state: &mut TestState,
-	event_rx: &mut Receiver<Event>,
-	com_cmd_tx: &Sender<ComCmd>,
-	file_cmd_tx: &Sender<FileCmd>,
-	printer: &mut Printer,
-) -> Mode {
-	printer.stat("starting test...").await;
-	com_cmd_tx
-		.send(ComCmd::BICommand(testing_command(
-			state.get_allow_undercurrent(),
-		)))
-		.await
-		.unwrap();
-	loop {
-		let event = match event_rx.recv().await {
-			Some(e) => e,
-			None => return Mode::Shutdown,
-		};
-		match event {
-			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
-			Event::ComReply(reply) => match reply.fault {
-				Err(f) => {
-					match f.kind {
-						FaultKind::I2C(i2ce) => {
-							printer.buf(|b| write!(b, "I2C Fault:\n{i2ce:?}")).await;
-						}
-						FaultKind::Undercurrent => {
-							printer.stat("Heater undercurret/not present!").await;
-						}
-						FaultKind::NoBattery => {
-							printer.stat("Battery Disconnected!").await;
-						}
-						FaultKind::Overcurrent => {
-							printer.stat("Heater overcurrent!").await;
-						}
-					}
-					break Mode::Fault;
-				}
-				Ok(()) => match reply.measurement {
-					Some(m) if m.vbat > state.cutoff() => {
-						// keep testing
-						file_cmd_tx
-							.send(FileCmd::Push(SaveData {
-								millivolts: m.vbat,
-								milliamps: m.ibat,
-								dt: m.dt,
-								duration: m.duration,
-							}))
-							.await
-							.unwrap();
-					}
-					Some(_m) => break Mode::EndTest, // at cutoff, stop testing
-					None => {
-						// no new data this time, keep testing
-					}
-				},
-			},
-			Event::CommDc => break Mode::CommDC,
-			Event::StartTest => {
-				printer.stat("already testing").await;
-			}
-			Event::CancelTest => break Mode::EndTest,
-			Event::Shutdown => break Mode::Shutdown,
-			Event::SetSerialDevice(_dev_id) => {
-				printer
-					.stat("can't change serial device while testing")
-					.await;
-			}
-			Event::BattID(_battery_id) => {
-				printer.stat("can't change battery ID while testing").await;
-			}
-			Event::FileError => break Mode::EndTest,
-			Event::ClearFault => {
-				printer.stat("no fault to clear").await;
-			}
-			Event::UnderCurrentResponse(allow_undercurrent) => {
-				state.set_allow_undercurrent(allow_undercurrent)
-			}
+/// Runs the server until it's shut down
+async fn run(cli: Cli) -> Result<(), Error> {
+	let profile = match &cli.profile {
+		Some(path) => Some(TestProfile::load(path)?),
+		None => None,
+	};
+	if let Some(trace_path) = &cli.replay {
+		return replay(
+			trace_path,
+			profile,
+			cli.terminate.unwrap_or_default(),
+			!cli.no_ir_pulse,
+			cli.ocv_rest_s.map(std::time::Duration::from_secs),
+		)
+		.await;
+	}
+	let output_directory = cli.output_directory.ok_or(Error::NoOutputDirectory)?;
+	let mut builder = EngineBuilder::new(output_directory).channels(cli.channels);
+	if let Some(name) = cli.name {
+		builder = builder.ipc_name(name.into_boxed_str());
+	}
+	if let Some(access) = cli.ipc_access {
+		builder = builder.ipc_access(access);
+	}
+	let token = match &cli.token_file {
+		Some(path) => Some(read_token(path)?),
+		None => None,
+	};
+	if let Some(token) = &token {
+		builder = builder.token(token.clone());
+	}
+	if let Some(addr) = cli.listen {
+		builder = builder.listen(addr);
+	}
+	if let Some(profile) = profile {
+		builder = builder.profile(profile);
+	}
+	if let Some(addr) = &cli.chamber {
+		builder = builder.chamber(ScpiChamber::connect(addr).await.map_err(Error::Chamber)?);
+	}
+	if let Some(port_name) = cli.signal_port {
+		builder = builder.signal_port(port_name.into_boxed_str());
+	}
+	if let Some(path) = cli.db {
+		builder = builder.db(path);
+	}
+	if let Some(path) = cli.registry {
+		builder = builder.registry(path);
+	}
+	if let Some(path) = cli.fallback_dir {
+		builder = builder.fallback_dir(path);
+	}
+	if let Some(flush) = cli.flush {
+		builder = builder.flush(flush);
+	}
+	builder = builder.rotation(Rotation {
+		every: cli
+			.rotate_hours
+			.map(|hours| std::time::Duration::from_secs(hours * 3600)),
+		max_bytes: cli.rotate_mb.map(|mb| mb * 1024 * 1024),
+	});
+	if let Some(rule) = cli.terminate {
+		builder = builder.termination(rule);
+	}
+	builder = builder.ir_pulse(!cli.no_ir_pulse);
+	if let Some(warmup) = cli.warmup {
+		builder = builder.warmup(warmup);
+	}
+	if let Some(secs) = cli.ocv_rest_s {
+		builder = builder.ocv_rest(std::time::Duration::from_secs(secs));
+	}
+	if let Some(poll_ms) = cli.poll_ms {
+		builder = builder.poll_ms(poll_ms);
+	}
+	if let Some(baud) = cli.baud {
+		if !baud_supported(baud) {
+			return Err(Error::UnsupportedBaud(baud));
 		}
+		builder = builder.baud(baud);
 	}
-}
-
-async fn wait_for_usr_start(
-	state: &mut TestState,
-	event_rx: &mut Receiver<Event>,
-	file_cmd_tx: &Sender<FileCmd>,
-	output_dir: &mut PathBuf,
-	printer: &mut Printer,
-) -> Mode {
-	printer.stat("waiting for user to start test...").await;
-	loop {
-		let event = match event_rx.recv().await {
-			Some(e) => e,
-			None => return Mode::Shutdown,
-		};
-		match event {
-			Event::BattID(battery_id) => match new_file(battery_id, output_dir, printer).await {
-				Ok(file) => {
-					file_cmd_tx.send(FileCmd::NewFile(file)).await.unwrap();
-					state.new_batt_id(battery_id)
-				}
-				Err(e) => {
-					printer
-						.buf(|tv| write!(tv, "can't create new output file:\n{e}"))
-						.await;
-					break Mode::EndTest;
-				}
-			},
-			Event::StartTest => break Mode::Testing,
-			Event::ComReply(reply) => match reply.fault {
-				Ok(()) => {
-					if let Some(m) = reply.measurement {
-						// double check that the battery is over cutoff
-						if !(m.vbat > state.cutoff()) {
-							break Mode::WaitForBattery;
-						}
-					}
-				}
-				Err(f) => {
-					printer.buf(|tv| write!(tv, "fault:\n{f:?}")).await;
-					break Mode::Fault;
-				}
-			},
-			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
-			Event::CommDc => break Mode::CommDC,
-			Event::CancelTest => break Mode::EndTest,
-			Event::SetSerialDevice(_) => {
-				// TODO: warn user
-			}
-			Event::Shutdown => break Mode::Shutdown,
-			Event::FileError => break Mode::EndTest,
-			Event::ClearFault => {
-				printer.stat("no fault to clear").await;
-			}
-			Event::UnderCurrentResponse(allow_undercurrent) => {
-				state.set_allow_undercurrent(allow_undercurrent)
-			}
+	if let Some(secs) = cli.live_every_s {
+		builder = builder.live_every(std::time::Duration::from_secs(secs));
+	}
+	if let Some(template) = cli.file_name {
+		builder = builder.file_name(template);
+	}
+	if let Some(format) = cli.format {
+		builder = builder.format(format);
+	}
+	if let Some(path) = &cli.columns {
+		builder = builder.columns(ColumnConfig::load(path)?);
+	}
+	if let Some(path) = &cli.notify {
+		builder = builder.notify(NotifyConfig::load(path)?);
+	}
+	if let Some(path) = cli.trace {
+		builder = builder.trace(path);
+	}
+	if let Some(path) = cli.dump_serial {
+		builder = builder.dump_serial(path);
+	}
+	#[cfg(feature = "kiosk")]
+	let kiosk = match cli.kiosk {
+		Some(path) => {
+			let config = KioskConfig::load(&path)?;
+			let token = token.ok_or(Error::KioskToken)?;
+			builder = builder.advertise(Feature::Kiosk);
+			Some((path, config, token))
 		}
+		None => None,
+	};
+	let sim_config = match &cli.sim_config {
+		Some(path) => Some(SimConfig::load(path)?),
+		None => cli.simulate.then(SimConfig::default),
+	};
+	let engine = match sim_config {
+		Some(sim_config) => {
+			builder
+				.transport(SimTransport::new(sim_config))
+				.spawn()
+				.await?
+		}
+		None => builder.spawn().await?,
+	};
+
+	// headless appliance, finds the device and takes commands from the dashboard
+	#[cfg(feature = "kiosk")]
+	let kiosk_task_handle = kiosk.map(|(path, config, token)| {
+		tokio::spawn(kiosk_task(
+			path,
+			config,
+			token,
+			// both exist, the engine has a channel 0
+			engine.event_sender(0).unwrap(),
+			engine.status(0).unwrap(),
+			engine.printer().task(Task::Kiosk),
+		))
+	});
+	tokio::spawn(shutdown_on_interrupt(
+		// both exist, the engine has a channel 0
+		engine.event_sender(0).unwrap(),
+		engine.printer().task(Task::Server),
+	));
+	engine.join().await;
+	#[cfg(feature = "kiosk")]
+	if let Some(handle) = kiosk_task_handle {
+		let _kiosk_res = handle.await;
 	}
+	print!("exiting...");
+	Ok(())
 }
 
-async fn wait_for_battery(
-	state: &mut TestState,
-	event_rx: &mut Receiver<Event>,
-	com_cmd_tx: &Sender<ComCmd>,
-	file_cmd_tx: &Sender<FileCmd>,
-	output_dir: &mut PathBuf,
-	printer: &mut Printer,
-) -> Mode {
-	printer.stat("waiting for battery connection...").await;
-	com_cmd_tx
-		.send(ComCmd::BICommand(volts_command()))
-		.await
-		.unwrap();
-	loop {
-		let event = match event_rx.recv().await {
-			Some(e) => e,
-			None => return Mode::Shutdown,
-		};
-		match event {
-			Event::BattID(battery_id) => match new_file(battery_id, output_dir, printer).await {
-				Ok(file) => {
-					file_cmd_tx.send(FileCmd::NewFile(file)).await.unwrap();
-					state.new_batt_id(battery_id)
-				}
-				Err(e) => {
-					printer
-						.buf(|tv| write!(tv, "can't create new output file:\n{e}"))
-						.await;
-					break Mode::EndTest;
-				}
-			},
-			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
-			Event::StartTest => {
-				printer
-					.stat("can't start test while waiting for battery")
-					.await;
-			}
-			Event::CommDc => {
-				break Mode::CommDC;
-			}
-			Event::ComReply(reply) => match reply.fault {
-				Ok(()) => {
-					if let Some(m) = reply.measurement {
-						if m.vbat > state.cutoff() {
-							// battery connected, wait for user to start
-							break Mode::WaitForUsrStart;
-						} else {
-							// battery not connected yet
-						}
-					}
-				}
-				Err(f) => {
-					printer.buf(|tv| write!(tv, "fault:\n{f:?}")).await;
-					break Mode::Fault;
-				}
-			},
-			Event::CancelTest => break Mode::EndTest,
-			Event::SetSerialDevice(_) => {
-				printer
-					.stat("can't change serial device while waiting for battery")
-					.await;
-			}
-			Event::Shutdown => break Mode::Shutdown,
-			Event::FileError => break Mode::EndTest,
-			Event::ClearFault => {
-				printer.stat("no fault to clear").await;
-			}
-			Event::UnderCurrentResponse(allow_undercurrent) => {
-				state.set_allow_undercurrent(allow_undercurrent)
+/// Shut down like the client's `shutdown` on Ctrl-C or SIGTERM, so the battery interface is
+/// sent the idle command and the files are closed. A second Ctrl-C exits straight away.
+async fn shutdown_on_interrupt(event_tx: Sender<Event>, printer: Printer) {
+	#[cfg(unix)]
+	let terminated = async {
+		match tokio::signal::unix::signal(SignalKind::terminate()) {
+			Ok(mut terminate) => {
+				terminate.recv().await;
 			}
+			Err(_) => std::future::pending().await,
 		}
+	};
+	// Ctrl-C only
+	#[cfg(not(unix))]
+	let terminated = std::future::pending::<()>();
+	select! {
+		Ok(()) = tokio::signal::ctrl_c() => {},
+		() = terminated => {},
+	}
+	printer.stat("interrupted, shutting down...").await;
+	// the engine is already shutting down if it's gone
+	let _ = event_tx.send(Event::Shutdown).await;
+	if tokio::signal::ctrl_c().await.is_ok() {
+		printer.error_stat("interrupted again, exiting now").await;
+		std::process::exit(130);
 	}
 }
 
-async fn fault(
-	state: &mut TestState,
-	event_rx: &mut Receiver<Event>,
-	com_cmd_tx: &Sender<ComCmd>,
-	file_cmd_tx: &Sender<FileCmd>,
-	output_dir: &mut PathBuf,
-	printer: &mut Printer,
-) -> Mode {
-	com_cmd_tx
-		.send(ComCmd::BICommand(idle_command()))
-		.await
-		.unwrap();
-	printer.stat("ending test, clear fault to continue").await;
-	file_cmd_tx.send(FileCmd::CloseFile).await.unwrap();
-	state.end_test();
-	loop {
-		let event = match event_rx.recv().await {
-			Some(e) => e,
-			None => return Mode::Shutdown,
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+	use argh::FromArgs;
+	use pc_common::Print;
+	use std::time::Duration;
+	use tokio::{sync::mpsc, time::timeout};
+
+	#[tokio::test]
+	async fn test_sigterm_shuts_down() {
+		let (event_tx, mut event_rx) = mpsc::channel(4);
+		let (print_tx, mut print_rx) = mpsc::channel::<Print>(4);
+		let interrupt = tokio::spawn(shutdown_on_interrupt(event_tx, Printer::new(print_tx)));
+		// the handlers are in place once it's waiting on them
+		tokio::task::yield_now().await;
+		let killed = std::process::Command::new("kill")
+			.args(["-TERM", &std::process::id().to_string()])
+			.status()
+			.unwrap();
+		assert!(killed.success());
+		let event = timeout(Duration::from_secs(10), event_rx.recv())
+			.await
+			.expect("not shut down");
+		assert!(matches!(event, Some(Event::Shutdown)));
+		let Some(Print::Info(line)) = print_rx.recv().await else {
+			panic!("the shutdown wasn't printed");
 		};
-		match event {
-			Event::BattID(battery_id) => match new_file(battery_id, output_dir, printer).await {
-				Ok(file) => {
-					file_cmd_tx.send(FileCmd::NewFile(file)).await.unwrap();
-					state.new_batt_id(battery_id);
-				}
-				Err(e) => {
-					printer
-						.buf(|tv| write!(tv, "can't create new output file:\n{e}"))
-						.await;
-					state.end_test();
-				}
-			},
-			Event::SetSerialDevice(dev_id) => {
-				printer
-					.buf(|tv| write!(tv, "setting device name to: {}", &dev_id))
-					.await;
-				com_cmd_tx
-					.send(ComCmd::NewDeviceName(dev_id))
-					.await
-					.unwrap();
-			}
-			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
-			Event::ComReply(reply) => match reply.fault {
-				Ok(()) => {
-					printer.stat("fault cleared").await;
-					break;
-				}
-				Err(_f) => {
-					// still getting a fault
-				}
-			},
-			Event::Shutdown => return Mode::Shutdown,
-			Event::CommDc => {
-				printer
-					.stat("lost serial comms with battery interface")
-					.await;
-				return Mode::Setup;
-			}
-			Event::StartTest => {
-				printer
-					.stat("cant't start test until fault is cleared")
-					.await;
-			}
-			Event::CancelTest => {
-				// TODO: warn user
-			}
-			Event::FileError => {}
-			Event::ClearFault => {
-				com_cmd_tx.send(ComCmd::ClearFault).await.unwrap();
-				// dont break or return because we want an OK(()) reply from BI
-			}
-			Event::UnderCurrentResponse(allow_undercurrent) => {
-				state.set_allow_undercurrent(allow_undercurrent)
-			}
-		}
+		assert_eq!(
+			String::from_utf8_lossy(line.text.as_bytes()),
+			"interrupted, shutting down..."
+		);
+		// waiting for a second Ctrl-C
+		interrupt.abort();
 	}
-	Mode::Setup
-}
-async fn setup(
-	state: &mut TestState,
-	event_rx: &mut Receiver<Event>,
-	com_cmd_tx: &Sender<ComCmd>,
-	file_cmd_tx: &Sender<FileCmd>,
-	output_dir: &mut PathBuf,
-	printer: &mut Printer,
-) -> Mode {
-	printer
-		.stat("setup: please set battery ID and tester serial port device name")
-		.await;
-	com_cmd_tx
-		.send(ComCmd::BICommand(idle_command()))
-		.await
+
+	#[tokio::test(start_paused = true)]
+	async fn test_demo_saves_a_test() {
+		let dir =
+			std::env::temp_dir().join(format!("battery-tester-server-demo-{}", std::process::id()));
+		let cli = Cli::from_args(
+			&["battery-tester-server"],
+			&["demo", "--output-directory", dir.to_str().unwrap()],
+		)
 		.unwrap();
-	printer.buf(|tv| write!(tv, "{:?}", &state)).await;
-	loop {
-		let event = match event_rx.recv().await {
-			Some(e) => e,
-			None => return Mode::Shutdown,
+		assert_eq!(cli.output_directory, None);
+		let Some(ServerSubcommands::Demo(demo_cli)) = cli.cmd else {
+			panic!("demo isn't a subcommand");
 		};
-		match event {
-			Event::BattID(battery_id) => match new_file(battery_id, output_dir, printer).await {
-				Ok(file) => {
-					file_cmd_tx.send(FileCmd::NewFile(file)).await.unwrap();
-					state.new_batt_id(battery_id);
-					if state.ready_for_battery() {
-						break Mode::WaitForBattery;
-					} else {
-						printer.buf(|tv| write!(tv, "{:?}", &state)).await;
-					}
-				}
-				Err(e) => {
-					printer
-						.buf(|tv| write!(tv, "can't create new output file:\n{e}"))
-						.await;
-					state.end_test();
-				}
-			},
-			Event::SetSerialDevice(dev_id) => {
-				printer
-					.buf(|tv| write!(tv, "setting device name to: {}", &dev_id))
-					.await;
-				com_cmd_tx
-					.send(ComCmd::NewDeviceName(dev_id.clone()))
-					.await
-					.unwrap();
-				state.new_device_name(dev_id);
-				printer.buf(|tv| write!(tv, "{:?}", &state)).await;
-			}
-			Event::SetCutoff(millivolts) => new_cutoff(state, millivolts, printer).await,
-			Event::ComReply(reply) => match reply.fault {
-				Ok(()) => {
-					if !state.got_first_reply() {
-						state.set_first_reply();
-						printer.buf(|tv| write!(tv, "{:?}", &state)).await;
-					}
-					if state.ready_for_battery() {
-						break Mode::WaitForBattery;
-					}
-				}
-				Err(f) => {
-					// got_ok_reply = false;
-					match f.kind {
-						FaultKind::I2C(i2ce) => {
-							printer.buf(|b| write!(b, "I2C Fault:\n{i2ce:?}")).await;
-						}
-						FaultKind::Undercurrent => {
-							printer.stat("Heater undercurret/not present!").await;
-						}
-						FaultKind::NoBattery => {
-							printer.stat("Battery Disconnected!").await;
-						}
-						FaultKind::Overcurrent => {
-							printer.stat("Heater overcurrent!").await;
-						}
-					}
-					break Mode::Fault;
-				}
-			},
-			Event::Shutdown => break Mode::Shutdown,
-			Event::CommDc => state.unset_first_reply(),
-			Event::StartTest => {
-				printer.stat("cant't start test during setup").await;
-			}
-			Event::CancelTest => {}
-			Event::FileError => state.end_test(),
-			Event::ClearFault => {
-				printer.stat("no fault to clear").await;
-			}
-			Event::UnderCurrentResponse(allow_undercurrent) => {
-				state.set_allow_undercurrent(allow_undercurrent)
-			}
-		}
-	}
-}
-
-async fn new_cutoff(state: &mut TestState, millivolts: MilliVolt, printer: &mut Printer) {
-	state.new_cutoff(millivolts);
-	printer
-		.buf(|tv| write!(tv, "new cutoff voltage (millivolts): {millivolts}"))
-		.await;
-}
-
-async fn new_file(
-	battery_id: BatteryID,
-	output_dir: &mut PathBuf,
-	printer: &mut Printer,
-) -> tokio::io::Result<File> {
-	let now = chrono::Local::now().format("%Y%m%d_%TUTC%Z");
-	let battery_year = battery_id.year;
-	let battery_idx = battery_id.index;
-	let file_name = format!("{battery_year}-{battery_idx}-{now}.tsv");
-	output_dir.push(file_name);
-	let res = OpenOptions::new()
-		.write(true)
-		.read(true)
-		.append(true)
-		.create_new(true)
-		.open(&output_dir)
-		.await;
-	if res.is_ok() {
-		printer
-			.buf(|tv| write!(tv, "created new file at: {:?}", &output_dir))
-			.await;
+		let (engine, demo_task_handle, output_dir) = start_demo(demo_cli).await.unwrap();
+		assert_eq!(output_dir, dir);
+		timeout(Duration::from_secs(3_600), engine.join())
+			.await
+			.expect("demo didn't shut down");
+		demo_task_handle.await.unwrap();
+		let saved: Vec<PathBuf> = std::fs::read_dir(&dir)
+			.unwrap()
+			.map(|entry| entry.unwrap().path())
+			.collect();
+		let test = saved
+			.iter()
+			.find(|path| path.extension().is_some_and(|ext| ext == "tsv"))
+			.expect("no test saved");
+		let name = test.file_name().unwrap().to_string_lossy();
+		assert!(name.starts_with("2000-1-"), "{name}");
+		let text = std::fs::read_to_string(test).unwrap();
+		let mut lines = text.lines();
+		assert!(
+			lines
+				.next()
+				.unwrap()
+				.starts_with("sample_index\tsample_start_ms")
+		);
+		assert!(lines.count() > 10, "{text}");
+		// it ran until the simulated battery was flat
+		let notes = std::fs::read_to_string(test.with_extension("notes.toml")).unwrap();
+		assert!(notes.contains("ended = \"cutoff\""), "{notes}");
+		std::fs::remove_dir_all(dir).unwrap();
 	}
-	output_dir.pop();
-	res
 }
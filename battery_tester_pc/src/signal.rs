@@ -0,0 +1,67 @@
+use std::io::Write;
+
+use tokio::sync::mpsc::Receiver;
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+
+use crate::{Mode, Printer};
+
+/// What external equipment should be told about the test.
+/// On the signal port DTR is asserted while testing and RTS while faulted,
+/// so a USB-serial adapter can drive relays, lamps, chart recorders etc.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum TestSignal {
+	#[default]
+	Idle,
+	Testing,
+	Fault,
+}
+
+impl From<Mode> for TestSignal {
+	fn from(mode: Mode) -> Self {
+		match mode {
+			Mode::Testing => TestSignal::Testing,
+			Mode::Fault => TestSignal::Fault,
+			_ => TestSignal::Idle,
+		}
+	}
+}
+
+pub async fn signal_task(
+	port_name: Box<str>,
+	mut signal_rx: Receiver<TestSignal>,
+	mut printer: Printer,
+) {
+	let mut port = match tokio_serial::new(port_name.as_ref(), 9600).open_native_async() {
+		Ok(p) => p,
+		Err(e) => {
+			printer
+				.error(|tv| write!(tv, "can't open signal port: {port_name} due to:\n{e}"))
+				.await;
+			// keep draining so the program task never blocks on us
+			while signal_rx.recv().await.is_some() {}
+			return;
+		}
+	};
+	set_lines(&mut port, TestSignal::Idle, &mut printer).await;
+	while let Some(signal) = signal_rx.recv().await {
+		set_lines(&mut port, signal, &mut printer).await;
+	}
+	set_lines(&mut port, TestSignal::Idle, &mut printer).await;
+	println!("exiting signal_task");
+}
+
+async fn set_lines(port: &mut SerialStream, signal: TestSignal, printer: &mut Printer) {
+	let (dtr, rts) = match signal {
+		TestSignal::Idle => (false, false),
+		TestSignal::Testing => (true, false),
+		TestSignal::Fault => (false, true),
+	};
+	let res = port
+		.write_data_terminal_ready(dtr)
+		.and_then(|_| port.write_request_to_send(rts));
+	if let Err(e) = res {
+		printer
+			.warn(|tv| write!(tv, "can't set signal port lines for {signal:?}:\n{e}"))
+			.await;
+	}
+}
@@ -0,0 +1,736 @@
+//! A simulated battery interface, and the demo that runs a test against it.
+//!
+//! The simulator speaks the same framed protocol as the firmware over an in-memory link,
+//! [`SimTransport`] hands the server the other end in place of a serial port.
+//! Simulated time runs fast so a whole discharge takes a few seconds.
+//!
+//! `battery-tester-server --simulate` runs the whole server against it, for working on
+//! the PC side without hardware. A [`SimConfig`] sets the battery's capacity, internal
+//! resistance, and noise, and faults to inject partway through a test.
+
+use battery_tester_common::{
+	AutonomousTest, BIReply, BatteryDetect, BiCommand, ClearFault, CommandKind, ControlWord,
+	DEFAULT_BAUD, DEFAULT_MAX_MILLIV, Fault, FaultKind, FirmwareVersion, I2CError, LoadChannel,
+	LoadModel, LoadPulse, LoadState, LoggedSample, Measurement, MilliAmp, MilliVolt, MilliWatt,
+	PROTOCOL_VERSION, ReplyKind, Reset, SelfTestReport, Status, Trim, UNSOLICITED_SEQ,
+	autonomous::SampleAverage,
+	frame::{self, COMMAND_FRAME_MAX_SIZE, FrameBuffer, REPLY_FRAME_MAX_SIZE},
+};
+use std::path::Path;
+
+use serde::Deserialize;
+
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+	sync::{mpsc::Sender, watch},
+};
+
+use crate::{BatteryID, Error, Event, Mode, Printer, ServerStatus, serial::Transport};
+
+/// The simulator isn't firmware, it reports version 0.0.0
+const SIM_FIRMWARE: FirmwareVersion = FirmwareVersion {
+	major: 0,
+	minor: 0,
+	patch: 0,
+};
+
+/// Not a real board's, nRF device IDs are random
+const SIM_DEVICE_ID: u64 = 0;
+
+/// Taken by the battery interface's own circuits, the load channel reads the rest
+const SIM_PARASITIC_MA: i16 = 12;
+
+/// As many samples as the firmware's autonomous log holds
+const SIM_AUTONOMOUS_SLOTS: usize = 3_276;
+
+/// Battery ID of the demo test
+pub const DEMO_BATTERY: BatteryID = BatteryID {
+	year: 2000,
+	index: 1,
+};
+
+/// The simulated battery, the defaults discharge in about 15 minutes of simulated time
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimConfig {
+	pub capacity_mah: f64,
+	/// Current while the load is on
+	pub load_ma: i16,
+	/// Open circuit voltage full and empty, linear in between
+	pub full_mv: u16,
+	pub empty_mv: u16,
+	/// The voltage sags this many mV per amp of load
+	pub internal_resistance_mohm: f64,
+	/// Measurements are off by up to this much either way
+	pub noise_mv: u16,
+	pub noise_ma: u16,
+	/// Same seed, same noise
+	pub seed: u64,
+	/// Simulated time per command, the server sends 2 a second so 500 runs in real time
+	pub step_ms: u64,
+	/// Each one happens once, in any order
+	pub faults: Vec<SimFault>,
+}
+
+impl Default for SimConfig {
+	fn default() -> Self {
+		Self {
+			capacity_mah: 500.0,
+			load_ma: 2_000,
+			full_mv: 12_600,
+			empty_mv: 10_500,
+			internal_resistance_mohm: 100.0,
+			noise_mv: 0,
+			noise_ma: 0,
+			seed: 1,
+			step_ms: 30_000,
+			faults: Vec::new(),
+		}
+	}
+}
+
+impl SimConfig {
+	pub fn load(path: &Path) -> Result<Self, Error> {
+		let text = std::fs::read_to_string(path)
+			.map_err(|e| Error::SimConfigRead(path.to_path_buf().into_boxed_path(), e))?;
+		let config: Self = toml::from_str(&text)
+			.map_err(|e| Error::SimConfigParse(path.to_path_buf().into_boxed_path(), e))?;
+		config
+			.check()
+			.map_err(|e| Error::SimConfigInvalid(path.to_path_buf().into_boxed_path(), e))?;
+		Ok(config)
+	}
+
+	/// What parses but can't be simulated
+	fn check(&self) -> Result<(), &'static str> {
+		// NaN too, it would never discharge
+		if self.capacity_mah.is_nan() || self.capacity_mah <= 0.0 {
+			return Err("capacity_mah has to be more than 0");
+		}
+		if self.empty_mv > self.full_mv {
+			return Err("empty_mv can't be more than full_mv");
+		}
+		Ok(())
+	}
+}
+
+/// Something going wrong partway through a test
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimFault {
+	/// Simulated seconds the load has been on
+	pub after_s: u64,
+	pub kind: SimFaultKind,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SimFaultKind {
+	/// The SHT4x reply fails its checksum
+	I2c,
+	Undercurrent,
+	NoBattery,
+	Overcurrent,
+	SensorIntegrity,
+	OverTemperature,
+	LoadOverTemperature,
+	/// The interface stops replying and the link closes, like a pulled cable.
+	/// Reconnecting gets a fresh battery.
+	Disconnect,
+}
+
+impl SimFaultKind {
+	/// `None` for [`SimFaultKind::Disconnect`], it isn't something the firmware reports
+	fn fault_kind(self) -> Option<FaultKind> {
+		match self {
+			SimFaultKind::I2c => Some(FaultKind::I2C(I2CError::Sht4xCrc)),
+			SimFaultKind::Undercurrent => Some(FaultKind::Undercurrent),
+			SimFaultKind::NoBattery => Some(FaultKind::NoBattery),
+			SimFaultKind::Overcurrent => Some(FaultKind::Overcurrent),
+			SimFaultKind::SensorIntegrity => Some(FaultKind::SensorIntegrity),
+			SimFaultKind::OverTemperature => Some(FaultKind::OverTemperature),
+			SimFaultKind::LoadOverTemperature => Some(FaultKind::LoadOverTemperature),
+			SimFaultKind::Disconnect => None,
+		}
+	}
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SimBattery {
+	config: SimConfig,
+	clock_ms: u64,
+	/// The next [`Measurement::sample_index`]
+	sample_index: u32,
+	/// How long the load has been on, what [`SimFault::after_s`] counts
+	load_on_ms: u64,
+	discharged_mah: f64,
+	/// Load latched off at the cutoff until a reset, like the firmware
+	cutoff_reached: bool,
+	/// Latched until a clear fault command, with the load off, like the firmware
+	fault: Option<Fault>,
+	/// Faults from the config that haven't happened yet
+	pending_faults: Vec<SimFault>,
+	disconnected: bool,
+	rng: u64,
+	/// Kept like the firmware keeps it in flash, the simulated readings aren't trimmed
+	trim: Trim,
+	/// Kept the same way, the simulated battery is there whatever it says
+	battery_detect: BatteryDetect,
+	/// As the last command left it, sent with every reply
+	load: LoadState,
+	/// What a heartbeat keeps going, without the one-off reset and clear fault
+	control: ControlWord,
+	/// The last autonomous test's samples
+	autonomous_log: Vec<LoggedSample>,
+}
+
+impl SimBattery {
+	pub fn new(config: SimConfig) -> Self {
+		Self {
+			pending_faults: config.faults.clone(),
+			// xorshift gets stuck at 0
+			rng: config.seed.max(1),
+			config,
+			..Self::default()
+		}
+	}
+
+	/// Once it's disconnected the link should be closed
+	pub fn disconnected(&self) -> bool {
+		self.disconnected
+	}
+
+	/// Every command gets a reply with a fresh measurement,
+	/// a log dump's unsolicited replies come before it
+	pub fn replies(&mut self, command: BiCommand) -> Vec<BIReply> {
+		let mut replies = Vec::new();
+		let bat_present = self.bat_present();
+		let kind = match command.kind {
+			CommandKind::Hello => ReplyKind::Version {
+				protocol: PROTOCOL_VERSION,
+				firmware: SIM_FIRMWARE,
+				device_id: SIM_DEVICE_ID,
+				self_test: Some(self.self_test()),
+				baud: DEFAULT_BAUD,
+			},
+			CommandKind::Control(control) => {
+				self.control = ControlWord {
+					reset: Reset::No,
+					clear_fault: ClearFault::No,
+					..control
+				};
+				ReplyKind::Status(self.step(control))
+			}
+			CommandKind::Heartbeat => ReplyKind::Status(self.step(self.control)),
+			CommandKind::SetTrim(trim) => {
+				self.trim = trim;
+				ReplyKind::Trim(Ok(trim))
+			}
+			CommandKind::GetTrim => ReplyKind::Trim(Ok(self.trim)),
+			// the simulated faults clear for good, nothing is logged
+			CommandKind::DumpFaultLog => ReplyKind::FaultLog(None),
+			CommandKind::StartAutonomous(test) => {
+				self.run_autonomous(test);
+				ReplyKind::Autonomous(Ok(()))
+			}
+			CommandKind::DumpAutonomousLog => {
+				let load = self.load;
+				replies.extend(self.autonomous_log.iter().map(|sample| BIReply {
+					seq: UNSOLICITED_SEQ,
+					bat_present,
+					load,
+					kind: ReplyKind::AutonomousLog(Some(*sample)),
+				}));
+				ReplyKind::AutonomousLog(None)
+			}
+			CommandKind::SelfTest => ReplyKind::SelfTest(self.self_test()),
+			CommandKind::SetBatteryDetect(detect) => {
+				self.battery_detect = detect;
+				ReplyKind::BatteryDetect(Ok(detect))
+			}
+			CommandKind::GetBatteryDetect => ReplyKind::BatteryDetect(Ok(self.battery_detect)),
+		};
+		replies.push(BIReply {
+			seq: command.seq,
+			bat_present,
+			load: self.load,
+			kind,
+		});
+		replies
+	}
+
+	/// Until a simulated [`SimFaultKind::NoBattery`] is cleared
+	fn bat_present(&self) -> bool {
+		!matches!(
+			self.fault,
+			Some(Fault {
+				kind: FaultKind::NoBattery,
+				..
+			})
+		)
+	}
+
+	/// The INA260s are always there, the pulse draws the full load without discharging anything
+	fn self_test(&mut self) -> SelfTestReport {
+		let milliamps = self.config.load_ma;
+		let vbat = MilliVolt::new(self.millivolts(milliamps));
+		// the simulated load is whatever it's configured to draw, not the heater
+		let model = LoadModel {
+			milliohms: u32::from(u16::from(vbat)) * 1000 / milliamps.unsigned_abs().max(1) as u32,
+			..LoadModel::default()
+		};
+		let (expected_min, expected_max) = model.current_range(vbat);
+		SelfTestReport {
+			ina_vin: Ok(()),
+			ina_load: Some(Ok(())),
+			load: Some(Ok(LoadPulse {
+				vbat,
+				ibat: MilliAmp::new(milliamps),
+				expected_min,
+				expected_max,
+			})),
+		}
+	}
+
+	/// Runs the whole test before answering, simulated time is fast anyway.
+	/// It ends at the cutoff, a fault, the test's max duration, or a flat battery.
+	fn run_autonomous(&mut self, test: AutonomousTest) {
+		let control = ControlWord {
+			load: LoadState::On,
+			clear_fault: ClearFault::No,
+			reset: Reset::No,
+			allow_undercurrent: test.allow_undercurrent,
+			cutoff: Some(test.cutoff),
+			load_model: test.load_model,
+			current_setpoint: test.current_setpoint,
+			max_voltage: test.max_voltage,
+			window_samples: test.window_samples,
+			filter: test.filter,
+			poll_ms: None,
+		};
+		let started = self.clock_ms;
+		let max_ms = test.max_duration_s.map(|max| u64::from(max) * 1000);
+		let mut average = SampleAverage::new(started);
+		self.autonomous_log.clear();
+		loop {
+			let status = self.step(control);
+			if let Some(m) = &status.measurement
+				&& let Some(sample) = average.push(m)
+			{
+				self.autonomous_log.push(sample);
+			}
+			let over = status.cutoff_reached
+				|| status.fault.is_err()
+				|| self.discharged_fraction() >= 1.0
+				|| max_ms.is_some_and(|max| self.clock_ms - started >= max)
+				|| self.autonomous_log.len() == SIM_AUTONOMOUS_SLOTS;
+			if over {
+				break;
+			}
+		}
+		self.autonomous_log.extend(average.finish());
+	}
+
+	fn step(&mut self, control: ControlWord) -> Status {
+		if control.reset == Reset::Yes {
+			self.cutoff_reached = false;
+		}
+		if control.clear_fault == ClearFault::Yes {
+			self.fault = None;
+		}
+		// like the firmware, the load isn't turned on over the limit
+		let max_voltage = control
+			.max_voltage
+			.unwrap_or(MilliVolt::new(DEFAULT_MAX_MILLIV));
+		if control.load == LoadState::On
+			&& self.load == LoadState::Off
+			&& self.fault.is_none()
+			&& MilliVolt::new(self.millivolts(0)) > max_voltage
+		{
+			self.fault = Some(Fault {
+				kind: FaultKind::Overvoltage,
+				time: self.clock_ms,
+			});
+		}
+		let load_on = control.load == LoadState::On && !self.cutoff_reached && self.fault.is_none();
+		self.load = if load_on {
+			LoadState::On
+		} else {
+			LoadState::Off
+		};
+		let milliamps = match control.current_setpoint {
+			_ if !load_on => 0,
+			// the firmware holds the setpoint as long as the load can draw it
+			Some(setpoint) => i16::from(setpoint).min(self.config.load_ma),
+			None => self.config.load_ma,
+		};
+		let step_ms = self.config.step_ms;
+		let dt = self.clock_ms;
+		self.clock_ms += step_ms;
+		let sample_index = self.sample_index;
+		self.sample_index = self.sample_index.wrapping_add(1);
+		if load_on {
+			self.load_on_ms += step_ms;
+		}
+		self.discharged_mah += f64::from(milliamps) * step_ms as f64 / 3_600_000.0;
+		let millivolts = MilliVolt::new(self.millivolts(milliamps));
+		if load_on && control.cutoff.is_some_and(|cutoff| millivolts <= cutoff) {
+			self.cutoff_reached = true;
+		}
+		self.inject_faults(dt);
+		let noise_ma = self.noise(self.config.noise_ma);
+		Status {
+			measurement: Some(Measurement {
+				vbat: millivolts,
+				ibat: MilliAmp::new(
+					(i32::from(milliamps) + noise_ma).clamp(i16::MIN.into(), i16::MAX.into())
+						as i16,
+				),
+				milliwatts: MilliWatt::new(
+					u32::from(u16::from(millivolts)) * u32::from(milliamps.unsigned_abs()) / 1000,
+				),
+				sample_index,
+				sample_start_ms: dt,
+				// like the firmware, a window runs from its first sample to its last
+				sample_duration_ms: step_ms - step_ms / 10,
+				// warms up a few degrees as it discharges
+				temp_centi_c: Some(2_500 + (self.discharged_fraction() * 500.0) as i16),
+				// the heater runs hot while it's on
+				load_temp_centi_c: Some(if load_on { 7_000 } else { 2_500 }),
+				fan_on: load_on,
+				load: Some(LoadChannel {
+					millivolts,
+					milliamps: MilliAmp::new((milliamps - SIM_PARASITIC_MA).max(0)),
+				}),
+			}),
+			fault: self.fault.map_or(Ok(()), Err),
+			cutoff_reached: self.cutoff_reached,
+			local_load: None,
+			reset_reason: None,
+			// it never starts over from the defaults
+			wants_control: false,
+		}
+	}
+
+	/// Start the faults that are due
+	fn inject_faults(&mut self, time: u64) {
+		let load_on_ms = self.load_on_ms;
+		let (due, pending) = self
+			.pending_faults
+			.iter()
+			.partition(|fault| fault.after_s * 1000 <= load_on_ms);
+		self.pending_faults = pending;
+		for SimFault { kind, .. } in due {
+			match kind.fault_kind() {
+				Some(kind) => self.fault = Some(Fault { kind, time }),
+				None => self.disconnected = true,
+			}
+		}
+	}
+
+	fn discharged_fraction(&self) -> f64 {
+		(self.discharged_mah / self.config.capacity_mah).clamp(0.0, 1.0)
+	}
+
+	fn millivolts(&mut self, milliamps: i16) -> u16 {
+		let full = f64::from(self.config.full_mv);
+		let empty = f64::from(self.config.empty_mv);
+		let open_circuit = full - (full - empty) * self.discharged_fraction();
+		let sag = self.config.internal_resistance_mohm * f64::from(milliamps) / 1000.0;
+		let noise = self.noise(self.config.noise_mv) as f64;
+		(open_circuit - sag + noise).max(0.0) as u16
+	}
+
+	/// Evenly spread over `-amplitude..=amplitude`
+	fn noise(&mut self, amplitude: u16) -> i32 {
+		if amplitude == 0 {
+			return 0;
+		}
+		// xorshift64, plenty for noise on a measurement
+		self.rng ^= self.rng << 13;
+		self.rng ^= self.rng >> 7;
+		self.rng ^= self.rng << 17;
+		let span = 2 * u64::from(amplitude) + 1;
+		(self.rng % span) as i32 - i32::from(amplitude)
+	}
+}
+
+/// Answer commands from the server until the link closes or the battery disconnects
+pub async fn sim_task<S>(mut port: S, config: SimConfig)
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	let mut battery = SimBattery::new(config);
+	let mut frames = FrameBuffer::<COMMAND_FRAME_MAX_SIZE>::new();
+	let mut read_buf = [0u8; 64];
+	let mut reply_buf = [0u8; REPLY_FRAME_MAX_SIZE];
+	loop {
+		let num_read = match port.read(&mut read_buf).await {
+			Ok(0) | Err(_) => break,
+			Ok(num_read) => num_read,
+		};
+		for &byte in &read_buf[..num_read] {
+			// a bad frame gets no reply, the server counts it lost
+			let Some(Ok(command)) = frames.push::<BiCommand>(byte) else {
+				continue;
+			};
+			for reply in battery.replies(command) {
+				// the buffer always fits the largest possible reply frame
+				let outgoing = frame::encode(&reply, &mut reply_buf).unwrap();
+				if port.write_all(outgoing).await.is_err() {
+					return;
+				}
+			}
+			if battery.disconnected() {
+				println!("simulated battery interface disconnected");
+				return;
+			}
+		}
+	}
+	println!("exiting sim_task");
+}
+
+/// Device name the demo sets, [`SimTransport`] takes any
+pub const SIM_DEVICE: &str = "simulator";
+
+/// Every link it opens is to a fresh [`SimBattery`]
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SimTransport {
+	config: SimConfig,
+}
+
+impl SimTransport {
+	pub fn new(config: SimConfig) -> Self {
+		Self { config }
+	}
+}
+
+impl Transport for SimTransport {
+	type Link = DuplexStream;
+
+	async fn open(&self, _device: &str, _baud: u32) -> std::io::Result<Self::Link> {
+		let (server_end, interface) = tokio::io::duplex(REPLY_FRAME_MAX_SIZE * 4);
+		tokio::spawn(sim_task(interface, self.config.clone()));
+		Ok(server_end)
+	}
+
+	/// There's no UART, whatever the server's `--baud`
+	fn uses_baud(&self, _device: &str) -> bool {
+		false
+	}
+}
+
+/// Plays the user for the demo, sets the test up, starts it, and shuts down once it's over.
+pub async fn demo_task(
+	event_tx: Sender<Event>,
+	mut status: watch::Receiver<ServerStatus>,
+	printer: Printer,
+) {
+	printer
+		.stat("demo: using the simulated battery interface")
+		.await;
+	event_tx
+		.send(Event::SetSerialDevice(SIM_DEVICE.into(), None))
+		.await
+		.unwrap();
+	event_tx.send(Event::BattID(DEMO_BATTERY)).await.unwrap();
+	let mut tested = false;
+	// the serial task prints the measurements every `--live-every-s`
+	while status.changed().await.is_ok() {
+		let mode = status.borrow_and_update().mode;
+		match mode {
+			Mode::WaitForUsrStart => {
+				printer.stat("demo: starting the test").await;
+				event_tx.send(Event::StartTest).await.unwrap();
+			}
+			Mode::Testing => tested = true,
+			Mode::Setup if tested => {
+				printer.stat("demo: test done, shutting down").await;
+				event_tx.send(Event::Shutdown).await.unwrap();
+				break;
+			}
+			Mode::Fault | Mode::CommDC => {
+				printer
+					.stat("demo: the test went wrong, shutting down")
+					.await;
+				event_tx.send(Event::Shutdown).await.unwrap();
+				break;
+			}
+			_ => {}
+		}
+	}
+	println!("exiting demo_task");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		Print, Task,
+		engine::EngineBuilder,
+		trace::{TraceRecord, read_trace},
+	};
+	use std::{path::PathBuf, time::Duration};
+	use tokio::{sync::mpsc, time::timeout};
+
+	fn test_dir(test: &str) -> PathBuf {
+		let dir =
+			std::env::temp_dir().join(format!("battery-tester-sim-{test}-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	/// Runs the demo against a [`SimTransport`] until it shuts down,
+	/// every mode channel 0's program task went through
+	async fn demo_modes(test: &str, config: SimConfig) -> Vec<Mode> {
+		let dir = test_dir(test);
+		let trace = dir.join("trace");
+		let (print_tx, mut print_rx) = mpsc::channel::<Print>(64);
+		tokio::spawn(async move { while print_rx.recv().await.is_some() {} });
+		let engine = EngineBuilder::new(dir.clone())
+			.ipc(false)
+			.transport(SimTransport::new(config))
+			.trace(trace.clone())
+			.print_to(print_tx)
+			.spawn()
+			.await
+			.unwrap();
+		let demo = tokio::spawn(demo_task(
+			engine.event_sender(0).unwrap(),
+			engine.status(0).unwrap().server,
+			engine.printer().task(Task::Demo),
+		));
+		timeout(Duration::from_secs(3_600), engine.join())
+			.await
+			.expect("demo didn't shut down");
+		demo.await.unwrap();
+		let modes = read_trace(&trace)
+			.unwrap()
+			.into_iter()
+			.filter_map(|record| match record {
+				TraceRecord::Mode(mode) => Some(mode),
+				_ => None,
+			})
+			.collect();
+		std::fs::remove_dir_all(dir).unwrap();
+		modes
+	}
+
+	/// Whether `modes` has `first`, and `then` after it
+	fn went_through(modes: &[Mode], first: Mode, then: Mode) -> bool {
+		modes
+			.iter()
+			.position(|&mode| mode == first)
+			.is_some_and(|i| modes[i..].contains(&then))
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_demo_runs_to_the_end() {
+		let modes = demo_modes("end", SimConfig::default()).await;
+		assert!(
+			went_through(&modes, Mode::Testing, Mode::EndTest),
+			"{modes:?}"
+		);
+		assert!(!modes.contains(&Mode::Fault), "{modes:?}");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_injected_faults_stop_the_test() {
+		for kind in [
+			SimFaultKind::I2c,
+			SimFaultKind::Undercurrent,
+			SimFaultKind::NoBattery,
+			SimFaultKind::Overcurrent,
+			SimFaultKind::SensorIntegrity,
+			SimFaultKind::OverTemperature,
+			SimFaultKind::LoadOverTemperature,
+		] {
+			let config = SimConfig {
+				faults: vec![SimFault { after_s: 60, kind }],
+				..SimConfig::default()
+			};
+			let modes = demo_modes("fault", config).await;
+			assert!(
+				went_through(&modes, Mode::Testing, Mode::Fault),
+				"{kind:?}: {modes:?}"
+			);
+			assert!(!modes.contains(&Mode::EndTest), "{kind:?}: {modes:?}");
+		}
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_disconnect_loses_the_link() {
+		let config = SimConfig {
+			faults: vec![SimFault {
+				after_s: 60,
+				kind: SimFaultKind::Disconnect,
+			}],
+			..SimConfig::default()
+		};
+		let modes = demo_modes("disconnect", config).await;
+		assert!(
+			went_through(&modes, Mode::Testing, Mode::CommDC),
+			"{modes:?}"
+		);
+		assert!(!modes.contains(&Mode::EndTest), "{modes:?}");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_noisy_test_still_ends() {
+		let config = SimConfig {
+			noise_mv: 50,
+			noise_ma: u16::MAX,
+			..SimConfig::default()
+		};
+		let modes = demo_modes("noise", config).await;
+		assert!(
+			went_through(&modes, Mode::Testing, Mode::EndTest),
+			"{modes:?}"
+		);
+		assert!(!modes.contains(&Mode::Fault), "{modes:?}");
+	}
+
+	#[test]
+	fn test_current_noise_saturates() {
+		let config = SimConfig {
+			noise_ma: u16::MAX,
+			..SimConfig::default()
+		};
+		let load = ControlWord {
+			load: LoadState::On,
+			..ControlWord::default()
+		};
+		let mut battery = SimBattery::new(config.clone());
+		for _ in 0..100 {
+			// the voltage has no noise, the current's is the next from the generator
+			let mut next = battery.clone();
+			let noise = next.noise(config.noise_ma);
+			let expected = (i32::from(config.load_ma) + noise).clamp(-32_768, 32_767);
+			let ibat = battery.step(load).measurement.unwrap().ibat;
+			assert_eq!(i32::from(i16::from(ibat)), expected);
+		}
+	}
+
+	#[test]
+	fn test_config_checked() {
+		let dir = test_dir("config");
+		let path = dir.join("sim.toml");
+		for (toml, valid) in [
+			("", true),
+			("capacity_mah = 1.5", true),
+			("capacity_mah = 0.0", false),
+			("capacity_mah = -10.0", false),
+			("capacity_mah = nan", false),
+			("full_mv = 4200\nempty_mv = 4200", true),
+			("full_mv = 4200\nempty_mv = 4201", false),
+		] {
+			std::fs::write(&path, toml).unwrap();
+			match SimConfig::load(&path) {
+				Ok(_) => assert!(valid, "{toml}"),
+				Err(Error::SimConfigInvalid(..)) => assert!(!valid, "{toml}"),
+				Err(e) => panic!("{toml}: {e}"),
+			}
+		}
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
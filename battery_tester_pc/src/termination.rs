@@ -0,0 +1,198 @@
+//! When a test has reached its cutoff, so one noisy measurement doesn't end it,
+//! or has run as long or taken as much out of the battery as the user asked for.
+//!
+//! `--terminate` picks the cutoff rule, the first measurement at the cutoff by default.
+
+use std::{collections::VecDeque, num::NonZeroU16, time::Duration};
+
+use battery_tester_common::{Measurement, MilliVolt};
+use serde::{Deserialize, Serialize};
+
+/// How far under the cutoff the battery interface turns the load off on its own when the
+/// PC debounces the cutoff, so it's left to the PC unless the PC stops talking to it
+const BACKSTOP_MARGIN_MILLIV: u16 = 300;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TerminationRule {
+	/// This many measurements in a row at or under the cutoff
+	Consecutive(NonZeroU16),
+	/// The average of the measurements over this long is at or under the cutoff
+	Average(Duration),
+}
+
+impl Default for TerminationRule {
+	/// The first measurement at the cutoff
+	fn default() -> Self {
+		TerminationRule::Consecutive(NonZeroU16::MIN)
+	}
+}
+
+impl std::str::FromStr for TerminationRule {
+	type Err = String;
+
+	/// `3` for 3 measurements in a row, `30s` for the average over 30 seconds
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let expected = || {
+			format!(
+				"unknown termination rule: {s}, expected a number of measurements (3) or seconds to average (30s)"
+			)
+		};
+		if let Some(secs) = s.strip_suffix('s') {
+			return match secs.parse::<u64>() {
+				Ok(secs @ 1..) => Ok(TerminationRule::Average(Duration::from_secs(secs))),
+				_ => Err(expected()),
+			};
+		}
+		s.parse()
+			.map(TerminationRule::Consecutive)
+			.map_err(|_| expected())
+	}
+}
+
+/// Why a test ended on its own, saved with the test's notes
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+	Cutoff,
+	MaxDuration,
+	MaxCapacity,
+	/// The voltage dropped under the disconnect threshold, the battery was pulled
+	Removed,
+}
+
+impl std::fmt::Display for EndReason {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			EndReason::Cutoff => "reached the cutoff",
+			EndReason::MaxDuration => "reached the maximum duration",
+			EndReason::MaxCapacity => "reached the maximum capacity",
+			EndReason::Removed => "the battery was removed",
+		})
+	}
+}
+
+/// Tracks the measurements of the running test against its [`TerminationRule`]
+/// and the limits on how long it runs and how much it takes out of the battery
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Termination {
+	rule: TerminationRule,
+	max_duration: Option<Duration>,
+	max_mah: Option<u32>,
+	/// Measurements in a row at or under the cutoff
+	under: u16,
+	/// Timestamp (ms) and voltage of the measurements within the averaging window
+	window: VecDeque<(u64, MilliVolt)>,
+	/// Timestamp (ms) of the newest measurement since testing last started
+	last_dt: Option<u64>,
+	/// Time spent testing this test, not counting pauses
+	tested_ms: u64,
+	/// Charge taken out of the battery this test, mA x ms
+	discharged: i64,
+}
+
+impl Termination {
+	pub fn set_rule(&mut self, rule: TerminationRule) {
+		self.rule = rule;
+	}
+
+	/// `None` to test until the cutoff
+	pub fn set_max_duration(&mut self, max_duration: Option<Duration>) {
+		self.max_duration = max_duration;
+	}
+
+	/// `None` to test until the cutoff
+	pub fn set_max_mah(&mut self, max_mah: Option<u32>) {
+		self.max_mah = max_mah;
+	}
+
+	pub fn max_duration(&self) -> Option<Duration> {
+		self.max_duration
+	}
+
+	pub fn max_mah(&self) -> Option<u32> {
+		self.max_mah
+	}
+
+	/// Forget the measurements when testing starts or carries on, e.g. after a profile step,
+	/// what's been tested so far still counts
+	pub fn reset(&mut self) {
+		self.under = 0;
+		self.window.clear();
+		self.last_dt = None;
+	}
+
+	/// Start counting the time and charge over for a new test
+	pub fn new_test(&mut self) {
+		self.reset();
+		self.tested_ms = 0;
+		self.discharged = 0;
+	}
+
+	/// Where the battery interface turns off the load on its own
+	pub fn backstop(&self, cutoff: MilliVolt) -> MilliVolt {
+		match self.rule {
+			TerminationRule::Consecutive(n) if n.get() == 1 => cutoff,
+			_ => MilliVolt::new(cutoff.saturating_sub(BACKSTOP_MARGIN_MILLIV)),
+		}
+	}
+
+	/// Take in a fresh measurement, `Some` once the test should end
+	pub fn reached(&mut self, m: &Measurement, cutoff: MilliVolt) -> Option<EndReason> {
+		// same integration as the mAh column of saved files
+		let interval_ms = match self.last_dt {
+			Some(last_dt) => m.sample_start_ms.saturating_sub(last_dt),
+			None => m.sample_duration_ms,
+		};
+		self.last_dt = Some(m.sample_start_ms);
+		self.tested_ms += interval_ms;
+		self.discharged += i64::from(i16::from(m.ibat)) * interval_ms as i64;
+		if self.reached_cutoff(m, cutoff) {
+			Some(EndReason::Cutoff)
+		} else if self
+			.max_duration
+			.is_some_and(|max| self.tested_ms >= max.as_millis() as u64)
+		{
+			Some(EndReason::MaxDuration)
+		} else if self
+			.max_mah
+			.is_some_and(|max| self.discharged >= i64::from(max) * 3_600_000)
+		{
+			Some(EndReason::MaxCapacity)
+		} else {
+			None
+		}
+	}
+
+	fn reached_cutoff(&mut self, m: &Measurement, cutoff: MilliVolt) -> bool {
+		match self.rule {
+			TerminationRule::Consecutive(n) => {
+				if m.vbat > cutoff {
+					self.under = 0;
+				} else {
+					self.under = self.under.saturating_add(1);
+				}
+				self.under >= n.get()
+			}
+			TerminationRule::Average(over) => {
+				let over = over.as_millis() as u64;
+				let newest = m.sample_start_ms + m.sample_duration_ms;
+				self.window.push_back((newest, m.vbat));
+				// keep one measurement from at or before the start of the window,
+				// so it's known the window is full
+				while self
+					.window
+					.get(1)
+					.is_some_and(|&(t, _)| newest.saturating_sub(t) >= over)
+				{
+					self.window.pop_front();
+				}
+				let full = self
+					.window
+					.front()
+					.is_some_and(|&(t, _)| newest.saturating_sub(t) >= over);
+				let total: u64 = self.window.iter().map(|&(_, mv)| u64::from(*mv)).sum();
+				full && total / self.window.len() as u64 <= u64::from(*cutoff)
+			}
+		}
+	}
+}
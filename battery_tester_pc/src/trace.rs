@@ -0,0 +1,124 @@
+//! Event traces for working out after the fact why the server did what it did.
+//!
+//! Every [`Event`] the program task receives is written to the trace in the
+//! order it was received, along with each [`Mode`] the program task entered.
+//! The program task only acts on events, so feeding the events back through
+//! it (`--replay`) reproduces the same modes.
+//!
+//! Records are postcard, each prefixed with its length as a big endian `u32`,
+//! and flushed as they're written so a crash loses nothing.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+	fs::File,
+	io::{AsyncWriteExt, BufWriter},
+	select,
+	sync::mpsc::{Receiver, Sender},
+	time::Instant,
+};
+
+use crate::{Error, Event, Mode, Printer};
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum TraceRecord {
+	/// First record in every trace
+	Start {
+		/// Local time the server started
+		started: Box<str>,
+		server_version: Box<str>,
+	},
+	Event {
+		/// Counts up from 0 with each event
+		seq: u64,
+		/// Time since [`TraceRecord::Start`]
+		elapsed_ms: u64,
+		event: Event,
+	},
+	/// The program task entered this mode
+	Mode(Mode),
+}
+
+/// Sits between everything sending events and the program task, writing down what passes through.
+/// Runs until every event sender is gone.
+pub async fn trace_task(
+	file: File,
+	mut event_rx: Receiver<Event>,
+	program_event_tx: Sender<Event>,
+	mut mode_rx: Receiver<Mode>,
+	printer: Printer,
+) {
+	let mut trace = BufWriter::new(file);
+	let started = Instant::now();
+	let start = TraceRecord::Start {
+		started: chrono::Local::now().to_rfc3339().into_boxed_str(),
+		server_version: env!("CARGO_PKG_VERSION").into(),
+	};
+	let mut write_ok = write_record(&mut trace, &start).await;
+	let mut seq: u64 = 0;
+	let mut modes_open = true;
+	loop {
+		let record = select! {
+			event = event_rx.recv() => match event {
+				Some(event) => {
+					let record = TraceRecord::Event {
+						seq,
+						elapsed_ms: started.elapsed().as_millis() as u64,
+						event: event.clone(),
+					};
+					seq += 1;
+					// the program task may be gone at shutdown, keep draining so senders don't fail
+					let _ = program_event_tx.send(event).await;
+					record
+				}
+				None => break,
+			},
+			mode = mode_rx.recv(), if modes_open => match mode {
+				Some(mode) => TraceRecord::Mode(mode),
+				None => {
+					modes_open = false;
+					continue;
+				}
+			},
+		};
+		// report once, not for every record after the disk fills up
+		if write_ok && !write_record(&mut trace, &record).await {
+			write_ok = false;
+			printer
+				.error_stat("can't write to the trace file, tracing stopped")
+				.await;
+		}
+	}
+	println!("exiting trace_task");
+}
+
+async fn write_record(trace: &mut BufWriter<File>, record: &TraceRecord) -> bool {
+	// only fails for types postcard can't represent, none are used here
+	let bytes = postcard::to_extend(record, Vec::new()).unwrap();
+	let written = async {
+		trace.write_u32(bytes.len() as u32).await?;
+		trace.write_all(&bytes).await?;
+		trace.flush().await
+	};
+	written.await.is_ok()
+}
+
+/// Read a whole trace, a record cut off by a crash ends it
+pub fn read_trace(path: &Path) -> Result<Vec<TraceRecord>, Error> {
+	let bytes = std::fs::read(path)
+		.map_err(|e| Error::TraceRead(path.to_path_buf().into_boxed_path(), e))?;
+	let mut records = Vec::new();
+	let mut rest = bytes.as_slice();
+	while let Some((len, after_len)) = rest.split_first_chunk::<4>() {
+		let len = u32::from_be_bytes(*len) as usize;
+		let Some((record, after_record)) = after_len.split_at_checked(len) else {
+			break;
+		};
+		let record = postcard::from_bytes(record)
+			.map_err(|e| Error::TraceParse(path.to_path_buf().into_boxed_path(), e))?;
+		records.push(record);
+		rest = after_record;
+	}
+	Ok(records)
+}
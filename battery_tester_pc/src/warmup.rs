@@ -0,0 +1,79 @@
+//! Time under the load before a test is recorded, so the transients of connecting the load
+//! don't count towards its capacity. Saved times start from 0 at the end of it.
+//!
+//! `--warmup` turns it on, tests are recorded from the first measurement by default.
+
+use std::{collections::VecDeque, time::Duration};
+
+use battery_tester_common::Measurement;
+
+/// Measurements the current has to stay within [`SETTLED_SPREAD_MILLIAMPS`] over
+const SETTLED_MEASUREMENTS: usize = 5;
+const SETTLED_SPREAD_MILLIAMPS: i16 = 50;
+/// Longest to wait for the current to settle, a noisy load may never
+const MAX_SETTLE: Duration = Duration::from_secs(120);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum WarmupRule {
+	/// Drop this long of measurements
+	For(Duration),
+	/// Drop measurements until the current stops changing, for at most [`MAX_SETTLE`]
+	Settled,
+}
+
+impl std::str::FromStr for WarmupRule {
+	type Err = String;
+
+	/// `30s` for 30 seconds, `settled` until the current settles
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s == "settled" {
+			return Ok(WarmupRule::Settled);
+		}
+		match s.strip_suffix('s').map(str::parse::<u64>) {
+			Some(Ok(secs @ 1..)) => Ok(WarmupRule::For(Duration::from_secs(secs))),
+			_ => Err(format!(
+				"unknown warmup: {s}, expected seconds (30s) or settled"
+			)),
+		}
+	}
+}
+
+/// Tracks the measurements under the load against a [`WarmupRule`]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Warmup {
+	rule: WarmupRule,
+	/// Timestamp (ms) of the first measurement under the load
+	started: Option<u64>,
+	/// The latest currents, for [`WarmupRule::Settled`]
+	currents: VecDeque<i16>,
+}
+
+impl Warmup {
+	pub fn new(rule: WarmupRule) -> Self {
+		Self {
+			rule,
+			started: None,
+			currents: VecDeque::with_capacity(SETTLED_MEASUREMENTS),
+		}
+	}
+
+	/// Take in a measurement under the load, `true` once the warmup is over
+	/// and it's the first one to record
+	pub fn measured(&mut self, m: &Measurement) -> bool {
+		let started = *self.started.get_or_insert(m.sample_start_ms);
+		let warmed_ms = m.sample_start_ms.saturating_sub(started);
+		match self.rule {
+			WarmupRule::For(warmup) => warmed_ms >= warmup.as_millis() as u64,
+			WarmupRule::Settled => {
+				if self.currents.len() == SETTLED_MEASUREMENTS {
+					self.currents.pop_front();
+				}
+				self.currents.push_back(*m.ibat);
+				let spread = self.currents.iter().max().unwrap_or(&0)
+					- self.currents.iter().min().unwrap_or(&0);
+				(self.currents.len() == SETTLED_MEASUREMENTS && spread <= SETTLED_SPREAD_MILLIAMPS)
+					|| warmed_ms >= MAX_SETTLE.as_millis() as u64
+			}
+		}
+	}
+}
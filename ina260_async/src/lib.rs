@@ -1,9 +1,17 @@
-use battery_tester_common::{MilliAmp, MilliVolt};
-use embassy_nrf::twim;
+//! Async driver for the TI INA260 current, voltage and power monitor.
+//!
+//! Generic over any [`embedded_hal_async::i2c::I2c`] bus, borrowed for each call so the bus
+//! can be shared with other devices. Readings are in the register's units scaled to mA, mV and mW,
+//! any calibration is up to the caller.
+
+#![no_std]
+
+use embedded_hal_async::i2c::I2c;
 
 #[allow(dead_code)]
 #[allow(non_camel_case_types)]
-#[derive(Copy, Clone, defmt::Format)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Register {
 	// Configuration Register
 	CONFIG = 0x00,
@@ -37,7 +45,8 @@ impl From<Register> for u8 {
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone, defmt::Format)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Averaging Mode
 /// Determines the number of samples that are collected and averaged.
 pub enum Averaging {
@@ -64,10 +73,24 @@ impl Averaging {
 	pub fn bits(self) -> u16 {
 		self as u16
 	}
+
+	pub fn samples(self) -> u32 {
+		match self {
+			Averaging::AVG1 => 1,
+			Averaging::AVG4 => 4,
+			Averaging::AVG16 => 16,
+			Averaging::AVG64 => 64,
+			Averaging::AVG128 => 128,
+			Averaging::AVG256 => 256,
+			Averaging::AVG512 => 512,
+			Averaging::AVG1024 => 1024,
+		}
+	}
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone, defmt::Format)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Bus Voltage Conversion Time
 /// Sets the conversion time for the bus voltage measurement
 pub enum BVConvTime {
@@ -94,10 +117,24 @@ impl BVConvTime {
 	pub fn bits(self) -> u16 {
 		self as u16
 	}
+
+	pub fn micros(self) -> u32 {
+		match self {
+			BVConvTime::US140 => 140,
+			BVConvTime::US204 => 204,
+			BVConvTime::US332 => 332,
+			BVConvTime::US588 => 588,
+			BVConvTime::MS1_1 => 1_100,
+			BVConvTime::MS2_116 => 2_116,
+			BVConvTime::MS4_156 => 4_156,
+			BVConvTime::MS8_244 => 8_244,
+		}
+	}
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone, defmt::Format)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Shunt Current Conversion Time
 /// Sets the conversion time for the shunt current measurement
 pub enum SCConvTime {
@@ -124,10 +161,24 @@ impl SCConvTime {
 	pub fn bits(self) -> u16 {
 		self as u16
 	}
+
+	pub fn micros(self) -> u32 {
+		match self {
+			SCConvTime::US140 => 140,
+			SCConvTime::US204 => 204,
+			SCConvTime::US332 => 332,
+			SCConvTime::US588 => 588,
+			SCConvTime::MS1_1 => 1_100,
+			SCConvTime::MS2_116 => 2_116,
+			SCConvTime::MS4_156 => 4_156,
+			SCConvTime::MS8_244 => 8_244,
+		}
+	}
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone, defmt::Format)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Operating Mode
 /// Selects continuous, triggered, or power-down mode of operation.
 pub enum OperMode {
@@ -252,7 +303,8 @@ impl MaskEnable {
 	}
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct INA260Config {
 	om: OperMode,
 	am: Averaging,
@@ -267,6 +319,12 @@ impl Default for INA260Config {
 }
 
 impl INA260Config {
+	/// How long each measurement takes, both conversions times the samples averaged.
+	/// The time for [`OperMode::SCBVC`], what the battery interface runs in.
+	pub fn conversion_us(&self) -> u32 {
+		self.am.samples() * (self.scct.micros() + self.bvct.micros())
+	}
+
 	pub fn new() -> Self {
 		Self {
 			om: OperMode::SCBVC,
@@ -291,52 +349,244 @@ impl INA260Config {
 		self.bvct = bvct;
 		self
 	}
+	pub fn bits(&self) -> u16 {
+		self.om.bits() | self.am.bits() | self.scct.bits() | self.bvct.bits()
+	}
 	pub fn as_be_bytes(&self) -> [u8; 2] {
-		let as_u16 = self.om.bits() | self.am.bits() | self.scct.bits() | self.bvct.bits();
-		as_u16.to_be_bytes()
+		self.bits().to_be_bytes()
 	}
 }
 
-pub async fn set_config(
+/// Texas Instruments, "TI" in ASCII
+pub const MANUFACTURER_ID: u16 = 0x5449;
+/// The upper 12 bits of [`Register::DIE_ID`]
+pub const CHIP_ID: u16 = 0x227;
+/// Bits of [`Register::CONFIG`] that read back what was written,
+/// the reset bit always reads 0 and the three above the averaging mode 110
+const CONFIG_MASK: u16 = 0x0FFF;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+	I2C(E),
+	/// The configuration register read back differently to what was written
+	ConfigMismatch {
+		written: u16,
+		read: u16,
+	},
+	/// Something answered at the address but its IDs aren't an INA260's
+	NotIna260 {
+		manufacturer_id: u16,
+		die_id: u16,
+	},
+}
+
+impl<E> From<E> for Error<E> {
+	fn from(e: E) -> Self {
+		Error::I2C(e)
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DieId {
+	pub chip_id: u16,
+	pub revision: u8,
+}
+
+/// Checks the manufacturer and chip IDs, returning the die revision
+pub async fn check_id<I: I2c>(address: u8, i2c: &mut I) -> Result<DieId, Error<I::Error>> {
+	let manufacturer_id = read_register(address, i2c, Register::MANUFACTURER_ID).await?;
+	let die_id = read_register(address, i2c, Register::DIE_ID).await?;
+	let id = DieId {
+		chip_id: die_id >> 4,
+		revision: (die_id & 0b1111) as u8,
+	};
+	if manufacturer_id != MANUFACTURER_ID || id.chip_id != CHIP_ID {
+		return Err(Error::NotIna260 {
+			manufacturer_id,
+			die_id,
+		});
+	}
+	Ok(id)
+}
+
+/// Writes `conf` and reads it back to check it took
+pub async fn set_config<I: I2c>(
 	address: u8,
-	i2c: &mut twim::Twim<'static>,
+	i2c: &mut I,
 	conf: INA260Config,
-) -> Result<(), twim::Error> {
+) -> Result<(), Error<I::Error>> {
 	let bytes = conf.as_be_bytes();
 	i2c.write(address, &[Register::CONFIG.into(), bytes[0], bytes[1]])
-		.await
+		.await?;
+	let read = read_register(address, i2c, Register::CONFIG).await?;
+	if read & CONFIG_MASK != conf.bits() {
+		return Err(Error::ConfigMismatch {
+			written: conf.bits(),
+			read,
+		});
+	}
+	Ok(())
 }
 
-pub async fn shutdown<I>(address: u8, i2c: &mut twim::Twim<'static>) -> Result<(), twim::Error>
-where
-	I: embassy_nrf::twim::Instance,
-{
+pub async fn shutdown<I: I2c>(address: u8, i2c: &mut I) -> Result<(), I::Error> {
 	let bytes = OperMode::SHUTDOWN.bits().to_be_bytes();
 	i2c.write(address, &[Register::CONFIG.into(), bytes[0], bytes[1]])
 		.await
 }
 
-/// Returns current in milliamps
-pub async fn get_amps(address: u8, i2c: &mut twim::Twim<'static>) -> Result<MilliAmp, twim::Error> {
-	let mut buffer = [0u8; 2];
-	let raw = i32::from({
-		i2c.write_read(address, &[Register::CURRENT.addr()], &mut buffer)
-			.await?;
-		u16::from_be_bytes(buffer) as i16
-	});
-	Ok(MilliAmp::new((raw * 1250 / 1000).unsigned_abs() as u16))
+/// Whether a conversion finished since the last call, reading the flag clears it
+pub async fn conversion_ready<I: I2c>(address: u8, i2c: &mut I) -> Result<bool, I::Error> {
+	let mask_enable = read_register(address, i2c, Register::MASK_ENABLE).await?;
+	Ok(mask_enable & MaskEnable::CVRF.bits() != 0)
 }
 
-/// Returns voltage as millivolts
-pub async fn get_voltage(
+/// Returns current in milliamps, positive flowing from IN+ to IN-.
+/// The register's full scale is past the 15 A the part can measure.
+pub async fn read_current<I: I2c>(address: u8, i2c: &mut I) -> Result<i32, I::Error> {
+	let raw = read_register(address, i2c, Register::CURRENT).await? as i16;
+	// 1.25 mA per bit
+	Ok(i32::from(raw) * 1250 / 1000)
+}
+
+/// Returns the bus voltage in millivolts
+pub async fn read_voltage<I: I2c>(address: u8, i2c: &mut I) -> Result<u32, I::Error> {
+	let raw = read_register(address, i2c, Register::VOLTAGE).await?;
+	// 1.25 mV per bit
+	Ok(u32::from(raw) * 1250 / 1000)
+}
+
+/// Returns power in milliwatts, the magnitude whichever way the current flows
+pub async fn read_power<I: I2c>(address: u8, i2c: &mut I) -> Result<u32, I::Error> {
+	let raw = read_register(address, i2c, Register::POWER).await?;
+	// 10 mW per bit
+	Ok(u32::from(raw) * 10)
+}
+
+async fn read_register<I: I2c>(
 	address: u8,
-	i2c: &mut twim::Twim<'static>,
-) -> Result<MilliVolt, twim::Error> {
+	i2c: &mut I,
+	register: Register,
+) -> Result<u16, I::Error> {
 	let mut buffer = [0u8; 2];
-	let raw = u32::from({
-		i2c.write_read(address, &[Register::VOLTAGE.addr()], &mut buffer)
-			.await?;
-		u16::from_be_bytes(buffer)
-	});
-	Ok(MilliVolt::new((raw * 1250 / 1000) as u16))
+	i2c.write_read(address, &[register.addr()], &mut buffer)
+		.await?;
+	Ok(u16::from_be_bytes(buffer))
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate std;
+
+	use std::vec;
+
+	use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+	use futures::executor::block_on;
+
+	use super::*;
+
+	const ADDRESS: u8 = 0x40;
+
+	fn read(register: Register, value: u16) -> Transaction {
+		Transaction::write_read(ADDRESS, vec![register.addr()], value.to_be_bytes().to_vec())
+	}
+
+	#[test]
+	fn test_default_config_bits() {
+		// continuous, 4 sample average, 1.1 ms conversions
+		assert_eq!(INA260Config::new().bits(), 0x0327);
+		assert_eq!(INA260Config::new().conversion_us(), 8_800);
+	}
+
+	#[test]
+	fn test_set_config_verifies_past_the_reserved_bits() {
+		let mut conf = INA260Config::new();
+		conf.set_averaging_mode(Averaging::AVG256)
+			.set_sccov_time(SCConvTime::US204)
+			.set_bvcov_time(BVConvTime::US204);
+		let mut i2c = Mock::new(&[
+			Transaction::write(ADDRESS, vec![Register::CONFIG.addr(), 0x0A, 0x4F]),
+			read(Register::CONFIG, 0x6A4F),
+		]);
+		assert_eq!(block_on(set_config(ADDRESS, &mut i2c, conf)), Ok(()));
+		i2c.done();
+	}
+
+	#[test]
+	fn test_set_config_mismatch() {
+		let mut i2c = Mock::new(&[
+			Transaction::write(ADDRESS, vec![Register::CONFIG.addr(), 0x03, 0x27]),
+			// still the power on default
+			read(Register::CONFIG, 0x6127),
+		]);
+		assert_eq!(
+			block_on(set_config(ADDRESS, &mut i2c, INA260Config::new())),
+			Err(Error::ConfigMismatch {
+				written: 0x0327,
+				read: 0x6127
+			})
+		);
+		i2c.done();
+	}
+
+	#[test]
+	fn test_check_id() {
+		let mut i2c = Mock::new(&[
+			read(Register::MANUFACTURER_ID, 0x5449),
+			read(Register::DIE_ID, 0x2270),
+		]);
+		assert_eq!(
+			block_on(check_id(ADDRESS, &mut i2c)),
+			Ok(DieId {
+				chip_id: 0x227,
+				revision: 0
+			})
+		);
+		i2c.done();
+	}
+
+	#[test]
+	fn test_check_id_rejects_other_parts() {
+		// some other part at the INA260's address
+		let mut i2c = Mock::new(&[
+			read(Register::MANUFACTURER_ID, 0x1234),
+			read(Register::DIE_ID, 0x2270),
+		]);
+		assert_eq!(
+			block_on(check_id(ADDRESS, &mut i2c)),
+			Err(Error::NotIna260 {
+				manufacturer_id: 0x1234,
+				die_id: 0x2270
+			})
+		);
+		i2c.done();
+	}
+
+	#[test]
+	fn test_readings_are_scaled() {
+		let mut i2c = Mock::new(&[
+			// -1.25 A
+			read(Register::CURRENT, (-1_000i16) as u16),
+			// 12.5 V
+			read(Register::VOLTAGE, 10_000),
+			read(Register::POWER, 1_563),
+		]);
+		assert_eq!(block_on(read_current(ADDRESS, &mut i2c)), Ok(-1_250));
+		assert_eq!(block_on(read_voltage(ADDRESS, &mut i2c)), Ok(12_500));
+		assert_eq!(block_on(read_power(ADDRESS, &mut i2c)), Ok(15_630));
+		i2c.done();
+	}
+
+	#[test]
+	fn test_conversion_ready() {
+		let mut i2c = Mock::new(&[
+			read(Register::MASK_ENABLE, 0x0008),
+			read(Register::MASK_ENABLE, 0x0400),
+		]);
+		assert_eq!(block_on(conversion_ready(ADDRESS, &mut i2c)), Ok(true));
+		// alert on conversion ready is set but the flag isn't
+		assert_eq!(block_on(conversion_ready(ADDRESS, &mut i2c)), Ok(false));
+		i2c.done();
+	}
 }